@@ -0,0 +1,13 @@
+//! Parsing arbitrary bytes must never panic: `cargo +nightly fuzz run parse`
+
+#![no_main]
+
+use dns_starter_rust::packet::{BytesPacket, DnsPacket};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = DnsPacket::parse(data) {
+        // whatever was parsed must also be serializable
+        let _ = BytesPacket::from(packet);
+    }
+});
@@ -0,0 +1,723 @@
+//! Certificates from an ACME certificate authority, e.g. Let's Encrypt (RFC 8555)
+//!
+//! The CA validates the domain with the HTTP-01 challenge: it fetches
+//! `http://<domain>/.well-known/acme-challenge/<token>`, answered by a small HTTP listener
+//! (port 80 of the domain must reach it). Obtained certificates and their keys are written
+//! to the files the DoT listener loads them from, it picks them up on its own (see
+//! [`crate::dot`]).
+//!
+//! The account key is kept in a file of its own, so renewals use the same account.
+//! Certificates are renewed when less than [`RENEW_BEFORE`] of their validity is left,
+//! checked every [`CHECK_INTERVAL`].
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING,
+};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, StreamOwned};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::base64;
+use crate::log::days_from_civil;
+use crate::tls::{der_element, read_http_response, system_roots, tbs_certificate, HttpResponse};
+
+/// Let's Encrypt production directory
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Certificates are renewed when they expire sooner than this
+pub const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// How often the certificate is checked for renewal
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+/// Time between polls of pending authorizations and orders
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Polls before giving up on an authorization or order
+const MAX_POLLS: usize = 60;
+
+/// Certificate for one domain, obtained and renewed from an ACME CA
+#[derive(Debug)]
+pub struct Acme {
+    directory: String,
+    domain: String,
+    contact: Option<String>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    account_key_path: PathBuf,
+    /// Key authorizations by token, served by the HTTP-01 listener
+    challenges: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Acme {
+    /// Certificate for `domain` written to `cert_path` (chain) and `key_path` (its key),
+    /// account key is kept in `<key_path>.acme-account`
+    pub fn new(
+        domain: impl Into<String>,
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Self {
+        let key_path = key_path.as_ref().to_path_buf();
+        let mut account_key_path = key_path.clone().into_os_string();
+        account_key_path.push(".acme-account");
+        Self {
+            directory: LETS_ENCRYPT.to_string(),
+            domain: domain.into(),
+            contact: None,
+            cert_path: cert_path.as_ref().to_path_buf(),
+            key_path,
+            account_key_path: account_key_path.into(),
+            challenges: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Directory URL of the CA, Let's Encrypt by default
+    pub fn with_directory(mut self, directory: impl Into<String>) -> Self {
+        self.directory = directory.into();
+        self
+    }
+
+    /// E-mail address the CA may use for notices about the account
+    pub fn with_contact(mut self, email: impl Into<String>) -> Self {
+        self.contact = Some(email.into());
+        self
+    }
+
+    /// Starts the HTTP-01 listener on `http_address`, obtains the certificate if there is
+    /// none yet and keeps renewing it in the background
+    pub fn start(self, http_address: &str) -> Result<()> {
+        let listener = TcpListener::bind(http_address).with_context(|| {
+            format!("Failed to bind ACME challenge listener to {}", http_address)
+        })?;
+        println!("ACME HTTP-01 challenges served on {}", http_address);
+        let challenges = self.challenges.clone();
+        thread::spawn(move || serve_challenges(listener, &challenges));
+
+        if self.needs_renewal() {
+            match self.obtain() {
+                Ok(()) => {}
+                // expiring certificate still works for a while
+                Err(e) if self.cert_path.exists() => {
+                    eprintln!("ACME: renewal of {} failed: {:#}", self.domain, e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        thread::spawn(move || loop {
+            thread::sleep(CHECK_INTERVAL);
+            if self.needs_renewal() {
+                if let Err(e) = self.obtain() {
+                    eprintln!("ACME: renewal of {} failed: {:#}", self.domain, e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Certificate file is missing, unreadable or expires soon
+    fn needs_renewal(&self) -> bool {
+        let not_after = std::fs::read(&self.cert_path)
+            .ok()
+            .and_then(|pem| CertificateDer::from_pem_slice(&pem).ok())
+            .and_then(|certificate| not_after(&certificate));
+        match not_after {
+            Some(not_after) => not_after
+                .duration_since(SystemTime::now())
+                .map_or(true, |left| left < RENEW_BEFORE),
+            None => true,
+        }
+    }
+
+    /// Orders a new certificate and writes it with its key to the files
+    fn obtain(&self) -> Result<()> {
+        println!("ACME: requesting certificate for {}", self.domain);
+        let account_key = load_or_create_key(&self.account_key_path)?;
+        let mut session = Session::new(&self.directory, account_key)?;
+        session.register(self.contact.as_deref())?;
+
+        let (order_url, order) = session.post(
+            &session.directory_url("newOrder")?,
+            Some(json!({ "identifiers": [{ "type": "dns", "value": self.domain }] })),
+        )?;
+        let order_url = order_url.context("order without location")?;
+        for authorization in json_array(&order["authorizations"]) {
+            self.authorize(
+                &mut session,
+                authorization.as_str().context("invalid order")?,
+            )?;
+        }
+
+        let certificate_key =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &SystemRandom::new())
+                .map_err(|_| anyhow::anyhow!("failed to generate certificate key"))?;
+        let csr = certificate_request(&self.domain, certificate_key.as_ref())?;
+        let finalize = order["finalize"]
+            .as_str()
+            .context("order without finalize URL")?;
+        session.post(finalize, Some(json!({ "csr": base64::encode_url(&csr) })))?;
+
+        let order = session.poll(&order_url, "order")?;
+        let certificate_url = order["certificate"]
+            .as_str()
+            .context("order without certificate")?;
+        let chain = session.download(certificate_url)?;
+
+        write_file(
+            &self.key_path,
+            base64::pem("PRIVATE KEY", certificate_key.as_ref()).as_bytes(),
+        )?;
+        write_file(&self.cert_path, &chain)?;
+        println!(
+            "ACME: certificate for {} written to {}",
+            self.domain,
+            self.cert_path.display()
+        );
+        Ok(())
+    }
+
+    /// Completes the HTTP-01 challenge of the authorization (if still pending)
+    fn authorize(&self, session: &mut Session, url: &str) -> Result<()> {
+        let (_, authorization) = session.post(url, None)?;
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+        let challenge = json_array(&authorization["challenges"])
+            .find(|challenge| challenge["type"] == "http-01")
+            .context("CA offers no HTTP-01 challenge")?;
+        let token = challenge["token"]
+            .as_str()
+            .context("challenge without token")?;
+        let challenge_url = challenge["url"].as_str().context("challenge without URL")?;
+
+        let key_authorization = format!("{}.{}", token, session.account_key.thumbprint());
+        self.challenges
+            .lock()
+            .expect("challenges lock poisoned")
+            .insert(token.to_string(), key_authorization);
+
+        let result = session
+            .post(challenge_url, Some(json!({})))
+            .and_then(|_| session.poll(url, "authorization"));
+        self.challenges
+            .lock()
+            .expect("challenges lock poisoned")
+            .remove(token);
+        result.map(|_| ())
+    }
+}
+
+/// Account with the CA, requests are signed by the account key (JWS, RFC 7515)
+struct Session {
+    client: Arc<ClientConfig>,
+    directory: Value,
+    account_key: AccountKey,
+    /// Account URL, identifies the key once the account exists
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Session {
+    fn new(directory_url: &str, account_key: AccountKey) -> Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .context("no usable TLS version")?
+            .with_root_certificates(system_roots()?)
+            .with_no_client_auth();
+        let client = Arc::new(client);
+
+        let response = https_request(&client, "GET", directory_url, None)?;
+        if response.status != 200 {
+            anyhow::bail!(
+                "ACME directory {} returned {}",
+                directory_url,
+                response.status
+            );
+        }
+        let directory = serde_json::from_slice(&response.body).context("invalid directory")?;
+
+        Ok(Self {
+            client,
+            directory,
+            account_key,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    fn directory_url(&self, name: &str) -> Result<String> {
+        self.directory[name]
+            .as_str()
+            .map(str::to_string)
+            .with_context(|| format!("ACME directory without {}", name))
+    }
+
+    /// Creates the account, or looks up the existing one of the key
+    fn register(&mut self, contact: Option<&str>) -> Result<()> {
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = contact {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let (location, _) = self.post(&self.directory_url("newAccount")?, Some(payload))?;
+        self.kid = Some(location.context("account without location")?);
+        Ok(())
+    }
+
+    /// Signed POST (POST-as-GET without payload), returns Location header and JSON body
+    fn post(&mut self, url: &str, payload: Option<Value>) -> Result<(Option<String>, Value)> {
+        let response = self.signed_request(url, payload)?;
+        let body = if response.body.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice(&response.body).context("invalid JSON from ACME server")?
+        };
+        Ok((response.header("location").map(str::to_string), body))
+    }
+
+    /// Certificate chain (PEM) of a valid order
+    fn download(&mut self, url: &str) -> Result<Vec<u8>> {
+        Ok(self.signed_request(url, None)?.body)
+    }
+
+    /// Polls the order or authorization until it is valid
+    fn poll(&mut self, url: &str, what: &str) -> Result<Value> {
+        for _ in 0..MAX_POLLS {
+            let (_, object) = self.post(url, None)?;
+            match object["status"].as_str() {
+                Some("valid") => return Ok(object),
+                Some("pending" | "processing" | "ready") => thread::sleep(POLL_INTERVAL),
+                status => anyhow::bail!("{} is {}: {}", what, status.unwrap_or("invalid"), object),
+            }
+        }
+        anyhow::bail!("{} still not valid after {} polls", what, MAX_POLLS)
+    }
+
+    fn signed_request(&mut self, url: &str, payload: Option<Value>) -> Result<HttpResponse> {
+        // a nonce may be rejected (e.g. expired), the error response carries a fresh one
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce()?,
+            };
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.account_key.jwk(),
+            }
+            let body = self.account_key.sign(&protected, payload.as_ref())?;
+
+            let response =
+                https_request(&self.client, "POST", url, Some(body.to_string().as_bytes()))?;
+            self.nonce = response.header("replay-nonce").map(str::to_string);
+            if (200..300).contains(&response.status) {
+                return Ok(response);
+            }
+
+            let problem: Value = serde_json::from_slice(&response.body).unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            anyhow::bail!(
+                "ACME server returned {} for {}: {}",
+                response.status,
+                url,
+                problem
+            );
+        }
+    }
+
+    fn new_nonce(&self) -> Result<String> {
+        let response = https_request(&self.client, "GET", &self.directory_url("newNonce")?, None)?;
+        response
+            .header("replay-nonce")
+            .map(str::to_string)
+            .context("ACME server sent no nonce")
+    }
+}
+
+/// ECDSA P-256 key of the account
+struct AccountKey {
+    pair: EcdsaKeyPair,
+    rng: SystemRandom,
+}
+
+impl AccountKey {
+    /// Public key as JWK (RFC 7517)
+    fn jwk(&self) -> Value {
+        let (x, y) = self.coordinates();
+        json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y })
+    }
+
+    /// JWK thumbprint (RFC 7638), members in lexicographic order without whitespace
+    fn thumbprint(&self) -> String {
+        let (x, y) = self.coordinates();
+        let jwk = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        base64::encode_url(&Sha256::digest(jwk.as_bytes()))
+    }
+
+    fn coordinates(&self) -> (String, String) {
+        // uncompressed point: 0x04, x, y
+        let point = self.pair.public_key().as_ref();
+        (
+            base64::encode_url(&point[1..33]),
+            base64::encode_url(&point[33..65]),
+        )
+    }
+
+    /// JWS in flattened JSON serialization, empty payload for POST-as-GET
+    fn sign(&self, protected: &Value, payload: Option<&Value>) -> Result<Value> {
+        let protected = base64::encode_url(protected.to_string().as_bytes());
+        let payload = payload
+            .map(|payload| base64::encode_url(payload.to_string().as_bytes()))
+            .unwrap_or_default();
+        let signature = self
+            .pair
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| anyhow::anyhow!("failed to sign ACME request"))?;
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": base64::encode_url(signature.as_ref()),
+        }))
+    }
+}
+
+fn load_or_create_key(path: &Path) -> Result<AccountKey> {
+    let rng = SystemRandom::new();
+    let pkcs8 = match PrivateKeyDer::from_pem_file(path) {
+        Ok(PrivateKeyDer::Pkcs8(key)) => key.secret_pkcs8_der().to_vec(),
+        Ok(_) => anyhow::bail!("{} is not a PKCS#8 key", path.display()),
+        Err(_) if !path.exists() => {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .map_err(|_| anyhow::anyhow!("failed to generate account key"))?;
+            write_file(path, base64::pem("PRIVATE KEY", pkcs8.as_ref()).as_bytes())?;
+            println!("ACME: new account key written to {}", path.display());
+            pkcs8.as_ref().to_vec()
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+        .map_err(|_| anyhow::anyhow!("{} is not an ECDSA P-256 key", path.display()))?;
+    Ok(AccountKey { pair, rng })
+}
+
+/// PKCS#10 certificate request for `domain` (RFC 2986), signed by the PKCS#8 key
+fn certificate_request(domain: &str, pkcs8: &[u8]) -> Result<Vec<u8>> {
+    const EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+    const PRIME256V1: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+    const ECDSA_WITH_SHA256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+    const COMMON_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+    const EXTENSION_REQUEST: &[u8] = &[
+        0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e,
+    ];
+    const SUBJECT_ALT_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x11];
+
+    let rng = SystemRandom::new();
+    let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8, &rng)
+        .map_err(|_| anyhow::anyhow!("invalid certificate key"))?;
+
+    let subject = der(
+        0x30,
+        &der(
+            0x31,
+            &der(0x30, &[COMMON_NAME, &der(0x0c, domain.as_bytes())].concat()),
+        ),
+    );
+    let public_key = der(
+        0x30,
+        &[
+            der(0x30, &[EC_PUBLIC_KEY, PRIME256V1].concat()),
+            der(0x03, &[&[0][..], pair.public_key().as_ref()].concat()),
+        ]
+        .concat(),
+    );
+    let alt_names = der(0x30, &der(0x82, domain.as_bytes()));
+    let extensions = der(
+        0x30,
+        &der(0x30, &[SUBJECT_ALT_NAME, &der(0x04, &alt_names)].concat()),
+    );
+    let attributes = der(
+        0xa0,
+        &der(0x30, &[EXTENSION_REQUEST, &der(0x31, &extensions)].concat()),
+    );
+    let info = der(
+        0x30,
+        &[&[0x02, 0x01, 0x00][..], &subject, &public_key, &attributes].concat(),
+    );
+
+    let signature = pair
+        .sign(&rng, &info)
+        .map_err(|_| anyhow::anyhow!("failed to sign certificate request"))?;
+    Ok(der(
+        0x30,
+        &[
+            info,
+            der(0x30, ECDSA_WITH_SHA256),
+            der(0x03, &[&[0][..], signature.as_ref()].concat()),
+        ]
+        .concat(),
+    ))
+}
+
+/// DER element with the tag and content
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    match content.len() {
+        len @ 0..=0x7f => element.push(len as u8),
+        len => {
+            let bytes = (len as u32).to_be_bytes();
+            let skip = bytes.iter().take_while(|b| **b == 0).count();
+            element.push(0x80 | (4 - skip) as u8);
+            element.extend_from_slice(&bytes[skip..]);
+        }
+    }
+    element.extend_from_slice(content);
+    element
+}
+
+/// End of validity of the certificate (`notAfter`, RFC 5280 section 4.1.2.5)
+fn not_after(certificate: &[u8]) -> Option<SystemTime> {
+    let (_, mut fields) = der_element(tbs_certificate(certificate)?)?;
+    // version (optional, explicitly tagged [0]), serial number, signature, issuer
+    let skip = if fields.first() == Some(&0xa0) { 4 } else { 3 };
+    for _ in 0..skip {
+        let (length, _) = der_element(fields)?;
+        fields = fields.get(length..)?;
+    }
+    let (_, validity) = der_element(fields)?;
+    let (length, _) = der_element(validity)?;
+    let not_after = validity.get(length..)?;
+    let (_, time) = der_element(not_after)?;
+    let time = std::str::from_utf8(time).ok()?;
+
+    // UTCTime YYMMDDHHMMSSZ or GeneralizedTime YYYYMMDDHHMMSSZ
+    let (year, rest) = match not_after[0] {
+        0x17 => {
+            let year: u64 = time.get(..2)?.parse().ok()?;
+            (
+                if year < 50 { 2000 + year } else { 1900 + year },
+                time.get(2..)?,
+            )
+        }
+        0x18 => (time.get(..4)?.parse().ok()?, time.get(4..)?),
+        _ => return None,
+    };
+    let number = |range: std::ops::Range<usize>| rest.get(range)?.parse::<u64>().ok();
+    let days = days_from_civil(year, number(0..2)?, number(2..4)?);
+    let seconds = days * 86400 + number(4..6)? * 3600 + number(6..8)? * 60 + number(8..10)?;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Replaces the file at once, so its readers never see it half-written
+fn write_file(path: &Path, content: &[u8]) -> Result<()> {
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, content)
+        .and_then(|_| std::fs::rename(&temporary, path))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn json_array(value: &Value) -> impl Iterator<Item = &Value> {
+    value.as_array().into_iter().flatten()
+}
+
+/// One request over a new connection
+fn https_request(
+    client: &Arc<ClientConfig>,
+    method: &str,
+    url: &str,
+    body: Option<&[u8]>,
+) -> Result<HttpResponse> {
+    let rest = url
+        .strip_prefix("https://")
+        .with_context(|| format!("ACME URL {} is not https", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        // bare IPv6 address has colons too
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+            (host, port.parse().context("invalid port")?)
+        }
+        _ => (authority, 443),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let address = (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve {}", host))?
+        .next()
+        .with_context(|| format!("{} has no address", host))?;
+    let socket = TcpStream::connect_timeout(&address, Duration::from_secs(10))
+        .with_context(|| format!("Failed to connect to {}", authority))?;
+    socket.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let server_name = ServerName::try_from(host.to_string())?;
+    let mut stream = StreamOwned::new(ClientConnection::new(client.clone(), server_name)?, socket);
+
+    let body = body.unwrap_or_default();
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: dns-starter-rust\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        authority,
+        body.len()
+    );
+    if method == "POST" {
+        request.push_str("Content-Type: application/jose+json\r\n");
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    read_http_response(&mut stream).with_context(|| format!("{} {}", method, url))
+}
+
+/// Answers CA's requests for `/.well-known/acme-challenge/<token>`
+fn serve_challenges(listener: TcpListener, challenges: &Mutex<HashMap<String, String>>) {
+    for stream in listener.incoming() {
+        let result = stream
+            .map_err(anyhow::Error::from)
+            .and_then(|stream| answer_challenge(stream, challenges));
+        if let Err(e) = result {
+            eprintln!("ACME: error answering challenge request: {}", e);
+        }
+    }
+}
+
+fn answer_challenge(
+    mut stream: TcpStream,
+    challenges: &Mutex<HashMap<String, String>>,
+) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    let key_authorization = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", target, _version] => target
+            .strip_prefix("/.well-known/acme-challenge/")
+            .and_then(|token| {
+                challenges
+                    .lock()
+                    .expect("challenges lock poisoned")
+                    .get(token)
+                    .cloned()
+            }),
+        _ => None,
+    };
+    let (status, body) = match key_authorization {
+        Some(key_authorization) => ("200 OK", key_authorization),
+        None => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_certificate_request_and_validity() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let csr = certificate_request("dns.example", pkcs8.as_ref()).unwrap();
+        let (length, content) = der_element(&csr).unwrap();
+        assert_eq!(length, csr.len());
+        assert!(content.windows(11).any(|window| window == b"dns.example"));
+
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2024, 3, 1), 19783);
+        assert_eq!(base64::encode(b"dns"), "ZG5z");
+        assert_eq!(base64::encode_url(&[0xfb, 0xff]), "-_8");
+    }
+
+    #[test]
+    fn test_malformed_certificates_have_no_validity() {
+        assert_eq!(not_after(&[]), None);
+        assert_eq!(not_after(&[0x30, 0x05, 0x30, 0x03]), None);
+        assert_eq!(not_after(&[0x30, 0x85, 0, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn test_unknown_challenges_are_not_found() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let challenges = Mutex::new(HashMap::from([(
+            "token".to_string(),
+            "token.thumbprint".to_string(),
+        )]));
+
+        let answer = |request: &str| {
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(request.as_bytes()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            answer_challenge(stream, &challenges).unwrap();
+            read_http_response(&mut client).unwrap()
+        };
+
+        let found = answer("GET /.well-known/acme-challenge/token HTTP/1.1\r\n\r\n");
+        assert_eq!(
+            (found.status, &found.body[..]),
+            (200, &b"token.thumbprint"[..])
+        );
+        for request in [
+            "GET /.well-known/acme-challenge/other HTTP/1.1\r\n\r\n",
+            "GET /token HTTP/1.1\r\n\r\n",
+            "POST /.well-known/acme-challenge/token HTTP/1.1\r\n\r\n",
+            "garbage\r\n\r\n",
+        ] {
+            let response = answer(request);
+            assert_eq!(
+                (response.status, response.body.len()),
+                (404, 0),
+                "{}",
+                request
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_account_keys_are_rejected() {
+        let path =
+            std::env::temp_dir().join(format!("dns-test-{}-account.pem", std::process::id()));
+        std::fs::write(&path, base64::pem("RSA PRIVATE KEY", b"dns")).unwrap();
+        let error = load_or_create_key(&path).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!("{} is not a PKCS#8 key", path.display())
+        );
+
+        std::fs::write(&path, base64::pem("PRIVATE KEY", b"dns")).unwrap();
+        let error = load_or_create_key(&path).err().unwrap();
+        assert!(error.to_string().ends_with("is not an ECDSA P-256 key"));
+
+        std::fs::write(&path, "not a key").unwrap();
+        assert!(load_or_create_key(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
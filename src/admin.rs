@@ -0,0 +1,324 @@
+//! Admin HTTP endpoint for container orchestrators and load balancers
+//!
+//! `GET /health` and `GET /ready` answer `200 OK` when the server can answer queries: all of
+//! its listeners are bound and, if it forwards queries, at least one upstream is up.
+//! Otherwise they answer `503 Service Unavailable` with the reasons, one per line.
+//!
+//! Upstreams are probed periodically with a query for the root name servers; any response
+//! counts, even an error one, since the upstream is there to give it. Like addresses checked
+//! by [`crate::health`], an upstream is down after [`FALL`] failed probes in a row, and until
+//! its first probe succeeds.
+//!
+//! `GET /stats` answers with a snapshot of the server statistics and `GET /top` with the
+//! busiest clients and the most queried, blocked and bogus domains, see [`crate::stats`].
+//!
+//! `GET /zones/<origin>` answers with the zone as served right now in JSON, or in YAML with
+//! `?format=yaml`, see [`crate::export`] (requires the json feature).
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::domain_name::DomainName;
+#[cfg(feature = "json")]
+use crate::export::{self, ExportFormat};
+#[cfg(feature = "json")]
+use crate::handler::SharedZone;
+use crate::health::FALL;
+use crate::listen;
+use crate::packet::DnsPacket;
+use crate::question::{DnsQuestion, QueryClass, QueryType};
+use crate::record::RecordType;
+use crate::stats;
+use crate::upstream::Upstream;
+
+/// State of the listeners and upstreams of the server
+#[derive(Default)]
+pub struct Status {
+    /// Listener names and whether they are still up
+    listeners: Mutex<Vec<(String, bool)>>,
+    upstreams: Vec<(String, Arc<dyn Upstream>)>,
+    /// Consecutive failed probes of each upstream, in the order of `upstreams`
+    failures: Mutex<Vec<u32>>,
+    /// Zones served by the server, for export
+    #[cfg(feature = "json")]
+    zones: Vec<SharedZone>,
+}
+
+impl Status {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Probes `upstream`, called `name` in reports
+    pub fn upstream(mut self, name: impl ToString, upstream: Arc<dyn Upstream>) -> Self {
+        self.upstreams.push((name.to_string(), upstream));
+        self.failures
+            .get_mut()
+            .expect("status lock poisoned")
+            .push(FALL);
+        self
+    }
+
+    /// Exports `zone` at `/zones/<origin>`
+    #[cfg(feature = "json")]
+    pub fn zone(mut self, zone: SharedZone) -> Self {
+        self.zones.push(zone);
+        self
+    }
+
+    /// Probes all upstreams once, concurrently
+    pub fn check_upstreams(&self) {
+        let results: Vec<bool> = thread::scope(|scope| {
+            let probes: Vec<_> = self
+                .upstreams
+                .iter()
+                .map(|(name, upstream)| {
+                    scope.spawn(move || {
+                        let result = upstream.exchange(&probe_query());
+                        if let Err(e) = &result {
+                            eprintln!("Probe of upstream {} failed: {:#}", name, e);
+                        }
+                        result.is_ok()
+                    })
+                })
+                .collect();
+
+            probes
+                .into_iter()
+                .map(|probe| probe.join().expect("upstream probe thread panicked"))
+                .collect()
+        });
+
+        let mut failures = self.failures.lock().expect("status lock poisoned");
+        for (((name, _), count), healthy) in
+            self.upstreams.iter().zip(failures.iter_mut()).zip(results)
+        {
+            match (healthy, *count >= FALL) {
+                (true, true) => println!("Upstream {} is up", name),
+                (false, false) if *count + 1 == FALL => println!("Upstream {} is down", name),
+                _ => {}
+            }
+            *count = if healthy { 0 } else { *count + 1 };
+        }
+    }
+
+    /// Probes all upstreams every `interval` in a background thread
+    pub fn start(self: &Arc<Self>, interval: Duration) {
+        if self.upstreams.is_empty() {
+            return;
+        }
+        let status = self.clone();
+        thread::spawn(move || loop {
+            status.check_upstreams();
+            thread::sleep(interval);
+        });
+    }
+
+    /// Runs `serve` of the listener called `name` in a new thread, the listener is down
+    /// once it fails (e.g. its address can't be bound)
+    pub fn spawn_listener<F>(self: &Arc<Self>, name: &str, serve: F)
+    where
+        F: FnOnce() -> Result<()> + Send + 'static,
+    {
+        let index = {
+            let mut listeners = self.listeners.lock().expect("status lock poisoned");
+            listeners.push((name.to_string(), true));
+            listeners.len() - 1
+        };
+
+        let (status, name) = (self.clone(), name.to_string());
+        thread::spawn(move || {
+            if let Err(e) = serve() {
+                eprintln!("{} server failed: {:#}", name, e);
+                let mut listeners = status.listeners.lock().expect("status lock poisoned");
+                listeners[index].1 = false;
+            }
+        });
+    }
+
+    /// Reasons why the server can't answer queries, none if it is healthy
+    pub fn problems(&self) -> Vec<String> {
+        let listeners = self.listeners.lock().expect("status lock poisoned");
+        let mut problems: Vec<String> = listeners
+            .iter()
+            .filter(|(_, up)| !up)
+            .map(|(name, _)| format!("{} listener is down", name))
+            .collect();
+
+        let failures = self.failures.lock().expect("status lock poisoned");
+        if !self.upstreams.is_empty() && failures.iter().all(|count| *count >= FALL) {
+            problems.push("no upstream is up".to_string());
+        }
+        problems
+    }
+}
+
+/// Query for the root name servers, which every resolver answers
+fn probe_query() -> DnsPacket {
+    DnsPacket::builder()
+        .id(rand::random())
+        .recursion_desired(true)
+        .question(DnsQuestion::new(
+            DomainName::new(),
+            QueryType::from(u16::from(RecordType::NS)),
+            QueryClass::IN,
+        ))
+        .build()
+}
+
+/// Serves `/health`, `/ready`, `/stats`, `/top` and `/zones/<origin>` on `address`
+pub fn serve(address: &str, status: Arc<Status>) -> Result<()> {
+    let listener = listen::tcp(address)
+        .with_context(|| format!("Failed to bind admin listener to {}", address))?;
+
+    println!("Admin endpoint listening on {}", address);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Admin: error accepting connection: {}", e);
+                continue;
+            }
+        };
+
+        if let Err(e) = handle_connection(stream, &status) {
+            eprintln!("Admin: error handling request: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_connection(mut stream: TcpStream, status: &Status) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // skip headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let request: Vec<&str> = request_line.split_whitespace().collect();
+    #[cfg(feature = "json")]
+    if let ["GET", target, _version] = request[..] {
+        if target.starts_with("/zones/") {
+            return match zone_export(status, target) {
+                Some((format, body)) => {
+                    respond(&mut stream, "200 OK", format.content_type(), &body)
+                }
+                None => respond(&mut stream, "404 Not Found", "text/plain", ""),
+            };
+        }
+    }
+
+    let (status, body) = match request[..] {
+        ["GET", "/health" | "/ready", _version] => health(status),
+        ["GET", "/stats", _version] => ("200 OK", stats::report()),
+        ["GET", "/top", _version] => ("200 OK", stats::top_report()),
+        ["GET", _target, _version] => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    respond(&mut stream, status, "text/plain", &body)
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+/// Zone at `/zones/<origin>[?format=json|yaml]`, `None` if it isn't served or the format is unknown
+#[cfg(feature = "json")]
+fn zone_export(status: &Status, target: &str) -> Option<(ExportFormat, String)> {
+    let target = target.strip_prefix("/zones/")?;
+    let (origin, format) = match target.split_once("?format=") {
+        Some((origin, format)) => (origin, format.parse().ok()?),
+        None => (target, ExportFormat::Json),
+    };
+    let origin = DomainName::from(origin);
+    let zone = status.zones.iter().find(|zone| {
+        zone.read()
+            .expect("zone lock poisoned")
+            .origin()
+            .eq_ignore_case(&origin)
+    })?;
+    let zone = zone.read().expect("zone lock poisoned");
+    Some((format, export::export(&zone, format)))
+}
+
+fn health(status: &Status) -> (&'static str, String) {
+    let problems = status.problems();
+    if problems.is_empty() {
+        ("200 OK", "ok\n".to_string())
+    } else {
+        let body = problems
+            .iter()
+            .map(|problem| format!("{}\n", problem))
+            .collect();
+        ("503 Service Unavailable", body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FailingUpstream;
+
+    impl Upstream for FailingUpstream {
+        fn exchange(&self, _packet: &DnsPacket) -> Result<DnsPacket> {
+            anyhow::bail!("unreachable")
+        }
+    }
+
+    #[test]
+    fn test_problems_of_listeners_and_upstreams() {
+        let status = Arc::new(Status::new().upstream("failing", Arc::new(FailingUpstream)));
+        assert_eq!(status.problems(), ["no upstream is up"]);
+
+        status.spawn_listener("TCP", || anyhow::bail!("address in use"));
+        while status.problems().len() < 2 {
+            thread::yield_now();
+        }
+        assert_eq!(status.problems()[0], "TCP listener is down");
+
+        assert!(Status::new().problems().is_empty());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_zone_export() {
+        let zone = crate::zone::Zone::parse(
+            "$ORIGIN home.arpa.\n@ IN SOA ns1 hostmaster 1 7200 900 1209600 300\n",
+        )
+        .unwrap();
+        let status = Status::new().zone(Arc::new(std::sync::RwLock::new(zone)));
+
+        let (format, body) = zone_export(&status, "/zones/HOME.arpa").unwrap();
+        assert_eq!(format, ExportFormat::Json);
+        assert!(body.contains(r#""origin": "home.arpa.""#));
+        let (format, body) = zone_export(&status, "/zones/home.arpa.?format=yaml").unwrap();
+        assert_eq!(format, ExportFormat::Yaml);
+        assert!(body.starts_with("origin: \"home.arpa.\"\n"));
+
+        assert!(zone_export(&status, "/zones/example").is_none());
+        assert!(zone_export(&status, "/zones/home.arpa?format=xml").is_none());
+    }
+}
@@ -0,0 +1,87 @@
+//! Anonymization of client addresses in logs
+//!
+//! Deployments that must minimize retention of personal data can log only the network
+//! of the client (`/24` for IPv4, `/48` for IPv6) or a keyed hash of the address.
+//! Hash keys are random per process, so hashes can be correlated only within one run.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+/// Prefix lengths kept by [`Anonymizer::Truncate`]
+const IPV4_PREFIX: u32 = 24;
+const IPV6_PREFIX: u32 = 48;
+
+/// How client addresses appear in logs
+#[derive(Debug, Clone, Default)]
+pub enum Anonymizer {
+    /// Full address with port
+    #[default]
+    None,
+    /// Network of the client, e.g. `192.0.2.0/24`
+    Truncate,
+    /// Keyed hash of the address, e.g. `client-3f2a9c01b4d2e877`
+    Hash(RandomState),
+}
+
+impl Anonymizer {
+    /// Client address as it should be logged
+    pub fn client(&self, address: SocketAddr) -> String {
+        match self {
+            Self::None => address.to_string(),
+            _ => self.host(address.ip()),
+        }
+    }
+
+    /// Client IP address (without port) as it should be logged
+    pub fn host(&self, ip: IpAddr) -> String {
+        match self {
+            Self::None => ip.to_string(),
+            Self::Truncate => match ip {
+                IpAddr::V4(ip) => {
+                    let mask = u32::MAX << (32 - IPV4_PREFIX);
+                    let network = Ipv4Addr::from(u32::from(ip) & mask);
+                    format!("{}/{}", network, IPV4_PREFIX)
+                }
+                IpAddr::V6(ip) => {
+                    let mask = u128::MAX << (128 - IPV6_PREFIX);
+                    let network = Ipv6Addr::from(u128::from(ip) & mask);
+                    format!("{}/{}", network, IPV6_PREFIX)
+                }
+            },
+            Self::Hash(state) => format!("client-{:016x}", state.hash_one(ip)),
+        }
+    }
+}
+
+impl FromStr for Anonymizer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "truncate" => Ok(Self::Truncate),
+            "hash" => Ok(Self::Hash(RandomState::new())),
+            _ => anyhow::bail!(
+                "unknown anonymization {} (expected none, truncate or hash)",
+                s
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncated_client_addresses() {
+        let anonymizer = Anonymizer::Truncate;
+        let v4: SocketAddr = "192.0.2.123:5353".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8:1:2::1]:5353".parse().unwrap();
+
+        assert_eq!(anonymizer.client(v4), "192.0.2.0/24");
+        assert_eq!(anonymizer.client(v6), "2001:db8:1::/48");
+    }
+}
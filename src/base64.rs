@@ -0,0 +1,87 @@
+//! Base64 (RFC 4648) of secrets, stamps and the like
+
+/// Decodes base64 in either standard or URL-safe alphabet, padding is optional
+pub fn decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+/// Encodes in standard alphabet with padding, e.g. for PEM
+#[cfg(any(feature = "acme", feature = "dnssec"))]
+pub fn encode(bytes: &[u8]) -> String {
+    encode_with(
+        bytes,
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/",
+        true,
+    )
+}
+
+/// Encodes in URL-safe alphabet without padding, e.g. for JWS (RFC 7515)
+#[cfg(feature = "acme")]
+pub fn encode_url(bytes: &[u8]) -> String {
+    encode_with(
+        bytes,
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_",
+        false,
+    )
+}
+
+/// PEM document (RFC 7468) with the `der` bytes labelled `label`, e.g. `PRIVATE KEY`
+#[cfg(any(feature = "acme", feature = "dnssec"))]
+pub fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+/// Contents of the first PEM block labelled `label`
+#[cfg(feature = "dnssec")]
+pub fn from_pem(text: &str, label: &str) -> Option<Vec<u8>> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let (_, rest) = text.split_once(&begin)?;
+    let (body, _) = rest.split_once(&end)?;
+    decode(&body.split_whitespace().collect::<String>())
+}
+
+#[cfg(any(feature = "acme", feature = "dnssec"))]
+fn encode_with(bytes: &[u8], alphabet: &[u8; 64], padding: bool) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, b)| bits | (*b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            text.push(alphabet[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+        if padding {
+            text.push_str(&"=="[..3 - chunk.len()]);
+        }
+    }
+    text
+}
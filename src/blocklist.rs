@@ -0,0 +1,500 @@
+//! Blocklists of domains, downloaded and refreshed on a schedule
+//!
+//! Lists are given by HTTPS URLs (or paths of local files) and read again every refresh
+//! interval, [`REFRESH_INTERVAL`] by default. Downloads send the ETag of the version last
+//! received, so unchanged lists aren't transferred again. Once all lists are refreshed, the
+//! blocked set is swapped at once: queries see either the old or the new one. A list which
+//! fails to download keeps its previous version, and lists are read again after
+//! [`RETRY_INTERVAL`] instead of the refresh interval.
+//!
+//! Lists are hosts files, plain lists of domains or AdBlock rules, see [`Blocklist::parse`].
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::domain_name::DomainName;
+
+/// Time between downloads of the lists
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+/// Time before the next attempt after a list failed to download
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Largest list downloaded
+#[cfg(feature = "tls")]
+const MAX_LIST_SIZE: usize = 64 << 20;
+
+/// Names of a hosts file which aren't blocked domains
+const HOSTS_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+];
+
+/// Domains blocked by one list, lowercase without the trailing dot
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Blocklist {
+    /// Blocked without their subdomains
+    names: HashSet<String>,
+    /// Blocked together with their subdomains
+    domains: HashSet<String>,
+}
+
+impl Blocklist {
+    /// List in any of the formats public lists come in, even mixed together
+    ///
+    /// - hosts file, `0.0.0.0 ads.example.com`: the names only, without subdomains
+    /// - plain list of domains, `ads.example.com`: with subdomains
+    /// - AdBlock rules, `||ads.example.com^`: with subdomains, rules with other patterns,
+    ///   options or exceptions don't block domains and are skipped
+    ///
+    /// Lines commented out with `#` (`!` in AdBlock lists) and lines that aren't valid
+    /// are skipped.
+    pub fn parse(text: &str) -> Self {
+        let mut list = Self::default();
+        for line in text.lines() {
+            list.parse_line(line);
+        }
+        list
+    }
+
+    fn parse_line(&mut self, line: &str) {
+        let line = line.trim();
+        if line.starts_with('!') || line.starts_with('[') {
+            return; // AdBlock comment or header
+        }
+        if let Some(rule) = line.strip_prefix("||") {
+            if let Some(domain) = rule.strip_suffix('^').and_then(normalize) {
+                self.domains.insert(domain);
+            }
+            return;
+        }
+
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(first) = fields.next() else {
+            return;
+        };
+        if first.parse::<IpAddr>().is_ok() {
+            let names = fields
+                .filter(|name| !HOSTS_NAMES.contains(name) && name.parse::<IpAddr>().is_err())
+                .filter_map(normalize);
+            self.names.extend(names);
+        } else if fields.next().is_none() {
+            if let Some(domain) = normalize(first) {
+                self.domains.insert(domain);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len() + self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.domains.is_empty()
+    }
+
+    /// Returns true if `name` is on the list, or one of its parent domains with subdomains
+    pub fn contains(&self, name: &DomainName) -> bool {
+        let name = name.to_string().to_ascii_lowercase();
+        let mut name = name.trim_end_matches('.');
+        if self.names.contains(name) {
+            return true;
+        }
+        loop {
+            if self.domains.contains(name) {
+                return true;
+            }
+            match name.split_once('.') {
+                Some((_, parent)) => name = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Domain in lowercase without the trailing dot, `None` if it isn't a host name
+fn normalize(domain: &str) -> Option<String> {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let valid = !domain.is_empty()
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63)
+        && domain
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_' | b'.'));
+    valid.then_some(domain)
+}
+
+/// Domains exempt from blocking, for false positives of the lists
+///
+/// `example.com` exempts just the name, `*.example.com` the domain with its subdomains.
+/// Allowed names are never blocked, whatever the lists say.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Allowlist(Blocklist);
+
+impl Allowlist {
+    pub fn new<S: AsRef<str>>(patterns: impl IntoIterator<Item = S>) -> Result<Self> {
+        let mut list = Blocklist::default();
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            let (domain, subdomains) = match pattern.strip_prefix("*.") {
+                Some(domain) => (domain, true),
+                None => (pattern, false),
+            };
+            // internationalized domains are listed by their A-labels
+            let domain = DomainName::from_unicode(domain)
+                .ok()
+                .and_then(|domain| normalize(&domain.to_string()))
+                .with_context(|| format!("invalid allowed domain {:?}", pattern))?;
+            match subdomains {
+                true => list.domains.insert(domain),
+                false => list.names.insert(domain),
+            };
+        }
+        Ok(Self(list))
+    }
+
+    pub fn contains(&self, name: &DomainName) -> bool {
+        self.0.contains(name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Blocked set made of the lists read last
+#[derive(Debug, Default)]
+pub struct BlockedSet {
+    lists: Vec<Arc<Blocklist>>,
+}
+
+impl BlockedSet {
+    /// Returns true if `name` is blocked by any of the lists
+    pub fn contains(&self, name: &DomainName) -> bool {
+        self.lists.iter().any(|list| list.contains(name))
+    }
+
+    /// Number of domains on the lists (counted once for each list)
+    pub fn len(&self) -> usize {
+        self.lists.iter().map(|list| list.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Where a list is read from and its version read last
+struct Source {
+    location: String,
+    etag: Option<String>,
+    list: Option<Arc<Blocklist>>,
+}
+
+/// Lists of blocked domains, refreshed in a background thread
+pub struct Blocklists {
+    sources: Mutex<Vec<Source>>,
+    current: RwLock<Arc<BlockedSet>>,
+}
+
+impl Blocklists {
+    /// Lists at HTTPS URLs or local paths, nothing is blocked until they're [refreshed]
+    ///
+    /// [refreshed]: Blocklists::refresh
+    pub fn new(locations: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut sources = Vec::new();
+        for location in locations {
+            if location.starts_with("http://") {
+                anyhow::bail!("blocklist {} must be downloaded over https", location);
+            }
+            #[cfg(not(feature = "tls"))]
+            if location.starts_with("https://") {
+                anyhow::bail!(
+                    "blocklist {} requires the server to be built with the tls feature",
+                    location
+                );
+            }
+            sources.push(Source {
+                location,
+                etag: None,
+                list: None,
+            });
+        }
+        Ok(Self {
+            sources: Mutex::new(sources),
+            current: RwLock::new(Arc::default()),
+        })
+    }
+
+    /// Blocked set, it isn't changed by refreshes in the meantime
+    pub fn current(&self) -> Arc<BlockedSet> {
+        self.current
+            .read()
+            .expect("blocklist lock poisoned")
+            .clone()
+    }
+
+    /// Reads the lists again and swaps the blocked set, returns false if any list failed
+    pub fn refresh(&self) -> bool {
+        let mut sources = self.sources.lock().expect("blocklist lock poisoned");
+        let mut complete = true;
+        for source in sources.iter_mut() {
+            match fetch(&source.location, source.etag.as_deref()) {
+                Ok(Some((text, etag))) => {
+                    let list = Blocklist::parse(&text);
+                    println!("Blocklist: {} domains from {}", list.len(), source.location);
+                    source.list = Some(Arc::new(list));
+                    source.etag = etag;
+                }
+                Ok(None) => {} // not modified
+                Err(e) => {
+                    complete = false;
+                    let kept = match &source.list {
+                        Some(_) => "keeping its previous version",
+                        None => "nothing blocked by it yet",
+                    };
+                    eprintln!(
+                        "Blocklist: failed to read {}: {:#}, {}",
+                        source.location, e, kept
+                    );
+                }
+            }
+        }
+
+        let blocked = BlockedSet {
+            lists: sources
+                .iter()
+                .filter_map(|source| source.list.clone())
+                .collect(),
+        };
+        *self.current.write().expect("blocklist lock poisoned") = Arc::new(blocked);
+        complete
+    }
+
+    /// Refreshes the lists every `interval`, or after [`RETRY_INTERVAL`] when some failed
+    pub fn start(self: &Arc<Self>, interval: Duration, mut complete: bool) {
+        let blocklists = self.clone();
+        thread::spawn(move || loop {
+            let wait = if complete {
+                interval
+            } else {
+                interval.min(RETRY_INTERVAL)
+            };
+            thread::sleep(wait);
+            complete = blocklists.refresh();
+        });
+    }
+}
+
+impl fmt::Display for Blocklists {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sources = self.sources.lock().expect("blocklist lock poisoned");
+        let locations: Vec<&str> = sources
+            .iter()
+            .map(|source| source.location.as_str())
+            .collect();
+        write!(f, "{}", locations.join(", "))
+    }
+}
+
+/// Content and ETag of the list, `None` if it didn't change since the version with `etag`
+fn fetch(location: &str, etag: Option<&str>) -> Result<Option<(String, Option<String>)>> {
+    if location.starts_with("https://") {
+        #[cfg(feature = "tls")]
+        return download(location, etag);
+        #[cfg(not(feature = "tls"))]
+        anyhow::bail!("downloads require the tls feature");
+    }
+    let _ = etag;
+    let text = std::fs::read_to_string(location)
+        .with_context(|| format!("failed to read {}", location))?;
+    Ok(Some((text, None)))
+}
+
+/// Maximal number of redirects followed
+#[cfg(feature = "tls")]
+const MAX_REDIRECTS: usize = 5;
+
+#[cfg(feature = "tls")]
+fn download(url: &str, etag: Option<&str>) -> Result<Option<(String, Option<String>)>> {
+    use std::io::Write;
+    use std::net::{TcpStream, ToSocketAddrs};
+
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, StreamOwned};
+
+    use crate::tls::{read_http_response_limited, system_roots};
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let client = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .context("no usable TLS version")?
+        .with_root_certificates(system_roots()?)
+        .with_no_client_auth();
+    let client = Arc::new(client);
+
+    let mut url = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        let rest = url
+            .strip_prefix("https://")
+            .with_context(|| format!("blocklist URL {} is not https", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // bare IPv6 address has colons too
+            Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+                (host, port.parse().context("invalid port")?)
+            }
+            _ => (authority, 443),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let address = (host, port)
+            .to_socket_addrs()
+            .with_context(|| format!("failed to resolve {}", host))?
+            .next()
+            .with_context(|| format!("{} has no address", host))?;
+        let socket = TcpStream::connect_timeout(&address, Duration::from_secs(10))
+            .with_context(|| format!("failed to connect to {}", authority))?;
+        socket.set_read_timeout(Some(Duration::from_secs(30)))?;
+        let server_name = ServerName::try_from(host.to_string())?;
+        let mut stream =
+            StreamOwned::new(ClientConnection::new(client.clone(), server_name)?, socket);
+
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: dns-starter-rust\r\nConnection: close\r\n",
+            path, authority
+        );
+        if let Some(etag) = etag {
+            request.push_str(&format!("If-None-Match: {}\r\n", etag));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+        stream.flush()?;
+
+        let response = read_http_response_limited(&mut stream, MAX_LIST_SIZE)?;
+        match response.status {
+            200 => {
+                let etag = response.header("etag").map(str::to_string);
+                let text = String::from_utf8_lossy(&response.body).into_owned();
+                return Ok(Some((text, etag)));
+            }
+            304 => return Ok(None),
+            301 | 302 | 303 | 307 | 308 => {
+                url = response
+                    .header("location")
+                    .context("redirect without location")?
+                    .to_string();
+            }
+            status => anyhow::bail!("HTTP status {}", status),
+        }
+    }
+    anyhow::bail!("too many redirects")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domains_and_subdomains_are_blocked() {
+        let list = Blocklist::parse(
+            "# ads
+ads.example.com
+Tracker.Example.NET.  # with a comment
+not a domain
+
+",
+        );
+        assert_eq!(list.len(), 2);
+        assert!(list.contains(&DomainName::from("ads.example.com")));
+        assert!(list.contains(&DomainName::from("eu.ADS.example.com")));
+        assert!(list.contains(&DomainName::from("tracker.example.net")));
+        assert!(!list.contains(&DomainName::from("example.com")));
+        assert!(!list.contains(&DomainName::from("bads.example.com")));
+    }
+
+    #[test]
+    fn test_list_formats() {
+        let hosts = Blocklist::parse(
+            "127.0.0.1 localhost
+::1 localhost ip6-localhost
+0.0.0.0 0.0.0.0
+0.0.0.0 ads.example.com # banners
+0.0.0.0\tpixel.example.net tracker.example.net
+",
+        );
+        assert_eq!(hosts.len(), 3);
+        assert!(hosts.contains(&DomainName::from("pixel.example.net")));
+        assert!(hosts.contains(&DomainName::from("tracker.example.net")));
+        // hosts file lists names only
+        assert!(!hosts.contains(&DomainName::from("www.ads.example.com")));
+        assert!(!hosts.contains(&DomainName::from("localhost")));
+
+        let adblock = Blocklist::parse(
+            "[Adblock Plus 2.0]
+! Title: ads
+||ads.example.com^
+||tracker.example.net^$third-party
+@@||good.example.com^
+||*.example.org^
+/banner/*
+",
+        );
+        assert_eq!(adblock.len(), 1);
+        assert!(adblock.contains(&DomainName::from("www.ads.example.com")));
+        assert!(!adblock.contains(&DomainName::from("good.example.com")));
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let allowed = Allowlist::new(["good.example.com", "*.cdn.example.net"]).unwrap();
+        assert!(allowed.contains(&DomainName::from("Good.example.com")));
+        assert!(!allowed.contains(&DomainName::from("www.good.example.com")));
+        assert!(allowed.contains(&DomainName::from("cdn.example.net")));
+        assert!(allowed.contains(&DomainName::from("eu.cdn.example.net")));
+        assert!(Allowlist::new(["*"]).is_err());
+
+        let allowed = Allowlist::new(["*.bücher.example"]).unwrap();
+        let name = DomainName::from_unicode("shop.bücher.example").unwrap();
+        assert!(allowed.contains(&name));
+    }
+
+    #[test]
+    fn test_failed_refresh_keeps_previous_list() {
+        let path = std::env::temp_dir().join(format!("blocklist-{}.txt", std::process::id()));
+        std::fs::write(&path, "ads.example.com\n").unwrap();
+        let blocklists = Blocklists::new([path.display().to_string()]).unwrap();
+        let ads = DomainName::from("ads.example.com");
+        assert!(!blocklists.current().contains(&ads));
+
+        assert!(blocklists.refresh());
+        let blocked = blocklists.current();
+        assert!(blocked.contains(&ads));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!blocklists.refresh());
+        assert!(blocklists.current().contains(&ads));
+
+        assert!(Blocklists::new(["http://example.com/list".to_string()]).is_err());
+    }
+}
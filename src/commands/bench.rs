@@ -0,0 +1,254 @@
+//! Load generator: `bench [@server] [--qps N] [--duration SECS] [--concurrency N] [--queries FILE]`
+//!
+//! Query list file contains one query per line: `<name> [type]`, empty lines and lines starting with `#` are skipped.
+
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use dns_starter_rust::domain_name::DomainName;
+use dns_starter_rust::header::ResponseCode;
+use dns_starter_rust::packet::{BytesPacket, DnsPacket};
+use dns_starter_rust::question::{DnsQuestion, QueryClass, QueryType};
+
+const DEFAULT_SERVER: &str = "127.0.0.1:2053";
+
+/// Results of a single worker thread
+#[derive(Default)]
+struct Stats {
+    sent: u64,
+    latencies: Vec<Duration>,
+    timeouts: u64,
+    errors: u64, // send errors, mismatched or unparsable responses
+    failed_rcode: u64,
+}
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut server = DEFAULT_SERVER.to_string();
+    let mut qps: u64 = 100;
+    let mut duration = Duration::from_secs(10);
+    let mut concurrency: u64 = 4;
+    let mut queries_file = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .with_context(|| format!("missing {} value", name))
+        };
+        match arg.as_str() {
+            "--qps" => qps = value("--qps")?.parse()?,
+            "--duration" => duration = Duration::from_secs(value("--duration")?.parse()?),
+            "--concurrency" => concurrency = value("--concurrency")?.parse()?,
+            "--queries" => queries_file = Some(value("--queries")?.clone()),
+            arg => match arg.strip_prefix('@') {
+                Some(address) => server = address.to_string(),
+                None => anyhow::bail!("unknown bench argument {}", arg),
+            },
+        }
+    }
+
+    let concurrency = concurrency.clamp(1, qps.max(1));
+    let questions = Arc::new(match queries_file {
+        Some(path) => read_queries(&path)?,
+        None => vec![DnsQuestion::new(
+            DomainName::from("codecrafters.io"),
+            QueryType::A,
+            QueryClass::IN,
+        )],
+    });
+
+    println!(
+        ";; Benchmarking {} with {} qps for {}s ({} workers, {} distinct queries)",
+        server,
+        qps,
+        duration.as_secs(),
+        concurrency,
+        questions.len()
+    );
+
+    // every worker sends its share of queries per second
+    let interval = Duration::from_secs_f64(concurrency as f64 / qps.max(1) as f64);
+    let start = Instant::now();
+
+    let workers: Vec<_> = (0..concurrency)
+        .map(|worker| {
+            let server = server.clone();
+            let questions = questions.clone();
+            thread::spawn(move || {
+                worker_loop(&server, &questions, worker as usize, interval, duration)
+            })
+        })
+        .collect();
+
+    let mut total = Stats::default();
+    for worker in workers {
+        let stats = worker.join().expect("bench worker panicked")?;
+        total.sent += stats.sent;
+        total.latencies.extend(stats.latencies);
+        total.timeouts += stats.timeouts;
+        total.errors += stats.errors;
+        total.failed_rcode += stats.failed_rcode;
+    }
+    let elapsed = start.elapsed();
+
+    report(&mut total, elapsed);
+
+    Ok(())
+}
+
+fn worker_loop(
+    server: &str,
+    questions: &[DnsQuestion],
+    worker: usize,
+    interval: Duration,
+    duration: Duration,
+) -> Result<Stats> {
+    let socket = UdpSocket::bind("0.0.0.0:0").context("Failed to bind bench socket")?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(Duration::from_secs(2)))?;
+
+    let mut stats = Stats::default();
+    let mut buf = [0; 512];
+    let start = Instant::now();
+    let mut next_send = start;
+    let mut i = worker;
+
+    while start.elapsed() < duration {
+        let now = Instant::now();
+        if now < next_send {
+            thread::sleep(next_send - now);
+        }
+        next_send += interval;
+
+        let id: u16 = rand::random();
+        let query = DnsPacket::builder()
+            .id(id)
+            .recursion_desired(true)
+            .question(questions[i % questions.len()].clone())
+            .build();
+        i += 1;
+
+        let bytes_packet = BytesPacket::from(query);
+        let sent_at = Instant::now();
+        stats.sent += 1;
+
+        if socket.send(&bytes_packet.buf).is_err() {
+            stats.errors += 1;
+            continue;
+        }
+
+        loop {
+            match socket.recv(&mut buf) {
+                Ok(size) if size >= 4 => {
+                    if u16::from_be_bytes([buf[0], buf[1]]) != id {
+                        continue; // late response to an earlier (timed out) query
+                    }
+
+                    stats.latencies.push(sent_at.elapsed());
+                    if ResponseCode::from(buf[3] & 0x0F) != ResponseCode::NOERROR {
+                        stats.failed_rcode += 1;
+                    }
+                }
+                Ok(_) => stats.errors += 1,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    stats.timeouts += 1
+                }
+                Err(_) => stats.errors += 1,
+            }
+            break;
+        }
+    }
+
+    Ok(stats)
+}
+
+fn report(stats: &mut Stats, elapsed: Duration) {
+    stats.latencies.sort();
+
+    let answered = stats.latencies.len() as u64;
+    let percentile = |p: f64| -> String {
+        if stats.latencies.is_empty() {
+            return "-".to_string();
+        }
+        let i = ((stats.latencies.len() - 1) as f64 * p).round() as usize;
+        format!("{:.2} ms", stats.latencies[i].as_secs_f64() * 1000.0)
+    };
+    let rate = |n: u64| -> f64 {
+        if stats.sent == 0 {
+            0.0
+        } else {
+            n as f64 * 100.0 / stats.sent as f64
+        }
+    };
+
+    println!(";; Queries sent:      {}", stats.sent);
+    println!(
+        ";; Queries answered:  {} ({:.2}%)",
+        answered,
+        rate(answered)
+    );
+    println!(
+        ";; Timeouts:          {} ({:.2}%)",
+        stats.timeouts,
+        rate(stats.timeouts)
+    );
+    println!(
+        ";; Errors:            {} ({:.2}%)",
+        stats.errors,
+        rate(stats.errors)
+    );
+    println!(
+        ";; Non-NOERROR:       {} ({:.2}%)",
+        stats.failed_rcode,
+        rate(stats.failed_rcode)
+    );
+    println!(
+        ";; Throughput:        {:.1} answers/s",
+        answered as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        ";; Latency:           p50 {}, p90 {}, p99 {}, max {}",
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(1.0)
+    );
+}
+
+fn read_queries(path: &str) -> Result<Vec<DnsQuestion>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+
+    let mut questions = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.split_whitespace();
+        let name = parts.next().expect("line is not empty");
+        let query_type = match parts.next() {
+            Some(query_type) => query_type.parse()?,
+            None => QueryType::A,
+        };
+
+        questions.push(DnsQuestion::new(
+            DomainName::from(name),
+            query_type,
+            QueryClass::IN,
+        ));
+    }
+
+    if questions.is_empty() {
+        anyhow::bail!("no queries in {}", path);
+    }
+
+    Ok(questions)
+}
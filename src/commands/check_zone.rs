@@ -0,0 +1,49 @@
+//! Zone lint: `check-zone <file> [--previous <file>]`
+//!
+//! Prints problems found in the zone (see [`lint`]) and fails when some of them are errors,
+//! so it can guard zone repositories in CI. With `--previous` (e.g. the deployed version)
+//! it also checks that the serial was increased if the zone changed.
+
+use anyhow::{Context, Result};
+
+use dns_starter_rust::lint::{self, Severity};
+use dns_starter_rust::zone::Zone;
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut file = None;
+    let mut previous = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--previous" => previous = Some(args.next().context("missing --previous value")?),
+            arg if file.is_none() => file = Some(arg),
+            arg => anyhow::bail!("unknown check-zone argument {}", arg),
+        }
+    }
+
+    let file = file.context("usage: check-zone <file> [--previous <file>]")?;
+    let zone = Zone::from_file(file)?;
+    let previous = previous.map(Zone::from_file).transpose()?;
+
+    let problems = lint::check(&zone, previous.as_ref());
+    for problem in &problems {
+        println!("{}: {}", file, problem);
+    }
+    let errors = problems
+        .iter()
+        .filter(|problem| problem.severity == Severity::Error)
+        .count();
+    println!(
+        ";; {}: {} records, {} errors, {} warnings",
+        zone.origin(),
+        zone.records().len(),
+        errors,
+        problems.len() - errors
+    );
+
+    if errors > 0 {
+        anyhow::bail!("zone {} has errors", zone.origin());
+    }
+    Ok(())
+}
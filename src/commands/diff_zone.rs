@@ -0,0 +1,87 @@
+//! Zone diff: `diff-zone <old> <new>`
+//!
+//! Prints RRsets added, removed and changed between two zone files, the same changes the
+//! server keeps in the zone's IXFR journal (see [`dns_starter_rust::journal`]). Either side can be
+//! `@server` instead of a file, to compare with the zone loaded by a running server; it's
+//! transferred with AXFR, so the server must allow transfers of the zone to this host.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use dns_starter_rust::domain_name::DomainName;
+use dns_starter_rust::header::ResponseCode;
+use dns_starter_rust::journal::ZoneDiff;
+use dns_starter_rust::packet::DnsPacket;
+use dns_starter_rust::question::{DnsQuestion, QueryClass, QueryType};
+use dns_starter_rust::record::RecordType;
+use dns_starter_rust::upstream::{TcpUpstream, Upstream};
+use dns_starter_rust::zone::Zone;
+
+use super::query::with_default_port;
+
+/// QTYPE of full zone transfer (RFC 5936)
+const AXFR: u16 = 252;
+
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn run(args: &[String]) -> Result<()> {
+    let [old, new] = args else {
+        anyhow::bail!("usage: diff-zone <old> <new>, either can be @server");
+    };
+
+    let (old, new) = match (old.strip_prefix('@'), new.strip_prefix('@')) {
+        (Some(_), Some(_)) => anyhow::bail!("diff-zone: at least one zone must be a file"),
+        (Some(server), None) => {
+            let new = Zone::from_file(new)?;
+            (transfer(server, new.origin())?, new)
+        }
+        (None, Some(server)) => {
+            let old = Zone::from_file(old)?;
+            let new = transfer(server, old.origin())?;
+            (old, new)
+        }
+        (None, None) => (Zone::from_file(old)?, Zone::from_file(new)?),
+    };
+
+    let diff = ZoneDiff::between(&old, &new)?;
+    print!("{}", diff);
+    if diff.is_empty() {
+        println!(";; no changes");
+    } else if diff.new_serial().wrapping_sub(diff.old_serial()) as i32 <= 0 {
+        println!(";; serial didn't increase, secondaries won't transfer the changes");
+    }
+    Ok(())
+}
+
+/// Zone `origin` as served by `server`
+pub fn transfer(server: &str, origin: &DomainName) -> Result<Zone> {
+    let server = with_default_port(server);
+    let query = DnsPacket::builder()
+        .id(rand::random())
+        .question(DnsQuestion::new(
+            origin.clone(),
+            QueryType::from(AXFR),
+            QueryClass::IN,
+        ))
+        .build();
+    let response = TcpUpstream::new(server.as_str())
+        .with_timeout(TRANSFER_TIMEOUT)
+        .exchange(&query)
+        .with_context(|| format!("Failed to transfer zone {} from {}", origin, server))?;
+    if response.header.rescode != ResponseCode::NOERROR {
+        anyhow::bail!(
+            "transfer of zone {} from {} failed with {:?}",
+            origin,
+            server,
+            response.header.rescode
+        );
+    }
+
+    // transfer ends with the SOA again
+    let mut records = response.answers;
+    if records.len() > 1 && records.last().map(|r| &r.record_type) == Some(&RecordType::SOA) {
+        records.pop();
+    }
+    Zone::new(origin.clone(), records)
+}
@@ -0,0 +1,38 @@
+//! Zone export: `export-zone <file> [--format json|yaml]` or `export-zone @server <origin> [--format json|yaml]`
+//!
+//! Prints the zone as a JSON (default) or YAML document, see [`dns_starter_rust::export`].
+//! A zone loaded by a running server is transferred with AXFR, its admin endpoint serves
+//! the same documents at `/zones/<origin>`.
+
+use anyhow::{Context, Result};
+
+use dns_starter_rust::domain_name::DomainName;
+use dns_starter_rust::export::{self, ExportFormat};
+use dns_starter_rust::zone::Zone;
+
+use super::diff_zone::transfer;
+
+const USAGE: &str = "usage: export-zone <file> | @server <origin> [--format json|yaml]";
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut positional = Vec::new();
+    let mut format = ExportFormat::Json;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = args.next().context("missing --format value")?.parse()?,
+            arg => positional.push(arg),
+        }
+    }
+
+    let zone = match positional[..] {
+        [server, origin] if server.starts_with('@') => {
+            transfer(&server[1..], &DomainName::from(origin))?
+        }
+        [file] if !file.starts_with('@') => Zone::from_file(file)?,
+        _ => anyhow::bail!(USAGE),
+    };
+    print!("{}", export::export(&zone, format));
+    Ok(())
+}
@@ -0,0 +1,11 @@
+//! Subcommands of the binary, the server itself runs when no subcommand is given
+
+pub mod bench;
+pub mod check_zone;
+pub mod diff_zone;
+#[cfg(feature = "json")]
+pub mod export_zone;
+pub mod query;
+pub mod replay;
+#[cfg(feature = "dnssec")]
+pub mod trace;
@@ -0,0 +1,66 @@
+//! dig-like query: `query <name> [type] [@server]`
+
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+
+use dns_starter_rust::domain_name::DomainName;
+use dns_starter_rust::packet::DnsPacket;
+use dns_starter_rust::question::{DnsQuestion, QueryClass, QueryType};
+use dns_starter_rust::resolver::Resolver;
+
+/// Queries this server by default
+const DEFAULT_SERVER: &str = "127.0.0.1:2053";
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut name = None;
+    let mut query_type = QueryType::A;
+    let mut server = DEFAULT_SERVER.to_string();
+
+    for arg in args {
+        if let Some(address) = arg.strip_prefix('@') {
+            server = with_default_port(address);
+        } else if name.is_none() {
+            name = Some(arg.as_str());
+        } else {
+            query_type = arg.parse()?;
+        }
+    }
+
+    let name = name.context("usage: query <name> [type] [@server]")?;
+
+    let query = DnsPacket::builder()
+        .id(rand::random())
+        .recursion_desired(true)
+        .question(DnsQuestion::new(
+            DomainName::from(name),
+            query_type,
+            QueryClass::IN,
+        ))
+        .build();
+
+    let start = Instant::now();
+    let response = Resolver::new(server.as_str()).query(&query)?;
+    let elapsed = start.elapsed();
+
+    println!("{}", response);
+    println!();
+    println!(";; Query time: {} msec", elapsed.as_millis());
+    println!(";; SERVER: {}", server);
+
+    Ok(())
+}
+
+/// Appends DNS port to the address if it is missing
+pub fn with_default_port(address: &str) -> String {
+    if address.parse::<std::net::SocketAddr>().is_ok() {
+        return address.to_string();
+    }
+
+    match address.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V6(ip)) => format!("[{}]:53", ip),
+        Ok(ip) => format!("{}:53", ip),
+        Err(_) if address.contains(':') => address.to_string(),
+        Err(_) => format!("{}:53", address),
+    }
+}
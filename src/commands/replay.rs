@@ -0,0 +1,251 @@
+//! Replays recorded queries: `replay <file> [@server] [--speed N]`
+//!
+//! The file is either a pcap capture (queries are taken from UDP payloads with QR=0,
+//! e.g. a capture made with `--pcap`) or a query log with one query per line:
+//! `[timestamp] <name> [type]`, where timestamp is in seconds (fractions allowed).
+//! Lines without timestamp are sent back to back.
+//!
+//! `--speed` scales the original pacing, `--speed 10` replays ten times faster,
+//! `--speed 0` sends everything as fast as possible.
+
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use dns_starter_rust::domain_name::DomainName;
+use dns_starter_rust::header::ResponseCode;
+use dns_starter_rust::packet::{BytesPacket, DnsPacket};
+use dns_starter_rust::pcap::PcapReader;
+use dns_starter_rust::question::{DnsQuestion, QueryClass, QueryType};
+
+const DEFAULT_SERVER: &str = "127.0.0.1:2053";
+
+/// How long to wait for late responses after the last query was sent
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Query with its offset from the start of the recording
+struct RecordedQuery {
+    offset: Duration,
+    message: Vec<u8>,
+}
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut file = None;
+    let mut server = DEFAULT_SERVER.to_string();
+    let mut speed: f64 = 1.0;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--speed" => speed = args.next().context("missing --speed value")?.parse()?,
+            arg => match arg.strip_prefix('@') {
+                Some(address) => server = address.to_string(),
+                None if file.is_none() => file = Some(arg.to_string()),
+                None => anyhow::bail!("unknown replay argument {}", arg),
+            },
+        }
+    }
+
+    let file = file.context("usage: replay <file> [@server] [--speed N]")?;
+    let queries = read_queries(&file)?;
+    if queries.is_empty() {
+        anyhow::bail!("no queries in {}", file);
+    }
+
+    println!(
+        ";; Replaying {} queries from {} to {} (speed {})",
+        queries.len(),
+        file,
+        server,
+        speed
+    );
+
+    let socket = UdpSocket::bind("0.0.0.0:0").context("Failed to bind replay socket")?;
+    socket.connect(&server)?;
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+    // send time of every query, indexed by the (rewritten) message ID
+    let sent_at = Arc::new(Mutex::new(vec![None; queries.len().min(1 << 16)]));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let receiver = {
+        let socket = socket.try_clone()?;
+        let sent_at = sent_at.clone();
+        let done = done.clone();
+        thread::spawn(move || receive_loop(&socket, &sent_at, &done))
+    };
+
+    let start = Instant::now();
+    let mut errors = 0;
+    for (i, query) in queries.iter().enumerate() {
+        if speed > 0.0 {
+            let due = start + query.offset.div_f64(speed);
+            let now = Instant::now();
+            if now < due {
+                thread::sleep(due - now);
+            }
+        }
+
+        // IDs are rewritten to match responses to queries, recorded IDs may repeat
+        let id = (i % (1 << 16)) as u16;
+        let mut message = query.message.clone();
+        message[..2].copy_from_slice(&id.to_be_bytes());
+
+        sent_at.lock().expect("replay lock poisoned")[id as usize] = Some(Instant::now());
+        if socket.send(&message).is_err() {
+            errors += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+
+    thread::sleep(DRAIN_TIMEOUT);
+    done.store(true, Ordering::Relaxed);
+    let (latencies, rcodes) = receiver.join().expect("replay receiver panicked");
+
+    let sent = queries.len();
+    println!(
+        ";; Queries sent:      {} in {:.2}s",
+        sent,
+        elapsed.as_secs_f64()
+    );
+    println!(";; Send errors:       {}", errors);
+    println!(
+        ";; Responses:         {} ({:.2}%)",
+        latencies.len(),
+        latencies.len() as f64 * 100.0 / sent as f64
+    );
+    for (rcode, count) in rcodes.iter().enumerate().filter(|(_, &count)| count > 0) {
+        let name = match rcode {
+            0..=5 => format!("{:?}", ResponseCode::from(rcode as u8)),
+            rcode => format!("RCODE{}", rcode),
+        };
+        println!(";;   {:<17} {}", format!("{}:", name), count);
+    }
+    if !latencies.is_empty() {
+        let average = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+        println!(
+            ";; Average latency:   {:.2} ms",
+            average.as_secs_f64() * 1000.0
+        );
+    }
+
+    Ok(())
+}
+
+/// Collects responses until `done` is set, returns latencies and counts of response codes
+fn receive_loop(
+    socket: &UdpSocket,
+    sent_at: &Mutex<Vec<Option<Instant>>>,
+    done: &AtomicBool,
+) -> (Vec<Duration>, [u64; 16]) {
+    let mut latencies = Vec::new();
+    let mut rcodes = [0; 16];
+    let mut buf = [0; 512];
+
+    while !done.load(Ordering::Relaxed) {
+        let Ok(size) = socket.recv(&mut buf) else {
+            continue; // timeout, check whether we are done
+        };
+        if size < 4 {
+            continue;
+        }
+
+        let id = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+        let sent = sent_at
+            .lock()
+            .expect("replay lock poisoned")
+            .get_mut(id)
+            .and_then(Option::take);
+        if let Some(sent) = sent {
+            latencies.push(sent.elapsed());
+            rcodes[(buf[3] & 0x0F) as usize] += 1;
+        }
+    }
+
+    (latencies, rcodes)
+}
+
+fn read_queries(path: &str) -> Result<Vec<RecordedQuery>> {
+    let bytes = std::fs::read(path).with_context(|| format!("Failed to read {}", path))?;
+
+    match PcapReader::from_bytes(bytes.clone()) {
+        Ok(reader) => Ok(read_pcap(reader)),
+        Err(_) => read_log(&String::from_utf8(bytes).context("neither pcap nor query log")?),
+    }
+}
+
+fn read_pcap(reader: PcapReader) -> Vec<RecordedQuery> {
+    let mut queries = Vec::new();
+    let mut first = None;
+
+    for record in reader {
+        let Some(udp) = record.udp() else {
+            continue;
+        };
+        // only queries (QR=0) with at least a header
+        if udp.payload.len() < 12 || udp.payload[2] & 0x80 != 0 {
+            continue;
+        }
+
+        let first = *first.get_or_insert(record.timestamp);
+        queries.push(RecordedQuery {
+            offset: record.timestamp.saturating_sub(first),
+            message: udp.payload.to_vec(),
+        });
+    }
+
+    queries
+}
+
+fn read_log(content: &str) -> Result<Vec<RecordedQuery>> {
+    let mut queries = Vec::new();
+    let mut first = None;
+
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts: Vec<&str> = line.split_whitespace().collect();
+        let timestamp = match parts.first().map(|part| part.parse::<f64>()) {
+            Some(Ok(timestamp)) if parts.len() > 1 => {
+                parts.remove(0);
+                Some(Duration::try_from_secs_f64(timestamp)?)
+            }
+            _ => None,
+        };
+
+        let name = parts[0];
+        let query_type = match parts.get(1) {
+            Some(query_type) => query_type.parse()?,
+            None => QueryType::A,
+        };
+
+        let offset = match timestamp {
+            Some(timestamp) => timestamp.saturating_sub(*first.get_or_insert(timestamp)),
+            None => queries
+                .last()
+                .map(|query: &RecordedQuery| query.offset)
+                .unwrap_or_default(),
+        };
+
+        let query = DnsPacket::builder()
+            .recursion_desired(true)
+            .question(DnsQuestion::new(
+                DomainName::from(name),
+                query_type,
+                QueryClass::IN,
+            ))
+            .build();
+        queries.push(RecordedQuery {
+            offset,
+            message: BytesPacket::from(query).buf.to_vec(),
+        });
+    }
+
+    Ok(queries)
+}
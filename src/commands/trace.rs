@@ -0,0 +1,71 @@
+//! delv-like chain of trust: `trace <name> [type] [@server] [--trust-anchor <file>]`
+//!
+//! Asks the server for the records with DO and CD set and validates them with the same
+//! validator the server uses for `--dnssec-validation`, printing every delegation, key set,
+//! signature and denial of existence it checks on the way from the trust anchor.
+//! Exits with an error when the answer is bogus.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+
+use dns_starter_rust::domain_name::DomainName;
+use dns_starter_rust::question::{DnsQuestion, QueryClass, QueryType};
+use dns_starter_rust::record::RecordType;
+use dns_starter_rust::resolver::Resolver;
+use dns_starter_rust::upstream::Upstream;
+use dns_starter_rust::validator::{self, Security, Validator};
+
+use super::query::with_default_port;
+
+const DEFAULT_SERVER: &str = "127.0.0.1:2053";
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut name = None;
+    let mut record_type = RecordType::A;
+    let mut server = DEFAULT_SERVER.to_string();
+    let mut trust_anchor_path = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trust-anchor" => {
+                trust_anchor_path = Some(args.next().context("missing --trust-anchor value")?)
+            }
+            arg => match arg.strip_prefix('@') {
+                Some(address) => server = with_default_port(address),
+                None if name.is_none() => name = Some(arg),
+                None => record_type = arg.parse()?,
+            },
+        }
+    }
+
+    let name = name.context("usage: trace <name> [type] [@server] [--trust-anchor <file>]")?;
+
+    let upstream: Arc<dyn Upstream> = Arc::new(Resolver::new(server.as_str()));
+    let mut validator = Validator::new(upstream).observe(|step| println!(";; {}", step));
+    if let Some(path) = trust_anchor_path {
+        validator = validator.trust_anchors(validator::read_trust_anchors(path)?)?;
+    }
+
+    let name = DomainName::from(name);
+    let response = validator
+        .query(&name, record_type.clone())
+        .with_context(|| format!("Failed to query {}", server))?;
+    println!("{}", response);
+    println!();
+
+    let question = DnsQuestion::new(
+        name,
+        QueryType::from(u16::from(record_type.clone())),
+        QueryClass::IN,
+    );
+    let security = validator.validate(&question, &response);
+    println!(";; result: {}", security);
+    println!(";; SERVER: {}", server);
+
+    if let Security::Bogus(_) = security {
+        anyhow::bail!("{} {} is bogus", question.domain_name, record_type);
+    }
+    Ok(())
+}
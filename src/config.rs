@@ -0,0 +1,602 @@
+//! Options of the server, parsed from its command line
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use dns_starter_rust::{
+    anonymize::Anonymizer,
+    blocklist,
+    connections::ConnectionLimits,
+    domain_name::DomainName,
+    edns,
+    handler::{HairpinRule, MOZILLA_CANARY},
+    health::Probe,
+    listen::SocketBuffers,
+    log::LogFormat,
+    network::Network,
+    overload::Overload,
+    packet::MIN_UDP_SIZE,
+    register::RegistrationKey,
+    syslog::Facility,
+    tsig::TsigKey,
+    upstream::{parse_spki_pin, TlsOptions},
+    zone::TransferRule,
+};
+
+/// Options of the server, each field is set by the option of (about) the same name
+pub struct Config {
+    pub resolver_address: String,
+    pub resolv_conf_path: String,
+    pub bootstrap: Vec<IpAddr>,
+    pub tls_options: TlsOptions,
+    pub json_api_address: String,
+    pub script_path: String,
+    pub pcap_path: String,
+    pub tcp_address: String,
+    pub interface: Option<String>,
+    pub dot_address: String,
+    pub https_address: String,
+    pub tls_cert_path: String,
+    pub tls_key_path: String,
+    pub acme_domain: String,
+    pub acme_email: String,
+    pub acme_directory: String,
+    pub acme_http_address: String,
+    pub tsig_keys: Vec<TsigKey>,
+    pub zone_paths: Vec<String>,
+    pub transfer_rules: Vec<(DomainName, TransferRule)>,
+    pub dnssec_keys: Vec<(DomainName, String)>,
+    pub publish_zonemd: bool,
+    pub publish_cds: bool,
+    pub zsk_rollovers: Vec<(DomainName, String)>,
+    pub zsk_lifetime: Option<Duration>,
+    pub key_sets: Vec<(DomainName, String)>,
+    pub dnssec_validation: String,
+    pub trust_anchor_path: String,
+    pub health_checks: Vec<(DomainName, Probe)>,
+    pub health_interval: Duration,
+    pub leases_path: String,
+    pub lease_domain: DomainName,
+    pub register_address: String,
+    pub register_keys: Vec<RegistrationKey>,
+    pub min_ttl: Option<u32>,
+    pub max_ttl: Option<u32>,
+    pub cache_size: usize,
+    pub cache_redis: String,
+    pub cache_peers: Vec<String>,
+    pub client_routes: Vec<(Vec<Network>, String)>,
+    pub cache_replication: String,
+    pub wire_cache: bool,
+    pub max_stale: Option<Duration>,
+    pub strip_ecs: bool,
+    pub minimal_responses: bool,
+    pub drop_unknown_edns: bool,
+    pub anonymizer: Anonymizer,
+    pub rebind_protection: bool,
+    pub rebind_allowed: Vec<DomainName>,
+    pub max_udp_size: u16,
+    pub upstream_timeout: Duration,
+    pub nxdomain_redirect: Option<Ipv4Addr>,
+    pub hairpin_rules: Vec<HairpinRule>,
+    pub hairpin_clients: Vec<Network>,
+    pub nxdomain_suffixes: Vec<DomainName>,
+    pub unix_stream_path: String,
+    pub unix_datagram_path: String,
+    pub proxy_protocol: bool,
+    pub dump_packets: bool,
+    pub admin_address: String,
+    pub otlp_endpoint: String,
+    pub log_format: LogFormat,
+    pub syslog_destination: String,
+    pub syslog_facility: Option<Facility>,
+    pub canaries: Vec<DomainName>,
+    pub blocklist_sources: Vec<String>,
+    pub mdns_interfaces: Vec<String>,
+    pub mdns_rate: Option<u32>,
+    pub blocklist_allowed: Vec<String>,
+    pub blocklist_refresh: Duration,
+    pub connection_limits: ConnectionLimits,
+    pub sample_path: String,
+    pub sample_rate: Option<u64>,
+    pub sample_max_size: Option<u64>,
+    pub strict_listeners: Vec<String>,
+    pub max_in_flight: Option<usize>,
+    pub overload: Option<Overload>,
+    pub socket_buffers: SocketBuffers,
+}
+
+impl Config {
+    /// Parses the options (arguments after the subcommand, if any), unknown ones are rejected
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        // ARGS: --resolver <address|tls://host|https://host/path|sdns://stamp> --bootstrap <ip>
+        //       --privacy <strict|opportunistic> --spki-pin sha256/<base64> --spki-pin-only
+        //       --resolv-conf <file> --json-api <address> --script <file.lua> --pcap <file> --hexdump
+        //       --min-ttl <seconds> --max-ttl <seconds> --cache-size <entries> --cache-redis <url> --strip-ecs
+        //       --cache-peer <address> --cache-replication <address> --wire-cache
+        //       --route <network>[,<network>...]=<resolver>
+        //       --anonymize <none|truncate|hash> --rebind-protection --rebind-allow <domain>
+        //       --max-udp-size <bytes> --upstream-timeout <seconds>
+        //       --nxdomain-redirect <address> --nxdomain-suffix <domain> --stale-if-error <seconds>
+        //       --hairpin <public address|name>=<internal address> --hairpin-client <network>
+        //       --unix <path> --unix-dgram <path> --proxy-protocol --drop-unknown-edns
+        //       --minimal-responses
+        //       --zone <file> --health-check <name>=<tcp:port|http:port/path> --health-interval <seconds>
+        //       --dhcp-leases <file> --lease-domain <domain>
+        //       --register <address> --register-key <name|*>=<secret>
+        //       --tcp <address> --tsig-key [hmac-sha256:]<name>:<secret> --interface <name>
+        //       --dot <address> --https <address> --tls-cert <file.pem> --tls-key <file.pem>
+        //       --acme <domain> --acme-email <address> --acme-directory <url> --acme-http <address>
+        //       --allow-transfer <zone>=<network|key:name|key:name@network>
+        //       --dnssec-key <zone>=[ksk:|zsk:]<ecdsap256sha256|ed25519>:<file.pem> --zonemd
+        //       --cds --zsk-rollover <zone>=<directory> --zsk-lifetime <days>
+        //       --dnssec-key-set <zone>=<file>
+        //       --dnssec-validation <enforce|permissive> --trust-anchor <file>
+        //       --admin <address> --otlp-endpoint <url> --log-format <text|json>
+        //       --syslog <local|host:port> --syslog-facility <facility>
+        //       --doh-canary --canary <domain>
+        //       --blocklist <https://url|file> --blocklist-refresh <seconds>
+        //       --blocklist-allow <domain|*.domain>
+        //       --mdns-repeat <address>/<prefix> --mdns-rate <messages per second>
+        //       --max-connections <N> --max-connections-per-client <N> --tcp-idle-timeout <seconds>
+        //       --tcp-message-timeout <seconds> --strict-parsing <udp|tcp|dot>
+        //       --sample <file.pcap> --sample-rate <N> --sample-max-size <bytes>
+        //       --max-in-flight <N> --overload <drop|refuse> --so-rcvbuf <bytes> --so-sndbuf <bytes>
+        let mut resolver_address = String::new();
+        let mut resolv_conf_path = String::new();
+        let mut bootstrap: Vec<IpAddr> = Vec::new();
+        let mut tls_options = TlsOptions::default();
+        let mut json_api_address = String::new();
+        let mut script_path = String::new();
+        let mut pcap_path = String::new();
+        let mut tcp_address = String::new();
+        let mut interface: Option<String> = None;
+        let mut dot_address = String::new();
+        let mut https_address = String::new();
+        let mut tls_cert_path = String::new();
+        let mut tls_key_path = String::new();
+        let mut acme_domain = String::new();
+        let mut acme_email = String::new();
+        let mut acme_directory = String::new();
+        let mut acme_http_address = String::new();
+        let mut tsig_keys: Vec<TsigKey> = Vec::new();
+        let mut zone_paths = Vec::new();
+        let mut transfer_rules: Vec<(DomainName, TransferRule)> = Vec::new();
+        let mut dnssec_keys: Vec<(DomainName, String)> = Vec::new();
+        let mut publish_zonemd = false;
+        let mut publish_cds = false;
+        let mut zsk_rollovers: Vec<(DomainName, String)> = Vec::new();
+        let mut zsk_lifetime = None;
+        let mut key_sets: Vec<(DomainName, String)> = Vec::new();
+        let mut dnssec_validation = String::new();
+        let mut trust_anchor_path = String::new();
+        let mut health_checks: Vec<(DomainName, Probe)> = Vec::new();
+        let mut health_interval = Duration::from_secs(10);
+        let mut leases_path = String::new();
+        let mut lease_domain = DomainName::from("lan");
+        let mut register_address = String::new();
+        let mut register_keys: Vec<RegistrationKey> = Vec::new();
+        let mut min_ttl = None;
+        let mut max_ttl = None;
+        let mut cache_size = 0;
+        let mut cache_redis = String::new();
+        let mut cache_peers: Vec<String> = Vec::new();
+        let mut client_routes: Vec<(Vec<Network>, String)> = Vec::new();
+        let mut cache_replication = String::new();
+        let mut wire_cache = false;
+        let mut max_stale = None;
+        let mut strip_ecs = false;
+        let mut minimal_responses = false;
+        let mut drop_unknown_edns = false;
+        let mut anonymizer = Anonymizer::None;
+        let mut rebind_protection = false;
+        let mut rebind_allowed = Vec::new();
+        let mut max_udp_size = edns::UDP_PAYLOAD_SIZE;
+        let mut upstream_timeout = Duration::from_secs(2);
+        let mut nxdomain_redirect: Option<Ipv4Addr> = None;
+        let mut hairpin_rules: Vec<HairpinRule> = Vec::new();
+        let mut hairpin_clients: Vec<Network> = Vec::new();
+        let mut nxdomain_suffixes = Vec::new();
+        let mut unix_stream_path = String::new();
+        let mut unix_datagram_path = String::new();
+        let mut proxy_protocol = false;
+        let mut dump_packets = false;
+        let mut admin_address = String::new();
+        let mut otlp_endpoint = String::new();
+        let mut log_format = LogFormat::default();
+        let mut syslog_destination = String::new();
+        let mut syslog_facility = None;
+        let mut canaries = Vec::new();
+        let mut blocklist_sources: Vec<String> = Vec::new();
+        let mut mdns_interfaces: Vec<String> = Vec::new();
+        let mut mdns_rate: Option<u32> = None;
+        let mut blocklist_allowed: Vec<String> = Vec::new();
+        let mut blocklist_refresh = blocklist::REFRESH_INTERVAL;
+        let mut connection_limits = ConnectionLimits::default();
+        let mut sample_path = String::new();
+        let mut sample_rate = None;
+        let mut sample_max_size = None;
+        let mut strict_listeners: Vec<String> = Vec::new();
+        let mut max_in_flight = None;
+        let mut overload = None;
+        let mut socket_buffers = SocketBuffers::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--resolver" => {
+                    resolver_address = args.next().context("missing resolver address")?
+                }
+                "--resolv-conf" => {
+                    resolv_conf_path = args.next().context("missing resolv.conf path")?
+                }
+                "--privacy" => {
+                    tls_options.privacy = args.next().context("missing privacy mode")?.parse()?
+                }
+                "--spki-pin" => tls_options
+                    .spki_pins
+                    .push(parse_spki_pin(&args.next().context("missing SPKI pin")?)?),
+                "--spki-pin-only" => tls_options.pins_only = true,
+                "--bootstrap" => {
+                    bootstrap.push(args.next().context("missing bootstrap address")?.parse()?)
+                }
+                "--json-api" => {
+                    json_api_address = args.next().context("missing JSON API address")?
+                }
+                "--doh" => anyhow::bail!("--doh is now --json-api, DoH (RFC 8484) is --https"),
+                "--script" => script_path = args.next().context("missing script path")?,
+                "--pcap" => pcap_path = args.next().context("missing pcap file")?,
+                "--tcp" => tcp_address = args.next().context("missing TCP address")?,
+                "--interface" => interface = Some(args.next().context("missing interface name")?),
+                "--dot" => dot_address = args.next().context("missing DoT address")?,
+                "--https" => https_address = args.next().context("missing DoH address")?,
+                "--tls-cert" => tls_cert_path = args.next().context("missing certificate file")?,
+                "--tls-key" => tls_key_path = args.next().context("missing private key file")?,
+                "--acme" => acme_domain = args.next().context("missing ACME domain")?,
+                "--acme-email" => acme_email = args.next().context("missing ACME contact")?,
+                "--acme-directory" => {
+                    acme_directory = args.next().context("missing ACME directory")?
+                }
+                "--acme-http" => acme_http_address = args.next().context("missing ACME address")?,
+                "--tsig-key" => tsig_keys.push(args.next().context("missing TSIG key")?.parse()?),
+                "--zone" => zone_paths.push(args.next().context("missing zone file")?),
+                "--allow-transfer" => {
+                    let rule = args.next().context("missing transfer rule")?;
+                    let (zone, rule) = rule
+                        .split_once('=')
+                        .ok_or_else(|| anyhow::anyhow!("transfer rule must be <zone>=<rule>"))?;
+                    transfer_rules.push((DomainName::from_unicode(zone)?, rule.parse()?));
+                }
+                "--zonemd" => publish_zonemd = true,
+                "--cds" => publish_cds = true,
+                "--zsk-rollover" => {
+                    let rollover = args.next().context("missing ZSK rollover")?;
+                    let (zone, directory) = rollover.split_once('=').ok_or_else(|| {
+                        anyhow::anyhow!("ZSK rollover must be <zone>=<directory>")
+                    })?;
+                    zsk_rollovers.push((DomainName::from_unicode(zone)?, directory.to_string()));
+                }
+                "--dnssec-key-set" => {
+                    let key_set = args.next().context("missing DNSSEC key set")?;
+                    let (zone, path) = key_set
+                        .split_once('=')
+                        .ok_or_else(|| anyhow::anyhow!("DNSSEC key set must be <zone>=<file>"))?;
+                    key_sets.push((DomainName::from_unicode(zone)?, path.to_string()));
+                }
+                "--zsk-lifetime" => {
+                    let days: u64 = args.next().context("missing ZSK lifetime")?.parse()?;
+                    zsk_lifetime = Some(Duration::from_secs(days * 24 * 3600));
+                }
+                "--dnssec-validation" => {
+                    dnssec_validation = args.next().context("missing validation mode")?
+                }
+                "--trust-anchor" => {
+                    trust_anchor_path = args.next().context("missing trust anchor file")?
+                }
+                "--dnssec-key" => {
+                    let key = args.next().context("missing DNSSEC key")?;
+                    let (zone, key) = key
+                        .split_once('=')
+                        .ok_or_else(|| anyhow::anyhow!("DNSSEC key must be <zone>=<key>"))?;
+                    dnssec_keys.push((DomainName::from_unicode(zone)?, key.to_string()));
+                }
+                "--health-check" => {
+                    let check = args.next().context("missing health check")?;
+                    let (name, probe) = check
+                        .split_once('=')
+                        .ok_or_else(|| anyhow::anyhow!("health check must be <name>=<probe>"))?;
+                    health_checks.push((DomainName::from_unicode(name)?, probe.parse()?));
+                }
+                "--health-interval" => {
+                    let seconds = args
+                        .next()
+                        .context("missing health check interval")?
+                        .parse()?;
+                    health_interval = Duration::from_secs(seconds);
+                }
+                "--dhcp-leases" => leases_path = args.next().context("missing leases file")?,
+                "--register" => {
+                    register_address = args.next().context("missing registration address")?
+                }
+                "--register-key" => {
+                    register_keys.push(args.next().context("missing registration key")?.parse()?)
+                }
+                "--lease-domain" => {
+                    lease_domain =
+                        DomainName::from_unicode(&args.next().context("missing lease domain")?)?
+                }
+                "--min-ttl" => min_ttl = Some(args.next().context("missing minimal TTL")?.parse()?),
+                "--max-ttl" => max_ttl = Some(args.next().context("missing maximal TTL")?.parse()?),
+                "--cache-size" => {
+                    cache_size = args.next().context("missing cache size")?.parse()?
+                }
+                "--cache-redis" => cache_redis = args.next().context("missing Redis URL")?,
+                "--route" => {
+                    let route = args.next().context("missing client route")?;
+                    let (networks, resolver) = route.split_once('=').ok_or_else(|| {
+                        anyhow::anyhow!("client route must be <network>=<resolver>")
+                    })?;
+                    let networks = networks
+                        .split(',')
+                        .map(str::parse)
+                        .collect::<Result<Vec<Network>>>()?;
+                    client_routes.push((networks, resolver.to_string()));
+                }
+                "--cache-peer" => cache_peers.push(args.next().context("missing cache peer")?),
+                "--cache-replication" => {
+                    cache_replication = args.next().context("missing replication address")?
+                }
+                "--wire-cache" => wire_cache = true,
+                "--stale-if-error" => {
+                    let seconds = args.next().context("missing staleness")?.parse()?;
+                    max_stale = Some(Duration::from_secs(seconds));
+                }
+                "--strip-ecs" => strip_ecs = true,
+                "--minimal-responses" => minimal_responses = true,
+                "--drop-unknown-edns" => drop_unknown_edns = true,
+                "--anonymize" => {
+                    anonymizer = args.next().context("missing anonymization")?.parse()?
+                }
+                "--rebind-protection" => rebind_protection = true,
+                "--rebind-allow" => rebind_allowed.push(DomainName::from_unicode(
+                    &args.next().context("missing allowed domain")?,
+                )?),
+                "--max-udp-size" => {
+                    max_udp_size = args.next().context("missing UDP size")?.parse()?;
+                    max_udp_size = max_udp_size.max(MIN_UDP_SIZE);
+                }
+                "--upstream-timeout" => {
+                    let seconds = args.next().context("missing upstream timeout")?.parse()?;
+                    upstream_timeout = Duration::try_from_secs_f64(seconds)?;
+                    if upstream_timeout.is_zero() {
+                        anyhow::bail!("--upstream-timeout must be positive");
+                    }
+                }
+                "--nxdomain-redirect" => {
+                    nxdomain_redirect =
+                        Some(args.next().context("missing redirect address")?.parse()?)
+                }
+                "--nxdomain-suffix" => nxdomain_suffixes.push(DomainName::from_unicode(
+                    &args.next().context("missing redirected domain")?,
+                )?),
+                "--hairpin" => {
+                    hairpin_rules.push(args.next().context("missing hairpin rule")?.parse()?)
+                }
+                "--hairpin-client" => {
+                    hairpin_clients.push(args.next().context("missing client network")?.parse()?)
+                }
+                "--unix" => unix_stream_path = args.next().context("missing socket path")?,
+                "--unix-dgram" => {
+                    unix_datagram_path = args.next().context("missing socket path")?
+                }
+                "--proxy-protocol" => proxy_protocol = true,
+                "--hexdump" => dump_packets = true,
+                "--admin" => admin_address = args.next().context("missing admin address")?,
+                "--otlp-endpoint" => {
+                    otlp_endpoint = args.next().context("missing OTLP endpoint")?
+                }
+                "--log-format" => {
+                    log_format = args.next().context("missing log format")?.parse()?
+                }
+                "--syslog" => {
+                    syslog_destination = args.next().context("missing syslog destination")?
+                }
+                "--max-connections" => {
+                    connection_limits.max_connections =
+                        args.next().context("missing connection limit")?.parse()?
+                }
+                "--max-connections-per-client" => {
+                    connection_limits.max_per_client =
+                        args.next().context("missing connection limit")?.parse()?
+                }
+                "--tcp-idle-timeout" => {
+                    let seconds = args.next().context("missing idle timeout")?.parse()?;
+                    connection_limits.idle_timeout = Duration::try_from_secs_f64(seconds)?;
+                    if connection_limits.idle_timeout.is_zero() {
+                        anyhow::bail!("--tcp-idle-timeout must be positive");
+                    }
+                }
+                "--tcp-message-timeout" => {
+                    let seconds = args.next().context("missing message timeout")?.parse()?;
+                    connection_limits.message_timeout = Duration::try_from_secs_f64(seconds)?;
+                    if connection_limits.message_timeout.is_zero() {
+                        anyhow::bail!("--tcp-message-timeout must be positive");
+                    }
+                }
+                "--doh-canary" => canaries.push(DomainName::from(MOZILLA_CANARY)),
+                "--canary" => canaries.push(DomainName::from_unicode(
+                    &args.next().context("missing canary domain")?,
+                )?),
+                "--blocklist" => blocklist_sources.push(args.next().context("missing blocklist")?),
+                "--blocklist-allow" => {
+                    blocklist_allowed.push(args.next().context("missing allowed domain")?)
+                }
+                "--mdns-repeat" => {
+                    mdns_interfaces.push(args.next().context("missing mDNS interface")?)
+                }
+                "--mdns-rate" => {
+                    mdns_rate = Some(args.next().context("missing mDNS rate")?.parse()?)
+                }
+                "--blocklist-refresh" => {
+                    let seconds = args.next().context("missing refresh interval")?.parse()?;
+                    blocklist_refresh = Duration::from_secs(seconds);
+                }
+                "--sample" => sample_path = args.next().context("missing sample file")?,
+                "--sample-rate" => {
+                    sample_rate = Some(args.next().context("missing sampling rate")?.parse()?)
+                }
+                "--sample-max-size" => {
+                    sample_max_size =
+                        Some(args.next().context("missing sample file size")?.parse()?)
+                }
+                "--max-in-flight" => {
+                    let limit = args.next().context("missing in-flight limit")?.parse()?;
+                    if limit == 0 {
+                        anyhow::bail!("--max-in-flight must be positive");
+                    }
+                    max_in_flight = Some(limit);
+                }
+                "--overload" => {
+                    overload = Some(args.next().context("missing overload policy")?.parse()?)
+                }
+                "--so-rcvbuf" => {
+                    socket_buffers.receive =
+                        Some(args.next().context("missing buffer size")?.parse()?)
+                }
+                "--so-sndbuf" => {
+                    socket_buffers.send = Some(args.next().context("missing buffer size")?.parse()?)
+                }
+                "--strict-parsing" => strict_listeners
+                    .push(args.next().context("missing listener (udp, tcp or dot)")?),
+                "--syslog-facility" => {
+                    syslog_facility = Some(args.next().context("missing syslog facility")?.parse()?)
+                }
+                unknown => anyhow::bail!("unknown option {:?}", unknown),
+            };
+        }
+
+        if let Some(listener) = strict_listeners
+            .iter()
+            .find(|listener| !matches!(listener.as_str(), "udp" | "tcp" | "dot"))
+        {
+            anyhow::bail!("--strict-parsing takes udp, tcp or dot, not {:?}", listener);
+        }
+
+        Ok(Self {
+            resolver_address,
+            resolv_conf_path,
+            bootstrap,
+            tls_options,
+            json_api_address,
+            script_path,
+            pcap_path,
+            tcp_address,
+            interface,
+            dot_address,
+            https_address,
+            tls_cert_path,
+            tls_key_path,
+            acme_domain,
+            acme_email,
+            acme_directory,
+            acme_http_address,
+            tsig_keys,
+            zone_paths,
+            transfer_rules,
+            dnssec_keys,
+            publish_zonemd,
+            publish_cds,
+            zsk_rollovers,
+            zsk_lifetime,
+            key_sets,
+            dnssec_validation,
+            trust_anchor_path,
+            health_checks,
+            health_interval,
+            leases_path,
+            lease_domain,
+            register_address,
+            register_keys,
+            min_ttl,
+            max_ttl,
+            cache_size,
+            cache_redis,
+            cache_peers,
+            client_routes,
+            cache_replication,
+            wire_cache,
+            max_stale,
+            strip_ecs,
+            minimal_responses,
+            drop_unknown_edns,
+            anonymizer,
+            rebind_protection,
+            rebind_allowed,
+            max_udp_size,
+            upstream_timeout,
+            nxdomain_redirect,
+            hairpin_rules,
+            hairpin_clients,
+            nxdomain_suffixes,
+            unix_stream_path,
+            unix_datagram_path,
+            proxy_protocol,
+            dump_packets,
+            admin_address,
+            otlp_endpoint,
+            log_format,
+            syslog_destination,
+            syslog_facility,
+            canaries,
+            blocklist_sources,
+            mdns_interfaces,
+            mdns_rate,
+            blocklist_allowed,
+            blocklist_refresh,
+            connection_limits,
+            sample_path,
+            sample_rate,
+            sample_max_size,
+            strict_listeners,
+            max_in_flight,
+            overload,
+            socket_buffers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config> {
+        Config::parse(args.iter().map(ToString::to_string))
+    }
+
+    #[test]
+    fn test_options_are_parsed() {
+        let config = parse(&[
+            "--resolver",
+            "8.8.8.8:53",
+            "--min-ttl",
+            "60",
+            "--wire-cache",
+        ])
+        .unwrap();
+        assert_eq!(config.resolver_address, "8.8.8.8:53");
+        assert_eq!(config.min_ttl, Some(60));
+        assert!(config.wire_cache);
+        assert!(config.max_ttl.is_none());
+    }
+
+    #[test]
+    fn test_unknown_options_are_rejected() {
+        let error = parse(&["--min-tll", "60"]).err().unwrap();
+        assert_eq!(error.to_string(), "unknown option \"--min-tll\"");
+        assert!(parse(&["8.8.8.8:53"]).is_err());
+        assert!(parse(&["--min-ttl"]).is_err());
+        assert!(parse(&["--strict-parsing", "https"]).is_err());
+        assert!(parse(&["--doh", "127.0.0.1:8053"]).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+//! Limits on connections of stream listeners (TCP, DoT, DoH)
+//!
+//! Every listener admits at most [`ConnectionLimits::max_connections`] connections, of which
+//! at most [`ConnectionLimits::max_per_client`] from a single address. When the listener is
+//! full, the connection idle for the longest time is closed to make room for the new one
+//! (RFC 7766 section 6.2.3 allows servers to close idle connections at any time); if all
+//! connections are busy answering queries, the new one is refused. Connections idle for
+//! [`ConnectionLimits::idle_timeout`] are closed too, as are those whose message doesn't
+//! arrive whole within [`ConnectionLimits::message_timeout`] after its first byte.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::tcp::{IDLE_TIMEOUT, MESSAGE_TIMEOUT};
+
+/// Connections of one listener at most, by default
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+/// Connections of one listener from a single address at most, by default
+pub const DEFAULT_MAX_PER_CLIENT: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    pub max_connections: usize,
+    pub max_per_client: usize,
+    /// How long a connection may stay open without any query
+    pub idle_timeout: Duration,
+    /// How long a message (or a TLS handshake) may take to arrive once it started
+    pub message_timeout: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_per_client: DEFAULT_MAX_PER_CLIENT,
+            idle_timeout: IDLE_TIMEOUT,
+            message_timeout: MESSAGE_TIMEOUT,
+        }
+    }
+}
+
+struct Tracked {
+    client: IpAddr,
+    /// Handle for closing the connection from another thread
+    stream: TcpStream,
+    /// Since when the connection waits for a query, `None` while a query is being answered
+    idle_since: Option<Instant>,
+}
+
+/// Open connections of a listener
+pub struct ConnectionTracker {
+    limits: ConnectionLimits,
+    connections: Mutex<(u64, HashMap<u64, Tracked>)>,
+}
+
+impl ConnectionTracker {
+    pub fn new(limits: ConnectionLimits) -> Arc<Self> {
+        Arc::new(Self {
+            limits,
+            connections: Mutex::new((0, HashMap::new())),
+        })
+    }
+
+    /// Admits the accepted connection, possibly closing the one idle the longest,
+    /// fails if the limits don't allow it
+    pub fn admit(self: &Arc<Self>, stream: &TcpStream) -> Result<Connection> {
+        let client = stream.peer_addr()?.ip().to_canonical();
+        let mut connections = self.connections.lock().expect("connections lock poisoned");
+        let (next_id, connections) = &mut *connections;
+
+        let from_client = connections
+            .values()
+            .filter(|connection| connection.client == client)
+            .count();
+        if from_client >= self.limits.max_per_client {
+            anyhow::bail!("{} has {} connections already", client, from_client);
+        }
+
+        if connections.len() >= self.limits.max_connections {
+            let oldest_idle = connections
+                .iter()
+                .filter_map(|(id, connection)| Some((*id, connection.idle_since?)))
+                .min_by_key(|(_, idle_since)| *idle_since)
+                .map(|(id, _)| id);
+            let Some(id) = oldest_idle else {
+                anyhow::bail!("too many connections, none of them idle");
+            };
+            if let Some(evicted) = connections.remove(&id) {
+                // its thread sees the end of stream and finishes
+                let _ = evicted.stream.shutdown(Shutdown::Both);
+            }
+        }
+
+        let id = *next_id;
+        *next_id += 1;
+        connections.insert(
+            id,
+            Tracked {
+                client,
+                stream: stream.try_clone()?,
+                idle_since: Some(Instant::now()),
+            },
+        );
+        Ok(Connection {
+            tracker: self.clone(),
+            id,
+        })
+    }
+
+    fn set_idle_since(&self, id: u64, idle_since: Option<Instant>) {
+        let mut connections = self.connections.lock().expect("connections lock poisoned");
+        if let Some(connection) = connections.1.get_mut(&id) {
+            connection.idle_since = idle_since;
+        }
+    }
+}
+
+/// Admitted connection, it is forgotten when dropped
+pub struct Connection {
+    tracker: Arc<ConnectionTracker>,
+    id: u64,
+}
+
+impl Connection {
+    /// How long the connection may wait for a query
+    pub fn idle_timeout(&self) -> Duration {
+        self.tracker.limits.idle_timeout
+    }
+
+    /// How long a message may take to arrive once it started
+    pub fn message_timeout(&self) -> Duration {
+        self.tracker.limits.message_timeout
+    }
+
+    /// A query is being answered, the connection must not be closed
+    pub fn busy(&self) {
+        self.tracker.set_idle_since(self.id, None);
+    }
+
+    /// The connection waits for another query
+    pub fn idle(&self) {
+        self.tracker.set_idle_since(self.id, Some(Instant::now()));
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut connections = self
+            .tracker
+            .connections
+            .lock()
+            .expect("connections lock poisoned");
+        connections.1.remove(&self.id);
+    }
+}
+
+/// Reads from the stream until the deadline, then fails, so data trickling in doesn't
+/// reset the timeout with every byte like a read timeout alone does
+pub(crate) struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl<'a> Deadline<'a> {
+    pub(crate) fn new(stream: &'a TcpStream, timeout: Duration) -> Self {
+        Self {
+            stream,
+            deadline: Instant::now() + timeout,
+        }
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(timed_out());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        // an expired read timeout is WouldBlock on Unix and TimedOut on Windows
+        self.stream.read(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => timed_out(),
+            _ => e,
+        })
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "data not received in time")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    /// Client and server ends of a new connection to `listener`
+    fn connect(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_connections_per_client_are_limited() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tracker = ConnectionTracker::new(ConnectionLimits {
+            max_per_client: 1,
+            ..ConnectionLimits::default()
+        });
+
+        let (_client, server) = connect(&listener);
+        let first = tracker.admit(&server).unwrap();
+        let (_client, server) = connect(&listener);
+        assert!(tracker.admit(&server).is_err());
+
+        drop(first);
+        assert!(tracker.admit(&server).is_ok());
+    }
+
+    #[test]
+    fn test_oldest_idle_connection_makes_room() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tracker = ConnectionTracker::new(ConnectionLimits {
+            max_connections: 2,
+            ..ConnectionLimits::default()
+        });
+
+        let (mut idle_client, server) = connect(&listener);
+        let _idle = tracker.admit(&server).unwrap();
+        let (_client, server) = connect(&listener);
+        let busy = tracker.admit(&server).unwrap();
+        busy.busy();
+
+        let (_client, server) = connect(&listener);
+        let newest = tracker.admit(&server).unwrap();
+        assert_eq!(idle_client.read(&mut [0; 1]).unwrap(), 0);
+
+        // all connections are busy
+        newest.busy();
+        let (_client, server) = connect(&listener);
+        assert!(tracker.admit(&server).is_err());
+    }
+
+    #[test]
+    fn test_stalled_client_is_cut_off_at_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, server) = connect(&listener);
+        std::io::Write::write_all(&mut client, b"GET").unwrap();
+
+        let started = Instant::now();
+        let mut deadline = Deadline::new(&server, Duration::from_millis(200));
+        let error = deadline.read_exact(&mut [0; 16]).unwrap_err();
+
+        // not a WouldBlock of the expired read timeout, which callers take for a retry
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(error.to_string(), "data not received in time");
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
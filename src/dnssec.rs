@@ -0,0 +1,1079 @@
+//! Signing of local zones (DNSSEC, RFC 4033-4035)
+//!
+//! Zones are signed when loaded, with keys of the algorithms recommended for signing by
+//! RFC 8624: ECDSA P-256 with SHA-256 (13) and Ed25519 (15). Every key has a role:
+//!
+//! - KSK signs just the DNSKEY RRset, its DS is published in the parent zone,
+//! - ZSK signs all the other RRsets,
+//! - CSK (combined) signs everything.
+//!
+//! Each RRset is signed by all keys of its role. Keys of two algorithms make the zone
+//! dual-signed, which is how the algorithm is changed (RFC 6781 section 4.1.4): keys of
+//! the new algorithm are added next to the old ones, DS at the parent is replaced once
+//! the new DNSKEYs spread to caches, and the old keys are removed after the old DS expires.
+//!
+//! CDS and CDNSKEY records of the KSKs can be published for parents which pick up DS
+//! changes on their own ([`publish_cds`]).
+//!
+//! KSKs can be kept offline too: the DNSKEY RRset is then signed elsewhere and imported
+//! with its signatures ([`KeySet`]), the server signs the rest of the zone with its ZSKs.
+//!
+//! Key files hold PKCS#8 private keys in PEM, missing ones are generated on first use.
+//! ZONEMD of the zone (see [`crate::zonemd`]) is recomputed after signing and signed too.
+//! Names and types which don't exist are proven by a chain of NSEC records (RFC 4034
+//! section 4). Signatures are valid for [`SIGNATURE_VALIDITY`] from the signing.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use bytes::{BufMut, BytesMut};
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, Ed25519KeyPair, KeyPair as _, RsaPublicKeyComponents, UnparsedPublicKey,
+    VerificationAlgorithm, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING,
+    ECDSA_P384_SHA384_FIXED, ED25519, RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY,
+    RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY, RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY,
+};
+
+use crate::base64;
+use crate::domain_name::{DomainName, LookupTable};
+use crate::record::{DnsRecord, RecordData, RecordType, Soa};
+use crate::zone::{self, Zone};
+use crate::zonemd;
+
+/// How long signatures stay valid after the zone is signed
+pub const SIGNATURE_VALIDITY: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// Signatures are valid this long before the signing, for resolvers with clocks behind
+const CLOCK_SKEW: Duration = Duration::from_secs(3600);
+
+/// DNSKEY protocol field, always 3 (RFC 4034 section 2.1.2)
+const PROTOCOL: u8 = 3;
+
+/// DNSKEY flags of a zone key (ZSK)
+const ZONE_KEY: u16 = 256;
+
+/// DNSKEY flags of a zone key with the Secure Entry Point flag (KSK, CSK)
+const SEP_KEY: u16 = 257;
+
+/// DS digest type SHA-256 (RFC 4509)
+const DIGEST_SHA256: u8 = 2;
+
+/// Signing algorithm (DNSKEY algorithm number)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    EcdsaP256Sha256 = 13,
+    Ed25519 = 15,
+}
+
+/// Parses mnemonics of the IANA registry, e.g. `ECDSAP256SHA256` or `ed25519`
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_uppercase().as_str() {
+            "ECDSAP256SHA256" | "13" => Ok(Self::EcdsaP256Sha256),
+            "ED25519" | "15" => Ok(Self::Ed25519),
+            _ => anyhow::bail!(
+                "unsupported DNSSEC algorithm '{}', expected ecdsap256sha256 or ed25519",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EcdsaP256Sha256 => f.write_str("ECDSAP256SHA256"),
+            Self::Ed25519 => f.write_str("ED25519"),
+        }
+    }
+}
+
+/// What the key signs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyRole {
+    /// Key signing key, signs the DNSKEY RRset
+    Ksk,
+    /// Zone signing key, signs the rest of the zone
+    Zsk,
+    /// Combined signing key, signs the whole zone
+    Csk,
+}
+
+impl KeyRole {
+    fn signs_keys(self) -> bool {
+        self != Self::Zsk
+    }
+
+    fn signs_zone(self) -> bool {
+        self != Self::Ksk
+    }
+
+    /// Whether the key signs RRsets of `record_type`
+    fn signs(self, record_type: &RecordType) -> bool {
+        match record_type {
+            // CDS and CDNSKEY by a key with DS at the parent (RFC 7344 section 4.1)
+            RecordType::DNSKEY | RecordType::CDS | RecordType::CDNSKEY => self.signs_keys(),
+            _ => self.signs_zone(),
+        }
+    }
+}
+
+/// Key file with the role and algorithm of the key, `[ksk:|zsk:]<algorithm>:<file.pem>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeySpec {
+    pub role: KeyRole,
+    pub algorithm: Algorithm,
+    pub path: PathBuf,
+}
+
+impl FromStr for KeySpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (role, rest) = match s.split_once(':') {
+            Some(("ksk", rest)) => (KeyRole::Ksk, rest),
+            Some(("zsk", rest)) => (KeyRole::Zsk, rest),
+            _ => (KeyRole::Csk, s),
+        };
+        let (algorithm, path) = rest
+            .split_once(':')
+            .with_context(|| format!("key must be [ksk:|zsk:]<algorithm>:<file>, not {:?}", s))?;
+        Ok(Self {
+            role,
+            algorithm: algorithm.parse()?,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+impl KeySpec {
+    /// Loads the key, generating it first if the file doesn't exist
+    pub fn load(&self) -> Result<SigningKey> {
+        let path = &self.path;
+        let pkcs8 = match std::fs::read_to_string(path) {
+            Ok(pem) => base64::from_pem(&pem, "PRIVATE KEY")
+                .with_context(|| format!("{} is not a PEM private key", path.display()))?,
+            Err(_) if !path.exists() => {
+                let pkcs8 = generate(self.algorithm)?;
+                write_key(path, &base64::pem("PRIVATE KEY", &pkcs8))?;
+                println!(
+                    "DNSSEC: new {} key written to {}",
+                    self.algorithm,
+                    path.display()
+                );
+                pkcs8
+            }
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        SigningKey::from_pkcs8(self.algorithm, self.role, &pkcs8)
+            .with_context(|| format!("invalid key {}", path.display()))
+    }
+}
+
+/// PKCS#8 document of a new private key
+fn generate(algorithm: Algorithm) -> Result<Vec<u8>> {
+    let rng = SystemRandom::new();
+    let pkcs8 = match algorithm {
+        Algorithm::EcdsaP256Sha256 => {
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+        }
+        Algorithm::Ed25519 => Ed25519KeyPair::generate_pkcs8(&rng),
+    }
+    .map_err(|_| anyhow::anyhow!("failed to generate {} key", algorithm))?;
+    Ok(pkcs8.as_ref().to_vec())
+}
+
+/// Private key, readable by its owner only
+fn write_key(path: &Path, pem: &str) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, pem.as_bytes())
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+enum KeyPair {
+    Ecdsa(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+/// Private key signing a zone
+pub struct SigningKey {
+    algorithm: Algorithm,
+    role: KeyRole,
+    pair: KeyPair,
+    /// DNSKEY RDATA
+    dnskey: Vec<u8>,
+    rng: SystemRandom,
+}
+
+impl SigningKey {
+    pub fn from_pkcs8(algorithm: Algorithm, role: KeyRole, pkcs8: &[u8]) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let invalid = |_| anyhow::anyhow!("not a PKCS#8 {} key", algorithm);
+        let (pair, public_key) = match algorithm {
+            Algorithm::EcdsaP256Sha256 => {
+                let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8, &rng)
+                    .map_err(invalid)?;
+                // uncompressed point without the leading 0x04 (RFC 6605 section 4)
+                let public_key = pair.public_key().as_ref()[1..].to_vec();
+                (KeyPair::Ecdsa(pair), public_key)
+            }
+            Algorithm::Ed25519 => {
+                // keys from OpenSSL are PKCS#8 v1, without the public key
+                let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8).map_err(invalid)?;
+                let public_key = pair.public_key().as_ref().to_vec();
+                (KeyPair::Ed25519(pair), public_key)
+            }
+        };
+
+        let flags = if role.signs_keys() { SEP_KEY } else { ZONE_KEY };
+        let mut dnskey = Vec::with_capacity(4 + public_key.len());
+        dnskey.put_u16(flags);
+        dnskey.put_u8(PROTOCOL);
+        dnskey.put_u8(algorithm as u8);
+        dnskey.extend_from_slice(&public_key);
+
+        Ok(Self {
+            algorithm,
+            role,
+            pair,
+            dnskey,
+            rng,
+        })
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    pub fn role(&self) -> KeyRole {
+        self.role
+    }
+
+    /// Identifies the key in signatures and DS records (RFC 4034 appendix B)
+    pub fn key_tag(&self) -> u16 {
+        key_tag(&self.dnskey)
+    }
+
+    /// DNSKEY record of the key in zone `origin`
+    pub fn dnskey(&self, origin: &DomainName, ttl: u32) -> DnsRecord {
+        DnsRecord::new(
+            origin.clone(),
+            RecordType::DNSKEY,
+            crate::record::RecordClass::IN,
+            ttl,
+            RecordData::Unknown(self.dnskey.clone()),
+        )
+    }
+
+    /// DS record data for the parent of zone `origin`, `<key tag> <algorithm> 2 <digest>`
+    pub fn ds(&self, origin: &DomainName) -> String {
+        let data = self.ds_rdata(origin);
+        let hex: String = data[4..].iter().map(|b| format!("{:02X}", b)).collect();
+        format!(
+            "{} {} {} {}",
+            self.key_tag(),
+            self.algorithm as u8,
+            DIGEST_SHA256,
+            hex
+        )
+    }
+
+    /// DS RDATA with SHA-256 digest of the key (RFC 4034 section 5.1.4)
+    pub(crate) fn ds_rdata(&self, origin: &DomainName) -> Vec<u8> {
+        let mut owner_and_key = name_bytes(&origin.canonicalize());
+        owner_and_key.extend_from_slice(&self.dnskey);
+        let digest = digest::digest(&digest::SHA256, &owner_and_key);
+
+        let mut data = Vec::with_capacity(4 + digest.as_ref().len());
+        data.put_u16(self.key_tag());
+        data.put_u8(self.algorithm as u8);
+        data.put_u8(DIGEST_SHA256);
+        data.extend_from_slice(digest.as_ref());
+        data
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let signature = match &self.pair {
+            KeyPair::Ecdsa(pair) => pair
+                .sign(&self.rng, data)
+                .map_err(|_| anyhow::anyhow!("failed to sign with key {}", self.key_tag()))?,
+            KeyPair::Ed25519(pair) => pair.sign(data),
+        };
+        Ok(signature.as_ref().to_vec())
+    }
+}
+
+/// `KSK 12345 ED25519`
+impl fmt::Display for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let role = match self.role {
+            KeyRole::Ksk => "KSK",
+            KeyRole::Zsk => "ZSK",
+            KeyRole::Csk => "CSK",
+        };
+        write!(f, "{} {} {}", role, self.key_tag(), self.algorithm)
+    }
+}
+
+/// Key tag of DNSKEY RDATA (RFC 4034 appendix B)
+pub(crate) fn key_tag(dnskey: &[u8]) -> u16 {
+    let sum = dnskey.iter().enumerate().fold(0u32, |sum, (i, &byte)| {
+        sum + if i % 2 == 0 {
+            (byte as u32) << 8
+        } else {
+            byte as u32
+        }
+    });
+    (sum + (sum >> 16)) as u16
+}
+
+/// DNSKEY RRset signed offline, by KSKs which never get to the server
+///
+/// The key set is a file in master file format with the DNSKEY records of the zone
+/// (KSKs and the server's ZSKs) and their RRSIGs, CDS and CDNSKEY records with their
+/// RRSIGs may come along. Signatures are checked when the key set is loaded.
+pub struct KeySet {
+    /// DNSKEY, CDS and CDNSKEY records
+    records: Vec<DnsRecord>,
+    signatures: Vec<DnsRecord>,
+}
+
+impl KeySet {
+    pub fn from_file(path: impl AsRef<Path>, origin: &DomainName) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read key set {}", path.display()))?;
+        Self::parse(&text, origin).with_context(|| format!("invalid key set {}", path.display()))
+    }
+
+    /// Parses RRsets at the apex of zone `origin` and checks they're signed by their keys
+    pub fn parse(text: &str, origin: &DomainName) -> Result<Self> {
+        let (signatures, records): (Vec<DnsRecord>, Vec<DnsRecord>) =
+            zone::parse_records(text, origin)?
+                .into_iter()
+                .partition(|record| record.record_type == RecordType::RRSIG);
+        for record in records.iter().chain(&signatures) {
+            if !record.domain_name.eq_ignore_case(origin) {
+                anyhow::bail!("{} is not at the apex of zone {}", record, origin);
+            }
+            if !matches!(
+                record.record_type,
+                RecordType::DNSKEY | RecordType::CDS | RecordType::CDNSKEY | RecordType::RRSIG
+            ) {
+                anyhow::bail!("{} is not a key record", record);
+            }
+        }
+
+        let key_set = Self {
+            records,
+            signatures,
+        };
+        let rrsets = rrsets(&key_set.records);
+        if !rrsets
+            .iter()
+            .any(|rrset| rrset[0].record_type == RecordType::DNSKEY)
+        {
+            anyhow::bail!("no DNSKEY records");
+        }
+        for signature in key_set.signatures.iter() {
+            let covered = covered_type(signature);
+            let rrset = rrsets
+                .iter()
+                .find(|rrset| u16::from(rrset[0].record_type.clone()) == covered)
+                .with_context(|| format!("no RRset for {}", signature))?;
+            key_set
+                .verify(signature, rrset, origin)
+                .with_context(|| format!("invalid {}", signature))?;
+        }
+        if let Some(rrset) = rrsets.iter().find(|rrset| {
+            let record_type = u16::from(rrset[0].record_type.clone());
+            !key_set
+                .signatures
+                .iter()
+                .any(|signature| covered_type(signature) == record_type)
+        }) {
+            anyhow::bail!("{} RRset is not signed", rrset[0].record_type);
+        }
+        Ok(key_set)
+    }
+
+    /// Checks `signature` of `rrset` was made by one of the DNSKEYs of the set
+    fn verify(
+        &self,
+        signature: &DnsRecord,
+        rrset: &[&DnsRecord],
+        origin: &DomainName,
+    ) -> Result<()> {
+        let RecordData::Unknown(data) = &signature.data else {
+            anyhow::bail!("RRSIG is not opaque");
+        };
+        let signer = name_bytes(&origin.canonicalize());
+        let prefix_len = 18 + signer.len();
+        if data.len() <= prefix_len || !data[18..prefix_len].eq_ignore_ascii_case(&signer) {
+            anyhow::bail!("not signed by zone {}", origin);
+        }
+        let (prefix, signed) = data.split_at(prefix_len);
+        if prefix[4..8] != rrset[0].ttl.to_be_bytes() {
+            anyhow::bail!("TTL of the RRset differs from the original TTL");
+        }
+
+        let algorithm = prefix[2];
+        let tag = u16::from_be_bytes([prefix[16], prefix[17]]);
+        let key = self
+            .records
+            .iter()
+            .filter(|record| record.record_type == RecordType::DNSKEY)
+            .find_map(|record| match &record.data {
+                RecordData::Unknown(key)
+                    if key.len() > 4 && key[3] == algorithm && key_tag(key) == tag =>
+                {
+                    Some(key)
+                }
+                _ => None,
+            })
+            .with_context(|| format!("key {} is not in the key set", tag))?;
+        verify_signature(key, &signed_data(prefix, rrset), signed)
+            .with_context(|| format!("signature by key {} doesn't match", tag))
+    }
+
+    /// Until when all RRsets of the set stay signed
+    pub fn expiration(&self) -> SystemTime {
+        let expirations = |covered: u16| {
+            self.signatures
+                .iter()
+                .filter(move |signature| covered_type(signature) == covered)
+                .filter_map(|signature| match &signature.data {
+                    RecordData::Unknown(data) => {
+                        Some(u32::from_be_bytes(data[8..12].try_into().ok()?))
+                    }
+                    _ => None,
+                })
+        };
+        let expiration = rrsets(&self.records)
+            .iter()
+            .filter_map(|rrset| expirations(u16::from(rrset[0].record_type.clone())).max())
+            .min()
+            .unwrap_or_default();
+        UNIX_EPOCH + Duration::from_secs(expiration.into())
+    }
+
+    /// Whether every RRset of the set has a signature valid at `now`
+    fn is_valid_at(&self, now: SystemTime) -> bool {
+        let now = timestamp(now);
+        rrsets(&self.records).iter().all(|rrset| {
+            let record_type = u16::from(rrset[0].record_type.clone());
+            self.signatures.iter().any(|signature| {
+                let RecordData::Unknown(data) = &signature.data else {
+                    return false;
+                };
+                let time = |offset: usize| {
+                    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+                };
+                covered_type(signature) == record_type && time(12) <= now && now <= time(8)
+            })
+        })
+    }
+}
+
+/// Checks `signature` of `message` by the key in DNSKEY RDATA `dnskey`
+///
+/// Besides the algorithms we sign with, the ones validators must support (RFC 8624
+/// section 3.1) are verified: RSA with SHA-1, SHA-256 and SHA-512 and ECDSA P-384.
+pub(crate) fn verify_signature(dnskey: &[u8], message: &[u8], signature: &[u8]) -> Result<()> {
+    let Some((&algorithm, public_key)) = dnskey.get(3).zip(dnskey.get(4..)) else {
+        anyhow::bail!("DNSKEY too short");
+    };
+    let invalid = |_| anyhow::anyhow!("invalid signature");
+    let rsa = match algorithm {
+        5 | 7 => &RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY,
+        8 => &RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+        10 => &RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY,
+        _ => {
+            let (verification, public_key): (&dyn VerificationAlgorithm, Vec<u8>) = match algorithm
+            {
+                13 => (&ECDSA_P256_SHA256_FIXED, [&[4][..], public_key].concat()),
+                14 => (&ECDSA_P384_SHA384_FIXED, [&[4][..], public_key].concat()),
+                15 => (&ED25519, public_key.to_vec()),
+                _ => anyhow::bail!("unsupported DNSSEC algorithm {}", algorithm),
+            };
+            return UnparsedPublicKey::new(verification, public_key)
+                .verify(message, signature)
+                .map_err(invalid);
+        }
+    };
+
+    // exponent length in one byte, or in two after a zero (RFC 3110 section 2)
+    let (exponent_len, rest) = match public_key {
+        [0, high, low, rest @ ..] => (u16::from_be_bytes([*high, *low]) as usize, rest),
+        [len, rest @ ..] => (*len as usize, rest),
+        [] => anyhow::bail!("empty RSA key"),
+    };
+    if exponent_len == 0 || rest.len() <= exponent_len {
+        anyhow::bail!("invalid RSA key");
+    }
+    let (e, n) = rest.split_at(exponent_len);
+    let trim = |bytes: &[u8]| bytes[bytes.iter().take_while(|&&b| b == 0).count()..].to_vec();
+    RsaPublicKeyComponents {
+        n: trim(n),
+        e: trim(e),
+    }
+    .verify(rsa, message, signature)
+    .map_err(invalid)
+}
+
+/// Whether signatures of DNSKEY algorithm `algorithm` can be checked by [`verify_signature`]
+pub(crate) fn is_supported(algorithm: u8) -> bool {
+    matches!(algorithm, 5 | 7 | 8 | 10 | 13 | 14 | 15)
+}
+
+/// Type covered by RRSIG record
+pub(crate) fn covered_type(signature: &DnsRecord) -> u16 {
+    match &signature.data {
+        RecordData::Unknown(data) if data.len() >= 2 => u16::from_be_bytes([data[0], data[1]]),
+        _ => 0,
+    }
+}
+
+/// Publishes CDS and CDNSKEY records of the KSKs and CSKs among `keys` at the apex of `zone`
+/// (RFC 7344), replacing existing ones; the zone is to be signed afterwards
+///
+/// Parents supporting RFC 8078 keep their DS records in sync with them, so a new KSK
+/// takes over once its DS shows up at the parent.
+pub fn publish_cds(zone: &Zone, keys: &[SigningKey]) -> Result<Zone> {
+    let origin = zone.origin();
+    let soa = zone.soa().expect("zone has SOA");
+    let mut records: Vec<DnsRecord> = zone
+        .records()
+        .iter()
+        .filter(|record| {
+            !(matches!(record.record_type, RecordType::CDS | RecordType::CDNSKEY)
+                && record.domain_name.eq_ignore_case(origin))
+        })
+        .cloned()
+        .collect();
+    for key in keys.iter().filter(|key| key.role.signs_keys()) {
+        for (record_type, data) in [
+            (RecordType::CDS, key.ds_rdata(origin)),
+            (RecordType::CDNSKEY, key.dnskey.clone()),
+        ] {
+            records.push(DnsRecord::new(
+                origin.clone(),
+                record_type,
+                soa.class.clone(),
+                soa.ttl,
+                RecordData::Unknown(data),
+            ));
+        }
+    }
+    Zone::new(origin.clone(), records)
+}
+
+/// Signs `zone` with `keys`: adds their DNSKEY records, NSEC chain and RRSIG of every
+/// authoritative RRset; signatures and NSEC records already in the zone are replaced
+pub fn sign_zone(zone: &Zone, keys: &[SigningKey], now: SystemTime) -> Result<Zone> {
+    sign_zone_with_standby(zone, keys, &[], now)
+}
+
+/// Signs `zone` like [`sign_zone`], publishing DNSKEY records of `standby` keys too, which
+/// don't sign yet or any more (key rollovers, see [`crate::rollover`])
+pub fn sign_zone_with_standby(
+    zone: &Zone,
+    keys: &[SigningKey],
+    standby: &[SigningKey],
+    now: SystemTime,
+) -> Result<Zone> {
+    if !keys.iter().any(|key| key.role.signs_keys()) {
+        anyhow::bail!("zone {} has no KSK or CSK", zone.origin());
+    }
+    let soa = zone.soa().expect("zone has SOA");
+    let dnskeys = keys
+        .iter()
+        .chain(standby)
+        .map(|key| key.dnskey(zone.origin(), soa.ttl))
+        .collect();
+    sign(zone, keys, dnskeys, &[], now)
+}
+
+/// Signs `zone` with ZSKs `keys` like [`sign_zone`], RRsets of the `key_set` signed offline
+/// replace those of the zone
+pub fn sign_zone_with_key_set(
+    zone: &Zone,
+    keys: &[SigningKey],
+    key_set: &KeySet,
+    now: SystemTime,
+) -> Result<Zone> {
+    let origin = zone.origin();
+    if let Some(key) = keys.iter().find(|key| key.role.signs_keys()) {
+        anyhow::bail!(
+            "DNSKEY RRset of zone {} is signed offline, {} must be a ZSK",
+            origin,
+            key
+        );
+    }
+    for key in keys {
+        let dnskey = RecordData::Unknown(key.dnskey.clone());
+        if !key_set
+            .records
+            .iter()
+            .any(|record| record.record_type == RecordType::DNSKEY && record.data == dnskey)
+        {
+            anyhow::bail!("{} is not in the key set of zone {}", key, origin);
+        }
+    }
+    if !key_set.is_valid_at(now) {
+        anyhow::bail!(
+            "signatures of the key set of zone {} are not valid now",
+            origin
+        );
+    }
+    sign(
+        zone,
+        keys,
+        key_set.records.clone(),
+        &key_set.signatures,
+        now,
+    )
+}
+
+/// Signs `zone` with `keys`, `apex` RRsets (DNSKEY and possibly CDS, CDNSKEY) replace those
+/// of the zone, RRsets covered by `presigned` signatures are not signed again
+fn sign(
+    zone: &Zone,
+    keys: &[SigningKey],
+    apex: Vec<DnsRecord>,
+    presigned: &[DnsRecord],
+    now: SystemTime,
+) -> Result<Zone> {
+    if !keys.iter().any(|key| key.role.signs_zone()) {
+        anyhow::bail!("zone {} has no ZSK or CSK", zone.origin());
+    }
+
+    let origin = zone.origin();
+    let soa = zone.soa().expect("zone has SOA");
+    let nsec_ttl = soa.negative_ttl().unwrap_or(soa.ttl);
+
+    let replaced = |record: &DnsRecord| {
+        record.domain_name.eq_ignore_case(origin)
+            && apex
+                .iter()
+                .any(|apex| apex.record_type == record.record_type)
+    };
+    let mut records: Vec<DnsRecord> = zone
+        .records()
+        .iter()
+        .filter(|record| {
+            !matches!(
+                record.record_type,
+                RecordType::DNSKEY | RecordType::RRSIG | RecordType::NSEC
+            ) && !replaced(record)
+        })
+        .cloned()
+        .collect();
+    records.extend(apex);
+    records.sort_by(|a, b| a.domain_name.canonical_cmp(&b.domain_name));
+
+    // names below zone cuts (glue) belong to the child zones, at the cuts only NSEC and DS
+    // are ours (RFC 4035 section 2.2)
+    let in_chain = |name: &DomainName| match zone.delegation(name) {
+        Some(ns) => ns[0].domain_name.eq_ignore_case(name),
+        None => true,
+    };
+    let authoritative = |record: &DnsRecord| {
+        in_chain(&record.domain_name)
+            && (record.record_type != RecordType::NS
+                || zone.delegation(&record.domain_name).is_none())
+    };
+
+    let mut owners: Vec<(DomainName, Vec<u16>)> = Vec::new();
+    for record in records
+        .iter()
+        .filter(|record| in_chain(&record.domain_name))
+    {
+        let record_type = u16::from(record.record_type.clone());
+        match owners.last_mut() {
+            Some((owner, types)) if owner.eq_ignore_case(&record.domain_name) => {
+                types.push(record_type)
+            }
+            _ => owners.push((record.domain_name.clone(), vec![record_type])),
+        }
+    }
+    for (index, (owner, types)) in owners.iter().enumerate() {
+        let (next, _) = &owners[(index + 1) % owners.len()];
+        let mut types = types.clone();
+        types.extend([RecordType::RRSIG, RecordType::NSEC].map(u16::from));
+        let mut data = name_bytes(&next.canonicalize());
+        data.extend(type_bitmap(&mut types));
+        records.push(DnsRecord::new(
+            owner.clone(),
+            RecordType::NSEC,
+            soa.class.clone(),
+            nsec_ttl,
+            RecordData::Unknown(data),
+        ));
+    }
+    records.sort_by(|a, b| a.domain_name.canonical_cmp(&b.domain_name));
+
+    let inception = timestamp(now - CLOCK_SKEW);
+    let expiration = timestamp(now + SIGNATURE_VALIDITY);
+    // ZONEMD is signed last, its digest covers all the other signatures
+    let is_zonemd = |record: &DnsRecord| {
+        record.record_type == RecordType::ZONEMD && record.domain_name.eq_ignore_case(origin)
+    };
+    let is_presigned = |record: &DnsRecord| {
+        let record_type = u16::from(record.record_type.clone());
+        record.domain_name.eq_ignore_case(origin)
+            && presigned
+                .iter()
+                .any(|signature| covered_type(signature) == record_type)
+    };
+    let mut signatures = presigned.to_vec();
+    for rrset in rrsets(&records) {
+        if !authoritative(rrset[0]) || is_zonemd(rrset[0]) || is_presigned(rrset[0]) {
+            continue;
+        }
+        for key in keys
+            .iter()
+            .filter(|key| key.role.signs(&rrset[0].record_type))
+        {
+            signatures.push(sign_rrset(key, &rrset, origin, inception, expiration)?);
+        }
+    }
+    records.extend(signatures);
+
+    if records.iter().any(is_zonemd) {
+        zonemd::update(origin, &mut records);
+        let rrset: Vec<&DnsRecord> = records.iter().filter(|record| is_zonemd(record)).collect();
+        let signatures = keys
+            .iter()
+            .filter(|key| key.role.signs(&RecordType::ZONEMD))
+            .map(|key| sign_rrset(key, &rrset, origin, inception, expiration))
+            .collect::<Result<Vec<_>>>()?;
+        records.extend(signatures);
+    }
+
+    Zone::new(origin.clone(), records)
+}
+
+/// Groups records of the same owner and type
+pub(crate) fn rrsets(records: &[DnsRecord]) -> Vec<Vec<&DnsRecord>> {
+    let mut rrsets: Vec<Vec<&DnsRecord>> = Vec::new();
+    for record in records {
+        let rrset = rrsets.iter_mut().rev().find(|rrset| {
+            rrset[0].record_type == record.record_type
+                && rrset[0].domain_name.eq_ignore_case(&record.domain_name)
+        });
+        match rrset {
+            Some(rrset) => rrset.push(record),
+            None => rrsets.push(vec![record]),
+        }
+    }
+    rrsets
+}
+
+/// RRSIG record of `rrset` made by `key` of zone `signer` (RFC 4034 section 3)
+fn sign_rrset(
+    key: &SigningKey,
+    rrset: &[&DnsRecord],
+    signer: &DomainName,
+    inception: u32,
+    expiration: u32,
+) -> Result<DnsRecord> {
+    let first = rrset[0];
+    let owner = &first.domain_name;
+    // wildcard label is not counted
+    let labels = owner.label_count() - usize::from(owner.labels().next() == Some(b"*"));
+
+    let mut data = Vec::new();
+    data.put_u16(first.record_type.clone().into());
+    data.put_u8(key.algorithm as u8);
+    data.put_u8(labels as u8);
+    data.put_u32(first.ttl);
+    data.put_u32(expiration);
+    data.put_u32(inception);
+    data.put_u16(key.key_tag());
+    data.extend(name_bytes(&signer.canonicalize()));
+
+    let signature = key.sign(&signed_data(&data, rrset))?;
+    data.extend(signature);
+    Ok(DnsRecord::new(
+        owner.clone(),
+        RecordType::RRSIG,
+        first.class.clone(),
+        first.ttl,
+        RecordData::Unknown(data),
+    ))
+}
+
+/// RRSIG RDATA without the signature followed by the RRset in canonical form
+/// (RFC 4034 section 3.1.8.1)
+pub(crate) fn signed_data(rrsig: &[u8], rrset: &[&DnsRecord]) -> Vec<u8> {
+    let first = rrset[0];
+    let owner = name_bytes(&first.domain_name.canonicalize());
+
+    let mut rdatas: Vec<Vec<u8>> = rrset
+        .iter()
+        .map(|record| canonical_rdata(&record.data))
+        .collect();
+    rdatas.sort();
+    rdatas.dedup();
+
+    let mut data = rrsig.to_vec();
+    for rdata in rdatas {
+        data.extend_from_slice(&owner);
+        data.put_u16(first.record_type.clone().into());
+        data.put_u16(first.class.clone().into());
+        data.put_u32(first.ttl);
+        data.put_u16(rdata.len() as u16);
+        data.extend(rdata);
+    }
+    data
+}
+
+/// RDATA with embedded names lowercased (RFC 4034 section 6.2)
+pub(crate) fn canonical_rdata(data: &RecordData) -> Vec<u8> {
+    let canonical = match data {
+        RecordData::Name(name) => RecordData::Name(name.canonicalize()),
+        RecordData::Mx {
+            preference,
+            exchange,
+        } => RecordData::Mx {
+            preference: *preference,
+            exchange: exchange.canonicalize(),
+        },
+        RecordData::Soa(soa) => RecordData::Soa(Soa {
+            mname: soa.mname.canonicalize(),
+            rname: soa.rname.canonicalize(),
+            ..soa.clone()
+        }),
+        data => data.clone(),
+    };
+    canonical.to_bytes().to_vec()
+}
+
+/// Uncompressed wire format of the name
+pub(crate) fn name_bytes(name: &DomainName) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    name.write_bytes(&mut buf, &mut LookupTable::new(0));
+    buf.to_vec()
+}
+
+/// Type bit maps field of NSEC (RFC 4034 section 4.1.2)
+fn type_bitmap(types: &mut [u16]) -> Vec<u8> {
+    types.sort_unstable();
+    let mut bitmap = Vec::new();
+    for window in types.chunk_by(|a, b| a >> 8 == b >> 8) {
+        let mut bits = vec![0u8; (window[window.len() - 1] & 0xFF) as usize / 8 + 1];
+        for record_type in window {
+            let bit = (record_type & 0xFF) as usize;
+            bits[bit / 8] |= 0x80 >> (bit % 8);
+        }
+        bitmap.push((window[0] >> 8) as u8);
+        bitmap.push(bits.len() as u8);
+        bitmap.extend(bits);
+    }
+    bitmap
+}
+
+/// Signature time, seconds since the epoch modulo 2^32 (RFC 4034 section 3.1.5)
+pub(crate) fn timestamp(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = "$ORIGIN example.
+@    IN SOA ns1 hostmaster 1 7200 900 1209600 300
+     IN NS  ns1
+ns1  IN A   192.0.2.1
+www  IN CNAME ns1
+sub  IN NS  ns.sub
+ns.sub IN A 192.0.2.2
+";
+
+    fn key(algorithm: Algorithm, role: KeyRole) -> SigningKey {
+        SigningKey::from_pkcs8(algorithm, role, &generate(algorithm).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_key_tag_and_ds() {
+        // DNSKEY of RFC 6605 section 6.1
+        let public_key = base64::decode(
+            "GojIhhXUN/u4v54ZQqGSnyhWJwaubCvTmeexv7bR6edbkrSqQpF64cYbcB7wNcP+e+MAnLr+Wi9xMWyQLc8NAA==",
+        )
+        .unwrap();
+        let mut key = key(Algorithm::EcdsaP256Sha256, KeyRole::Csk);
+        key.dnskey.truncate(4);
+        key.dnskey.extend(public_key);
+
+        assert_eq!(key.key_tag(), 55648);
+        assert_eq!(
+            key.ds(&"example.net".into()),
+            "55648 13 2 B4C8C1FE2E7477127B27115656AD6256F424625BF5C1E2770CE6D6E37DF61D17"
+        );
+    }
+
+    #[test]
+    fn test_type_bitmap() {
+        // RFC 4034 section 4.3: A MX RRSIG NSEC TYPE1234
+        let mut types = [1, 15, 46, 47, 1234];
+        let bitmap = type_bitmap(&mut types);
+        let mut expected = vec![0x00, 0x06, 0x40, 0x01, 0x00, 0x00, 0x00, 0x03, 0x04, 0x1b];
+        expected.extend([0; 26]);
+        expected.push(0x20);
+        assert_eq!(bitmap, expected);
+    }
+
+    #[test]
+    fn test_zone_is_dual_signed() {
+        let zone = Zone::parse(ZONE).unwrap();
+        let keys = [
+            key(Algorithm::EcdsaP256Sha256, KeyRole::Ksk),
+            key(Algorithm::EcdsaP256Sha256, KeyRole::Zsk),
+            key(Algorithm::Ed25519, KeyRole::Csk),
+        ];
+        let signed = sign_zone(&zone, &keys, SystemTime::now()).unwrap();
+
+        let count = |name: &str, record_type: RecordType| {
+            signed
+                .records_at(&name.into())
+                .filter(|record| record.record_type == record_type)
+                .count()
+        };
+        assert_eq!(count("example", RecordType::DNSKEY), 3);
+        // DNSKEY by KSK and CSK, SOA by ZSK and CSK, and so on
+        assert_eq!(count("example", RecordType::RRSIG), 2 * 4);
+        assert_eq!(count("www.example", RecordType::RRSIG), 2 * 2);
+        // delegation: only NSEC is signed, glue is not in the chain at all
+        assert_eq!(count("sub.example", RecordType::RRSIG), 2);
+        assert_eq!(count("ns.sub.example", RecordType::RRSIG), 0);
+        assert_eq!(count("ns.sub.example", RecordType::NSEC), 0);
+
+        let nsec: Vec<&DnsRecord> = signed
+            .records()
+            .iter()
+            .filter(|record| record.record_type == RecordType::NSEC)
+            .collect();
+        let owners: Vec<String> = nsec.iter().map(|r| r.domain_name.to_string()).collect();
+        assert_eq!(
+            owners,
+            ["example.", "ns1.example.", "sub.example.", "www.example."]
+        );
+        // last one points back to the apex
+        assert!(nsec[3]
+            .data
+            .to_bytes()
+            .starts_with(&name_bytes(&"example".into())));
+
+        // every signature verifies with the public key of its key tag
+        for rrsig in signed
+            .records()
+            .iter()
+            .filter(|record| record.record_type == RecordType::RRSIG)
+        {
+            let RecordData::Unknown(data) = &rrsig.data else {
+                panic!("RRSIG is opaque");
+            };
+            let covered = RecordType::from(u16::from_be_bytes([data[0], data[1]]));
+            let rrset: Vec<&DnsRecord> = signed
+                .records_at(&rrsig.domain_name)
+                .filter(|record| record.record_type == covered)
+                .collect();
+            let key_tag = u16::from_be_bytes([data[16], data[17]]);
+            let key = keys.iter().find(|key| key.key_tag() == key_tag).unwrap();
+            let prefix_len = 18 + name_bytes(&"example".into()).len();
+            let (prefix, signature) = data.split_at(prefix_len);
+
+            let public_key = match key.algorithm {
+                Algorithm::EcdsaP256Sha256 => [&[4][..], &key.dnskey[4..]].concat(),
+                Algorithm::Ed25519 => key.dnskey[4..].to_vec(),
+            };
+            let verification: &dyn ring::signature::VerificationAlgorithm = match key.algorithm {
+                Algorithm::EcdsaP256Sha256 => &ECDSA_P256_SHA256_FIXED,
+                Algorithm::Ed25519 => &ED25519,
+            };
+            UnparsedPublicKey::new(verification, public_key)
+                .verify(&signed_data(prefix, &rrset), signature)
+                .unwrap_or_else(|_| panic!("bad signature of {}", rrsig));
+        }
+    }
+
+    #[test]
+    fn test_cds_follows_key_signing_keys() {
+        let keys = [
+            key(Algorithm::EcdsaP256Sha256, KeyRole::Ksk),
+            key(Algorithm::EcdsaP256Sha256, KeyRole::Zsk),
+        ];
+        let zone = publish_cds(&Zone::parse(ZONE).unwrap(), &keys).unwrap();
+        let signed = sign_zone(&zone, &keys, SystemTime::now()).unwrap();
+
+        let origin = signed.origin();
+        let cds: Vec<&DnsRecord> = signed
+            .records_at(origin)
+            .filter(|record| record.record_type == RecordType::CDS)
+            .collect();
+        assert_eq!(cds.len(), 1);
+        assert_eq!(cds[0].data, RecordData::Unknown(keys[0].ds_rdata(origin)));
+        // signed by the KSK only
+        let signatures = signed.signatures(origin, &RecordType::CDNSKEY);
+        assert_eq!(signatures.len(), 1);
+        let RecordData::Unknown(data) = &signatures[0].data else {
+            panic!("RRSIG is opaque");
+        };
+        assert_eq!(data[16..18], keys[0].key_tag().to_be_bytes());
+    }
+
+    #[test]
+    fn test_key_set_signed_offline() {
+        let origin = DomainName::from("example.");
+        let ksk = key(Algorithm::EcdsaP256Sha256, KeyRole::Ksk);
+        let zsk = key(Algorithm::EcdsaP256Sha256, KeyRole::Zsk);
+        let dnskeys = [ksk.dnskey(&origin, 3600), zsk.dnskey(&origin, 3600)];
+        let rrset: Vec<&DnsRecord> = dnskeys.iter().collect();
+        let (inception, expiration) = (timestamp(SystemTime::now()), u32::MAX);
+        let rrsig = sign_rrset(&ksk, &rrset, &origin, inception, expiration).unwrap();
+        let text = format!("{}\n{}\n{}\n", dnskeys[0], dnskeys[1], rrsig);
+
+        let key_set = KeySet::parse(&text, &origin).unwrap();
+        let zone = Zone::parse(ZONE).unwrap();
+        let signed = sign_zone_with_key_set(&zone, &[zsk], &key_set, SystemTime::now()).unwrap();
+        assert_eq!(signed.signatures(&origin, &RecordType::DNSKEY), [rrsig]);
+        assert_eq!(
+            signed
+                .signatures(&"ns1.example.".into(), &RecordType::A)
+                .len(),
+            1
+        );
+
+        // KSK stays offline, the server signs with ZSKs only
+        let other = key(Algorithm::EcdsaP256Sha256, KeyRole::Zsk);
+        assert!(sign_zone_with_key_set(&zone, &[other], &key_set, SystemTime::now()).is_err());
+        let tampered = text.replacen(&dnskeys[1].to_string(), "", 1);
+        assert!(KeySet::parse(&tampered, &origin).is_err());
+    }
+
+    #[test]
+    fn test_key_specs() {
+        let spec: KeySpec = "ksk:ed25519:/var/lib/dns/ksk.pem".parse().unwrap();
+        assert_eq!(spec.role, KeyRole::Ksk);
+        assert_eq!(spec.algorithm, Algorithm::Ed25519);
+        assert_eq!(spec.path, PathBuf::from("/var/lib/dns/ksk.pem"));
+
+        let spec: KeySpec = "ECDSAP256SHA256:key.pem".parse().unwrap();
+        assert_eq!(spec.role, KeyRole::Csk);
+        assert!("rsasha256:key.pem".parse::<KeySpec>().is_err());
+    }
+}
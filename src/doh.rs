@@ -0,0 +1,410 @@
+/*
+JSON API of DNS over HTTPS (application/dns-json)
+
+De-facto format used by Google and Cloudflare public resolvers:
+  GET /resolve?name=example.com&type=A
+
+It is not standardized (RFC 8484 DoH is served by crate::https), but it is
+handy for debugging with curl and for simple web clients. The listener of
+--json-api speaks plain HTTP/1.1, TLS is expected to be terminated in front
+of it. The HTTPS listener answers the same requests.
+
+Each connection is handled in its own thread, within the same connection
+limits as the TCP listener (see crate::connections). The whole request,
+PROXY header included, must arrive within the message timeout and its
+request line and headers are capped in size, so slow or endless requests
+can't hold the listener.
+*/
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::connections::{Connection, ConnectionLimits, ConnectionTracker, Deadline};
+use crate::domain_name::DomainName;
+use crate::handler::{response_builder, Request};
+use crate::header::ResponseCode;
+use crate::listen;
+use crate::log;
+use crate::packet::DnsPacket;
+use crate::question::{DnsQuestion, QueryClass, QueryType};
+use crate::trace;
+
+/// Path of JSON queries
+pub const PATH: &str = "/resolve";
+
+pub(crate) const CONTENT_TYPE: &str = "application/dns-json";
+
+/// Longest request line accepted, a name percent-encoded in full fits many times over
+const MAX_REQUEST_LINE: usize = 4096;
+
+/// Most bytes of headers accepted, all of them together
+const MAX_HEADERS_SIZE: usize = 16 * 1024;
+
+/// JSON answer
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct JsonResponse {
+    status: u8,
+    #[serde(rename = "TC")]
+    tc: bool,
+    #[serde(rename = "RD")]
+    rd: bool,
+    #[serde(rename = "RA")]
+    ra: bool,
+    #[serde(rename = "AD")]
+    ad: bool,
+    #[serde(rename = "CD")]
+    cd: bool,
+    question: Vec<JsonQuestion>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    answer: Vec<JsonRecord>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonQuestion {
+    name: String,
+    #[serde(rename = "type")]
+    query_type: u16,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonRecord {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    data: String,
+}
+
+impl From<&DnsPacket> for JsonResponse {
+    fn from(packet: &DnsPacket) -> Self {
+        Self {
+            status: packet.header.rescode as u8,
+            tc: packet.header.truncated_message,
+            rd: packet.header.recursion_desired,
+            ra: packet.header.recursion_available,
+            ad: packet.header.authed_data,
+            cd: packet.header.checking_disabled,
+            question: packet
+                .questions
+                .iter()
+                .map(|q| JsonQuestion {
+                    name: q.domain_name.to_string(),
+                    query_type: q.query_type.clone().into(),
+                })
+                .collect(),
+            answer: packet
+                .answers
+                .iter()
+                .map(|r| JsonRecord {
+                    name: r.domain_name.to_string(),
+                    record_type: r.record_type.clone().into(),
+                    ttl: r.ttl,
+                    data: r.data.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Serves `GET /resolve` requests, every query is answered by `handler`
+///
+/// With `proxy_protocol`, every connection must start with PROXY protocol v2 header
+/// (see [`crate::proxy_protocol`]) and the client address is taken from it.
+pub fn serve<F>(
+    address: &str,
+    proxy_protocol: bool,
+    limits: ConnectionLimits,
+    handler: F,
+) -> Result<()>
+where
+    F: Fn(&Request) -> Result<DnsPacket> + Send + Sync + 'static,
+{
+    let listener = listen::tcp(address)
+        .with_context(|| format!("Failed to bind JSON API listener to {}", address))?;
+
+    println!(
+        "JSON API ({}) listening on {}{}",
+        CONTENT_TYPE, address, PATH
+    );
+
+    let handler = Arc::new(handler);
+    let tracker = ConnectionTracker::new(limits);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("JSON API: error accepting connection: {}", e);
+                continue;
+            }
+        };
+        let connection = match tracker.admit(&stream) {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("JSON API: refused connection: {:#}", e);
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        thread::spawn(move || {
+            let handled = handle_connection(stream, &connection, proxy_protocol, handler.as_ref());
+            if let Err(e) = handled {
+                eprintln!("JSON API: error handling request: {:#}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    connection: &Connection,
+    proxy_protocol: bool,
+    handler: &impl Fn(&Request) -> Result<DnsPacket>,
+) -> Result<()> {
+    let mut client = stream.peer_addr().ok();
+    let mut request = Deadline::new(&stream, connection.message_timeout());
+
+    if proxy_protocol {
+        if let Some(source) = crate::proxy_protocol::read_header(&mut request)? {
+            client = Some(source);
+        }
+    }
+
+    let mut reader = BufReader::new(request);
+    let request_line = read_line(&mut reader, MAX_REQUEST_LINE)?;
+
+    // skip headers
+    let mut headers_size = 0;
+    loop {
+        let line = read_line(&mut reader, MAX_HEADERS_SIZE - headers_size)
+            .context("reading request headers")?;
+        if line.trim_end().is_empty() {
+            break;
+        }
+        headers_size += line.len();
+    }
+
+    connection.busy();
+
+    let (status, body) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", target, _version] => match answer(target, client, handler) {
+            (200, body) => ("200 OK", body),
+            (_, body) => ("400 Bad Request", body),
+        },
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+
+    write!(
+        &stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+/// Reads a line of at most `limit` bytes, line ending included
+fn read_line(reader: &mut impl BufRead, limit: usize) -> Result<String> {
+    let mut line = Vec::new();
+    reader.take(limit as u64).read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\n") {
+        if line.len() >= limit {
+            anyhow::bail!("line longer than {} bytes", limit);
+        }
+        anyhow::bail!("connection closed in the middle of the request");
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// HTTP status and JSON body answering `GET <target>`
+pub(crate) fn answer(
+    target: &str,
+    client: Option<SocketAddr>,
+    handler: &impl Fn(&Request) -> Result<DnsPacket>,
+) -> (u16, String) {
+    match resolve(target, client, handler) {
+        Ok(body) => (200, body),
+        Err(e) => (
+            400,
+            serde_json::json!({ "error": e.to_string() }).to_string(),
+        ),
+    }
+}
+
+/// JSON answer to the query in `target`, errors are the client's
+///
+/// Failures of the handler are answered with SERVFAIL, as over the other transports.
+fn resolve(
+    target: &str,
+    client: Option<SocketAddr>,
+    handler: &impl Fn(&Request) -> Result<DnsPacket>,
+) -> Result<String> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != PATH {
+        anyhow::bail!("unknown path {}", path);
+    }
+
+    let mut name = None;
+    let mut query_type = QueryType::A;
+    let mut checking_disabled = false;
+
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        let value = percent_decode(value);
+        match key {
+            "name" => name = Some(value),
+            "type" => query_type = value.parse()?,
+            "cd" => checking_disabled = value == "1" || value == "true",
+            _ => {}
+        }
+    }
+
+    let name = name.context("missing name parameter")?;
+
+    let mut query = DnsPacket::builder()
+        .id(rand::random())
+        .recursion_desired(true)
+        .question(DnsQuestion::new(
+            DomainName::from(name.as_str()),
+            query_type,
+            QueryClass::IN,
+        ))
+        .build();
+    query.header.checking_disabled = checking_disabled;
+
+    let mut span = trace::query_span("https", &query, client);
+    let query_log = log::query("https");
+    let request = Request::new(query, client);
+    let response = match span.record(handler(&request)) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("JSON API: failed to answer query: {:#}", e);
+            response_builder(&request.query)
+                .rescode(ResponseCode::SERVFAIL)
+                .build()
+        }
+    };
+    span.response(&response);
+    query_log.finish(client, &response);
+
+    let _span = trace::span("dns.serialize");
+    Ok(serde_json::to_string(&JsonResponse::from(&response))?)
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    /// Client end of a new connection and the server end admitted within `limits`
+    fn connect(limits: ConnectionLimits) -> (TcpStream, TcpStream, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let connection = ConnectionTracker::new(limits).admit(&server).unwrap();
+        (client, server, connection)
+    }
+
+    fn unreachable_handler(_: &Request) -> Result<DnsPacket> {
+        unreachable!("request must be rejected before it is answered")
+    }
+
+    #[test]
+    fn test_failed_queries_are_answered_with_servfail() {
+        let failing = |_: &Request| -> Result<DnsPacket> { anyhow::bail!("upstream unreachable") };
+        let (status, body) = answer("/resolve?name=example.com", None, &failing);
+        assert_eq!(status, 200);
+        assert!(body.contains("\"Status\":2"), "{}", body);
+
+        let (status, body) = answer("/resolve?name=example.com&type=BOGUS", None, &failing);
+        assert_eq!(status, 400);
+        assert!(body.contains("error"), "{}", body);
+        assert_eq!(answer("/query?name=a", None, &failing).0, 400);
+    }
+
+    #[test]
+    fn test_oversized_request_line_is_rejected() {
+        let (mut client, server, connection) = connect(ConnectionLimits::default());
+        let line = format!(
+            "GET /resolve?name={} HTTP/1.1",
+            "a".repeat(MAX_REQUEST_LINE)
+        );
+        client.write_all(line.as_bytes()).unwrap();
+
+        let error =
+            handle_connection(server, &connection, false, &unreachable_handler).unwrap_err();
+        assert!(error.to_string().contains("longer than"), "{:#}", error);
+    }
+
+    #[test]
+    fn test_trickled_request_times_out() {
+        let (mut client, server, connection) = connect(ConnectionLimits {
+            message_timeout: Duration::from_millis(200),
+            ..ConnectionLimits::default()
+        });
+        client
+            .write_all(b"GET /resolve?name=example.com HTTP/1.1\r\n")
+            .unwrap();
+        thread::spawn(move || {
+            // every byte arrives in time on its own, the whole request doesn't
+            for _ in 0..100 {
+                thread::sleep(Duration::from_millis(20));
+                if client.write_all(b"X").is_err() {
+                    break;
+                }
+            }
+        });
+
+        let started = Instant::now();
+        let error =
+            handle_connection(server, &connection, false, &unreachable_handler).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(
+            format!("{:#}", error).contains("not received in time"),
+            "{:#}",
+            error
+        );
+    }
+}
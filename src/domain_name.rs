@@ -1,157 +1,768 @@
-#[derive(Debug, Clone, PartialEq)]
-pub struct DomainName(String);
+use std::fmt;
+use std::net::IpAddr;
+
+use bytes::{BufMut, BytesMut};
+use smallvec::SmallVec;
+
+use crate::idn;
+use crate::packet::{ensure_remaining, ParseError};
+
+/// Maximum length of a name in wire format, including length octets and the root label
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Maximum length of a label (RFC 1035 section 2.3.4)
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// Names up to this many octets in wire format are stored without heap allocation
+const INLINE_NAME_LENGTH: usize = 32;
+
+/// Domain name stored as a sequence of raw labels (without the terminating root label)
+///
+/// Labels are kept as bytes, so names containing dots, NULs or non-UTF8 bytes
+/// inside a label round-trip unchanged between the wire and this representation.
+///
+/// Labels are stored one after another in wire format (length octet and the label), most
+/// names inline, so reading, cloning and writing them usually doesn't allocate.
+#[derive(Clone, PartialEq, Eq, Hash, Default)]
+pub struct DomainName(SmallVec<[u8; INLINE_NAME_LENGTH]>);
+
+/// Labels of wire format `labels` (without pointers and the root label)
+fn split_labels(mut labels: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        let (&len, rest) = labels.split_first()?;
+        let (label, rest) = rest.split_at(len as usize);
+        labels = rest;
+        Some(label)
+    })
+}
 
 impl DomainName {
     pub fn new() -> Self {
-        Self(String::new())
+        Self(SmallVec::new())
+    }
+
+    /// Creates [`DomainName`] from raw labels (root label must not be included)
+    pub fn from_labels<I, L>(labels: I) -> Self
+    where
+        I: IntoIterator<Item = L>,
+        L: AsRef<[u8]>,
+    {
+        let mut domain_name = Self::new();
+        for label in labels {
+            domain_name.push_label(label);
+        }
+        domain_name
+    }
+
+    /// Iterates over raw labels, starting with the leftmost one
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item = &[u8]> + ExactSizeIterator {
+        split_labels(&self.0)
+            .collect::<SmallVec<[&[u8]; 8]>>()
+            .into_iter()
+    }
+
+    /// Number of labels (root label is not counted)
+    pub fn label_count(&self) -> usize {
+        split_labels(&self.0).count()
+    }
+
+    /// Root domain "."
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Appends label, labels longer than 63 octets (invalid anyway) are cut
+    pub fn push_label(&mut self, label: impl AsRef<[u8]>) {
+        let label = label.as_ref();
+        let label = &label[..label.len().min(MAX_LABEL_LENGTH)];
+        self.0.push(label.len() as u8);
+        self.0.extend_from_slice(label);
+    }
+
+    /// Compares names ASCII case-insensitively (RFC 4343)
+    pub fn eq_ignore_case(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len()
+            && split_labels(&self.0)
+                .zip(split_labels(&other.0))
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+
+    /// Canonical form of the name (lowercase, fully qualified), suitable as a key for lookups
+    pub fn canonicalize(&self) -> Self {
+        let mut canonical = self.clone();
+        let mut pos = 0;
+        while pos < canonical.0.len() {
+            let end = pos + 1 + canonical.0[pos] as usize;
+            canonical.0[pos + 1..end].make_ascii_lowercase();
+            pos = end;
+        }
+        canonical
+    }
+
+    /// Canonical DNS name order (RFC 4034 section 6.1), labels compared from the rightmost one
+    pub fn canonical_cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.labels()
+            .rev()
+            .map(<[u8]>::to_ascii_lowercase)
+            .cmp(other.labels().rev().map(<[u8]>::to_ascii_lowercase))
+    }
+
+    /// Returns true if the name is equal to `parent` or lies below it (case-insensitive)
+    pub fn is_subdomain_of(&self, parent: &Self) -> bool {
+        self.0.len() >= parent.0.len()
+            && self
+                .labels()
+                .rev()
+                .zip(parent.labels().rev())
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+
+    /// Name of the PTR record for the address, in `in-addr.arpa` or `ip6.arpa` (RFC 3596)
+    pub fn reverse(address: IpAddr) -> Self {
+        let labels: Vec<String> = match address {
+            IpAddr::V4(address) => address
+                .octets()
+                .iter()
+                .rev()
+                .map(u8::to_string)
+                .chain(["in-addr".into(), "arpa".into()])
+                .collect(),
+            IpAddr::V6(address) => address
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|octet| [octet & 0x0F, octet >> 4])
+                .map(|nibble| format!("{:x}", nibble))
+                .chain(["ip6".into(), "arpa".into()])
+                .collect(),
+        };
+        Self::from_labels(labels.into_iter().map(String::into_bytes))
+    }
+
+    /// Parses presentation format (as the conversion from `&str`) with Unicode labels
+    /// converted to A-labels (xn--...), trailing dot is optional
+    ///
+    /// Unlike the conversion from `&str`, names which can't be encoded are rejected: empty
+    /// labels, labels longer than 63 octets, names longer than 255 octets and `\DDD` escapes
+    /// above 255. Names from configuration and zone files are parsed this way.
+    pub fn from_unicode(s: &str) -> anyhow::Result<Self> {
+        let mut domain_name = Self::new();
+        if s == "." {
+            return Ok(domain_name);
+        }
+
+        let mut label = Vec::new();
+        let mut unicode = false;
+        let mut chars = s.chars();
+        loop {
+            let c = chars.next();
+            match c {
+                Some('\\') => match chars.next() {
+                    Some(digit) if digit.is_ascii_digit() => {
+                        // \DDD
+                        let digits = [Some(digit), chars.next(), chars.next()];
+                        let value = digits.iter().try_fold(0u16, |value, digit| {
+                            let digit = digit.and_then(|digit| digit.to_digit(10))?;
+                            Some(value * 10 + digit as u16)
+                        });
+                        match value.and_then(|value| u8::try_from(value).ok()) {
+                            Some(byte) => label.push(byte),
+                            None => anyhow::bail!("invalid escape in name {:?}", s),
+                        }
+                    }
+                    Some(escaped) => {
+                        unicode |= !escaped.is_ascii();
+                        label.extend_from_slice(escaped.encode_utf8(&mut [0; 4]).as_bytes());
+                    }
+                    None => anyhow::bail!("name {:?} ends with an escape", s),
+                },
+                Some(c) if !idn::LABEL_SEPARATORS.contains(&c) => {
+                    unicode |= !c.is_ascii();
+                    label.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                // separator or end of the name
+                _ => {
+                    if label.is_empty() {
+                        match c {
+                            // trailing dot
+                            None if !domain_name.is_root() => break,
+                            _ => anyhow::bail!("empty label in name {:?}", s),
+                        }
+                    }
+                    let mut ascii = std::mem::take(&mut label);
+                    if std::mem::take(&mut unicode) {
+                        let unicode = String::from_utf8(ascii)
+                            .map_err(|_| anyhow::anyhow!("invalid label in name {:?}", s))?;
+                        ascii = idn::label_to_ascii(&unicode)
+                            .ok_or_else(|| {
+                                anyhow::anyhow!("invalid internationalized label {:?}", unicode)
+                            })?
+                            .into_bytes();
+                    }
+                    if ascii.len() > MAX_LABEL_LENGTH {
+                        anyhow::bail!("label longer than 63 octets in name {:?}", s);
+                    }
+                    domain_name.push_label(ascii);
+                    if c.is_none() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if domain_name.0.len() + 1 > MAX_NAME_LENGTH {
+            anyhow::bail!("name {:?} is longer than 255 octets", s);
+        }
+        Ok(domain_name)
     }
 
-    pub fn read_bytes(&mut self, buf: &mut impl bytes::Buf, lookup_table: &mut LookupTable) {
+    /// Presentation format with A-labels (xn--...) shown as Unicode U-labels
+    pub fn to_unicode(&self) -> String {
+        format!("{:#}", self)
+    }
+
+    /// Reads labels from wire format, compression pointers are resolved via `lookup_table`
+    pub fn read_bytes(
+        &mut self,
+        buf: &mut impl bytes::Buf,
+        lookup_table: &mut LookupTable,
+    ) -> Result<(), ParseError> {
+        let start = lookup_table.read_position(buf);
+        let first_label = self.0.len();
+
         loop {
             // length of label
+            ensure_remaining(buf, 1)?;
             let len = buf.get_u8();
 
             if len == 0 {
                 // end of domain name -> store into lookup table
-                lookup_table.insert(self);
+                lookup_table.insert_read(&self.0[first_label..], start);
                 break;
             }
 
             // Label with pointer -> get from lookup table
             if (len & 0xC0) == 0xC0 {
                 // two MSB 0xC000 (in binary 11000000) marks pointer
+                let pointer_pos = lookup_table.read_position(buf).saturating_sub(1);
+                ensure_remaining(buf, 1)?;
                 let next_byte = buf.get_u8() as u16;
                 let pos = (((len as u16) ^ 0xC0) << 8) | next_byte;
 
-                let label = lookup_table
-                    .decompress(pos)
-                    .expect("label should be stored in lookup table in previous steps");
-
-                self.0.push_str(label);
+                let literal_end = self.0.len();
+                lookup_table.decompress(pos, pointer_pos, self)?;
+                self.ensure_max_length()?;
+                lookup_table.insert_read(&self.0[first_label..literal_end], start);
 
                 break;
             }
 
-            for _i in 0..len {
-                // read one label
-                let c = buf.get_u8() as char;
-                self.0.push(c);
+            if (len & 0xC0) != 0 {
+                return Err(ParseError::InvalidLabel(len)); // extended label types are obsolete
             }
-            self.0.push('.');
+
+            // read one label
+            ensure_remaining(buf, len as usize)?;
+            let label_start = self.0.len() + 1;
+            self.0.push(len);
+            self.0.resize(label_start + len as usize, 0);
+            buf.copy_to_slice(&mut self.0[label_start..]);
+            self.ensure_max_length()?;
         }
+
+        Ok(())
     }
 
-    pub fn from_bytes(buf: &mut impl bytes::Buf, lookup_table: &mut LookupTable) -> Self {
+    /// Creates [`DomainName`] from wire format
+    pub fn from_bytes(
+        buf: &mut impl bytes::Buf,
+        lookup_table: &mut LookupTable,
+    ) -> Result<Self, ParseError> {
         let mut domain_name = Self::new();
-        domain_name.read_bytes(buf, lookup_table);
-        domain_name
+        domain_name.read_bytes(buf, lookup_table)?;
+        Ok(domain_name)
     }
 
-    pub fn write_bytes(&self, buf: &mut impl bytes::BufMut, lookup_table: &mut LookupTable) {
-        if let Some(&pos) = lookup_table.compress(self) {
-            // two MSB 0xC000 (in binary 11000000 00000000) marks pointer
-            let pos = pos | 0xC000;
-            buf.put_u16(pos);
-            return;
+    /// Drops the labels past the 255 octets a name can have in wire format
+    fn truncate_to_max_length(&mut self) {
+        let mut end = 0;
+        for label in split_labels(&self.0) {
+            // the root label needs one more octet
+            if end + 1 + label.len() + 1 > MAX_NAME_LENGTH {
+                break;
+            }
+            end += 1 + label.len();
+        }
+        self.0.truncate(end);
+    }
+
+    /// Names are limited to 255 octets in wire format (RFC 1035 section 2.3.4)
+    pub(crate) fn ensure_max_length(&self) -> Result<(), ParseError> {
+        if self.0.len() + 1 > MAX_NAME_LENGTH {
+            return Err(ParseError::NameTooLong);
         }
+        Ok(())
+    }
+
+    /// Writes wire format, the longest suffix written before is replaced by a pointer to it
+    pub fn write_bytes(&self, buf: &mut BytesMut, lookup_table: &mut LookupTable) {
+        let start = buf.len();
 
-        for label in self.0.split('.') {
-            let len = label.len() as u8;
-            buf.put_u8(len);
-            buf.put(label.as_bytes());
+        let mut literal_end = 0;
+        while literal_end < self.0.len() {
+            if let Some(pos) = lookup_table.compress(&self.0[literal_end..], buf) {
+                buf.put_slice(&self.0[..literal_end]);
+                // two MSB 0xC000 (in binary 11000000 00000000) marks pointer
+                buf.put_u16(pos | 0xC000);
+                lookup_table.insert_written(&self.0, start, literal_end);
+                return;
+            }
+            literal_end += 1 + self.0[literal_end] as usize;
         }
+        buf.put_slice(&self.0);
+        buf.put_u8(0); // root label
 
-        lookup_table.insert(self);
+        lookup_table.insert_written(&self.0, start, self.0.len());
+    }
+}
+
+/// Presentation format (RFC 1035 section 5.1), always fully qualified with trailing dot
+///
+/// Dots and backslashes inside a label are escaped as `\.` and `\\`,
+/// non-printable bytes as `\DDD` (decimal).
+///
+/// Alternate flag (`{:#}`) displays A-labels (xn--...) as Unicode U-labels, unless they don't
+/// convert back or aren't safe to show (see [`idn::label_to_unicode`]), those stay escaped.
+impl fmt::Display for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return f.write_str(".");
+        }
+
+        for label in self.labels() {
+            if f.alternate() {
+                if let Some(unicode) = idn::label_to_unicode(label) {
+                    write!(f, "{}.", unicode)?;
+                    continue;
+                }
+            }
+
+            for &b in label {
+                match b {
+                    b'.' | b'\\' => write!(f, "\\{}", b as char)?,
+                    0x21..=0x7E => write!(f, "{}", b as char)?,
+                    _ => write!(f, "\\{:03}", b)?,
+                }
+            }
+            f.write_str(".")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Pretty-printed debug output (`{:#?}`) shows U-labels
+impl fmt::Debug for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = if f.alternate() {
+            self.to_unicode()
+        } else {
+            self.to_string()
+        };
+
+        f.debug_tuple("DomainName").field(&name).finish()
     }
 }
 
 impl From<String> for DomainName {
     fn from(s: String) -> Self {
-        Self(s)
+        Self::from(s.as_str())
     }
 }
 
+/// Parses presentation format, trailing dot is optional
+///
+/// The conversion never fails, names which can't be encoded are cut instead: labels to 63
+/// octets and names to the leading labels fitting into 255 octets. Use
+/// [`DomainName::from_unicode`] to have them rejected.
 impl From<&str> for DomainName {
     fn from(s: &str) -> Self {
-        Self(String::from(s))
+        let mut domain_name = Self::new();
+        let mut label = Vec::new();
+        let bytes = s.as_bytes();
+        let mut i = 0;
+
+        while i < bytes.len() {
+            match bytes[i] {
+                b'.' => {
+                    if !label.is_empty() {
+                        domain_name.push_label(std::mem::take(&mut label));
+                    }
+                }
+                b'\\' => {
+                    let rest = &bytes[i + 1..];
+                    let escaped = rest
+                        .get(..3)
+                        .filter(|digits| digits.iter().all(u8::is_ascii_digit))
+                        .map(|digits| digits.iter().fold(0u16, |n, d| n * 10 + (d - b'0') as u16))
+                        .and_then(|n| u8::try_from(n).ok());
+                    if let Some(byte) = escaped {
+                        // \DDD
+                        label.push(byte);
+                        i += 3;
+                    } else if let Some(&c) = rest.first() {
+                        // \X
+                        label.push(c);
+                        i += 1;
+                    }
+                }
+                b => label.push(b),
+            }
+            i += 1;
+        }
+
+        if !label.is_empty() {
+            domain_name.push_label(label);
+        }
+
+        domain_name.truncate_to_max_length();
+        domain_name
     }
 }
 
+/// Serialized as presentation format string
+#[cfg(feature = "serde")]
+impl serde::Serialize for DomainName {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for DomainName {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        Ok(Self::from(s.as_ref()))
+    }
+}
+
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{BuildHasher, BuildHasherDefault};
 
 /// For message compression and decompression
 /// https://www.rfc-editor.org/rfc/rfc1035#section-4.1.4
-pub struct LookupTable {
-    decompression: Decompression,
-    compression: Compression,
+///
+/// Remembers where every (suffix of a) name read or written so far starts in the message.
+/// Names are taken from the message itself, so the table holds only their positions
+/// (and hashes of the names written), no copies of the labels.
+pub struct LookupTable<'a> {
+    /// Length of the message being read, positions are derived from the bytes remaining
+    message_len: usize,
+    /// Message being read, pointer targets are read from it
+    message: &'a [u8],
+    /// Pointers may target any name earlier in the message, not only names read before
+    lenient: bool,
+    /// Where names read so far start, in increasing order
+    read: Vec<u16>,
+    /// Where names written so far start, by hash of their labels in wire format
+    written: HashMap<u64, u16, BuildHasherDefault<DefaultHasher>>,
 }
 
-impl LookupTable {
-    pub fn new(pos: u16) -> Self {
+impl<'a> LookupTable<'a> {
+    /// Table for writing a message, or for reading data which can't contain pointers
+    /// (e.g. RDATA alone) `message_len` bytes long
+    pub fn new(message_len: usize) -> Self {
+        Self {
+            message_len,
+            message: &[],
+            lenient: false,
+            read: Vec::new(),
+            written: HashMap::default(),
+        }
+    }
+
+    /// Table for reading `message`, pointers may target only names read before
+    pub fn strict(message: &'a [u8]) -> Self {
+        Self {
+            message,
+            ..Self::new(message.len())
+        }
+    }
+
+    /// Table for reading `message` which follows pointers to any name earlier in it
+    ///
+    /// Some servers point into names the parser reads as opaque RDATA (e.g. SRV targets or
+    /// RRSIG signers), such pointers are resolved as well.
+    pub fn lenient(message: &'a [u8]) -> Self {
         Self {
-            decompression: Decompression::new(pos),
-            compression: Compression::new(),
+            lenient: true,
+            ..Self::strict(message)
         }
     }
 
-    pub fn insert(&mut self, domain_name: &DomainName) {
-        let mut labels: Vec<(u16, String)> = Vec::new();
-        let mut pos = self.decompression.pos;
+    /// Position in the message being read, `buf` holds the rest of the message
+    pub fn read_position(&self, buf: &impl bytes::Buf) -> usize {
+        self.message_len.saturating_sub(buf.remaining())
+    }
 
-        for label in domain_name.0.split('.') {
-            let mut label = label.to_string();
-            if label.is_empty() {
+    /// Remembers where the name read at `pos` and each of its suffixes starting with one of
+    /// its `literal` labels (the rest is reached via compression pointer) start
+    pub fn insert_read(&mut self, literal: &[u8], pos: usize) {
+        let mut pos = pos;
+        for label in split_labels(literal) {
+            // pointers have only 14 bits, names are read in order
+            if pos > 0x3FFF {
                 break;
             }
-            label.push('.');
-            let len = label.len() as u16;
-            labels.push((pos, label));
-            pos += len;
+            if self.read.last().is_none_or(|&last| (last as usize) < pos) {
+                self.read.push(pos as u16);
+            }
+            pos += label.len() + 1;
         }
+    }
 
-        labels.reverse();
+    /// Appends labels of the name `pointer` at `pointer_pos` points to
+    pub fn decompress(
+        &self,
+        pointer: u16,
+        pointer_pos: usize,
+        domain_name: &mut DomainName,
+    ) -> Result<(), ParseError> {
+        // only names read before are targets, unless lenient, so pointer loops are not possible
+        if !self.lenient && self.read.binary_search(&pointer).is_err() {
+            return Err(ParseError::InvalidPointer(pointer));
+        }
+        self.read_prior(pointer as usize, pointer_pos, domain_name)
+            .ok_or(ParseError::InvalidPointer(pointer))
+    }
 
-        while let Some((pos, mut label)) = labels.pop() {
-            for (_, next_label) in labels.iter().rev() {
-                label.push_str(next_label);
+    /// Appends labels of the name at `pos` read from the message bytes, the name must lie
+    /// before `before` and so must each name it points to, so pointers can't loop
+    fn read_prior(
+        &self,
+        mut pos: usize,
+        mut before: usize,
+        domain_name: &mut DomainName,
+    ) -> Option<()> {
+        loop {
+            if pos >= before || domain_name.0.len() >= MAX_NAME_LENGTH {
+                return None;
+            }
+            let len = *self.message.get(pos)? as usize;
+            match len {
+                0 => return Some(()),
+                len if len & 0xC0 == 0xC0 => {
+                    let next_byte = *self.message.get(pos + 1)? as usize;
+                    before = pos;
+                    pos = ((len & 0x3F) << 8) | next_byte;
+                }
+                len if len & 0xC0 == 0 => {
+                    let end = pos + 1 + len;
+                    if end > before {
+                        return None;
+                    }
+                    domain_name.0.extend_from_slice(self.message.get(pos..end)?);
+                    pos = end;
+                }
+                _ => return None,
             }
-            self.decompression.map.insert(pos, label.to_string());
-            self.compression.map.insert(label.to_string(), pos);
-            self.decompression.pos = pos;
         }
     }
 
-    pub fn decompress(&self, pos: u16) -> Option<&String> {
-        self.decompression.map.get(&pos)
+    /// Remembers where the name written at `pos` and each of its suffixes starting within its
+    /// first `literal_len` octets (the rest is reached via compression pointer) start
+    pub fn insert_written(&mut self, labels: &[u8], pos: usize, literal_len: usize) {
+        let mut offset = 0;
+        while offset < literal_len {
+            // pointers have only 14 bits
+            if pos + offset > 0x3FFF {
+                break;
+            }
+            let suffix = &labels[offset..];
+            let hash = self.written.hasher().hash_one(suffix);
+            self.written.entry(hash).or_insert((pos + offset) as u16);
+            offset += 1 + labels[offset] as usize;
+        }
     }
 
-    pub fn compress(&self, domain_name: &DomainName) -> Option<&u16> {
-        self.compression.map.get(&domain_name.0)
+    /// Position of the name with wire format `labels` in `message` written so far
+    pub fn compress(&self, labels: &[u8], message: &[u8]) -> Option<u16> {
+        // root is never compressed, pointer is longer than the root label
+        if labels.is_empty() {
+            return None;
+        }
+        let pos = *self.written.get(&self.written.hasher().hash_one(labels))?;
+        // names differing but with the same hash stay uncompressed
+        written_equals(message, pos as usize, labels).then_some(pos)
     }
 }
 
-struct Decompression {
-    pos: u16, // position
-    map: HashMap<u16, String>,
+/// Returns true if the name at `pos` of the written `message` has wire format `labels`
+fn written_equals(message: &[u8], mut pos: usize, mut labels: &[u8]) -> bool {
+    // written pointers point back, but loops are cut short anyway
+    for _ in 0..MAX_NAME_LENGTH {
+        let Some(&len) = message.get(pos) else {
+            return false;
+        };
+        if len & 0xC0 == 0xC0 {
+            let Some(&next_byte) = message.get(pos + 1) else {
+                return false;
+            };
+            pos = ((len as usize & 0x3F) << 8) | next_byte as usize;
+            continue;
+        }
+        if len == 0 {
+            return labels.is_empty();
+        }
+        let label_len = 1 + len as usize;
+        match (message.get(pos..pos + label_len), labels.get(..label_len)) {
+            (Some(written), Some(label)) if written == label => {}
+            _ => return false,
+        }
+        labels = &labels[label_len..];
+        pos += label_len;
+    }
+    false
 }
 
-impl Decompression {
-    fn new(pos: u16) -> Self {
-        Self {
-            pos,
-            map: HashMap::new(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_labels_round_trip() {
+        let domain_name = DomainName::from_labels([&b"a.b"[..], &b"\0\xFF"[..], &b"io"[..]]);
+        assert_eq!(domain_name.to_string(), "a\\.b.\\000\\255.io.");
+        assert_eq!(DomainName::from(domain_name.to_string()), domain_name);
+
+        let mut buf = bytes::BytesMut::new();
+        domain_name.write_bytes(&mut buf, &mut LookupTable::new(0));
+
+        let parsed = DomainName::from_bytes(&mut buf, &mut LookupTable::new(0)).unwrap();
+        assert_eq!(parsed, domain_name);
+        assert_eq!(parsed.label_count(), 3);
+    }
+
+    #[test]
+    fn test_names_are_inline_and_compressed() {
+        let name = DomainName::from("www.example.com");
+        assert!(!name.0.spilled());
+        assert!(DomainName::from("a-rather-long-host-name.of.example.com")
+            .0
+            .spilled());
+
+        let mut buf = BytesMut::new();
+        let mut lookup_table = LookupTable::new(0);
+        for name in [
+            "example.com",
+            "www.example.com",
+            "WWW.example.com",
+            "www.example.com",
+        ] {
+            DomainName::from(name).write_bytes(&mut buf, &mut lookup_table);
         }
+        // suffixes are compressed only when equal including case
+        assert_eq!(
+            &buf[13..],
+            b"\x03www\xC0\x00\x03WWW\xC0\x00\xC0\x0D".as_slice()
+        );
+
+        let mut lookup_table = LookupTable::strict(&buf);
+        let mut rest = &buf[..];
+        let names: Vec<String> = (0..4)
+            .map(|_| {
+                DomainName::from_bytes(&mut rest, &mut lookup_table)
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "example.com.",
+                "www.example.com.",
+                "WWW.example.com.",
+                "www.example.com."
+            ]
+        );
     }
-}
 
-struct Compression {
-    map: HashMap<String, u16>,
-}
+    #[test]
+    fn test_case_insensitive_comparison() {
+        let name = DomainName::from("WWW.Example.COM");
 
-impl Compression {
-    fn new() -> Self {
-        Self {
-            map: HashMap::new(),
+        assert!(name.eq_ignore_case(&DomainName::from("www.example.com.")));
+        assert_eq!(name.canonicalize(), DomainName::from("www.example.com"));
+        assert!(name.is_subdomain_of(&DomainName::from("example.com")));
+        assert!(name.is_subdomain_of(&DomainName::new()));
+        assert!(!name.is_subdomain_of(&DomainName::from("ample.com")));
+    }
+
+    #[test]
+    fn test_canonical_order() {
+        // RFC 4034 section 6.1
+        let ordered = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "*.z.example",
+        ];
+        let mut names: Vec<DomainName> = ordered.iter().rev().map(|&n| n.into()).collect();
+        names.sort_by(DomainName::canonical_cmp);
+        assert_eq!(names, ordered.map(DomainName::from));
+    }
+
+    #[test]
+    fn test_internationalized_name() {
+        let name = DomainName::from_unicode("Bücher.example").unwrap();
+
+        assert_eq!(name.to_string(), "xn--bcher-kva.example.");
+        assert_eq!(name.to_unicode(), "bücher.example.");
+
+        let escaped = DomainName::from_unicode("a\\.b.\\255.Example.").unwrap();
+        assert_eq!(escaped, DomainName::from("a\\.b.\\255.Example."));
+        assert!(DomainName::from_unicode(".").unwrap().is_root());
+    }
+
+    #[test]
+    fn test_unencodable_names_are_rejected() {
+        let long_label = "a".repeat(64);
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        for name in [
+            long_label.as_str(),
+            long_name.as_str(),
+            "a..example",
+            "",
+            "\\256.example",
+            "\\25.example",
+            "example\\",
+        ] {
+            assert!(DomainName::from_unicode(name).is_err(), "{:?}", name);
         }
+        assert!(DomainName::from_unicode(&vec!["a".repeat(63); 3].join(".")).is_ok());
+
+        // infallible conversion cuts long labels and names, so they can still be written
+        let cut = DomainName::from(long_label.as_str());
+        assert_eq!(cut.labels().next().unwrap().len(), 63);
+        assert_eq!(DomainName::from("\\256").labels().next(), Some(&b"256"[..]));
+    }
+
+    #[test]
+    fn test_long_names_are_cut() {
+        // 50 labels of 5 octets, 6 in wire format each
+        let long_name = format!("{}.", vec!["abcde"; 50].join("."));
+        assert_eq!(long_name.len(), 300);
+        let name = DomainName::from(long_name.as_str());
+        assert_eq!(name.label_count(), 42);
+        assert!(name.ensure_max_length().is_ok());
+        assert!(DomainName::from_unicode(&long_name).is_err());
+
+        let mut buf = BytesMut::new();
+        name.write_bytes(&mut buf, &mut LookupTable::new(0));
+        assert_eq!(buf.len(), 42 * 6 + 1);
     }
 }
@@ -0,0 +1,329 @@
+//! DNS over TLS listener (RFC 7858)
+//!
+//! Queries are answered like over plain TCP (see [`crate::tcp`]), only inside TLS.
+//!
+//! The certificate and key files are checked for changes at most every [`RELOAD_INTERVAL`]
+//! and reloaded when they were modified, e.g. renewed by certbot, so the server doesn't need
+//! a restart. New handshakes get the new certificate, established connections keep going.
+//! Files which fail to load (e.g. caught in the middle of being written) are tried again
+//! at the next check, the previous certificate is used in the meantime.
+
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::connections::{Connection, ConnectionLimits, ConnectionTracker};
+use crate::handler::Request;
+use crate::listen;
+use crate::packet::{DnsPacket, ParseMode};
+use crate::proxy_protocol;
+use crate::tcp::{self, ReadTimeout};
+use crate::tsig::TsigKey;
+
+/// How often the certificate and key files are checked for changes
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Certificate chain and private key from PEM files, reloaded when the files change
+#[derive(Debug)]
+pub struct CertificateFiles {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+    loaded: Mutex<LoadedCertificate>,
+}
+
+#[derive(Debug)]
+struct LoadedCertificate {
+    certified_key: Arc<CertifiedKey>,
+    /// Modification times of the files the certificate was loaded from
+    modified: [Option<SystemTime>; 2],
+    checked: Instant,
+}
+
+impl CertificateFiles {
+    /// Loads the certificate chain (leaf first) and the private key, both PEM
+    pub fn load(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<Self> {
+        let (cert_path, key_path) = (cert_path.as_ref(), key_path.as_ref());
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let modified = modification_times(cert_path, key_path);
+        let certified_key = read_certified_key(cert_path, key_path, &provider)?;
+
+        Ok(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            provider,
+            loaded: Mutex::new(LoadedCertificate {
+                certified_key,
+                modified,
+                checked: Instant::now(),
+            }),
+        })
+    }
+
+    /// Current certificate, reloaded first if the files changed since the last check
+    fn current(&self) -> Arc<CertifiedKey> {
+        let mut loaded = self.loaded.lock().expect("certificate lock poisoned");
+        if loaded.checked.elapsed() >= RELOAD_INTERVAL {
+            loaded.checked = Instant::now();
+            let modified = modification_times(&self.cert_path, &self.key_path);
+            if modified != loaded.modified {
+                match read_certified_key(&self.cert_path, &self.key_path, &self.provider) {
+                    Ok(certified_key) => {
+                        println!("Reloaded TLS certificate {}", self.cert_path.display());
+                        loaded.certified_key = certified_key;
+                        loaded.modified = modified;
+                    }
+                    Err(e) => eprintln!("DoT: keeping the previous certificate: {:#}", e),
+                }
+            }
+        }
+        loaded.certified_key.clone()
+    }
+}
+
+impl ResolvesServerCert for CertificateFiles {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+fn modification_times(cert_path: &Path, key_path: &Path) -> [Option<SystemTime>; 2] {
+    [cert_path, key_path].map(|path| {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    })
+}
+
+fn read_certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+    provider: &CryptoProvider,
+) -> Result<Arc<CertifiedKey>> {
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates from {}", cert_path.display()))?;
+    if chain.is_empty() {
+        anyhow::bail!("no certificate in {}", cert_path.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Failed to read private key from {}", key_path.display()))?;
+    let signing_key = provider
+        .key_provider
+        .load_private_key(key)
+        .with_context(|| format!("unsupported private key in {}", key_path.display()))?;
+
+    let certified_key = CertifiedKey::new(chain, signing_key);
+    certified_key.keys_match().with_context(|| {
+        format!(
+            "{} doesn't match {}",
+            key_path.display(),
+            cert_path.display()
+        )
+    })?;
+    Ok(Arc::new(certified_key))
+}
+
+/// Serves queries on `address` with the certificate from `certificates`, each connection
+/// in its own thread
+///
+/// Queries signed with one of `keys` are answered with signed responses, queries are
+/// parsed as strictly as `mode` says. With `proxy_protocol`, every connection must start
+/// with PROXY protocol v2 header (see [`crate::proxy_protocol`]) before the TLS handshake.
+pub fn serve<F>(
+    address: &str,
+    certificates: Arc<CertificateFiles>,
+    keys: Vec<TsigKey>,
+    limits: ConnectionLimits,
+    mode: ParseMode,
+    proxy_protocol: bool,
+    handler: F,
+) -> Result<()>
+where
+    F: Fn(&Request) -> Result<DnsPacket> + Send + Sync + 'static,
+{
+    let config = server_config(certificates, b"dot")?;
+    let listener = listen::tcp(address)
+        .with_context(|| format!("Failed to bind DoT listener to {}", address))?;
+
+    println!("Listening on DoT {}", address);
+
+    let keys: Arc<[TsigKey]> = keys.into();
+    let handler = Arc::new(handler);
+    let tracker = ConnectionTracker::new(limits);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("DoT: error accepting connection: {}", e);
+                continue;
+            }
+        };
+        let connection = match tracker.admit(&stream) {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("DoT: refused connection: {:#}", e);
+                continue;
+            }
+        };
+
+        let (config, keys, handler) = (config.clone(), keys.clone(), handler.clone());
+        thread::spawn(move || {
+            let connected = handle_connection(
+                stream,
+                config,
+                &connection,
+                &keys,
+                mode,
+                proxy_protocol,
+                handler.as_ref(),
+            );
+            if let Err(e) = connected {
+                eprintln!("DoT: error handling connection: {:#}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// TLS configuration of a listener with certificates from `certificates`, offering the
+/// `alpn` protocol
+pub(crate) fn server_config(
+    certificates: Arc<CertificateFiles>,
+    alpn: &[u8],
+) -> Result<Arc<ServerConfig>> {
+    let provider = certificates.provider.clone();
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .context("no usable TLS version")?
+        .with_no_client_auth()
+        .with_cert_resolver(certificates);
+    config.alpn_protocols = vec![alpn.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Completes the TLS handshake of an accepted connection, which must not take longer
+/// than `handshake_timeout`
+pub(crate) fn accept(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+    handshake_timeout: Duration,
+) -> Result<StreamOwned<ServerConnection, TcpStream>> {
+    let client = stream.peer_addr()?;
+    let deadline = Instant::now() + handshake_timeout;
+
+    let mut stream = StreamOwned::new(ServerConnection::new(config)?, stream);
+    while stream.conn.is_handshaking() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            anyhow::bail!("TLS handshake with {} timed out", client);
+        }
+        stream.sock.set_read_timeout(Some(remaining))?;
+        stream
+            .conn
+            .complete_io(&mut stream.sock)
+            .with_context(|| format!("TLS handshake with {} failed", client))?;
+    }
+    Ok(stream)
+}
+
+impl ReadTimeout for StreamOwned<ServerConnection, TcpStream> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+    connection: &Connection,
+    keys: &[TsigKey],
+    mode: ParseMode,
+    proxy_protocol: bool,
+    handler: &(impl Fn(&Request) -> Result<DnsPacket> + Sync),
+) -> Result<()> {
+    let client = match proxy_protocol {
+        true => proxy_protocol::read_client(&stream, connection.message_timeout())?,
+        false => stream.peer_addr()?,
+    };
+    let mut stream = accept(stream, config, connection.message_timeout())?;
+    tcp::handle_messages(&mut stream, client, connection, keys, mode, handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_certificate_files_are_reported() {
+        let error =
+            CertificateFiles::load("/nonexistent/cert.pem", "/nonexistent/key.pem").unwrap_err();
+        assert!(format!("{:#}", error).contains("/nonexistent/cert.pem"));
+    }
+
+    /// Resolver without any certificate, handshakes fail once the ClientHello is read
+    #[derive(Debug)]
+    struct NoCertificate;
+
+    impl ResolvesServerCert for NoCertificate {
+        fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+            None
+        }
+    }
+
+    /// Error of a DoT connection expecting PROXY protocol on which the client sent `bytes`
+    fn connection_error(bytes: &[u8]) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let connection = ConnectionTracker::new(ConnectionLimits::default())
+            .admit(&server)
+            .unwrap();
+        std::io::Write::write_all(&mut client, bytes).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(NoCertificate));
+        let handler = |_: &Request| -> Result<DnsPacket> { unreachable!("no query is sent") };
+        let error = handle_connection(
+            server,
+            Arc::new(config),
+            &connection,
+            &[],
+            ParseMode::Lenient,
+            true,
+            &handler,
+        )
+        .unwrap_err();
+        format!("{:#}", error)
+    }
+
+    #[test]
+    fn test_proxy_header_precedes_handshake() {
+        let error = connection_error(b"not a PROXY protocol header");
+        assert!(error.contains("invalid PROXY protocol header"), "{}", error);
+
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]); // v2 PROXY, TCP over IPv4
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xC3, 0x50, 0x03, 0x55]);
+        header.extend_from_slice(b"not a ClientHello");
+        let error = connection_error(&header);
+        assert!(error.contains("TLS handshake"), "{}", error);
+        assert!(!error.contains("PROXY"), "{}", error);
+    }
+}
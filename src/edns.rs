@@ -0,0 +1,171 @@
+//! Extension mechanisms for DNS - EDNS(0) (RFC 6891)
+//!
+//! EDNS is carried in a pseudo-record OPT in the additional section. Its CLASS field holds
+//! the requestor's UDP payload size and its TTL field holds the extended RCODE, version
+//! and flags (DO bit).
+//!
+//! ```text
+//!                  +0 (MSB)                            +1 (LSB)
+//!     +---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+
+//!  0: |                          OPTION-CODE                          |
+//!     +---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+
+//!  2: |                         OPTION-LENGTH                         |
+//!     +---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+
+//!  4: |                                                               |
+//!     /                          OPTION-DATA                          /
+//!     /                                                               /
+//!     +---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+
+//! ```
+
+use std::time::Duration;
+
+use crate::packet::{ensure_remaining, ParseError};
+
+/// TYPE of the OPT pseudo-record
+pub const OPT_RECORD_TYPE: u16 = 41;
+
+/// UDP payload size advertised by this server in responses
+pub const UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// EDNS Client Subnet (RFC 7871)
+pub const OPTION_CLIENT_SUBNET: u16 = 8;
+/// DNS Cookie (RFC 7873)
+pub const OPTION_COOKIE: u16 = 10;
+/// edns-tcp-keepalive (RFC 7828)
+pub const OPTION_TCP_KEEPALIVE: u16 = 11;
+/// Padding (RFC 7830)
+pub const OPTION_PADDING: u16 = 12;
+/// Extended DNS Error (RFC 8914)
+pub const OPTION_EXTENDED_ERROR: u16 = 15;
+
+/// OPT pseudo-record
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OptRecord {
+    /// Maximal UDP payload size the sender can reassemble
+    pub udp_payload_size: u16,
+    /// Upper 8 bits of the 12-bit RCODE
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// DNSSEC OK - sender is able to accept DNSSEC records
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+/// Single option in the OPT record RDATA
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+impl EdnsOption {
+    /// edns-tcp-keepalive, empty in queries, with the idle timeout in responses
+    pub fn tcp_keepalive(timeout: Option<Duration>) -> Self {
+        // TIMEOUT is in units of 100 milliseconds
+        let data = timeout
+            .map(|timeout| ((timeout.as_millis() / 100).min(u16::MAX as u128) as u16).to_be_bytes())
+            .map_or(Vec::new(), |timeout| timeout.to_vec());
+
+        Self {
+            code: OPTION_TCP_KEEPALIVE,
+            data,
+        }
+    }
+}
+
+impl Default for OptRecord {
+    fn default() -> Self {
+        Self::new(UDP_PAYLOAD_SIZE)
+    }
+}
+
+impl OptRecord {
+    pub fn new(udp_payload_size: u16) -> Self {
+        Self {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    /// Options which only make sense between two directly communicating parties
+    /// (cookies, keepalive, padding), they must not be passed on when forwarding
+    pub fn is_hop_by_hop(code: u16) -> bool {
+        matches!(code, OPTION_COOKIE | OPTION_TCP_KEEPALIVE | OPTION_PADDING)
+    }
+
+    /// Options this server understands, everything else is carried opaquely
+    pub fn is_known(code: u16) -> bool {
+        Self::is_hop_by_hop(code) || matches!(code, OPTION_CLIENT_SUBNET | OPTION_EXTENDED_ERROR)
+    }
+
+    /// Options which may be passed on when forwarding
+    pub fn end_to_end_options(&self) -> impl Iterator<Item = &EdnsOption> {
+        self.options
+            .iter()
+            .filter(|option| !Self::is_hop_by_hop(option.code))
+    }
+
+    pub fn has_option(&self, code: u16) -> bool {
+        self.options.iter().any(|option| option.code == code)
+    }
+
+    /// Idle timeout announced in edns-tcp-keepalive option of a response
+    pub fn tcp_keepalive_timeout(&self) -> Option<Duration> {
+        self.options
+            .iter()
+            .find(|option| option.code == OPTION_TCP_KEEPALIVE)
+            .and_then(|option| <[u8; 2]>::try_from(option.data.as_slice()).ok())
+            .map(|timeout| Duration::from_millis(u16::from_be_bytes(timeout) as u64 * 100))
+    }
+
+    /// Creates [`OptRecord`] from CLASS, TTL and RDATA of the record
+    pub fn from_parts(class: u16, ttl: u32, mut rdata: &[u8]) -> Result<Self, ParseError> {
+        use bytes::Buf;
+
+        let mut options = Vec::new();
+        while rdata.has_remaining() {
+            ensure_remaining(&rdata, 4)?;
+            let code = rdata.get_u16();
+            let length = rdata.get_u16() as usize;
+            ensure_remaining(&rdata, length)?;
+            options.push(EdnsOption {
+                code,
+                data: rdata[..length].to_vec(),
+            });
+            rdata.advance(length);
+        }
+
+        Ok(Self {
+            udp_payload_size: class,
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            dnssec_ok: ttl & 0x8000 != 0,
+            options,
+        })
+    }
+
+    /// Writes the whole pseudo-record (with root owner name)
+    pub fn write_bytes(&self, buf: &mut impl bytes::BufMut) {
+        buf.put_u8(0); // root
+        buf.put_u16(OPT_RECORD_TYPE);
+        buf.put_u16(self.udp_payload_size);
+
+        let ttl = (self.extended_rcode as u32) << 24
+            | (self.version as u32) << 16
+            | (self.dnssec_ok as u32) << 15;
+        buf.put_u32(ttl);
+
+        let length: usize = self.options.iter().map(|o| 4 + o.data.len()).sum();
+        buf.put_u16(length as u16);
+        for option in self.options.iter() {
+            buf.put_u16(option.code);
+            buf.put_u16(option.data.len() as u16);
+            buf.put_slice(&option.data);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Zone export as structured data, for tooling which shouldn't parse master files
+//!
+//! The zone is a document with its origin, serial and records, each record with its owner,
+//! type, class, TTL and RDATA in presentation format:
+//!
+//! ```text
+//! origin: "home.arpa."
+//! serial: 1
+//! records:
+//!   - name: "nas.home.arpa."
+//!     type: "A"
+//!     class: "IN"
+//!     ttl: 3600
+//!     data: "192.168.1.10"
+//! ```
+//!
+//! YAML is written with double-quoted scalars only, which are JSON strings too.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::record::RecordData;
+use crate::zone::Zone;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Yaml,
+}
+
+impl ExportFormat {
+    /// Media type of the exported document
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Yaml => "application/yaml",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "yaml" | "yml" => Ok(Self::Yaml),
+            _ => anyhow::bail!("unknown export format {}, expected json or yaml", s),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ZoneExport {
+    origin: String,
+    serial: u32,
+    records: Vec<RecordExport>,
+}
+
+#[derive(Debug, Serialize)]
+struct RecordExport {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    class: String,
+    ttl: u32,
+    data: String,
+}
+
+impl From<&Zone> for ZoneExport {
+    fn from(zone: &Zone) -> Self {
+        let serial = match zone.soa().map(|soa| &soa.data) {
+            Some(RecordData::Soa(soa)) => soa.serial,
+            _ => 0,
+        };
+        Self {
+            origin: zone.origin().to_string(),
+            serial,
+            records: zone
+                .records()
+                .iter()
+                .map(|record| RecordExport {
+                    name: record.domain_name.to_string(),
+                    record_type: record.record_type.to_string(),
+                    class: record.class.to_string(),
+                    ttl: record.ttl,
+                    data: record.data.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Zone as a JSON or YAML document
+pub fn export(zone: &Zone, format: ExportFormat) -> String {
+    let export = ZoneExport::from(zone);
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_string_pretty(&export).expect("zone export is always serializable")
+        }
+        ExportFormat::Yaml => to_yaml(&export),
+    }
+}
+
+fn to_yaml(export: &ZoneExport) -> String {
+    let mut yaml = String::new();
+    let _ = writeln!(yaml, "origin: {}", quote(&export.origin));
+    let _ = writeln!(yaml, "serial: {}", export.serial);
+    yaml.push_str("records:\n");
+    for record in export.records.iter() {
+        let _ = writeln!(yaml, "  - name: {}", quote(&record.name));
+        let _ = writeln!(yaml, "    type: {}", quote(&record.record_type));
+        let _ = writeln!(yaml, "    class: {}", quote(&record.class));
+        let _ = writeln!(yaml, "    ttl: {}", record.ttl);
+        let _ = writeln!(yaml, "    data: {}", quote(&record.data));
+    }
+    yaml
+}
+
+/// Double-quoted scalar, JSON escaping is valid YAML escaping
+fn quote(value: &str) -> String {
+    serde_json::to_string(value).expect("string is always serializable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = "$ORIGIN home.arpa.
+@    IN SOA ns1 hostmaster 7 7200 900 1209600 300
+     IN NS  ns1
+ns1  IN A   192.168.1.1
+";
+
+    #[test]
+    fn test_export() {
+        let zone = Zone::parse(ZONE).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&export(&zone, ExportFormat::Json)).unwrap();
+        assert_eq!(json["origin"], "home.arpa.");
+        assert_eq!(json["serial"], 7);
+        assert_eq!(json["records"][2]["name"], "ns1.home.arpa.");
+        assert_eq!(json["records"][2]["type"], "A");
+        assert_eq!(json["records"][2]["data"], "192.168.1.1");
+
+        let yaml = export(&zone, ExportFormat::Yaml);
+        assert!(yaml.starts_with("origin: \"home.arpa.\"\nserial: 7\nrecords:\n"));
+        assert!(yaml.contains(
+            "  - name: \"ns1.home.arpa.\"\n    type: \"A\"\n    class: \"IN\"\n    ttl: 3600\n    data: \"192.168.1.1\"\n"
+        ));
+    }
+}
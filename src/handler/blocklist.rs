@@ -0,0 +1,213 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use super::{response_builder, Handler, Next, Request};
+use crate::blocklist::{Allowlist, BlockedSet, Blocklists};
+use crate::domain_name::DomainName;
+use crate::header::ResponseCode;
+use crate::packet::DnsPacket;
+use crate::record::{RecordData, RecordType};
+use crate::stats;
+
+/// Answers queries for names blocked by the blocklists with NXDOMAIN
+///
+/// Answers of the following handlers are blocked too when any CNAME in them points at a
+/// blocked name, as trackers hide behind aliases of the site's own names. Names on the
+/// [`Allowlist`] are looked up first and never blocked, not even for their aliases.
+pub struct BlocklistHandler {
+    blocklists: Arc<Blocklists>,
+    allowlist: Allowlist,
+}
+
+impl BlocklistHandler {
+    pub fn new(blocklists: Arc<Blocklists>) -> Self {
+        Self {
+            blocklists,
+            allowlist: Allowlist::default(),
+        }
+    }
+
+    /// Names on `allowlist` are resolved even if the blocklists block them
+    pub fn allow(mut self, allowlist: Allowlist) -> Self {
+        self.allowlist = allowlist;
+        self
+    }
+
+    fn is_blocked(&self, blocked: &BlockedSet, name: &DomainName) -> bool {
+        !self.allowlist.contains(name) && blocked.contains(name)
+    }
+}
+
+impl Handler for BlocklistHandler {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+        let query = &request.query;
+        let blocked = self.blocklists.current();
+        if let Some(question) = query
+            .questions
+            .iter()
+            .find(|question| self.is_blocked(&blocked, &question.domain_name))
+        {
+            stats::blocked(&question.domain_name);
+            return Ok(blocked_response(query));
+        }
+
+        let response = next.run(request)?;
+        let allowed = query
+            .questions
+            .iter()
+            .all(|question| self.allowlist.contains(&question.domain_name));
+        if allowed {
+            return Ok(response);
+        }
+        // trackers hide behind CNAMEs of first-party names (CNAME cloaking)
+        let cloaked = response
+            .answers
+            .iter()
+            .find_map(|answer| match &answer.data {
+                RecordData::Name(target)
+                    if answer.record_type == RecordType::CNAME
+                        && self.is_blocked(&blocked, target) =>
+                {
+                    Some(target)
+                }
+                _ => None,
+            });
+        match cloaked {
+            Some(target) => {
+                let name = query
+                    .questions
+                    .first()
+                    .map(|question| question.domain_name.clone())
+                    .unwrap_or_default();
+                eprintln!(
+                    "Blocklist: blocked {}, its alias {} is listed",
+                    name, target
+                );
+                stats::blocked(target);
+                Ok(blocked_response(query))
+            }
+            None => Ok(response),
+        }
+    }
+}
+
+fn blocked_response(query: &DnsPacket) -> DnsPacket {
+    response_builder(query)
+        .recursion_available(true)
+        .rescode(ResponseCode::NXDOMAIN)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::handler::Pipeline;
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+    use crate::record::{DnsRecord, RecordClass};
+
+    /// Upstream stand-in answering with a CNAME chain to a tracker
+    struct CloakedHandler;
+
+    impl Handler for CloakedHandler {
+        fn handle(&self, request: &Request, _next: Next<'_>) -> Result<DnsPacket> {
+            let name = request.query.questions[0].domain_name.clone();
+            let alias = DomainName::from("shop.example.com.edge.example.net");
+            let tracker = DomainName::from("collect.tracker.example.org");
+            Ok(response_builder(&request.query)
+                .answers(vec![
+                    DnsRecord::new(
+                        name,
+                        RecordType::CNAME,
+                        RecordClass::IN,
+                        300,
+                        RecordData::Name(alias.clone()),
+                    ),
+                    DnsRecord::new(
+                        alias,
+                        RecordType::CNAME,
+                        RecordClass::IN,
+                        300,
+                        RecordData::Name(tracker.clone()),
+                    ),
+                    DnsRecord::new(
+                        tracker,
+                        RecordType::A,
+                        RecordClass::IN,
+                        300,
+                        Ipv4Addr::new(192, 0, 2, 1),
+                    ),
+                ])
+                .build())
+        }
+    }
+
+    fn resolve(handler: BlocklistHandler, name: &str) -> ResponseCode {
+        let pipeline = Pipeline::new().with(handler).with(CloakedHandler);
+        let query = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from(name),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+        pipeline
+            .handle(&Request::new(query, None))
+            .unwrap()
+            .header
+            .rescode
+    }
+
+    #[test]
+    fn test_cloaked_trackers_are_blocked() {
+        let path = std::env::temp_dir().join(format!("cloaking-{}.txt", std::process::id()));
+        std::fs::write(&path, "||tracker.example.org^\n").unwrap();
+        let blocklists = Arc::new(Blocklists::new([path.display().to_string()]).unwrap());
+        blocklists.refresh();
+        std::fs::remove_file(&path).unwrap();
+
+        let handler = BlocklistHandler::new(blocklists.clone());
+        assert_eq!(
+            resolve(handler, "metrics.example.com"),
+            ResponseCode::NXDOMAIN
+        );
+
+        let allowlist = Allowlist::new(["*.tracker.example.org"]).unwrap();
+        let handler = BlocklistHandler::new(blocklists.clone()).allow(allowlist);
+        assert_eq!(
+            resolve(handler, "metrics.example.com"),
+            ResponseCode::NOERROR
+        );
+
+        // allowlisted names are exempt, whatever their aliases are
+        let allowlist = Allowlist::new(["metrics.example.com"]).unwrap();
+        let handler = BlocklistHandler::new(blocklists.clone()).allow(allowlist);
+        assert_eq!(
+            resolve(handler, "metrics.example.com"),
+            ResponseCode::NOERROR
+        );
+        let handler = BlocklistHandler::new(blocklists)
+            .allow(Allowlist::new(["metrics.example.com"]).unwrap());
+        assert_eq!(resolve(handler, "shop.example.com"), ResponseCode::NXDOMAIN);
+    }
+
+    #[test]
+    fn test_unlisted_chains_are_answered() {
+        let path = std::env::temp_dir().join(format!("unlisted-{}.txt", std::process::id()));
+        std::fs::write(&path, "||ads.example.org^\n").unwrap();
+        let blocklists = Arc::new(Blocklists::new([path.display().to_string()]).unwrap());
+        blocklists.refresh();
+        std::fs::remove_file(&path).unwrap();
+
+        let handler = BlocklistHandler::new(blocklists.clone());
+        assert_eq!(
+            resolve(handler, "metrics.example.com"),
+            ResponseCode::NOERROR
+        );
+        let handler = BlocklistHandler::new(blocklists);
+        assert_eq!(resolve(handler, "ads.example.org"), ResponseCode::NXDOMAIN);
+        assert!(Allowlist::new(["*."]).is_err());
+    }
+}
@@ -0,0 +1,887 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use super::{response_builder, Handler, Next, Request, TtlClampHandler};
+use crate::domain_name::DomainName;
+use crate::header::ResponseCode;
+use crate::log;
+use crate::packet::{BytesPacket, DnsPacket};
+use crate::record::{DnsRecord, RecordData};
+use crate::stats::{self, CacheStats};
+use crate::trace;
+
+/// Question the answers are cached for (name is canonicalized), whether the client asked
+/// for DNSSEC records (DO), answers with them and without them are kept apart, and whether
+/// it disabled checking (CD), unvalidated answers must not reach clients relying on validation
+type CacheKey = (DomainName, u16, u16, bool, bool);
+
+/// TTL of expired answers served because of an upstream error (RFC 8767 section 4)
+const STALE_TTL: u32 = 30;
+
+#[derive(Clone)]
+struct CacheEntry {
+    rescode: ResponseCode,
+    /// AD flag of the response, the answers were validated
+    authed_data: bool,
+    answers: Vec<DnsRecord>,
+    /// SOA of a negative answer, its TTL is the negative TTL, with the proofs of nonexistence
+    authorities: Vec<DnsRecord>,
+    stored_at: Instant,
+}
+
+impl CacheEntry {
+    fn new(rescode: ResponseCode, answers: Vec<DnsRecord>, authorities: Vec<DnsRecord>) -> Self {
+        Self {
+            rescode,
+            authed_data: false,
+            answers,
+            authorities,
+            stored_at: Instant::now(),
+        }
+    }
+
+    /// Entry for the response, if it can be cached
+    ///
+    /// Positive answers are cached for their TTL, NXDOMAIN and NODATA proven with SOA
+    /// for the negative TTL of the zone (RFC 2308 section 5), proofs of nonexistence
+    /// (NSEC, ...) no longer than that.
+    fn from_response(response: &DnsPacket) -> Option<Self> {
+        if response.header.truncated_message {
+            return None;
+        }
+        let authed_data = response.header.authed_data;
+
+        if !response.answers.is_empty() {
+            let cacheable = response.header.rescode == ResponseCode::NOERROR
+                && response.answers.iter().all(|answer| answer.ttl > 0);
+            return cacheable.then(|| Self {
+                authed_data,
+                ..Self::new(ResponseCode::NOERROR, response.answers.clone(), Vec::new())
+            });
+        }
+
+        if !matches!(
+            response.header.rescode,
+            ResponseCode::NOERROR | ResponseCode::NXDOMAIN
+        ) {
+            return None;
+        }
+        let soas: Vec<DnsRecord> = response
+            .authorities
+            .iter()
+            .filter_map(|authority| {
+                let mut soa = authority.clone();
+                soa.ttl = authority.negative_ttl().filter(|&ttl| ttl > 0)?;
+                Some(soa)
+            })
+            .collect();
+
+        let negative_ttl = soas.iter().map(|soa| soa.ttl).min()?;
+        let proofs = response
+            .authorities
+            .iter()
+            .filter(|authority| authority.negative_ttl().is_none())
+            .map(|authority| {
+                let mut proof = authority.clone();
+                proof.ttl = proof.ttl.min(negative_ttl);
+                proof
+            });
+        let authorities = soas.into_iter().chain(proofs).collect();
+        Some(Self {
+            authed_data,
+            ..Self::new(response.header.rescode, Vec::new(), authorities)
+        })
+    }
+
+    /// Approximate memory taken by the entry stored under `key`
+    fn approximate_size(&self, key: &CacheKey) -> usize {
+        let records: usize = self
+            .answers
+            .iter()
+            .chain(self.authorities.iter())
+            .map(|record| {
+                let data = match &record.data {
+                    RecordData::Name(name) | RecordData::Mx { exchange: name, .. } => {
+                        name_size(name)
+                    }
+                    RecordData::Soa(soa) => name_size(&soa.mname) + name_size(&soa.rname),
+                    RecordData::Unknown(data) => data.len(),
+                    RecordData::A(_) | RecordData::Aaaa(_) => 0,
+                };
+                size_of::<DnsRecord>() + name_size(&record.domain_name) + data
+            })
+            .sum();
+        size_of::<(CacheKey, CacheEntry)>() + name_size(&key.0) + records
+    }
+
+    fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.answers.iter().chain(self.authorities.iter())
+    }
+
+    fn records_mut(&mut self) -> impl Iterator<Item = &mut DnsRecord> {
+        self.answers.iter_mut().chain(self.authorities.iter_mut())
+    }
+
+    /// How long ago the first of the records expired, `None` if all are still valid
+    fn expired_for(&self) -> Option<Duration> {
+        let min_ttl = self
+            .answers
+            .iter()
+            .chain(self.authorities.iter())
+            .map(|record| record.ttl)
+            .min()?;
+        self.stored_at
+            .elapsed()
+            .checked_sub(Duration::from_secs(min_ttl as u64))
+    }
+
+    /// Copy with TTLs decremented by the time spent in the cache, expired records
+    /// get `stale_ttl` (or the copy is `None` without it)
+    fn with_remaining_ttl(&self, stale_ttl: Option<u32>) -> Option<Self> {
+        let elapsed = self.stored_at.elapsed().as_secs();
+        let mut entry = self.clone();
+        for record in entry.records_mut() {
+            record.ttl = (record.ttl as u64)
+                .checked_sub(elapsed)
+                .filter(|&ttl| ttl > 0)
+                .map(|ttl| ttl as u32)
+                .or(stale_ttl)?;
+        }
+        Some(entry)
+    }
+
+    /// Entry for the shared cache: milliseconds since UNIX epoch it was stored at, then
+    /// a DNS message with its records (with their original TTLs)
+    fn to_bytes(&self) -> Vec<u8> {
+        let stored_at = SystemTime::now() - self.stored_at.elapsed();
+        let millis = stored_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let message = DnsPacket::builder()
+            .rescode(self.rescode)
+            .authed_data(self.authed_data)
+            .answers(self.answers.clone())
+            .authorities(self.authorities.clone())
+            .build();
+
+        let mut bytes = millis.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&BytesPacket::from(message).buf);
+        bytes
+    }
+
+    /// Entry from the shared cache, see [`CacheEntry::to_bytes`]
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let Some((millis, message)) = bytes.split_first_chunk::<8>() else {
+            anyhow::bail!("cache entry of {} bytes is too short", bytes.len());
+        };
+        let stored_at = UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(*millis));
+        let age = SystemTime::now()
+            .duration_since(stored_at)
+            .unwrap_or_default();
+        let message = DnsPacket::parse(message)?;
+
+        Ok(Self {
+            rescode: message.header.rescode,
+            authed_data: message.header.authed_data,
+            answers: message.answers,
+            authorities: message.authorities,
+            stored_at: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+        })
+    }
+
+    fn response(self, query: &DnsPacket) -> DnsPacket {
+        response_builder(query)
+            .recursion_available(true)
+            .rescode(self.rescode)
+            .authed_data(self.authed_data && query.wants_authed_data())
+            .answers(self.answers)
+            .authorities(self.authorities)
+            .build()
+    }
+}
+
+/// Key of the question in the shared cache, e.g. `dns:example.com.:1:1`
+/// (`dns:example.com.:1:1:do` for answers with DNSSEC records, `:cd` appended for
+/// answers to queries with checking disabled)
+fn shared_key(key: &CacheKey) -> String {
+    let dnssec = if key.3 { ":do" } else { "" };
+    let unchecked = if key.4 { ":cd" } else { "" };
+    format!("dns:{}:{}:{}{}{}", key.0, key.1, key.2, dnssec, unchecked)
+}
+
+/// Heap memory taken by the labels of `name`
+fn name_size(name: &DomainName) -> usize {
+    name.labels()
+        .map(|label| size_of::<Box<[u8]>>() + label.len())
+        .sum()
+}
+
+/// Store of cache entries shared by several servers, the second level behind their own caches
+pub trait CacheBackend: Send + Sync {
+    /// Value stored under `key`, `None` if there's none (or it expired)
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Stores `value` under `key` for `ttl`
+    fn set(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()>;
+}
+
+impl<T: CacheBackend> CacheBackend for Arc<T> {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get(key)
+    }
+
+    fn set(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        (**self).set(key, value, ttl)
+    }
+}
+
+/// Caches answers of the following handlers until their TTL expires
+///
+/// Answers served from the cache carry the remaining TTL, so downstream caches don't
+/// keep the records longer than the original TTL allows. Only single-question queries
+/// with at least one answer, or negative answers with SOA (cached for the negative TTL
+/// together with the SOA), are cached. Queries with DO get answers cached for queries
+/// with DO (with signatures), others those cached without it, and the AD flag only if
+/// they understand it.
+///
+/// With [`CacheHandler::stale_if_error`] set, expired answers are kept a while longer and
+/// served when the following handlers fail or answer with SERVFAIL/REFUSED, instead of
+/// passing the error on to clients.
+///
+/// With [`CacheHandler::shared`] set, answers missing in the cache are looked up in a
+/// store shared with other servers (e.g. [`crate::redis::RedisCache`], or entries pushed by
+/// peers with [`crate::replication::Replicator`]) before they're resolved, and answers
+/// resolved are stored there too. The in-memory cache stays in front of it, holding what
+/// the server needed recently.
+///
+/// With [`CacheHandler::clamp_ttl`] set, TTLs of resolved answers are clamped before they
+/// are cached, so the cache keeps them as long as clients are told to.
+///
+/// Entry count, approximate memory and efficiency of the cache are kept in [`stats`].
+pub struct CacheHandler {
+    capacity: usize,
+    /// How long after expiration answers may still be served on errors
+    max_stale: Option<Duration>,
+    entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+    shared: Option<Arc<dyn CacheBackend>>,
+    ttl_clamp: Option<TtlClampHandler>,
+    stats: Arc<CacheStats>,
+}
+
+impl CacheHandler {
+    /// Cache holding at most `capacity` questions
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            max_stale: None,
+            entries: Mutex::new(HashMap::new()),
+            shared: None,
+            ttl_clamp: None,
+            stats: stats::cache(),
+        }
+    }
+
+    /// Answers expired at most `max_stale` ago are used when the fresh ones can't be obtained
+    pub fn stale_if_error(mut self, max_stale: Duration) -> Self {
+        self.max_stale = Some(max_stale);
+        self
+    }
+
+    /// Looks up answers missing in memory in `backend` shared with other servers, and
+    /// stores resolved answers there
+    pub fn shared(mut self, backend: impl CacheBackend + 'static) -> Self {
+        self.shared = Some(Arc::new(backend));
+        self
+    }
+
+    /// Keeps TTLs of resolved answers (and negative TTLs) within `[min, max]`, like
+    /// [`TtlClampHandler`] in front of the cache does for clients
+    pub fn clamp_ttl(mut self, min: u32, max: u32) -> Self {
+        self.ttl_clamp = Some(TtlClampHandler::new(min, max));
+        self
+    }
+
+    /// Entry is of no use anymore, not even as a stale answer
+    fn is_dead(&self, entry: &CacheEntry) -> bool {
+        entry
+            .expired_for()
+            .is_some_and(|expired_for| !matches!(self.max_stale, Some(max) if expired_for <= max))
+    }
+
+    /// Cached entry with TTLs decremented by the time spent in the cache
+    fn lookup(&self, key: &CacheKey) -> Option<CacheEntry> {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        let Some(entry) = entries.get(key) else {
+            self.stats.miss();
+            return None;
+        };
+
+        let fresh = entry.with_remaining_ttl(None);
+        if fresh.is_some() {
+            self.stats.hit();
+        } else {
+            self.stats.miss();
+            self.stats.expired();
+            if self.is_dead(entry) {
+                // some record expired
+                self.stats.removed(entry.approximate_size(key));
+                entries.remove(key);
+            }
+        }
+
+        fresh
+    }
+
+    /// Cached entry, even if expired (within the allowed staleness), expired records get [`STALE_TTL`]
+    fn lookup_stale(&self, key: &CacheKey) -> Option<CacheEntry> {
+        let entries = self.entries.lock().expect("cache lock poisoned");
+        let entry = entries.get(key).filter(|entry| !self.is_dead(entry))?;
+
+        entry.with_remaining_ttl(Some(STALE_TTL))
+    }
+
+    /// Fresh entry from the shared cache, kept in memory too
+    fn lookup_shared(&self, key: &CacheKey) -> Option<CacheEntry> {
+        let shared = self.shared.as_ref()?;
+        // unreachable store is reported by the store itself
+        let bytes = shared.get(&shared_key(key)).ok()??;
+        let entry = match CacheEntry::from_bytes(&bytes) {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("Cache: invalid shared entry for {}: {:#}", key.0, e);
+                return None;
+            }
+        };
+        let fresh = entry.with_remaining_ttl(None)?;
+        self.stats.shared_hit();
+        self.store(key.clone(), entry);
+        Some(fresh)
+    }
+
+    /// Stores entry in the shared cache until it's of no use even as a stale answer
+    fn store_shared(&self, key: &CacheKey, entry: &CacheEntry) {
+        let Some(shared) = &self.shared else {
+            return;
+        };
+        let Some(min_ttl) = entry.records().map(|record| record.ttl).min() else {
+            return;
+        };
+        let ttl = Duration::from_secs(min_ttl.into()) + self.max_stale.unwrap_or_default();
+        // unreachable store is reported by the store itself
+        let _ = shared.set(&shared_key(key), &entry.to_bytes(), ttl);
+    }
+
+    fn store(&self, key: CacheKey, entry: CacheEntry) {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|key, entry| {
+                let dead = self.is_dead(entry);
+                if dead {
+                    self.stats.evicted();
+                    self.stats.removed(entry.approximate_size(key));
+                }
+                !dead
+            });
+            if entries.len() >= self.capacity {
+                self.stats.rejected();
+                return; // full of live entries
+            }
+        }
+
+        self.stats.inserted(entry.approximate_size(&key));
+        if let Some(replaced) = entries.insert(key.clone(), entry) {
+            self.stats.removed(replaced.approximate_size(&key));
+        }
+    }
+}
+
+impl Handler for CacheHandler {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+        let query = &request.query;
+        let [question] = &query.questions[..] else {
+            return next.run(request);
+        };
+        if query.header.opcode != 0 {
+            return next.run(request);
+        }
+
+        let key = (
+            question.domain_name.canonicalize(),
+            u16::from(question.query_type.clone()),
+            u16::from(question.class.clone()),
+            query.dnssec_ok(),
+            query.header.checking_disabled,
+        );
+
+        let mut span = trace::span("cache.lookup");
+        let entry = self.lookup(&key);
+        span.attribute("cache.hit", entry.is_some());
+        drop(span);
+        if let Some(entry) = entry.or_else(|| self.lookup_shared(&key)) {
+            log::cache_hit();
+            return Ok(entry.response(query));
+        }
+
+        let mut response = match next.run(request) {
+            Ok(response)
+                if !matches!(
+                    response.header.rescode,
+                    ResponseCode::SERVFAIL | ResponseCode::REFUSED
+                ) =>
+            {
+                response
+            }
+            failed => {
+                let stale = self.max_stale.and_then(|_| self.lookup_stale(&key));
+                return match stale {
+                    Some(entry) => {
+                        eprintln!("Cache: serving stale answers for {}", question.domain_name);
+                        log::cache_hit();
+                        Ok(entry.response(query))
+                    }
+                    None => failed,
+                };
+            }
+        };
+
+        if let Some(ttl_clamp) = &self.ttl_clamp {
+            ttl_clamp.clamp(&mut response);
+        }
+        if let Some(entry) = CacheEntry::from_response(&response) {
+            self.store_shared(&key, &entry);
+            self.store(key, entry);
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::edns::OptRecord;
+    use crate::handler::{ForwardHandler, Pipeline, StaticAnswerHandler, TtlClampHandler};
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+    use crate::record::{RecordClass, RecordType};
+    use crate::upstream::MockUpstream;
+
+    /// Shared cache in memory, ignoring expiration
+    #[derive(Default)]
+    struct MemoryBackend {
+        values: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    impl CacheBackend for MemoryBackend {
+        fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.values.lock().unwrap().get(key).cloned())
+        }
+
+        fn set(&self, key: &str, value: &[u8], _ttl: Duration) -> Result<()> {
+            self.values
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), value.to_vec());
+            Ok(())
+        }
+    }
+
+    /// Upstream stand-in answering every query with the same address
+    struct AnswerHandler;
+
+    impl Handler for AnswerHandler {
+        fn handle(&self, request: &Request, _next: Next<'_>) -> Result<DnsPacket> {
+            let question = &request.query.questions[0];
+            Ok(response_builder(&request.query)
+                .answers(vec![DnsRecord::new(
+                    question.domain_name.clone(),
+                    RecordType::A,
+                    RecordClass::IN,
+                    300,
+                    Ipv4Addr::new(192, 0, 2, 1),
+                )])
+                .build())
+        }
+    }
+
+    #[test]
+    fn test_cached_answers_have_remaining_ttl() {
+        let cache = CacheHandler::new(10);
+        let key = (DomainName::from("example.com"), 1, 1, false, false);
+        let answer = DnsRecord::new(
+            DomainName::from("example.com"),
+            RecordType::A,
+            RecordClass::IN,
+            300,
+            Ipv4Addr::new(192, 0, 2, 1),
+        );
+        cache.store(
+            key.clone(),
+            CacheEntry::new(ResponseCode::NOERROR, vec![answer], Vec::new()),
+        );
+
+        // pretend the answer was stored 100 seconds ago
+        let stored_at = Instant::now() - Duration::from_secs(100);
+        cache
+            .entries
+            .lock()
+            .unwrap()
+            .get_mut(&key)
+            .unwrap()
+            .stored_at = stored_at;
+        assert_eq!(cache.lookup(&key).unwrap().answers[0].ttl, 200);
+
+        let stored_at = Instant::now() - Duration::from_secs(300);
+        cache
+            .entries
+            .lock()
+            .unwrap()
+            .get_mut(&key)
+            .unwrap()
+            .stored_at = stored_at;
+        assert!(cache.lookup(&key).is_none());
+    }
+
+    #[test]
+    fn test_expired_answers_are_kept_for_errors() {
+        let cache = CacheHandler::new(10).stale_if_error(Duration::from_secs(3600));
+        let key = (DomainName::from("example.com"), 1, 1, false, false);
+        let answer = DnsRecord::new(
+            DomainName::from("example.com"),
+            RecordType::A,
+            RecordClass::IN,
+            300,
+            Ipv4Addr::new(192, 0, 2, 1),
+        );
+        cache.store(
+            key.clone(),
+            CacheEntry::new(ResponseCode::NOERROR, vec![answer], Vec::new()),
+        );
+
+        let set_age = |age| {
+            cache
+                .entries
+                .lock()
+                .unwrap()
+                .get_mut(&key)
+                .unwrap()
+                .stored_at = Instant::now() - Duration::from_secs(age);
+        };
+
+        set_age(400);
+        assert!(cache.lookup(&key).is_none());
+        assert_eq!(cache.lookup_stale(&key).unwrap().answers[0].ttl, STALE_TTL);
+
+        set_age(4000);
+        assert!(cache.lookup_stale(&key).is_none());
+    }
+
+    #[test]
+    fn test_answers_are_shared_between_caches() {
+        let backend = Arc::new(MemoryBackend::default());
+        let query = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from("Example.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+
+        let resolving = Pipeline::new()
+            .with(CacheHandler::new(10).shared(backend.clone()))
+            .with(AnswerHandler);
+        let resolved = resolving
+            .handle(&Request::new(query.clone(), None))
+            .unwrap();
+        assert!(backend
+            .values
+            .lock()
+            .unwrap()
+            .contains_key("dns:example.com.:1:1"));
+
+        // answered by the other cache, nothing behind it would answer
+        let other = Pipeline::new().with(CacheHandler::new(10).shared(backend));
+        let shared = other.handle(&Request::new(query, None)).unwrap();
+        assert_eq!(shared.header.rescode, ResponseCode::NOERROR);
+        assert_eq!(shared.answers, resolved.answers);
+    }
+
+    /// Upstream stand-in answering validated, with a signature for DO queries
+    struct SignedHandler {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Handler for SignedHandler {
+        fn handle(&self, request: &Request, _next: Next<'_>) -> Result<DnsPacket> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            let name = request.query.questions[0].domain_name.clone();
+            let mut answers = vec![DnsRecord::new(
+                name.clone(),
+                RecordType::A,
+                RecordClass::IN,
+                300,
+                Ipv4Addr::new(192, 0, 2, 1),
+            )];
+            if request.query.dnssec_ok() {
+                answers.push(DnsRecord::new(
+                    name,
+                    RecordType::RRSIG,
+                    RecordClass::IN,
+                    300,
+                    RecordData::Unknown(vec![0; 20]),
+                ));
+            }
+            Ok(response_builder(&request.query)
+                .authed_data(true)
+                .answers(answers)
+                .build())
+        }
+    }
+
+    #[test]
+    fn test_dnssec_answers_are_cached_apart() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let pipeline = Pipeline::new()
+            .with(CacheHandler::new(10))
+            .with(SignedHandler {
+                calls: calls.clone(),
+            });
+        let query = |dnssec_ok| {
+            let mut opt = OptRecord::new(1232);
+            opt.dnssec_ok = dnssec_ok;
+            let query = DnsPacket::builder()
+                .question(DnsQuestion::new(
+                    DomainName::from("example.com"),
+                    QueryType::A,
+                    QueryClass::IN,
+                ))
+                .opt(Some(opt))
+                .build();
+            pipeline.handle(&Request::new(query, None)).unwrap()
+        };
+
+        query(true);
+        let signed = query(true);
+        assert_eq!(signed.answers.len(), 2);
+        assert!(signed.header.authed_data);
+
+        query(false);
+        let unsigned = query(false);
+        assert_eq!(unsigned.answers.len(), 1);
+        assert!(!unsigned.header.authed_data);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    /// Validating upstream stand-in, answers are bogus and pass only with checking disabled
+    struct BogusHandler;
+
+    impl Handler for BogusHandler {
+        fn handle(&self, request: &Request, _next: Next<'_>) -> Result<DnsPacket> {
+            if !request.query.header.checking_disabled {
+                return Ok(response_builder(&request.query)
+                    .rescode(ResponseCode::SERVFAIL)
+                    .build());
+            }
+            Ok(response_builder(&request.query)
+                .answers(vec![DnsRecord::new(
+                    request.query.questions[0].domain_name.clone(),
+                    RecordType::A,
+                    RecordClass::IN,
+                    300,
+                    Ipv4Addr::new(192, 0, 2, 66),
+                )])
+                .build())
+        }
+    }
+
+    #[test]
+    fn test_unchecked_answers_are_cached_apart() {
+        let pipeline = Pipeline::new()
+            .with(CacheHandler::new(10))
+            .with(BogusHandler);
+        let query = |checking_disabled| {
+            let mut query = DnsPacket::builder()
+                .question(DnsQuestion::new(
+                    DomainName::from("example.com"),
+                    QueryType::A,
+                    QueryClass::IN,
+                ))
+                .build();
+            query.header.checking_disabled = checking_disabled;
+            pipeline.handle(&Request::new(query, None)).unwrap()
+        };
+
+        assert_eq!(query(true).answers.len(), 1);
+        let checked = query(false);
+        assert_eq!(checked.header.rescode, ResponseCode::SERVFAIL);
+        assert!(checked.answers.is_empty());
+        assert_eq!(query(true).answers.len(), 1);
+    }
+
+    #[test]
+    fn test_size_is_tracked() {
+        let cache = CacheHandler::new(1);
+        let answer = |name| {
+            DnsRecord::new(
+                DomainName::from(name),
+                RecordType::A,
+                RecordClass::IN,
+                0,
+                Ipv4Addr::new(192, 0, 2, 1),
+            )
+        };
+        let entry = |name| CacheEntry::new(ResponseCode::NOERROR, vec![answer(name)], Vec::new());
+
+        let key = (DomainName::from("example.com"), 1, 1, false, false);
+        cache.store(key.clone(), entry("example.com"));
+        let (entries, bytes) = cache.stats.size();
+        assert_eq!(entries, 1);
+        assert!(bytes > 0);
+        cache.store(key, entry("example.com"));
+        assert_eq!(cache.stats.size(), (1, bytes));
+
+        // the expired entry is evicted to make room
+        cache.store(
+            (DomainName::from("example.org"), 1, 1, false, false),
+            entry("example.org"),
+        );
+        assert_eq!(cache.stats.size(), (1, bytes));
+    }
+
+    /// Cache shared with the test, so it can age the entries
+    struct SharedCache(Arc<CacheHandler>);
+
+    impl Handler for SharedCache {
+        fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+            self.0.handle(request, next)
+        }
+    }
+
+    #[test]
+    fn test_stale_answers_replace_upstream_errors() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let upstream = MockUpstream::new({
+            let failing = failing.clone();
+            move |query: &DnsPacket| {
+                let response = DnsPacket::builder()
+                    .header(query.header)
+                    .response()
+                    .questions(query.questions.clone());
+                if failing.load(Ordering::Relaxed) {
+                    return Ok(response.rescode(ResponseCode::SERVFAIL).build());
+                }
+                Ok(response
+                    .answer(DnsRecord::new(
+                        query.questions[0].domain_name.clone(),
+                        RecordType::A,
+                        RecordClass::IN,
+                        300,
+                        Ipv4Addr::new(192, 0, 2, 1),
+                    ))
+                    .build())
+            }
+        });
+        let cache = Arc::new(CacheHandler::new(10).stale_if_error(Duration::from_secs(3600)));
+        let pipeline = Pipeline::new()
+            .with(SharedCache(cache.clone()))
+            .with(ForwardHandler::new(Arc::new(upstream)))
+            .with(StaticAnswerHandler::default());
+        let query = Request::new(
+            DnsPacket::builder()
+                .question(DnsQuestion::new(
+                    DomainName::from("example.com"),
+                    QueryType::A,
+                    QueryClass::IN,
+                ))
+                .build(),
+            None,
+        );
+
+        pipeline.handle(&query).unwrap();
+        for entry in cache.entries.lock().unwrap().values_mut() {
+            entry.stored_at = Instant::now() - Duration::from_secs(400);
+        }
+        failing.store(true, Ordering::Relaxed);
+
+        let response = pipeline.handle(&query).unwrap();
+        assert_eq!(response.header.rescode, ResponseCode::NOERROR);
+        assert_eq!(response.answers[0].data, Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(response.answers[0].ttl, STALE_TTL);
+
+        // without a stale answer the error is relayed, not answered by the handlers behind
+        let response = pipeline
+            .handle(&Request::new(
+                DnsPacket::builder()
+                    .question(DnsQuestion::new(
+                        DomainName::from("example.org"),
+                        QueryType::UNKNOWN(28),
+                        QueryClass::IN,
+                    ))
+                    .build(),
+                None,
+            ))
+            .unwrap();
+        assert_eq!(response.header.rescode, ResponseCode::SERVFAIL);
+        assert!(response.answers.is_empty());
+    }
+
+    /// Upstream stand-in answering with a TTL of one second
+    struct ShortTtlHandler {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Handler for ShortTtlHandler {
+        fn handle(&self, request: &Request, _next: Next<'_>) -> Result<DnsPacket> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok(response_builder(&request.query)
+                .answer(DnsRecord::new(
+                    request.query.questions[0].domain_name.clone(),
+                    RecordType::A,
+                    RecordClass::IN,
+                    1,
+                    Ipv4Addr::new(192, 0, 2, 1),
+                ))
+                .build())
+        }
+    }
+
+    #[test]
+    fn test_clamped_ttls_are_cached() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = Arc::new(CacheHandler::new(10).clamp_ttl(60, u32::MAX));
+        let pipeline = Pipeline::new()
+            .with(TtlClampHandler::new(60, u32::MAX))
+            .with(SharedCache(cache.clone()))
+            .with(ShortTtlHandler {
+                calls: calls.clone(),
+            });
+        let query = Request::new(
+            DnsPacket::builder()
+                .question(DnsQuestion::new(
+                    DomainName::from("example.com"),
+                    QueryType::A,
+                    QueryClass::IN,
+                ))
+                .build(),
+            None,
+        );
+
+        assert_eq!(pipeline.handle(&query).unwrap().answers[0].ttl, 60);
+        // long past the upstream's TTL, but not the clamped one
+        for entry in cache.entries.lock().unwrap().values_mut() {
+            entry.stored_at = Instant::now() - Duration::from_secs(10);
+        }
+        // answered from the cache, the floor applies to the remaining TTL too
+        assert_eq!(pipeline.handle(&query).unwrap().answers[0].ttl, 60);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,86 @@
+use anyhow::Result;
+
+use super::{response_builder, Handler, Next, Request};
+use crate::domain_name::DomainName;
+use crate::header::ResponseCode;
+use crate::packet::DnsPacket;
+
+/// Canary domain of Firefox: NXDOMAIN disables its DNS over HTTPS
+pub const MOZILLA_CANARY: &str = "use-application-dns.net";
+
+/// Answers queries for canary domains with NXDOMAIN
+///
+/// Browsers and operating systems query a canary domain to learn whether the network wants
+/// them to keep using its resolver rather than their built-in encrypted DNS. Only the names
+/// themselves are canaries, not their subdomains.
+pub struct CanaryHandler {
+    canaries: Vec<DomainName>,
+}
+
+impl CanaryHandler {
+    pub fn new(canaries: impl IntoIterator<Item = DomainName>) -> Self {
+        Self {
+            canaries: canaries.into_iter().collect(),
+        }
+    }
+
+    fn is_canary(&self, domain_name: &DomainName) -> bool {
+        self.canaries
+            .iter()
+            .any(|canary| canary.eq_ignore_case(domain_name))
+    }
+}
+
+impl Handler for CanaryHandler {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+        let query = &request.query;
+        if !query
+            .questions
+            .iter()
+            .any(|question| self.is_canary(&question.domain_name))
+        {
+            return next.run(request);
+        }
+
+        Ok(response_builder(query)
+            .recursion_available(true)
+            .rescode(ResponseCode::NXDOMAIN)
+            .build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::{Pipeline, StaticAnswerHandler};
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+
+    fn resolve(name: &str) -> ResponseCode {
+        let pipeline = Pipeline::new()
+            .with(CanaryHandler::new([DomainName::from(MOZILLA_CANARY)]))
+            .with(StaticAnswerHandler::default());
+        let query = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from(name),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+
+        pipeline
+            .handle(&Request::new(query, None))
+            .unwrap()
+            .header
+            .rescode
+    }
+
+    #[test]
+    fn test_only_canaries_are_nxdomain() {
+        assert_eq!(resolve("Use-Application-DNS.net"), ResponseCode::NXDOMAIN);
+        assert_eq!(
+            resolve("www.use-application-dns.net"),
+            ResponseCode::NOERROR
+        );
+        assert_eq!(resolve("example.com"), ResponseCode::NOERROR);
+    }
+}
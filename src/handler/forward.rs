@@ -0,0 +1,649 @@
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::Result;
+
+use super::{response_builder, Handler, Next, Request};
+#[cfg(feature = "dnssec")]
+use crate::edns::OPTION_EXTENDED_ERROR;
+use crate::edns::{EdnsOption, OptRecord, OPTION_CLIENT_SUBNET};
+use crate::header::ResponseCode;
+use crate::log;
+use crate::packet::DnsPacket;
+use crate::question::DnsQuestion;
+use crate::record::{DnsRecord, RecordData, RecordType};
+#[cfg(feature = "dnssec")]
+use crate::stats;
+use crate::trace;
+use crate::upstream::{Upstream, MAX_UDP_PAYLOAD_SIZE};
+#[cfg(feature = "dnssec")]
+use crate::validator::{Security, ValidationMode, Validator};
+
+/// DNAME (RFC 6672), not modelled, its synthesized CNAME carries the chain on
+const TYPE_DNAME: u16 = 39;
+
+/// Extended DNS error "DNSSEC Bogus" (RFC 8914 section 4.7)
+#[cfg(feature = "dnssec")]
+const EDE_DNSSEC_BOGUS: u16 = 6;
+
+/// Forwards queries to the upstream, queries without any answer are passed to the next handlers
+/// (refused when there are none)
+///
+/// Failed exchanges (e.g. upstream timeout) are answered with SERVFAIL, errors of the upstream
+/// (SERVFAIL, REFUSED, ...) are relayed, so caches in front can fall back to stale answers.
+/// When the upstream says that none of the queried names exists, its NXDOMAIN (and AD flag)
+/// is relayed.
+///
+/// EDNS options not meant only for the next hop are passed on in both directions, including
+/// ones unknown to us, so new EDNS extensions work end-to-end.
+///
+/// Authority and additional records of the upstream response are not relayed (except its
+/// OPT), only the answers. Negative answers (NXDOMAIN, or NODATA when the upstream proves it
+/// with SOA) carry the zone's SOA in the authority section.
+///
+/// The DO bit of the client is passed on. Clients which set it get the signatures with the
+/// answers and the proofs of nonexistence with negative answers, others get neither.
+///
+/// With a [`Validator`], answers are queried with DO and CD set and validated by us, the AD
+/// flag tells our own result. Bogus answers are SERVFAIL, or just logged and counted in the
+/// permissive mode. Clients which set CD get the answers unchecked (RFC 4035 section 3.2.2).
+pub struct ForwardHandler {
+    upstream: Arc<dyn Upstream>,
+    strip_client_subnet: bool,
+    drop_unknown_options: bool,
+    #[cfg(feature = "dnssec")]
+    validator: Option<Arc<Validator>>,
+}
+
+impl ForwardHandler {
+    pub fn new(upstream: Arc<dyn Upstream>) -> Self {
+        Self {
+            upstream,
+            strip_client_subnet: false,
+            drop_unknown_options: false,
+            #[cfg(feature = "dnssec")]
+            validator: None,
+        }
+    }
+
+    /// Validates answers with DNSSEC instead of trusting the upstream's AD flag
+    #[cfg(feature = "dnssec")]
+    pub fn validator(mut self, validator: Arc<Validator>) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    fn is_validating(&self) -> bool {
+        #[cfg(feature = "dnssec")]
+        return self.validator.is_some();
+        #[cfg(not(feature = "dnssec"))]
+        false
+    }
+
+    /// Passes on only EDNS options we understand (in queries and responses)
+    pub fn drop_unknown_options(mut self) -> Self {
+        self.drop_unknown_options = true;
+        self
+    }
+
+    fn is_passed_on(&self, option: &EdnsOption) -> bool {
+        !self.drop_unknown_options || OptRecord::is_known(option.code)
+    }
+
+    /// Removes EDNS Client Subnet from forwarded queries, so upstreams don't learn client networks
+    pub fn strip_client_subnet(mut self) -> Self {
+        self.strip_client_subnet = true;
+        self
+    }
+}
+
+impl ForwardHandler {
+    /// Sends the single question `q` of the `query` to the upstream
+    fn forward(
+        &self,
+        query: &DnsPacket,
+        q: &DnsQuestion,
+        opt: &Option<OptRecord>,
+    ) -> Result<DnsPacket> {
+        let forwarded_msg_id = rand::random();
+        let mut forwarded = DnsPacket::builder()
+            .header(query.header)
+            .id(forwarded_msg_id)
+            .authed_data(true) // we want to know whether the upstream validated the response (RFC 6840)
+            .question(q.clone())
+            .opt(opt.clone())
+            .build();
+        // validator needs the signatures, including those the upstream finds bogus
+        forwarded.header.checking_disabled |= self.is_validating();
+        if log::is_text() {
+            println!(">>> Forwarding > Sent DNS packet:\n{}", forwarded);
+        }
+
+        let received = self.upstream.exchange(&forwarded)?;
+
+        if log::is_text() {
+            println!("<<< Forwarding < Received DNS packet:\n{}", received);
+        }
+
+        if received.header.id != forwarded_msg_id {
+            anyhow::bail!(
+                "Forwarding: ID mismatch: expected ID {}, got {}",
+                forwarded_msg_id,
+                received.header.id,
+            );
+        }
+
+        Ok(received)
+    }
+}
+
+impl Handler for ForwardHandler {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+        let query = &request.query;
+        let mut resolved_answers: Vec<DnsRecord> = Vec::new(); // answers returned by extrenal resolver
+        let mut upstream_opt: Option<OptRecord> = None;
+        let mut nxdomain = !query.questions.is_empty();
+        let mut nodata = !query.questions.is_empty();
+        let mut authed_data = true;
+        let mut negative_records: Vec<DnsRecord> = Vec::new(); // SOAs (and proofs) of negative answers
+
+        // client's EDNS is passed on, except options meant only for us (we never add ECS ourselves)
+        let forwarded_opt = query.opt.as_ref().map(|opt| OptRecord {
+            udp_payload_size: opt.udp_payload_size.min(MAX_UDP_PAYLOAD_SIZE),
+            options: opt
+                .end_to_end_options()
+                .filter(|o| !(self.strip_client_subnet && o.code == OPTION_CLIENT_SUBNET))
+                .filter(|o| self.is_passed_on(o))
+                .cloned()
+                .collect(),
+            ..opt.clone()
+        });
+        let client_dnssec_ok = query.dnssec_ok();
+        let forwarded_opt = match self.is_validating() {
+            true => Some(OptRecord {
+                dnssec_ok: true,
+                ..forwarded_opt.unwrap_or_else(|| OptRecord::new(MAX_UDP_PAYLOAD_SIZE))
+            }),
+            false => forwarded_opt,
+        };
+
+        // Resolver can work only with a single question, we need to split them into separate DNS packets,
+        // send them concurrently and then merge responses into one DNS packet (in order of questions)
+        let received: Vec<Result<DnsPacket>> = match &query.questions[..] {
+            [q] => vec![self.forward(query, q, &forwarded_opt)],
+            questions => thread::scope(|scope| {
+                let (span, forwarded_opt) = (trace::current(), &forwarded_opt);
+                let exchanges: Vec<_> = questions
+                    .iter()
+                    .map(|q| {
+                        scope.spawn(move || {
+                            trace::set_current(span);
+                            self.forward(query, q, forwarded_opt)
+                        })
+                    })
+                    .collect();
+
+                exchanges
+                    .into_iter()
+                    .map(|exchange| exchange.join().expect("forwarding thread panicked"))
+                    .collect()
+            }),
+        };
+
+        for (q, received) in query.questions.iter().zip(received) {
+            // unreachable or silent upstream must not leave the client without an answer
+            let received = match received {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("Forwarding: {:#}", e);
+                    return Ok(response_builder(query)
+                        .rescode(ResponseCode::SERVFAIL)
+                        .build());
+                }
+            };
+            if !matches!(
+                received.header.rescode,
+                ResponseCode::NOERROR | ResponseCode::NXDOMAIN
+            ) {
+                eprintln!(
+                    "Forwarding: upstream answered {:?} for {}",
+                    received.header.rescode, q.domain_name
+                );
+                return Ok(response_builder(query)
+                    .rescode(received.header.rescode)
+                    .build());
+            }
+            #[cfg(feature = "dnssec")]
+            if let Some(validator) = &self.validator {
+                let security = match query.header.checking_disabled {
+                    true => Security::Insecure,
+                    false => validator.validate(q, &received),
+                };
+                match &security {
+                    Security::Bogus(reason) => {
+                        stats::bogus(&q.domain_name);
+                        if validator.mode() == ValidationMode::Enforce {
+                            eprintln!("DNSSEC: bogus answer for {} ({})", q.domain_name, reason);
+                            let mut response = response_builder(query)
+                                .rescode(ResponseCode::SERVFAIL)
+                                .build();
+                            if let Some(opt) = response.opt.as_mut() {
+                                opt.options.push(EdnsOption {
+                                    code: OPTION_EXTENDED_ERROR,
+                                    data: EDE_DNSSEC_BOGUS.to_be_bytes().to_vec(),
+                                });
+                            }
+                            return Ok(response);
+                        }
+                        eprintln!(
+                            "DNSSEC: bogus answer for {} ({}), returned in permissive mode",
+                            q.domain_name, reason
+                        );
+                    }
+                    security => stats::validated(*security == Security::Secure),
+                }
+                authed_data &= security == Security::Secure;
+            }
+            let authorities = negative_authorities(received.authorities, q, client_dnssec_ok);
+            nxdomain &= received.header.rescode == ResponseCode::NXDOMAIN;
+            // no data of the queried type, SOA tells us it's an authoritative "no"
+            nodata &= received.header.rescode == ResponseCode::NOERROR
+                && received.answers.is_empty()
+                && !authorities.is_empty();
+            if !self.is_validating() {
+                authed_data &= received.header.authed_data;
+            }
+            negative_records.extend(authorities);
+            let answers = scrub(received.answers, q);
+            resolved_answers.extend(match client_dnssec_ok {
+                true => answers,
+                false => strip_dnssec(answers, q),
+            });
+            if upstream_opt.is_none() {
+                upstream_opt = received.opt;
+            }
+        }
+
+        // AD only for clients which understand it (RFC 6840 section 5.8)
+        let wants_ad = query.wants_authed_data();
+        let mut response = if nxdomain && resolved_answers.is_empty() {
+            response_builder(query)
+                .rescode(ResponseCode::NXDOMAIN)
+                .authed_data(authed_data && wants_ad)
+                .authorities(negative_records)
+                .build()
+        } else if nodata && resolved_answers.is_empty() {
+            response_builder(query)
+                .authed_data(authed_data && wants_ad)
+                .authorities(negative_records)
+                .build()
+        } else if resolved_answers.is_empty() {
+            return next.run(request);
+        } else {
+            response_builder(query)
+                .authed_data(self.is_validating() && authed_data && wants_ad)
+                .answers(resolved_answers)
+                .build()
+        };
+
+        // upstream's EDNS information (extended errors, ...) is merged into our own OPT
+        if let (Some(opt), Some(upstream_opt)) = (response.opt.as_mut(), upstream_opt) {
+            opt.extended_rcode = upstream_opt.extended_rcode;
+            opt.options.extend(
+                upstream_opt
+                    .end_to_end_options()
+                    .filter(|o| self.is_passed_on(o))
+                    .cloned(),
+            );
+        }
+
+        Ok(response)
+    }
+}
+
+/// SOA records from the authority section of a negative answer to the question
+///
+/// Only SOA of a zone containing the queried name is relevant, negative caches take
+/// their TTL from it (RFC 2308 section 5). Clients which set DO also get the proofs of
+/// nonexistence from the zone (NSEC, NSEC3 and signatures, RFC 4035 section 3.1.3).
+/// Other authority records are not relayed.
+fn negative_authorities(
+    authorities: Vec<DnsRecord>,
+    question: &DnsQuestion,
+    dnssec_ok: bool,
+) -> Vec<DnsRecord> {
+    let (soas, others): (Vec<DnsRecord>, Vec<DnsRecord>) =
+        authorities.into_iter().partition(|authority| {
+            authority.record_type == RecordType::SOA
+                && question.domain_name.is_subdomain_of(&authority.domain_name)
+        });
+    if !dnssec_ok || soas.is_empty() {
+        return soas;
+    }
+    let proofs: Vec<DnsRecord> = others
+        .into_iter()
+        .filter(|authority| {
+            matches!(
+                authority.record_type,
+                RecordType::NSEC | RecordType::NSEC3 | RecordType::RRSIG
+            ) && soas
+                .iter()
+                .any(|soa| authority.domain_name.is_subdomain_of(&soa.domain_name))
+        })
+        .collect();
+    soas.into_iter().chain(proofs).collect()
+}
+
+/// Drops signatures and denial records the client didn't ask for (RFC 4035 section 3.2.1)
+///
+/// Upstreams add them to answers only when asked with DO, which a validating forwarder
+/// does on its own.
+fn strip_dnssec(answers: Vec<DnsRecord>, question: &DnsQuestion) -> Vec<DnsRecord> {
+    let query_type = u16::from(question.query_type.clone());
+    answers
+        .into_iter()
+        .filter(|answer| {
+            !matches!(
+                answer.record_type,
+                RecordType::RRSIG | RecordType::NSEC | RecordType::NSEC3
+            ) || u16::from(answer.record_type.clone()) == query_type
+        })
+        .collect()
+}
+
+/// Drops records outside the bailiwick of the question
+///
+/// Only records owned by the queried name (or names below it) and by the names its CNAME
+/// chain leads to are relayed, together with DNAMEs above them the chain was synthesized
+/// from, so a misbehaving upstream can't inject data for unrelated names into the response
+/// or the cache.
+fn scrub(answers: Vec<DnsRecord>, question: &DnsQuestion) -> Vec<DnsRecord> {
+    let before = answers.len();
+    // records of the chain may come in any order
+    let mut chain = vec![question.domain_name.canonicalize()];
+    loop {
+        let length = chain.len();
+        for answer in &answers {
+            if let (RecordType::CNAME, RecordData::Name(target)) =
+                (&answer.record_type, &answer.data)
+            {
+                let target = target.canonicalize();
+                if chain.contains(&answer.domain_name.canonicalize()) && !chain.contains(&target) {
+                    chain.push(target);
+                }
+            }
+        }
+        if chain.len() == length {
+            break;
+        }
+    }
+
+    let answers: Vec<DnsRecord> = answers
+        .into_iter()
+        .filter(|answer| {
+            chain.iter().any(|name| {
+                answer.domain_name.is_subdomain_of(name)
+                    || (answer.record_type == RecordType::UNKNOWN(TYPE_DNAME)
+                        && name.is_subdomain_of(&answer.domain_name))
+            })
+        })
+        .collect();
+
+    if answers.len() != before {
+        eprintln!(
+            "Forwarding: dropped {} out-of-bailiwick record(s) in response for {}",
+            before - answers.len(),
+            question.domain_name
+        );
+    }
+
+    answers
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::domain_name::DomainName;
+    use crate::edns::{EdnsOption, OPTION_CLIENT_SUBNET, OPTION_COOKIE, OPTION_EXTENDED_ERROR};
+    use crate::handler::{Pipeline, Request};
+    use crate::question::{QueryClass, QueryType};
+    use crate::record::{RecordClass, RecordData, RecordType, Soa};
+    use crate::upstream::MockUpstream;
+
+    #[test]
+    fn test_questions_are_forwarded_separately() {
+        let upstream = MockUpstream::new(|query: &DnsPacket| {
+            assert_eq!(query.questions.len(), 1);
+
+            let question = &query.questions[0];
+            let answer = DnsRecord::new(
+                question.domain_name.clone(),
+                RecordType::A,
+                RecordClass::IN,
+                300,
+                Ipv4Addr::new(192, 0, 2, question.domain_name.label_count() as u8),
+            );
+
+            Ok(DnsPacket::builder()
+                .header(query.header)
+                .response()
+                .questions(query.questions.clone())
+                .answer(answer)
+                .build())
+        });
+
+        let query = DnsPacket::builder()
+            .id(1234)
+            .question(DnsQuestion::new(
+                DomainName::from("abc.example.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .question(DnsQuestion::new(
+                DomainName::from("example.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+
+        let pipeline = Pipeline::new().with(ForwardHandler::new(Arc::new(upstream)));
+        let response = pipeline.handle(&Request::new(query, None)).unwrap();
+
+        assert_eq!(response.header.id, 1234);
+        assert_eq!(response.header.answer_entries, 2);
+        assert_eq!(response.answers[0].data, Ipv4Addr::new(192, 0, 2, 3));
+        assert_eq!(response.answers[1].data, Ipv4Addr::new(192, 0, 2, 2));
+    }
+
+    #[test]
+    fn test_empty_answer_without_soa_is_not_made_up() {
+        let upstream = MockUpstream::new(|query: &DnsPacket| {
+            Ok(DnsPacket::builder()
+                .header(query.header)
+                .response()
+                .questions(query.questions.clone())
+                .build())
+        });
+        let query = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from("example.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+
+        // nothing behind the forwarder, as in the server's pipeline with an upstream
+        let pipeline = Pipeline::new().with(ForwardHandler::new(Arc::new(upstream)));
+        let response = pipeline.handle(&Request::new(query, None)).unwrap();
+
+        assert_eq!(response.header.rescode, ResponseCode::REFUSED);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn test_denial_proofs_only_for_dnssec_clients() {
+        let upstream = MockUpstream::new(|query: &DnsPacket| {
+            let zone = DomainName::from("example.com");
+            let record = |record_type| {
+                let data = match record_type {
+                    RecordType::SOA => RecordData::Soa(Soa {
+                        mname: DomainName::from("ns.example.com"),
+                        rname: DomainName::from("hostmaster.example.com"),
+                        serial: 1,
+                        refresh: 7200,
+                        retry: 3600,
+                        expire: 1209600,
+                        minimum: 300,
+                    }),
+                    _ => RecordData::Unknown(vec![0; 20]),
+                };
+                DnsRecord::new(zone.clone(), record_type, RecordClass::IN, 300, data)
+            };
+            Ok(DnsPacket::builder()
+                .header(query.header)
+                .response()
+                .rescode(ResponseCode::NXDOMAIN)
+                .authed_data(true)
+                .questions(query.questions.clone())
+                .authorities([RecordType::SOA, RecordType::RRSIG, RecordType::NSEC].map(record))
+                .build())
+        });
+        let pipeline = Pipeline::new().with(ForwardHandler::new(Arc::new(upstream)));
+        let resolve = |opt: Option<OptRecord>| {
+            let query = DnsPacket::builder()
+                .question(DnsQuestion::new(
+                    DomainName::from("missing.example.com"),
+                    QueryType::A,
+                    QueryClass::IN,
+                ))
+                .opt(opt)
+                .build();
+            pipeline.handle(&Request::new(query, None)).unwrap()
+        };
+
+        let mut opt = OptRecord::new(1232);
+        opt.dnssec_ok = true;
+        let response = resolve(Some(opt));
+        assert_eq!(response.header.rescode, ResponseCode::NXDOMAIN);
+        assert_eq!(response.authorities.len(), 3);
+        assert!(response.header.authed_data);
+
+        let response = resolve(None);
+        assert_eq!(response.header.rescode, ResponseCode::NXDOMAIN);
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(response.authorities[0].record_type, RecordType::SOA);
+        assert!(!response.header.authed_data);
+    }
+
+    #[test]
+    fn test_client_edns_is_forwarded() {
+        const UNKNOWN_OPTION: u16 = 65001; // reserved for local/experimental use
+
+        let upstream = MockUpstream::new(|query: &DnsPacket| {
+            let opt = query.opt.as_ref().expect("OPT should be forwarded");
+            assert!(opt.dnssec_ok);
+            let codes: Vec<u16> = opt.options.iter().map(|o| o.code).collect();
+            assert_eq!(codes, [OPTION_CLIENT_SUBNET, UNKNOWN_OPTION]);
+
+            let mut upstream_opt = OptRecord::new(4096);
+            upstream_opt.options.push(EdnsOption {
+                code: OPTION_EXTENDED_ERROR,
+                data: vec![0, 0],
+            });
+            Ok(DnsPacket::builder()
+                .header(query.header)
+                .response()
+                .questions(query.questions.clone())
+                .answer(DnsRecord::new(
+                    query.questions[0].domain_name.clone(),
+                    RecordType::A,
+                    RecordClass::IN,
+                    300,
+                    Ipv4Addr::new(192, 0, 2, 1),
+                ))
+                .opt(Some(upstream_opt))
+                .build())
+        });
+
+        let mut opt = OptRecord::new(4096);
+        opt.dnssec_ok = true;
+        for code in [OPTION_COOKIE, OPTION_CLIENT_SUBNET, UNKNOWN_OPTION] {
+            opt.options.push(EdnsOption { code, data: vec![] });
+        }
+        let query = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from("example.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .opt(Some(opt))
+            .build();
+
+        let pipeline = Pipeline::new().with(ForwardHandler::new(Arc::new(upstream)));
+        let response = pipeline.handle(&Request::new(query, None)).unwrap();
+
+        let opt = response.opt.unwrap();
+        assert!(opt.dnssec_ok);
+        assert_eq!(opt.options[0].code, OPTION_EXTENDED_ERROR);
+    }
+
+    #[test]
+    fn test_cname_chains_leaving_the_zone_are_kept() {
+        let upstream = MockUpstream::new(|query: &DnsPacket| {
+            let record = |name: &str, record_type, data: RecordData| {
+                DnsRecord::new(
+                    DomainName::from(name),
+                    record_type,
+                    RecordClass::IN,
+                    300,
+                    data,
+                )
+            };
+            let cname = |name, target| {
+                record(
+                    name,
+                    RecordType::CNAME,
+                    RecordData::Name(DomainName::from(target)),
+                )
+            };
+            let address = |name, address| record(name, RecordType::A, RecordData::A(address));
+            Ok(DnsPacket::builder()
+                .header(query.header)
+                .response()
+                .questions(query.questions.clone())
+                .answers(vec![
+                    cname("WWW.example.com", "www.example.com.cdn.cloudflare.net"),
+                    address("edge.cloudflare.net", Ipv4Addr::new(104, 16, 1, 1)),
+                    cname("www.example.com.cdn.cloudflare.net", "edge.cloudflare.net"),
+                    address("bank.example", Ipv4Addr::new(192, 0, 2, 66)),
+                ])
+                .build())
+        });
+
+        let query = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from("www.example.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+        let pipeline = Pipeline::new().with(ForwardHandler::new(Arc::new(upstream)));
+        let response = pipeline.handle(&Request::new(query, None)).unwrap();
+
+        let owners: Vec<String> = response
+            .answers
+            .iter()
+            .map(|answer| answer.domain_name.to_string())
+            .collect();
+        assert_eq!(
+            owners,
+            [
+                "WWW.example.com.",
+                "edge.cloudflare.net.",
+                "www.example.com.cdn.cloudflare.net."
+            ]
+        );
+    }
+}
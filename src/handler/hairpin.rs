@@ -0,0 +1,205 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+use super::{Handler, Next, Request};
+use crate::domain_name::DomainName;
+use crate::network::Network;
+use crate::packet::DnsPacket;
+use crate::record::RecordData;
+
+/// Client networks inside the LAN by default: private, unique local and loopback
+const LAN_NETWORKS: &[&str] = &[
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "127.0.0.0/8",
+    "fc00::/7",
+    "::1",
+];
+
+/// Public address (or addresses of a name) and the internal address replacing it
+#[derive(Debug, Clone, PartialEq)]
+pub struct HairpinRule {
+    public: Public,
+    internal: IpAddr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Public {
+    Address(IpAddr),
+    /// All addresses of the name
+    Name(DomainName),
+}
+
+impl HairpinRule {
+    /// Internal address replacing `address` of the record owned by `owner`
+    fn rewrite(&self, owner: &DomainName, address: IpAddr) -> Option<IpAddr> {
+        let matches = match &self.public {
+            Public::Address(public) => *public == address,
+            Public::Name(name) => name.eq_ignore_case(owner),
+        };
+        (matches && self.internal.is_ipv4() == address.is_ipv4()).then_some(self.internal)
+    }
+}
+
+/// Parses `<public address|name>=<internal address>`
+impl FromStr for HairpinRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (public, internal) = s
+            .split_once('=')
+            .context("hairpin rule must be <public address|name>=<internal address>")?;
+        let public = match public.parse() {
+            Ok(address) => Public::Address(address),
+            Err(_) => Public::Name(DomainName::from_unicode(public)?),
+        };
+        let internal = internal
+            .parse()
+            .with_context(|| format!("invalid internal address {:?}", internal))?;
+        Ok(Self { public, internal })
+    }
+}
+
+impl fmt::Display for HairpinRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.public {
+            Public::Address(address) => write!(f, "{} -> {}", address, self.internal),
+            Public::Name(name) => write!(f, "{} -> {}", name, self.internal),
+        }
+    }
+}
+
+/// Rewrites public addresses in answers to internal ones for clients inside the LAN
+///
+/// Servers behind NAT are reachable from the LAN only at their internal addresses, unless
+/// the router supports NAT loopback. Answers for LAN clients get the internal address
+/// instead of the public one (of any name, or of just the name in the rule), clients
+/// outside get the answers unchanged. Queries not from network (e.g. Unix sockets) come
+/// from inside. Rewritten answers lose the AD flag, they aren't what was signed.
+pub struct HairpinHandler {
+    rules: Vec<HairpinRule>,
+    clients: Vec<Network>,
+}
+
+impl HairpinHandler {
+    /// Rewrites answers for clients in private networks
+    pub fn new(rules: impl IntoIterator<Item = HairpinRule>) -> Self {
+        Self {
+            rules: rules.into_iter().collect(),
+            clients: LAN_NETWORKS
+                .iter()
+                .map(|network| network.parse().expect("valid LAN network"))
+                .collect(),
+        }
+    }
+
+    /// Rewrites answers for clients in `networks` instead of private networks
+    pub fn clients(mut self, networks: impl IntoIterator<Item = Network>) -> Self {
+        self.clients = networks.into_iter().collect();
+        self
+    }
+
+    fn rewrite(&self, owner: &DomainName, address: IpAddr) -> Option<IpAddr> {
+        self.rules
+            .iter()
+            .find_map(|rule| rule.rewrite(owner, address))
+    }
+}
+
+impl Handler for HairpinHandler {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+        let mut response = next.run(request)?;
+        let inside = request.client.is_none_or(|client| {
+            self.clients
+                .iter()
+                .any(|network| network.contains(client.ip()))
+        });
+        if !inside {
+            return Ok(response);
+        }
+
+        let mut rewritten = false;
+        for answer in response.answers.iter_mut() {
+            let address = match answer.data {
+                RecordData::A(address) => IpAddr::V4(address),
+                RecordData::Aaaa(address) => IpAddr::V6(address),
+                _ => continue,
+            };
+            answer.data = match self.rewrite(&answer.domain_name, address) {
+                Some(IpAddr::V4(internal)) => RecordData::A(internal),
+                Some(IpAddr::V6(internal)) => RecordData::Aaaa(internal),
+                None => continue,
+            };
+            rewritten = true;
+        }
+        if rewritten {
+            response.header.authed_data = false;
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::handler::{Pipeline, StaticAnswerHandler};
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+
+    fn resolve(handler: HairpinHandler, name: &str, client: &str) -> RecordData {
+        let pipeline = Pipeline::new()
+            .with(handler)
+            .with(StaticAnswerHandler::new(Ipv4Addr::new(203, 0, 113, 7), 60));
+        let query = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from(name),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+        let request = Request::new(query, Some(client.parse().unwrap()));
+        pipeline.handle(&request).unwrap().answers[0].data.clone()
+    }
+
+    #[test]
+    fn test_public_addresses_are_rewritten_inside() {
+        let internal = RecordData::A(Ipv4Addr::new(192, 168, 1, 5));
+        let public = RecordData::A(Ipv4Addr::new(203, 0, 113, 7));
+
+        let by_address = || HairpinHandler::new(["203.0.113.7=192.168.1.5".parse().unwrap()]);
+        assert_eq!(
+            resolve(by_address(), "myserver.example.com", "192.168.1.20:5353"),
+            internal
+        );
+        assert_eq!(
+            resolve(by_address(), "myserver.example.com", "198.51.100.1:5353"),
+            public
+        );
+
+        let by_name = HairpinHandler::new(["myserver.example.com=192.168.1.5".parse().unwrap()])
+            .clients(["10.0.0.0/8".parse().unwrap()]);
+        assert_eq!(
+            resolve(by_name, "MyServer.example.com", "10.1.2.3:5353"),
+            internal
+        );
+        let by_name = HairpinHandler::new(["myserver.example.com=192.168.1.5".parse().unwrap()]);
+        assert_eq!(
+            resolve(by_name, "other.example.com", "192.168.1.20:5353"),
+            public
+        );
+
+        // addresses of the other family are left alone
+        let ipv6 = HairpinHandler::new(["203.0.113.7=fd00::5".parse().unwrap()]);
+        assert_eq!(
+            resolve(ipv6, "myserver.example.com", "192.168.1.20:5353"),
+            public
+        );
+        assert!("203.0.113.7".parse::<HairpinRule>().is_err());
+    }
+}
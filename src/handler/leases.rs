@@ -0,0 +1,189 @@
+use anyhow::Result;
+
+use super::{response_builder, Handler, Next, Request};
+use crate::domain_name::DomainName;
+use crate::leases::{unix_time, Lease, LeaseSource};
+use crate::packet::DnsPacket;
+use crate::question::DnsQuestion;
+use crate::record::{DnsRecord, RecordClass, RecordData, RecordType};
+
+/// TTL of the answers, short since leases come and go (capped by the remaining lease time)
+const LEASE_TTL: u32 = 60;
+
+/// Answers A/AAAA and PTR queries for hosts holding a lease (e.g. from DHCP server)
+///
+/// Host `laptop` with a lease on 192.168.1.23 resolves as `laptop.<domain>` and
+/// `23.1.168.192.in-addr.arpa` points back to it.
+pub struct LeaseHandler {
+    source: Box<dyn LeaseSource>,
+    domain: DomainName,
+}
+
+impl LeaseHandler {
+    /// Host names of the leases from `source` are placed under `domain`
+    pub fn new(source: impl LeaseSource + 'static, domain: DomainName) -> Self {
+        Self {
+            source: Box::new(source),
+            domain,
+        }
+    }
+
+    fn host_name(&self, lease: &Lease) -> DomainName {
+        let mut name = DomainName::from_labels([lease.hostname.as_bytes()]);
+        for label in self.domain.labels() {
+            name.push_label(label);
+        }
+        name
+    }
+
+    /// Answers for the question, `None` if it's not about any leased host
+    fn answer(&self, question: &DnsQuestion, leases: &[Lease]) -> Option<Vec<DnsRecord>> {
+        let query_type = u16::from(question.query_type.clone());
+        let now = unix_time();
+        let ttl = |lease: &Lease| {
+            let remaining = lease
+                .expires
+                .map_or(u64::MAX, |expires| expires.saturating_sub(now));
+            remaining.min(LEASE_TTL as u64) as u32
+        };
+
+        let forward: Vec<&Lease> = leases
+            .iter()
+            .filter(|lease| self.host_name(lease).eq_ignore_case(&question.domain_name))
+            .collect();
+        if !forward.is_empty() {
+            // other types of a known host are answered with no data
+            let answers = forward
+                .into_iter()
+                .filter(|lease| u16::from(RecordType::of_address(&lease.address)) == query_type)
+                .map(|lease| {
+                    DnsRecord::new(
+                        question.domain_name.clone(),
+                        RecordType::of_address(&lease.address),
+                        RecordClass::IN,
+                        ttl(lease),
+                        lease.address,
+                    )
+                });
+            return Some(answers.collect());
+        }
+
+        let reverse = leases.iter().find(|lease| {
+            DomainName::reverse(lease.address).eq_ignore_case(&question.domain_name)
+        })?;
+        let answers = (query_type == u16::from(RecordType::PTR)).then(|| {
+            DnsRecord::new(
+                question.domain_name.clone(),
+                RecordType::PTR,
+                RecordClass::IN,
+                ttl(reverse),
+                RecordData::Name(self.host_name(reverse)),
+            )
+        });
+        Some(answers.into_iter().collect())
+    }
+}
+
+impl Handler for LeaseHandler {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+        let query = &request.query;
+        if query.questions.is_empty() {
+            return next.run(request);
+        }
+
+        let leases = self.source.active_leases();
+        let answers: Option<Vec<Vec<DnsRecord>>> = query
+            .questions
+            .iter()
+            .map(|question| self.answer(question, &leases))
+            .collect();
+
+        // questions about other names go on, e.g. to the upstream
+        match answers {
+            Some(answers) => Ok(response_builder(query)
+                .authoritative_answer(true)
+                .answers(answers.into_iter().flatten())
+                .build()),
+            None => next.run(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::handler::Pipeline;
+    use crate::header::ResponseCode;
+    use crate::question::{QueryClass, QueryType};
+
+    /// Fixed leases
+    struct StaticLeases(Vec<Lease>);
+
+    impl LeaseSource for StaticLeases {
+        fn active_leases(&self) -> Vec<Lease> {
+            self.0.clone()
+        }
+    }
+
+    const LAPTOP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 23));
+
+    fn resolve(name: &str, query_type: QueryType) -> DnsPacket {
+        let leases = StaticLeases(vec![Lease {
+            hostname: "laptop".to_string(),
+            address: LAPTOP,
+            expires: Some(unix_time() + 10),
+        }]);
+        let pipeline = Pipeline::new().with(LeaseHandler::new(leases, DomainName::from("lan")));
+        let query = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from(name),
+                query_type,
+                QueryClass::IN,
+            ))
+            .build();
+        pipeline.handle(&Request::new(query, None)).unwrap()
+    }
+
+    #[test]
+    fn test_leased_hosts_are_answered() {
+        let forward = resolve("Laptop.LAN", QueryType::A);
+        assert!(forward.header.authoritative_answer);
+        assert_eq!(forward.answers.len(), 1);
+        assert_eq!(forward.answers[0].data.address(), Some(LAPTOP));
+        assert!(forward.answers[0].ttl <= 10);
+
+        let reverse = resolve(
+            "23.1.168.192.in-addr.arpa",
+            QueryType::from(u16::from(RecordType::PTR)),
+        );
+        assert_eq!(
+            reverse.answers[0].data,
+            RecordData::Name(DomainName::from("laptop.lan"))
+        );
+    }
+
+    #[test]
+    fn test_other_types_of_leased_hosts_have_no_data() {
+        for (name, query_type) in [
+            ("laptop.lan", QueryType::from(u16::from(RecordType::AAAA))),
+            ("laptop.lan", QueryType::from(u16::from(RecordType::PTR))),
+            ("23.1.168.192.in-addr.arpa", QueryType::A),
+        ] {
+            let response = resolve(name, query_type);
+            assert_eq!(response.header.rescode, ResponseCode::NOERROR);
+            assert!(response.header.authoritative_answer, "{}", name);
+            assert!(response.answers.is_empty(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_other_names_are_passed_on() {
+        for name in ["desktop.lan", "laptop.example", "24.1.168.192.in-addr.arpa"] {
+            let response = resolve(name, QueryType::A);
+            assert_eq!(response.header.rescode, ResponseCode::REFUSED, "{}", name);
+            assert!(!response.header.authoritative_answer);
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::Result;
+
+use super::{Handler, Next, Request};
+use crate::header::ResponseCode;
+use crate::packet::DnsPacket;
+use crate::record::RecordType;
+use crate::tsig::TSIG_RECORD_TYPE;
+
+/// Leaves only the answers in positive responses of the following handlers
+///
+/// Authority records (NS of the zone) and additional records (glue) are only hints, clients
+/// asked for the answers. Dropping them keeps responses small, so fewer of them are
+/// truncated over UDP. Negative responses and referrals keep all sections, their SOA or NS
+/// records are what the client needs. Clients which set DO keep the NSEC and NSEC3 proofs
+/// (and their signatures) of wildcard answers, OPT and TSIG are always kept.
+pub struct MinimalResponsesHandler;
+
+impl Handler for MinimalResponsesHandler {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+        let mut response = next.run(request)?;
+        if response.header.rescode != ResponseCode::NOERROR || response.answers.is_empty() {
+            return Ok(response);
+        }
+
+        let dnssec_ok = request.query.dnssec_ok();
+        response.authorities.retain(|record| {
+            dnssec_ok
+                && matches!(
+                    record.record_type,
+                    RecordType::NSEC | RecordType::NSEC3 | RecordType::RRSIG
+                )
+        });
+        response
+            .additionals
+            .retain(|record| record.record_type == RecordType::UNKNOWN(TSIG_RECORD_TYPE));
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::domain_name::DomainName;
+    use crate::edns::OptRecord;
+    use crate::handler::{response_builder, Pipeline};
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+    use crate::record::{DnsRecord, RecordClass, RecordData};
+
+    /// Handler answering every query with `rescode`, NS records of the zone and their glue,
+    /// signed with TSIG if `signed`; queries with DO get the NSEC proof of a wildcard answer
+    struct ZoneAnswerHandler {
+        rescode: ResponseCode,
+        signed: bool,
+    }
+
+    impl Handler for ZoneAnswerHandler {
+        fn handle(&self, request: &Request, _next: Next<'_>) -> Result<DnsPacket> {
+            let name = DomainName::from("www.example.com");
+            let zone = DomainName::from("example.com");
+            let ns = DomainName::from("ns.example.com");
+            let mut response = response_builder(&request.query)
+                .rescode(self.rescode)
+                .authority(DnsRecord::new(
+                    zone,
+                    RecordType::NS,
+                    RecordClass::IN,
+                    3600,
+                    RecordData::Name(ns.clone()),
+                ))
+                .additional(DnsRecord::new(
+                    ns,
+                    RecordType::A,
+                    RecordClass::IN,
+                    3600,
+                    Ipv4Addr::new(192, 0, 2, 53),
+                ));
+            if self.rescode == ResponseCode::NOERROR {
+                response = response.answer(DnsRecord::new(
+                    name,
+                    RecordType::A,
+                    RecordClass::IN,
+                    300,
+                    Ipv4Addr::new(192, 0, 2, 1),
+                ));
+            }
+            if request.query.dnssec_ok() {
+                for record_type in [RecordType::NSEC, RecordType::RRSIG] {
+                    response = response.authority(DnsRecord::new(
+                        DomainName::from("a.example.com"),
+                        record_type,
+                        RecordClass::IN,
+                        3600,
+                        RecordData::Unknown(vec![0; 8]),
+                    ));
+                }
+            }
+            if self.signed {
+                // MAC and the rest don't matter here, only that the record stays last
+                response = response.additional(DnsRecord::new(
+                    DomainName::from("key.example.com"),
+                    RecordType::UNKNOWN(TSIG_RECORD_TYPE),
+                    RecordClass::UNKNOWN(255), // ANY
+                    0,
+                    RecordData::Unknown(vec![0; 16]),
+                ));
+            }
+            Ok(response.build())
+        }
+    }
+
+    fn resolve(handler: ZoneAnswerHandler, dnssec_ok: bool) -> DnsPacket {
+        let pipeline = Pipeline::new().with(MinimalResponsesHandler).with(handler);
+        let mut opt = OptRecord::new(1232);
+        opt.dnssec_ok = dnssec_ok;
+        let query = DnsPacket::builder()
+            .opt(Some(opt))
+            .question(DnsQuestion::new(
+                DomainName::from("www.example.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+
+        pipeline.handle(&Request::new(query, None)).unwrap()
+    }
+
+    #[test]
+    fn test_only_positive_responses_are_minimal() {
+        let positive = resolve(
+            ZoneAnswerHandler {
+                rescode: ResponseCode::NOERROR,
+                signed: false,
+            },
+            false,
+        );
+        assert_eq!(positive.answers.len(), 1);
+        assert!(positive.authorities.is_empty());
+        assert!(positive.additionals.is_empty());
+
+        let negative = resolve(
+            ZoneAnswerHandler {
+                rescode: ResponseCode::NXDOMAIN,
+                signed: false,
+            },
+            false,
+        );
+        assert_eq!(negative.authorities.len(), 1);
+        assert_eq!(negative.additionals.len(), 1);
+    }
+
+    #[test]
+    fn test_tsig_record_is_kept() {
+        let response = resolve(
+            ZoneAnswerHandler {
+                rescode: ResponseCode::NOERROR,
+                signed: true,
+            },
+            false,
+        );
+        assert!(response.authorities.is_empty());
+        let types: Vec<_> = response
+            .additionals
+            .iter()
+            .map(|r| &r.record_type)
+            .collect();
+        assert_eq!(types, [&RecordType::UNKNOWN(TSIG_RECORD_TYPE)]);
+    }
+
+    #[test]
+    fn test_denial_proofs_are_kept_with_dnssec_ok() {
+        let handler = || ZoneAnswerHandler {
+            rescode: ResponseCode::NOERROR,
+            signed: false,
+        };
+        let types = |response: DnsPacket| -> Vec<RecordType> {
+            response
+                .authorities
+                .into_iter()
+                .map(|r| r.record_type)
+                .collect()
+        };
+
+        assert_eq!(
+            types(resolve(handler(), true)),
+            [RecordType::NSEC, RecordType::RRSIG]
+        );
+        assert!(types(resolve(handler(), false)).is_empty());
+    }
+}
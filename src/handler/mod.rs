@@ -0,0 +1,197 @@
+//! Query handling - creates responses to the received queries
+//!
+//! Queries pass through a [`Pipeline`] of [`Handler`]s (cache, local zones, forwarding, ...).
+//! Every handler either answers the query itself or delegates it to the rest of the chain via [`Next`].
+
+use std::net::SocketAddr;
+
+use anyhow::Result;
+
+use crate::domain_name::DomainName;
+use crate::edns::OptRecord;
+use crate::header::ResponseCode;
+use crate::packet::{DnsPacket, PacketBuilder};
+
+mod blocklist;
+mod cache;
+mod canary;
+mod forward;
+mod hairpin;
+mod leases;
+mod minimal;
+mod nxdomain_redirect;
+mod rebinding;
+mod route;
+#[cfg(feature = "lua")]
+mod script;
+mod static_answer;
+mod ttl_clamp;
+mod zone;
+
+pub use blocklist::BlocklistHandler;
+pub use cache::{CacheBackend, CacheHandler};
+pub use canary::{CanaryHandler, MOZILLA_CANARY};
+pub use forward::ForwardHandler;
+pub use hairpin::{HairpinHandler, HairpinRule};
+pub use leases::LeaseHandler;
+pub use minimal::MinimalResponsesHandler;
+pub use nxdomain_redirect::NxdomainRedirectHandler;
+pub use rebinding::RebindingFilterHandler;
+pub use route::ClientRouteHandler;
+#[cfg(feature = "lua")]
+pub use script::ScriptHandler;
+pub use static_answer::StaticAnswerHandler;
+pub use ttl_clamp::TtlClampHandler;
+pub use zone::{watch_zone_file, SharedZone, ZoneHandler};
+
+/// Query together with information about its origin
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub query: DnsPacket,
+    /// Address of the client, if the query came over network
+    pub client: Option<SocketAddr>,
+    /// Name of the TSIG key which signed the query, if the signature was verified
+    pub tsig_key: Option<DomainName>,
+}
+
+impl Request {
+    pub fn new(query: DnsPacket, client: Option<SocketAddr>) -> Self {
+        Self {
+            query,
+            client,
+            tsig_key: None,
+        }
+    }
+
+    /// Query signed by the TSIG key `name`
+    pub fn signed_by(mut self, name: DomainName) -> Self {
+        self.tsig_key = Some(name);
+        self
+    }
+}
+
+/// Single step of query processing
+pub trait Handler: Send + Sync {
+    /// Answers the query or passes it (possibly modified) to the `next` handlers
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket>;
+}
+
+/// Rest of the handler chain
+pub struct Next<'a> {
+    handlers: &'a [Box<dyn Handler>],
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &Request) -> Result<DnsPacket> {
+        match self.handlers.split_first() {
+            Some((handler, handlers)) => handler.handle(request, Next { handlers }),
+            // nobody is willing to answer
+            None => Ok(response_builder(&request.query)
+                .rescode(ResponseCode::REFUSED)
+                .build()),
+        }
+    }
+}
+
+/// Ordered chain of handlers, configured at startup
+#[derive(Default)]
+pub struct Pipeline {
+    handlers: Vec<Box<dyn Handler>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends handler to the end of the chain
+    pub fn with(mut self, handler: impl Handler + 'static) -> Self {
+        self.handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Creates response to the query
+    pub fn handle(&self, request: &Request) -> Result<DnsPacket> {
+        Next {
+            handlers: &self.handlers,
+        }
+        .run(request)
+    }
+}
+
+/// Response to the query without any answers
+///
+/// ID, opcode, RD flag and questions are copied from the query,
+/// opcodes other than standard query are answered with NOTIMP.
+/// Queries with EDNS get an OPT record with our payload size and the DO bit of the query.
+pub fn response_builder(query: &DnsPacket) -> PacketBuilder {
+    let rescode = match query.header.opcode {
+        0 => ResponseCode::NOERROR,
+        _ => ResponseCode::NOTIMP, // Not implemented
+    };
+
+    let opt = query.opt.as_ref().map(|opt| OptRecord {
+        dnssec_ok: opt.dnssec_ok,
+        ..OptRecord::default()
+    });
+
+    DnsPacket::builder()
+        .id(query.header.id)
+        .response()
+        .opcode(query.header.opcode)
+        .recursion_desired(query.header.recursion_desired)
+        .rescode(rescode)
+        .questions(query.questions.clone())
+        .opt(opt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+
+    /// Handler failing every query
+    struct FailingHandler;
+
+    impl Handler for FailingHandler {
+        fn handle(&self, _request: &Request, _next: Next<'_>) -> Result<DnsPacket> {
+            anyhow::bail!("upstream unreachable")
+        }
+    }
+
+    fn query(opcode: u8) -> DnsPacket {
+        DnsPacket::builder()
+            .id(1234)
+            .opcode(opcode)
+            .question(DnsQuestion::new(
+                DomainName::from("codecrafters.io"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build()
+    }
+
+    #[test]
+    fn test_unanswered_queries_are_refused() {
+        let response = Pipeline::new()
+            .handle(&Request::new(query(0), None))
+            .unwrap();
+        assert_eq!(response.header.id, 1234);
+        assert_eq!(response.header.rescode, ResponseCode::REFUSED);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_opcodes_are_not_implemented() {
+        let response = response_builder(&query(2)).build();
+        assert_eq!(response.header.opcode, 2);
+        assert_eq!(response.header.rescode, ResponseCode::NOTIMP);
+    }
+
+    #[test]
+    fn test_handler_errors_are_returned() {
+        let pipeline = Pipeline::new().with(FailingHandler);
+        let error = pipeline.handle(&Request::new(query(0), None)).unwrap_err();
+        assert_eq!(error.to_string(), "upstream unreachable");
+    }
+}
@@ -0,0 +1,124 @@
+use std::net::Ipv4Addr;
+
+use anyhow::Result;
+
+use super::{response_builder, Handler, Next, Request};
+use crate::domain_name::DomainName;
+use crate::header::ResponseCode;
+use crate::packet::DnsPacket;
+use crate::question::QueryType;
+use crate::record::{DnsRecord, RecordClass, RecordType};
+
+/// TTL of the redirecting answers, short so the real answer is used soon after the name appears
+const REDIRECT_TTL: u32 = 60;
+
+/// Answers non-existent names under the selected suffixes with a fixed address
+///
+/// Useful e.g. for pointing mistyped names on a guest network at a captive landing page.
+/// NXDOMAIN validated with DNSSEC (AD flag of the following handlers' response) is never
+/// rewritten, validating clients would reject the forged answer anyway.
+pub struct NxdomainRedirectHandler {
+    suffixes: Vec<DomainName>,
+    address: Ipv4Addr,
+}
+
+impl NxdomainRedirectHandler {
+    pub fn new(suffixes: impl IntoIterator<Item = DomainName>, address: Ipv4Addr) -> Self {
+        Self {
+            suffixes: suffixes.into_iter().collect(),
+            address,
+        }
+    }
+
+    fn is_redirected(&self, domain_name: &DomainName) -> bool {
+        self.suffixes
+            .iter()
+            .any(|suffix| domain_name.is_subdomain_of(suffix))
+    }
+}
+
+impl Handler for NxdomainRedirectHandler {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+        let response = next.run(request)?;
+        let query = &request.query;
+
+        let redirect = response.header.rescode == ResponseCode::NXDOMAIN
+            && !response.header.authed_data
+            && !query.questions.is_empty()
+            && query.questions.iter().all(|question| {
+                question.query_type == QueryType::A && self.is_redirected(&question.domain_name)
+            });
+        if !redirect {
+            return Ok(response);
+        }
+
+        println!(
+            "NXDOMAIN redirect: answering {} with {}",
+            query.questions[0].domain_name, self.address
+        );
+        let answers = query.questions.iter().map(|question| {
+            DnsRecord::new(
+                question.domain_name.clone(),
+                RecordType::A,
+                RecordClass::IN,
+                REDIRECT_TTL,
+                self.address,
+            )
+        });
+
+        Ok(response_builder(query).answers(answers).build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::Pipeline;
+    use crate::question::{DnsQuestion, QueryClass};
+
+    /// Handler answering every query with NXDOMAIN
+    struct NxdomainHandler {
+        authed_data: bool,
+    }
+
+    impl Handler for NxdomainHandler {
+        fn handle(&self, request: &Request, _next: Next<'_>) -> Result<DnsPacket> {
+            Ok(response_builder(&request.query)
+                .rescode(ResponseCode::NXDOMAIN)
+                .authed_data(self.authed_data)
+                .build())
+        }
+    }
+
+    fn resolve(name: &str, authed_data: bool) -> DnsPacket {
+        let pipeline = Pipeline::new()
+            .with(NxdomainRedirectHandler::new(
+                [DomainName::from("guest.lan")],
+                Ipv4Addr::new(192, 168, 1, 1),
+            ))
+            .with(NxdomainHandler { authed_data });
+        let query = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from(name),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+
+        pipeline.handle(&Request::new(query, None)).unwrap()
+    }
+
+    #[test]
+    fn test_only_unsigned_nxdomain_under_suffix_is_redirected() {
+        let response = resolve("typo.guest.lan", false);
+        assert_eq!(response.header.rescode, ResponseCode::NOERROR);
+        assert_eq!(response.answers[0].data, Ipv4Addr::new(192, 168, 1, 1));
+
+        let response = resolve("typo.example.com", false);
+        assert_eq!(response.header.rescode, ResponseCode::NXDOMAIN);
+
+        let response = resolve("typo.guest.lan", true);
+        assert_eq!(response.header.rescode, ResponseCode::NXDOMAIN);
+        assert!(response.answers.is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+use std::net::Ipv4Addr;
+
+use anyhow::Result;
+
+use super::{Handler, Next, Request};
+use crate::domain_name::DomainName;
+use crate::packet::DnsPacket;
+use crate::record::RecordData;
+use crate::stats;
+
+/// Protects LAN devices against DNS rebinding attacks
+///
+/// Answers of the following handlers pointing at private, loopback or link-local
+/// addresses are removed, unless their owner lies in one of the allowed domains
+/// (e.g. the local domain served by a home router).
+pub struct RebindingFilterHandler {
+    allowed: Vec<DomainName>,
+}
+
+impl RebindingFilterHandler {
+    pub fn new(allowed: impl IntoIterator<Item = DomainName>) -> Self {
+        Self {
+            allowed: allowed.into_iter().collect(),
+        }
+    }
+
+    fn is_allowed(&self, domain_name: &DomainName) -> bool {
+        self.allowed
+            .iter()
+            .any(|allowed| domain_name.is_subdomain_of(allowed))
+    }
+}
+
+/// Addresses which must not be returned for external names
+fn is_internal(address: Ipv4Addr) -> bool {
+    let [a, b, ..] = address.octets();
+
+    address.is_private()
+        || address.is_loopback()
+        || address.is_link_local()
+        || address.is_unspecified()
+        || (a == 100 && (b & 0xC0) == 64) // shared address space 100.64.0.0/10
+}
+
+impl Handler for RebindingFilterHandler {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+        let mut response = next.run(request)?;
+
+        let before = response.answers.len();
+        response.answers.retain(|answer| match answer.data {
+            RecordData::A(address) => !is_internal(address) || self.is_allowed(&answer.domain_name),
+            _ => true,
+        });
+
+        if response.answers.len() != before {
+            let name = response
+                .questions
+                .first()
+                .map(|question| question.domain_name.clone())
+                .unwrap_or_default();
+            eprintln!(
+                "Rebinding protection: removed {} internal address(es) from response for {}",
+                before - response.answers.len(),
+                name
+            );
+            response.header.answer_entries = response.answers.len() as u16;
+            stats::blocked(&name);
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::{response_builder, Pipeline};
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+    use crate::record::{DnsRecord, RecordClass, RecordType};
+
+    /// Handler answering with all of `addresses`
+    struct AddressesHandler {
+        addresses: Vec<Ipv4Addr>,
+    }
+
+    impl Handler for AddressesHandler {
+        fn handle(&self, request: &Request, _next: Next<'_>) -> Result<DnsPacket> {
+            let name = &request.query.questions[0].domain_name;
+            Ok(response_builder(&request.query)
+                .answers(self.addresses.iter().map(|address| {
+                    DnsRecord::new(name.clone(), RecordType::A, RecordClass::IN, 60, *address)
+                }))
+                .build())
+        }
+    }
+
+    fn resolve(name: &str, addresses: &[Ipv4Addr]) -> Vec<Ipv4Addr> {
+        let pipeline = Pipeline::new()
+            .with(RebindingFilterHandler::new([DomainName::from("home.arpa")]))
+            .with(AddressesHandler {
+                addresses: addresses.to_vec(),
+            });
+        let query = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from(name),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+        let response = pipeline.handle(&Request::new(query, None)).unwrap();
+        assert_eq!(
+            response.header.answer_entries as usize,
+            response.answers.len()
+        );
+        response
+            .answers
+            .iter()
+            .filter_map(|answer| match answer.data {
+                RecordData::A(address) => Some(address),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_internal_addresses_of_external_names_are_removed() {
+        let public = Ipv4Addr::new(93, 184, 216, 34);
+        let internal = [
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(192, 168, 1, 1),
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::new(169, 254, 0, 1),
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::new(100, 64, 0, 1),
+        ];
+        let all: Vec<Ipv4Addr> = internal.iter().copied().chain([public]).collect();
+
+        assert_eq!(resolve("rebind.example", &all), [public]);
+        assert!(resolve("rebind.example", &internal).is_empty());
+        // just outside of the shared address space
+        let carrier = Ipv4Addr::new(100, 128, 0, 1);
+        assert_eq!(resolve("rebind.example", &[carrier]), [carrier]);
+    }
+
+    #[test]
+    fn test_internal_addresses_of_allowed_names_are_kept() {
+        let internal = [Ipv4Addr::new(192, 168, 1, 10)];
+        assert_eq!(resolve("nas.home.arpa", &internal), internal);
+        assert_eq!(resolve("HOME.ARPA", &internal), internal);
+        assert!(resolve("nothome.arpa", &internal).is_empty());
+    }
+}
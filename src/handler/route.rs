@@ -0,0 +1,99 @@
+use anyhow::Result;
+
+use super::{Handler, Next, Pipeline, Request};
+use crate::network::Network;
+use crate::packet::DnsPacket;
+
+/// Resolves queries of some client networks with pipelines of their own
+///
+/// Each route lists client networks and the pipeline handling their queries, typically
+/// a cache and forwarding to an upstream just for them (e.g. a filtering resolver for the
+/// kids' VLAN). The first route whose network contains the client's address is taken,
+/// queries of other clients (and those not from network) are passed to the next handlers.
+///
+/// Put in front of the shared cache, so answers of one route are never served to clients
+/// of another.
+#[derive(Default)]
+pub struct ClientRouteHandler {
+    routes: Vec<(Vec<Network>, Pipeline)>,
+}
+
+impl ClientRouteHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queries of clients in `networks` are handled by `pipeline`
+    pub fn route(
+        mut self,
+        networks: impl IntoIterator<Item = Network>,
+        pipeline: Pipeline,
+    ) -> Self {
+        self.routes.push((networks.into_iter().collect(), pipeline));
+        self
+    }
+}
+
+impl Handler for ClientRouteHandler {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+        let Some(client) = request.client else {
+            return next.run(request);
+        };
+        let route = self
+            .routes
+            .iter()
+            .find(|(networks, _)| networks.iter().any(|network| network.contains(client.ip())));
+        match route {
+            Some((_, pipeline)) => pipeline.handle(request),
+            None => next.run(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::domain_name::DomainName;
+    use crate::handler::StaticAnswerHandler;
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+    use crate::record::RecordData;
+
+    fn answer(address: Ipv4Addr) -> StaticAnswerHandler {
+        StaticAnswerHandler::new(address, 60)
+    }
+
+    #[test]
+    fn test_queries_are_routed_by_client_network() {
+        let kids: Network = "10.0.20.0/24".parse().unwrap();
+        let pipeline = Pipeline::new()
+            .with(ClientRouteHandler::new().route(
+                [kids],
+                Pipeline::new().with(answer(Ipv4Addr::new(192, 0, 2, 1))),
+            ))
+            .with(answer(Ipv4Addr::new(192, 0, 2, 2)));
+        let query = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from("example.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+
+        let resolve = |client: Option<&str>| {
+            let request = Request::new(query.clone(), client.map(|c| c.parse().unwrap()));
+            let response = pipeline.handle(&request).unwrap();
+            response.answers[0].data.clone()
+        };
+        assert_eq!(
+            resolve(Some("10.0.20.7:5353")),
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        assert_eq!(
+            resolve(Some("10.0.30.7:5353")),
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 2))
+        );
+        assert_eq!(resolve(None), RecordData::A(Ipv4Addr::new(192, 0, 2, 2)));
+    }
+}
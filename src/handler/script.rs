@@ -0,0 +1,273 @@
+//! Query policies written in Lua
+//!
+//! The script defines a global function `on_query(q)` which is called for every question.
+//! `q` is a table with fields `name` (without the trailing dot), `type` (number), `type_name`,
+//! `class` and `client` (client address or `nil`). The function returns `nil` to let the query continue unchanged,
+//! or a table with one of the actions:
+//!
+//! ```lua
+//! function on_query(q)
+//!     if q.name:match("ads%.example%.com$") then
+//!         return { action = "block" }                          -- NXDOMAIN
+//!     elseif q.name == "router.lan" then
+//!         return { action = "redirect", address = "192.168.1.1" } -- answer with A record
+//!     elseif q.name == "old.example.com" then
+//!         return { action = "rewrite", name = "new.example.com" } -- resolve another name
+//!     end
+//! end
+//! ```
+//!
+//! Scripts run in a sandbox with only the `string`, `table`, `math` and `utf8` libraries.
+//! Errors raised by the script are logged and the query continues as if `nil` was returned.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use mlua::{Function, Lua, LuaOptions, StdLib, Table, Value};
+
+use super::{response_builder, Handler, Next, Request};
+use crate::domain_name::DomainName;
+use crate::header::ResponseCode;
+use crate::packet::DnsPacket;
+use crate::question::DnsQuestion;
+use crate::record::{DnsRecord, RecordClass, RecordType};
+use crate::stats;
+
+/// TTL of the records synthesized by the `redirect` action
+const REDIRECT_TTL: u32 = 60;
+
+/// Decision of the script for a single question
+enum Action {
+    Continue,
+    Block,
+    Redirect(Ipv4Addr),
+    Rewrite(DomainName),
+}
+
+/// Applies policy from a Lua script to every query
+pub struct ScriptHandler {
+    lua: Mutex<Lua>,
+}
+
+impl ScriptHandler {
+    /// Loads the script from file
+    pub fn from_file(path: &str) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read script {}", path))?;
+        Self::new(&source, path)
+    }
+
+    /// Loads the script from `source`, `name` is used in error messages
+    pub fn new(source: &str, name: &str) -> Result<Self> {
+        let lua = Lua::new_with(
+            StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8,
+            LuaOptions::default(),
+        )?;
+        lua.load(source)
+            .set_name(name)
+            .exec()
+            .with_context(|| format!("Failed to load script {}", name))?;
+        lua.globals()
+            .get::<_, Function>("on_query")
+            .with_context(|| format!("script {} does not define on_query function", name))?;
+
+        Ok(Self {
+            lua: Mutex::new(lua),
+        })
+    }
+
+    fn decide(&self, question: &DnsQuestion, client: Option<SocketAddr>) -> Result<Action> {
+        let lua = self.lua.lock().expect("script lock poisoned");
+
+        let q = lua.create_table()?;
+        let name = question.domain_name.to_string();
+        q.set(
+            "name",
+            name.strip_suffix('.')
+                .filter(|n| !n.is_empty())
+                .unwrap_or(&name),
+        )?;
+        q.set("type", u16::from(question.query_type.clone()))?;
+        q.set("type_name", question.query_type.to_string())?;
+        q.set("class", question.class.to_string())?;
+        q.set("client", client.map(|client| client.ip().to_string()))?;
+
+        let on_query: Function = lua.globals().get("on_query")?;
+        let result: Option<Table> = match on_query.call(q)? {
+            Value::Nil => None,
+            Value::Table(table) => Some(table),
+            other => anyhow::bail!(
+                "on_query returned {}, expected table or nil",
+                other.type_name()
+            ),
+        };
+        let Some(result) = result else {
+            return Ok(Action::Continue);
+        };
+
+        let action: String = result.get("action")?;
+        Ok(match action.as_str() {
+            "continue" => Action::Continue,
+            "block" => Action::Block,
+            "redirect" => {
+                let address: String = result.get("address")?;
+                Action::Redirect(
+                    address
+                        .parse()
+                        .with_context(|| format!("invalid redirect address {}", address))?,
+                )
+            }
+            "rewrite" => {
+                let name: String = result.get("name")?;
+                Action::Rewrite(DomainName::from(name))
+            }
+            action => anyhow::bail!("unknown action {}", action),
+        })
+    }
+}
+
+impl Handler for ScriptHandler {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+        let query = &request.query;
+
+        let actions: Vec<Action> = query
+            .questions
+            .iter()
+            .map(|question| {
+                self.decide(question, request.client).unwrap_or_else(|e| {
+                    eprintln!("Script: {:#} (query for {})", e, question.domain_name);
+                    Action::Continue
+                })
+            })
+            .collect();
+
+        if let Some((question, _)) = query
+            .questions
+            .iter()
+            .zip(actions.iter())
+            .find(|(_, action)| matches!(action, Action::Block))
+        {
+            stats::blocked(&question.domain_name);
+            return Ok(response_builder(query)
+                .rescode(ResponseCode::NXDOMAIN)
+                .build());
+        }
+
+        // redirected questions are answered here, the rest goes on (possibly renamed)
+        let mut answers = Vec::new();
+        let mut forwarded = Vec::new();
+        let mut renamed = Vec::new(); // (new name, original name)
+        for (question, action) in query.questions.iter().zip(actions) {
+            match action {
+                Action::Redirect(address) => answers.push(DnsRecord::new(
+                    question.domain_name.clone(),
+                    RecordType::A,
+                    RecordClass::IN,
+                    REDIRECT_TTL,
+                    address,
+                )),
+                Action::Rewrite(name) => {
+                    renamed.push((name.clone(), question.domain_name.clone()));
+                    forwarded.push(DnsQuestion::new(
+                        name,
+                        question.query_type.clone(),
+                        question.class.clone(),
+                    ));
+                }
+                Action::Continue | Action::Block => forwarded.push(question.clone()),
+            }
+        }
+
+        if answers.is_empty() && renamed.is_empty() {
+            return next.run(request);
+        }
+
+        let mut rescode = ResponseCode::NOERROR;
+        if !forwarded.is_empty() {
+            let inner = Request::new(
+                DnsPacket::builder()
+                    .header(query.header)
+                    .questions(forwarded)
+                    .build(),
+                request.client,
+            );
+            let response = next.run(&inner)?;
+            rescode = response.header.rescode;
+
+            // client must see the names it asked for
+            answers.extend(response.answers.into_iter().map(|mut answer| {
+                if let Some((_, original)) = renamed
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_case(&answer.domain_name))
+                {
+                    answer.domain_name = original.clone();
+                }
+                answer
+            }));
+        }
+
+        Ok(response_builder(query)
+            .rescode(rescode)
+            .answers(answers)
+            .build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::{Pipeline, StaticAnswerHandler};
+    use crate::question::{QueryClass, QueryType};
+
+    const SCRIPT: &str = r#"
+        function on_query(q)
+            if q.name == "ads.example.com" then
+                return { action = "block" }
+            elseif q.name == "router.lan" then
+                return { action = "redirect", address = "192.168.1.1" }
+            elseif q.name == "old.example.com" then
+                return { action = "rewrite", name = "new.example.com" }
+            end
+        end
+    "#;
+
+    fn resolve(name: &str) -> DnsPacket {
+        let pipeline = Pipeline::new()
+            .with(ScriptHandler::new(SCRIPT, "test").unwrap())
+            .with(StaticAnswerHandler::default());
+        let query = DnsPacket::builder()
+            .id(1)
+            .question(DnsQuestion::new(
+                DomainName::from(name),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+
+        pipeline.handle(&Request::new(query, None)).unwrap()
+    }
+
+    #[test]
+    fn test_script_actions() {
+        let response = resolve("ads.example.com");
+        assert_eq!(response.header.rescode, ResponseCode::NXDOMAIN);
+        assert!(response.answers.is_empty());
+
+        let response = resolve("router.lan");
+        assert_eq!(response.answers[0].data, Ipv4Addr::new(192, 168, 1, 1));
+
+        let response = resolve("old.example.com");
+        assert_eq!(
+            response.questions[0].domain_name.to_string(),
+            "old.example.com."
+        );
+        assert_eq!(
+            response.answers[0].domain_name.to_string(),
+            "old.example.com."
+        );
+
+        let response = resolve("codecrafters.io");
+        assert_eq!(response.answers[0].data, Ipv4Addr::new(8, 8, 8, 8));
+    }
+}
@@ -0,0 +1,84 @@
+use std::net::Ipv4Addr;
+
+use anyhow::Result;
+
+use super::{response_builder, Handler, Next, Request};
+use crate::packet::DnsPacket;
+use crate::record::{DnsRecord, RecordClass, RecordType};
+
+/// Answers every question with the same A record
+///
+/// Only meant for the server without any upstream (the Codecrafters stages), pipelines
+/// with an upstream leave unanswered queries to be refused.
+pub struct StaticAnswerHandler {
+    address: Ipv4Addr,
+    ttl: u32,
+}
+
+impl StaticAnswerHandler {
+    pub fn new(address: Ipv4Addr, ttl: u32) -> Self {
+        Self { address, ttl }
+    }
+}
+
+impl Default for StaticAnswerHandler {
+    fn default() -> Self {
+        Self::new(Ipv4Addr::new(8, 8, 8, 8), 60)
+    }
+}
+
+impl Handler for StaticAnswerHandler {
+    fn handle(&self, request: &Request, _next: Next<'_>) -> Result<DnsPacket> {
+        let query = &request.query;
+        let answers = query.questions.iter().map(|question| {
+            DnsRecord::new(
+                question.domain_name.clone(),
+                RecordType::A,
+                RecordClass::IN,
+                self.ttl,
+                self.address,
+            )
+        });
+
+        Ok(response_builder(query).answers(answers).build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain_name::DomainName;
+    use crate::handler::Pipeline;
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+
+    #[test]
+    fn test_every_question_gets_the_address() {
+        let pipeline =
+            Pipeline::new().with(StaticAnswerHandler::new(Ipv4Addr::new(192, 0, 2, 1), 60));
+        let query = DnsPacket::builder()
+            .id(42)
+            .question(DnsQuestion::new(
+                DomainName::from("a.example.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .question(DnsQuestion::new(
+                DomainName::from("b.example.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+
+        let response = pipeline.handle(&Request::new(query, None)).unwrap();
+        assert_eq!(response.header.id, 42);
+        assert_eq!(response.answers.len(), 2);
+        assert_eq!(
+            response.answers[1].domain_name,
+            DomainName::from("b.example.com")
+        );
+        assert!(response
+            .answers
+            .iter()
+            .all(|answer| answer.ttl == 60 && answer.data == Ipv4Addr::new(192, 0, 2, 1)));
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::Result;
+
+use super::{Handler, Next, Request};
+use crate::packet::DnsPacket;
+use crate::record::RecordData;
+
+/// Keeps TTLs of the answers produced by the following handlers within `[min, max]`
+///
+/// A floor prevents clients from re-querying every second, a ceiling bounds how long
+/// stale data can live in downstream caches. Authority records are clamped too, and so is
+/// the negative TTL of SOA records, which tells how long NXDOMAIN and NODATA are cached.
+///
+/// Caches between this handler and the upstream must store clamped TTLs as well, see
+/// [`super::CacheHandler::clamp_ttl`], otherwise answers expire from them (or aren't
+/// cached at all, with TTL 0) sooner than clients are told.
+#[derive(Clone, Copy)]
+pub struct TtlClampHandler {
+    min: u32,
+    max: u32,
+}
+
+impl TtlClampHandler {
+    pub fn new(min: u32, max: u32) -> Self {
+        Self {
+            min,
+            max: max.max(min),
+        }
+    }
+
+    /// Clamps TTLs of the answer and authority records of `response`
+    ///
+    /// Additional records are left alone, their TTLs carry no meaning for OPT and TSIG.
+    pub(crate) fn clamp(&self, response: &mut DnsPacket) {
+        for record in response
+            .answers
+            .iter_mut()
+            .chain(response.authorities.iter_mut())
+        {
+            record.ttl = record.ttl.clamp(self.min, self.max);
+            if let RecordData::Soa(soa) = &mut record.data {
+                soa.minimum = soa.minimum.clamp(self.min, self.max);
+            }
+        }
+    }
+}
+
+impl Handler for TtlClampHandler {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+        let mut response = next.run(request)?;
+        self.clamp(&mut response);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::domain_name::DomainName;
+    use crate::handler::{response_builder, Pipeline};
+    use crate::header::ResponseCode;
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+    use crate::record::{DnsRecord, RecordClass, RecordType, Soa};
+
+    /// Handler answering with `ttl`, or NXDOMAIN with SOA of negative TTL `ttl`
+    struct TtlHandler {
+        ttl: u32,
+        exists: bool,
+    }
+
+    impl Handler for TtlHandler {
+        fn handle(&self, request: &Request, _next: Next<'_>) -> Result<DnsPacket> {
+            let name = request.query.questions[0].domain_name.clone();
+            if self.exists {
+                return Ok(response_builder(&request.query)
+                    .answer(DnsRecord::new(
+                        name,
+                        RecordType::A,
+                        RecordClass::IN,
+                        self.ttl,
+                        Ipv4Addr::new(192, 0, 2, 1),
+                    ))
+                    .build());
+            }
+            let soa = Soa {
+                mname: DomainName::from("ns.example.com"),
+                rname: DomainName::from("hostmaster.example.com"),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: self.ttl,
+            };
+            Ok(response_builder(&request.query)
+                .rescode(ResponseCode::NXDOMAIN)
+                .authority(DnsRecord::new(
+                    DomainName::from("example.com"),
+                    RecordType::SOA,
+                    RecordClass::IN,
+                    self.ttl,
+                    RecordData::Soa(soa),
+                ))
+                .build())
+        }
+    }
+
+    fn resolve(ttl: u32, exists: bool) -> DnsPacket {
+        let pipeline = Pipeline::new()
+            .with(TtlClampHandler::new(60, 3600))
+            .with(TtlHandler { ttl, exists });
+        let query = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from("www.example.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+        pipeline.handle(&Request::new(query, None)).unwrap()
+    }
+
+    #[test]
+    fn test_answer_ttls_are_clamped() {
+        assert_eq!(resolve(0, true).answers[0].ttl, 60);
+        assert_eq!(resolve(300, true).answers[0].ttl, 300);
+        assert_eq!(resolve(86400, true).answers[0].ttl, 3600);
+    }
+
+    #[test]
+    fn test_negative_ttls_are_clamped() {
+        let short = resolve(1, false);
+        assert_eq!(short.authorities[0].negative_ttl(), Some(60));
+        let long = resolve(86400, false);
+        assert_eq!(long.authorities[0].negative_ttl(), Some(3600));
+    }
+
+    #[test]
+    fn test_max_below_min_is_raised() {
+        let clamp = TtlClampHandler::new(300, 60);
+        assert_eq!((clamp.min, clamp.max), (300, 300));
+    }
+}
@@ -0,0 +1,694 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+
+use super::{response_builder, Handler, Next, Request};
+use crate::domain_name::DomainName;
+use crate::header::ResponseCode;
+use crate::health::HealthChecker;
+use crate::journal::ZoneDiff;
+use crate::packet::DnsPacket;
+use crate::question::DnsQuestion;
+use crate::record::{DnsRecord, RecordData, RecordType};
+use crate::zone::{TransferRule, Zone};
+
+/// Longest CNAME chain followed inside the zone
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// QTYPE of full zone transfer (RFC 5936)
+const AXFR: u16 = 252;
+
+/// QTYPE of incremental zone transfer (RFC 1995)
+const IXFR: u16 = 251;
+
+/// How often zone files followed by [`watch_zone_file`] are checked for changes
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Zone served by [`ZoneHandler`], replaced whole when it changes (e.g. is signed again)
+pub type SharedZone = Arc<RwLock<Zone>>;
+
+/// Answers authoritatively from local zones, questions outside of them are passed to the next handlers
+///
+/// Delegated names get a referral (NS of the child zone in the authority section),
+/// non-existent names NXDOMAIN or NODATA with the zone's SOA. NS records in answers
+/// and referrals come with in-zone A/AAAA glue in the additional section.
+///
+/// Every A/AAAA record gets a PTR record in `in-addr.arpa`/`ip6.arpa` pointing back
+/// to its owner, unless a loaded reverse zone defines the name itself.
+///
+/// Zones are transferred (AXFR, IXFR) only to clients matching a transfer rule of
+/// the zone, others are refused. IXFR is answered with the changes since the client's
+/// serial when the zone's journal has them, with the whole zone otherwise.
+///
+/// Zones can be replaced while being served, see [`ZoneHandler::shared_zone`].
+pub struct ZoneHandler {
+    zones: Vec<SharedZone>,
+    /// Synthesized PTR records by (canonical) reverse name
+    reverse: HashMap<DomainName, Vec<DnsRecord>>,
+    health: Option<Arc<HealthChecker>>,
+    /// Transfer rules by (canonical) zone origin
+    transfer_rules: HashMap<DomainName, Vec<TransferRule>>,
+}
+
+impl ZoneHandler {
+    pub fn new(zones: impl IntoIterator<Item = Zone>) -> Self {
+        let zones: Vec<Zone> = zones.into_iter().collect();
+
+        let mut reverse: HashMap<DomainName, Vec<DnsRecord>> = HashMap::new();
+        for record in zones.iter().flat_map(|zone| zone.records()) {
+            let Some(address) = record.data.address() else {
+                continue;
+            };
+            let name = DomainName::reverse(address);
+            let ptr = DnsRecord::new(
+                name.clone(),
+                RecordType::PTR,
+                record.class.clone(),
+                record.ttl,
+                RecordData::Name(record.domain_name.clone()),
+            );
+            let ptrs = reverse.entry(name).or_default();
+            if !ptrs.contains(&ptr) {
+                ptrs.push(ptr);
+            }
+        }
+
+        Self {
+            zones: zones
+                .into_iter()
+                .map(|zone| Arc::new(RwLock::new(zone)))
+                .collect(),
+            reverse,
+            health: None,
+            transfer_rules: HashMap::new(),
+        }
+    }
+
+    /// Allows transfers of zone `origin` to clients matching `rule`
+    pub fn allow_transfer(mut self, origin: &DomainName, rule: TransferRule) -> Self {
+        self.transfer_rules
+            .entry(origin.canonicalize())
+            .or_default()
+            .push(rule);
+        self
+    }
+
+    /// Handle for replacing zone `origin` with its new version, e.g. from a background thread
+    pub fn shared_zone(&self, origin: &DomainName) -> Option<SharedZone> {
+        self.zones
+            .iter()
+            .find(|zone| read(zone).origin().eq_ignore_case(origin))
+            .cloned()
+    }
+
+    /// Handles of all the zones, see [`ZoneHandler::shared_zone`]
+    pub fn shared_zones(&self) -> &[SharedZone] {
+        &self.zones
+    }
+
+    /// Leaves addresses which are down according to `checker` out of answers
+    pub fn health_checker(mut self, checker: Arc<HealthChecker>) -> Self {
+        self.health = Some(checker);
+        self
+    }
+
+    /// Answer to the question, `None` if the name is not ours
+    ///
+    /// With `dnssec_ok`, answers from signed zones carry their signatures, negative
+    /// answers and referrals NSEC records too.
+    fn answer(&self, question: &DnsQuestion, dnssec_ok: bool) -> Option<ZoneAnswer> {
+        let synthesized = || {
+            let ptrs = self.reverse.get(&question.domain_name.canonicalize())?;
+            let is_ptr = u16::from(question.query_type.clone()) == u16::from(RecordType::PTR);
+            Some(ZoneAnswer {
+                rescode: ResponseCode::NOERROR,
+                authoritative: true,
+                answers: if is_ptr { ptrs.clone() } else { Vec::new() },
+                ..Default::default()
+            })
+        };
+
+        match self.zone_for(&question.domain_name) {
+            Some(zone) => {
+                let mut answer = resolve(&zone, question, dnssec_ok);
+                if let Some(health) = &self.health {
+                    health.filter(&mut answer.answers);
+                }
+                if dnssec_ok {
+                    add_signatures(&zone, &mut answer.answers);
+                    add_signatures(&zone, &mut answer.authorities);
+                }
+                match answer.rescode {
+                    ResponseCode::NXDOMAIN => synthesized().or(Some(answer)),
+                    _ => Some(answer),
+                }
+            }
+            None => synthesized(),
+        }
+    }
+
+    /// Whole zone for clients allowed to transfer it, REFUSED for others
+    fn transfer(&self, request: &Request, zone: &Zone, incremental: bool) -> DnsPacket {
+        let builder = response_builder(&request.query);
+        if request.query.header.opcode != 0 {
+            return builder.build();
+        }
+
+        let allowed = self
+            .transfer_rules
+            .get(&zone.origin().canonicalize())
+            .is_some_and(|rules| rules.iter().any(|rule| allows(rule, request)));
+        if !allowed {
+            eprintln!(
+                "Refused transfer of zone {} to {}",
+                zone.origin(),
+                request
+                    .client
+                    .map_or("local client".to_string(), |client| client.ip().to_string())
+            );
+            return builder.rescode(ResponseCode::REFUSED).build();
+        }
+
+        let soa = zone.soa().expect("zone has SOA");
+        let RecordData::Soa(ours) = &soa.data else {
+            unreachable!("SOA record has SOA data");
+        };
+        let theirs = request
+            .query
+            .authorities
+            .iter()
+            .find_map(|record| match &record.data {
+                RecordData::Soa(theirs) if incremental => Some(theirs.serial),
+                _ => None,
+            });
+        let answers = match theirs {
+            // client having the current version gets just the SOA (RFC 1995 section 2),
+            // serial number arithmetic (RFC 1982)
+            Some(serial) if serial.wrapping_sub(ours.serial) as i32 >= 0 => vec![soa.clone()],
+            // changes between SOAs of the versions (RFC 1995 section 4)
+            Some(serial) => match zone.changes_since(serial) {
+                Some(changes) => std::iter::once(soa)
+                    .chain(changes.iter().flat_map(ZoneDiff::ixfr_records))
+                    .chain(std::iter::once(soa))
+                    .cloned()
+                    .collect(),
+                None => zone.transfer(),
+            },
+            None => zone.transfer(),
+        };
+
+        println!(
+            "Transferring zone {} ({} records)",
+            zone.origin(),
+            answers.len()
+        );
+        builder.authoritative_answer(true).answers(answers).build()
+    }
+
+    /// The most specific zone containing `name`
+    fn zone_for(&self, name: &DomainName) -> Option<RwLockReadGuard<'_, Zone>> {
+        self.zones
+            .iter()
+            .map(read)
+            .filter(|zone| name.is_subdomain_of(zone.origin()))
+            .max_by_key(|zone| zone.origin().label_count())
+    }
+}
+
+/// Replaces `zone` with its new version from `path` whenever the file is modified, keeping
+/// the changes in the zone's journal, in a background thread
+///
+/// Broken or half-written files keep the current version.
+pub fn watch_zone_file(zone: SharedZone, path: impl Into<PathBuf>) {
+    let path = path.into();
+    let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut loaded: Option<SystemTime> = modified(&path);
+    thread::spawn(move || loop {
+        thread::sleep(RELOAD_INTERVAL);
+        let current = modified(&path);
+        if current == loaded {
+            continue;
+        }
+        loaded = current;
+        let new = match Zone::from_file(&path) {
+            Ok(new) => new,
+            Err(e) => {
+                eprintln!("Zone not reloaded: {:#}", e);
+                continue;
+            }
+        };
+        let mut zone = zone.write().expect("zone lock poisoned");
+        let diff = match ZoneDiff::between(&zone, &new) {
+            Ok(diff) => diff,
+            Err(e) => {
+                eprintln!("Zone not reloaded from {}: {:#}", path.display(), e);
+                continue;
+            }
+        };
+        println!(
+            "Reloaded zone {} from {}: serial {} -> {}, {} RRsets changed",
+            new.origin(),
+            path.display(),
+            diff.old_serial(),
+            diff.new_serial(),
+            diff.rrsets().len()
+        );
+        if !diff.is_empty() && diff.new_serial().wrapping_sub(diff.old_serial()) as i32 <= 0 {
+            eprintln!(
+                "Serial of zone {} didn't increase, secondaries won't transfer the changes",
+                new.origin()
+            );
+        }
+        *zone = zone.next_version(new);
+    });
+}
+
+fn read(zone: &SharedZone) -> RwLockReadGuard<'_, Zone> {
+    zone.read().expect("zone lock poisoned")
+}
+
+/// Returns true if the client of the request matches the rule (both network and key, if both are given)
+fn allows(rule: &TransferRule, request: &Request) -> bool {
+    let network_matches = match &rule.network {
+        Some(network) => request
+            .client
+            .is_some_and(|client| network.contains(client.ip())),
+        None => true,
+    };
+    let key_matches = match &rule.key {
+        Some(key) => request
+            .tsig_key
+            .as_ref()
+            .is_some_and(|signed| signed.eq_ignore_case(key)),
+        None => true,
+    };
+    network_matches && key_matches
+}
+
+/// Sections of the response to one question
+#[derive(Default)]
+struct ZoneAnswer {
+    rescode: ResponseCode,
+    authoritative: bool,
+    answers: Vec<DnsRecord>,
+    authorities: Vec<DnsRecord>,
+    additionals: Vec<DnsRecord>,
+}
+
+impl ZoneAnswer {
+    /// NXDOMAIN or NODATA, SOA TTL is the negative TTL (RFC 2308 section 3)
+    ///
+    /// `denial` are NSEC records proving the answer, if the client wants them.
+    fn negative(
+        zone: &Zone,
+        rescode: ResponseCode,
+        answers: Vec<DnsRecord>,
+        denial: Vec<DnsRecord>,
+    ) -> Self {
+        let soa = zone.soa().map(|soa| DnsRecord {
+            ttl: soa.negative_ttl().unwrap_or(soa.ttl),
+            ..soa.clone()
+        });
+
+        Self {
+            rescode,
+            authoritative: true,
+            answers,
+            authorities: soa.into_iter().chain(denial).collect(),
+            additionals: Vec::new(),
+        }
+    }
+}
+
+fn resolve(zone: &Zone, question: &DnsQuestion, dnssec_ok: bool) -> ZoneAnswer {
+    let query_type = u16::from(question.query_type.clone());
+    let mut answers = Vec::new();
+    let mut name = question.domain_name.clone();
+    let denial = |name: &DomainName, nxdomain: bool| match dnssec_ok {
+        true => zone.denial(name, nxdomain),
+        false => Vec::new(),
+    };
+
+    for _ in 0..MAX_CNAME_CHAIN {
+        if let Some(mut ns) = zone.delegation(&name) {
+            // NSEC of the cut proves the child zone is unsigned (no DS)
+            let cut = ns[0].domain_name.clone();
+            let additionals = zone.glue(&ns);
+            ns.extend(denial(&cut, false));
+            return ZoneAnswer {
+                rescode: ResponseCode::NOERROR,
+                authoritative: !answers.is_empty(),
+                additionals,
+                answers,
+                authorities: ns,
+            };
+        }
+
+        let records: Vec<&DnsRecord> = zone.records_at(&name).collect();
+        let matching: Vec<DnsRecord> = records
+            .iter()
+            .filter(|record| u16::from(record.record_type.clone()) == query_type)
+            .map(|&record| record.clone())
+            .collect();
+
+        if !matching.is_empty() {
+            let additionals = if query_type == u16::from(RecordType::NS) {
+                zone.glue(&matching)
+            } else {
+                Vec::new()
+            };
+            answers.extend(matching);
+            return ZoneAnswer {
+                rescode: ResponseCode::NOERROR,
+                authoritative: true,
+                answers,
+                authorities: Vec::new(),
+                additionals,
+            };
+        }
+
+        // alias is followed as long as the target stays in the zone
+        let cname = records
+            .iter()
+            .find(|record| record.record_type == RecordType::CNAME);
+        match cname {
+            Some(&cname) => {
+                answers.push(cname.clone());
+                match &cname.data {
+                    RecordData::Name(target) if target.is_subdomain_of(zone.origin()) => {
+                        name = target.clone()
+                    }
+                    _ => break,
+                }
+            }
+            None if zone.contains_name(&name) => {
+                let denial = denial(&name, false);
+                return ZoneAnswer::negative(zone, ResponseCode::NOERROR, answers, denial);
+            }
+            None => {
+                let denial = denial(&name, true);
+                return ZoneAnswer::negative(zone, ResponseCode::NXDOMAIN, answers, denial);
+            }
+        }
+    }
+
+    ZoneAnswer {
+        rescode: ResponseCode::NOERROR,
+        authoritative: true,
+        answers,
+        ..Default::default()
+    }
+}
+
+/// Appends RRSIG records of the RRsets among `records`
+fn add_signatures(zone: &Zone, records: &mut Vec<DnsRecord>) {
+    let mut signatures: Vec<DnsRecord> = Vec::new();
+    for record in records.iter() {
+        for signature in zone.signatures(&record.domain_name, &record.record_type) {
+            if !signatures.contains(&signature) {
+                signatures.push(signature);
+            }
+        }
+    }
+    records.extend(signatures);
+}
+
+impl Handler for ZoneHandler {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+        let query = &request.query;
+        let dnssec_ok = query.dnssec_ok();
+
+        if let [question] = &query.questions[..] {
+            let query_type = u16::from(question.query_type.clone());
+            let zone = self
+                .zones
+                .iter()
+                .map(read)
+                .find(|zone| zone.origin().eq_ignore_case(&question.domain_name));
+            if let (AXFR | IXFR, Some(zone)) = (query_type, zone) {
+                return Ok(self.transfer(request, &zone, query_type == IXFR));
+            }
+        }
+
+        // all questions must be ours, mixing local and forwarded answers is not worth it
+        let answers: Option<Vec<ZoneAnswer>> = query
+            .questions
+            .iter()
+            .map(|question| self.answer(question, dnssec_ok))
+            .collect();
+        let answers = match answers {
+            Some(answers) if !answers.is_empty() => answers,
+            _ => return next.run(request),
+        };
+
+        let mut merged = ZoneAnswer {
+            rescode: ResponseCode::NXDOMAIN,
+            authoritative: true,
+            ..Default::default()
+        };
+        for answer in answers {
+            // NXDOMAIN only when none of the names exists
+            if answer.rescode != ResponseCode::NXDOMAIN {
+                merged.rescode = ResponseCode::NOERROR;
+            }
+            merged.authoritative &= answer.authoritative;
+            merged.answers.extend(answer.answers);
+            for (section, records) in [
+                (&mut merged.authorities, answer.authorities),
+                (&mut merged.additionals, answer.additionals),
+            ] {
+                for record in records {
+                    if !section.contains(&record) {
+                        section.push(record);
+                    }
+                }
+            }
+        }
+
+        let mut builder = response_builder(query);
+        if query.header.opcode == 0 {
+            builder = builder
+                .rescode(merged.rescode)
+                .authoritative_answer(merged.authoritative)
+                .answers(merged.answers)
+                .authorities(merged.authorities)
+                .additionals(merged.additionals);
+        }
+        Ok(builder.build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::handler::Pipeline;
+    use crate::question::{QueryClass, QueryType};
+
+    const ZONE: &str = "$ORIGIN home.arpa.
+@    IN SOA ns1 hostmaster 1 7200 900 1209600 300
+     IN NS  ns1
+ns1  IN A   192.168.1.1
+lab  IN NS  ns.lab
+ns.lab IN A 192.168.2.1
+";
+
+    fn query(name: &str, query_type: QueryType) -> DnsPacket {
+        let response = Pipeline::new()
+            .with(ZoneHandler::new([Zone::parse(ZONE).unwrap()]))
+            .handle(&Request::new(
+                DnsPacket::builder()
+                    .question(DnsQuestion::new(name.into(), query_type, QueryClass::IN))
+                    .build(),
+                None,
+            ));
+        response.unwrap()
+    }
+
+    #[test]
+    fn test_ns_answers_and_referrals_carry_glue() {
+        let ns = query("home.arpa", QueryType::from(u16::from(RecordType::NS)));
+        assert!(ns.header.authoritative_answer);
+        assert_eq!(ns.answers.len(), 1);
+        assert_eq!(ns.header.additional_entries, 1);
+        assert_eq!(ns.additionals[0].data, Ipv4Addr::new(192, 168, 1, 1));
+
+        let referral = query("printer.lab.home.arpa", QueryType::A);
+        assert!(!referral.header.authoritative_answer);
+        assert!(referral.answers.is_empty());
+        assert_eq!(
+            referral.authorities[0].data,
+            RecordData::Name("ns.lab.home.arpa".into())
+        );
+        assert_eq!(referral.additionals[0].data, Ipv4Addr::new(192, 168, 2, 1));
+
+        let missing = query("missing.home.arpa", QueryType::A);
+        assert_eq!(missing.header.rescode, ResponseCode::NXDOMAIN);
+        assert_eq!(missing.authorities[0].ttl, 300);
+    }
+
+    #[test]
+    fn test_ptr_records_are_synthesized() {
+        let ptr = query(
+            "1.1.168.192.in-addr.arpa",
+            QueryType::from(u16::from(RecordType::PTR)),
+        );
+        assert!(ptr.header.authoritative_answer);
+        assert_eq!(
+            ptr.answers[0].data,
+            RecordData::Name("ns1.home.arpa".into())
+        );
+
+        let unknown = query(
+            "9.9.168.192.in-addr.arpa",
+            QueryType::from(u16::from(RecordType::PTR)),
+        );
+        assert_eq!(unknown.header.rescode, ResponseCode::REFUSED); // passed on
+    }
+
+    #[test]
+    fn test_transfers_follow_zone_rules() {
+        let origin = DomainName::from("home.arpa");
+        let handler = ZoneHandler::new([Zone::parse(ZONE).unwrap()])
+            .allow_transfer(&origin, "192.168.1.0/24".parse().unwrap())
+            .allow_transfer(&origin, "key:xfr@10.0.0.0/8".parse().unwrap());
+        let pipeline = Pipeline::new().with(handler);
+        let axfr = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                origin.clone(),
+                QueryType::from(AXFR),
+                QueryClass::IN,
+            ))
+            .build();
+        let transfer = |client: &str, key: Option<&str>| {
+            let mut request = Request::new(axfr.clone(), Some(client.parse().unwrap()));
+            request.tsig_key = key.map(DomainName::from);
+            pipeline.handle(&request).unwrap()
+        };
+
+        let allowed = transfer("192.168.1.2:5353", None);
+        assert!(allowed.header.authoritative_answer);
+        assert_eq!(allowed.answers.len(), 6);
+        assert_eq!(allowed.answers[0].record_type, RecordType::SOA);
+        assert_eq!(allowed.answers[5].record_type, RecordType::SOA);
+
+        assert_eq!(transfer("10.1.1.1:5353", Some("XFR")).answers.len(), 6);
+        for (client, key) in [("10.1.1.1:5353", None), ("172.16.0.1:5353", Some("xfr"))] {
+            let refused = transfer(client, key);
+            assert_eq!(refused.header.rescode, ResponseCode::REFUSED);
+            assert!(refused.answers.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_ixfr_answers_with_changes_from_journal() {
+        let origin = DomainName::from("home.arpa");
+        let handler = ZoneHandler::new([Zone::parse(ZONE).unwrap()])
+            .allow_transfer(&origin, "192.168.1.0/24".parse().unwrap());
+        let zone = handler.shared_zone(&origin).unwrap();
+        let pipeline = Pipeline::new().with(handler);
+        let ixfr = |serial: u32| {
+            let mut soa = read(&zone).soa().unwrap().clone();
+            if let RecordData::Soa(data) = &mut soa.data {
+                data.serial = serial;
+            }
+            let query = DnsPacket::builder()
+                .question(DnsQuestion::new(
+                    origin.clone(),
+                    QueryType::from(IXFR),
+                    QueryClass::IN,
+                ))
+                .authorities(vec![soa])
+                .build();
+            let request = Request::new(query, Some("192.168.1.2:5353".parse().unwrap()));
+            pipeline.handle(&request).unwrap().answers
+        };
+
+        let changed = ZONE.replace("hostmaster 1", "hostmaster 2") + "nas IN A 192.168.1.10\n";
+        let new = read(&zone).next_version(Zone::parse(&changed).unwrap());
+        *zone.write().unwrap() = new;
+
+        // new SOA, old SOA, nothing removed, new SOA, added A, new SOA
+        let answers = ixfr(1);
+        let types: Vec<RecordType> = answers.iter().map(|r| r.record_type.clone()).collect();
+        assert_eq!(
+            types,
+            vec![
+                RecordType::SOA,
+                RecordType::SOA,
+                RecordType::SOA,
+                RecordType::A,
+                RecordType::SOA
+            ]
+        );
+        assert_eq!(answers[3].domain_name, DomainName::from("nas.home.arpa"));
+
+        assert_eq!(ixfr(2).len(), 1);
+        // serial unknown to the journal gets the whole zone
+        assert_eq!(ixfr(0).len(), 7);
+    }
+
+    #[cfg(feature = "dnssec")]
+    #[test]
+    fn test_signed_zone_answers_carry_proofs() {
+        use crate::dnssec::{self, KeySpec};
+        use crate::edns::OptRecord;
+
+        let path = std::env::temp_dir().join(format!("dns-test-{}-zone.pem", std::process::id()));
+        let spec: KeySpec = format!("ed25519:{}", path.display()).parse().unwrap();
+        let key = spec.load().unwrap();
+        std::fs::remove_file(path).unwrap();
+        let zone = Zone::parse(ZONE).unwrap();
+        let signed = dnssec::sign_zone(&zone, &[key], std::time::SystemTime::now()).unwrap();
+        let pipeline = Pipeline::new().with(ZoneHandler::new([signed]));
+
+        let query = |name: &str, dnssec_ok: bool| {
+            let mut opt = OptRecord::new(4096);
+            opt.dnssec_ok = dnssec_ok;
+            let query = DnsPacket::builder()
+                .question(DnsQuestion::new(name.into(), QueryType::A, QueryClass::IN))
+                .opt(Some(opt))
+                .build();
+            pipeline.handle(&Request::new(query, None)).unwrap()
+        };
+        let types = |records: &[DnsRecord]| -> Vec<RecordType> {
+            records
+                .iter()
+                .map(|record| record.record_type.clone())
+                .collect()
+        };
+
+        let answer = query("ns1.home.arpa", true);
+        assert_eq!(types(&answer.answers), [RecordType::A, RecordType::RRSIG]);
+        assert_eq!(
+            types(&query("ns1.home.arpa", false).answers),
+            [RecordType::A]
+        );
+
+        // NSEC of lab.home.arpa. covers the name, NSEC of home.arpa. the wildcard
+        let missing = query("missing.home.arpa", true);
+        assert_eq!(missing.header.rescode, ResponseCode::NXDOMAIN);
+        assert_eq!(
+            types(&missing.authorities),
+            [
+                RecordType::SOA,
+                RecordType::NSEC,
+                RecordType::NSEC,
+                RecordType::RRSIG,
+                RecordType::RRSIG,
+                RecordType::RRSIG
+            ]
+        );
+
+        let referral = query("printer.lab.home.arpa", true);
+        assert_eq!(
+            types(&referral.authorities),
+            [RecordType::NS, RecordType::NSEC, RecordType::RRSIG]
+        );
+    }
+}
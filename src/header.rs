@@ -1,7 +1,12 @@
+use std::fmt;
+
+use crate::packet::{ensure_remaining, ParseError};
+
 pub const HEADER_LENGTH: u16 = 12; // Header is 12 bytes long
 
-#[allow(clippy::upper_case_acronyms, dead_code)]
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Default, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ResponseCode {
     // No error condition
     #[default]
@@ -24,6 +29,10 @@ pub enum ResponseCode {
     // For example, a name server may not wish to provide the information to the particular requester,
     // or a name server may not wish to perform a particular operation (e.g., zone transfer) for particular data.
     REFUSED = 5,
+
+    // Not Authorized - The server is not authoritative for the zone, or the TSIG signature
+    // of the request is not valid (RFC 8945 section 5.2).
+    NOTAUTH = 9,
 }
 
 impl From<u8> for ResponseCode {
@@ -34,12 +43,14 @@ impl From<u8> for ResponseCode {
             3 => Self::NXDOMAIN,
             4 => Self::NOTIMP,
             5 => Self::REFUSED,
+            9 => Self::NOTAUTH,
             _ => Self::NOERROR,
         }
     }
 }
 
 #[derive(Default, Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsHeader {
     /// Packet Identifier (ID)
     /// A random identifier is assigned to query packets. Response packets must reply with the same id.
@@ -72,9 +83,12 @@ pub struct DnsHeader {
 
     /// Reserved (Z) - 3 bits
     /// Originally reserved for later use, but now used for DNSSEC queries.
+    /// Checking Disabled (CD) - the client validates DNSSEC itself (RFC 4035 section 3.2.2)
     pub checking_disabled: bool, // 1 bit
+    /// Authentic Data (AD) - all data in the response were validated (RFC 4035 section 3.2.3)
     pub authed_data: bool, // 1 bit
-    pub z: bool,           // 1 bit
+    /// The last still reserved bit, must be zero but is kept as received
+    pub z: bool, // 1 bit
 
     /// Response Code (RCODE)
     /// Set by the server to indicate the status of the response, i.e. whether or not it was successful or failed,
@@ -104,15 +118,58 @@ impl DnsHeader {
     }
 }
 
+/// dig-like header lines
+impl fmt::Display for DnsHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opcode = match self.opcode {
+            0 => "QUERY".to_string(),
+            1 => "IQUERY".to_string(),
+            2 => "STATUS".to_string(),
+            4 => "NOTIFY".to_string(),
+            5 => "UPDATE".to_string(),
+            n => n.to_string(),
+        };
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {:?}, id: {}",
+            opcode, self.rescode, self.id
+        )?;
+
+        let flags = [
+            (self.response, "qr"),
+            (self.authoritative_answer, "aa"),
+            (self.truncated_message, "tc"),
+            (self.recursion_desired, "rd"),
+            (self.recursion_available, "ra"),
+            (self.authed_data, "ad"),
+            (self.checking_disabled, "cd"),
+        ];
+        f.write_str(";; flags:")?;
+        for (_, name) in flags.iter().filter(|(set, _)| *set) {
+            write!(f, " {}", name)?;
+        }
+
+        write!(
+            f,
+            "; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            self.question_entries,
+            self.answer_entries,
+            self.authoritative_entries,
+            self.additional_entries
+        )
+    }
+}
+
 impl DnsHeader {
     /// Creates [`DnsHeader`] from it's bytes representation
     ///
+    /// ```text
     ///                                  1  1  1  1  1  1
     ///    0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5
     ///  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
     ///  |                      ID                       |
     ///  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-    ///  |QR|   Opcode  |AA|TC|RD|RA|   Z    |   RCODE   |
+    ///  |QR|   Opcode  |AA|TC|RD|RA| Z|AD|CD|   RCODE   |
     ///  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
     ///  |                    QDCOUNT                    |
     ///  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
@@ -122,8 +179,9 @@ impl DnsHeader {
     ///  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
     ///  |                    ARCOUNT                    |
     ///  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-    ///
-    pub fn read_bytes(&mut self, buf: &mut impl bytes::Buf) {
+    /// ```
+    pub fn read_bytes(&mut self, buf: &mut impl bytes::Buf) -> Result<(), ParseError> {
+        ensure_remaining(buf, HEADER_LENGTH as usize)?;
         self.id = buf.get_u16();
 
         let flags = buf.get_u16();
@@ -138,22 +196,27 @@ impl DnsHeader {
 
         self.recursion_available = (b & (1 << 7)) > 0;
         self.z = (b & (1 << 6)) > 0;
+        self.authed_data = (b & (1 << 5)) > 0;
+        self.checking_disabled = (b & (1 << 4)) > 0;
         self.rescode = ResponseCode::from(b & 0x0F);
 
         self.question_entries = buf.get_u16();
         self.answer_entries = buf.get_u16();
         self.authoritative_entries = buf.get_u16();
         self.additional_entries = buf.get_u16();
+
+        Ok(())
     }
 
     /// Converts [`DnsHeader`] to bytes representation
     ///
+    /// ```text
     ///                                  1  1  1  1  1  1
     ///    0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5
     ///  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
     ///  |                      ID                       |
     ///  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-    ///  |QR|   Opcode  |AA|TC|RD|RA|   Z    |   RCODE   |
+    ///  |QR|   Opcode  |AA|TC|RD|RA| Z|AD|CD|   RCODE   |
     ///  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
     ///  |                    QDCOUNT                    |
     ///  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
@@ -163,7 +226,7 @@ impl DnsHeader {
     ///  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
     ///  |                    ARCOUNT                    |
     ///  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-    ///
+    /// ```
     pub fn write_bytes(&self, buf: &mut impl bytes::BufMut) {
         buf.put_u16(self.id);
 
@@ -173,8 +236,11 @@ impl DnsHeader {
             | (self.truncated_message as u8) << 1
             | (self.recursion_desired as u8);
 
-        let b: u8 =
-            (self.recursion_available as u8) << 7 | (self.z as u8) << 6 | (self.rescode as u8);
+        let b: u8 = (self.recursion_available as u8) << 7
+            | (self.z as u8) << 6
+            | (self.authed_data as u8) << 5
+            | (self.checking_disabled as u8) << 4
+            | (self.rescode as u8);
 
         let flags = (a as u16) << 8 | (b as u16);
         buf.put_u16(flags);
@@ -185,3 +251,48 @@ impl DnsHeader {
         buf.put_u16(self.additional_entries);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(header: DnsHeader) -> DnsHeader {
+        let mut bytes = Vec::new();
+        header.write_bytes(&mut bytes);
+        let mut parsed = DnsHeader::new();
+        parsed.read_bytes(&mut &bytes[..]).unwrap();
+        parsed
+    }
+
+    #[test]
+    fn test_every_flag_round_trips() {
+        let flags: [fn(&mut DnsHeader); 8] = [
+            |h| h.response = true,
+            |h| h.authoritative_answer = true,
+            |h| h.truncated_message = true,
+            |h| h.recursion_desired = true,
+            |h| h.recursion_available = true,
+            |h| h.z = true,
+            |h| h.authed_data = true,
+            |h| h.checking_disabled = true,
+        ];
+        let mut all = DnsHeader::new();
+        for (i, set) in flags.iter().enumerate() {
+            let mut header = DnsHeader::new();
+            set(&mut header);
+            assert_eq!(round_trip(header), header, "flag {}", i);
+            set(&mut all);
+        }
+
+        all.id = 0xBEEF;
+        all.opcode = 5;
+        all.rescode = ResponseCode::NOTAUTH;
+        all.question_entries = 1;
+        all.additional_entries = 2;
+        assert_eq!(round_trip(all), all);
+
+        let mut bytes = Vec::new();
+        all.write_bytes(&mut bytes);
+        assert_eq!(bytes[2..4], [0xAF, 0xF9]);
+    }
+}
@@ -0,0 +1,298 @@
+//! Health checks of addresses in answer pools
+//!
+//! Names of local zones with several A/AAAA records (e.g. replicas of a service) can
+//! be health-checked: every address is periodically probed and addresses failing
+//! [`FALL`] probes in a row are left out of answers until a probe succeeds again.
+//! When all addresses of a name fail, all of them are answered, since a possibly
+//! broken answer is better than none.
+//!
+//! Clients cache answers for their TTL, so pooled records should have TTLs not much
+//! longer than the check interval for the failover to be quick.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::domain_name::DomainName;
+use crate::record::DnsRecord;
+
+/// Consecutive failed probes after which an address is considered down
+pub const FALL: u32 = 2;
+
+/// Time limit of a single probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How an address is checked
+#[derive(Debug, Clone, PartialEq)]
+pub enum Probe {
+    /// TCP connection to the port can be established
+    Tcp(u16),
+    /// HTTP GET of the path returns 2xx or 3xx status
+    Http { port: u16, path: String },
+}
+
+/// Parses `tcp:<port>` or `http:<port>[/path]`
+impl FromStr for Probe {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, target) = s
+            .split_once(':')
+            .context("probe must be tcp:<port> or http:<port>[/path]")?;
+        match kind {
+            "tcp" => Ok(Self::Tcp(target.parse().context("invalid probe port")?)),
+            "http" => {
+                let (port, path) = match target.find('/') {
+                    Some(slash) => target.split_at(slash),
+                    None => (target, "/"),
+                };
+                Ok(Self::Http {
+                    port: port.parse().context("invalid probe port")?,
+                    path: path.to_string(),
+                })
+            }
+            _ => anyhow::bail!("unknown probe {} (expected tcp or http)", kind),
+        }
+    }
+}
+
+impl Probe {
+    fn check(&self, address: IpAddr, host: &DomainName) -> Result<()> {
+        let port = match self {
+            Self::Tcp(port) | Self::Http { port, .. } => *port,
+        };
+        let mut stream =
+            TcpStream::connect_timeout(&SocketAddr::new(address, port), PROBE_TIMEOUT)?;
+
+        if let Self::Http { path, .. } = self {
+            stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+            stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+            write!(
+                stream,
+                "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                path,
+                host.to_string().trim_end_matches('.'),
+            )?;
+
+            let mut status_line = String::new();
+            BufReader::new(stream).read_line(&mut status_line)?;
+            let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+            if !(status.starts_with('2') || status.starts_with('3')) {
+                anyhow::bail!("HTTP status {:?}", status);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Pool of addresses of a name checked by the same probe
+struct Pool {
+    name: DomainName,
+    probe: Probe,
+    addresses: Vec<IpAddr>,
+}
+
+/// Periodically probes addresses of the pools and remembers which of them are down
+#[derive(Default)]
+pub struct HealthChecker {
+    pools: Vec<Pool>,
+    /// Consecutive failed probes by name and address
+    failures: Mutex<HashMap<(DomainName, IpAddr), u32>>,
+}
+
+impl HealthChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks `addresses` of `name` with `probe`
+    pub fn pool(mut self, name: DomainName, probe: Probe, addresses: Vec<IpAddr>) -> Self {
+        self.pools.push(Pool {
+            name: name.canonicalize(),
+            probe,
+            addresses,
+        });
+        self
+    }
+
+    /// Probes all addresses once, concurrently
+    pub fn check_all(&self) {
+        let results: Vec<(DomainName, IpAddr, bool)> = thread::scope(|scope| {
+            let probes: Vec<_> = self
+                .pools
+                .iter()
+                .flat_map(|pool| pool.addresses.iter().map(move |address| (pool, *address)))
+                .map(|(pool, address)| {
+                    scope.spawn(move || {
+                        let result = pool.probe.check(address, &pool.name);
+                        if let Err(e) = &result {
+                            eprintln!("Health check of {} {} failed: {:#}", pool.name, address, e);
+                        }
+                        (pool.name.clone(), address, result.is_ok())
+                    })
+                })
+                .collect();
+
+            probes
+                .into_iter()
+                .map(|probe| probe.join().expect("health check thread panicked"))
+                .collect()
+        });
+
+        let mut failures = self.failures.lock().expect("health lock poisoned");
+        for (name, address, healthy) in results {
+            let count = failures.entry((name.clone(), address)).or_default();
+            match (healthy, *count >= FALL) {
+                (true, true) => println!("Health check: {} {} is up again", name, address),
+                (false, false) if *count + 1 == FALL => {
+                    println!("Health check: {} {} is down", name, address)
+                }
+                _ => {}
+            }
+            *count = if healthy { 0 } else { *count + 1 };
+        }
+    }
+
+    /// Probes all addresses every `interval` in a background thread
+    pub fn start(self: &Arc<Self>, interval: Duration) {
+        let checker = self.clone();
+        thread::spawn(move || loop {
+            checker.check_all();
+            thread::sleep(interval);
+        });
+    }
+
+    fn is_down(&self, name: &DomainName, address: IpAddr) -> bool {
+        let failures = self.failures.lock().expect("health lock poisoned");
+        failures
+            .get(&(name.canonicalize(), address))
+            .is_some_and(|count| *count >= FALL)
+    }
+
+    /// Removes A/AAAA records of addresses which are down, unless all of their name are down
+    pub fn filter(&self, records: &mut Vec<DnsRecord>) {
+        let is_down = |record: &DnsRecord| {
+            record
+                .data
+                .address()
+                .is_some_and(|address| self.is_down(&record.domain_name, address))
+        };
+
+        let healthy_names: Vec<DomainName> = records
+            .iter()
+            .filter(|record| !is_down(record))
+            .map(|record| record.domain_name.canonicalize())
+            .collect();
+        records.retain(|record| {
+            !is_down(record) || !healthy_names.contains(&record.domain_name.canonicalize())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use super::*;
+    use crate::record::{RecordClass, RecordType};
+
+    #[test]
+    fn test_failing_addresses_are_left_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (up, down) = (Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(127, 0, 0, 2));
+
+        let name = DomainName::from("www.home.arpa");
+        let checker =
+            HealthChecker::new().pool(name.clone(), Probe::Tcp(port), vec![up.into(), down.into()]);
+        let record =
+            |address| DnsRecord::new(name.clone(), RecordType::A, RecordClass::IN, 60, address);
+
+        for _ in 0..FALL {
+            checker.check_all();
+        }
+        let mut records = vec![record(up), record(down)];
+        checker.filter(&mut records);
+        assert_eq!(records, [record(up)]);
+
+        // nothing is left out when all addresses are down
+        let mut records = vec![record(down)];
+        checker.filter(&mut records);
+        assert_eq!(records, [record(down)]);
+    }
+
+    /// Answers `connections` HTTP requests with `status_line`
+    fn http_server(status_line: &'static str, connections: usize) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                BufReader::new(&stream).read_line(&mut request).unwrap();
+                write!(stream, "{}\r\n\r\n", status_line).unwrap();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_http_errors_fail_the_probe() {
+        let host = DomainName::from("www.home.arpa");
+        let localhost = IpAddr::from(Ipv4Addr::LOCALHOST);
+        let probe = |port| Probe::Http {
+            port,
+            path: "/health".to_string(),
+        };
+
+        let port = http_server("HTTP/1.0 204 No Content", 1);
+        probe(port).check(localhost, &host).unwrap();
+
+        let port = http_server("HTTP/1.0 503 Service Unavailable", 1);
+        let error = probe(port).check(localhost, &host).unwrap_err();
+        assert_eq!(error.to_string(), r#"HTTP status "503""#);
+
+        let port = http_server("garbage", 1);
+        assert!(probe(port).check(localhost, &host).is_err());
+    }
+
+    #[test]
+    fn test_recovered_addresses_are_answered_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let (name, address) = (DomainName::from("www.home.arpa"), Ipv4Addr::LOCALHOST);
+        let checker =
+            HealthChecker::new().pool(name.clone(), Probe::Tcp(port), vec![address.into()]);
+        for _ in 0..FALL {
+            checker.check_all();
+        }
+        assert!(checker.is_down(&name, address.into()));
+
+        let _listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        checker.check_all();
+        assert!(!checker.is_down(&name, address.into()));
+    }
+
+    #[test]
+    fn test_invalid_probes_are_rejected() {
+        assert_eq!(
+            "http:8080".parse::<Probe>().unwrap(),
+            Probe::Http {
+                port: 8080,
+                path: "/".to_string()
+            }
+        );
+        for probe in ["tcp", "tcp:", "tcp:http", "http:99999/", "icmp:0"] {
+            assert!(probe.parse::<Probe>().is_err(), "{}", probe);
+        }
+    }
+}
@@ -0,0 +1,249 @@
+/*
+Debug facility for chasing serialization bugs
+
+Prints raw packet bytes as a classic hexdump and as an annotated decode,
+where every field of the packet is listed with its offset and bytes.
+Annotation works directly on the bytes (not on parsed DnsPacket) and never
+panics, so it is usable also for malformed or truncated packets.
+*/
+
+use std::fmt::Write;
+
+use crate::header::HEADER_LENGTH;
+use crate::question::{QueryClass, QueryType};
+
+/// Classic hexdump, 16 bytes per line with offset and ASCII column
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        let _ = write!(out, "{:04x}  ", i * 16);
+        for j in 0..16 {
+            match chunk.get(j) {
+                Some(b) => {
+                    let _ = write!(out, "{:02x} ", b);
+                }
+                None => out.push_str("   "),
+            }
+            if j == 7 {
+                out.push(' ');
+            }
+        }
+
+        out.push_str(" |");
+        out.extend(chunk.iter().map(|&b| match b {
+            0x20..=0x7E => b as char,
+            _ => '.',
+        }));
+        out.push_str("|\n");
+    }
+
+    out
+}
+
+/// Field-by-field annotated decode of the packet
+pub fn annotate(bytes: &[u8]) -> String {
+    let mut cursor = Cursor {
+        bytes,
+        pos: 0,
+        out: String::new(),
+    };
+
+    if cursor.annotate_packet().is_none() {
+        let pos = cursor.pos;
+        cursor.line(pos, 0, "!! truncated packet".to_string());
+    } else if cursor.pos < bytes.len() {
+        let pos = cursor.pos;
+        let len = bytes.len() - pos;
+        cursor.line(pos, len, format!("{} trailing byte(s)", len));
+    }
+
+    cursor.out
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    out: String,
+}
+
+impl Cursor<'_> {
+    fn annotate_packet(&mut self) -> Option<()> {
+        let id = self.u16("")?;
+        self.annotate_last(2, format!("ID: {}", id));
+
+        let flags = self.u16("")?;
+        self.annotate_last(
+            2,
+            format!(
+                "flags: QR={} OPCODE={} AA={} TC={} RD={} RA={} Z={} AD={} CD={} RCODE={}",
+                flags >> 15,
+                (flags >> 11) & 0x0F,
+                (flags >> 10) & 1,
+                (flags >> 9) & 1,
+                (flags >> 8) & 1,
+                (flags >> 7) & 1,
+                (flags >> 6) & 1,
+                (flags >> 5) & 1,
+                (flags >> 4) & 1,
+                flags & 0x0F,
+            ),
+        );
+
+        let qdcount = self.u16("QDCOUNT")?;
+        let ancount = self.u16("ANCOUNT")?;
+        let nscount = self.u16("NSCOUNT")?;
+        let arcount = self.u16("ARCOUNT")?;
+        debug_assert_eq!(self.pos, HEADER_LENGTH as usize);
+
+        for i in 0..qdcount {
+            self.section(format!("question #{}", i + 1));
+            self.name()?;
+            let query_type = self.u16("")?;
+            self.annotate_last(2, format!("QTYPE: {}", QueryType::from(query_type)));
+            let class = self.u16("")?;
+            self.annotate_last(2, format!("QCLASS: {}", QueryClass::from(class)));
+        }
+
+        for (section, count) in [
+            ("answer", ancount),
+            ("authority", nscount),
+            ("additional", arcount),
+        ] {
+            for i in 0..count {
+                self.section(format!("{} #{}", section, i + 1));
+                self.record()?;
+            }
+        }
+
+        Some(())
+    }
+
+    fn record(&mut self) -> Option<()> {
+        self.name()?;
+        let record_type = self.u16("")?;
+        self.annotate_last(2, format!("TYPE: {}", QueryType::from(record_type)));
+        let class = self.u16("")?;
+        self.annotate_last(2, format!("CLASS: {}", QueryClass::from(class)));
+
+        let start = self.pos;
+        let ttl = u32::from_be_bytes(self.take(4)?.try_into().ok()?);
+        self.line(start, 4, format!("TTL: {}", ttl));
+
+        let rdlength = self.u16("RDLENGTH")? as usize;
+        let start = self.pos;
+        let rdata = self.take(rdlength)?;
+        let description = match (record_type, rdata) {
+            (1, [a, b, c, d]) => format!("RDATA: {}.{}.{}.{}", a, b, c, d),
+            _ => "RDATA".to_string(),
+        };
+        self.line(start, rdlength, description);
+
+        Some(())
+    }
+
+    fn name(&mut self) -> Option<()> {
+        loop {
+            let start = self.pos;
+            let len = *self.bytes.get(self.pos)?;
+
+            match len {
+                0 => {
+                    self.pos += 1;
+                    self.line(start, 1, "root".to_string());
+                    return Some(());
+                }
+                len if len & 0xC0 == 0xC0 => {
+                    let pointer = self.u16_raw()? & 0x3FFF;
+                    let target = read_name_at(self.bytes, pointer as usize, 0)
+                        .unwrap_or_else(|| "!! invalid target".to_string());
+                    self.line(start, 2, format!("pointer -> {:04x} ({})", pointer, target));
+                    return Some(());
+                }
+                len => {
+                    self.pos += 1;
+                    let label = self.take(len as usize)?;
+                    let label = String::from_utf8_lossy(label).into_owned();
+                    self.line(start, len as usize + 1, format!("label {:?}", label));
+                }
+            }
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.bytes.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u16_raw(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Reads u16 and annotates it with `name` (annotation is left to the caller if `name` is empty)
+    fn u16(&mut self, name: &str) -> Option<u16> {
+        let value = self.u16_raw()?;
+        if !name.is_empty() {
+            self.annotate_last(2, format!("{}: {}", name, value));
+        }
+        Some(value)
+    }
+
+    fn annotate_last(&mut self, len: usize, description: String) {
+        self.line(self.pos - len, len, description);
+    }
+
+    fn section(&mut self, name: String) {
+        let _ = writeln!(self.out, ";; {}", name);
+    }
+
+    fn line(&mut self, pos: usize, len: usize, description: String) {
+        let end = (pos + len).min(self.bytes.len());
+        let shown = &self.bytes[pos.min(end)..end];
+
+        let mut hex = shown
+            .iter()
+            .take(8)
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if shown.len() > 8 {
+            hex.push_str(" ..");
+        }
+
+        let _ = writeln!(self.out, "{:04x}  {:<26} {}", pos, hex, description);
+    }
+}
+
+/// Reads domain name at `pos` following pointers (for showing pointer targets)
+fn read_name_at(bytes: &[u8], mut pos: usize, depth: usize) -> Option<String> {
+    if depth > 16 {
+        return None; // pointer loop
+    }
+
+    let mut name = String::new();
+    loop {
+        let len = *bytes.get(pos)?;
+        match len {
+            0 => break,
+            len if len & 0xC0 == 0xC0 => {
+                let pointer = (u16::from_be_bytes([len, *bytes.get(pos + 1)?]) & 0x3FFF) as usize;
+                name.push_str(&read_name_at(bytes, pointer, depth + 1)?);
+                return Some(name);
+            }
+            len => {
+                let label = bytes.get(pos + 1..pos + 1 + len as usize)?;
+                name.push_str(&String::from_utf8_lossy(label));
+                name.push('.');
+                pos += 1 + len as usize;
+            }
+        }
+    }
+
+    if name.is_empty() {
+        name.push('.');
+    }
+
+    Some(name)
+}
@@ -0,0 +1,399 @@
+//! HPACK, header compression of HTTP/2 (RFC 7541)
+//!
+//! Header blocks of a connection are decoded in full: indexed fields, the dynamic table and
+//! Huffman coded strings. Encoding is kept simple, fields which aren't in the static table
+//! are sent as plain literals which never enter the peer's dynamic table.
+
+use std::collections::VecDeque;
+
+use anyhow::{Context, Result};
+
+/// Header field, HTTP/2 names are lowercase
+pub(crate) type Header = (String, String);
+
+/// Size counted for every dynamic table entry besides its name and value (section 4.1)
+const ENTRY_OVERHEAD: usize = 32;
+
+/// Default size of the dynamic table (SETTINGS_HEADER_TABLE_SIZE)
+pub(crate) const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// Decoding state of one connection, header blocks must be decoded in the order they came
+#[derive(Debug)]
+pub(crate) struct Decoder {
+    /// Most recently inserted entry first
+    table: VecDeque<Header>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+}
+
+impl Decoder {
+    /// Decodes a complete header block (HEADERS frame with its CONTINUATIONs)
+    pub(crate) fn decode(&mut self, mut block: &[u8]) -> Result<Vec<Header>> {
+        let mut headers = Vec::new();
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                let index = read_integer(&mut block, 7)?;
+                headers.push(self.entry(index)?);
+            } else if first & 0x40 != 0 {
+                let header = self.read_literal(&mut block, 6)?;
+                self.insert(header.clone());
+                headers.push(header);
+            } else if first & 0x20 != 0 {
+                let size = read_integer(&mut block, 5)?;
+                if size > DEFAULT_TABLE_SIZE {
+                    anyhow::bail!("dynamic table size {} over the limit", size);
+                }
+                self.max_size = size;
+                self.evict(0);
+            } else {
+                // without indexing or never indexed, the difference matters only to proxies
+                headers.push(self.read_literal(&mut block, 4)?);
+            }
+        }
+        Ok(headers)
+    }
+
+    /// Entry of the static table (1..=61) or the dynamic one (62..)
+    fn entry(&self, index: usize) -> Result<Header> {
+        let entry = match index {
+            0 => None,
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Some((name.to_string(), value.to_string()))
+            }
+            _ => self.table.get(index - STATIC_TABLE.len() - 1).cloned(),
+        };
+        entry.with_context(|| format!("invalid header table index {}", index))
+    }
+
+    fn read_literal(&self, block: &mut &[u8], prefix_bits: u32) -> Result<Header> {
+        let name = match read_integer(block, prefix_bits)? {
+            0 => read_string(block)?,
+            index => self.entry(index)?.0,
+        };
+        Ok((name, read_string(block)?))
+    }
+
+    fn insert(&mut self, header: Header) {
+        let size = entry_size(&header);
+        self.evict(size);
+        // an entry larger than the whole table just empties it
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(header);
+        }
+    }
+
+    /// Drops the oldest entries until there is `room` left
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            match self.table.pop_back() {
+                Some(header) => self.size -= entry_size(&header),
+                None => break,
+            }
+        }
+    }
+}
+
+fn entry_size((name, value): &Header) -> usize {
+    name.len() + value.len() + ENTRY_OVERHEAD
+}
+
+/// Encodes header fields, fully indexed ones from the static table or literals without indexing
+pub(crate) fn encode(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for &(name, value) in headers {
+        let static_index = |matches: &dyn Fn(&(&str, &str)) -> bool| {
+            STATIC_TABLE.iter().position(matches).map(|i| i + 1)
+        };
+        if let Some(index) = static_index(&|&(n, v)| n == name && v == value) {
+            write_integer(&mut block, index, 7, 0x80);
+        } else if let Some(index) = static_index(&|&(n, _)| n == name) {
+            write_integer(&mut block, index, 4, 0x00);
+            write_string(&mut block, value);
+        } else {
+            block.push(0x00);
+            write_string(&mut block, name);
+            write_string(&mut block, value);
+        }
+    }
+    block
+}
+
+/// Integer with an N-bit prefix (section 5.1), the rest of the first byte are flags
+fn read_integer(block: &mut &[u8], prefix_bits: u32) -> Result<usize> {
+    let (&first, rest) = block.split_first().context("truncated header block")?;
+    *block = rest;
+
+    let max_prefix = (1 << prefix_bits) - 1;
+    let mut value = (first & max_prefix) as usize;
+    if value < max_prefix as usize {
+        return Ok(value);
+    }
+    for shift in (0..28).step_by(7) {
+        let (&byte, rest) = block.split_first().context("truncated header block")?;
+        *block = rest;
+        value += ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    anyhow::bail!("header block integer too large")
+}
+
+fn write_integer(block: &mut Vec<u8>, value: usize, prefix_bits: u32, flags: u8) {
+    let max_prefix = (1 << prefix_bits) - 1;
+    if value < max_prefix {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max_prefix as u8);
+    let mut rest = value - max_prefix;
+    while rest >= 0x80 {
+        block.push(0x80 | (rest & 0x7F) as u8);
+        rest >>= 7;
+    }
+    block.push(rest as u8);
+}
+
+/// String literal (section 5.2), optionally Huffman coded
+fn read_string(block: &mut &[u8]) -> Result<String> {
+    let huffman = block.first().is_some_and(|first| first & 0x80 != 0);
+    let len = read_integer(block, 7)?;
+    if block.len() < len {
+        anyhow::bail!("truncated header block");
+    }
+    let (data, rest) = block.split_at(len);
+    *block = rest;
+
+    let data = if huffman {
+        decode_huffman(data)?
+    } else {
+        data.to_vec()
+    };
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+fn write_string(block: &mut Vec<u8>, value: &str) {
+    write_integer(block, value.len(), 7, 0x00);
+    block.extend_from_slice(value.as_bytes());
+}
+
+/// Decodes the canonical Huffman code of Appendix B
+///
+/// Codes of the same length are consecutive numbers, ordered by symbol, so the code read so
+/// far is a symbol as soon as it falls into the range of codes of its length.
+fn decode_huffman(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(data.len() * 8 / 5);
+    // code read so far, its length, the first code of that length and its symbol index
+    let (mut code, mut len, mut first, mut index) = (0u32, 0, 0u32, 0);
+
+    for bit in data
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| byte >> i & 1))
+    {
+        code = code << 1 | bit as u32;
+        len += 1;
+        let count = *CODE_LENGTH_COUNTS
+            .get(len)
+            .context("invalid Huffman code")? as u32;
+        if code < first + count {
+            match SYMBOLS[index + (code - first) as usize] {
+                EOS => anyhow::bail!("EOS in Huffman coded string"),
+                symbol => decoded.push(symbol as u8),
+            }
+            (code, len, first, index) = (0, 0, 0, 0);
+        } else {
+            index += count as usize;
+            first = (first + count) << 1;
+        }
+    }
+
+    // padded with the most significant bits of EOS, i.e. ones
+    if len > 7 || code != (1 << len) - 1 {
+        anyhow::bail!("invalid Huffman padding");
+    }
+    Ok(decoded)
+}
+
+const EOS: u16 = 256;
+
+/// Number of Huffman codes of each length in bits
+const CODE_LENGTH_COUNTS: [u16; 31] = [
+    0, 0, 0, 0, 0, 10, 26, 32, 6, 0, 5, 3, 2, 6, 2, 3, 0, 0, 0, 3, 8, 13, 26, 29, 12, 4, 15, 19,
+    29, 0, 4,
+];
+
+/// Huffman coded symbols by the length of their code, then by value
+const SYMBOLS: [u16; 257] = [
+    48, 49, 50, 97, 99, 101, 105, 111, 115, 116, 32, 37, 45, 46, 47, 51, 52, 53, 54, 55, 56, 57,
+    61, 65, 95, 98, 100, 102, 103, 104, 108, 109, 110, 112, 114, 117, 58, 66, 67, 68, 69, 70, 71,
+    72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84, 85, 86, 87, 89, 106, 107, 113, 118, 119,
+    120, 121, 122, 38, 42, 44, 59, 88, 90, 33, 34, 40, 41, 63, 39, 43, 124, 35, 62, 0, 36, 64, 91,
+    93, 126, 94, 125, 60, 96, 123, 92, 195, 208, 128, 130, 131, 162, 184, 194, 224, 226, 153, 161,
+    167, 172, 176, 177, 179, 209, 216, 217, 227, 229, 230, 129, 132, 133, 134, 136, 146, 154, 156,
+    160, 163, 164, 169, 170, 173, 178, 181, 185, 186, 187, 189, 190, 196, 198, 228, 232, 233, 1,
+    135, 137, 138, 139, 140, 141, 143, 147, 149, 150, 151, 152, 155, 157, 158, 165, 166, 168, 174,
+    175, 180, 182, 183, 188, 191, 197, 231, 239, 9, 142, 144, 145, 148, 159, 171, 206, 215, 225,
+    236, 237, 199, 207, 234, 235, 192, 193, 200, 201, 202, 205, 210, 213, 218, 219, 238, 240, 242,
+    243, 255, 203, 204, 211, 212, 214, 221, 222, 223, 241, 244, 245, 246, 247, 248, 250, 251, 252,
+    253, 254, 2, 3, 4, 5, 6, 7, 8, 11, 12, 14, 15, 16, 17, 18, 19, 20, 21, 23, 24, 25, 26, 27, 28,
+    29, 30, 31, 127, 220, 249, 10, 13, 22, 256,
+];
+
+/// Static table (Appendix A)
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_requests_with_huffman_coding() {
+        // RFC 7541 appendix C.4, the later requests refer to the dynamic table
+        let mut decoder = Decoder::default();
+        let blocks = [
+            "828684418cf1e3c2e5f23a6ba0ab90f4ff",
+            "828684be5886a8eb10649cbf",
+            "828785bf408825a849e95ba97d7f8925a849e95bb8e8b4bf",
+        ];
+        let headers: Vec<_> = blocks
+            .iter()
+            .map(|block| decoder.decode(&hex(block)).unwrap())
+            .collect();
+
+        assert_eq!(
+            headers[1][3..],
+            [
+                (":authority".to_string(), "www.example.com".to_string()),
+                ("cache-control".to_string(), "no-cache".to_string()),
+            ]
+        );
+        assert_eq!(
+            headers[2][2..],
+            [
+                (":path".to_string(), "/index.html".to_string()),
+                (":authority".to_string(), "www.example.com".to_string()),
+                ("custom-key".to_string(), "custom-value".to_string()),
+            ]
+        );
+        assert_eq!(decoder.size, 164);
+    }
+
+    #[test]
+    fn test_encoded_headers_decode() {
+        let headers = [
+            (":status", "200"),
+            (":status", "415"),
+            ("content-type", "application/dns-message"),
+            ("x-long", &"a".repeat(300)),
+        ];
+        let decoded = Decoder::default().decode(&encode(&headers)).unwrap();
+
+        assert_eq!(decoded.len(), headers.len());
+        for ((name, value), decoded) in headers.iter().zip(decoded) {
+            assert_eq!((*name, *value), (decoded.0.as_str(), decoded.1.as_str()));
+        }
+        assert_eq!(encode(&[(":status", "200")]), [0x88]);
+    }
+
+    #[test]
+    fn test_malformed_blocks_are_rejected() {
+        for block in [
+            "80",         // index 0
+            "be",         // first dynamic table entry, the table is empty
+            "ff",         // integer missing its continuation
+            "ff80808080", // integer too large
+            "4005",       // literal name shorter than its length
+            "3fe21f",     // dynamic table size of 4097 bytes
+        ] {
+            assert!(Decoder::default().decode(&hex(block)).is_err(), "{}", block);
+        }
+    }
+
+    #[test]
+    fn test_invalid_huffman_padding_is_rejected() {
+        // "a" (00011) padded with zeros instead of ones
+        assert!(decode_huffman(&[0b0001_1000]).is_err());
+        assert_eq!(decode_huffman(&[0b0001_1111]).unwrap(), b"a");
+    }
+}
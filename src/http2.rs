@@ -0,0 +1,929 @@
+//! HTTP/2 server connections (RFC 9113), as much of the protocol as DNS over HTTPS needs
+//!
+//! Every request is answered in its own thread as soon as its stream is complete, so a slow
+//! answer doesn't hold up the other streams of the connection. Responses respect the
+//! client's flow control windows and maximal frame size. Server push isn't used, priorities
+//! and request trailers are ignored.
+//!
+//! The connection is closed after the idle timeout of its listener without any stream being
+//! answered, and when a frame doesn't arrive whole within the message timeout once it
+//! started, see [`crate::connections`]. It counts as busy while streams are answered.
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use rustls::ServerConnection;
+
+use crate::connections::{self, Deadline};
+use crate::hpack::{self, Header};
+use crate::tls::decrypt;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Streams answered at the same time, more are refused until some of them finish
+pub(crate) const MAX_CONCURRENT_STREAMS: usize = 100;
+
+/// Largest request body accepted, DNS messages can't be longer
+const MAX_BODY_SIZE: usize = 65_535;
+
+/// Largest header block (HEADERS with its CONTINUATIONs) accepted
+const MAX_HEADER_BLOCK_SIZE: usize = 16 * 1024;
+
+/// Frame size limit both sides start with, we never ask for more
+const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024;
+
+/// Flow control window of the connection and of every stream until the peer changes it
+const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+
+/// Largest flow control window (section 6.9.1)
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+const FRAME_HEADER_SIZE: usize = 9;
+
+// frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+// settings
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const CANCEL: u32 = 0x8;
+const COMPRESSION_ERROR: u32 = 0x9;
+
+/// Request received on one stream
+#[derive(Debug, Default)]
+pub(crate) struct Request {
+    /// Including pseudo-headers (`:method`, `:path`, ...)
+    pub(crate) headers: Vec<Header>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of the first header called `name` (lowercase)
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Response to a [`Request`], `content-length` is added when it is sent
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(&'static str, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Response {
+    pub(crate) fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub(crate) fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+}
+
+/// Connection shared by the thread reading it and the threads answering its streams
+struct Connection {
+    socket: TcpStream,
+    /// Admitted by the listener, busy while streams are answered
+    tracked: Arc<connections::Connection>,
+    /// Locked briefly for encrypting a frame or decrypting received data, never while
+    /// waiting for the socket to become readable
+    tls: Mutex<ServerConnection>,
+    flow: Mutex<FlowControl>,
+    /// Notified when the client opens its windows or the connection ends
+    window_opened: Condvar,
+}
+
+/// Sending side of flow control (section 5.2)
+struct FlowControl {
+    connection_window: i64,
+    /// Windows of the streams being answered, a stream missing here was reset by the client
+    stream_windows: HashMap<u32, i64>,
+    initial_window: i64,
+    max_frame_size: usize,
+    closed: bool,
+}
+
+/// Stream whose request is still being received
+struct IncomingStream {
+    request: Request,
+    /// Too large request, the rest of it is skipped
+    refused: bool,
+}
+
+impl Connection {
+    fn send_frame(&self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Result<()> {
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+
+        let mut tls = self.tls.lock().expect("connection lock poisoned");
+        tls.writer().write_all(&frame)?;
+        while tls.wants_write() {
+            tls.write_tls(&mut &self.socket)?;
+        }
+        Ok(())
+    }
+
+    fn reset_stream(&self, stream: u32, error_code: u32) -> Result<()> {
+        self.send_frame(RST_STREAM, 0, stream, &error_code.to_be_bytes())
+    }
+
+    /// Tells the client the connection is done, streams after `last_stream` weren't processed
+    fn go_away(&self, last_stream: u32, error_code: u32) {
+        let mut payload = last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&error_code.to_be_bytes());
+        let _ = self.send_frame(GOAWAY, 0, 0, &payload);
+    }
+
+    fn send_response(&self, stream: u32, response: &Response) -> Result<()> {
+        let status = response.status.to_string();
+        let content_length = response.body.len().to_string();
+        let mut headers = vec![(":status", status.as_str())];
+        headers.extend(response.headers.iter().map(|(n, v)| (*n, v.as_str())));
+        headers.push(("content-length", &content_length));
+
+        // response headers are small, they always fit in a single frame
+        let flags = END_HEADERS
+            | if response.body.is_empty() {
+                END_STREAM
+            } else {
+                0
+            };
+        self.send_frame(HEADERS, flags, stream, &hpack::encode(&headers))?;
+
+        let mut body = response.body.as_slice();
+        while !body.is_empty() {
+            let (chunk, rest) = body.split_at(self.reserve(stream, body.len())?);
+            let flags = if rest.is_empty() { END_STREAM } else { 0 };
+            self.send_frame(DATA, flags, stream, chunk)?;
+            body = rest;
+        }
+        Ok(())
+    }
+
+    /// Waits until up to `wanted` bytes of DATA may be sent on `stream`, takes them from
+    /// the windows
+    fn reserve(&self, stream: u32, wanted: usize) -> Result<usize> {
+        let mut flow = self.flow.lock().expect("connection lock poisoned");
+        loop {
+            if flow.closed {
+                anyhow::bail!("connection closed");
+            }
+            let stream_window = *flow
+                .stream_windows
+                .get(&stream)
+                .context("stream reset by the client")?;
+            let available = stream_window
+                .min(flow.connection_window)
+                .min(flow.max_frame_size as i64);
+            if available > 0 {
+                let len = wanted.min(available as usize);
+                flow.connection_window -= len as i64;
+                flow.stream_windows
+                    .insert(stream, stream_window - len as i64);
+                return Ok(len);
+            }
+
+            let (guard, wait) = self
+                .window_opened
+                .wait_timeout(flow, self.tracked.idle_timeout())
+                .expect("connection lock poisoned");
+            if wait.timed_out() {
+                anyhow::bail!("flow control window stayed closed");
+            }
+            flow = guard;
+        }
+    }
+
+    fn answering_streams(&self) -> usize {
+        let flow = self.flow.lock().expect("connection lock poisoned");
+        flow.stream_windows.len()
+    }
+
+    fn close(&self) {
+        let mut flow = self.flow.lock().expect("connection lock poisoned");
+        flow.closed = true;
+        self.window_opened.notify_all();
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
+
+/// Serves requests of an established TLS connection (ALPN `h2`) until the client is done,
+/// idle for too long or breaks the protocol
+pub(crate) fn serve_connection<H>(
+    socket: TcpStream,
+    mut tls: ServerConnection,
+    tracked: Arc<connections::Connection>,
+    handler: H,
+) -> Result<()>
+where
+    H: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    // the preface may come right along with the end of the handshake
+    let mut plaintext = Vec::new();
+    match tls.reader().read_to_end(&mut plaintext) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    socket.set_write_timeout(Some(tracked.idle_timeout()))?;
+    let connection = Arc::new(Connection {
+        socket: socket.try_clone()?,
+        tracked,
+        tls: Mutex::new(tls),
+        flow: Mutex::new(FlowControl {
+            connection_window: DEFAULT_WINDOW_SIZE,
+            stream_windows: HashMap::new(),
+            initial_window: DEFAULT_WINDOW_SIZE,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            closed: false,
+        }),
+        window_opened: Condvar::new(),
+    });
+
+    let mut reader = FrameReader {
+        socket,
+        connection: connection.clone(),
+        handler: Arc::new(handler),
+        plaintext,
+        decoder: hpack::Decoder::default(),
+        incoming: HashMap::new(),
+        last_stream: 0,
+    };
+    let result = reader.read_frames();
+    connection.close();
+    result
+}
+
+/// Reads frames of the connection and dispatches complete requests
+struct FrameReader<H> {
+    socket: TcpStream,
+    connection: Arc<Connection>,
+    handler: Arc<H>,
+    plaintext: Vec<u8>,
+    decoder: hpack::Decoder,
+    incoming: HashMap<u32, IncomingStream>,
+    /// Highest stream opened by the client so far
+    last_stream: u32,
+}
+
+impl<H> FrameReader<H>
+where
+    H: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    fn read_frames(&mut self) -> Result<()> {
+        if !self.fill(PREFACE.len())? || self.take(PREFACE.len()) != PREFACE {
+            anyhow::bail!("missing HTTP/2 connection preface");
+        }
+        let mut settings = SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes().to_vec();
+        settings.extend_from_slice(&(MAX_CONCURRENT_STREAMS as u32).to_be_bytes());
+        self.connection.send_frame(SETTINGS, 0, 0, &settings)?;
+
+        // header block being continued: stream, flags of its HEADERS and the block so far
+        let mut continued: Option<(u32, u8, Vec<u8>)> = None;
+        loop {
+            if !self.fill(FRAME_HEADER_SIZE)? {
+                self.connection.go_away(self.last_stream, NO_ERROR);
+                return Ok(());
+            }
+            let header = &self.plaintext[..FRAME_HEADER_SIZE];
+            let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+            let (kind, flags) = (header[3], header[4]);
+            let stream =
+                u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & !(1 << 31);
+            if len > DEFAULT_MAX_FRAME_SIZE {
+                return Err(self.error(FRAME_SIZE_ERROR, "frame too large"));
+            }
+            if !self.fill(FRAME_HEADER_SIZE + len)? {
+                return Ok(());
+            }
+            let frame = self.take(FRAME_HEADER_SIZE + len);
+            let payload = &frame[FRAME_HEADER_SIZE..];
+
+            if let Some((continued_stream, headers_flags, mut block)) = continued.take() {
+                if kind != CONTINUATION || stream != continued_stream {
+                    return Err(self.error(PROTOCOL_ERROR, "header block not continued"));
+                }
+                block.extend_from_slice(payload);
+                if block.len() > MAX_HEADER_BLOCK_SIZE {
+                    return Err(self.error(PROTOCOL_ERROR, "header block too large"));
+                }
+                if flags & END_HEADERS == 0 {
+                    continued = Some((stream, headers_flags, block));
+                } else {
+                    self.headers(stream, headers_flags, &block)?;
+                }
+                continue;
+            }
+
+            match kind {
+                DATA => self.data(stream, flags, payload)?,
+                HEADERS => {
+                    let mut block = self.unpad(flags, payload)?;
+                    if flags & PRIORITY != 0 {
+                        block = block.get(5..).ok_or_else(|| {
+                            self.error(FRAME_SIZE_ERROR, "HEADERS too short for priority")
+                        })?;
+                    }
+                    if stream == 0 || stream.is_multiple_of(2) {
+                        return Err(self.error(PROTOCOL_ERROR, "invalid stream"));
+                    }
+                    if flags & END_HEADERS == 0 {
+                        continued = Some((stream, flags, block.to_vec()));
+                    } else {
+                        self.headers(stream, flags, block)?;
+                    }
+                }
+                RST_STREAM => {
+                    self.incoming.remove(&stream);
+                    let mut flow = self
+                        .connection
+                        .flow
+                        .lock()
+                        .expect("connection lock poisoned");
+                    flow.stream_windows.remove(&stream);
+                    self.connection.window_opened.notify_all();
+                }
+                SETTINGS if stream != 0 => {
+                    return Err(self.error(PROTOCOL_ERROR, "SETTINGS on a stream"))
+                }
+                SETTINGS if flags & ACK == 0 => self.settings(payload)?,
+                PING if flags & ACK == 0 => self.connection.send_frame(PING, ACK, 0, payload)?,
+                WINDOW_UPDATE => self.window_update(stream, payload)?,
+                PUSH_PROMISE | CONTINUATION => {
+                    return Err(self.error(PROTOCOL_ERROR, "unexpected frame"))
+                }
+                // the client still waits for responses to its streams after GOAWAY,
+                // PRIORITY and unknown frames don't need any action
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads until at least `len` bytes of plaintext are buffered, `false` when the client
+    /// closed the connection or was idle too long
+    ///
+    /// Once part of a frame (or of the TLS record carrying it) arrived, the rest of it must
+    /// follow within the message timeout.
+    fn fill(&mut self, len: usize) -> Result<bool> {
+        let mut received = vec![0; 16 * 1024];
+        let message_timeout = self.connection.tracked.message_timeout();
+        let mut started = (!self.plaintext.is_empty()).then(Instant::now);
+        while self.plaintext.len() < len {
+            let read = match started {
+                Some(started) => {
+                    let remaining = message_timeout.saturating_sub(started.elapsed());
+                    Deadline::new(&self.socket, remaining).read(&mut received)
+                }
+                None => {
+                    let idle_timeout = self.connection.tracked.idle_timeout();
+                    self.socket.set_read_timeout(Some(idle_timeout))?;
+                    self.socket.read(&mut received)
+                }
+            };
+            let received_len = match read {
+                Ok(0) => return Ok(false),
+                Ok(received_len) => received_len,
+                // streams being answered keep the connection from being idle
+                Err(e)
+                    if started.is_none()
+                        && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    if self.connection.answering_streams() == 0 {
+                        return Ok(false);
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e).context("reading HTTP/2 frame"),
+            };
+            started.get_or_insert_with(Instant::now);
+            let mut tls = self
+                .connection
+                .tls
+                .lock()
+                .expect("connection lock poisoned");
+            match decrypt(
+                &mut tls,
+                &self.connection.socket,
+                &received[..received_len],
+                &mut self.plaintext,
+            ) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    fn take(&mut self, len: usize) -> Vec<u8> {
+        self.plaintext.drain(..len).collect()
+    }
+
+    /// Sends GOAWAY with `error_code`, the returned error ends the connection
+    fn error(&self, error_code: u32, message: &str) -> anyhow::Error {
+        self.connection.go_away(self.last_stream, error_code);
+        anyhow::anyhow!("HTTP/2 error: {}", message)
+    }
+
+    /// Payload of DATA or HEADERS without padding
+    fn unpad<'a>(&self, flags: u8, payload: &'a [u8]) -> Result<&'a [u8]> {
+        if flags & PADDED == 0 {
+            return Ok(payload);
+        }
+        match payload.split_first() {
+            Some((&padding, rest)) if (padding as usize) <= rest.len() => {
+                Ok(&rest[..rest.len() - padding as usize])
+            }
+            _ => Err(self.error(PROTOCOL_ERROR, "invalid padding")),
+        }
+    }
+
+    fn headers(&mut self, stream: u32, flags: u8, block: &[u8]) -> Result<()> {
+        // every block must be decoded to keep the dynamic table in sync, even refused ones
+        let headers = match self.decoder.decode(block) {
+            Ok(headers) => headers,
+            Err(e) => return Err(self.error(COMPRESSION_ERROR, &e.to_string())),
+        };
+
+        if self.incoming.contains_key(&stream) {
+            // trailers end the request
+            if flags & END_STREAM == 0 {
+                return Err(self.error(PROTOCOL_ERROR, "trailers without END_STREAM"));
+            }
+            let incoming = self.incoming.remove(&stream).expect("stream is incoming");
+            self.dispatch(stream, incoming.request);
+            return Ok(());
+        }
+        if stream <= self.last_stream {
+            return Err(self.error(PROTOCOL_ERROR, "HEADERS on a closed stream"));
+        }
+        self.last_stream = stream;
+
+        if self.incoming.len() + self.connection.answering_streams() >= MAX_CONCURRENT_STREAMS {
+            return self.connection.reset_stream(stream, REFUSED_STREAM);
+        }
+        let request = Request {
+            headers,
+            body: Vec::new(),
+        };
+        if flags & END_STREAM != 0 {
+            self.dispatch(stream, request);
+        } else {
+            let incoming = IncomingStream {
+                request,
+                refused: false,
+            };
+            self.incoming.insert(stream, incoming);
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, stream: u32, flags: u8, payload: &[u8]) -> Result<()> {
+        if stream == 0 {
+            return Err(self.error(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        // whatever happens to the data, the client may send as much again
+        if !payload.is_empty() {
+            let increment = (payload.len() as u32).to_be_bytes();
+            self.connection
+                .send_frame(WINDOW_UPDATE, 0, 0, &increment)?;
+            if flags & END_STREAM == 0 && self.incoming.contains_key(&stream) {
+                self.connection
+                    .send_frame(WINDOW_UPDATE, 0, stream, &increment)?;
+            }
+        }
+
+        let data = self.unpad(flags, payload)?;
+        // data of refused or reset streams is dropped
+        let Some(incoming) = self.incoming.get_mut(&stream) else {
+            return Ok(());
+        };
+        if !incoming.refused {
+            incoming.request.body.extend_from_slice(data);
+            if incoming.request.body.len() > MAX_BODY_SIZE {
+                incoming.refused = true;
+                self.connection.reset_stream(stream, CANCEL)?;
+            }
+        }
+        if flags & END_STREAM != 0 {
+            let incoming = self.incoming.remove(&stream).expect("stream is incoming");
+            if !incoming.refused {
+                self.dispatch(stream, incoming.request);
+            }
+        }
+        Ok(())
+    }
+
+    fn settings(&mut self, payload: &[u8]) -> Result<()> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(self.error(FRAME_SIZE_ERROR, "invalid SETTINGS length"));
+        }
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            let mut flow = self
+                .connection
+                .flow
+                .lock()
+                .expect("connection lock poisoned");
+            match id {
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if value > i32::MAX as u32 {
+                        drop(flow);
+                        return Err(self.error(FLOW_CONTROL_ERROR, "initial window too large"));
+                    }
+                    // the change applies to the windows of open streams too
+                    let delta = value as i64 - flow.initial_window;
+                    if flow
+                        .stream_windows
+                        .values()
+                        .any(|window| window + delta > MAX_WINDOW_SIZE)
+                    {
+                        drop(flow);
+                        return Err(self.error(FLOW_CONTROL_ERROR, "stream window too large"));
+                    }
+                    flow.initial_window = value as i64;
+                    flow.stream_windows
+                        .values_mut()
+                        .for_each(|window| *window += delta);
+                    self.connection.window_opened.notify_all();
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(DEFAULT_MAX_FRAME_SIZE as u32..1 << 24).contains(&value) {
+                        drop(flow);
+                        return Err(self.error(PROTOCOL_ERROR, "invalid maximal frame size"));
+                    }
+                    flow.max_frame_size = value as usize;
+                }
+                // the dynamic table size limits our encoder, which doesn't use the table
+                _ => {}
+            }
+        }
+        self.connection.send_frame(SETTINGS, ACK, 0, &[])
+    }
+
+    fn window_update(&mut self, stream: u32, payload: &[u8]) -> Result<()> {
+        let increment = match payload {
+            [a, b, c, d] => u32::from_be_bytes([*a, *b, *c, *d]) & !(1 << 31),
+            _ => return Err(self.error(FRAME_SIZE_ERROR, "invalid WINDOW_UPDATE length")),
+        };
+        // errors of a stream's window end just the stream (section 6.9)
+        if increment == 0 {
+            if stream == 0 {
+                return Err(self.error(PROTOCOL_ERROR, "WINDOW_UPDATE without increment"));
+            }
+            return self.reset_answered_stream(stream, PROTOCOL_ERROR);
+        }
+        let mut flow = self
+            .connection
+            .flow
+            .lock()
+            .expect("connection lock poisoned");
+        let window = match stream {
+            0 => &mut flow.connection_window,
+            stream => match flow.stream_windows.get_mut(&stream) {
+                Some(window) => window,
+                None => return Ok(()),
+            },
+        };
+        if *window + increment as i64 > MAX_WINDOW_SIZE {
+            drop(flow);
+            if stream == 0 {
+                return Err(self.error(FLOW_CONTROL_ERROR, "connection window too large"));
+            }
+            return self.reset_answered_stream(stream, FLOW_CONTROL_ERROR);
+        }
+        *window += increment as i64;
+        self.connection.window_opened.notify_all();
+        Ok(())
+    }
+
+    /// Resets `stream`, its response isn't sent
+    fn reset_answered_stream(&self, stream: u32, error_code: u32) -> Result<()> {
+        let mut flow = self
+            .connection
+            .flow
+            .lock()
+            .expect("connection lock poisoned");
+        flow.stream_windows.remove(&stream);
+        self.connection.window_opened.notify_all();
+        drop(flow);
+        self.connection.reset_stream(stream, error_code)
+    }
+
+    /// Answers the request in a new thread
+    fn dispatch(&self, stream: u32, request: Request) {
+        {
+            let mut flow = self
+                .connection
+                .flow
+                .lock()
+                .expect("connection lock poisoned");
+            let initial_window = flow.initial_window;
+            flow.stream_windows.insert(stream, initial_window);
+            self.connection.tracked.busy();
+        }
+
+        let (connection, handler) = (self.connection.clone(), self.handler.clone());
+        thread::spawn(move || {
+            let response = handler(&request);
+            if let Err(e) = connection.send_response(stream, &response) {
+                eprintln!("HTTP/2: failed to answer stream {}: {:#}", stream, e);
+            }
+            let mut flow = connection.flow.lock().expect("connection lock poisoned");
+            flow.stream_windows.remove(&stream);
+            if flow.stream_windows.is_empty() {
+                connection.tracked.idle();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+    use rustls::server::{ClientHello, ResolvesServerCert};
+    use rustls::sign::CertifiedKey;
+    use rustls::{
+        ClientConfig, ClientConnection, DigitallySignedStruct, ServerConfig, SignatureScheme,
+        StreamOwned,
+    };
+
+    use super::*;
+    use crate::connections::{ConnectionLimits, ConnectionTracker};
+
+    /// Ed25519 key of RFC 8410 section 10.3
+    const KEY: &str = "302e020100300506032b657004220420\
+                       d4ee72dbf913584ad5b6d8f1f769f8ad3afe7c28cbf1d4fbe097a88f44755842";
+
+    /// Server certificate, its content doesn't matter to [`AnyCertificate`]
+    #[derive(Debug)]
+    struct TestCertificate(Arc<CertifiedKey>);
+
+    impl ResolvesServerCert for TestCertificate {
+        fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+            Some(self.0.clone())
+        }
+    }
+
+    /// Client side verifier trusting any certificate and signature
+    #[derive(Debug)]
+    struct AnyCertificate;
+
+    impl ServerCertVerifier for AnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            vec![SignatureScheme::ED25519]
+        }
+    }
+
+    type Client = StreamOwned<ClientConnection, TcpStream>;
+
+    /// Client of a connection served within `limits` after the preface, and the server's
+    /// result; requests are answered with their path
+    fn connect(limits: ConnectionLimits) -> (Client, JoinHandle<Result<()>>) {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let key: Vec<u8> = (0..KEY.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&KEY[i..i + 2], 16).unwrap())
+            .collect();
+        let key = PrivateKeyDer::try_from(key).unwrap();
+        let key = rustls::crypto::ring::sign::any_supported_type(&key).unwrap();
+        let certificate = CertifiedKey::new(vec![CertificateDer::from(vec![0x30, 0])], key);
+        let mut server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(TestCertificate(Arc::new(certificate))));
+        server_config.alpn_protocols = vec![b"h2".to_vec()];
+        let mut client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate))
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"h2".to_vec()];
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let server = thread::spawn(move || {
+            let tracked = ConnectionTracker::new(limits).admit(&stream)?;
+            let stream =
+                crate::dot::accept(stream, Arc::new(server_config), limits.message_timeout)?;
+            let (tls, socket) = stream.into_parts();
+            serve_connection(socket, tls, Arc::new(tracked), |request: &Request| {
+                let path = request.header(":path").unwrap_or_default();
+                Response::new(200).with_body(path.as_bytes().to_vec())
+            })
+        });
+
+        let name = ServerName::try_from("localhost").unwrap();
+        let tls = ClientConnection::new(Arc::new(client_config), name).unwrap();
+        let mut client = StreamOwned::new(tls, socket);
+        client.write_all(PREFACE).unwrap();
+        (client, server)
+    }
+
+    fn send(client: &mut Client, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        client.write_all(&frame).unwrap();
+    }
+
+    /// Type, stream and payload of the next frame
+    fn receive(client: &mut Client) -> (u8, u32, Vec<u8>) {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        client.read_exact(&mut header).unwrap();
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let stream = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+        let mut payload = vec![0; len];
+        client.read_exact(&mut payload).unwrap();
+        (header[3], stream, payload)
+    }
+
+    /// Error code of the GOAWAY the server ends the connection with
+    fn go_away_error(client: &mut Client) -> u32 {
+        loop {
+            if let (GOAWAY, 0, payload) = receive(client) {
+                return u32::from_be_bytes(payload[4..8].try_into().unwrap());
+            }
+        }
+    }
+
+    fn get(path: &str) -> Vec<u8> {
+        hpack::encode(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":authority", "localhost"),
+            (":path", path),
+        ])
+    }
+
+    #[test]
+    fn test_request_is_answered() {
+        let (mut client, _server) = connect(ConnectionLimits::default());
+        send(
+            &mut client,
+            HEADERS,
+            END_HEADERS | END_STREAM,
+            1,
+            &get("/dns-query"),
+        );
+
+        let body = loop {
+            if let (DATA, 1, payload) = receive(&mut client) {
+                break payload;
+            }
+        };
+        assert_eq!(body, b"/dns-query");
+    }
+
+    #[test]
+    fn test_invalid_window_updates_are_rejected() {
+        let (mut client, server) = connect(ConnectionLimits::default());
+        send(&mut client, WINDOW_UPDATE, 0, 0, &0u32.to_be_bytes());
+        assert_eq!(go_away_error(&mut client), PROTOCOL_ERROR);
+        assert!(server.join().unwrap().is_err());
+
+        // the window may not grow over 2^31-1
+        let (mut client, server) = connect(ConnectionLimits::default());
+        send(
+            &mut client,
+            WINDOW_UPDATE,
+            0,
+            0,
+            &((1u32 << 31) - 1).to_be_bytes(),
+        );
+        assert_eq!(go_away_error(&mut client), FLOW_CONTROL_ERROR);
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_settings_on_a_stream_are_rejected() {
+        let (mut client, server) = connect(ConnectionLimits::default());
+        send(&mut client, SETTINGS, 0, 1, &[]);
+        assert_eq!(go_away_error(&mut client), PROTOCOL_ERROR);
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_undecodable_headers_are_rejected() {
+        let (mut client, server) = connect(ConnectionLimits::default());
+        send(&mut client, HEADERS, END_HEADERS | END_STREAM, 1, &[0x80]);
+        assert_eq!(go_away_error(&mut client), COMPRESSION_ERROR);
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_trickled_frame_times_out() {
+        let (mut client, server) = connect(ConnectionLimits {
+            message_timeout: Duration::from_millis(300),
+            ..ConnectionLimits::default()
+        });
+        let block = get("/dns-query");
+        let mut frame = (block.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[HEADERS, END_HEADERS | END_STREAM, 0, 0, 0, 1]);
+        frame.extend_from_slice(&block);
+        // every byte arrives in time on its own, the whole frame doesn't
+        for byte in frame {
+            if client.write_all(&[byte]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let error = server.join().unwrap().unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("not received in time"),
+            "{:#}",
+            error
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! DNS over HTTPS listener (RFC 8484) over HTTP/2
+//!
+//! Queries are POSTed to [`PATH`] as `application/dns-message` or sent by GET in its `dns`
+//! parameter (base64url). A connection carries many queries at the same time, each answered
+//! as soon as it is ready; browsers use DoH only over HTTP/2.
+//!
+//! With the `json` feature, `GET` requests of [`crate::doh::PATH`] are answered in JSON as
+//! well, the same way the `--json-api` listener answers them.
+//!
+//! Connections are limited and timed out like those of the other stream listeners, see
+//! [`crate::connections`], and may start with a PROXY protocol header, see
+//! [`crate::proxy_protocol`]. Queries the handlers fail are answered with SERVFAIL, not an
+//! HTTP error, as over the other transports.
+//!
+//! Certificates are loaded and reloaded the same way as for DoT, see
+//! [`crate::dot::CertificateFiles`].
+//!
+//! HTTP/3 isn't offered: it runs over QUIC, which the server doesn't implement. Responses
+//! carry no `Alt-Svc` header, so clients don't go looking for it and stay on HTTP/2.
+
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{Context, Result};
+use rustls::ServerConfig;
+
+use crate::base64;
+use crate::connections::{Connection, ConnectionLimits, ConnectionTracker};
+use crate::dot::{self, CertificateFiles};
+use crate::handler::{response_builder, Request};
+use crate::header::ResponseCode;
+use crate::http2;
+use crate::listen;
+use crate::log;
+use crate::packet::{BytesPacket, DnsPacket};
+use crate::proxy_protocol;
+use crate::trace;
+
+/// Path of DoH queries
+pub const PATH: &str = "/dns-query";
+
+const CONTENT_TYPE: &str = "application/dns-message";
+
+/// Serves queries on `address` with the certificate from `certificates`, each connection
+/// in its own thread
+///
+/// With `proxy_protocol`, every connection must start with PROXY protocol v2 header and the
+/// client address is taken from it.
+pub fn serve<F>(
+    address: &str,
+    certificates: Arc<CertificateFiles>,
+    limits: ConnectionLimits,
+    proxy_protocol: bool,
+    handler: F,
+) -> Result<()>
+where
+    F: Fn(&Request) -> Result<DnsPacket> + Send + Sync + 'static,
+{
+    let config = dot::server_config(certificates, b"h2")?;
+    let listener = listen::tcp(address)
+        .with_context(|| format!("Failed to bind DoH listener to {}", address))?;
+
+    println!("Listening on DoH (HTTP/2) {}{}", address, PATH);
+
+    let handler = Arc::new(handler);
+    let tracker = ConnectionTracker::new(limits);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("DoH: error accepting connection: {}", e);
+                continue;
+            }
+        };
+        let connection = match tracker.admit(&stream) {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("DoH: refused connection: {:#}", e);
+                continue;
+            }
+        };
+
+        let (config, handler) = (config.clone(), handler.clone());
+        thread::spawn(move || {
+            let connected = handle_connection(stream, config, connection, proxy_protocol, handler);
+            if let Err(e) = connected {
+                eprintln!("DoH: error handling connection: {:#}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_connection<F>(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+    connection: Connection,
+    proxy_protocol: bool,
+    handler: Arc<F>,
+) -> Result<()>
+where
+    F: Fn(&Request) -> Result<DnsPacket> + Send + Sync + 'static,
+{
+    let client = match proxy_protocol {
+        true => proxy_protocol::read_client(&stream, connection.message_timeout())?,
+        false => stream.peer_addr()?,
+    };
+    let (tls, socket) = dot::accept(stream, config, connection.message_timeout())?.into_parts();
+    if tls.alpn_protocol() != Some(b"h2") {
+        anyhow::bail!("{} didn't negotiate HTTP/2", client);
+    }
+
+    http2::serve_connection(socket, tls, Arc::new(connection), move |request| {
+        answer(request, client, handler.as_ref())
+    })
+}
+
+fn answer(
+    request: &http2::Request,
+    client: SocketAddr,
+    handler: &impl Fn(&Request) -> Result<DnsPacket>,
+) -> http2::Response {
+    #[cfg(feature = "json")]
+    if let Some(response) = answer_json(request, client, handler) {
+        return response;
+    }
+    let message = match query_message(request) {
+        Ok(message) => message,
+        Err(response) => return response,
+    };
+    let query = match DnsPacket::parse(&message) {
+        Ok(query) => query,
+        Err(e) => {
+            eprintln!("DoH: malformed query from {}: {}", client, e);
+            return http2::Response::new(400);
+        }
+    };
+    if log::is_text() {
+        println!("<<< Received DNS packet (DoH from {}):\n{}", client, query);
+    }
+    let mut span = trace::query_span("https", &query, Some(client));
+    let query_log = log::query("https");
+
+    let request = Request::new(query, Some(client));
+    let response = match span.record(handler(&request)) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("DoH: failed to answer {}: {:#}", client, e);
+            response_builder(&request.query)
+                .rescode(ResponseCode::SERVFAIL)
+                .build()
+        }
+    };
+    span.response(&response);
+    query_log.finish(Some(client), &response);
+    if log::is_text() {
+        println!(">>> Sent DNS packet (DoH to {}):\n{}", client, response);
+    }
+
+    let _span = trace::span("dns.serialize");
+    let max_age = max_age(&response);
+    http2::Response::new(200)
+        .with_header("content-type", CONTENT_TYPE)
+        .with_header("cache-control", format!("max-age={}", max_age))
+        .with_body(BytesPacket::from(response).buf.to_vec())
+}
+
+/// Response to a request of the JSON API, `None` if it's for another path
+#[cfg(feature = "json")]
+fn answer_json(
+    request: &http2::Request,
+    client: SocketAddr,
+    handler: &impl Fn(&Request) -> Result<DnsPacket>,
+) -> Option<http2::Response> {
+    let target = request.header(":path").unwrap_or_default();
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+    if path != crate::doh::PATH {
+        return None;
+    }
+    if request.header(":method") != Some("GET") {
+        return Some(http2::Response::new(405).with_header("allow", "GET"));
+    }
+    let (status, body) = crate::doh::answer(target, Some(client), handler);
+    Some(
+        http2::Response::new(status)
+            .with_header("content-type", crate::doh::CONTENT_TYPE)
+            .with_body(body.into_bytes()),
+    )
+}
+
+/// Wire format query of the request, or the error response to it
+fn query_message(request: &http2::Request) -> Result<Vec<u8>, http2::Response> {
+    let target = request.header(":path").unwrap_or_default();
+    let (path, parameters) = target.split_once('?').unwrap_or((target, ""));
+    if path != PATH {
+        return Err(http2::Response::new(404));
+    }
+
+    match request.header(":method") {
+        Some("GET") => parameters
+            .split('&')
+            .find_map(|parameter| parameter.strip_prefix("dns="))
+            .and_then(base64::decode)
+            .ok_or_else(|| http2::Response::new(400)),
+        Some("POST") if request.header("content-type") == Some(CONTENT_TYPE) => {
+            Ok(request.body.clone())
+        }
+        Some("POST") => Err(http2::Response::new(415)),
+        _ => Err(http2::Response::new(405).with_header("allow", "GET, POST")),
+    }
+}
+
+/// How long HTTP caches may keep the response, as long as its shortest lived record
+/// (RFC 8484 section 5.1)
+fn max_age(response: &DnsPacket) -> u32 {
+    response
+        .answers
+        .iter()
+        .chain(&response.authorities)
+        .chain(&response.additionals)
+        .map(|record| record.negative_ttl().unwrap_or(record.ttl))
+        .min()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain_name::DomainName;
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+
+    fn request(method: &str, path: &str, content_type: &str, body: &[u8]) -> http2::Request {
+        http2::Request {
+            headers: vec![
+                (":method".to_string(), method.to_string()),
+                (":path".to_string(), path.to_string()),
+                ("content-type".to_string(), content_type.to_string()),
+            ],
+            body: body.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_query_message_by_get_and_post() {
+        // example.com A from RFC 8484 section 4.1.1
+        let dns = "AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB";
+        let query = query_message(&request("GET", &format!("/dns-query?dns={}", dns), "", &[]));
+        let query = query.unwrap();
+        assert!(DnsPacket::parse(&query).is_ok());
+
+        let posted = request("POST", "/dns-query", CONTENT_TYPE, &query);
+        assert_eq!(query_message(&posted).unwrap(), query);
+
+        let status = |request| query_message(&request).unwrap_err().status;
+        assert_eq!(
+            status(request("POST", "/dns-query", "text/plain", &query)),
+            415
+        );
+        assert_eq!(status(request("GET", "/dns-query?name=a", "", &[])), 400);
+        assert_eq!(status(request("GET", "/resolve", "", &[])), 404);
+        assert_eq!(status(request("PUT", "/dns-query", "", &[])), 405);
+    }
+
+    #[test]
+    fn test_failed_queries_are_answered_with_servfail() {
+        let failing = |_: &Request| -> Result<DnsPacket> { anyhow::bail!("upstream unreachable") };
+        let client = SocketAddr::from(([192, 0, 2, 1], 443));
+        let query = DnsPacket::builder()
+            .id(7)
+            .question(DnsQuestion::new(
+                DomainName::from("example.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+
+        let posted = request("POST", PATH, CONTENT_TYPE, &BytesPacket::from(query).buf);
+        let response = answer(&posted, client, &failing);
+        assert_eq!(response.status, 200);
+        let answered = DnsPacket::parse(&response.body).unwrap();
+        assert_eq!(answered.header.id, 7);
+        assert_eq!(answered.header.rescode, ResponseCode::SERVFAIL);
+
+        let malformed = request("POST", PATH, CONTENT_TYPE, b"not a DNS message");
+        assert_eq!(answer(&malformed, client, &failing).status, 400);
+    }
+
+    #[test]
+    fn test_proxy_header_is_required() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let connection = ConnectionTracker::new(ConnectionLimits::default())
+            .admit(&server)
+            .unwrap();
+        std::io::Write::write_all(&mut client, b"\x16\x03\x01 a ClientHello").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let handler = |_: &Request| -> Result<DnsPacket> { unreachable!("no query is sent") };
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(rustls::server::ResolvesServerCertUsingSni::new()));
+        let error = handle_connection(
+            server,
+            Arc::new(config),
+            connection,
+            true,
+            Arc::new(handler),
+        )
+        .unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("invalid PROXY protocol header"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_api_is_served_too() {
+        use crate::handler::{Pipeline, StaticAnswerHandler};
+
+        let pipeline = Pipeline::new().with(StaticAnswerHandler::default());
+        let handler = |request: &Request| pipeline.handle(request);
+        let client = SocketAddr::from(([192, 0, 2, 1], 443));
+
+        let resolved = request("GET", "/resolve?name=example.com", "", &[]);
+        let response = answer(&resolved, client, &handler);
+        assert_eq!(response.status, 200);
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.contains("\"Status\":0"), "{}", body);
+
+        let posted = request("POST", "/resolve?name=example.com", "", &[]);
+        assert_eq!(answer(&posted, client, &handler).status, 405);
+        let nameless = request("GET", "/resolve?type=A", "", &[]);
+        assert_eq!(answer(&nameless, client, &handler).status, 400);
+    }
+}
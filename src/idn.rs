@@ -0,0 +1,237 @@
+//! Internationalized domain names - conversion between U-labels and A-labels (xn--...)
+//!
+//! Punycode: https://www.rfc-editor.org/rfc/rfc3492
+//!
+//! Only lowercasing is applied to Unicode labels, full IDNA2008/UTS #46 mapping is not performed.
+
+pub const ACE_PREFIX: &str = "xn--";
+
+/// Dots separating labels of Unicode names (UTS #46 section 2.3)
+pub const LABEL_SEPARATORS: [char; 4] = ['.', '\u{3002}', '\u{FF0E}', '\u{FF61}'];
+
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+/// Converts Unicode label to A-label, ASCII labels are returned lowercased and unchanged otherwise
+pub fn label_to_ascii(label: &str) -> Option<String> {
+    if label.is_ascii() {
+        return Some(label.to_ascii_lowercase());
+    }
+
+    let label = label.to_lowercase();
+    let encoded = encode(&label.chars().collect::<Vec<_>>())?;
+
+    Some(format!("{ACE_PREFIX}{encoded}"))
+}
+
+/// Converts A-label to Unicode label, returns `None` if the label is not a valid A-label
+///
+/// Labels whose Unicode form doesn't convert back to them, or would show control, invisible
+/// or bidirectional formatting characters, separators or backslashes, are not valid either,
+/// so crafted labels can't forge names or lines of logs.
+pub fn label_to_unicode(label: &[u8]) -> Option<String> {
+    let label = std::str::from_utf8(label).ok()?;
+    let prefix = label.get(..ACE_PREFIX.len())?;
+
+    if !prefix.eq_ignore_ascii_case(ACE_PREFIX) {
+        return None;
+    }
+
+    let unicode = decode(&label[ACE_PREFIX.len()..].to_ascii_lowercase())?;
+    let displayable = !unicode.is_ascii()
+        && !unicode
+            .chars()
+            .any(|c| c.is_control() || c.is_whitespace() || is_hidden(c) || c == '\\')
+        && !unicode.contains(LABEL_SEPARATORS);
+    let round_trip =
+        label_to_ascii(&unicode).is_some_and(|ascii| ascii.eq_ignore_ascii_case(label));
+    (displayable && round_trip).then_some(unicode)
+}
+
+/// Zero width and bidirectional formatting characters
+fn is_hidden(c: char) -> bool {
+    matches!(c, '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}')
+}
+
+fn adapt(mut delta: u32, num_points: u32, first_time: bool) -> u32 {
+    delta = if first_time { delta / DAMP } else { delta / 2 };
+    delta += delta / num_points;
+
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+
+    k + (((BASE - TMIN + 1) * delta) / (delta + SKEW))
+}
+
+fn threshold(k: u32, bias: u32) -> u32 {
+    if k <= bias {
+        TMIN
+    } else if k >= bias + TMAX {
+        TMAX
+    } else {
+        k - bias
+    }
+}
+
+fn encode_digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    }
+}
+
+fn decode_digit(c: char) -> Option<u32> {
+    match c {
+        'a'..='z' => Some(c as u32 - 'a' as u32),
+        'A'..='Z' => Some(c as u32 - 'A' as u32),
+        '0'..='9' => Some(c as u32 - '0' as u32 + 26),
+        _ => None,
+    }
+}
+
+fn encode(input: &[char]) -> Option<String> {
+    let mut output: String = input.iter().filter(|c| c.is_ascii()).collect();
+
+    let basic = output.len() as u32;
+    let mut handled = basic;
+    if basic > 0 {
+        output.push('-');
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+
+    while (handled as usize) < input.len() {
+        let m = input.iter().map(|&c| c as u32).filter(|&c| c >= n).min()?;
+
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+
+        for &c in input {
+            let c = c as u32;
+
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = threshold(k, bias);
+                    if q < t {
+                        break;
+                    }
+                    output.push(encode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(encode_digit(q));
+
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+
+        delta = delta.checked_add(1)?;
+        n += 1;
+    }
+
+    Some(output)
+}
+
+fn decode(input: &str) -> Option<String> {
+    let (basic, extended) = match input.rfind('-') {
+        Some(i) => (&input[..i], &input[i + 1..]),
+        None => ("", input),
+    };
+
+    if !basic.is_ascii() {
+        return None;
+    }
+
+    let mut output: Vec<char> = basic.chars().collect();
+
+    let mut n = INITIAL_N;
+    let mut i: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut chars = extended.chars().peekable();
+
+    while chars.peek().is_some() {
+        let old_i = i;
+        let mut w: u32 = 1;
+        let mut k = BASE;
+        loop {
+            let digit = decode_digit(chars.next()?)?;
+            i = i.checked_add(digit.checked_mul(w)?)?;
+
+            let t = threshold(k, bias);
+            if digit < t {
+                break;
+            }
+            w = w.checked_mul(BASE - t)?;
+            k += BASE;
+        }
+
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - old_i, len, old_i == 0);
+        n = n.checked_add(i / len)?;
+        i %= len;
+
+        output.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+
+    Some(output.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_punycode_labels() {
+        for (unicode, ascii) in [
+            ("bücher", "xn--bcher-kva"),
+            ("münchen", "xn--mnchen-3ya"),
+            ("例え", "xn--r8jz45g"),
+        ] {
+            assert_eq!(label_to_ascii(unicode).as_deref(), Some(ascii));
+            assert_eq!(label_to_unicode(ascii.as_bytes()).as_deref(), Some(unicode));
+        }
+
+        assert_eq!(label_to_ascii("Example").as_deref(), Some("example"));
+        assert_eq!(label_to_unicode(b"example"), None);
+    }
+
+    #[test]
+    fn test_unsafe_labels_are_not_decoded() {
+        let crafted = |unicode: &str| {
+            let encoded = encode(&unicode.chars().collect::<Vec<_>>()).unwrap();
+            format!("{ACE_PREFIX}{encoded}")
+        };
+
+        for unicode in ["ä\nb", "ä.b", "ä\u{202E}b", "ä\\b", "Äb", "ab"] {
+            assert_eq!(
+                label_to_unicode(crafted(unicode).as_bytes()),
+                None,
+                "{:?}",
+                unicode
+            );
+        }
+        assert_eq!(
+            label_to_unicode(crafted("äb").as_bytes()).as_deref(),
+            Some("äb")
+        );
+    }
+}
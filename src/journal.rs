@@ -0,0 +1,246 @@
+//! Changes between versions of a zone, for incremental zone transfers (IXFR, RFC 1995)
+//!
+//! A [`ZoneDiff`] lists the records removed from and added to the zone, `diff-zone` prints
+//! it by RRset. Zones replaced while being served (reloaded from a changed file, signed
+//! again) keep the last [`JOURNAL_LENGTH`] diffs, so secondaries get just the changes since
+//! the serial they have, see [`Zone::next_version`].
+
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::domain_name::DomainName;
+use crate::record::{DnsRecord, RecordData, RecordType};
+use crate::zone::Zone;
+
+/// Most versions of a zone kept in its journal
+pub const JOURNAL_LENGTH: usize = 32;
+
+/// Records removed from and added to a zone between two of its versions
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneDiff {
+    pub old_soa: DnsRecord,
+    pub new_soa: DnsRecord,
+    pub removed: Vec<DnsRecord>,
+    pub added: Vec<DnsRecord>,
+}
+
+impl ZoneDiff {
+    /// Changes from `old` to `new` version of the same zone, records with changed TTL are
+    /// removed and added again
+    pub fn between(old: &Zone, new: &Zone) -> Result<Self> {
+        if !old.origin().eq_ignore_case(new.origin()) {
+            anyhow::bail!(
+                "zones {} and {} are not versions of the same zone",
+                old.origin(),
+                new.origin()
+            );
+        }
+        let soa = |zone: &Zone| zone.soa().expect("zone has SOA").clone();
+        let content = |zone: &Zone| -> Vec<DnsRecord> {
+            zone.records()
+                .iter()
+                .filter(|record| record.record_type != RecordType::SOA)
+                .cloned()
+                .collect()
+        };
+        let (old_records, new_records) = (content(old), content(new));
+
+        Ok(Self {
+            old_soa: soa(old),
+            new_soa: soa(new),
+            removed: old_records
+                .iter()
+                .filter(|record| !new_records.contains(record))
+                .cloned()
+                .collect(),
+            added: new_records
+                .iter()
+                .filter(|record| !old_records.contains(record))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// Returns true if the versions differ in nothing but the SOA
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty()
+    }
+
+    pub fn old_serial(&self) -> u32 {
+        serial(&self.old_soa)
+    }
+
+    pub fn new_serial(&self) -> u32 {
+        serial(&self.new_soa)
+    }
+
+    /// Changes grouped by RRset, in the order of the first change of each
+    pub fn rrsets(&self) -> Vec<RrsetChange> {
+        let mut changes: Vec<RrsetChange> = Vec::new();
+        let all = self
+            .removed
+            .iter()
+            .map(|record| (record, true))
+            .chain(self.added.iter().map(|record| (record, false)));
+        for (record, removed) in all {
+            let change = match changes.iter_mut().find(|change| {
+                change.owner.eq_ignore_case(&record.domain_name)
+                    && change.record_type == record.record_type
+            }) {
+                Some(change) => change,
+                None => {
+                    changes.push(RrsetChange {
+                        owner: record.domain_name.clone(),
+                        record_type: record.record_type.clone(),
+                        removed: Vec::new(),
+                        added: Vec::new(),
+                    });
+                    changes.last_mut().expect("change was just added")
+                }
+            };
+            if removed {
+                change.removed.push(record.clone());
+            } else {
+                change.added.push(record.clone());
+            }
+        }
+        changes
+    }
+
+    /// Records of the diff in IXFR format: old SOA, removed records, new SOA, added records
+    pub fn ixfr_records(&self) -> impl Iterator<Item = &DnsRecord> {
+        std::iter::once(&self.old_soa)
+            .chain(self.removed.iter())
+            .chain(std::iter::once(&self.new_soa))
+            .chain(self.added.iter())
+    }
+}
+
+/// `serial 1 -> 2` with one line per changed RRset
+impl fmt::Display for ZoneDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "serial {} -> {}", self.old_serial(), self.new_serial())?;
+        for change in self.rrsets() {
+            write!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
+
+/// Changes of the records of one owner and type
+#[derive(Debug, Clone, PartialEq)]
+pub struct RrsetChange {
+    pub owner: DomainName,
+    pub record_type: RecordType,
+    pub removed: Vec<DnsRecord>,
+    pub added: Vec<DnsRecord>,
+}
+
+/// Kind of change on the first line, then removed records prefixed with `-` and added with `+`
+impl fmt::Display for RrsetChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match (self.removed.is_empty(), self.added.is_empty()) {
+            (true, _) => "added",
+            (_, true) => "removed",
+            _ => "changed",
+        };
+        writeln!(f, "{} {} {}", kind, self.owner, self.record_type)?;
+        for record in self.removed.iter() {
+            writeln!(f, "- {}", record)?;
+        }
+        for record in self.added.iter() {
+            writeln!(f, "+ {}", record)?;
+        }
+        Ok(())
+    }
+}
+
+fn serial(soa: &DnsRecord) -> u32 {
+    match &soa.data {
+        RecordData::Soa(soa) => soa.serial,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = "$ORIGIN example.
+@       IN  SOA  ns1 hostmaster ( 1 7200 900 1209600 300 )
+        IN  NS   ns1
+ns1     IN  A    192.0.2.1
+www     IN  A    192.0.2.10
+www     IN  A    192.0.2.11
+old     IN  A    192.0.2.20
+";
+
+    const NEW: &str = "$ORIGIN example.
+@       IN  SOA  ns1 hostmaster ( 2 7200 900 1209600 300 )
+        IN  NS   ns1
+ns1     IN  A    192.0.2.1
+www     IN  A    192.0.2.10
+www     IN  A    192.0.2.12
+new     IN  AAAA 2001:db8::1
+";
+
+    #[test]
+    fn test_rrset_changes() {
+        let old = Zone::parse(OLD).unwrap();
+        let new = Zone::parse(NEW).unwrap();
+        let diff = ZoneDiff::between(&old, &new).unwrap();
+
+        assert_eq!((diff.old_serial(), diff.new_serial()), (1, 2));
+        let changes: Vec<(String, usize, usize)> = diff
+            .rrsets()
+            .iter()
+            .map(|change| {
+                (
+                    format!("{} {}", change.owner, change.record_type),
+                    change.removed.len(),
+                    change.added.len(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("www.example. A".to_string(), 1, 1),
+                ("old.example. A".to_string(), 1, 0),
+                ("new.example. AAAA".to_string(), 0, 1),
+            ]
+        );
+        assert_eq!(diff.ixfr_records().count(), 2 + 2 + 2);
+
+        assert!(ZoneDiff::between(&old, &old).unwrap().is_empty());
+        let other = Zone::parse(&OLD.replace("example.", "example.org.")).unwrap();
+        assert!(ZoneDiff::between(&old, &other).is_err());
+    }
+
+    #[test]
+    fn test_journal() {
+        let old = Zone::parse(OLD).unwrap();
+        let new = old.next_version(Zone::parse(NEW).unwrap());
+        let newer = new.next_version(Zone::parse(&NEW.replace("( 2 ", "( 3 ")).unwrap());
+
+        let serials = |diffs: &[ZoneDiff]| -> Vec<(u32, u32)> {
+            diffs
+                .iter()
+                .map(|diff| (diff.old_serial(), diff.new_serial()))
+                .collect()
+        };
+        assert_eq!(
+            serials(newer.changes_since(1).unwrap()),
+            vec![(1, 2), (2, 3)]
+        );
+        assert_eq!(serials(newer.changes_since(2).unwrap()), vec![(2, 3)]);
+        assert!(newer.changes_since(0).is_none());
+
+        // changes without a new serial can't be transferred incrementally
+        let unnumbered =
+            newer.next_version(Zone::parse(OLD.replace("( 1 ", "( 3 ").as_str()).unwrap());
+        assert!(unnumbered.changes_since(1).is_none());
+        assert!(unnumbered.journal().is_empty());
+    }
+}
@@ -0,0 +1,426 @@
+/*
+Representing DNS Messages in JSON
+https://www.rfc-editor.org/rfc/rfc8427
+
+Only the members needed to describe messages this server understands are
+supported. Names are written in presentation format, RDATA of known types
+as `rdata<TYPE>` members, everything else as `RDATAHEX`. The OPT
+pseudo-record is written apart from the additional section as `OPT`, with
+its fields decoded, since its CLASS and TTL aren't a class and a TTL.
+*/
+
+use std::net::Ipv4Addr;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::domain_name::{DomainName, LookupTable};
+use crate::edns::{EdnsOption, OptRecord};
+use crate::header::{DnsHeader, ResponseCode};
+use crate::packet::DnsPacket;
+use crate::question::{DnsQuestion, QueryClass, QueryType};
+use crate::record::{DnsRecord, RecordClass, RecordData, RecordType};
+
+/// DNS message object (RFC 8427 section 2.1)
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct DnsJson {
+    pub id: u16,
+    pub qr: u8,
+    #[serde(rename = "Opcode")]
+    pub opcode: u8,
+    pub aa: u8,
+    pub tc: u8,
+    pub rd: u8,
+    pub ra: u8,
+    pub ad: u8,
+    pub cd: u8,
+    pub rcode: u8,
+    pub qdcount: u16,
+    pub ancount: u16,
+    pub nscount: u16,
+    pub arcount: u16,
+
+    /// First question, shortcut for the most common case of a single question
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qtype: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qclass: Option<u16>,
+
+    #[serde(rename = "questionRRs", default)]
+    pub question_rrs: Vec<QuestionJson>,
+    #[serde(rename = "answerRRs", default)]
+    pub answer_rrs: Vec<RecordJson>,
+    #[serde(
+        rename = "authorityRRs",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub authority_rrs: Vec<RecordJson>,
+    #[serde(
+        rename = "additionalRRs",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub additional_rrs: Vec<RecordJson>,
+    /// EDNS pseudo-record, counted in ARCOUNT but not in `additionalRRs`
+    #[serde(rename = "OPT", default, skip_serializing_if = "Option::is_none")]
+    pub opt: Option<OptJson>,
+}
+
+/// Question object (RFC 8427 section 2.2)
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct QuestionJson {
+    pub name: String,
+    #[serde(rename = "TYPE")]
+    pub query_type: u16,
+    pub class: u16,
+}
+
+/// Resource record object (RFC 8427 section 2.2)
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct RecordJson {
+    pub name: String,
+    #[serde(rename = "TYPE")]
+    pub record_type: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdlength: u16,
+    #[serde(rename = "rdataA", skip_serializing_if = "Option::is_none")]
+    pub rdata_a: Option<String>,
+    #[serde(rename = "RDATAHEX", skip_serializing_if = "Option::is_none")]
+    pub rdata_hex: Option<String>,
+}
+
+/// OPT pseudo-record (RFC 6891 section 6.1.2)
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct OptJson {
+    #[serde(rename = "UDPPAYLOADSIZE")]
+    pub udp_payload_size: u16,
+    #[serde(rename = "EXTENDEDRCODE", default)]
+    pub extended_rcode: u8,
+    #[serde(default)]
+    pub version: u8,
+    #[serde(rename = "DO", default)]
+    pub dnssec_ok: u8,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<OptionJson>,
+}
+
+/// Single EDNS option, its data as hex like `RDATAHEX`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub struct OptionJson {
+    pub code: u16,
+    #[serde(rename = "DATAHEX")]
+    pub data_hex: String,
+}
+
+impl From<&DnsPacket> for DnsJson {
+    fn from(packet: &DnsPacket) -> Self {
+        let header = &packet.header;
+
+        let question_rrs: Vec<QuestionJson> = packet
+            .questions
+            .iter()
+            .map(|q| QuestionJson {
+                name: q.domain_name.to_string(),
+                query_type: q.query_type.clone().into(),
+                class: q.class.clone().into(),
+            })
+            .collect();
+
+        let answer_rrs = packet.answers.iter().map(RecordJson::from).collect();
+        let authority_rrs = packet.authorities.iter().map(RecordJson::from).collect();
+        let additional_rrs = packet.additionals.iter().map(RecordJson::from).collect();
+
+        let first = question_rrs.first();
+
+        Self {
+            id: header.id,
+            qr: header.response as u8,
+            opcode: header.opcode,
+            aa: header.authoritative_answer as u8,
+            tc: header.truncated_message as u8,
+            rd: header.recursion_desired as u8,
+            ra: header.recursion_available as u8,
+            ad: header.authed_data as u8,
+            cd: header.checking_disabled as u8,
+            rcode: header.rescode as u8,
+            qdcount: header.question_entries,
+            ancount: header.answer_entries,
+            nscount: header.authoritative_entries,
+            arcount: header.additional_entries,
+            qname: first.map(|q| q.name.clone()),
+            qtype: first.map(|q| q.query_type),
+            qclass: first.map(|q| q.class),
+            question_rrs,
+            answer_rrs,
+            authority_rrs,
+            additional_rrs,
+            opt: packet.opt.as_ref().map(OptJson::from),
+        }
+    }
+}
+
+impl From<&OptRecord> for OptJson {
+    fn from(opt: &OptRecord) -> Self {
+        Self {
+            udp_payload_size: opt.udp_payload_size,
+            extended_rcode: opt.extended_rcode,
+            version: opt.version,
+            dnssec_ok: opt.dnssec_ok as u8,
+            options: opt
+                .options
+                .iter()
+                .map(|option| OptionJson {
+                    code: option.code,
+                    data_hex: encode_hex(&option.data),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<OptJson> for OptRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(opt: OptJson) -> Result<Self, Self::Error> {
+        let options = opt
+            .options
+            .into_iter()
+            .map(|option| {
+                Ok(EdnsOption {
+                    code: option.code,
+                    data: decode_hex(&option.data_hex)?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            udp_payload_size: opt.udp_payload_size,
+            extended_rcode: opt.extended_rcode,
+            version: opt.version,
+            dnssec_ok: opt.dnssec_ok != 0,
+            options,
+        })
+    }
+}
+
+impl From<&DnsRecord> for RecordJson {
+    fn from(record: &DnsRecord) -> Self {
+        let (rdata_a, rdata_hex) = match &record.data {
+            RecordData::A(address) => (Some(address.to_string()), None),
+            data => (None, Some(encode_hex(&data.to_bytes()))),
+        };
+
+        Self {
+            name: record.domain_name.to_string(),
+            record_type: record.record_type.clone().into(),
+            class: record.class.clone().into(),
+            ttl: record.ttl,
+            rdlength: record.data.to_bytes().len() as u16,
+            rdata_a,
+            rdata_hex,
+        }
+    }
+}
+
+impl TryFrom<RecordJson> for DnsRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(r: RecordJson) -> Result<Self, Self::Error> {
+        let record_type = RecordType::from(r.record_type);
+        let data = match (r.rdata_a, r.rdata_hex) {
+            (Some(a), _) => RecordData::A(
+                a.parse::<Ipv4Addr>()
+                    .with_context(|| format!("invalid rdataA {:?}", a))?,
+            ),
+            (None, Some(hex)) => {
+                let octets = decode_hex(&hex)?;
+                RecordData::from_bytes(
+                    &record_type,
+                    octets.len(),
+                    &mut &octets[..],
+                    &mut LookupTable::new(octets.len()),
+                )
+                .with_context(|| format!("invalid RDATAHEX {:?}", hex))?
+            }
+            (None, None) => anyhow::bail!("record {:?} has no RDATA", r.name),
+        };
+
+        Ok(DnsRecord::new(
+            DomainName::from(r.name),
+            record_type,
+            RecordClass::from(r.class),
+            r.ttl,
+            data,
+        ))
+    }
+}
+
+impl TryFrom<DnsJson> for DnsPacket {
+    type Error = anyhow::Error;
+
+    fn try_from(json: DnsJson) -> Result<Self, Self::Error> {
+        let mut header = DnsHeader::new();
+        header.id = json.id;
+        header.response = json.qr != 0;
+        header.opcode = json.opcode & 0x0F;
+        header.authoritative_answer = json.aa != 0;
+        header.truncated_message = json.tc != 0;
+        header.recursion_desired = json.rd != 0;
+        header.recursion_available = json.ra != 0;
+        header.authed_data = json.ad != 0;
+        header.checking_disabled = json.cd != 0;
+        header.rescode = ResponseCode::from(json.rcode);
+        header.question_entries = json.qdcount;
+        header.answer_entries = json.ancount;
+        header.authoritative_entries = json.nscount;
+        header.additional_entries = json.arcount;
+
+        let mut questions: Vec<DnsQuestion> = json
+            .question_rrs
+            .into_iter()
+            .map(|q| {
+                DnsQuestion::new(
+                    DomainName::from(q.name),
+                    QueryType::from(q.query_type),
+                    QueryClass::from(q.class),
+                )
+            })
+            .collect();
+
+        if questions.is_empty() {
+            if let Some(qname) = json.qname {
+                questions.push(DnsQuestion::new(
+                    DomainName::from(qname),
+                    QueryType::from(json.qtype.unwrap_or(1)),
+                    QueryClass::from(json.qclass.unwrap_or(1)),
+                ));
+            }
+        }
+
+        let answers = json
+            .answer_rrs
+            .into_iter()
+            .map(DnsRecord::try_from)
+            .collect::<Result<_, _>>()?;
+        let authorities = json
+            .authority_rrs
+            .into_iter()
+            .map(DnsRecord::try_from)
+            .collect::<Result<_, _>>()?;
+        let additionals = json
+            .additional_rrs
+            .into_iter()
+            .map(DnsRecord::try_from)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            header,
+            questions,
+            answers,
+            authorities,
+            additionals,
+            opt: json.opt.map(OptRecord::try_from).transpose()?,
+        })
+    }
+}
+
+impl DnsPacket {
+    /// Serializes packet to DNS-in-JSON (RFC 8427)
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&DnsJson::from(self)).expect("DNS-in-JSON is always serializable")
+    }
+
+    /// Parses packet from DNS-in-JSON (RFC 8427)
+    pub fn from_json(s: &str) -> anyhow::Result<Self> {
+        let json: DnsJson = serde_json::from_str(s).context("invalid DNS-in-JSON message")?;
+        Self::try_from(json)
+    }
+}
+
+fn encode_hex(octets: &[u8]) -> String {
+    octets.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| anyhow::anyhow!("invalid hex string {:?}", hex))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dns_packet_to_json_and_back() {
+        let mut dns_packet = DnsPacket::new();
+        dns_packet.header.id = 19678;
+        dns_packet.header.response = true;
+        dns_packet.header.question_entries = 1;
+        dns_packet.header.answer_entries = 1;
+        dns_packet.questions.push(DnsQuestion::new(
+            DomainName::from("example.com."),
+            QueryType::A,
+            QueryClass::IN,
+        ));
+        dns_packet.answers.push(DnsRecord::new(
+            DomainName::from("example.com."),
+            RecordType::A,
+            RecordClass::IN,
+            300,
+            Ipv4Addr::new(192, 0, 2, 1),
+        ));
+
+        let json = dns_packet.to_json();
+        assert!(json.contains(r#""QNAME":"example.com.""#));
+        assert!(json.contains(r#""rdataA":"192.0.2.1""#));
+
+        assert_eq!(DnsPacket::from_json(&json).unwrap(), dns_packet);
+    }
+
+    #[test]
+    fn test_edns_query_to_json_and_back() {
+        let mut opt = OptRecord::new(1232);
+        opt.dnssec_ok = true;
+        opt.options.push(EdnsOption {
+            code: crate::edns::OPTION_COOKIE,
+            data: vec![0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF],
+        });
+
+        let mut dns_packet = DnsPacket::new();
+        dns_packet.header.id = 4242;
+        dns_packet.header.recursion_desired = true;
+        dns_packet.header.question_entries = 1;
+        dns_packet.header.additional_entries = 1;
+        dns_packet.questions.push(DnsQuestion::new(
+            DomainName::from("example.com."),
+            QueryType::A,
+            QueryClass::IN,
+        ));
+        dns_packet.opt = Some(opt);
+
+        let json = dns_packet.to_json();
+        assert!(json.contains(r#""ARCOUNT":1"#));
+        assert!(!json.contains("additionalRRs"));
+        assert!(json.contains(r#""UDPPAYLOADSIZE":1232"#));
+        assert!(json.contains(r#""DATAHEX":"0123456789ABCDEF""#));
+
+        assert_eq!(DnsPacket::from_json(&json).unwrap(), dns_packet);
+    }
+}
@@ -0,0 +1,264 @@
+//! DHCP lease files
+//!
+//! Two formats are understood, detected from the content:
+//!
+//! - dnsmasq, one lease per line: `<expiry> <mac> <address> <hostname> <client-id>`,
+//!   expiry 0 means infinite lease, hostname `*` unknown one
+//! - Kea memfile CSV with a header line (`address,hwaddr,...,expire,...,hostname,state,...`),
+//!   the file is append-only, so later lines override earlier ones for the same address
+//!
+//! ```text
+//! 1700000000 aa:bb:cc:dd:ee:ff 192.168.1.23 laptop 01:aa:bb:cc:dd:ee:ff
+//! ```
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+/// Kea lease state of an active lease (others are declined or expired)
+const KEA_STATE_DEFAULT: &str = "0";
+
+/// How often the leases file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Address leased to a host
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    /// Host name sent by the client (single label, lowercase)
+    pub hostname: String,
+    pub address: IpAddr,
+    /// Expiration as seconds since UNIX epoch, `None` for infinite leases
+    pub expires: Option<u64>,
+}
+
+impl Lease {
+    /// Returns true if the lease is valid at `now` (seconds since UNIX epoch)
+    pub fn is_active(&self, now: u64) -> bool {
+        !matches!(self.expires, Some(expires) if expires <= now)
+    }
+}
+
+/// Provider of host name to address mappings with limited lifetime
+pub trait LeaseSource: Send + Sync {
+    /// Leases valid right now
+    fn active_leases(&self) -> Vec<Lease>;
+}
+
+impl<T: LeaseSource> LeaseSource for Arc<T> {
+    fn active_leases(&self) -> Vec<Lease> {
+        (**self).active_leases()
+    }
+}
+
+/// Leases file of a DHCP server, re-read when it changes
+///
+/// The file is checked for changes at most every [`RELOAD_INTERVAL`], so newly
+/// connected hosts show up within seconds.
+pub struct LeaseFile {
+    path: PathBuf,
+    state: Mutex<LeaseFileState>,
+}
+
+/// Leases loaded from the last version of the file
+struct LeaseFileState {
+    leases: Vec<Lease>,
+    modified: SystemTime,
+    checked: Instant,
+}
+
+impl LeaseFile {
+    /// Loads leases from `path` (dnsmasq or Kea format)
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let state = Mutex::new(LeaseFileState::load(&path)?);
+        Ok(Self { path, state })
+    }
+}
+
+impl LeaseFileState {
+    fn load(path: &Path) -> Result<Self> {
+        let modified = std::fs::metadata(path)
+            .and_then(|m| m.modified())
+            .with_context(|| format!("failed to read leases file {}", path.display()))?;
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read leases file {}", path.display()))?;
+        let leases =
+            parse(&text).with_context(|| format!("invalid leases file {}", path.display()))?;
+
+        Ok(Self {
+            leases,
+            modified,
+            checked: Instant::now(),
+        })
+    }
+}
+
+impl LeaseSource for LeaseFile {
+    fn active_leases(&self) -> Vec<Lease> {
+        let mut state = self.state.lock().expect("lease file lock poisoned");
+
+        if state.checked.elapsed() >= RELOAD_INTERVAL {
+            state.checked = Instant::now();
+            let modified = std::fs::metadata(&self.path).and_then(|m| m.modified());
+            if modified.ok() != Some(state.modified) {
+                // broken or half-written file keeps the previous leases
+                match LeaseFileState::load(&self.path) {
+                    Ok(loaded) => *state = loaded,
+                    Err(e) => eprintln!("DHCP leases: {:#}", e),
+                }
+            }
+        }
+
+        let now = unix_time();
+        state
+            .leases
+            .iter()
+            .filter(|lease| lease.is_active(now))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Current time as seconds since UNIX epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Parses leases file in dnsmasq or Kea format, leases without usable host name are skipped
+pub fn parse(text: &str) -> Result<Vec<Lease>> {
+    match text.lines().next() {
+        Some(header) if header.starts_with("address,") => parse_kea(text),
+        _ => parse_dnsmasq(text),
+    }
+}
+
+fn parse_dnsmasq(text: &str) -> Result<Vec<Lease>> {
+    let mut leases = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields[..] {
+            [] | ["duid", ..] => continue, // DHCPv6 server identity
+            [expiry, _mac_or_iaid, address, hostname, ..] => {
+                let expiry: u64 = expiry
+                    .parse()
+                    .with_context(|| format!("line {}: invalid expiry {:?}", index + 1, expiry))?;
+                let address = address.parse().with_context(|| {
+                    format!("line {}: invalid address {:?}", index + 1, address)
+                })?;
+
+                leases.extend(hostname_label(hostname).map(|hostname| Lease {
+                    hostname,
+                    address,
+                    expires: (expiry != 0).then_some(expiry),
+                }));
+            }
+            _ => anyhow::bail!("line {}: too few fields", index + 1),
+        }
+    }
+
+    Ok(leases)
+}
+
+fn parse_kea(text: &str) -> Result<Vec<Lease>> {
+    let mut lines = text.lines().enumerate();
+    let (_, header) = lines.next().context("missing CSV header")?;
+    let columns: Vec<&str> = header.split(',').collect();
+    let column = |name: &str| {
+        columns
+            .iter()
+            .position(|column| *column == name)
+            .with_context(|| format!("missing column {}", name))
+    };
+    let (address_column, expire_column) = (column("address")?, column("expire")?);
+    let (hostname_column, state_column) = (column("hostname")?, column("state")?);
+
+    // later lines replace earlier ones, the lease is the last state of the address
+    let mut leases: HashMap<IpAddr, Option<Lease>> = HashMap::new();
+    let mut order = Vec::new();
+    for (index, line) in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').collect();
+        let field = |column: usize| {
+            fields
+                .get(column)
+                .copied()
+                .with_context(|| format!("line {}: too few fields", index + 1))
+        };
+
+        let address: IpAddr = field(address_column)?
+            .parse()
+            .with_context(|| format!("line {}: invalid address", index + 1))?;
+        let expires: u64 = field(expire_column)?
+            .parse()
+            .with_context(|| format!("line {}: invalid expire", index + 1))?;
+        let active = field(state_column)? == KEA_STATE_DEFAULT;
+
+        let lease = hostname_label(field(hostname_column)?)
+            .filter(|_| active)
+            .map(|hostname| Lease {
+                hostname,
+                address,
+                expires: Some(expires),
+            });
+        if leases.insert(address, lease).is_none() {
+            order.push(address);
+        }
+    }
+
+    Ok(order
+        .into_iter()
+        .filter_map(|address| leases.remove(&address).flatten())
+        .collect())
+}
+
+/// First label of the host name (lowercase), if it is a valid one (letters, digits and hyphens)
+pub fn hostname_label(hostname: &str) -> Option<String> {
+    let label = hostname.split('.').next().unwrap_or_default();
+    let valid = !label.is_empty()
+        && label.len() <= 63
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !label.starts_with('-')
+        && !label.ends_with('-');
+
+    valid.then(|| label.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lease_files() {
+        let dnsmasq = "1700000000 aa:bb:cc:dd:ee:ff 192.168.1.23 Laptop 01:aa:bb:cc:dd:ee:ff
+0 aa:bb:cc:dd:ee:00 192.168.1.24 printer *
+1700000000 aa:bb:cc:dd:ee:01 192.168.1.25 * *
+duid 00:01:00:01:2c:5e:4f:1a:aa:bb:cc:dd:ee:ff
+";
+        let leases = parse(dnsmasq).unwrap();
+        assert_eq!(leases.len(), 2);
+        assert_eq!(leases[0].hostname, "laptop");
+        assert_eq!(leases[0].expires, Some(1700000000));
+        assert_eq!(leases[1].expires, None);
+
+        let kea = "address,hwaddr,client_id,valid_lifetime,expire,subnet_id,fqdn_fwd,fqdn_rev,hostname,state,user_context
+192.168.1.23,aa:bb:cc:dd:ee:ff,,3600,1700000000,1,0,0,laptop.example.org.,0,
+192.168.1.24,aa:bb:cc:dd:ee:00,,3600,1700000000,1,0,0,printer,0,
+192.168.1.24,aa:bb:cc:dd:ee:00,,0,1700000100,1,0,0,printer,2,
+";
+        let leases = parse(kea).unwrap();
+        assert_eq!(leases.len(), 1);
+        assert_eq!(leases[0].hostname, "laptop");
+        assert_eq!(leases[0].address, "192.168.1.23".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,101 @@
+//! DNS message encoding and decoding
+//!
+//! Building blocks of the DNS server, usable on their own:
+//!
+//! - [`packet::DnsPacket`] - whole DNS message, converted from/to wire format via [`packet::BytesPacket`]
+//! - [`header::DnsHeader`], [`question::DnsQuestion`], [`record::DnsRecord`] - message sections
+//! - [`domain_name::DomainName`] - domain names with message compression support
+//! - `test_util` - packet generators, a corpus of messages and round-trip assertions
+//!   (`test_util` feature)
+//!
+//! ```
+//! use dns_starter_rust::domain_name::DomainName;
+//! use dns_starter_rust::packet::{BytesPacket, DnsPacket};
+//! use dns_starter_rust::question::{DnsQuestion, QueryClass, QueryType};
+//!
+//! let mut query = DnsPacket::new();
+//! query.header.id = 1234;
+//! query.add_question(DnsQuestion::new(
+//!     DomainName::from("codecrafters.io."),
+//!     QueryType::A,
+//!     QueryClass::IN,
+//! ));
+//!
+//! let bytes_packet = BytesPacket::from(query.clone());
+//! assert_eq!(DnsPacket::parse(&bytes_packet.buf).unwrap(), query);
+//! ```
+
+#[cfg(feature = "acme")]
+pub mod acme;
+pub mod admin;
+pub mod anonymize;
+mod base64;
+pub mod blocklist;
+pub mod connections;
+#[cfg(feature = "dnssec")]
+pub mod dnssec;
+#[cfg(feature = "json")]
+pub mod doh;
+pub mod domain_name;
+#[cfg(feature = "tls")]
+pub mod dot;
+pub mod edns;
+#[cfg(feature = "json")]
+pub mod export;
+pub mod handler;
+pub mod header;
+pub mod health;
+pub mod hexdump;
+#[cfg(feature = "tls")]
+mod hpack;
+#[cfg(feature = "tls")]
+mod http2;
+#[cfg(feature = "tls")]
+pub mod https;
+pub mod idn;
+pub mod journal;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod leases;
+pub mod lint;
+pub mod listen;
+pub mod log;
+#[cfg(feature = "sockets")]
+pub mod mdns;
+pub mod network;
+#[cfg(feature = "otel")]
+pub mod otlp;
+pub mod overload;
+pub mod packet;
+pub mod pcap;
+pub mod proxy_protocol;
+pub mod question;
+pub mod record;
+pub mod redis;
+pub mod register;
+pub mod replication;
+pub mod resolv_conf;
+pub mod resolver;
+#[cfg(feature = "dnssec")]
+pub mod rollover;
+pub mod sample;
+pub mod server;
+pub mod stamp;
+pub mod stats;
+pub mod syslog;
+pub mod tcp;
+#[cfg(any(test, feature = "test_util"))]
+pub mod test_util;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trace;
+pub mod tsig;
+#[cfg(unix)]
+pub mod unix;
+pub mod upstream;
+#[cfg(feature = "dnssec")]
+pub mod validator;
+pub mod wire_cache;
+pub mod zone;
+#[cfg(feature = "dnssec")]
+pub mod zonemd;
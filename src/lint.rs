@@ -0,0 +1,309 @@
+//! Checks of zone contents, run by the `check-zone` subcommand before a zone is deployed
+//!
+//! Errors make resolvers fail to use (part of) the zone: NS records without addresses or
+//! glue, CNAME at the apex or next to other records, a serial which secondaries won't take
+//! as newer. Warnings are legal but suspicious, e.g. records of one RRset with different TTLs.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::domain_name::DomainName;
+use crate::record::{DnsRecord, RecordData, RecordType};
+use crate::zone::Zone;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Problem found in a zone
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Problem {
+    fn error(message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            message,
+        }
+    }
+
+    fn warning(message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error: {}", self.message),
+            Severity::Warning => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+/// Checks `zone`, and that its serial increased if it changed since the `previous` version
+pub fn check(zone: &Zone, previous: Option<&Zone>) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    check_apex(zone, &mut problems);
+    check_cnames(zone, &mut problems);
+    check_ns(zone, &mut problems);
+    check_ttls(zone, &mut problems);
+    if let Some(previous) = previous {
+        check_serial(zone, previous, &mut problems);
+    }
+    problems
+}
+
+fn check_apex(zone: &Zone, problems: &mut Vec<Problem>) {
+    let origin = zone.origin();
+    if !zone
+        .records_at(origin)
+        .any(|record| record.record_type == RecordType::NS)
+    {
+        problems.push(Problem::error(format!("no NS records at apex {}", origin)));
+    }
+    if zone
+        .records_at(origin)
+        .any(|record| record.record_type == RecordType::CNAME)
+    {
+        problems.push(Problem::error(format!("CNAME at apex {}", origin)));
+    }
+}
+
+/// CNAME can't coexist with other data, except DNSSEC records (RFC 2181 section 10.1)
+fn check_cnames(zone: &Zone, problems: &mut Vec<Problem>) {
+    let origin = zone.origin();
+    for cname in zone.records().iter().filter(|record| {
+        record.record_type == RecordType::CNAME && !record.domain_name.eq_ignore_case(origin)
+    }) {
+        let other = zone.records_at(&cname.domain_name).find(|record| {
+            !matches!(
+                record.record_type,
+                RecordType::CNAME | RecordType::RRSIG | RecordType::NSEC
+            )
+        });
+        if let Some(other) = other {
+            problems.push(Problem::error(format!(
+                "CNAME at {} next to {} record",
+                cname.domain_name, other.record_type
+            )));
+        }
+    }
+}
+
+/// Name servers in the zone need addresses, as glue if they're inside a delegated zone
+fn check_ns(zone: &Zone, problems: &mut Vec<Problem>) {
+    for ns in zone
+        .records()
+        .iter()
+        .filter(|record| record.record_type == RecordType::NS)
+    {
+        let RecordData::Name(target) = &ns.data else {
+            continue;
+        };
+        if !target.is_subdomain_of(zone.origin()) || has_address(zone, target) {
+            continue;
+        }
+        if zone.delegation(target).is_some() {
+            problems.push(Problem::error(format!(
+                "missing glue for NS {} of {}",
+                target, ns.domain_name
+            )));
+        } else if zone
+            .records_at(target)
+            .any(|record| record.record_type == RecordType::CNAME)
+        {
+            // RFC 2181 section 10.3
+            problems.push(Problem::error(format!(
+                "NS {} of {} is an alias",
+                target, ns.domain_name
+            )));
+        } else {
+            problems.push(Problem::error(format!(
+                "dangling NS {} of {}, it has no A or AAAA records",
+                target, ns.domain_name
+            )));
+        }
+    }
+}
+
+fn has_address(zone: &Zone, name: &DomainName) -> bool {
+    zone.records_at(name)
+        .any(|record| matches!(record.record_type, RecordType::A | RecordType::AAAA))
+}
+
+/// Records of an RRset should have the same TTL (RFC 2181 section 5.2)
+fn check_ttls(zone: &Zone, problems: &mut Vec<Problem>) {
+    let mut rrsets: Vec<(&DnsRecord, Vec<u32>)> = Vec::new();
+    let mut index: HashMap<(DomainName, u16), usize> = HashMap::new();
+    for record in zone.records() {
+        let key = (
+            record.domain_name.canonicalize(),
+            u16::from(record.record_type.clone()),
+        );
+        match index.get(&key) {
+            Some(&i) => rrsets[i].1.push(record.ttl),
+            None => {
+                index.insert(key, rrsets.len());
+                rrsets.push((record, vec![record.ttl]));
+            }
+        }
+    }
+    for (first, mut ttls) in rrsets {
+        ttls.sort_unstable();
+        ttls.dedup();
+        // signatures of different RRsets share the owner and type
+        if ttls.len() > 1 && first.record_type != RecordType::RRSIG {
+            let ttls: Vec<String> = ttls.iter().map(u32::to_string).collect();
+            problems.push(Problem::warning(format!(
+                "{} {} records have different TTLs {}",
+                first.domain_name,
+                first.record_type,
+                ttls.join(", ")
+            )));
+        }
+    }
+}
+
+/// Secondaries transfer only zones with a greater serial (RFC 1982 serial number arithmetic)
+fn check_serial(zone: &Zone, previous: &Zone, problems: &mut Vec<Problem>) {
+    if !zone.origin().eq_ignore_case(previous.origin()) {
+        problems.push(Problem::error(format!(
+            "previous version is zone {}, not {}",
+            previous.origin(),
+            zone.origin()
+        )));
+        return;
+    }
+    let (serial, previous_serial) = (serial(zone), serial(previous));
+    if serial.wrapping_sub(previous_serial) as i32 > 0 {
+        return;
+    }
+    let content = |zone: &Zone| -> Vec<DnsRecord> {
+        zone.records()
+            .iter()
+            .filter(|record| record.record_type != RecordType::SOA)
+            .cloned()
+            .collect()
+    };
+    let (records, previous_records) = (content(zone), content(previous));
+    let changed = records.len() != previous_records.len()
+        || records
+            .iter()
+            .any(|record| !previous_records.contains(record));
+    if changed || serial != previous_serial {
+        problems.push(Problem::error(format!(
+            "serial {} of zone {} isn't greater than previous {}",
+            serial,
+            zone.origin(),
+            previous_serial
+        )));
+    }
+}
+
+fn serial(zone: &Zone) -> u32 {
+    match zone.soa().map(|soa| &soa.data) {
+        Some(RecordData::Soa(soa)) => soa.serial,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = "$ORIGIN example.
+@       IN  SOA  ns1 hostmaster ( 2 7200 900 1209600 300 )
+        IN  NS   ns1
+        IN  NS   ns2
+ns1     IN  A    192.0.2.1
+ns2     IN  CNAME ns1
+www 300 IN  A    192.0.2.10
+www 600 IN  A    192.0.2.11
+alias   IN  CNAME www
+alias   IN  MX   10 mail
+sub     IN  NS   ns.sub
+sub     IN  NS   ns.other.sub
+ns.sub  IN  A    192.0.2.53
+lame    IN  NS   ns3
+";
+
+    fn messages(problems: &[Problem], severity: Severity) -> Vec<&str> {
+        problems
+            .iter()
+            .filter(|problem| problem.severity == severity)
+            .map(|problem| problem.message.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_problems() {
+        let zone = Zone::parse(ZONE).unwrap();
+        let problems = check(&zone, None);
+
+        assert_eq!(
+            messages(&problems, Severity::Error),
+            vec![
+                "CNAME at alias.example. next to MX record",
+                "NS ns2.example. of example. is an alias",
+                "missing glue for NS ns.other.sub.example. of sub.example.",
+                "dangling NS ns3.example. of lame.example., it has no A or AAAA records",
+            ]
+        );
+        assert_eq!(
+            messages(&problems, Severity::Warning),
+            vec!["www.example. A records have different TTLs 300, 600"]
+        );
+    }
+
+    #[test]
+    fn test_apex() {
+        let zone = Zone::parse(
+            "$ORIGIN example.
+@   IN  SOA   ns1 hostmaster ( 1 7200 900 1209600 300 )
+@   IN  CNAME other.",
+        )
+        .unwrap();
+        let problems = check(&zone, None);
+
+        assert_eq!(
+            messages(&problems, Severity::Error),
+            vec!["no NS records at apex example.", "CNAME at apex example."]
+        );
+    }
+
+    #[test]
+    fn test_serial() {
+        let previous = Zone::parse(ZONE).unwrap();
+        assert!(
+            messages(&check(&previous, Some(&previous)), Severity::Error)
+                .iter()
+                .all(|message| !message.starts_with("serial"))
+        );
+
+        let changed = Zone::parse(&format!("{}new IN A 192.0.2.99\n", ZONE)).unwrap();
+        assert!(messages(&check(&changed, Some(&previous)), Severity::Error)
+            .contains(&"serial 2 of zone example. isn't greater than previous 2"));
+
+        let increased = Zone::parse(&ZONE.replace("( 2 ", "( 3 ")).unwrap();
+        assert!(
+            messages(&check(&increased, Some(&previous)), Severity::Error)
+                .iter()
+                .all(|message| !message.starts_with("serial"))
+        );
+
+        // serial wrapped around
+        let wrapped = Zone::parse(&ZONE.replace("( 2 ", "( 4294967295 ")).unwrap();
+        assert!(messages(&check(&previous, Some(&wrapped)), Severity::Error)
+            .iter()
+            .all(|message| !message.starts_with("serial")));
+    }
+}
@@ -0,0 +1,203 @@
+//! Listening sockets, optionally bound to a network interface
+//!
+//! Listener addresses are `<address>[@<interface>]`, e.g. `0.0.0.0:53@eth1`. A listener
+//! bound to an interface (`SO_BINDTODEVICE`, Linux only) gets only packets and connections
+//! which arrived through it, so a server of a multi-homed host serves just the network
+//! segments it should, even when listening on the wildcard address.
+//!
+//! Kernel buffers of UDP sockets (listeners as well as upstream sockets) can be enlarged,
+//! see [`SocketBuffers`], so bursts of packets wait in them instead of being dropped.
+//!
+//! Interfaces and buffer sizes need socket options std doesn't have, they require the
+//! `sockets` feature. Without it listeners are plain std sockets.
+
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+
+use anyhow::{Context, Result};
+#[cfg(feature = "sockets")]
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+/// Pending connections of TCP listeners
+#[cfg(feature = "sockets")]
+const BACKLOG: i32 = 128;
+
+/// Address and interface of `<address>[@<interface>]`
+pub fn split_interface(address: &str) -> (&str, Option<&str>) {
+    match address.rsplit_once('@') {
+        Some((address, interface)) => (address, Some(interface)),
+        None => (address, None),
+    }
+}
+
+/// Listener `address`, bound to `interface` unless it has an interface of its own
+pub fn with_default_interface(address: &str, interface: Option<&str>) -> String {
+    match (split_interface(address), interface) {
+        ((_, None), Some(interface)) => format!("{}@{}", address, interface),
+        _ => address.to_string(),
+    }
+}
+
+/// TCP listener on `<address>[@<interface>]`
+#[cfg(feature = "sockets")]
+pub fn tcp(address: &str) -> Result<TcpListener> {
+    let socket = socket(address, Type::STREAM, Protocol::TCP)?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+/// TCP listener on `<address>`
+#[cfg(not(feature = "sockets"))]
+pub fn tcp(address: &str) -> Result<TcpListener> {
+    Ok(TcpListener::bind(std_socket_address(address)?)?)
+}
+
+/// UDP socket on `<address>[@<interface>]`
+#[cfg(feature = "sockets")]
+pub fn udp(address: &str) -> Result<UdpSocket> {
+    Ok(socket(address, Type::DGRAM, Protocol::UDP)?.into())
+}
+
+/// UDP socket on `<address>`
+#[cfg(not(feature = "sockets"))]
+pub fn udp(address: &str) -> Result<UdpSocket> {
+    Ok(UdpSocket::bind(std_socket_address(address)?)?)
+}
+
+/// Kernel buffer sizes of UDP sockets, the system defaults unless set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketBuffers {
+    /// `SO_RCVBUF` in bytes
+    pub receive: Option<usize>,
+    /// `SO_SNDBUF` in bytes
+    pub send: Option<usize>,
+}
+
+impl SocketBuffers {
+    pub fn is_set(&self) -> bool {
+        self.receive.is_some() || self.send.is_some()
+    }
+
+    /// Sets the buffer sizes of `socket`, returns the effective receive and send sizes
+    ///
+    /// The kernel may grant sizes other than requested, Linux doubles them for its
+    /// bookkeeping and caps them at `net.core.rmem_max` and `net.core.wmem_max`.
+    #[cfg(feature = "sockets")]
+    pub fn apply(&self, socket: &UdpSocket) -> Result<(usize, usize)> {
+        let socket = SockRef::from(socket);
+        if let Some(size) = self.receive {
+            socket
+                .set_recv_buffer_size(size)
+                .with_context(|| format!("failed to set receive buffer to {} bytes", size))?;
+        }
+        if let Some(size) = self.send {
+            socket
+                .set_send_buffer_size(size)
+                .with_context(|| format!("failed to set send buffer to {} bytes", size))?;
+        }
+        Ok((socket.recv_buffer_size()?, socket.send_buffer_size()?))
+    }
+
+    #[cfg(not(feature = "sockets"))]
+    pub fn apply(&self, _socket: &UdpSocket) -> Result<(usize, usize)> {
+        anyhow::bail!("socket buffer sizes require the server to be built with the sockets feature")
+    }
+}
+
+/// Socket address of `<address>[@<interface>]`
+fn socket_address(address: &str) -> Result<SocketAddr> {
+    let address = split_interface(address).0;
+    address
+        .to_socket_addrs()
+        .with_context(|| format!("invalid listener address {}", address))?
+        .next()
+        .with_context(|| format!("listener address {} not resolved", address))
+}
+
+/// Socket address of `<address>`, listeners can't be bound to interfaces with std alone
+#[cfg(not(feature = "sockets"))]
+fn std_socket_address(address: &str) -> Result<SocketAddr> {
+    if let (_, Some(interface)) = split_interface(address) {
+        anyhow::bail!(
+            "binding to interface {} requires the server to be built with the sockets feature",
+            interface
+        );
+    }
+    socket_address(address)
+}
+
+#[cfg(feature = "sockets")]
+fn socket(address: &str, kind: Type, protocol: Protocol) -> Result<Socket> {
+    let socket_address = socket_address(address)?;
+    let interface = split_interface(address).1;
+
+    let socket = Socket::new(Domain::for_address(socket_address), kind, Some(protocol))?;
+    if kind == Type::STREAM {
+        // as std does, so the server can be restarted while connections are closing
+        socket.set_reuse_address(true)?;
+    }
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+    socket.bind(&socket_address.into())?;
+    Ok(socket)
+}
+
+#[cfg(all(feature = "sockets", any(target_os = "linux", target_os = "android")))]
+fn bind_device(socket: &Socket, interface: &str) -> Result<()> {
+    socket
+        .bind_device(Some(interface.as_bytes()))
+        .with_context(|| format!("failed to bind to interface {}", interface))
+}
+
+#[cfg(all(
+    feature = "sockets",
+    not(any(target_os = "linux", target_os = "android"))
+))]
+fn bind_device(_socket: &Socket, interface: &str) -> Result<()> {
+    anyhow::bail!(
+        "binding to interface {} is supported only on Linux",
+        interface
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listener_addresses() {
+        assert_eq!(
+            split_interface("0.0.0.0:53@eth1"),
+            ("0.0.0.0:53", Some("eth1"))
+        );
+        assert_eq!(split_interface("[::]:53"), ("[::]:53", None));
+        assert_eq!(
+            with_default_interface("[::]:53", Some("eth0")),
+            "[::]:53@eth0"
+        );
+        assert_eq!(
+            with_default_interface("0.0.0.0:53@eth1", Some("eth0")),
+            "0.0.0.0:53@eth1"
+        );
+
+        let listener = tcp("127.0.0.1:0").unwrap();
+        assert!(listener.local_addr().unwrap().port() > 0);
+        #[cfg(target_os = "linux")]
+        assert!(udp("127.0.0.1:0@no-such-interface").is_err());
+    }
+
+    #[cfg(feature = "sockets")]
+    #[test]
+    fn test_socket_buffers_are_enlarged() {
+        let socket = udp("127.0.0.1:0").unwrap();
+        let (receive, send) = SocketBuffers::default().apply(&socket).unwrap();
+
+        let buffers = SocketBuffers {
+            receive: Some(receive + 4096),
+            send: None,
+        };
+        let (enlarged, unchanged) = buffers.apply(&socket).unwrap();
+        assert!(enlarged > receive);
+        assert_eq!(unchanged, send);
+    }
+}
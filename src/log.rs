@@ -0,0 +1,259 @@
+//! Query log
+//!
+//! In the default text format, received and sent packets are printed in full as they pass
+//! through the server. The JSON format instead prints one object per answered query,
+//! on a single line, ready for ingestion into Loki, Elasticsearch and the like:
+//!
+//! ```text
+//! {"timestamp":"2024-03-01T12:00:00.250Z","transport":"udp","client":"127.0.0.1:53124","qname":"codecrafters.io.","qtype":"A","rcode":"NOERROR","duration_ms":1.234,"cache_hit":false}
+//! ```
+//!
+//! With syslog configured, every query is logged there instead (in the chosen format, or
+//! as a one-line summary for text), while packets are still printed in the text format.
+//!
+//! The format is chosen once at startup ([`install`]), text is used until then.
+
+use std::cell::Cell;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::anonymize::Anonymizer;
+use crate::packet::DnsPacket;
+use crate::stats;
+use crate::syslog::{Severity, Syslog};
+
+/// How the query log is printed
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LogFormat {
+    /// Packets printed in full, for humans
+    #[default]
+    Text,
+    /// One JSON object per query
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => anyhow::bail!("unknown log format '{}', expected text or json", s),
+        }
+    }
+}
+
+struct Config {
+    format: LogFormat,
+    anonymizer: Anonymizer,
+    syslog: Option<Syslog>,
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+thread_local! {
+    static CACHE_HIT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Sets the format of the log, client addresses are logged as given by `anonymizer`;
+/// queries are logged to `syslog` if given
+pub fn install(format: LogFormat, anonymizer: Anonymizer, syslog: Option<Syslog>) -> Result<()> {
+    CONFIG
+        .set(Config {
+            format,
+            anonymizer,
+            syslog,
+        })
+        .map_err(|_| anyhow::anyhow!("log format already set"))
+}
+
+/// Whether packets should be printed in full
+pub fn is_text() -> bool {
+    format() == LogFormat::Text
+}
+
+fn format() -> LogFormat {
+    CONFIG.get().map(|config| config.format).unwrap_or_default()
+}
+
+/// Marks the query being handled in this thread as answered from the cache
+pub fn cache_hit() {
+    CACHE_HIT.with(|hit| hit.set(true));
+}
+
+/// Starts timing a query, which is logged by [`QueryLog::finish`]
+pub fn query(transport: &'static str) -> QueryLog {
+    CACHE_HIT.with(|hit| hit.set(false));
+    QueryLog {
+        transport,
+        started: Instant::now(),
+    }
+}
+
+/// Query being handled
+pub struct QueryLog {
+    transport: &'static str,
+    started: Instant,
+}
+
+impl QueryLog {
+    /// Logs the query with its `response`, and counts it in [`stats`]
+    pub fn finish(self, client: Option<SocketAddr>, response: &DnsPacket) {
+        let config = CONFIG.get();
+        let anonymizer = config.map_or(&Anonymizer::None, |config| &config.anonymizer);
+        stats::query(
+            client.map(|client| anonymizer.host(client.ip())).as_deref(),
+            response
+                .questions
+                .first()
+                .map(|question| &question.domain_name),
+            CACHE_HIT.with(Cell::get),
+        );
+
+        let Some(config) =
+            config.filter(|config| config.format == LogFormat::Json || config.syslog.is_some())
+        else {
+            return;
+        };
+        let client = client.map(|client| config.anonymizer.client(client));
+        let line = match config.format {
+            LogFormat::Json => self.json(client, response),
+            LogFormat::Text => self.summary(client, response),
+        };
+        match &config.syslog {
+            Some(syslog) => syslog.send(Severity::Info, &line),
+            None => println!("{}", line),
+        }
+    }
+
+    fn json(&self, client: Option<String>, response: &DnsPacket) -> String {
+        let mut line = String::with_capacity(256);
+        let _ = write!(
+            line,
+            "{{\"timestamp\":\"{}\",\"transport\":\"{}\"",
+            timestamp(SystemTime::now()),
+            self.transport
+        );
+        if let Some(client) = client {
+            line.push_str(",\"client\":");
+            push_string(&mut line, &client);
+        }
+        if let Some(question) = response.questions.first() {
+            line.push_str(",\"qname\":");
+            push_string(&mut line, &question.domain_name.to_string());
+            line.push_str(",\"qtype\":");
+            push_string(&mut line, &question.query_type.to_string());
+        }
+        let _ = write!(
+            line,
+            ",\"rcode\":\"{:?}\",\"duration_ms\":{:.3},\"cache_hit\":{}}}",
+            response.header.rescode,
+            self.started.elapsed().as_secs_f64() * 1000.0,
+            CACHE_HIT.with(Cell::get)
+        );
+        line
+    }
+
+    /// `udp 127.0.0.1:53124 codecrafters.io. A NOERROR 1.234ms cached`
+    fn summary(&self, client: Option<String>, response: &DnsPacket) -> String {
+        let mut line = format!("{} {}", self.transport, client.as_deref().unwrap_or("-"));
+        if let Some(question) = response.questions.first() {
+            let _ = write!(line, " {} {}", question.domain_name, question.query_type);
+        }
+        let _ = write!(
+            line,
+            " {:?} {:.3}ms",
+            response.header.rescode,
+            self.started.elapsed().as_secs_f64() * 1000.0
+        );
+        if CACHE_HIT.with(Cell::get) {
+            line.push_str(" cached");
+        }
+        line
+    }
+}
+
+/// Appends `value` as a JSON string
+fn push_string(line: &mut String, value: &str) {
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+/// RFC 3339 timestamp in UTC with milliseconds
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days(seconds / 86400);
+    let time_of_day = seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Days since 1970-01-01 of the date (proleptic Gregorian calendar)
+pub(crate) fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Date of the day `days` after 1970-01-01 (Howard Hinnant's algorithm)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(19_783 * 86_400_000 + 45_296_789);
+        assert_eq!(timestamp(time), "2024-03-01T12:34:56.789Z");
+    }
+
+    #[test]
+    fn test_strings_are_escaped() {
+        let mut line = String::new();
+        push_string(&mut line, "a\"b\\c\n");
+        assert_eq!(line, r#""a\"b\\c\u000a""#);
+    }
+}
@@ -1,146 +1,962 @@
 use anyhow::Result;
-use rand::prelude::*;
-use std::net::UdpSocket;
+use std::net::{IpAddr, UdpSocket};
+use std::sync::Arc;
 
-use crate::{
-    header::ResponseCode,
-    packet::{BytesPacket, DnsPacket},
-    record::DnsRecord,
+#[cfg(feature = "acme")]
+use dns_starter_rust::acme::Acme;
+#[cfg(feature = "json")]
+use dns_starter_rust::doh;
+#[cfg(any(feature = "json", feature = "tls"))]
+use dns_starter_rust::handler::Request;
+#[cfg(feature = "json")]
+use dns_starter_rust::handler::SharedZone;
+use dns_starter_rust::{
+    admin::{self, Status},
+    blocklist::{Allowlist, Blocklists},
+    domain_name::DomainName,
+    handler::{
+        watch_zone_file, BlocklistHandler, CacheHandler, CanaryHandler, ClientRouteHandler,
+        ForwardHandler, HairpinHandler, LeaseHandler, MinimalResponsesHandler,
+        NxdomainRedirectHandler, Pipeline, RebindingFilterHandler, StaticAnswerHandler,
+        TtlClampHandler, ZoneHandler,
+    },
+    health::HealthChecker,
+    leases::LeaseFile,
+    listen,
+    log::{self, LogFormat},
+    network::Network,
+    overload::Overload,
+    packet::ParseMode,
+    pcap::PcapWriter,
+    record::RecordType,
+    redis::RedisCache,
+    register::{self, Registry},
+    replication::Replicator,
+    resolv_conf,
+    sample::{self, Sampler},
+    server::Server,
+    stats,
+    syslog::Syslog,
+    tcp,
+    upstream::{FailoverUpstream, UdpUpstream, Upstream, UpstreamSpec},
+    zone::Zone,
 };
+#[cfg(feature = "dnssec")]
+use dns_starter_rust::{
+    dnssec, rollover,
+    validator::{self, ValidationMode, Validator},
+    zonemd,
+};
+#[cfg(feature = "tls")]
+use dns_starter_rust::{dot, https};
+#[cfg(feature = "otel")]
+use dns_starter_rust::{otlp::OtlpExporter, trace};
 
-mod domain_name;
-mod header;
-mod packet;
-mod question;
-mod record;
+#[cfg(feature = "lua")]
+use dns_starter_rust::handler::ScriptHandler;
+#[cfg(feature = "sockets")]
+use dns_starter_rust::mdns;
 
-fn main() -> Result<()> {
-    let udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
-    let mut buf = [0; 512];
+mod commands;
+mod config;
 
-    // ARGS: --resolver <address>
-    let mut resolver_address = String::new();
-    let mut args = std::env::args();
+use config::Config;
 
-    while let Some(arg) = args.next() {
-        resolver_address = match arg.as_str() {
-            "--resolver" => args.next().expect("missing resolver address"),
-            _ => resolver_address,
-        };
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    // SUBCOMMANDS: query <name> [type] [@server]
+    //              bench [@server] [--qps N] [--duration SECS] [--concurrency N] [--queries FILE]
+    //              replay <file> [@server] [--speed N]
+    //              trace <name> [type] [@server] [--trust-anchor <file>]
+    //              check-zone <file> [--previous <file>]
+    //              diff-zone <old> <new>
+    //              export-zone <file> | @server <origin> [--format json|yaml]
+    match args.split_first().map(|(cmd, args)| (cmd.as_str(), args)) {
+        Some(("query", args)) => return commands::query::run(args),
+        Some(("bench", args)) => return commands::bench::run(args),
+        Some(("replay", args)) => return commands::replay::run(args),
+        Some(("check-zone", args)) => return commands::check_zone::run(args),
+        Some(("diff-zone", args)) => return commands::diff_zone::run(args),
+        #[cfg(feature = "json")]
+        Some(("export-zone", args)) => return commands::export_zone::run(args),
+        #[cfg(not(feature = "json"))]
+        Some(("export-zone", _)) => {
+            anyhow::bail!("export-zone requires the server to be built with the json feature")
+        }
+        #[cfg(feature = "dnssec")]
+        Some(("trace", args)) => return commands::trace::run(args),
+        #[cfg(not(feature = "dnssec"))]
+        Some(("trace", _)) => {
+            anyhow::bail!("trace requires the server to be built with the dnssec feature")
+        }
+        _ => {}
     }
 
-    loop {
-        let mut resolved_answers: Vec<DnsRecord> = Vec::new(); // answers returned by extrenal resolver
+    let mut udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
 
-        match udp_socket.recv_from(&mut buf) {
-            Ok((size, source)) => {
-                println!("< Received {} bytes from {}", size, source);
+    let Config {
+        resolver_address,
+        resolv_conf_path,
+        bootstrap,
+        tls_options,
+        mut json_api_address,
+        script_path,
+        pcap_path,
+        mut tcp_address,
+        interface,
+        mut dot_address,
+        mut https_address,
+        tls_cert_path,
+        tls_key_path,
+        acme_domain,
+        acme_email,
+        acme_directory,
+        acme_http_address,
+        tsig_keys,
+        zone_paths,
+        transfer_rules,
+        dnssec_keys,
+        publish_zonemd,
+        publish_cds,
+        zsk_rollovers,
+        zsk_lifetime,
+        key_sets,
+        dnssec_validation,
+        trust_anchor_path,
+        health_checks,
+        health_interval,
+        leases_path,
+        lease_domain,
+        register_address,
+        register_keys,
+        min_ttl,
+        max_ttl,
+        cache_size,
+        cache_redis,
+        cache_peers,
+        client_routes,
+        cache_replication,
+        wire_cache,
+        max_stale,
+        strip_ecs,
+        minimal_responses,
+        drop_unknown_edns,
+        anonymizer,
+        rebind_protection,
+        rebind_allowed,
+        max_udp_size,
+        upstream_timeout,
+        nxdomain_redirect,
+        hairpin_rules,
+        hairpin_clients,
+        nxdomain_suffixes,
+        unix_stream_path,
+        unix_datagram_path,
+        proxy_protocol,
+        dump_packets,
+        admin_address,
+        otlp_endpoint,
+        log_format,
+        syslog_destination,
+        syslog_facility,
+        canaries,
+        blocklist_sources,
+        mdns_interfaces,
+        mdns_rate,
+        blocklist_allowed,
+        blocklist_refresh,
+        connection_limits,
+        sample_path,
+        sample_rate,
+        sample_max_size,
+        strict_listeners,
+        max_in_flight,
+        overload,
+        socket_buffers,
+    } = Config::parse(args)?;
+    let parse_mode = |listener: &str| match strict_listeners.iter().any(|l| l == listener) {
+        true => ParseMode::Strict,
+        false => ParseMode::Lenient,
+    };
 
-                let mut bp = BytesPacket::new();
-                bp.buf.extend_from_slice(&buf);
-                let orig = DnsPacket::from(bp);
-                println!("<<< Received DNS packet: {:#?}", orig);
+    // listener addresses may be <address>@<interface>, others get the --interface
+    if let Some(interface) = interface.as_deref() {
+        println!("Serving only queries arriving through {}", interface);
+        let address = udp_socket.local_addr()?;
+        drop(udp_socket);
+        udp_socket = listen::udp(&format!("{}@{}", address, interface))?;
+        for address in [
+            &mut tcp_address,
+            &mut dot_address,
+            &mut https_address,
+            &mut json_api_address,
+        ] {
+            if !address.is_empty() {
+                *address = listen::with_default_interface(address, Some(interface));
+            }
+        }
+    }
+    if socket_buffers.is_set() {
+        let (receive, send) = socket_buffers.apply(&udp_socket)?;
+        println!(
+            "UDP listener: {} bytes receive buffer, {} bytes send buffer",
+            receive, send
+        );
+    }
 
-                // Forward to the resolver?
-                if !resolver_address.is_empty() {
-                    let resolver =
-                        UdpSocket::bind("localhost:0").expect("Failed to bind to resolver address");
+    if tcp_address.is_empty() && dot_address.is_empty() && !tsig_keys.is_empty() {
+        anyhow::bail!("--tsig-key requires --tcp or --dot");
+    }
+    let tls_listeners = !dot_address.is_empty() || !https_address.is_empty();
+    if tls_listeners && (tls_cert_path.is_empty() || tls_key_path.is_empty()) {
+        anyhow::bail!("--dot and --https require --tls-cert and --tls-key");
+    }
+    if !acme_domain.is_empty() && !tls_listeners {
+        anyhow::bail!("--acme requires --dot or --https");
+    }
+    let acme_options = [&acme_email, &acme_directory, &acme_http_address];
+    if acme_domain.is_empty() && acme_options.iter().any(|option| !option.is_empty()) {
+        anyhow::bail!("--acme-email, --acme-directory and --acme-http require --acme");
+    }
+    if !register_address.is_empty() && register_keys.is_empty() {
+        anyhow::bail!("--register requires at least one --register-key");
+    }
+    // UDP can't carry the header, only stream listeners can sit behind a TCP load balancer
+    if proxy_protocol
+        && [
+            &tcp_address,
+            &dot_address,
+            &https_address,
+            &json_api_address,
+        ]
+        .iter()
+        .all(|a| a.is_empty())
+    {
+        anyhow::bail!("--proxy-protocol requires --tcp, --dot, --https or --json-api");
+    }
+    if !trust_anchor_path.is_empty() && dnssec_validation.is_empty() {
+        anyhow::bail!("--trust-anchor requires --dnssec-validation");
+    }
+    if !dnssec_validation.is_empty() && resolver_address.is_empty() && resolv_conf_path.is_empty() {
+        anyhow::bail!("--dnssec-validation requires --resolver or --resolv-conf");
+    }
+    if dump_packets && log_format != LogFormat::Text {
+        anyhow::bail!("--hexdump requires --log-format text");
+    }
+    // answers served from encoded responses must be the same for every client
+    if wire_cache && (!client_routes.is_empty() || !hairpin_rules.is_empty()) {
+        anyhow::bail!("--wire-cache can't be combined with --route or --hairpin");
+    }
+    if wire_cache && !script_path.is_empty() {
+        anyhow::bail!("--wire-cache can't be combined with --script");
+    }
+    let syslog = if !syslog_destination.is_empty() {
+        let syslog = Syslog::open(&syslog_destination, syslog_facility.unwrap_or_default())?;
+        println!("Logging queries to syslog at {}", syslog);
+        Some(syslog)
+    } else if syslog_facility.is_some() {
+        anyhow::bail!("--syslog-facility requires --syslog");
+    } else {
+        None
+    };
+    log::install(log_format, anonymizer.clone(), syslog)?;
+    stats::start();
+    #[cfg(unix)]
+    stats::dump_on_signal()?;
+    if !otlp_endpoint.is_empty() {
+        #[cfg(feature = "otel")]
+        {
+            println!("Exporting query traces to {}", otlp_endpoint);
+            trace::install(OtlpExporter::new(&otlp_endpoint)?)?;
+        }
+        #[cfg(not(feature = "otel"))]
+        anyhow::bail!("--otlp-endpoint requires the server to be built with the otel feature");
+    }
 
-                    println!(">>> Forwarding to {}", resolver_address);
-                    let orig_questions = orig.questions.clone();
+    // Query handlers, in order of processing
+    let mut pipeline = Pipeline::new();
+    // sees the final responses, whichever handler answered
+    if minimal_responses {
+        println!("Leaving only the answers in positive responses");
+        pipeline = pipeline.with(MinimalResponsesHandler);
+    }
+    if !canaries.is_empty() {
+        for canary in canaries.iter() {
+            println!("Answering canary domain {} with NXDOMAIN", canary);
+        }
+        pipeline = pipeline.with(CanaryHandler::new(canaries));
+    }
+    if !script_path.is_empty() {
+        #[cfg(feature = "lua")]
+        {
+            println!("Applying query policy from {}", script_path);
+            pipeline = pipeline.with(ScriptHandler::from_file(&script_path)?);
+        }
+        #[cfg(not(feature = "lua"))]
+        anyhow::bail!("--script requires the server to be built with the lua feature");
+    }
+    if !blocklist_sources.is_empty() {
+        let blocklists = Arc::new(Blocklists::new(blocklist_sources)?);
+        println!(
+            "Blocking domains from {}, refreshed every {:?}",
+            blocklists, blocklist_refresh
+        );
+        let complete = blocklists.refresh();
+        blocklists.start(blocklist_refresh, complete);
+        let allowlist = Allowlist::new(&blocklist_allowed)?;
+        for allowed in blocklist_allowed.iter() {
+            println!("Never blocking {}", allowed);
+        }
+        pipeline = pipeline.with(BlocklistHandler::new(blocklists).allow(allowlist));
+    } else if !blocklist_allowed.is_empty() {
+        anyhow::bail!("--blocklist-allow requires --blocklist");
+    }
+    if min_ttl.is_some() || max_ttl.is_some() {
+        let (min_ttl, max_ttl) = (min_ttl.unwrap_or(0), max_ttl.unwrap_or(u32::MAX));
+        println!(
+            "Clamping answer and negative TTLs to {}..{}",
+            min_ttl, max_ttl
+        );
+        pipeline = pipeline.with(TtlClampHandler::new(min_ttl, max_ttl));
+    }
+    // redirect address is typically a private one, so it must not pass through rebinding filter
+    if let Some(address) = nxdomain_redirect {
+        if nxdomain_suffixes.is_empty() {
+            anyhow::bail!("--nxdomain-redirect requires at least one --nxdomain-suffix");
+        }
+        println!("Redirecting non-existent names to {}", address);
+        pipeline = pipeline.with(NxdomainRedirectHandler::new(nxdomain_suffixes, address));
+    }
+    // internal addresses must not pass through rebinding filter either
+    if !hairpin_rules.is_empty() {
+        for rule in hairpin_rules.iter() {
+            println!("Rewriting answers for LAN clients: {}", rule);
+        }
+        let mut hairpin = HairpinHandler::new(hairpin_rules);
+        if !hairpin_clients.is_empty() {
+            hairpin = hairpin.clients(hairpin_clients);
+        }
+        pipeline = pipeline.with(hairpin);
+    } else if !hairpin_clients.is_empty() {
+        anyhow::bail!("--hairpin-client requires --hairpin");
+    }
+    // zones are exported by the admin endpoint
+    #[cfg(feature = "json")]
+    let mut served_zones: Vec<SharedZone> = Vec::new();
+    // local zones typically hold private addresses too
+    if !zone_paths.is_empty() {
+        let mut zones = Vec::new();
+        for path in zone_paths.iter() {
+            let zone = Zone::from_file(path)?;
+            println!("Serving zone {} from {}", zone.origin(), path);
+            zones.push(zone);
+        }
+        // signed and digested zones are prepared once, only plain ones follow their files
+        let reloaded: Vec<(DomainName, String)> = zone_paths
+            .iter()
+            .zip(zones.iter())
+            .filter(|(_, zone)| {
+                !publish_zonemd
+                    && !dnssec_keys
+                        .iter()
+                        .any(|(origin, _)| origin.eq_ignore_case(zone.origin()))
+                    && !zone
+                        .records_at(zone.origin())
+                        .any(|record| record.record_type == RecordType::ZONEMD)
+            })
+            .map(|(path, zone)| (zone.origin().clone(), path.clone()))
+            .collect();
 
-                    // Resolver can work only with a single question, we need to split them into separate DNS packets,
-                    // send them separately and then merge responses into one DNS packet
-                    for q in orig_questions {
-                        let mut forwarded = DnsPacket::new();
-                        forwarded.header = orig.header;
-                        forwarded.questions.push(q);
-                        forwarded.header.question_entries = 1;
+        if publish_zonemd && cfg!(not(feature = "dnssec")) {
+            anyhow::bail!("--zonemd requires the server to be built with the dnssec feature");
+        }
+        // digest is checked before the zone is changed by publishing or signing
+        for zone in zones.iter_mut() {
+            let has_zonemd = zone
+                .records_at(zone.origin())
+                .any(|record| record.record_type == RecordType::ZONEMD);
+            #[cfg(feature = "dnssec")]
+            {
+                if has_zonemd && zonemd::verify(zone)? {
+                    println!("ZONEMD of zone {} verified", zone.origin());
+                }
+                if publish_zonemd {
+                    *zone = zonemd::publish(zone)?;
+                }
+            }
+            #[cfg(not(feature = "dnssec"))]
+            if has_zonemd {
+                println!(
+                    "ZONEMD of zone {} not verified, the server is built without the dnssec feature",
+                    zone.origin()
+                );
+            }
+        }
 
-                        let forwarded_msg_id = random();
-                        forwarded.header.id = forwarded_msg_id;
-                        println!(">>> Forwarding > Sent DNS packet: {:#?} ", forwarded);
+        for (origin, _) in dnssec_keys.iter() {
+            if !zones
+                .iter()
+                .any(|zone| zone.origin().eq_ignore_case(origin))
+            {
+                anyhow::bail!("--dnssec-key: zone {} is not served", origin);
+            }
+        }
+        if publish_cds && dnssec_keys.is_empty() {
+            anyhow::bail!("--cds requires --dnssec-key");
+        }
+        for (origin, _) in zsk_rollovers.iter() {
+            if !dnssec_keys
+                .iter()
+                .any(|(keys_origin, _)| keys_origin.eq_ignore_case(origin))
+            {
+                anyhow::bail!("--zsk-rollover: zone {} has no --dnssec-key", origin);
+            }
+        }
+        for (origin, _) in key_sets.iter() {
+            if !dnssec_keys
+                .iter()
+                .any(|(keys_origin, _)| keys_origin.eq_ignore_case(origin))
+            {
+                anyhow::bail!("--dnssec-key-set: zone {} has no --dnssec-key", origin);
+            }
+            if zsk_rollovers
+                .iter()
+                .any(|(rollover_origin, _)| rollover_origin.eq_ignore_case(origin))
+            {
+                anyhow::bail!(
+                    "--dnssec-key-set: ZSKs of zone {} can't be rolled over, the key set is signed offline",
+                    origin
+                );
+            }
+            if publish_cds {
+                anyhow::bail!(
+                    "--cds: CDS and CDNSKEY of zone {} signed offline belong to its key set",
+                    origin
+                );
+            }
+        }
+        if zsk_lifetime.is_some() && zsk_rollovers.is_empty() {
+            anyhow::bail!("--zsk-lifetime requires --zsk-rollover");
+        }
+        #[cfg(feature = "dnssec")]
+        let mut rollovers = Vec::new();
+        if !dnssec_keys.is_empty() {
+            #[cfg(feature = "dnssec")]
+            for zone in zones.iter_mut() {
+                let specs = dnssec_keys
+                    .iter()
+                    .filter(|(origin, _)| origin.eq_ignore_case(zone.origin()))
+                    .map(|(_, key)| key.parse::<dnssec::KeySpec>())
+                    .collect::<Result<Vec<_>>>()?;
+                let keys = specs
+                    .iter()
+                    .map(dnssec::KeySpec::load)
+                    .collect::<Result<Vec<_>>>()?;
+                if keys.is_empty() {
+                    continue;
+                }
+                for key in keys.iter() {
+                    println!("Signing zone {} with {}", zone.origin(), key);
+                    if key.role() != dnssec::KeyRole::Zsk {
+                        println!("  DS for the parent zone: {}", key.ds(zone.origin()));
+                    }
+                }
+                if publish_cds {
+                    *zone = dnssec::publish_cds(zone, &keys)?;
+                }
 
-                        let bytes_packet = BytesPacket::from(forwarded);
+                let rollover_directory = zsk_rollovers
+                    .iter()
+                    .find(|(origin, _)| origin.eq_ignore_case(zone.origin()))
+                    .map(|(_, directory)| directory);
+                let key_set_path = key_sets
+                    .iter()
+                    .find(|(origin, _)| origin.eq_ignore_case(zone.origin()))
+                    .map(|(_, path)| path);
+                match (rollover_directory, key_set_path) {
+                    (Some(directory), _) => {
+                        println!(
+                            "Rolling over ZSKs of zone {} in {}",
+                            zone.origin(),
+                            directory
+                        );
+                        let rollover = rollover::ZskRollover::new(
+                            zone.clone(),
+                            specs,
+                            directory,
+                            zsk_lifetime.unwrap_or(rollover::DEFAULT_ZSK_LIFETIME),
+                        )?;
+                        *zone = rollover.sign(std::time::SystemTime::now())?;
+                        rollovers.push(Arc::new(rollover));
+                    }
+                    (None, Some(path)) => {
+                        let key_set = dnssec::KeySet::from_file(path, zone.origin())?;
+                        let now = std::time::SystemTime::now();
+                        let valid = key_set.expiration().duration_since(now).unwrap_or_default();
+                        println!(
+                            "DNSKEY RRset of zone {} signed offline, the signatures expire in {} days",
+                            zone.origin(),
+                            valid.as_secs() / (24 * 3600)
+                        );
+                        *zone = dnssec::sign_zone_with_key_set(zone, &keys, &key_set, now)?;
+                    }
+                    (None, None) => {
+                        *zone = dnssec::sign_zone(zone, &keys, std::time::SystemTime::now())?
+                    }
+                }
+            }
+            #[cfg(not(feature = "dnssec"))]
+            anyhow::bail!("--dnssec-key requires the server to be built with the dnssec feature");
+        }
 
-                        resolver
-                            .send_to(&bytes_packet.buf, &resolver_address)
-                            .expect("Failed to forward message");
+        let mut checker = HealthChecker::new();
+        for (name, probe) in health_checks.iter() {
+            let addresses: Vec<IpAddr> = zones
+                .iter()
+                .flat_map(|zone| zone.records_at(name))
+                .filter_map(|record| record.data.address())
+                .collect();
+            if addresses.is_empty() {
+                anyhow::bail!("--health-check: {} has no addresses in local zones", name);
+            }
+            println!("Health-checking {} addresses of {}", addresses.len(), name);
+            checker = checker.pool(name.clone(), probe.clone(), addresses);
+        }
 
-                        let mut buf = [0; 512];
-                        resolver
-                            .recv_from(&mut buf)
-                            .expect("Failed to receive response to forwarded message");
+        for (origin, _) in transfer_rules.iter() {
+            if !zones
+                .iter()
+                .any(|zone| zone.origin().eq_ignore_case(origin))
+            {
+                anyhow::bail!("--allow-transfer: zone {} is not served", origin);
+            }
+        }
 
-                        let mut bp = BytesPacket::new();
-                        bp.buf.extend_from_slice(&buf);
+        let mut zone_handler = ZoneHandler::new(zones);
+        for (origin, rule) in transfer_rules {
+            println!("Allowing transfers of zone {} to {}", origin, rule);
+            zone_handler = zone_handler.allow_transfer(&origin, rule);
+        }
+        if !health_checks.is_empty() {
+            let checker = Arc::new(checker);
+            checker.start(health_interval);
+            zone_handler = zone_handler.health_checker(checker);
+        }
+        #[cfg(feature = "dnssec")]
+        for rollover in rollovers {
+            let zone = zone_handler
+                .shared_zone(rollover.origin())
+                .expect("signed zone is served");
+            rollover.start(zone);
+        }
+        for (origin, path) in reloaded {
+            let zone = zone_handler
+                .shared_zone(&origin)
+                .expect("reloaded zone is served");
+            watch_zone_file(zone, path);
+        }
+        #[cfg(feature = "json")]
+        served_zones.extend(zone_handler.shared_zones().iter().cloned());
+        pipeline = pipeline.with(zone_handler);
+    } else if !health_checks.is_empty() {
+        anyhow::bail!("--health-check requires --zone");
+    } else if !transfer_rules.is_empty() {
+        anyhow::bail!("--allow-transfer requires --zone");
+    } else if !dnssec_keys.is_empty() {
+        anyhow::bail!("--dnssec-key requires --zone");
+    } else if publish_zonemd {
+        anyhow::bail!("--zonemd requires --zone");
+    } else if !zsk_rollovers.is_empty() {
+        anyhow::bail!("--zsk-rollover requires --zone");
+    } else if !key_sets.is_empty() {
+        anyhow::bail!("--dnssec-key-set requires --zone");
+    }
+    let registry = Arc::new(Registry::new(register_keys));
+    if !register_address.is_empty() {
+        println!("Serving registered hosts under {}", lease_domain);
+        pipeline = pipeline.with(LeaseHandler::new(registry.clone(), lease_domain.clone()));
+    }
+    if !leases_path.is_empty() {
+        println!(
+            "Serving DHCP leases from {} under {}",
+            leases_path, lease_domain
+        );
+        pipeline = pipeline.with(LeaseHandler::new(
+            LeaseFile::open(&leases_path)?,
+            lease_domain,
+        ));
+    }
+    if rebind_protection {
+        println!("Rebinding protection enabled");
+        pipeline = pipeline.with(RebindingFilterHandler::new(rebind_allowed));
+    }
+    if !cache_redis.is_empty() && !cache_peers.is_empty() {
+        anyhow::bail!("--cache-peer can't be combined with --cache-redis");
+    }
+    if !cache_replication.is_empty() && cache_peers.is_empty() {
+        anyhow::bail!("--cache-replication requires --cache-peer");
+    }
+    if cache_size == 0 {
+        if max_stale.is_some() {
+            anyhow::bail!("--stale-if-error requires --cache-size");
+        } else if !cache_redis.is_empty() {
+            anyhow::bail!("--cache-redis requires --cache-size");
+        } else if !cache_peers.is_empty() {
+            anyhow::bail!("--cache-peer requires --cache-size");
+        } else if wire_cache {
+            anyhow::bail!("--wire-cache requires --cache-size");
+        }
+    }
+    if tls_options.pins_only && tls_options.spki_pins.is_empty() {
+        anyhow::bail!("--spki-pin-only requires --spki-pin");
+    }
+    if !bootstrap.is_empty() && resolver_address.is_empty() {
+        anyhow::bail!("--bootstrap requires --resolver");
+    }
+    // upstreams are probed for the admin endpoint
+    let mut status = Status::new();
+    #[cfg(feature = "json")]
+    for zone in served_zones {
+        status = status.zone(zone);
+    }
+    let upstream: Option<Arc<dyn Upstream>> = if !resolver_address.is_empty() {
+        let spec = resolver_address
+            .parse::<UpstreamSpec>()?
+            .with_bootstrap(&bootstrap);
+        println!("Forwarding to {}", spec);
+        let upstream = spec.build(upstream_timeout, &tls_options, socket_buffers)?;
+        status = status.upstream(&spec, upstream.clone());
+        Some(stats::measure(&spec, upstream))
+    } else if !resolv_conf_path.is_empty() {
+        // forwarding to ourselves would loop
+        let local_addresses = [
+            Some(udp_socket.local_addr()?),
+            listen::split_interface(&tcp_address).0.parse().ok(),
+        ];
+        let nameservers: Vec<_> = resolv_conf::read_nameservers(&resolv_conf_path)?
+            .into_iter()
+            .filter(|nameserver| !local_addresses.contains(&Some(*nameserver)))
+            .collect();
+        if nameservers.is_empty() {
+            anyhow::bail!("no usable nameserver in {}", resolv_conf_path);
+        }
 
-                        let received = DnsPacket::from(bp);
+        let mut upstreams = Vec::new();
+        for nameserver in nameservers {
+            println!("Forwarding to {} (from {})", nameserver, resolv_conf_path);
+            let upstream: Arc<dyn Upstream> = Arc::new(
+                UdpUpstream::new(nameserver.to_string())
+                    .with_timeout(upstream_timeout)
+                    .with_buffers(socket_buffers),
+            );
+            status = status.upstream(nameserver, upstream.clone());
+            upstreams.push(stats::measure(nameserver, upstream));
+        }
+        Some(Arc::new(FailoverUpstream::new(upstreams)))
+    } else {
+        None
+    };
+    #[cfg(feature = "dnssec")]
+    let mut validator: Option<Arc<Validator>> = None;
+    if !dnssec_validation.is_empty() {
+        #[cfg(feature = "dnssec")]
+        {
+            let upstream = upstream.clone().expect("validation requires an upstream");
+            let mode: ValidationMode = dnssec_validation.parse()?;
+            let mut dnssec_validator = Validator::new(upstream);
+            if !trust_anchor_path.is_empty() {
+                println!("Trusting DNSSEC anchors from {}", trust_anchor_path);
+                let anchors = validator::read_trust_anchors(&trust_anchor_path)?;
+                dnssec_validator = dnssec_validator.trust_anchors(anchors)?;
+            }
+            if mode == ValidationMode::Permissive {
+                dnssec_validator = dnssec_validator.permissive();
+            }
+            println!(
+                "Validating answers with DNSSEC ({} mode)",
+                dnssec_validation
+            );
+            validator = Some(Arc::new(dnssec_validator));
+        }
+        #[cfg(not(feature = "dnssec"))]
+        anyhow::bail!(
+            "--dnssec-validation requires the server to be built with the dnssec feature"
+        );
+    }
+    let forward_to = |upstream: Arc<dyn Upstream>| {
+        let mut forward = ForwardHandler::new(upstream);
+        if strip_ecs {
+            forward = forward.strip_client_subnet();
+        }
+        if drop_unknown_edns {
+            forward = forward.drop_unknown_options();
+        }
+        #[cfg(feature = "dnssec")]
+        if let Some(validator) = &validator {
+            forward = forward.validator(validator.clone());
+        }
+        forward
+    };
+    let new_cache = || {
+        let mut cache = CacheHandler::new(cache_size);
+        if let Some(max_stale) = max_stale {
+            cache = cache.stale_if_error(max_stale);
+        }
+        // cached as long as clients are told, TTL 0 answers are cached for the floor
+        if min_ttl.is_some() || max_ttl.is_some() {
+            cache = cache.clamp_ttl(min_ttl.unwrap_or(0), max_ttl.unwrap_or(u32::MAX));
+        }
+        cache
+    };
+    if cache_size > 0 {
+        println!("Caching answers for up to {} questions", cache_size);
+        if let Some(max_stale) = max_stale {
+            println!("Serving answers up to {:?} stale on errors", max_stale);
+        }
+    }
+    // routed clients get caches of their own, answers of their upstreams are not for others
+    if !client_routes.is_empty() {
+        if upstream.is_none() {
+            anyhow::bail!("--route requires --resolver or --resolv-conf");
+        }
+        let mut router = ClientRouteHandler::new();
+        for (networks, resolver) in client_routes {
+            let spec = resolver.parse::<UpstreamSpec>()?.with_bootstrap(&bootstrap);
+            let names: Vec<String> = networks.iter().map(Network::to_string).collect();
+            println!("Forwarding queries of {} to {}", names.join(", "), spec);
+            let route_upstream = spec.build(upstream_timeout, &tls_options, socket_buffers)?;
+            status = status.upstream(&spec, route_upstream.clone());
+            let mut routed = Pipeline::new();
+            if cache_size > 0 {
+                routed = routed.with(new_cache());
+            }
+            routed = routed.with(forward_to(stats::measure(&spec, route_upstream)));
+            router = router.route(networks, routed);
+        }
+        pipeline = pipeline.with(router);
+    }
+    if cache_size > 0 {
+        let mut cache = new_cache();
+        if !cache_redis.is_empty() {
+            let redis = RedisCache::new(&cache_redis)?;
+            println!("Sharing cached answers in Redis {}", redis);
+            cache = cache.shared(redis);
+        } else if !cache_peers.is_empty() {
+            let mut replicator = Replicator::new(cache_size);
+            if !cache_replication.is_empty() {
+                replicator.listen(&cache_replication, &cache_peers)?;
+                println!("Receiving cached answers on {}", cache_replication);
+            }
+            for peer in cache_peers.iter() {
+                println!("Replicating cached answers to {}", peer);
+                replicator = replicator.peer(peer.as_str());
+            }
+            cache = cache.shared(replicator);
+        }
+        pipeline = pipeline.with(cache);
+    }
+    // queries nobody answered (e.g. NODATA of the upstream without SOA) are refused, only
+    // without any upstream every question gets the same made-up answer (Codecrafters stage)
+    match upstream {
+        Some(upstream) => pipeline = pipeline.with(forward_to(upstream)),
+        None => pipeline = pipeline.with(StaticAnswerHandler::default()),
+    }
+    let pipeline = Arc::new(pipeline);
+    let status = Arc::new(status);
 
-                        println!("<<< Forwarding < Received DNS packet: {:#?}", received);
+    if !mdns_interfaces.is_empty() {
+        #[cfg(feature = "sockets")]
+        {
+            let interfaces = mdns_interfaces
+                .iter()
+                .map(|interface| interface.parse())
+                .collect::<Result<Vec<mdns::Interface>>>()?;
+            let mut repeater = mdns::Repeater::new(interfaces.iter().copied())?;
+            if let Some(rate) = mdns_rate {
+                repeater = repeater.rate(rate);
+            }
+            let interfaces: Vec<String> = interfaces.iter().map(ToString::to_string).collect();
+            println!("Relaying mDNS between {}", interfaces.join(", "));
+            status.spawn_listener("mDNS", move || repeater.serve());
+        }
+        #[cfg(not(feature = "sockets"))]
+        anyhow::bail!("--mdns-repeat requires the server to be built with the sockets feature");
+    } else if mdns_rate.is_some() {
+        anyhow::bail!("--mdns-rate requires --mdns-repeat");
+    }
 
-                        if received.header.id != forwarded_msg_id {
-                            anyhow::bail!(
-                                "Forwarding: ID mismatch: expected ID {}, got {}",
-                                forwarded_msg_id,
-                                received.header.id,
-                            );
-                        }
+    if !register_address.is_empty() {
+        status.spawn_listener("Registration", move || {
+            register::serve(&register_address, registry)
+        });
+    }
 
-                        for answer in received.answers {
-                            resolved_answers.push(answer);
-                        }
-                    }
-                }
+    if !json_api_address.is_empty() {
+        #[cfg(feature = "json")]
+        {
+            let pipeline = pipeline.clone();
+            status.spawn_listener("JSON API", move || {
+                let handler = move |request: &Request| pipeline.handle(request);
+                doh::serve(
+                    &json_api_address,
+                    proxy_protocol,
+                    connection_limits,
+                    handler,
+                )
+            });
+        }
+        #[cfg(not(feature = "json"))]
+        anyhow::bail!("--json-api requires the server to be built with the json feature");
+    }
 
-                // Response
-                let mut response = DnsPacket::new();
-                response.header.id = orig.header.id;
-                response.header.response = true;
-                response.header.opcode = orig.header.opcode;
-                response.header.recursion_desired = orig.header.recursion_desired;
-                response.header.rescode = match orig.header.opcode {
-                    0 => ResponseCode::NOERROR,
-                    _ => ResponseCode::NOTIMP, // Not implemented
-                };
-                response.questions = orig.questions;
-                response.header.question_entries = response.questions.len() as u16;
-
-                if resolved_answers.is_empty() {
-                    // manually creating answers
-                    for question in response.questions.iter() {
-                        let domain_name = question.domain_name.clone();
-                        let dns_answer = DnsRecord::new(
-                            domain_name,
-                            record::RecordType::A,
-                            record::RecordClass::IN,
-                            60,
-                            std::net::Ipv4Addr::new(8, 8, 8, 8),
-                        );
-                        response.answers.push(dns_answer);
-                    }
-                } else {
-                    response.answers = resolved_answers; // answers returned by extrenal resolver
+    if tls_listeners {
+        if !acme_domain.is_empty() {
+            #[cfg(feature = "acme")]
+            {
+                let mut acme = Acme::new(&acme_domain, &tls_cert_path, &tls_key_path);
+                if !acme_email.is_empty() {
+                    acme = acme.with_contact(&acme_email);
                 }
+                if !acme_directory.is_empty() {
+                    acme = acme.with_directory(&acme_directory);
+                }
+                match acme_http_address.as_str() {
+                    "" => acme.start("0.0.0.0:80")?,
+                    address => acme.start(address)?,
+                }
+            }
+            #[cfg(not(feature = "acme"))]
+            anyhow::bail!("--acme requires the server to be built with the acme feature");
+        }
+        #[cfg(feature = "tls")]
+        {
+            let certificates =
+                Arc::new(dot::CertificateFiles::load(&tls_cert_path, &tls_key_path)?);
+            if !dot_address.is_empty() {
+                let (pipeline, certificates) = (pipeline.clone(), certificates.clone());
+                let tsig_keys = tsig_keys.clone();
+                let mode = parse_mode("dot");
+                status.spawn_listener("DoT", move || {
+                    let handler = move |request: &Request| pipeline.handle(request);
+                    dot::serve(
+                        &dot_address,
+                        certificates,
+                        tsig_keys,
+                        connection_limits,
+                        mode,
+                        proxy_protocol,
+                        handler,
+                    )
+                });
+            }
+            if !https_address.is_empty() {
+                let pipeline = pipeline.clone();
+                status.spawn_listener("DoH (HTTP/2)", move || {
+                    let handler = move |request: &Request| pipeline.handle(request);
+                    https::serve(
+                        &https_address,
+                        certificates,
+                        connection_limits,
+                        proxy_protocol,
+                        handler,
+                    )
+                });
+            }
+        }
+        #[cfg(not(feature = "tls"))]
+        anyhow::bail!("--dot and --https require the server to be built with the tls feature");
+    }
 
-                response.header.answer_entries = response.answers.len() as u16;
-
-                println!(">>> Sent DNS packet: {:#?}", response);
+    if !tcp_address.is_empty() {
+        let pipeline = pipeline.clone();
+        let mode = parse_mode("tcp");
+        status.spawn_listener("TCP", move || {
+            tcp::serve(
+                &tcp_address,
+                tsig_keys,
+                connection_limits,
+                mode,
+                proxy_protocol,
+                move |request| pipeline.handle(request),
+            )
+        });
+    }
 
-                let bytes_packet = BytesPacket::from(response);
+    #[cfg(unix)]
+    {
+        use dns_starter_rust::unix;
 
-                println!("> Sent {} bytes to {}", bytes_packet.buf.len(), source);
+        if !unix_stream_path.is_empty() {
+            let pipeline = pipeline.clone();
+            status.spawn_listener("Unix stream socket", move || {
+                let path = std::path::Path::new(&unix_stream_path);
+                unix::serve_stream(path, move |request| pipeline.handle(request))
+            });
+        }
+        if !unix_datagram_path.is_empty() {
+            let pipeline = pipeline.clone();
+            status.spawn_listener("Unix datagram socket", move || {
+                let path = std::path::Path::new(&unix_datagram_path);
+                unix::serve_datagram(path, |request| pipeline.handle(request))
+            });
+        }
+    }
+    #[cfg(not(unix))]
+    if !unix_stream_path.is_empty() || !unix_datagram_path.is_empty() {
+        anyhow::bail!("unix domain sockets are not supported on this platform");
+    }
 
-                udp_socket
-                    .send_to(&bytes_packet.buf, source)
-                    .expect("Failed to send response");
+    if !admin_address.is_empty() {
+        status.start(health_interval);
+        let status = status.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = admin::serve(&admin_address, status) {
+                eprintln!("Admin endpoint failed: {:#}", e);
             }
-            Err(e) => {
-                eprintln!("Error receiving data: {}", e);
-                break;
-            }
-        }
+        });
+    }
+
+    let mut server = Server::builder()
+        .socket(udp_socket)
+        .pipeline(pipeline)
+        .max_udp_size(max_udp_size)
+        .parse_mode(parse_mode("udp"))
+        .anonymizer(anonymizer)
+        .dump_packets(dump_packets);
+    if !pcap_path.is_empty() {
+        println!("Capturing packets to {}", pcap_path);
+        server = server.pcap(PcapWriter::create(&pcap_path)?);
+    }
+    if !sample_path.is_empty() {
+        let rate = sample_rate.unwrap_or(sample::DEFAULT_RATE);
+        let max_size = sample_max_size.unwrap_or(sample::DEFAULT_MAX_SIZE);
+        println!(
+            "Sampling 1 in {} queries to {} (up to {} bytes)",
+            rate, sample_path, max_size
+        );
+        server = server.sampler(Sampler::create(&sample_path, rate, max_size)?);
+    } else if sample_rate.is_some() || sample_max_size.is_some() {
+        anyhow::bail!("--sample-rate and --sample-max-size require --sample");
+    }
+    if wire_cache {
+        println!("Answering repeated UDP queries with encoded responses");
+        server = server.wire_cache(cache_size);
+    }
+    if let Some(limit) = max_in_flight {
+        let policy: Overload = overload.unwrap_or_default();
+        let shed = match policy {
+            Overload::Drop => "dropping",
+            Overload::Refuse => "refusing",
+        };
+        println!(
+            "Answering up to {} UDP queries at the same time, {} the rest",
+            limit, shed
+        );
+        server = server.max_in_flight(limit, policy);
+    } else if overload.is_some() {
+        anyhow::bail!("--overload requires --max-in-flight");
     }
 
-    Ok(())
+    server.build()?.run()
 }
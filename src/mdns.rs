@@ -0,0 +1,218 @@
+//! mDNS repeater, relaying multicast DNS between networks of the server (e.g. VLANs)
+//!
+//! Devices find each other with mDNS (RFC 6762) only within their network, the messages
+//! are link-local multicast. The repeater listens to `224.0.0.251:5353` on each of the
+//! given interfaces and sends every message heard on one of them to all the others, so
+//! e.g. phones on the Wi-Fi VLAN discover printers on the wired one.
+//!
+//! Interfaces are given by the address of the server in them and the prefix length of their
+//! network (`192.168.20.1/24`), messages belong to the interface whose network contains
+//! their source. Messages sent by the repeater itself come from one of its addresses and are
+//! never relayed back. Each interface relays at most `rate` messages a second, the rest is
+//! dropped, so one chatty device can't flood the other networks. Only IPv4 is relayed.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+use crate::network::Network;
+
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_PORT: u16 = 5353;
+
+/// Messages relayed from each interface per second by default
+pub const DEFAULT_RATE: u32 = 100;
+
+/// Largest mDNS message (RFC 6762 section 17)
+const MAX_MESSAGE_SIZE: usize = 9000;
+
+/// Interface of the server: its address and the network the address is in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interface {
+    address: Ipv4Addr,
+    network: Network,
+}
+
+/// Parses `<address>/<prefix>`
+impl FromStr for Interface {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, _) = s
+            .split_once('/')
+            .with_context(|| format!("interface {:?} must be <address>/<prefix>", s))?;
+        let address = address
+            .parse()
+            .with_context(|| format!("invalid IPv4 interface address {:?}", address))?;
+        Ok(Self {
+            address,
+            network: s.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.network)
+    }
+}
+
+/// Messages relayed from one interface in the current second
+#[derive(Debug, Default)]
+struct RateLimit {
+    second: Option<Instant>,
+    count: u32,
+}
+
+impl RateLimit {
+    /// Counts a message, returns false if `rate` messages were relayed in the last second
+    fn allow(&mut self, rate: u32, now: Instant) -> bool {
+        match self.second {
+            Some(second) if now.duration_since(second) < Duration::from_secs(1) => {}
+            _ => {
+                self.second = Some(now);
+                self.count = 0;
+            }
+        }
+        self.count += 1;
+        self.count <= rate
+    }
+}
+
+/// Relays mDNS between interfaces
+pub struct Repeater {
+    interfaces: Vec<Interface>,
+    rate: u32,
+}
+
+impl Repeater {
+    pub fn new(interfaces: impl IntoIterator<Item = Interface>) -> Result<Self> {
+        let interfaces: Vec<Interface> = interfaces.into_iter().collect();
+        if interfaces.len() < 2 {
+            anyhow::bail!("mDNS is relayed between at least two interfaces");
+        }
+        Ok(Self {
+            interfaces,
+            rate: DEFAULT_RATE,
+        })
+    }
+
+    /// Relays at most `rate` messages a second from each interface
+    pub fn rate(mut self, rate: u32) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// Interface the message from `source` came from, `None` if it's from the repeater itself
+    fn interface_of(&self, source: IpAddr) -> Option<usize> {
+        if self
+            .interfaces
+            .iter()
+            .any(|interface| IpAddr::V4(interface.address) == source)
+        {
+            return None;
+        }
+        self.interfaces
+            .iter()
+            .position(|interface| interface.network.contains(source))
+    }
+
+    /// Relays messages until the socket fails
+    pub fn serve(self) -> Result<()> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        // mDNS responder of the host listens on the port too
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT));
+        socket
+            .bind(&address.into())
+            .with_context(|| format!("Failed to bind mDNS repeater to {}", address))?;
+        for interface in self.interfaces.iter() {
+            socket
+                .join_multicast_v4(&MDNS_GROUP, &interface.address)
+                .with_context(|| format!("failed to join mDNS group on {}", interface))?;
+        }
+        socket.set_multicast_loop_v4(false)?;
+        socket.set_multicast_ttl_v4(255)?; // RFC 6762 section 11
+        let socket: UdpSocket = socket.into();
+
+        let mut limits: Vec<RateLimit> = self
+            .interfaces
+            .iter()
+            .map(|_| RateLimit::default())
+            .collect();
+        let mut buf = [0; MAX_MESSAGE_SIZE];
+        loop {
+            let (size, source) = socket.recv_from(&mut buf)?;
+            let Some(from) = self.interface_of(source.ip()) else {
+                continue;
+            };
+            if size < 12 {
+                continue; // not even a DNS header
+            }
+            let limit = &mut limits[from];
+            if !limit.allow(self.rate, Instant::now()) {
+                if limit.count == self.rate + 1 {
+                    eprintln!(
+                        "mDNS: more than {} messages a second from {}, dropping them",
+                        self.rate, self.interfaces[from]
+                    );
+                }
+                continue;
+            }
+
+            for (i, interface) in self.interfaces.iter().enumerate() {
+                if i == from {
+                    continue;
+                }
+                SockRef::from(&socket).set_multicast_if_v4(&interface.address)?;
+                if let Err(e) = socket.send_to(&buf[..size], (MDNS_GROUP, MDNS_PORT)) {
+                    eprintln!("mDNS: failed to relay message to {}: {}", interface, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_are_assigned_to_interfaces() {
+        let repeater = Repeater::new([
+            "192.168.1.1/24".parse().unwrap(),
+            "192.168.20.1/24".parse().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(
+            repeater.interface_of("192.168.20.7".parse().unwrap()),
+            Some(1)
+        );
+        assert_eq!(
+            repeater.interface_of("192.168.1.7".parse().unwrap()),
+            Some(0)
+        );
+        assert_eq!(repeater.interface_of("10.0.0.7".parse().unwrap()), None);
+        // relayed by ourselves
+        assert_eq!(repeater.interface_of("192.168.20.1".parse().unwrap()), None);
+
+        assert!(Repeater::new(["192.168.1.1/24".parse().unwrap()]).is_err());
+        assert!("192.168.1.1".parse::<Interface>().is_err());
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut limit = RateLimit::default();
+        let start = Instant::now();
+        assert!(limit.allow(2, start));
+        assert!(limit.allow(2, start + Duration::from_millis(100)));
+        assert!(!limit.allow(2, start + Duration::from_millis(200)));
+        assert!(limit.allow(2, start + Duration::from_millis(1100)));
+    }
+}
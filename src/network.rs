@@ -0,0 +1,86 @@
+//! IP networks in CIDR notation, for access rules based on client addresses
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::Context;
+
+/// Range of addresses sharing the first `prefix` bits, e.g. `192.168.1.0/24`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    /// Returns true if `address` belongs to the network
+    ///
+    /// IPv4 addresses mapped to IPv6 (`::ffff:a.b.c.d`, from dual-stack sockets) match IPv4 networks.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses `<address>/<prefix>`, single address without prefix is a network of its own
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .with_context(|| format!("invalid network address {:?}", address))?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .with_context(|| format!("invalid prefix length {:?}", prefix))?,
+            None => max_prefix,
+        };
+
+        Ok(Self { address, prefix })
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_contains_address() {
+        let lan: Network = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains("192.168.1.200".parse().unwrap()));
+        assert!(lan.contains("::ffff:192.168.1.2".parse().unwrap()));
+        assert!(!lan.contains("192.168.2.1".parse().unwrap()));
+        assert!(!lan.contains("fd00::1".parse().unwrap()));
+
+        let any: Network = "::/0".parse().unwrap();
+        assert!(any.contains("2001:db8::1".parse().unwrap()));
+        let host: Network = "10.0.0.1".parse().unwrap();
+        assert!(!host.contains("10.0.0.2".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+    }
+}
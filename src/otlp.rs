@@ -0,0 +1,237 @@
+//! Export of query traces to an OpenTelemetry collector, by OTLP/HTTP with JSON encoding
+//!
+//! Spans are exported in batches from a background thread, so that queries never wait for
+//! the collector. When it can't keep up and [`QUEUE_SIZE`] spans are waiting, new spans are
+//! dropped.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value as Json};
+
+use crate::trace::{SpanData, SpanExporter, Value};
+
+/// Spans waiting for export at most
+pub const QUEUE_SIZE: usize = 2048;
+/// Spans exported in one request at most
+pub const BATCH_SIZE: usize = 512;
+/// How long a span waits for others to fill the batch at most
+pub const EXPORT_DELAY: Duration = Duration::from_secs(1);
+
+const DEFAULT_PORT: u16 = 4318;
+const TRACES_PATH: &str = "/v1/traces";
+const SERVICE_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Exporter to the collector at an `http://host[:port][/path]` endpoint
+///
+/// Without a path, spans are sent to the standard `/v1/traces`.
+pub struct OtlpExporter {
+    queue: SyncSender<SpanData>,
+}
+
+impl OtlpExporter {
+    pub fn new(endpoint: &str) -> Result<Self> {
+        let endpoint = Endpoint::parse(endpoint)?;
+        let (queue, spans) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::spawn(move || export_batches(&endpoint, spans));
+        Ok(Self { queue })
+    }
+}
+
+impl SpanExporter for OtlpExporter {
+    fn export(&self, span: SpanData) {
+        let _ = self.queue.try_send(span);
+    }
+}
+
+struct Endpoint {
+    /// `host:port` to connect to
+    address: String,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .with_context(|| format!("OTLP endpoint {} is not an http:// URL", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) if rest.len() > slash + 1 => (&rest[..slash], &rest[slash..]),
+            Some(slash) => (&rest[..slash], TRACES_PATH),
+            None => (rest, TRACES_PATH),
+        };
+        if authority.is_empty() {
+            anyhow::bail!("OTLP endpoint {} has no host", url);
+        }
+        let address = if authority
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.contains(']'))
+        {
+            authority.to_string()
+        } else {
+            format!("{}:{}", authority, DEFAULT_PORT)
+        };
+        Ok(Self {
+            address,
+            path: path.to_string(),
+        })
+    }
+}
+
+fn export_batches(endpoint: &Endpoint, spans: Receiver<SpanData>) {
+    while let Ok(first) = spans.recv() {
+        let deadline = Instant::now() + EXPORT_DELAY;
+        let mut batch = vec![first];
+        while batch.len() < BATCH_SIZE {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            match spans.recv_timeout(wait) {
+                Ok(span) => batch.push(span),
+                Err(_) => break,
+            }
+        }
+
+        if let Err(e) = post(endpoint, &encode(&batch)) {
+            eprintln!("OTLP: failed to export {} spans: {:#}", batch.len(), e);
+        }
+    }
+}
+
+fn post(endpoint: &Endpoint, body: &str) -> Result<()> {
+    let mut stream = TcpStream::connect(&endpoint.address)
+        .with_context(|| format!("Failed to connect to {}", endpoint.address))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        endpoint.path,
+        endpoint.address,
+        body.len(),
+        body
+    )?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => anyhow::bail!("collector answered {:?}", status_line.trim_end()),
+    }
+}
+
+/// `ExportTraceServiceRequest` with the spans
+fn encode(spans: &[SpanData]) -> String {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", &Value::from(SERVICE_NAME))],
+            },
+            "scopeSpans": [{
+                "scope": { "name": SERVICE_NAME },
+                "spans": spans.iter().map(encode_span).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+    .to_string()
+}
+
+fn encode_span(span: &SpanData) -> Json {
+    let mut encoded = json!({
+        "traceId": hex(&span.context.trace_id),
+        "spanId": hex(&span.context.span_id),
+        "name": span.name,
+        "kind": span.kind as u8,
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(span.end),
+        "attributes": span
+            .attributes
+            .iter()
+            .map(|(key, value)| attribute(key, value))
+            .collect::<Vec<_>>(),
+    });
+    if let Some(parent) = &span.parent_span_id {
+        encoded["parentSpanId"] = hex(parent).into();
+    }
+    if let Some(error) = &span.error {
+        // STATUS_CODE_ERROR
+        encoded["status"] = json!({ "code": 2, "message": error });
+    }
+    encoded
+}
+
+fn attribute(key: &str, value: &Value) -> Json {
+    let value = match value {
+        Value::String(s) => json!({ "stringValue": s }),
+        // 64-bit integers are strings in OTLP JSON
+        Value::Int(i) => json!({ "intValue": i.to_string() }),
+        Value::Bool(b) => json!({ "boolValue": b }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_nanos().to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{SpanContext, SpanKind};
+
+    #[test]
+    fn test_encode_span() {
+        let start = UNIX_EPOCH + Duration::from_millis(1500);
+        let span = SpanData {
+            context: SpanContext {
+                trace_id: [0xab; 16],
+                span_id: [1, 2, 3, 4, 5, 6, 7, 8],
+            },
+            parent_span_id: Some([0xff; 8]),
+            name: "upstream.exchange",
+            kind: SpanKind::Client,
+            start,
+            end: start + Duration::from_micros(250),
+            attributes: vec![
+                ("server.address", "1.1.1.1:53".into()),
+                ("dns.response.answers", 2usize.into()),
+            ],
+            error: Some("timed out".to_string()),
+        };
+
+        let request: Json = serde_json::from_str(&encode(&[span])).unwrap();
+        let encoded = &request["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(encoded["traceId"], "ab".repeat(16));
+        assert_eq!(encoded["spanId"], "0102030405060708");
+        assert_eq!(encoded["parentSpanId"], "ffffffffffffffff");
+        assert_eq!(encoded["kind"], 3);
+        assert_eq!(encoded["startTimeUnixNano"], "1500000000");
+        assert_eq!(encoded["endTimeUnixNano"], "1500250000");
+        assert_eq!(
+            encoded["attributes"][1],
+            json!({ "key": "dns.response.answers", "value": { "intValue": "2" } })
+        );
+        assert_eq!(encoded["status"]["code"], 2);
+    }
+
+    #[test]
+    fn test_endpoint_defaults() {
+        let endpoint = Endpoint::parse("http://collector").unwrap();
+        assert_eq!(
+            (endpoint.address.as_str(), endpoint.path.as_str()),
+            ("collector:4318", "/v1/traces")
+        );
+        let endpoint = Endpoint::parse("http://[::1]:4000/otlp/traces").unwrap();
+        assert_eq!(
+            (endpoint.address.as_str(), endpoint.path.as_str()),
+            ("[::1]:4000", "/otlp/traces")
+        );
+        assert!(Endpoint::parse("https://collector").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+//! Limit on queries answered at the same time
+//!
+//! Queries beyond the limit are shed right away instead of waiting for a turn, so an
+//! overloaded server answers what it can in time rather than everything late, and memory
+//! doesn't grow with the backlog. Shed queries are either dropped (clients retry, possibly
+//! elsewhere) or refused, and counted in the [`report`](crate::stats::report).
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// What happens to queries over the limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overload {
+    /// No response, as if the packet was lost
+    #[default]
+    Drop,
+    /// REFUSED response, so clients move to another server without waiting for a timeout
+    Refuse,
+}
+
+impl FromStr for Overload {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Self::Drop),
+            "refuse" => Ok(Self::Refuse),
+            _ => anyhow::bail!("unknown overload policy {} (expected drop or refuse)", s),
+        }
+    }
+}
+
+/// Queries being answered, `limit` of them at most
+#[derive(Debug)]
+pub struct InFlight {
+    limit: usize,
+    current: AtomicUsize,
+    policy: Overload,
+}
+
+impl InFlight {
+    pub fn new(limit: usize, policy: Overload) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            current: AtomicUsize::new(0),
+            policy,
+        })
+    }
+
+    /// What happens to queries over the limit
+    pub fn policy(&self) -> Overload {
+        self.policy
+    }
+
+    /// Slot for another query, `None` if `limit` queries are being answered already
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        self.current
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+                (current < self.limit).then_some(current + 1)
+            })
+            .ok()?;
+        Some(Permit(self.clone()))
+    }
+}
+
+/// Query being answered, its slot is freed when dropped
+#[derive(Debug)]
+pub struct Permit(Arc<InFlight>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.current.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permits_are_limited_until_released() {
+        let in_flight = InFlight::new(2, "refuse".parse().unwrap());
+        let first = in_flight.try_acquire().unwrap();
+        let _second = in_flight.try_acquire().unwrap();
+        assert!(in_flight.try_acquire().is_none());
+
+        drop(first);
+        assert!(in_flight.try_acquire().is_some());
+        assert_eq!(in_flight.policy(), Overload::Refuse);
+        assert!("queue".parse::<Overload>().is_err());
+    }
+
+    #[test]
+    fn test_zero_limit_sheds_everything() {
+        let in_flight = InFlight::new(0, Overload::default());
+        assert!(in_flight.try_acquire().is_none());
+        assert_eq!(in_flight.policy(), Overload::Drop);
+        assert!("Refuse".parse::<Overload>().is_err());
+        assert!("".parse::<Overload>().is_err());
+    }
+}
@@ -32,19 +32,31 @@ which relate to the query, but are not strictly answers for the
 question.
 */
 
-use crate::header::DnsHeader;
+use crate::domain_name::LookupTable;
+use crate::edns::{OptRecord, OPT_RECORD_TYPE};
+use crate::header::{DnsHeader, ResponseCode};
 use crate::question::DnsQuestion;
-use crate::record::DnsRecord;
-use crate::{domain_name::LookupTable, header::HEADER_LENGTH};
+use crate::record::{DnsRecord, RecordData, RecordType};
 
 use bytes::BytesMut;
+use std::fmt;
+
+/// Every client accepts UDP messages of this size
+pub const MIN_UDP_SIZE: u16 = 512;
 
 /// Whole DNS packet
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsPacket {
     pub header: DnsHeader,
     pub questions: Vec<DnsQuestion>,
     pub answers: Vec<DnsRecord>,
+    /// Authority section (e.g. SOA of negative answers)
+    pub authorities: Vec<DnsRecord>,
+    /// Additional section without OPT (e.g. glue addresses of name servers)
+    pub additionals: Vec<DnsRecord>,
+    /// EDNS pseudo-record from the additional section
+    pub opt: Option<OptRecord>,
 }
 
 impl DnsPacket {
@@ -53,49 +65,481 @@ impl DnsPacket {
             header: DnsHeader::new(),
             questions: Vec::new(),
             answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            opt: None,
+        }
+    }
+
+    /// Creates [`PacketBuilder`] which keeps header counts in sync with the sections
+    pub fn builder() -> PacketBuilder {
+        PacketBuilder::default()
+    }
+
+    /// Appends a question and counts it in the header (QDCOUNT)
+    pub fn add_question(&mut self, question: DnsQuestion) {
+        self.questions.push(question);
+        self.header.question_entries = self.questions.len() as u16;
+    }
+
+    /// Appends an answer and counts it in the header (ANCOUNT)
+    pub fn add_answer(&mut self, answer: DnsRecord) {
+        self.answers.push(answer);
+        self.header.answer_entries = self.answers.len() as u16;
+    }
+
+    /// Appends an authority record and counts it in the header (NSCOUNT)
+    pub fn add_authority(&mut self, authority: DnsRecord) {
+        self.authorities.push(authority);
+        self.header.authoritative_entries = self.authorities.len() as u16;
+    }
+
+    /// Appends an additional record and counts it in the header (ARCOUNT, OPT included)
+    pub fn add_additional(&mut self, additional: DnsRecord) {
+        self.additionals.push(additional);
+        self.header.additional_entries =
+            (self.additionals.len() + self.opt.is_some() as usize) as u16;
+    }
+}
+
+/// Fluent builder of [`DnsPacket`]
+///
+/// Section counts in the header (QDCOUNT, ANCOUNT, ...) are always set from the
+/// actual number of questions and records when the packet is built.
+///
+/// ```
+/// use dns_starter_rust::packet::DnsPacket;
+/// use dns_starter_rust::question::{DnsQuestion, QueryClass, QueryType};
+///
+/// let packet = DnsPacket::builder()
+///     .id(1234)
+///     .recursion_desired(true)
+///     .question(DnsQuestion::new("codecrafters.io".into(), QueryType::A, QueryClass::IN))
+///     .build();
+///
+/// assert_eq!(packet.header.question_entries, 1);
+/// ```
+#[derive(Debug, Default)]
+pub struct PacketBuilder {
+    packet: DnsPacket,
+}
+
+impl PacketBuilder {
+    /// Uses all header flags of `header`, counts are ignored
+    pub fn header(mut self, header: DnsHeader) -> Self {
+        self.packet.header = header;
+        self
+    }
+
+    pub fn id(mut self, id: u16) -> Self {
+        self.packet.header.id = id;
+        self
+    }
+
+    /// Marks packet as a response (QR)
+    pub fn response(mut self) -> Self {
+        self.packet.header.response = true;
+        self
+    }
+
+    pub fn opcode(mut self, opcode: u8) -> Self {
+        self.packet.header.opcode = opcode;
+        self
+    }
+
+    pub fn rescode(mut self, rescode: ResponseCode) -> Self {
+        self.packet.header.rescode = rescode;
+        self
+    }
+
+    pub fn authoritative_answer(mut self, authoritative_answer: bool) -> Self {
+        self.packet.header.authoritative_answer = authoritative_answer;
+        self
+    }
+
+    pub fn truncated_message(mut self, truncated_message: bool) -> Self {
+        self.packet.header.truncated_message = truncated_message;
+        self
+    }
+
+    pub fn recursion_desired(mut self, recursion_desired: bool) -> Self {
+        self.packet.header.recursion_desired = recursion_desired;
+        self
+    }
+
+    /// Authentic data (AD) - all data in the response were validated with DNSSEC (RFC 4035)
+    pub fn authed_data(mut self, authed_data: bool) -> Self {
+        self.packet.header.authed_data = authed_data;
+        self
+    }
+
+    pub fn recursion_available(mut self, recursion_available: bool) -> Self {
+        self.packet.header.recursion_available = recursion_available;
+        self
+    }
+
+    pub fn question(mut self, question: DnsQuestion) -> Self {
+        self.packet.questions.push(question);
+        self
+    }
+
+    pub fn questions(mut self, questions: impl IntoIterator<Item = DnsQuestion>) -> Self {
+        self.packet.questions.extend(questions);
+        self
+    }
+
+    pub fn answer(mut self, answer: DnsRecord) -> Self {
+        self.packet.answers.push(answer);
+        self
+    }
+
+    pub fn answers(mut self, answers: impl IntoIterator<Item = DnsRecord>) -> Self {
+        self.packet.answers.extend(answers);
+        self
+    }
+
+    pub fn authority(mut self, authority: DnsRecord) -> Self {
+        self.packet.authorities.push(authority);
+        self
+    }
+
+    pub fn authorities(mut self, authorities: impl IntoIterator<Item = DnsRecord>) -> Self {
+        self.packet.authorities.extend(authorities);
+        self
+    }
+
+    pub fn additional(mut self, additional: DnsRecord) -> Self {
+        self.packet.additionals.push(additional);
+        self
+    }
+
+    pub fn additionals(mut self, additionals: impl IntoIterator<Item = DnsRecord>) -> Self {
+        self.packet.additionals.extend(additionals);
+        self
+    }
+
+    /// Sets (or removes) the EDNS OPT pseudo-record
+    pub fn opt(mut self, opt: Option<OptRecord>) -> Self {
+        self.packet.opt = opt;
+        self
+    }
+
+    pub fn build(mut self) -> DnsPacket {
+        self.packet.header.question_entries = self.packet.questions.len() as u16;
+        self.packet.header.answer_entries = self.packet.answers.len() as u16;
+        self.packet.header.authoritative_entries = self.packet.authorities.len() as u16;
+        self.packet.header.additional_entries =
+            (self.packet.additionals.len() + self.packet.opt.is_some() as usize) as u16;
+        self.packet
+    }
+}
+
+/// dig-like output of the whole packet
+impl fmt::Display for DnsPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.header)?;
+
+        if let Some(opt) = &self.opt {
+            write!(f, "\n\n;; OPT PSEUDOSECTION:")?;
+            write!(
+                f,
+                "\n; EDNS: version: {}, flags:{}; udp: {}",
+                opt.version,
+                if opt.dnssec_ok { " do" } else { "" },
+                opt.udp_payload_size
+            )?;
+            for option in opt.options.iter() {
+                write!(f, "\n; OPTION {}: {} bytes", option.code, option.data.len())?;
+            }
+        }
+
+        if !self.questions.is_empty() {
+            write!(f, "\n;; QUESTION SECTION:")?;
+            for question in self.questions.iter() {
+                write!(f, "\n{}", question)?;
+            }
+        }
+
+        if !self.answers.is_empty() {
+            write!(f, "\n;; ANSWER SECTION:")?;
+            for answer in self.answers.iter() {
+                write!(f, "\n{}", answer)?;
+            }
+        }
+
+        if !self.authorities.is_empty() {
+            write!(f, "\n;; AUTHORITY SECTION:")?;
+            for authority in self.authorities.iter() {
+                write!(f, "\n{}", authority)?;
+            }
         }
+
+        if !self.additionals.is_empty() {
+            write!(f, "\n;; ADDITIONAL SECTION:")?;
+            for additional in self.additionals.iter() {
+                write!(f, "\n{}", additional)?;
+            }
+        }
+
+        Ok(())
     }
 }
 
-impl From<BytesPacket> for DnsPacket {
-    fn from(bytes_packet: BytesPacket) -> Self {
-        let mut buf = bytes_packet.buf;
+impl DnsPacket {
+    /// Parses packet from wire format, tolerating quirks of real-world servers
+    ///
+    /// Never panics and never reads past the end of `bytes`, malformed input results in [`ParseError`].
+    /// OPT record is taken out of the additional section into [`DnsPacket::opt`].
+    pub fn parse(bytes: &[u8]) -> Result<Self, ParseError> {
+        Self::parse_with(bytes, ParseMode::Lenient)
+    }
+
+    /// Parses packet, rejecting any violation of the RFCs, see [`ParseMode::Strict`]
+    pub fn parse_strict(bytes: &[u8]) -> Result<Self, ParseError> {
+        Self::parse_with(bytes, ParseMode::Strict)
+    }
+
+    /// Parses packet as strictly as `mode` says
+    pub fn parse_with(bytes: &[u8], mode: ParseMode) -> Result<Self, ParseError> {
+        let (packet, rest) = Self::read(bytes, mode)?;
+        if mode == ParseMode::Strict {
+            packet.check_strict(rest)?;
+        }
+        Ok(packet)
+    }
+
+    /// Finds what [`ParseMode::Strict`] rejects in the parsed packet and `rest` after it
+    fn check_strict(&self, rest: &[u8]) -> Result<(), ParseError> {
+        // smuggled data, or a record read shorter than it is (parser bug), zeros are
+        // the padding of messages read into fixed buffers
+        if rest.iter().any(|&byte| byte != 0) {
+            return Err(ParseError::TrailingData(rest.len()));
+        }
+        if self.header.z {
+            return Err(ParseError::Violation("reserved Z bit set"));
+        }
+        let records = || {
+            self.answers
+                .iter()
+                .chain(self.authorities.iter())
+                .chain(self.additionals.iter())
+        };
+        // OPT owned by the root in the additional section was taken out already
+        if records().any(|record| record.record_type == RecordType::UNKNOWN(OPT_RECORD_TYPE)) {
+            return Err(ParseError::Violation("misplaced OPT record"));
+        }
+        // lenient parsing keeps them as unknown data
+        let misshapen_address = records().any(|record| {
+            matches!(record.record_type, RecordType::A | RecordType::AAAA)
+                && matches!(record.data, RecordData::Unknown(_))
+        });
+        if misshapen_address {
+            return Err(ParseError::Violation("address record of wrong length"));
+        }
+        Ok(())
+    }
+
+    /// Packet and the bytes after its last record
+    fn read(bytes: &[u8], mode: ParseMode) -> Result<(Self, &[u8]), ParseError> {
+        let mut buf = bytes;
 
         // Header
         let mut header = DnsHeader::new();
-        header.read_bytes(&mut buf);
+        header.read_bytes(&mut buf)?;
 
-        let mut lookup_table = LookupTable::new(HEADER_LENGTH); // For message decompression
+        // For message decompression
+        let mut lookup_table = match mode {
+            ParseMode::Lenient => LookupTable::lenient(bytes),
+            ParseMode::Strict => LookupTable::strict(bytes),
+        };
 
         // Questions
-        let mut questions = vec![];
+        let mut questions = Vec::with_capacity(section_capacity(header.question_entries, buf));
         for _i in 0..header.question_entries {
-            let question = DnsQuestion::from_bytes(&mut buf, &mut lookup_table);
+            let question = DnsQuestion::from_bytes(&mut buf, &mut lookup_table)?;
             questions.push(question);
         }
 
         // Answers
-        let mut answers = vec![];
+        let mut answers = Vec::with_capacity(section_capacity(header.answer_entries, buf));
         for _i in 0..header.answer_entries {
-            let answer = DnsRecord::from_bytes(&mut buf, &mut lookup_table);
+            let answer = DnsRecord::from_bytes(&mut buf, &mut lookup_table)?;
             answers.push(answer);
         }
 
-        Self {
+        // Authority
+        let mut authorities =
+            Vec::with_capacity(section_capacity(header.authoritative_entries, buf));
+        for _i in 0..header.authoritative_entries {
+            let authority = DnsRecord::from_bytes(&mut buf, &mut lookup_table)?;
+            authorities.push(authority);
+        }
+
+        // Additional
+        let mut additionals = Vec::with_capacity(section_capacity(header.additional_entries, buf));
+        let mut opt = None;
+        for _i in 0..header.additional_entries {
+            let additional = DnsRecord::from_bytes(&mut buf, &mut lookup_table)?;
+
+            match additional {
+                DnsRecord {
+                    domain_name,
+                    record_type: RecordType::UNKNOWN(OPT_RECORD_TYPE),
+                    class,
+                    ttl,
+                    data: RecordData::Unknown(rdata),
+                } if domain_name.is_root() => {
+                    if opt.is_some() && mode == ParseMode::Strict {
+                        return Err(ParseError::Violation("more than one OPT record"));
+                    }
+                    opt = Some(OptRecord::from_parts(class.into(), ttl, &rdata)?);
+                }
+                additional => additionals.push(additional),
+            }
+        }
+
+        let packet = Self {
             header,
             questions,
             answers,
+            authorities,
+            additionals,
+            opt,
+        };
+        Ok((packet, buf))
+    }
+}
+
+impl DnsPacket {
+    /// Largest UDP response the sender of this query accepts, but at most `max_size`
+    ///
+    /// Without EDNS it is 512 bytes (RFC 1035 section 4.2.1), with EDNS the size from the OPT record.
+    pub fn udp_response_limit(&self, max_size: u16) -> usize {
+        let max_size = max_size.max(MIN_UDP_SIZE);
+        match &self.opt {
+            Some(opt) => opt.udp_payload_size.clamp(MIN_UDP_SIZE, max_size) as usize,
+            None => MIN_UDP_SIZE as usize,
+        }
+    }
+
+    /// Whether the sender of this query wants DNSSEC records (DO bit of EDNS, RFC 3225)
+    pub fn dnssec_ok(&self) -> bool {
+        self.opt.as_ref().is_some_and(|opt| opt.dnssec_ok)
+    }
+
+    /// Whether the sender of this query understands the AD flag (RFC 6840 section 5.8)
+    pub fn wants_authed_data(&self) -> bool {
+        self.dnssec_ok() || self.header.authed_data
+    }
+
+    /// Wire format not longer than `max_size`
+    ///
+    /// Packets which don't fit are sent without answers and with the TC flag set,
+    /// so the client can retry over TCP.
+    pub fn to_bytes_limited(&self, max_size: usize) -> BytesPacket {
+        let bytes_packet = BytesPacket::from(self);
+        if bytes_packet.buf.len() <= max_size {
+            return bytes_packet;
+        }
+
+        // counts are set from the sections when encoding
+        let mut truncated = Self {
+            header: self.header,
+            questions: self.questions.clone(),
+            opt: self.opt.clone(),
+            ..Self::new()
+        };
+        truncated.header.truncated_message = true;
+        BytesPacket::from(truncated)
+    }
+}
+
+impl TryFrom<BytesPacket> for DnsPacket {
+    type Error = ParseError;
+
+    fn try_from(bytes_packet: BytesPacket) -> Result<Self, Self::Error> {
+        Self::parse(&bytes_packet.buf)
+    }
+}
+
+/// How strictly messages are parsed
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ParseMode {
+    /// Tolerates common quirks: compression pointers into names inside opaque RDATA,
+    /// misplaced or repeated OPT records, address records of odd length (kept as unknown
+    /// data), reserved bits and trailing data
+    #[default]
+    Lenient,
+    /// Rejects any violation of the RFCs, for fuzzing and validation
+    Strict,
+}
+
+/// Reasons why a packet could not be parsed
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// Packet ended in the middle of a field
+    UnexpectedEnd,
+    /// Compression pointer does not point to a previously read name
+    InvalidPointer(u16),
+    /// Label length with reserved bits set
+    InvalidLabel(u8),
+    /// Domain name is longer than 255 octets
+    NameTooLong,
+    /// RDATA does not match its type or length
+    InvalidRecordData,
+    /// Bytes left after the last record (only [`ParseMode::Strict`])
+    TrailingData(usize),
+    /// Message breaking a rule of the RFCs (only [`ParseMode::Strict`])
+    Violation(&'static str),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEnd => f.write_str("unexpected end of packet"),
+            Self::InvalidPointer(pos) => write!(f, "invalid compression pointer to {}", pos),
+            Self::InvalidLabel(len) => write!(f, "invalid label type {:#04x}", len),
+            Self::NameTooLong => f.write_str("domain name too long"),
+            Self::InvalidRecordData => f.write_str("invalid record data"),
+            Self::TrailingData(len) => write!(f, "{} bytes after the last record", len),
+            Self::Violation(rule) => write!(f, "RFC violation: {}", rule),
         }
     }
 }
 
+impl std::error::Error for ParseError {}
+
+/// Checks that `buf` has at least `len` bytes left (`Buf` getters panic otherwise)
+pub(crate) fn ensure_remaining(buf: &impl bytes::Buf, len: usize) -> Result<(), ParseError> {
+    if buf.remaining() < len {
+        return Err(ParseError::UnexpectedEnd);
+    }
+    Ok(())
+}
+
 //////////////////////////////////////////////////////////////////////////////
 
+/// Smallest question (root name, type and class), records are even larger
+const MIN_ENTRY_LENGTH: usize = 5;
+
+/// Capacity for a section of `count` entries, at most as many as fit into the rest of the message
+///
+/// Counts come from the sender, a forged one doesn't make the parser allocate much.
+fn section_capacity(count: u16, rest: &[u8]) -> usize {
+    (count as usize).min(rest.len() / MIN_ENTRY_LENGTH)
+}
+
 /// Binary representation of DNS packet
 pub struct BytesPacket {
     pub buf: BytesMut,
 }
 
+impl Default for BytesPacket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BytesPacket {
     pub fn new() -> Self {
         Self {
@@ -104,35 +548,52 @@ impl BytesPacket {
     }
 }
 
+/// Section counts in the header are those of the sections, whatever the header says
 impl From<DnsPacket> for BytesPacket {
     fn from(dns_packet: DnsPacket) -> Self {
+        Self::from(&dns_packet)
+    }
+}
+
+/// Encodes the packet without taking (or cloning) it
+impl From<&DnsPacket> for BytesPacket {
+    fn from(dns_packet: &DnsPacket) -> Self {
         let mut bp = BytesPacket::new();
 
-        // Header
-        dns_packet.header.write_bytes(&mut bp.buf);
+        // Header, counts are those of the sections (OPT is kept apart from other records)
+        let mut header = dns_packet.header;
+        header.question_entries = dns_packet.questions.len() as u16;
+        header.answer_entries = dns_packet.answers.len() as u16;
+        header.authoritative_entries = dns_packet.authorities.len() as u16;
+        header.additional_entries =
+            (dns_packet.additionals.len() + dns_packet.opt.is_some() as usize) as u16;
+        header.write_bytes(&mut bp.buf);
 
-        let mut lookup_table = LookupTable::new(HEADER_LENGTH); // For message compression
+        let mut lookup_table = LookupTable::new(0); // For message compression
 
         // Questions
-        for i in 0..dns_packet.header.question_entries as usize {
-            let question = dns_packet
-                .questions
-                .get(i)
-                .expect("questions should not be empty if correct count was set");
-
+        for question in dns_packet.questions.iter() {
             question.write_bytes(&mut bp.buf, &mut lookup_table);
         }
 
         // Answers
-        for i in 0..dns_packet.header.answer_entries as usize {
-            let answer = dns_packet
-                .answers
-                .get(i)
-                .expect("answers should not be empty if correct count was set");
-
+        for answer in dns_packet.answers.iter() {
             answer.write_bytes(&mut bp.buf, &mut lookup_table);
         }
 
+        // Authority
+        for authority in dns_packet.authorities.iter() {
+            authority.write_bytes(&mut bp.buf, &mut lookup_table);
+        }
+
+        // Additional
+        for additional in dns_packet.additionals.iter() {
+            additional.write_bytes(&mut bp.buf, &mut lookup_table);
+        }
+        if let Some(opt) = &dns_packet.opt {
+            opt.write_bytes(&mut bp.buf);
+        }
+
         bp
     }
 }
@@ -143,8 +604,9 @@ mod tests {
 
     use crate::{
         domain_name::DomainName,
+        header::HEADER_LENGTH,
         question::{QueryClass, QueryType},
-        record::{RecordClass, RecordType},
+        record::{RecordClass, RecordData, RecordType, Soa},
     };
 
     use super::*;
@@ -158,12 +620,10 @@ mod tests {
         dns_packet.header.recursion_available = true;
         dns_packet.header.rescode = crate::header::ResponseCode::SERVFAIL;
 
-        dns_packet.header.question_entries = 1;
         let domain_name = DomainName::from("codecrafters.io.");
         let dns_question = DnsQuestion::new(domain_name, QueryType::A, QueryClass::IN);
-        dns_packet.questions.push(dns_question);
+        dns_packet.add_question(dns_question);
 
-        dns_packet.header.answer_entries = 1;
         let domain_name = DomainName::from("codecrafters.io.");
         let dns_answer = DnsRecord::new(
             domain_name,
@@ -172,12 +632,188 @@ mod tests {
             3600,
             Ipv4Addr::new(127, 0, 0, 1),
         );
-        dns_packet.answers.push(dns_answer);
+        dns_packet.add_answer(dns_answer);
 
         let bytes_packet = BytesPacket::from(dns_packet.clone());
 
-        let parsed_dns_packet = DnsPacket::from(bytes_packet);
+        let parsed_dns_packet = DnsPacket::try_from(bytes_packet).unwrap();
 
         assert_eq!(dns_packet, parsed_dns_packet);
     }
+
+    #[test]
+    fn test_counts_follow_sections() {
+        let mut dns_packet = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from("codecrafters.io."),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+        // sections changed behind the header's back
+        dns_packet.header.question_entries = 3;
+        dns_packet.answers.push(DnsRecord::new(
+            DomainName::from("codecrafters.io."),
+            RecordType::A,
+            RecordClass::IN,
+            60,
+            Ipv4Addr::new(8, 8, 8, 8),
+        ));
+
+        let parsed = DnsPacket::parse(&BytesPacket::from(dns_packet.clone()).buf).unwrap();
+        assert_eq!(parsed.header.question_entries, 1);
+        assert_eq!(parsed.header.answer_entries, 1);
+        assert_eq!(parsed.answers, dns_packet.answers);
+    }
+
+    #[test]
+    fn test_malformed_packets_are_rejected() {
+        let dns_packet = DnsPacket::builder()
+            .id(1234)
+            .question(DnsQuestion::new(
+                DomainName::from("codecrafters.io."),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .answer(DnsRecord::new(
+                DomainName::from("codecrafters.io."),
+                RecordType::A,
+                RecordClass::IN,
+                60,
+                Ipv4Addr::new(8, 8, 8, 8),
+            ))
+            .build();
+        let bytes = BytesPacket::from(dns_packet).buf;
+
+        for len in 0..bytes.len() {
+            assert_eq!(
+                DnsPacket::parse(&bytes[..len]),
+                Err(ParseError::UnexpectedEnd)
+            );
+        }
+
+        // pointer to itself
+        let mut looped = bytes[..HEADER_LENGTH as usize].to_vec();
+        looped.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+        assert_eq!(
+            DnsPacket::parse(&looped),
+            Err(ParseError::InvalidPointer(12))
+        );
+    }
+
+    #[test]
+    fn test_trailing_data_is_rejected_in_strict_mode() {
+        let dns_packet = DnsPacket::builder()
+            .id(1234)
+            .question(DnsQuestion::new(
+                DomainName::from("codecrafters.io."),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+        let mut bytes = BytesPacket::from(dns_packet.clone()).buf.to_vec();
+        assert_eq!(DnsPacket::parse_strict(&bytes).unwrap(), dns_packet);
+
+        // padding of a fixed buffer
+        bytes.extend_from_slice(&[0; 16]);
+        assert_eq!(DnsPacket::parse_strict(&bytes).unwrap(), dns_packet);
+
+        bytes.extend_from_slice(b"smuggled");
+        assert_eq!(DnsPacket::parse(&bytes).unwrap(), dns_packet);
+        assert_eq!(
+            DnsPacket::parse_strict(&bytes),
+            Err(ParseError::TrailingData(24))
+        );
+    }
+
+    #[test]
+    fn test_lenient_and_strict_modes() {
+        let mut message = vec![0, 1, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0];
+        // SRV, its target is opaque RDATA for the parser
+        message.extend_from_slice(b"\x07example\x03com\x00");
+        message.extend_from_slice(&[0, 33, 0, 1, 0, 0, 0, 60, 0, 26, 0, 0, 0, 0, 0, 53]);
+        let target = message.len() as u8;
+        message.extend_from_slice(b"\x06target\x07example\x03com\x00");
+        // A record of the target, owner compressed to point into the SRV
+        message.extend_from_slice(&[0xC0, target, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+
+        let packet = DnsPacket::parse(&message).unwrap();
+        assert_eq!(
+            packet.answers[1].domain_name,
+            DomainName::from("target.example.com")
+        );
+        assert_eq!(
+            DnsPacket::parse_strict(&message),
+            Err(ParseError::InvalidPointer(target as u16))
+        );
+
+        let query = DnsPacket::builder()
+            .question(DnsQuestion::new(
+                DomainName::from("codecrafters.io."),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .opt(Some(OptRecord::new(1232)))
+            .build();
+        let mut bytes = BytesPacket::from(query).buf.to_vec();
+        assert!(DnsPacket::parse_strict(&bytes).is_ok());
+
+        bytes[3] |= 0x40; // Z
+        assert!(DnsPacket::parse(&bytes).is_ok());
+        assert_eq!(
+            DnsPacket::parse_strict(&bytes),
+            Err(ParseError::Violation("reserved Z bit set"))
+        );
+        bytes[3] &= !0x40;
+
+        // the OPT once more
+        let opt = bytes[bytes.len() - 11..].to_vec();
+        bytes.extend_from_slice(&opt);
+        bytes[11] = 2;
+        assert!(DnsPacket::parse(&bytes).is_ok());
+        assert_eq!(
+            DnsPacket::parse_strict(&bytes),
+            Err(ParseError::Violation("more than one OPT record"))
+        );
+    }
+
+    #[test]
+    fn test_compression_pointers_use_real_positions() {
+        let soa = Soa {
+            mname: DomainName::from("ns.b.com"),
+            rname: DomainName::from("hostmaster.b.com"),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+        };
+        let dns_packet = DnsPacket::builder()
+            .response()
+            .question(DnsQuestion::new(
+                DomainName::from("a.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .question(DnsQuestion::new(
+                DomainName::from("b.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .authority(DnsRecord::new(
+                DomainName::from("b.com"),
+                RecordType::SOA,
+                RecordClass::IN,
+                3600,
+                RecordData::Soa(soa),
+            ))
+            .build();
+        let bytes = BytesPacket::from(dns_packet.clone()).buf;
+
+        // a.com at 12, b.com after its QTYPE and QCLASS at 23 (with "com" compressed)
+        let authority = 12 + (7 + 4) + (2 + 2 + 4);
+        assert_eq!(bytes[authority..authority + 2], [0xC0, 23]);
+
+        assert_eq!(DnsPacket::parse_strict(&bytes).unwrap(), dns_packet);
+    }
 }
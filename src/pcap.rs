@@ -0,0 +1,425 @@
+//! Capture of DNS traffic in pcap format (readable by Wireshark, tcpdump, ...)
+//!
+//! Only the DNS payload is known to the server, so every packet is wrapped in synthesized
+//! IP and UDP headers built from the socket addresses. The link type is `LINKTYPE_RAW`,
+//! i.e. records start directly with the IPv4 or IPv6 header.
+//!
+//! [`PcapReader`] reads captures back (also those made by tcpdump on Ethernet or loopback
+//! interfaces) and extracts UDP datagrams from them.
+
+use std::fs::File;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+const MAGIC: u32 = 0xa1b2c3d4; // microsecond timestamps
+const MAGIC_NANOS: u32 = 0xa1b23c4d; // nanosecond timestamps
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const SNAPLEN: u32 = 65535;
+
+const LINKTYPE_NULL: u32 = 0; // BSD loopback
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113; // tcpdump -i any
+
+const IPPROTO_UDP: u8 = 17;
+const UDP_HEADER_LENGTH: usize = 8;
+
+/// Writes packets to a pcap file, safe to share between threads
+pub struct PcapWriter {
+    /// The file and its size so far
+    file: Mutex<(File, u64)>,
+    /// Size the file must not exceed
+    max_size: Option<u64>,
+}
+
+impl PcapWriter {
+    /// Creates (or truncates) the file and writes pcap global header
+    pub fn create(path: &str) -> Result<Self> {
+        let mut file =
+            File::create(path).with_context(|| format!("Failed to create pcap file {}", path))?;
+
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC.to_le_bytes());
+        header.extend_from_slice(&VERSION_MAJOR.to_le_bytes());
+        header.extend_from_slice(&VERSION_MINOR.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes()); // GMT offset
+        header.extend_from_slice(&0u32.to_le_bytes()); // timestamp accuracy
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        file.write_all(&header)?;
+
+        Ok(Self {
+            file: Mutex::new((file, header.len() as u64)),
+            max_size: None,
+        })
+    }
+
+    /// Packets which would make the file larger than `max_size` bytes are not written
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Appends UDP datagram with DNS `payload` sent from `source` to `destination`
+    pub fn write_udp(
+        &self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        payload: &[u8],
+    ) -> Result<()> {
+        self.write_records(&[(source, destination, payload)])?;
+        Ok(())
+    }
+
+    /// Appends `query` from `client` to `server` and the `response` back, both or none
+    ///
+    /// Returns false if they didn't fit in the maximal size of the file.
+    pub fn write_exchange(
+        &self,
+        client: SocketAddr,
+        server: SocketAddr,
+        query: &[u8],
+        response: &[u8],
+    ) -> Result<bool> {
+        self.write_records(&[(client, server, query), (server, client, response)])
+    }
+
+    fn write_records(&self, packets: &[(SocketAddr, SocketAddr, &[u8])]) -> Result<bool> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut records = Vec::new();
+        for (source, destination, payload) in packets {
+            let packet = ip_udp_packet(*source, *destination, payload);
+            records.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+            records.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+            records.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // captured length
+            records.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // original length
+            records.extend_from_slice(&packet);
+        }
+
+        let mut file = self.file.lock().expect("pcap lock poisoned");
+        let (file, size) = &mut *file;
+        if self
+            .max_size
+            .is_some_and(|max_size| *size + records.len() as u64 > max_size)
+        {
+            return Ok(false);
+        }
+        // whole records in one write, so the file stays readable when the server is killed
+        file.write_all(&records)?;
+        *size += records.len() as u64;
+
+        Ok(true)
+    }
+}
+
+/// Reads packets from a pcap file
+pub struct PcapReader {
+    bytes: Vec<u8>,
+    pos: usize,
+    big_endian: bool,
+    nanos: bool,
+    linktype: u32,
+}
+
+/// Single captured packet
+pub struct PcapRecord {
+    /// Time since UNIX epoch
+    pub timestamp: Duration,
+    /// Captured bytes, starting with the link layer header
+    pub data: Vec<u8>,
+    linktype: u32,
+}
+
+/// UDP datagram extracted from a captured packet
+pub struct UdpDatagram<'a> {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: &'a [u8],
+}
+
+impl PcapReader {
+    pub fn open(path: &str) -> Result<Self> {
+        let bytes =
+            std::fs::read(path).with_context(|| format!("Failed to read pcap file {}", path))?;
+        Self::from_bytes(bytes)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let Some(magic) = bytes.get(..4) else {
+            anyhow::bail!("not a pcap file");
+        };
+        let magic = [magic[0], magic[1], magic[2], magic[3]];
+
+        let (big_endian, nanos) = if u32::from_le_bytes(magic) == MAGIC {
+            (false, false)
+        } else if u32::from_be_bytes(magic) == MAGIC {
+            (true, false)
+        } else if u32::from_le_bytes(magic) == MAGIC_NANOS {
+            (false, true)
+        } else if u32::from_be_bytes(magic) == MAGIC_NANOS {
+            (true, true)
+        } else {
+            anyhow::bail!("not a pcap file (pcapng is not supported)");
+        };
+
+        let mut reader = Self {
+            bytes,
+            pos: 20,
+            big_endian,
+            nanos,
+            linktype: 0,
+        };
+        reader.linktype = reader.u32().context("truncated pcap header")?;
+
+        Ok(reader)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes: [u8; 4] = self.bytes.get(self.pos..self.pos + 4)?.try_into().ok()?;
+        self.pos += 4;
+        Some(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+}
+
+/// Iterates over records, stops at the end of file or at a truncated record
+impl Iterator for PcapReader {
+    type Item = PcapRecord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let seconds = self.u32()?;
+        let fraction = self.u32()?;
+        let captured_length = self.u32()? as usize;
+        let _original_length = self.u32()?;
+
+        let data = self
+            .bytes
+            .get(self.pos..self.pos + captured_length)?
+            .to_vec();
+        self.pos += captured_length;
+
+        let fraction = match self.nanos {
+            true => Duration::from_nanos(fraction as u64),
+            false => Duration::from_micros(fraction as u64),
+        };
+
+        Some(PcapRecord {
+            timestamp: Duration::from_secs(seconds as u64) + fraction,
+            data,
+            linktype: self.linktype,
+        })
+    }
+}
+
+impl PcapRecord {
+    /// UDP datagram carried by the packet, `None` for other protocols or unsupported link types
+    pub fn udp(&self) -> Option<UdpDatagram<'_>> {
+        let data = &self.data[..];
+        let ip = match self.linktype {
+            LINKTYPE_RAW => data,
+            LINKTYPE_NULL => data.get(4..)?,
+            LINKTYPE_ETHERNET => {
+                // skip 802.1Q VLAN tags
+                let mut offset = 12;
+                while data.get(offset..offset + 2)? == [0x81, 0x00] {
+                    offset += 4;
+                }
+                data.get(offset + 2..)?
+            }
+            LINKTYPE_LINUX_SLL => data.get(16..)?,
+            _ => return None,
+        };
+
+        let (source, destination, udp) = match ip.first()? >> 4 {
+            4 => {
+                let header_length = ((ip[0] & 0x0F) as usize) * 4;
+                if *ip.get(9)? != IPPROTO_UDP {
+                    return None;
+                }
+                let source: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+                let destination: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+                (
+                    IpAddr::V4(Ipv4Addr::from(source)),
+                    IpAddr::V4(Ipv4Addr::from(destination)),
+                    ip.get(header_length..)?,
+                )
+            }
+            6 => {
+                // extension headers are not followed
+                if *ip.get(6)? != IPPROTO_UDP {
+                    return None;
+                }
+                let source: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+                let destination: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+                (
+                    IpAddr::V6(Ipv6Addr::from(source)),
+                    IpAddr::V6(Ipv6Addr::from(destination)),
+                    ip.get(40..)?,
+                )
+            }
+            _ => return None,
+        };
+
+        let source_port = u16::from_be_bytes([*udp.first()?, *udp.get(1)?]);
+        let destination_port = u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]);
+        let length = u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]) as usize;
+
+        Some(UdpDatagram {
+            source: SocketAddr::new(source, source_port),
+            destination: SocketAddr::new(destination, destination_port),
+            payload: udp.get(UDP_HEADER_LENGTH..length.max(UDP_HEADER_LENGTH))?,
+        })
+    }
+}
+
+/// Wraps `payload` in UDP and IPv4/IPv6 headers
+///
+/// Mixed address families (IPv4 client of IPv6 socket) are written as IPv6.
+fn ip_udp_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_length = UDP_HEADER_LENGTH + payload.len();
+    let mut udp = Vec::with_capacity(udp_length);
+    udp.extend_from_slice(&source.port().to_be_bytes());
+    udp.extend_from_slice(&destination.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_length as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]); // checksum, filled in below
+    udp.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(40 + udp_length);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet.push(0x45); // version 4, header length 5 words
+            packet.push(0); // DSCP, ECN
+            packet.extend_from_slice(&((20 + udp_length) as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0]); // identification
+            packet.extend_from_slice(&[0x40, 0]); // don't fragment
+            packet.push(64); // TTL
+            packet.push(IPPROTO_UDP);
+            packet.extend_from_slice(&[0, 0]); // header checksum, filled in below
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let checksum = internet_checksum(&packet, 0);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            let mut pseudo_header = Vec::with_capacity(12);
+            pseudo_header.extend_from_slice(&src.octets());
+            pseudo_header.extend_from_slice(&dst.octets());
+            pseudo_header.extend_from_slice(&[0, IPPROTO_UDP]);
+            pseudo_header.extend_from_slice(&(udp_length as u16).to_be_bytes());
+            set_udp_checksum(&mut udp, &pseudo_header);
+        }
+        (src, dst) => {
+            let src = to_ipv6(src).octets();
+            let dst = to_ipv6(dst).octets();
+            packet.extend_from_slice(&[0x60, 0, 0, 0]); // version 6, traffic class, flow label
+            packet.extend_from_slice(&(udp_length as u16).to_be_bytes());
+            packet.push(IPPROTO_UDP); // next header
+            packet.push(64); // hop limit
+            packet.extend_from_slice(&src);
+            packet.extend_from_slice(&dst);
+
+            let mut pseudo_header = Vec::with_capacity(40);
+            pseudo_header.extend_from_slice(&src);
+            pseudo_header.extend_from_slice(&dst);
+            pseudo_header.extend_from_slice(&(udp_length as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+            set_udp_checksum(&mut udp, &pseudo_header);
+        }
+    }
+
+    packet.extend_from_slice(&udp);
+    packet
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn set_udp_checksum(udp: &mut [u8], pseudo_header: &[u8]) {
+    let partial = !internet_checksum(pseudo_header, 0);
+    let checksum = match internet_checksum(udp, partial) {
+        0 => 0xFFFF, // zero means "no checksum" in UDP
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// One's complement checksum (RFC 1071), `initial` is a not yet complemented partial sum
+fn internet_checksum(bytes: &[u8], initial: u16) -> u16 {
+    let mut sum = initial as u32;
+    for chunk in bytes.chunks(2) {
+        let word = match chunk {
+            [a, b] => u16::from_be_bytes([*a, *b]),
+            [a] => u16::from_be_bytes([*a, 0]),
+            _ => unreachable!(),
+        };
+        sum += word as u32;
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_written_packets_are_read_back() {
+        let path = std::env::temp_dir().join(format!("dns-test-{}.pcap", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let client: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let server: SocketAddr = "[::1]:2053".parse().unwrap();
+
+        let writer = PcapWriter::create(path).unwrap();
+        writer.write_udp(client, server, b"query").unwrap();
+        writer.write_udp(server, client, b"response").unwrap();
+        drop(writer);
+
+        let records: Vec<_> = PcapReader::open(path).unwrap().collect();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(records.len(), 2);
+        let udp = records[0].udp().unwrap();
+        assert_eq!(udp.source, "[::ffff:127.0.0.1]:40000".parse().unwrap());
+        assert_eq!(udp.destination, server);
+        assert_eq!(udp.payload, b"query");
+        assert_eq!(records[1].udp().unwrap().payload, b"response");
+    }
+
+    #[test]
+    fn test_size_limit_keeps_exchanges_whole() {
+        let path = std::env::temp_dir().join(format!("dns-test-{}-limit.pcap", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let client: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let server: SocketAddr = "127.0.0.1:2053".parse().unwrap();
+
+        // global header, one exchange of two 16 + 20 + 8 + 5 byte records and a bit more
+        let writer = PcapWriter::create(path).unwrap().max_size(24 + 2 * 49 + 20);
+        assert!(writer
+            .write_exchange(client, server, b"query", b"reply")
+            .unwrap());
+        assert!(!writer
+            .write_exchange(client, server, b"query", b"reply")
+            .unwrap());
+        drop(writer);
+
+        let records = PcapReader::open(path).unwrap().count();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(records, 2);
+    }
+}
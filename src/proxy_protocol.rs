@@ -0,0 +1,166 @@
+//! PROXY protocol version 2 (HAProxy)
+//!
+//! TCP load balancers in front of the server prepend every connection with a binary header
+//! carrying the address of the real client, which would otherwise be hidden behind
+//! the balancer's own address. With `--proxy-protocol`, all stream listeners (TCP, DoT,
+//! DoH and the JSON API) expect the header; UDP can't carry it. Connection limits per client still
+//! apply to the balancer's address, the header is read only after the connection is
+//! admitted.
+//!
+//! ```text
+//!  0: signature "\r\n\r\n\0\r\nQUIT\n" (12 bytes)
+//! 12: version (high nibble, 2) and command (low nibble, 0 = LOCAL, 1 = PROXY)
+//! 13: address family (high nibble) and transport protocol (low nibble)
+//! 14: length of the rest (addresses and TLVs)
+//! 16: source address, destination address, source port, destination port, TLVs
+//! ```
+
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::connections::Deadline;
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+const COMMAND_LOCAL: u8 = 0x0;
+const COMMAND_PROXY: u8 = 0x1;
+
+const FAMILY_INET: u8 = 0x1;
+const FAMILY_INET6: u8 = 0x2;
+
+/// Reads the header from the beginning of the connection, nothing more is consumed
+///
+/// Returns the address of the original client, `None` when the balancer speaks for itself
+/// (LOCAL command, e.g. health checks) or the client isn't an IP one.
+pub fn read_header(stream: &mut impl Read) -> Result<Option<SocketAddr>> {
+    let mut header = [0; 16];
+    stream.read_exact(&mut header)?;
+
+    if header[..12] != SIGNATURE {
+        anyhow::bail!("missing PROXY protocol v2 header");
+    }
+    let (version, command) = (header[12] >> 4, header[12] & 0x0F);
+    if version != 2 {
+        anyhow::bail!("unsupported PROXY protocol version {}", version);
+    }
+
+    let mut addresses = vec![0; u16::from_be_bytes([header[14], header[15]]) as usize];
+    stream.read_exact(&mut addresses)?;
+
+    match command {
+        COMMAND_LOCAL => return Ok(None),
+        COMMAND_PROXY => {}
+        _ => anyhow::bail!("unknown PROXY protocol command {}", command),
+    }
+
+    // only the source is interesting, TLVs after the addresses are ignored
+    let source = match header[13] >> 4 {
+        FAMILY_INET if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[0..4].try_into()?;
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port)
+        }
+        FAMILY_INET6 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[0..16].try_into()?;
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)
+        }
+        FAMILY_INET | FAMILY_INET6 => anyhow::bail!("truncated PROXY protocol addresses"),
+        _ => return Ok(None), // unspecified or unix
+    };
+
+    Ok(Some(source))
+}
+
+/// Client of an accepted connection, taken from the header which must arrive within
+/// `timeout`, the peer itself if the header doesn't name anyone
+pub fn read_client(stream: &TcpStream, timeout: Duration) -> Result<SocketAddr> {
+    let peer = stream.peer_addr()?;
+    let source = read_header(&mut Deadline::new(stream, timeout))
+        .with_context(|| format!("invalid PROXY protocol header from {}", peer))?;
+    Ok(source.unwrap_or(peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_address_is_read() {
+        let mut header = SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]); // v2 PROXY, TCP over IPv4
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xC3, 0x50, 0x01, 0xBB]);
+        header.extend_from_slice(b"GET /");
+
+        let mut stream = &header[..];
+        let source = read_header(&mut stream).unwrap();
+        assert_eq!(source, Some("192.0.2.1:50000".parse().unwrap()));
+        assert_eq!(stream, b"GET /");
+
+        let mut local = SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut &local[..]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_malformed_headers_are_rejected() {
+        let header = |rest: &[u8]| [&SIGNATURE[..], rest].concat();
+        let rejected = |header: &[u8]| read_header(&mut &header[..]).unwrap_err().to_string();
+
+        let text = b"PROXY TCP4 192.0.2.1 198.51.100.1 50000 53\r\n";
+        assert_eq!(rejected(text), "missing PROXY protocol v2 header");
+        assert_eq!(
+            rejected(&header(&[0x11, 0x11, 0, 0])),
+            "unsupported PROXY protocol version 1"
+        );
+        assert_eq!(
+            rejected(&header(&[0x22, 0x11, 0, 0])),
+            "unknown PROXY protocol command 2"
+        );
+        assert_eq!(
+            rejected(&header(&[0x21, 0x11, 0, 4, 192, 0, 2, 1])),
+            "truncated PROXY protocol addresses"
+        );
+        assert_eq!(
+            rejected(&header(&[0x21, 0x21, 0, 12, 192, 0, 2, 1])),
+            "failed to fill whole buffer"
+        );
+        assert!(read_header(&mut &SIGNATURE[..]).is_err());
+    }
+
+    #[test]
+    fn test_missing_header_times_out() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, peer) = listener.accept().unwrap();
+
+        let error = read_client(&server, Duration::from_millis(50)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("invalid PROXY protocol header from {}", peer)
+        );
+        assert_eq!(error.root_cause().to_string(), "data not received in time");
+    }
+
+    #[test]
+    fn test_client_is_taken_from_connection_header() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, peer) = listener.accept().unwrap();
+
+        let mut header = SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]); // v2 PROXY, TCP over IPv4
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xC3, 0x50, 0x00, 0x35]);
+        header.extend_from_slice(&SIGNATURE);
+        header.extend_from_slice(&[0x20, 0x00, 0, 0]); // v2 LOCAL
+        std::io::Write::write_all(&mut client, &header).unwrap();
+
+        let timeout = Duration::from_secs(1);
+        let source = read_client(&server, timeout).unwrap();
+        assert_eq!(source, "192.0.2.1:50000".parse().unwrap());
+        assert_eq!(read_client(&server, timeout).unwrap(), peer);
+    }
+}
@@ -1,4 +1,9 @@
+use std::fmt;
+
+use bytes::{BufMut, BytesMut};
+
 use crate::domain_name::{DomainName, LookupTable};
+use crate::packet::{ensure_remaining, ParseError};
 
 /// The question section contains a list of questions (usually just 1) that the sender wants to ask the receiver.
 /// This section is present in both query and reply packets.
@@ -31,6 +36,7 @@ use crate::domain_name::{DomainName, LookupTable};
 ///           can match more than one type of RR.c
 ///
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsQuestion {
     pub domain_name: DomainName,
     pub query_type: QueryType,
@@ -46,25 +52,43 @@ impl DnsQuestion {
         }
     }
 
-    pub fn from_bytes(buf: &mut impl bytes::Buf, lookup_table: &mut LookupTable) -> Self {
-        let domain_name = DomainName::from_bytes(buf, lookup_table);
+    /// Creates [`DnsQuestion`] from wire format
+    pub fn from_bytes(
+        buf: &mut impl bytes::Buf,
+        lookup_table: &mut LookupTable,
+    ) -> Result<Self, ParseError> {
+        let domain_name = DomainName::from_bytes(buf, lookup_table)?;
+        ensure_remaining(buf, 4)?;
         let query_type = QueryType::from(buf.get_u16());
         let class = QueryClass::from(buf.get_u16());
 
-        Self::new(domain_name, query_type, class)
+        Ok(Self::new(domain_name, query_type, class))
     }
 
-    pub fn write_bytes(&self, buf: &mut impl bytes::BufMut, lookup_table: &mut LookupTable) {
+    /// Converts [`DnsQuestion`] to wire format
+    pub fn write_bytes(&self, buf: &mut BytesMut, lookup_table: &mut LookupTable) {
         self.domain_name.write_bytes(buf, lookup_table);
 
-        buf.put_u16(QueryType::A.into());
-        buf.put_u16(QueryClass::IN.into());
+        buf.put_u16(self.query_type.clone().into());
+        buf.put_u16(self.class.clone().into());
+    }
+}
+
+/// dig-like question line, e.g. `;example.com.  IN  A`
+impl fmt::Display for DnsQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            ";{:#}\t\t{}\t{}",
+            self.domain_name, self.class, self.query_type
+        )
     }
 }
 
 #[allow(clippy::upper_case_acronyms)]
 #[repr(u16)]
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum QueryType {
     A = 1, // 1 a host address
     UNKNOWN(u16),
@@ -88,9 +112,37 @@ impl From<QueryType> for u16 {
     }
 }
 
+/// Parses mnemonic (`A`), generic (`TYPE1`, RFC 3597) or numeric (`1`) query type
+impl std::str::FromStr for QueryType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        let number = upper.strip_prefix("TYPE").unwrap_or(&upper);
+
+        match upper.as_str() {
+            "A" => Ok(Self::A),
+            _ => number
+                .parse::<u16>()
+                .map(Self::from)
+                .map_err(|_| anyhow::anyhow!("unknown query type {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UNKNOWN(n) => write!(f, "TYPE{}", n), // RFC 3597 section 5
+            known => write!(f, "{:?}", known),
+        }
+    }
+}
+
 #[allow(clippy::upper_case_acronyms)]
 #[repr(u16)]
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum QueryClass {
     IN = 1, // 1 the Internet
     CS = 2, // 2 the CSNET class (Obsolete - used only for examples in some obsolete RFCs)
@@ -120,7 +172,38 @@ impl From<u16> for QueryClass {
             2 => Self::CS,
             3 => Self::CH,
             4 => Self::HS,
+            255 => Self::ANY,
             n => Self::UNKNOWN(n),
         }
     }
 }
+
+impl fmt::Display for QueryClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UNKNOWN(n) => write!(f, "CLASS{}", n), // RFC 3597 section 5
+            known => write!(f, "{:?}", known),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_type_and_class_are_preserved() {
+        for (query_type, class) in [
+            (QueryType::UNKNOWN(28), QueryClass::IN),   // AAAA
+            (QueryType::UNKNOWN(16), QueryClass::CH),   // TXT, e.g. version.bind
+            (QueryType::UNKNOWN(255), QueryClass::ANY), // ANY
+        ] {
+            let question = DnsQuestion::new(DomainName::from("example.com"), query_type, class);
+            let mut buf = BytesMut::new();
+            question.write_bytes(&mut buf, &mut LookupTable::new(0));
+
+            let parsed = DnsQuestion::from_bytes(&mut &buf[..], &mut LookupTable::new(buf.len()));
+            assert_eq!(parsed.unwrap(), question);
+        }
+    }
+}
@@ -1,6 +1,10 @@
-use std::net::Ipv4Addr;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use bytes::{BufMut, BytesMut};
 
 use crate::domain_name::{DomainName, LookupTable};
+use crate::packet::{ensure_remaining, ParseError};
 
 /// Resource record format
 ///
@@ -55,13 +59,49 @@ use crate::domain_name::{DomainName, LookupTable};
 ///                 the RDATA field is a 4 octet ARPA Internet address.
 ///
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsRecord {
     pub domain_name: DomainName,
     pub record_type: RecordType,
     pub class: RecordClass,
     pub ttl: u32,
-    pub length: u16,
-    pub data: Ipv4Addr,
+    pub data: RecordData,
+}
+
+/// RDATA of the record
+///
+/// Types with domain names inside are decoded, since the names may be compressed
+/// and point to other parts of the message. Everything else is kept opaque.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    /// NS, CNAME or PTR target
+    Name(DomainName),
+    Mx {
+        preference: u16,
+        exchange: DomainName,
+    },
+    Soa(Soa),
+    /// RDATA of other types as is (RFC 3597)
+    Unknown(Vec<u8>),
+}
+
+/// Start of a zone of authority (RFC 1035 section 3.3.13)
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Soa {
+    /// Primary name server of the zone
+    pub mname: DomainName,
+    /// Mailbox of the person responsible for the zone
+    pub rname: DomainName,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    /// TTL of negative answers from the zone (RFC 2308 section 4)
+    pub minimum: u32,
 }
 
 impl DnsRecord {
@@ -70,51 +110,283 @@ impl DnsRecord {
         record_type: RecordType,
         class: RecordClass,
         ttl: u32,
-        data: Ipv4Addr,
+        data: impl Into<RecordData>,
     ) -> Self {
         Self {
             domain_name,
             record_type,
             class,
             ttl,
-            length: 4,
-            data,
+            data: data.into(),
         }
     }
 
-    pub fn from_bytes(buf: &mut impl bytes::Buf, lookup_table: &mut LookupTable) -> Self {
-        let domain_name = DomainName::from_bytes(buf, lookup_table);
-        let query_type = RecordType::from(buf.get_u16());
+    /// TTL for caching the absence of data, if this is SOA of a negative answer (RFC 2308 section 5)
+    pub fn negative_ttl(&self) -> Option<u32> {
+        match &self.data {
+            RecordData::Soa(soa) => Some(self.ttl.min(soa.minimum)),
+            _ => None,
+        }
+    }
+
+    /// Creates [`DnsRecord`] from wire format
+    pub fn from_bytes(
+        buf: &mut impl bytes::Buf,
+        lookup_table: &mut LookupTable,
+    ) -> Result<Self, ParseError> {
+        let domain_name = DomainName::from_bytes(buf, lookup_table)?;
+        ensure_remaining(buf, 10)?;
+        let record_type = RecordType::from(buf.get_u16());
         let class = RecordClass::from(buf.get_u16());
         let ttl = buf.get_u32();
-        let _length = buf.get_u16();
-        let data = Ipv4Addr::new(buf.get_u8(), buf.get_u8(), buf.get_u8(), buf.get_u8());
+        let length = buf.get_u16() as usize;
 
-        Self::new(domain_name, query_type, class, ttl, data)
+        let data = RecordData::from_bytes(&record_type, length, buf, lookup_table)?;
+
+        Ok(Self::new(domain_name, record_type, class, ttl, data))
     }
 
-    pub fn write_bytes(&self, buf: &mut impl bytes::BufMut, lookup_table: &mut LookupTable) {
+    /// Converts [`DnsRecord`] to wire format
+    pub fn write_bytes(&self, buf: &mut BytesMut, lookup_table: &mut LookupTable) {
         self.domain_name.write_bytes(buf, lookup_table);
-        buf.put_u16(RecordType::A.into());
-        buf.put_u16(RecordClass::IN.into());
+        buf.put_u16(self.record_type.clone().into());
+        buf.put_u16(self.class.clone().into());
         buf.put_u32(self.ttl);
-        buf.put_u16(self.length);
-        buf.put(&self.data.octets()[..]);
+
+        // RDLENGTH is known only after (possibly compressed) RDATA is written
+        let length_pos = buf.len();
+        buf.put_u16(0);
+        self.data.write_bytes(buf, lookup_table);
+        let length = (buf.len() - length_pos - 2) as u16;
+        buf[length_pos..length_pos + 2].copy_from_slice(&length.to_be_bytes());
+    }
+}
+
+impl RecordData {
+    /// Creates [`RecordData`] of the given type from `length` bytes of wire format
+    pub fn from_bytes(
+        record_type: &RecordType,
+        length: usize,
+        buf: &mut impl bytes::Buf,
+        lookup_table: &mut LookupTable,
+    ) -> Result<Self, ParseError> {
+        ensure_remaining(buf, length)?;
+        let end = buf.remaining() - length;
+
+        let data = match (record_type, length) {
+            (RecordType::A, 4) => Self::A(Ipv4Addr::new(
+                buf.get_u8(),
+                buf.get_u8(),
+                buf.get_u8(),
+                buf.get_u8(),
+            )),
+            (RecordType::AAAA, 16) => Self::Aaaa(Ipv6Addr::from(buf.get_u128())),
+            (RecordType::NS | RecordType::CNAME | RecordType::PTR, _) => {
+                Self::Name(DomainName::from_bytes(buf, lookup_table)?)
+            }
+            (RecordType::MX, _) => {
+                ensure_remaining(buf, 2)?;
+                Self::Mx {
+                    preference: buf.get_u16(),
+                    exchange: DomainName::from_bytes(buf, lookup_table)?,
+                }
+            }
+            (RecordType::SOA, _) => {
+                let mname = DomainName::from_bytes(buf, lookup_table)?;
+                let rname = DomainName::from_bytes(buf, lookup_table)?;
+                ensure_remaining(buf, 20)?;
+                Self::Soa(Soa {
+                    mname,
+                    rname,
+                    serial: buf.get_u32(),
+                    refresh: buf.get_u32(),
+                    retry: buf.get_u32(),
+                    expire: buf.get_u32(),
+                    minimum: buf.get_u32(),
+                })
+            }
+            _ => {
+                let mut data = vec![0; length];
+                buf.copy_to_slice(&mut data);
+                Self::Unknown(data)
+            }
+        };
+
+        // decoded RDATA must take exactly RDLENGTH bytes
+        if buf.remaining() != end {
+            return Err(ParseError::InvalidRecordData);
+        }
+
+        Ok(data)
+    }
+
+    /// Writes wire format, names are compressed via `lookup_table`
+    pub fn write_bytes(&self, buf: &mut BytesMut, lookup_table: &mut LookupTable) {
+        match self {
+            Self::A(address) => buf.put_slice(&address.octets()),
+            Self::Aaaa(address) => buf.put_slice(&address.octets()),
+            Self::Name(name) => name.write_bytes(buf, lookup_table),
+            Self::Mx {
+                preference,
+                exchange,
+            } => {
+                buf.put_u16(*preference);
+                exchange.write_bytes(buf, lookup_table);
+            }
+            Self::Soa(soa) => {
+                soa.mname.write_bytes(buf, lookup_table);
+                soa.rname.write_bytes(buf, lookup_table);
+                for value in [soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                    buf.put_u32(value);
+                }
+            }
+            Self::Unknown(data) => buf.put_slice(data),
+        }
+    }
+
+    /// Address of A/AAAA record
+    pub fn address(&self) -> Option<IpAddr> {
+        match self {
+            Self::A(address) => Some(IpAddr::V4(*address)),
+            Self::Aaaa(address) => Some(IpAddr::V6(*address)),
+            _ => None,
+        }
+    }
+
+    /// Uncompressed wire format, e.g. for RDLENGTH or hex presentation
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        match self {
+            // names written with a fresh table each, so nothing is compressed
+            Self::Soa(soa) => {
+                soa.mname.write_bytes(&mut buf, &mut LookupTable::new(0));
+                soa.rname.write_bytes(&mut buf, &mut LookupTable::new(0));
+                for value in [soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                    buf.put_u32(value);
+                }
+            }
+            data => data.write_bytes(&mut buf, &mut LookupTable::new(0)),
+        }
+        buf
+    }
+}
+
+/// dig-like record line, e.g. `example.com.  300  IN  A  1.2.3.4`
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#}\t{}\t{}\t{}\t{}",
+            self.domain_name, self.ttl, self.class, self.record_type, self.data
+        )
+    }
+}
+
+impl From<Ipv4Addr> for RecordData {
+    fn from(address: Ipv4Addr) -> Self {
+        Self::A(address)
+    }
+}
+
+impl From<Ipv6Addr> for RecordData {
+    fn from(address: Ipv6Addr) -> Self {
+        Self::Aaaa(address)
+    }
+}
+
+impl From<IpAddr> for RecordData {
+    fn from(address: IpAddr) -> Self {
+        match address {
+            IpAddr::V4(address) => Self::A(address),
+            IpAddr::V6(address) => Self::Aaaa(address),
+        }
+    }
+}
+
+impl PartialEq<Ipv4Addr> for RecordData {
+    fn eq(&self, other: &Ipv4Addr) -> bool {
+        matches!(self, Self::A(address) if address == other)
+    }
+}
+
+/// Presentation format of RDATA, unknown types in the generic format (RFC 3597 section 5)
+impl fmt::Display for RecordData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::A(address) => write!(f, "{}", address),
+            Self::Aaaa(address) => write!(f, "{}", address),
+            Self::Name(name) => write!(f, "{}", name),
+            Self::Mx {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, exchange),
+            Self::Soa(soa) => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                soa.mname, soa.rname, soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum
+            ),
+            Self::Unknown(data) => {
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    f.write_str(" ")?;
+                }
+                data.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+        }
     }
 }
 
 #[allow(clippy::upper_case_acronyms)]
 #[repr(u16)]
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RecordType {
-    A = 1, // 1 a host address
+    A = 1,        // 1 a host address
+    NS = 2,       // 2 an authoritative name server
+    CNAME = 5,    // 5 the canonical name for an alias
+    SOA = 6,      // 6 marks the start of a zone of authority
+    PTR = 12,     // 12 a domain name pointer
+    MX = 15,      // 15 mail exchange
+    AAAA = 28,    // 28 an IPv6 host address (RFC 3596)
+    DS = 43,      // 43 delegation signer (RFC 4034)
+    RRSIG = 46,   // 46 signature of an RRset (RFC 4034)
+    NSEC = 47,    // 47 next secure name, authenticated denial of existence (RFC 4034)
+    DNSKEY = 48,  // 48 public key of a zone (RFC 4034)
+    NSEC3 = 50,   // 50 hashed next secure name (RFC 5155)
+    CDS = 59,     // 59 child copy of DS (RFC 7344)
+    CDNSKEY = 60, // 60 child copy of DNSKEY for the parent's DS (RFC 7344)
+    ZONEMD = 63,  // 63 message digest of the zone (RFC 8976)
     UNKNOWN(u16),
 }
 
+impl RecordType {
+    /// Type of the address record (A or AAAA) for the address
+    pub fn of_address(address: &IpAddr) -> Self {
+        match address {
+            IpAddr::V4(_) => Self::A,
+            IpAddr::V6(_) => Self::AAAA,
+        }
+    }
+}
+
 impl From<u16> for RecordType {
     fn from(value: u16) -> Self {
         match value {
             1 => Self::A,
+            2 => Self::NS,
+            5 => Self::CNAME,
+            6 => Self::SOA,
+            12 => Self::PTR,
+            15 => Self::MX,
+            28 => Self::AAAA,
+            43 => Self::DS,
+            46 => Self::RRSIG,
+            47 => Self::NSEC,
+            48 => Self::DNSKEY,
+            50 => Self::NSEC3,
+            59 => Self::CDS,
+            60 => Self::CDNSKEY,
+            63 => Self::ZONEMD,
             n => Self::UNKNOWN(n),
         }
     }
@@ -124,14 +396,70 @@ impl From<RecordType> for u16 {
     fn from(value: RecordType) -> u16 {
         match value {
             RecordType::A => 1,
+            RecordType::NS => 2,
+            RecordType::CNAME => 5,
+            RecordType::SOA => 6,
+            RecordType::PTR => 12,
+            RecordType::MX => 15,
+            RecordType::AAAA => 28,
+            RecordType::DS => 43,
+            RecordType::RRSIG => 46,
+            RecordType::NSEC => 47,
+            RecordType::DNSKEY => 48,
+            RecordType::NSEC3 => 50,
+            RecordType::CDS => 59,
+            RecordType::CDNSKEY => 60,
+            RecordType::ZONEMD => 63,
             RecordType::UNKNOWN(n) => n,
         }
     }
 }
 
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UNKNOWN(n) => write!(f, "TYPE{}", n), // RFC 3597 section 5
+            known => write!(f, "{:?}", known),
+        }
+    }
+}
+
+/// Parses mnemonic (`MX`), generic (`TYPE15`, RFC 3597) or numeric (`15`) record type
+impl std::str::FromStr for RecordType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        let number = upper.strip_prefix("TYPE").unwrap_or(&upper);
+
+        match upper.as_str() {
+            "A" => Ok(Self::A),
+            "NS" => Ok(Self::NS),
+            "CNAME" => Ok(Self::CNAME),
+            "SOA" => Ok(Self::SOA),
+            "PTR" => Ok(Self::PTR),
+            "MX" => Ok(Self::MX),
+            "AAAA" => Ok(Self::AAAA),
+            "DS" => Ok(Self::DS),
+            "RRSIG" => Ok(Self::RRSIG),
+            "NSEC" => Ok(Self::NSEC),
+            "DNSKEY" => Ok(Self::DNSKEY),
+            "NSEC3" => Ok(Self::NSEC3),
+            "CDS" => Ok(Self::CDS),
+            "CDNSKEY" => Ok(Self::CDNSKEY),
+            "ZONEMD" => Ok(Self::ZONEMD),
+            _ => number
+                .parse::<u16>()
+                .map(Self::from)
+                .map_err(|_| anyhow::anyhow!("unknown record type {:?}", s)),
+        }
+    }
+}
+
 #[allow(clippy::upper_case_acronyms)]
 #[repr(u16)]
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RecordClass {
     IN = 1, // 1 the Internet
     CS = 2, // 2 the CSNET class (Obsolete - used only for examples in some obsolete RFCs)
@@ -163,3 +491,12 @@ impl From<u16> for RecordClass {
         }
     }
 }
+
+impl fmt::Display for RecordClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UNKNOWN(n) => write!(f, "CLASS{}", n), // RFC 3597 section 5
+            known => write!(f, "{:?}", known),
+        }
+    }
+}
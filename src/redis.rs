@@ -0,0 +1,251 @@
+//! Redis as a cache shared by several servers, see [`CacheHandler::shared`]
+//!
+//! Servers behind a load balancer store the answers they get from upstreams in Redis and
+//! look there when their own (in-memory) cache misses, so a name resolved by one of them
+//! is warm in all of them. Entries expire in Redis together with their records.
+//!
+//! Only the few commands needed are spoken (RESP2): `AUTH` and `SELECT` when connecting,
+//! `GET` and `SET ... PX`. Redis is given as `redis://[:password@]host[:port][/db]`.
+//! When it can't be reached, it isn't asked again for [`RETRY_INTERVAL`], so queries don't
+//! wait for its timeouts and the server keeps going with its own cache.
+//!
+//! [`CacheHandler::shared`]: crate::handler::CacheHandler::shared
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::handler::CacheBackend;
+
+/// Port of Redis if the address has none
+const DEFAULT_PORT: u16 = 6379;
+
+/// Time to wait for connection and replies, the cache is useless if slower than upstreams
+const TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How long Redis isn't used after a failure
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Connection to Redis, reopened when it breaks
+pub struct RedisCache {
+    address: String,
+    password: Option<String>,
+    database: u32,
+    connection: Mutex<Connection>,
+}
+
+#[derive(Default)]
+struct Connection {
+    stream: Option<BufReader<TcpStream>>,
+    /// Last failure, Redis is skipped until [`RETRY_INTERVAL`] after it
+    failed_at: Option<Instant>,
+}
+
+/// Reply to a command
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+}
+
+impl RedisCache {
+    /// Redis at `redis://[:password@]host[:port][/db]` or `host[:port]`, connected lazily
+    pub fn new(url: &str) -> Result<Self> {
+        let rest = url.strip_prefix("redis://").unwrap_or(url);
+        let (password, rest) = match rest.rsplit_once('@') {
+            Some((credentials, rest)) => {
+                // user name (Redis 6 ACL) is not supported, only the password
+                let password = credentials.rsplit(':').next().unwrap_or(credentials);
+                (Some(password.to_string()), rest)
+            }
+            None => (None, rest),
+        };
+        let (host, database) = match rest.split_once('/') {
+            Some((host, "")) => (host, 0),
+            Some((host, database)) => (
+                host,
+                database
+                    .parse()
+                    .with_context(|| format!("invalid Redis database {:?}", database))?,
+            ),
+            None => (rest, 0),
+        };
+        if host.is_empty() {
+            anyhow::bail!("Redis URL {:?} has no host", url);
+        }
+        let address = if host
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| port.parse::<u16>().is_ok())
+        {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, DEFAULT_PORT)
+        };
+
+        Ok(Self {
+            address,
+            password,
+            database,
+            connection: Mutex::new(Connection::default()),
+        })
+    }
+
+    /// Runs `command` over the open connection, opening it first if needed
+    fn command(&self, command: &[&[u8]]) -> Result<Reply> {
+        let mut connection = self.connection.lock().expect("redis lock poisoned");
+        if connection
+            .failed_at
+            .is_some_and(|failed_at| failed_at.elapsed() < RETRY_INTERVAL)
+        {
+            anyhow::bail!("Redis {} is down", self.address);
+        }
+
+        let result = self.exchange(&mut connection, command);
+        match &result {
+            Ok(_) => connection.failed_at = None,
+            Err(e) => {
+                if connection.failed_at.is_none() {
+                    eprintln!("Redis: {:#}, retrying in {:?}", e, RETRY_INTERVAL);
+                }
+                connection.stream = None;
+                connection.failed_at = Some(Instant::now());
+            }
+        }
+        result
+    }
+
+    fn exchange(&self, connection: &mut Connection, command: &[&[u8]]) -> Result<Reply> {
+        let stream = match &mut connection.stream {
+            Some(stream) => stream,
+            None => connection.stream.insert(self.connect()?),
+        };
+        send(stream.get_mut(), command)?;
+        read_reply(stream)
+    }
+
+    fn connect(&self) -> Result<BufReader<TcpStream>> {
+        let address = self
+            .address
+            .to_socket_addrs()?
+            .next()
+            .with_context(|| format!("Redis address {} not resolved", self.address))?;
+        let stream = TcpStream::connect_timeout(&address, TIMEOUT)
+            .with_context(|| format!("failed to connect to Redis {}", self.address))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        let mut stream = BufReader::new(stream);
+
+        if let Some(password) = &self.password {
+            send(stream.get_mut(), &[b"AUTH", password.as_bytes()])?;
+            read_reply(&mut stream).context("Redis authentication failed")?;
+        }
+        if self.database != 0 {
+            let database = self.database.to_string();
+            send(stream.get_mut(), &[b"SELECT", database.as_bytes()])?;
+            read_reply(&mut stream)?;
+        }
+        Ok(stream)
+    }
+}
+
+/// Address and database, without the password
+impl fmt::Display for RedisCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.database)
+    }
+}
+
+impl CacheBackend for RedisCache {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.command(&[b"GET", key.as_bytes()])? {
+            Reply::Bulk(value) => Ok(value),
+            reply => anyhow::bail!("unexpected Redis reply to GET: {:?}", reply),
+        }
+    }
+
+    fn set(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        let millis = ttl.as_millis().max(1).to_string();
+        self.command(&[b"SET", key.as_bytes(), value, b"PX", millis.as_bytes()])?;
+        Ok(())
+    }
+}
+
+/// Writes command as an array of bulk strings
+fn send(stream: &mut TcpStream, command: &[&[u8]]) -> Result<()> {
+    let mut message = format!("*{}\r\n", command.len()).into_bytes();
+    for argument in command {
+        message.extend_from_slice(format!("${}\r\n", argument.len()).as_bytes());
+        message.extend_from_slice(argument);
+        message.extend_from_slice(b"\r\n");
+    }
+    stream.write_all(&message)?;
+    Ok(())
+}
+
+fn read_reply(stream: &mut impl BufRead) -> Result<Reply> {
+    let mut line = String::new();
+    if stream.read_line(&mut line)? == 0 {
+        anyhow::bail!("Redis closed the connection");
+    }
+    let line = line.trim_end_matches("\r\n");
+    let mut chars = line.chars();
+    let (kind, rest) = (chars.next(), chars.as_str());
+    match kind {
+        Some('+') => Ok(Reply::Status(rest.to_string())),
+        Some('-') => anyhow::bail!("Redis error: {}", rest),
+        Some(':') => Ok(Reply::Integer(rest.parse()?)),
+        Some('$') => {
+            let length: i64 = rest.parse()?;
+            if length < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            let mut value = vec![0; length as usize + 2];
+            stream.read_exact(&mut value)?;
+            value.truncate(length as usize);
+            Ok(Reply::Bulk(Some(value)))
+        }
+        _ => anyhow::bail!("unexpected Redis reply {:?}", line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url() {
+        let redis = RedisCache::new("redis://:secret@cache.internal/2").unwrap();
+        assert_eq!(redis.address, "cache.internal:6379");
+        assert_eq!(redis.password.as_deref(), Some("secret"));
+        assert_eq!(redis.database, 2);
+
+        let redis = RedisCache::new("10.0.0.5:6380").unwrap();
+        assert_eq!(redis.address, "10.0.0.5:6380");
+        assert_eq!(redis.password, None);
+        assert_eq!(redis.database, 0);
+
+        assert!(RedisCache::new("redis://").is_err());
+    }
+
+    #[test]
+    fn test_replies() {
+        let mut replies: &[u8] = b"+OK\r\n$5\r\nhe\r\no\r\n$-1\r\n:3\r\n-ERR wrong\r\n";
+        assert_eq!(
+            read_reply(&mut replies).unwrap(),
+            Reply::Status("OK".to_string())
+        );
+        assert_eq!(
+            read_reply(&mut replies).unwrap(),
+            Reply::Bulk(Some(b"he\r\no".to_vec()))
+        );
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Bulk(None));
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Integer(3));
+        assert!(read_reply(&mut replies).is_err());
+    }
+}
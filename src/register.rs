@@ -0,0 +1,292 @@
+//! Dynamic registration of LAN hosts
+//!
+//! Devices register (and refresh) their own name to address mapping over plain HTTP:
+//!
+//! ```text
+//! GET /register?name=laptop&ip=192.168.1.23&ttl=3600 HTTP/1.1
+//! Authorization: Bearer <key>
+//! ```
+//!
+//! `ip` defaults to the address the request came from, `ttl` to [`DEFAULT_TTL`]
+//! seconds and `ttl=0` removes the registration. Each key is either bound to a single
+//! host name (`laptop=<key>`) or allowed to register any name (`*=<key>`).
+//! Registered hosts are answered like DHCP leases, see [`crate::handler::LeaseHandler`].
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::leases::{hostname_label, unix_time, Lease, LeaseSource};
+use crate::listen;
+
+/// Lifetime of registrations without `ttl`
+pub const DEFAULT_TTL: u64 = 3600;
+
+/// Registrations must be refreshed at least weekly
+const MAX_TTL: u64 = 7 * 24 * 3600;
+
+/// Secret allowing registration of one host name or all of them
+#[derive(Debug, Clone)]
+pub struct RegistrationKey {
+    /// `None` for any name
+    name: Option<String>,
+    secret: String,
+}
+
+/// Parses `<name>=<secret>` or `*=<secret>`
+impl FromStr for RegistrationKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, secret) = s
+            .split_once('=')
+            .context("registration key must be <name>=<secret> or *=<secret>")?;
+        if secret.is_empty() {
+            anyhow::bail!("registration key of {} is empty", name);
+        }
+        let name = match name {
+            "*" => None,
+            name => Some(hostname_label(name).context("invalid host name of registration key")?),
+        };
+
+        Ok(Self {
+            name,
+            secret: secret.to_string(),
+        })
+    }
+}
+
+/// Why a registration was refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refusal {
+    /// Missing or unknown key
+    Unauthorized,
+    /// Key is not allowed to register the name
+    Forbidden,
+    /// Name is not a valid host name
+    InvalidName,
+}
+
+/// Hosts registered via the API
+pub struct Registry {
+    keys: Vec<RegistrationKey>,
+    hosts: Mutex<Vec<Lease>>,
+}
+
+impl Registry {
+    pub fn new(keys: impl IntoIterator<Item = RegistrationKey>) -> Self {
+        Self {
+            keys: keys.into_iter().collect(),
+            hosts: Mutex::default(),
+        }
+    }
+
+    /// Registers `address` for host `name` for `ttl` seconds (0 removes the host)
+    ///
+    /// The new address replaces the previous one of the same family (IPv4 or IPv6).
+    pub fn register(
+        &self,
+        key: Option<&str>,
+        name: &str,
+        address: IpAddr,
+        ttl: u64,
+    ) -> Result<(), Refusal> {
+        let name = hostname_label(name)
+            .filter(|label| label.len() == name.trim_end_matches('.').len())
+            .ok_or(Refusal::InvalidName)?;
+        let key = key
+            .and_then(|key| {
+                self.keys
+                    .iter()
+                    .find(|known| constant_time_eq(known.secret.as_bytes(), key.as_bytes()))
+            })
+            .ok_or(Refusal::Unauthorized)?;
+        if key.name.as_ref().is_some_and(|allowed| *allowed != name) {
+            return Err(Refusal::Forbidden);
+        }
+
+        let now = unix_time();
+        let mut hosts = self.hosts.lock().expect("registry lock poisoned");
+        hosts.retain(|host| {
+            host.is_active(now)
+                && !(host.hostname == name && host.address.is_ipv4() == address.is_ipv4())
+        });
+        if ttl > 0 {
+            hosts.push(Lease {
+                hostname: name,
+                address,
+                expires: Some(now + ttl.min(MAX_TTL)),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl LeaseSource for Registry {
+    fn active_leases(&self) -> Vec<Lease> {
+        let now = unix_time();
+        let hosts = self.hosts.lock().expect("registry lock poisoned");
+        hosts
+            .iter()
+            .filter(|host| host.is_active(now))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Compares secrets without leaking the length of the matching prefix via timing
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Serves `GET /register` requests, registrations are stored in `registry`
+pub fn serve(address: &str, registry: Arc<Registry>) -> Result<()> {
+    let listener = listen::tcp(address)
+        .with_context(|| format!("Failed to bind registration listener to {}", address))?;
+
+    println!("Host registration listening on {}", address);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Registration: error accepting connection: {}", e);
+                continue;
+            }
+        };
+
+        if let Err(e) = handle_connection(stream, &registry) {
+            eprintln!("Registration: error handling request: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_connection(mut stream: TcpStream, registry: &Registry) -> Result<()> {
+    let client = stream.peer_addr()?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    let mut key = None;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        if let Some((header, value)) = line.split_once(':') {
+            if header.eq_ignore_ascii_case("authorization") {
+                key = value.trim().strip_prefix("Bearer ").map(str::to_string);
+            }
+        }
+        line.clear();
+    }
+
+    let (status, body) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", target, _version] => register(target, key.as_deref(), client, registry),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+/// HTTP status and body of the response to the request for `target`
+fn register(
+    target: &str,
+    key: Option<&str>,
+    client: SocketAddr,
+    registry: &Registry,
+) -> (&'static str, String) {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/register" {
+        return ("404 Not Found", format!("unknown path {}\n", path));
+    }
+
+    let mut name = None;
+    let mut address = client.ip();
+    let mut ttl = DEFAULT_TTL;
+    for (param, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        let parsed = match param {
+            "name" => {
+                name = Some(value);
+                Ok(())
+            }
+            "ip" => value.parse().map(|value| address = value).map_err(|_| ()),
+            "ttl" => value.parse().map(|value| ttl = value).map_err(|_| ()),
+            _ => Ok(()),
+        };
+        if parsed.is_err() {
+            return (
+                "400 Bad Request",
+                format!("invalid {} {:?}\n", param, value),
+            );
+        }
+    }
+    let Some(name) = name else {
+        return ("400 Bad Request", "missing name parameter\n".into());
+    };
+
+    match registry.register(key, name, address, ttl) {
+        Ok(()) => {
+            println!("Registration: {} -> {} for {}s", name, address, ttl);
+            ("200 OK", format!("ok {} {} {}\n", name, address, ttl))
+        }
+        Err(Refusal::Unauthorized) => ("401 Unauthorized", "invalid key\n".into()),
+        Err(Refusal::Forbidden) => ("403 Forbidden", format!("key can't register {}\n", name)),
+        Err(Refusal::InvalidName) => ("400 Bad Request", format!("invalid name {:?}\n", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_are_bound_to_names() {
+        let registry =
+            Registry::new(["laptop=secret".parse().unwrap(), "*=admin".parse().unwrap()]);
+        let address: IpAddr = "192.168.1.23".parse().unwrap();
+
+        let register = |key, name| registry.register(key, name, address, 60);
+        assert_eq!(register(None, "laptop"), Err(Refusal::Unauthorized));
+        assert_eq!(
+            register(Some("wrong"), "laptop"),
+            Err(Refusal::Unauthorized)
+        );
+        assert_eq!(register(Some("secret"), "printer"), Err(Refusal::Forbidden));
+        assert_eq!(
+            register(Some("admin"), "bad.name"),
+            Err(Refusal::InvalidName)
+        );
+        assert_eq!(register(Some("secret"), "Laptop"), Ok(()));
+        assert_eq!(register(Some("admin"), "printer"), Ok(()));
+
+        // new address replaces the old one, TTL 0 removes the host
+        let moved: IpAddr = "192.168.1.99".parse().unwrap();
+        registry
+            .register(Some("secret"), "laptop", moved, 60)
+            .unwrap();
+        registry
+            .register(Some("admin"), "printer", address, 0)
+            .unwrap();
+        let leases = registry.active_leases();
+        assert_eq!(leases.len(), 1);
+        assert_eq!(
+            (leases[0].hostname.as_str(), leases[0].address),
+            ("laptop", moved)
+        );
+    }
+}
@@ -0,0 +1,293 @@
+//! Cache replication between servers, without an external store
+//!
+//! Each server streams answers it resolves to its peers over TCP, and peers keep what they
+//! receive until it expires. [`Replicator`] is the shared store of the cache (see
+//! [`CacheHandler::shared`]): storing pushes the entry to all peers, looking up finds entries
+//! pushed by them. Entries received from peers are not pushed further, so every server
+//! lists all the others as peers.
+//!
+//! Peers are trusted, their entries are served as they are. Connections are accepted only
+//! from the addresses of the peers, so the replication port must not be reachable by
+//! untrusted hosts sharing those addresses (e.g. behind the same NAT).
+//!
+//! Every message is a 4-byte length followed by the key length (2 bytes), the key, the
+//! time to live in milliseconds (8 bytes) and the value. Messages for a peer which is down
+//! or slow are dropped once [`QUEUE_LENGTH`] of them are waiting.
+//!
+//! [`CacheHandler::shared`]: crate::handler::CacheHandler::shared
+
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::handler::CacheBackend;
+
+/// Messages waiting for each peer at most
+pub const QUEUE_LENGTH: usize = 1024;
+
+/// Longest message accepted, a DNS message with its key and expiration
+const MAX_MESSAGE_LENGTH: usize = 70_000;
+
+/// Time to wait before connecting again to a peer which is down
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Values received from peers by key, with their expiration
+type Received = HashMap<String, (Vec<u8>, Instant)>;
+
+/// Store of the entries received from peers, pushing stored ones to them
+pub struct Replicator {
+    /// Most entries received from peers kept at once
+    capacity: usize,
+    received: Arc<Mutex<Received>>,
+    peers: Vec<SyncSender<Arc<Vec<u8>>>>,
+}
+
+impl Replicator {
+    /// Keeps at most `capacity` entries received from peers
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            received: Arc::new(Mutex::new(HashMap::new())),
+            peers: Vec::new(),
+        }
+    }
+
+    /// Pushes stored entries to the peer at `address`, connected in a background thread
+    pub fn peer(mut self, address: impl Into<String>) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        let address = address.into();
+        thread::spawn(move || push(&address, receiver));
+        self.peers.push(sender);
+        self
+    }
+
+    /// Accepts entries from `peers` on `address`, in a background thread
+    pub fn listen(&self, address: &str, peers: &[String]) -> Result<()> {
+        let listener = TcpListener::bind(address)
+            .with_context(|| format!("Failed to bind replication listener to {}", address))?;
+        let mut allowed: Vec<IpAddr> = Vec::new();
+        for peer in peers {
+            let addresses = peer
+                .to_socket_addrs()
+                .with_context(|| format!("invalid cache peer {}", peer))?;
+            allowed.extend(addresses.map(|address| address.ip()));
+        }
+
+        let (received, capacity) = (self.received.clone(), self.capacity);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("Replication: error accepting connection: {}", e);
+                        continue;
+                    }
+                };
+                let peer = stream.peer_addr().map(|address| address.ip());
+                if !peer.as_ref().is_ok_and(|peer| allowed.contains(peer)) {
+                    eprintln!("Replication: refused connection from {:?}", peer);
+                    continue;
+                }
+                let received = received.clone();
+                thread::spawn(move || {
+                    if let Err(e) = receive(stream, &received, capacity) {
+                        eprintln!("Replication: {:#}", e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+impl CacheBackend for Replicator {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let received = self.received.lock().expect("replication lock poisoned");
+        Ok(received
+            .get(key)
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(value, _)| value.clone()))
+    }
+
+    fn set(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        let message = Arc::new(encode(key, value, ttl));
+        for peer in self.peers.iter() {
+            match peer.try_send(message.clone()) {
+                Ok(()) | Err(TrySendError::Full(_)) => {}
+                Err(TrySendError::Disconnected(_)) => anyhow::bail!("replication thread ended"),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn encode(key: &str, value: &[u8], ttl: Duration) -> Vec<u8> {
+    let length = 2 + key.len() + 8 + value.len();
+    let mut message = Vec::with_capacity(4 + length);
+    message.extend_from_slice(&(length as u32).to_be_bytes());
+    message.extend_from_slice(&(key.len() as u16).to_be_bytes());
+    message.extend_from_slice(key.as_bytes());
+    message.extend_from_slice(&(ttl.as_millis() as u64).to_be_bytes());
+    message.extend_from_slice(value);
+    message
+}
+
+/// Key, value and time to live of a message without its length
+fn decode(message: &[u8]) -> Result<(String, Vec<u8>, Duration)> {
+    let (key_length, rest) = message
+        .split_first_chunk::<2>()
+        .context("message too short")?;
+    let key_length = u16::from_be_bytes(*key_length) as usize;
+    if rest.len() < key_length + 8 {
+        anyhow::bail!("message too short");
+    }
+    let (key, rest) = rest.split_at(key_length);
+    let (ttl, value) = rest.split_at(8);
+    let ttl = u64::from_be_bytes(ttl.try_into().expect("8 bytes"));
+    Ok((
+        String::from_utf8(key.to_vec()).context("invalid key")?,
+        value.to_vec(),
+        Duration::from_millis(ttl),
+    ))
+}
+
+/// Sends queued messages to the peer, reconnecting when the connection breaks
+fn push(address: &str, receiver: Receiver<Arc<Vec<u8>>>) {
+    let mut connection: Option<BufWriter<TcpStream>> = None;
+    let mut failed_at: Option<Instant> = None;
+    while let Ok(message) = receiver.recv() {
+        if connection.is_none() {
+            if failed_at.is_some_and(|failed_at| failed_at.elapsed() < RECONNECT_INTERVAL) {
+                continue; // dropped while the peer is down
+            }
+            match connect(address) {
+                Ok(stream) => {
+                    println!("Replication: connected to peer {}", address);
+                    connection = Some(BufWriter::new(stream));
+                    failed_at = None;
+                }
+                Err(e) => {
+                    if failed_at.is_none() {
+                        eprintln!("Replication: peer {} is down: {:#}", address, e);
+                    }
+                    failed_at = Some(Instant::now());
+                    continue;
+                }
+            }
+        }
+
+        let stream = connection.as_mut().expect("peer is connected");
+        let mut sent = stream.write_all(&message);
+        // more messages are likely waiting, they're sent together
+        while sent.is_ok() {
+            match receiver.try_recv() {
+                Ok(message) => sent = stream.write_all(&message),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = sent.and_then(|_| stream.flush()) {
+            eprintln!("Replication: connection to peer {} failed: {}", address, e);
+            connection = None;
+            failed_at = Some(Instant::now());
+        }
+    }
+}
+
+fn connect(address: &str) -> Result<TcpStream> {
+    let socket_address: SocketAddr = address
+        .to_socket_addrs()?
+        .next()
+        .with_context(|| format!("address {} not resolved", address))?;
+    let stream = TcpStream::connect_timeout(&socket_address, CONNECT_TIMEOUT)?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Stores messages from the peer until it disconnects
+fn receive(stream: TcpStream, received: &Mutex<Received>, capacity: usize) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut length = [0; 4];
+        if reader.read_exact(&mut length).is_err() {
+            return Ok(()); // peer disconnected
+        }
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_MESSAGE_LENGTH {
+            anyhow::bail!("message of {} bytes is too long", length);
+        }
+        let mut message = vec![0; length];
+        reader.read_exact(&mut message)?;
+        let (key, value, ttl) = decode(&message)?;
+
+        let mut received = received.lock().expect("replication lock poisoned");
+        if received.len() >= capacity && !received.contains_key(&key) {
+            let now = Instant::now();
+            received.retain(|_, (_, expires)| *expires > now);
+            if received.len() >= capacity {
+                continue; // full of live entries
+            }
+        }
+        received.insert(key, (value, Instant::now() + ttl));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_are_pushed_to_peers() {
+        let receiving = Replicator::new(10);
+        let port = {
+            let probe = TcpListener::bind("127.0.0.1:0").unwrap();
+            probe.local_addr().unwrap().port()
+        };
+        let address = format!("127.0.0.1:{}", port);
+        receiving
+            .listen(&address, &["127.0.0.1:0".to_string()])
+            .unwrap();
+        let sending = Replicator::new(10).peer(address);
+
+        sending
+            .set("dns:example.com.:1:1", b"entry", Duration::from_secs(60))
+            .unwrap();
+        sending
+            .set("dns:expired.com.:1:1", b"entry", Duration::ZERO)
+            .unwrap();
+        let start = Instant::now();
+        while receiving.get("dns:example.com.:1:1").unwrap().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            receiving.get("dns:example.com.:1:1").unwrap(),
+            Some(b"entry".to_vec())
+        );
+        assert_eq!(receiving.get("dns:expired.com.:1:1").unwrap(), None);
+        // nothing is pushed back
+        assert_eq!(sending.get("dns:example.com.:1:1").unwrap(), None);
+    }
+
+    #[test]
+    fn test_message_format() {
+        let message = encode("key", b"value", Duration::from_millis(1500));
+        assert_eq!(message[..4], [0, 0, 0, 18]);
+        assert_eq!(
+            decode(&message[4..]).unwrap(),
+            (
+                "key".to_string(),
+                b"value".to_vec(),
+                Duration::from_millis(1500)
+            )
+        );
+        assert!(decode(&message[4..10]).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+//! System resolver configuration (`/etc/resolv.conf`)
+//!
+//! Only `nameserver` lines are of interest, they become the default upstreams when
+//! none is given. Name servers are always queried on port 53, addresses with IPv6
+//! zone index (`fe80::1%eth0`) are skipped.
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// Port name servers of resolv.conf listen on
+const DNS_PORT: u16 = 53;
+
+/// Reads name servers from the resolv.conf file at `path`, in order of preference
+pub fn read_nameservers(path: impl AsRef<Path>) -> Result<Vec<SocketAddr>> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(parse_nameservers(&text))
+}
+
+/// Name server addresses of resolv.conf text
+pub fn parse_nameservers(text: &str) -> Vec<SocketAddr> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some("nameserver"), Some(address)) => address.parse::<IpAddr>().ok(),
+                _ => None,
+            }
+        })
+        .map(|address| SocketAddr::new(address, DNS_PORT))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nameservers() {
+        let nameservers = parse_nameservers(
+            "# Generated by NetworkManager
+search home.arpa
+nameserver 192.168.1.1
+nameserver fe80::1%eth0
+; nameserver 10.0.0.1
+nameserver   2001:db8::53
+options edns0 trust-ad
+",
+        );
+        assert_eq!(
+            nameservers,
+            [
+                "192.168.1.1:53".parse().unwrap(),
+                "[2001:db8::53]:53".parse::<SocketAddr>().unwrap()
+            ]
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Stub resolver - sends queries to an upstream DNS server and validates its responses
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::domain_name::DomainName;
+use crate::header::ResponseCode;
+use crate::packet::DnsPacket;
+use crate::question::{DnsQuestion, QueryClass, QueryType};
+use crate::record::{DnsRecord, RecordType};
+use crate::upstream::{TcpUpstream, UdpUpstream, Upstream};
+
+/// Stub resolver for a single upstream server
+///
+/// Queries are sent over UDP and repeated over TCP when the response is truncated.
+/// Responses with a mismatching ID or question are ignored.
+///
+/// ```no_run
+/// use dns_starter_rust::record::RecordType;
+/// use dns_starter_rust::resolver::Resolver;
+///
+/// let resolver = Resolver::new("8.8.8.8:53");
+/// for record in resolver.lookup("codecrafters.io", RecordType::A)? {
+///     println!("{}", record);
+/// }
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    upstream: String,
+    retries: u32,
+    udp: Arc<UdpUpstream>,
+    tcp: Arc<TcpUpstream>,
+}
+
+impl Resolver {
+    pub fn new(upstream: impl Into<String>) -> Self {
+        let upstream = upstream.into();
+        let timeout = Duration::from_secs(2);
+        Self {
+            udp: Arc::new(UdpUpstream::new(upstream.as_str()).with_timeout(timeout)),
+            tcp: Arc::new(TcpUpstream::new(upstream.as_str()).with_timeout(timeout)),
+            upstream,
+            retries: 2,
+        }
+    }
+
+    /// Time to wait for a single response
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.udp = Arc::new(UdpUpstream::new(self.upstream.as_str()).with_timeout(timeout));
+        self.tcp = Arc::new(TcpUpstream::new(self.upstream.as_str()).with_timeout(timeout));
+        self
+    }
+
+    /// How many times the query is repeated when no response arrives in time
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Looks up records of the given type, non-existent domain results in no records
+    pub fn lookup(
+        &self,
+        name: impl Into<DomainName>,
+        record_type: RecordType,
+    ) -> Result<Vec<DnsRecord>> {
+        let query = DnsPacket::builder()
+            .id(rand::random())
+            .recursion_desired(true)
+            .question(DnsQuestion::new(
+                name.into(),
+                QueryType::from(u16::from(record_type)),
+                QueryClass::IN,
+            ))
+            .build();
+
+        let response = self.query(&query)?;
+
+        match response.header.rescode {
+            ResponseCode::NOERROR => Ok(response.answers),
+            ResponseCode::NXDOMAIN => Ok(Vec::new()),
+            rescode => anyhow::bail!("upstream {} responded with {:?}", self.upstream, rescode),
+        }
+    }
+
+    /// Sends the query to the upstream and returns its validated response
+    pub fn query(&self, query: &DnsPacket) -> Result<DnsPacket> {
+        let mut attempt = 0;
+        let response = loop {
+            match self.udp.exchange(query) {
+                Ok(response) => break response,
+                Err(e) if attempt < self.retries => {
+                    eprintln!("Resolver: {} (attempt {})", e, attempt + 1);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        if response.header.truncated_message {
+            return self.tcp.exchange(query);
+        }
+
+        Ok(response)
+    }
+}
+
+/// Resolver can be used as upstream of the server (with retries and TCP fallback)
+impl Upstream for Resolver {
+    fn exchange(&self, packet: &DnsPacket) -> Result<DnsPacket> {
+        self.query(packet)
+    }
+}
@@ -0,0 +1,492 @@
+//! Automated rollover of zone signing keys (RFC 6781 section 4.1.1.1, pre-publish method)
+//!
+//! Zone keeps its KSKs, the ZSKs are generated and replaced after their lifetime in three
+//! steps:
+//!
+//! 1. DNSKEY of the successor is published next to the signing key, early enough to be in
+//!    caches when it starts signing (DNSKEY TTL and [`PROPAGATION_DELAY`]),
+//! 2. the successor signs the zone instead of the old key, when its lifetime ends,
+//! 3. the old DNSKEY is removed (and its key file deleted) once the signatures it made
+//!    expire from caches (the longest TTL of the zone and [`PROPAGATION_DELAY`]).
+//!
+//! Every algorithm of the KSKs has its own series of ZSKs, the whole zone must be signed
+//! with each algorithm of its DNSKEY RRset (RFC 6840 section 5.11). Timings of the keys
+//! are kept in a state file next to them, so the schedule survives restarts. The zone is
+//! signed again when its keys change and when the signatures get old ([`RESIGN_INTERVAL`]).
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::dnssec::{self, Algorithm, KeyRole, KeySpec, SigningKey, SIGNATURE_VALIDITY};
+use crate::domain_name::DomainName;
+use crate::handler::SharedZone;
+use crate::zone::Zone;
+
+/// How long a ZSK signs the zone, by default
+pub const DEFAULT_ZSK_LIFETIME: Duration = Duration::from_secs(90 * 24 * 3600);
+
+/// Time for a change of the zone to reach all of its name servers
+pub const PROPAGATION_DELAY: Duration = Duration::from_secs(3600);
+
+/// Zone is signed again when its signatures are this old
+pub const RESIGN_INTERVAL: Duration = Duration::from_secs(SIGNATURE_VALIDITY.as_secs() / 4);
+
+/// How often the schedule is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Key timings in the key directory
+const STATE_FILE: &str = "rollover.state";
+
+/// Lifetime of a managed ZSK, times are seconds since the epoch
+#[derive(Debug, Clone, PartialEq)]
+struct KeyTiming {
+    /// Key file in the key directory
+    file: String,
+    algorithm: Algorithm,
+    /// DNSKEY is published from
+    publish: u64,
+    /// Key signs the zone from
+    activate: u64,
+    /// Key doesn't sign the zone from
+    inactive: u64,
+    /// DNSKEY is removed at
+    delete: u64,
+}
+
+impl KeyTiming {
+    fn new(algorithm: Algorithm, publish: u64, activate: u64, timings: Timings) -> Self {
+        let inactive = activate + timings.lifetime;
+        Self {
+            file: format!("zsk-{}-{}.pem", algorithm as u8, publish),
+            algorithm,
+            publish,
+            activate,
+            inactive,
+            delete: inactive + timings.retire,
+        }
+    }
+
+    fn is_published(&self, now: u64) -> bool {
+        self.publish <= now && now < self.delete
+    }
+
+    fn is_active(&self, now: u64) -> bool {
+        self.activate <= now && now < self.inactive
+    }
+}
+
+/// Intervals of the rollover in seconds
+#[derive(Debug, Clone, Copy)]
+struct Timings {
+    /// How long a key signs
+    lifetime: u64,
+    /// How long the successor is published before it signs
+    prepublish: u64,
+    /// How long the old key stays published after it stopped signing
+    retire: u64,
+}
+
+/// Managed ZSKs of a zone
+#[derive(Debug, Default, PartialEq)]
+struct Schedule {
+    keys: Vec<KeyTiming>,
+}
+
+impl Schedule {
+    /// Parses the state file, a line per key: `<file> <algorithm> <publish> <activate>
+    /// <inactive> <delete>`
+    fn parse(text: &str) -> Result<Self> {
+        let mut keys = Vec::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || format!("invalid key timing: {}", line);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [file, algorithm, times @ ..] = &fields[..] else {
+                anyhow::bail!(invalid());
+            };
+            let times = times
+                .iter()
+                .map(|time| time.parse::<u64>())
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(invalid)?;
+            let [publish, activate, inactive, delete] = times[..] else {
+                anyhow::bail!(invalid());
+            };
+            keys.push(KeyTiming {
+                file: file.to_string(),
+                algorithm: algorithm.parse()?,
+                publish,
+                activate,
+                inactive,
+                delete,
+            });
+        }
+        Ok(Self { keys })
+    }
+
+    /// Brings the schedule to `now`: drops removed keys and keys of `algorithms` no more
+    /// used, schedules successors of keys whose lifetime ends soon
+    ///
+    /// Returns the added and the dropped keys.
+    fn advance(
+        &mut self,
+        algorithms: &[Algorithm],
+        now: u64,
+        timings: Timings,
+    ) -> (Vec<KeyTiming>, Vec<KeyTiming>) {
+        let mut added = Vec::new();
+        for &algorithm in algorithms {
+            let newest = self
+                .keys
+                .iter_mut()
+                .filter(|key| key.algorithm == algorithm)
+                .max_by_key(|key| key.activate);
+            match newest {
+                None => added.push(KeyTiming::new(algorithm, now, now, timings)),
+                Some(newest) if newest.inactive <= now + timings.prepublish => {
+                    // server which was down catches up, the successor needs to be in caches
+                    // before it signs anyway
+                    let activate = newest.inactive.max(now + timings.prepublish);
+                    newest.inactive = activate;
+                    newest.delete = activate + timings.retire;
+                    added.push(KeyTiming::new(algorithm, now, activate, timings));
+                }
+                Some(_) => {}
+            }
+        }
+        self.keys.extend(added.iter().cloned());
+
+        // after the successors are scheduled, keys which sign are never removed
+        let (removed, kept) = std::mem::take(&mut self.keys)
+            .into_iter()
+            .partition(|key| key.delete <= now || !algorithms.contains(&key.algorithm));
+        self.keys = kept;
+        (added, removed)
+    }
+
+    /// Keys published at `now`, those signing and the others
+    fn keys_at(&self, now: u64) -> (Vec<&KeyTiming>, Vec<&KeyTiming>) {
+        self.keys
+            .iter()
+            .filter(|key| key.is_published(now))
+            .partition(|key| key.is_active(now))
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "# <file> <algorithm> <publish> <activate> <inactive> <delete>, seconds since the epoch"
+        )?;
+        for key in self.keys.iter() {
+            writeln!(
+                f,
+                "{} {} {} {} {} {}",
+                key.file, key.algorithm, key.publish, key.activate, key.inactive, key.delete
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Keys the zone was last signed with
+#[derive(Debug, PartialEq)]
+struct Signed {
+    at: u64,
+    /// Files of the signing ZSKs
+    active: Vec<String>,
+    /// Files of the ZSKs published only
+    standby: Vec<String>,
+}
+
+/// Signs a zone with its KSKs and managed ZSKs, rolling the ZSKs over when their lifetime ends
+pub struct ZskRollover {
+    /// Unsigned zone
+    zone: Zone,
+    key_signing_keys: Vec<KeySpec>,
+    directory: PathBuf,
+    timings: Timings,
+    state: Mutex<(Schedule, Option<Signed>)>,
+}
+
+impl ZskRollover {
+    /// Rollover of ZSKs of `zone` signed by `key_signing_keys`, keeping the ZSKs and their
+    /// timings in `directory`
+    pub fn new(
+        zone: Zone,
+        key_signing_keys: Vec<KeySpec>,
+        directory: impl Into<PathBuf>,
+        lifetime: Duration,
+    ) -> Result<Self> {
+        let origin = zone.origin();
+        if key_signing_keys.is_empty() {
+            anyhow::bail!("zone {} has no KSK", origin);
+        }
+        if let Some(key) = key_signing_keys.iter().find(|key| key.role != KeyRole::Ksk) {
+            anyhow::bail!(
+                "ZSKs of zone {} are managed, {} must be a KSK",
+                origin,
+                key.path.display()
+            );
+        }
+
+        let soa = zone.soa().expect("zone has SOA");
+        let max_ttl = zone.records().iter().map(|record| record.ttl).max();
+        let timings = Timings {
+            lifetime: lifetime.as_secs(),
+            prepublish: u64::from(soa.ttl) + PROPAGATION_DELAY.as_secs(),
+            retire: u64::from(max_ttl.unwrap_or(soa.ttl)) + PROPAGATION_DELAY.as_secs(),
+        };
+        if timings.lifetime <= timings.prepublish {
+            anyhow::bail!(
+                "ZSK lifetime must be longer than {} seconds for zone {}",
+                timings.prepublish,
+                origin
+            );
+        }
+
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)
+            .with_context(|| format!("failed to create {}", directory.display()))?;
+        let path = directory.join(STATE_FILE);
+        let schedule = match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                Schedule::parse(&text).with_context(|| format!("invalid {}", path.display()))?
+            }
+            Err(_) if !path.exists() => Schedule::default(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+
+        Ok(Self {
+            zone,
+            key_signing_keys,
+            directory,
+            timings,
+            state: Mutex::new((schedule, None)),
+        })
+    }
+
+    pub fn origin(&self) -> &DomainName {
+        self.zone.origin()
+    }
+
+    /// Zone signed with the keys scheduled for `now`
+    pub fn sign(&self, now: SystemTime) -> Result<Zone> {
+        let signed = self.update(now, true)?;
+        Ok(signed.expect("zone is signed when forced"))
+    }
+
+    /// Checks the schedule every few minutes in a background thread, replaces `zone` when
+    /// it's signed again
+    pub fn start(self: &Arc<Self>, zone: SharedZone) {
+        let rollover = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(CHECK_INTERVAL);
+            match rollover.update(SystemTime::now(), false) {
+                Ok(Some(signed)) => {
+                    let mut zone = zone.write().expect("zone lock poisoned");
+                    *zone = zone.next_version(signed);
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!(
+                    "DNSSEC: signing of zone {} failed: {:#}",
+                    rollover.origin(),
+                    e
+                ),
+            }
+        });
+    }
+
+    /// Advances the schedule, signs the zone if its keys changed, its signatures got old or
+    /// `force` is set
+    fn update(&self, now: SystemTime, force: bool) -> Result<Option<Zone>> {
+        let mut state = self.state.lock().expect("rollover lock poisoned");
+        let (schedule, signed) = &mut *state;
+        let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        let mut algorithms: Vec<Algorithm> = Vec::new();
+        for key in self.key_signing_keys.iter() {
+            if !algorithms.contains(&key.algorithm) {
+                algorithms.push(key.algorithm);
+            }
+        }
+        let (added, removed) = schedule.advance(&algorithms, seconds, self.timings);
+        if !added.is_empty() || !removed.is_empty() {
+            write_file(
+                &self.directory.join(STATE_FILE),
+                schedule.to_string().as_bytes(),
+            )?;
+        }
+        for key in removed {
+            println!("DNSSEC: ZSK {} of zone {} removed", key.file, self.origin());
+            let path = self.directory.join(&key.file);
+            if let Err(e) = std::fs::remove_file(&path) {
+                eprintln!("DNSSEC: failed to delete {}: {}", path.display(), e);
+            }
+        }
+
+        let (active, standby) = schedule.keys_at(seconds);
+        let files = |keys: &[&KeyTiming]| keys.iter().map(|key| key.file.clone()).collect();
+        let current = Signed {
+            at: seconds,
+            active: files(&active),
+            standby: files(&standby),
+        };
+        let up_to_date = signed.as_ref().is_some_and(|signed| {
+            signed.active == current.active
+                && signed.standby == current.standby
+                && seconds < signed.at + RESIGN_INTERVAL.as_secs()
+        });
+        if up_to_date && !force {
+            return Ok(None);
+        }
+
+        let load = |keys: &[&KeyTiming]| {
+            keys.iter()
+                .map(|key| {
+                    KeySpec {
+                        role: KeyRole::Zsk,
+                        algorithm: key.algorithm,
+                        path: self.directory.join(&key.file),
+                    }
+                    .load()
+                })
+                .collect::<Result<Vec<SigningKey>>>()
+        };
+        let mut keys = self
+            .key_signing_keys
+            .iter()
+            .map(KeySpec::load)
+            .collect::<Result<Vec<_>>>()?;
+        keys.extend(load(&active)?);
+        let standby_keys = load(&standby)?;
+
+        let changed = signed.as_ref().is_none_or(|signed| {
+            signed.active != current.active || signed.standby != current.standby
+        });
+        if changed {
+            for key in keys.iter().filter(|key| key.role() == KeyRole::Zsk) {
+                println!("Signing zone {} with {}", self.origin(), key);
+            }
+            for (key, timing) in standby_keys.iter().zip(standby.iter()) {
+                let (state, time) = match timing.activate > seconds {
+                    true => ("signs from", timing.activate),
+                    false => ("removed at", timing.delete),
+                };
+                println!(
+                    "  {} published, {} {}",
+                    key,
+                    state,
+                    crate::log::timestamp(UNIX_EPOCH + Duration::from_secs(time))
+                );
+            }
+        }
+
+        let zone = dnssec::sign_zone_with_standby(&self.zone, &keys, &standby_keys, now)?;
+        *signed = Some(current);
+        Ok(Some(zone))
+    }
+}
+
+/// Replaces the file at once, so it's never left half-written
+fn write_file(path: &Path, content: &[u8]) -> Result<()> {
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, content)
+        .and_then(|_| std::fs::rename(&temporary, path))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::RecordType;
+
+    const TIMINGS: Timings = Timings {
+        lifetime: 1000,
+        prepublish: 100,
+        retire: 200,
+    };
+
+    #[test]
+    fn test_zsk_is_pre_published_and_retired() {
+        let algorithms = [Algorithm::Ed25519];
+        let mut schedule = Schedule::default();
+
+        let (added, _) = schedule.advance(&algorithms, 0, TIMINGS);
+        assert_eq!(added, [KeyTiming::new(Algorithm::Ed25519, 0, 0, TIMINGS)]);
+        assert_eq!(
+            schedule.advance(&algorithms, 899, TIMINGS),
+            (vec![], vec![])
+        );
+
+        // successor is published before the old key stops signing
+        let (added, _) = schedule.advance(&algorithms, 900, TIMINGS);
+        assert_eq!(added[0].activate, 1000);
+        let (active, standby) = schedule.keys_at(999);
+        assert_eq!((active[0].publish, standby[0].publish), (0, 900));
+        let (active, standby) = schedule.keys_at(1000);
+        assert_eq!((active[0].publish, standby[0].publish), (900, 0));
+
+        // old key is removed once its signatures expire
+        let (_, removed) = schedule.advance(&algorithms, 1200, TIMINGS);
+        assert_eq!(removed[0].publish, 0);
+        assert_eq!(schedule.keys.len(), 1);
+
+        let parsed = Schedule::parse(&schedule.to_string()).unwrap();
+        assert_eq!(parsed, schedule);
+    }
+
+    #[test]
+    fn test_late_rollover_keeps_timings() {
+        let algorithms = [Algorithm::EcdsaP256Sha256];
+        let mut schedule = Schedule {
+            keys: vec![KeyTiming::new(algorithms[0], 0, 0, TIMINGS)],
+        };
+
+        // the server was down when the successor was due
+        schedule.advance(&algorithms, 1500, TIMINGS);
+        let [old, new] = &schedule.keys[..] else {
+            panic!("expected two keys");
+        };
+        assert_eq!((old.inactive, old.delete), (1600, 1800));
+        assert_eq!((new.publish, new.activate), (1500, 1600));
+    }
+
+    #[test]
+    fn test_zone_is_signed_with_managed_zsk() {
+        let directory =
+            std::env::temp_dir().join(format!("dns-test-{}-rollover", std::process::id()));
+        let zone = Zone::parse(
+            "$ORIGIN example.\n@ IN SOA ns1 hostmaster 1 7200 900 1209600 300\nns1 IN A 192.0.2.1\n",
+        )
+        .unwrap();
+        let ksk = KeySpec {
+            role: KeyRole::Ksk,
+            algorithm: Algorithm::Ed25519,
+            path: directory.join("ksk.pem"),
+        };
+        let rollover = ZskRollover::new(zone, vec![ksk], &directory, DEFAULT_ZSK_LIFETIME).unwrap();
+
+        let signed = rollover.sign(SystemTime::now()).unwrap();
+        let dnskeys = signed
+            .records_at(signed.origin())
+            .filter(|record| record.record_type == RecordType::DNSKEY)
+            .count();
+        assert_eq!(dnskeys, 2);
+        assert!(rollover.update(SystemTime::now(), false).unwrap().is_none());
+
+        // schedule survives restarts
+        let state = std::fs::read_to_string(directory.join(STATE_FILE)).unwrap();
+        assert_eq!(Schedule::parse(&state).unwrap().keys.len(), 1);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
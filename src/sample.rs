@@ -0,0 +1,87 @@
+//! Sampling of queries for offline analysis
+//!
+//! Every N-th query received over UDP is recorded in a pcap file together with its
+//! response, in wire format, with the time and the addresses of the exchange. Unlike a full
+//! capture (`--pcap`) this costs next to nothing on the other queries, and the file stops
+//! growing at a configured size. Samples can be inspected in Wireshark or fed to the
+//! `replay` subcommand.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use anyhow::Result;
+
+use crate::pcap::PcapWriter;
+
+/// Queries sampled by default: one in
+pub const DEFAULT_RATE: u64 = 100;
+/// Size of the sample file by default
+pub const DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Records 1-in-N query/response pairs
+pub struct Sampler {
+    writer: PcapWriter,
+    rate: u64,
+    /// Queries seen so far
+    seen: AtomicU64,
+    /// The file reached its maximal size
+    full: AtomicBool,
+}
+
+impl Sampler {
+    /// Samples one in `rate` queries to `path`, which grows to `max_size` bytes at most
+    pub fn create(path: &str, rate: u64, max_size: u64) -> Result<Self> {
+        if rate == 0 {
+            anyhow::bail!("sampling rate must be positive");
+        }
+        Ok(Self {
+            writer: PcapWriter::create(path)?.max_size(max_size),
+            rate,
+            seen: AtomicU64::new(0),
+            full: AtomicBool::new(false),
+        })
+    }
+
+    /// Whether the query just received should be sampled
+    pub fn pick(&self) -> bool {
+        !self.full.load(Ordering::Relaxed)
+            && self
+                .seen
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.rate)
+    }
+
+    /// Records `query` from `client` to `server` with its `response`
+    pub fn record(
+        &self,
+        client: SocketAddr,
+        server: SocketAddr,
+        query: &[u8],
+        response: &[u8],
+    ) -> Result<()> {
+        if !self
+            .writer
+            .write_exchange(client, server, query, response)?
+            && !self.full.swap(true, Ordering::Relaxed)
+        {
+            eprintln!("Sampling: file is full, no more queries are sampled");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_in_rate_queries_is_picked() {
+        let path =
+            std::env::temp_dir().join(format!("dns-test-{}-sample.pcap", std::process::id()));
+        let sampler = Sampler::create(path.to_str().unwrap(), 3, DEFAULT_MAX_SIZE).unwrap();
+        let picked: Vec<bool> = (0..6).map(|_| sampler.pick()).collect();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(picked, [true, false, false, true, false, false]);
+    }
+}
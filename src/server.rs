@@ -0,0 +1,695 @@
+//! DNS server answering queries over UDP, embeddable in other programs
+//!
+//! ```no_run
+//! use dns_starter_rust::server::Server;
+//!
+//! Server::builder()
+//!     .bind("127.0.0.1:2053")
+//!     .resolver("8.8.8.8:53")
+//!     .build()?
+//!     .run()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Queries are answered by a [`Pipeline`] of handlers, either given whole or forwarding
+//! to a resolver. Queries are answered one after another, unless the server has a limit on
+//! queries answered at the same time, see [`ServerBuilder::max_in_flight`]. Other transports
+//! (TCP, DoT, DoH, ...) serve the same pipeline, see their modules.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::anonymize::Anonymizer;
+use crate::edns;
+use crate::handler::{response_builder, ForwardHandler, Pipeline, Request};
+use crate::header::{DnsHeader, ResponseCode};
+use crate::hexdump;
+use crate::listen::{self, SocketBuffers};
+use crate::log;
+use crate::overload::{InFlight, Overload};
+use crate::packet::{BytesPacket, DnsPacket, ParseMode, MIN_UDP_SIZE};
+use crate::pcap::PcapWriter;
+use crate::sample::Sampler;
+use crate::stats;
+use crate::trace;
+use crate::upstream::{TlsOptions, UpstreamSpec};
+use crate::wire_cache::{WireCache, WireQuery};
+
+/// Address the server listens on unless told otherwise
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:2053";
+
+/// How long the resolver of [`ServerBuilder::resolver`] may take to answer
+const RESOLVER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest pause after repeated failures to receive
+const MAX_RECEIVE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Builder of [`Server`]
+pub struct ServerBuilder {
+    address: String,
+    socket: Option<UdpSocket>,
+    pipeline: Option<Arc<Pipeline>>,
+    resolver: Option<String>,
+    max_udp_size: u16,
+    parse_mode: ParseMode,
+    anonymizer: Anonymizer,
+    dump_packets: bool,
+    pcap: Option<PcapWriter>,
+    sampler: Option<Sampler>,
+    wire_cache: Option<WireCache>,
+    in_flight: Option<Arc<InFlight>>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            address: DEFAULT_ADDRESS.to_string(),
+            socket: None,
+            pipeline: None,
+            resolver: None,
+            max_udp_size: edns::UDP_PAYLOAD_SIZE,
+            parse_mode: ParseMode::default(),
+            anonymizer: Anonymizer::None,
+            dump_packets: false,
+            pcap: None,
+            sampler: None,
+            wire_cache: None,
+            in_flight: None,
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Listens on `<address>[@<interface>]`, see [`crate::listen`]
+    pub fn bind(mut self, address: &str) -> Self {
+        self.address = address.to_string();
+        self
+    }
+
+    /// Serves on an already bound socket instead of binding one
+    pub fn socket(mut self, socket: UdpSocket) -> Self {
+        self.socket = Some(socket);
+        self
+    }
+
+    /// Answers queries with `pipeline`
+    pub fn pipeline(mut self, pipeline: Arc<Pipeline>) -> Self {
+        self.pipeline = Some(pipeline);
+        self
+    }
+
+    /// Forwards queries to `resolver` (any [`UpstreamSpec`]) instead of a custom pipeline,
+    /// queries it leaves without an answer are refused
+    pub fn resolver(mut self, resolver: &str) -> Self {
+        self.resolver = Some(resolver.to_string());
+        self
+    }
+
+    /// Largest response over UDP advertised in EDNS and sent to EDNS clients
+    pub fn max_udp_size(mut self, max_udp_size: u16) -> Self {
+        self.max_udp_size = max_udp_size.max(MIN_UDP_SIZE);
+        self
+    }
+
+    /// How strictly queries are parsed, lenient by default
+    pub fn parse_mode(mut self, parse_mode: ParseMode) -> Self {
+        self.parse_mode = parse_mode;
+        self
+    }
+
+    /// How client addresses appear in the log
+    pub fn anonymizer(mut self, anonymizer: Anonymizer) -> Self {
+        self.anonymizer = anonymizer;
+        self
+    }
+
+    /// Prints hexdump of every packet received and sent
+    pub fn dump_packets(mut self, dump_packets: bool) -> Self {
+        self.dump_packets = dump_packets;
+        self
+    }
+
+    /// Captures all packets received and sent
+    pub fn pcap(mut self, pcap: PcapWriter) -> Self {
+        self.pcap = Some(pcap);
+        self
+    }
+
+    /// Records a sample of queries with their responses
+    pub fn sampler(mut self, sampler: Sampler) -> Self {
+        self.sampler = Some(sampler);
+        self
+    }
+
+    /// Answers repeated plain queries with encoded responses of up to `capacity` questions,
+    /// only for pipelines whose answers don't depend on the client, see [`crate::wire_cache`]
+    pub fn wire_cache(mut self, capacity: usize) -> Self {
+        self.wire_cache = Some(WireCache::new(capacity));
+        self
+    }
+
+    /// Answers up to `limit` queries at the same time, each in its own thread, further ones
+    /// are dropped or refused as `policy` says, see [`crate::overload`]
+    pub fn max_in_flight(mut self, limit: usize, policy: Overload) -> Self {
+        self.in_flight = Some(InFlight::new(limit, policy));
+        self
+    }
+
+    /// Binds the socket, fails without a pipeline or resolver
+    pub fn build(self) -> Result<Server> {
+        let pipeline = match (self.pipeline, self.resolver) {
+            (Some(pipeline), None) => pipeline,
+            (None, Some(resolver)) => {
+                let upstream = resolver.parse::<UpstreamSpec>()?.build(
+                    RESOLVER_TIMEOUT,
+                    &TlsOptions::default(),
+                    SocketBuffers::default(),
+                )?;
+                Arc::new(Pipeline::new().with(ForwardHandler::new(upstream)))
+            }
+            (Some(_), Some(_)) => anyhow::bail!("server takes either a pipeline or a resolver"),
+            (None, None) => anyhow::bail!("server needs a pipeline or a resolver"),
+        };
+        let socket = match self.socket {
+            Some(socket) => socket,
+            None => listen::udp(&self.address)
+                .with_context(|| format!("Failed to bind to {}", self.address))?,
+        };
+        Ok(Server {
+            local_address: socket.local_addr()?,
+            socket,
+            pipeline,
+            max_udp_size: self.max_udp_size,
+            parse_mode: self.parse_mode,
+            anonymizer: self.anonymizer,
+            dump_packets: self.dump_packets,
+            pcap: self.pcap,
+            sampler: self.sampler,
+            wire_cache: self.wire_cache,
+            in_flight: self.in_flight,
+        })
+    }
+}
+
+/// Answers queries arriving over UDP
+pub struct Server {
+    socket: UdpSocket,
+    local_address: SocketAddr,
+    pipeline: Arc<Pipeline>,
+    max_udp_size: u16,
+    parse_mode: ParseMode,
+    anonymizer: Anonymizer,
+    dump_packets: bool,
+    pcap: Option<PcapWriter>,
+    sampler: Option<Sampler>,
+    wire_cache: Option<WireCache>,
+    in_flight: Option<Arc<InFlight>>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// Address the server listens on, with the actual port when bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    /// Serves queries forever
+    ///
+    /// Every query is handled on its own, a failure (even a panicking handler) is logged and
+    /// answered with SERVFAIL, malformed queries with FORMERR, and the server goes on.
+    pub fn run(self) -> Result<()> {
+        let server = Arc::new(self);
+        let mut buf = vec![0; server.max_udp_size as usize];
+        let mut failures = 0;
+        loop {
+            let (size, source) = match server.socket.recv_from(&mut buf) {
+                Ok(received) => {
+                    failures = 0;
+                    received
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // e.g. out of memory, or ICMP errors of earlier responses reported on
+                    // Windows, waiting a while keeps a lasting failure from spinning the loop
+                    failures += 1;
+                    let pause = receive_backoff(failures);
+                    eprintln!("UDP: error receiving data: {}, pausing for {:?}", e, pause);
+                    thread::sleep(pause);
+                    continue;
+                }
+            };
+            let query = &buf[..size];
+            let Some(in_flight) = &server.in_flight else {
+                server.answer_logged(query, source);
+                continue;
+            };
+            match in_flight.try_acquire() {
+                Some(permit) => {
+                    let (server, query) = (server.clone(), query.to_vec());
+                    thread::spawn(move || {
+                        server.answer_logged(&query, source);
+                        drop(permit);
+                    });
+                }
+                None => server.shed(query, source, in_flight.policy()),
+            }
+        }
+    }
+
+    fn answer_logged(&self, query: &[u8], source: SocketAddr) {
+        if let Err(e) = self.answer(query, source) {
+            let client = self.anonymizer.client(source);
+            eprintln!("UDP: error answering {}: {:#}", client, e);
+        }
+    }
+
+    /// Drops or refuses a query arriving while too many are being answered
+    fn shed(&self, query: &[u8], source: SocketAddr, policy: Overload) {
+        stats::shed(policy);
+        self.capture(source, self.local_address, query);
+        if policy == Overload::Drop {
+            return;
+        }
+        if let Some(response) = error_response(query, ResponseCode::REFUSED) {
+            if let Err(e) = self.send(&BytesPacket::from(response).buf, source) {
+                let client = self.anonymizer.client(source);
+                eprintln!("UDP: error refusing {}: {:#}", client, e);
+            }
+        }
+    }
+
+    fn answer(&self, query: &[u8], source: SocketAddr) -> Result<()> {
+        let client = self.anonymizer.client(source);
+        if log::is_text() {
+            println!("< Received {} bytes from {}", query.len(), client);
+        }
+        if self.dump_packets {
+            print_dump(query);
+        }
+        self.capture(source, self.local_address, query);
+
+        let wire_query = self
+            .wire_cache
+            .as_ref()
+            .and_then(|_| WireQuery::parse(query));
+        // a query found in the cache parses leniently like the one its response was stored
+        // for, strictly parsed queries are validated before being answered from it
+        let validated_first = self.parse_mode == ParseMode::Strict;
+        if !validated_first {
+            if let Some(response) = self.cached(wire_query.as_ref(), query) {
+                return self.send(&response, source);
+            }
+        }
+
+        let orig = match DnsPacket::parse_with(query, self.parse_mode) {
+            Ok(packet) => packet,
+            Err(e) => {
+                eprintln!("Malformed packet from {}: {}", client, e);
+                return match error_response(query, ResponseCode::FORMERR) {
+                    Some(response) => self.send(&BytesPacket::from(response).buf, source),
+                    None => Ok(()),
+                };
+            }
+        };
+        if validated_first {
+            if let Some(response) = self.cached(wire_query.as_ref(), query) {
+                return self.send(&response, source);
+            }
+        }
+        if log::is_text() {
+            println!("<<< Received DNS packet:\n{}", orig);
+        }
+        let size_limit = orig.udp_response_limit(self.max_udp_size);
+        let mut span = trace::query_span("udp", &orig, Some(source));
+        let query_log = log::query("udp");
+
+        let request = Request::new(orig, Some(source));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.pipeline.handle(&request)))
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("handler panicked")));
+        let mut response = match span.record(result) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("UDP: failed to answer query from {}: {:#}", client, e);
+                response_builder(&request.query)
+                    .rescode(ResponseCode::SERVFAIL)
+                    .build()
+            }
+        };
+        if let Some(opt) = response.opt.as_mut() {
+            opt.udp_payload_size = self.max_udp_size;
+        }
+        span.response(&response);
+        query_log.finish(Some(source), &response);
+
+        if log::is_text() {
+            println!(">>> Sent DNS packet:\n{}", response);
+        }
+
+        let bytes_packet = {
+            let _span = trace::span("dns.serialize");
+            response.to_bytes_limited(size_limit)
+        };
+        if let (Some(cache), Some(wire_query)) = (&self.wire_cache, &wire_query) {
+            cache.store(wire_query, &response, &bytes_packet.buf);
+        }
+        if let Some(sampler) = self.sampler.as_ref().filter(|sampler| sampler.pick()) {
+            let sampled = sampler.record(source, self.local_address, query, &bytes_packet.buf);
+            if let Err(e) = sampled {
+                eprintln!("Sampling: {:#}", e);
+            }
+        }
+        self.send(&bytes_packet.buf, source)
+    }
+
+    /// Response to `query` from the wire cache
+    fn cached(&self, wire_query: Option<&WireQuery>, query: &[u8]) -> Option<Vec<u8>> {
+        let (cache, wire_query) = (self.wire_cache.as_ref()?, wire_query?);
+        let size_limit = wire_query.udp_response_limit(self.max_udp_size);
+        cache.lookup(wire_query, query, size_limit)
+    }
+
+    fn send(&self, response: &[u8], destination: SocketAddr) -> Result<()> {
+        if log::is_text() {
+            let client = self.anonymizer.client(destination);
+            println!("> Sent {} bytes to {}", response.len(), client);
+        }
+        if self.dump_packets {
+            print_dump(response);
+        }
+        self.capture(self.local_address, destination, response);
+
+        self.socket
+            .send_to(response, destination)
+            .context("failed to send response")?;
+        Ok(())
+    }
+
+    /// Writes the packet to the capture, a full disk doesn't stop answering
+    fn capture(&self, source: SocketAddr, destination: SocketAddr, packet: &[u8]) {
+        if let Some(pcap) = &self.pcap {
+            if let Err(e) = pcap.write_udp(source, destination, packet) {
+                eprintln!("Capture: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Pause after `failures` failures to receive in a row, doubling up to a second
+fn receive_backoff(failures: u32) -> Duration {
+    Duration::from_millis(1 << failures.min(10)).min(MAX_RECEIVE_BACKOFF)
+}
+
+/// Response with just `rescode` to a query which wasn't parsed (or couldn't be), `None` if not
+/// even its header could
+///
+/// Responses get no response, two servers must not keep answering each other's errors.
+fn error_response(query: &[u8], rescode: ResponseCode) -> Option<DnsPacket> {
+    let mut header = DnsHeader::new();
+    header.read_bytes(&mut &query[..]).ok()?;
+    if header.response {
+        return None;
+    }
+    Some(
+        DnsPacket::builder()
+            .id(header.id)
+            .response()
+            .opcode(header.opcode)
+            .recursion_desired(header.recursion_desired)
+            .rescode(rescode)
+            .build(),
+    )
+}
+
+/// Prints hexdump and annotated decode of the packet
+fn print_dump(bytes: &[u8]) {
+    println!("{}", hexdump::hexdump(bytes));
+    println!("{}", hexdump::annotate(bytes));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::domain_name::DomainName;
+    use crate::handler::{Handler, Next, StaticAnswerHandler};
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+    use crate::record::RecordData;
+
+    /// Fails queries for `error.example`, panics on `panic.example`
+    struct FaultyHandler;
+
+    impl Handler for FaultyHandler {
+        fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+            let name = request.query.questions[0].domain_name.to_string();
+            match name.trim_end_matches('.') {
+                "error.example" => anyhow::bail!("upstream unreachable"),
+                "panic.example" => panic!("bug in handler"),
+                _ => next.run(request),
+            }
+        }
+    }
+
+    fn start_server() -> SocketAddr {
+        let pipeline = Pipeline::new()
+            .with(FaultyHandler)
+            .with(StaticAnswerHandler::new(Ipv4Addr::new(192, 0, 2, 1), 60));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .pipeline(Arc::new(pipeline))
+            .build()
+            .unwrap();
+        let address = server.local_addr();
+        std::thread::spawn(move || server.run());
+        address
+    }
+
+    fn exchange(server: SocketAddr, message: &[u8]) -> DnsPacket {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.send_to(message, server).unwrap();
+        let mut buf = [0; 512];
+        let size = client.recv(&mut buf).unwrap();
+        DnsPacket::parse(&buf[..size]).unwrap()
+    }
+
+    fn query(name: &str) -> Vec<u8> {
+        let query = DnsPacket::builder()
+            .id(4321)
+            .question(DnsQuestion::new(
+                DomainName::from(name),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+        BytesPacket::from(query).buf.to_vec()
+    }
+
+    #[test]
+    fn test_server_answers_in_process() {
+        let server = start_server();
+        let response = exchange(server, &query("codecrafters.io"));
+
+        assert_eq!(response.header.id, 4321);
+        assert_eq!(
+            response.answers[0].data,
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+
+        assert!(Server::builder().bind("127.0.0.1:0").build().is_err());
+        assert!(Server::builder()
+            .bind("127.0.0.1:0")
+            .pipeline(Arc::new(Pipeline::new()))
+            .resolver("127.0.0.1:53")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_failed_queries_dont_stop_server() {
+        let server = start_server();
+
+        let mut malformed = query("codecrafters.io");
+        malformed.truncate(20);
+        let response = exchange(server, &malformed);
+        assert_eq!(response.header.id, 4321);
+        assert_eq!(response.header.rescode, ResponseCode::FORMERR);
+
+        let response = exchange(server, &query("error.example"));
+        assert_eq!(response.header.rescode, ResponseCode::SERVFAIL);
+        let response = exchange(server, &query("panic.example"));
+        assert_eq!(response.header.rescode, ResponseCode::SERVFAIL);
+
+        let response = exchange(server, &query("codecrafters.io"));
+        assert_eq!(response.header.rescode, ResponseCode::NOERROR);
+    }
+
+    /// Counts queries which made it to the pipeline
+    struct CountingHandler(Arc<AtomicUsize>);
+
+    impl Handler for CountingHandler {
+        fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            next.run(request)
+        }
+    }
+
+    #[test]
+    fn test_repeated_queries_are_answered_from_wire_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let pipeline = Pipeline::new()
+            .with(CountingHandler(calls.clone()))
+            .with(StaticAnswerHandler::new(Ipv4Addr::new(192, 0, 2, 1), 60));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .pipeline(Arc::new(pipeline))
+            .wire_cache(10)
+            .build()
+            .unwrap();
+        let address = server.local_addr();
+        std::thread::spawn(move || server.run());
+
+        let first = exchange(address, &query("codecrafters.io"));
+        let mut repeated = query("CodeCrafters.io");
+        repeated[1] = 0x42;
+        let second = exchange(address, &repeated);
+
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(second.header.id, u16::from_be_bytes([repeated[0], 0x42]));
+        assert_eq!(
+            second.questions[0].domain_name.to_string(),
+            "CodeCrafters.io."
+        );
+        // owner points to the question, as answers from the handlers do
+        assert_eq!(
+            second.answers[0].domain_name,
+            second.questions[0].domain_name
+        );
+        assert_eq!(second.answers[0].data, first.answers[0].data);
+    }
+
+    #[test]
+    fn test_wire_cache_serves_strictly_parsed_queries() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let pipeline = Pipeline::new()
+            .with(CountingHandler(calls.clone()))
+            .with(StaticAnswerHandler::new(Ipv4Addr::new(192, 0, 2, 1), 60));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .pipeline(Arc::new(pipeline))
+            .parse_mode(ParseMode::Strict)
+            .wire_cache(10)
+            .build()
+            .unwrap();
+        let address = server.local_addr();
+        std::thread::spawn(move || server.run());
+
+        exchange(address, &query("codecrafters.io"));
+        let repeated = exchange(address, &query("codecrafters.io"));
+        assert_eq!(repeated.header.rescode, ResponseCode::NOERROR);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        let mut smuggling = query("codecrafters.io");
+        smuggling.push(0xFF);
+        let rejected = exchange(address, &smuggling);
+        assert_eq!(rejected.header.rescode, ResponseCode::FORMERR);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_receive_failures_back_off() {
+        assert_eq!(receive_backoff(1), Duration::from_millis(2));
+        assert_eq!(receive_backoff(5), Duration::from_millis(32));
+        assert_eq!(receive_backoff(100), MAX_RECEIVE_BACKOFF);
+    }
+
+    /// Takes its time with queries for `slow.example`
+    struct SlowHandler;
+
+    impl Handler for SlowHandler {
+        fn handle(&self, request: &Request, next: Next<'_>) -> Result<DnsPacket> {
+            if request.query.questions[0].domain_name == DomainName::from("slow.example") {
+                std::thread::sleep(Duration::from_millis(300));
+            }
+            next.run(request)
+        }
+    }
+
+    #[test]
+    fn test_queries_over_in_flight_limit_are_refused() {
+        let pipeline = Pipeline::new()
+            .with(SlowHandler)
+            .with(StaticAnswerHandler::new(Ipv4Addr::new(192, 0, 2, 1), 60));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .pipeline(Arc::new(pipeline))
+            .max_in_flight(1, Overload::Refuse)
+            .build()
+            .unwrap();
+        let address = server.local_addr();
+        std::thread::spawn(move || server.run());
+
+        let slow_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        slow_client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        slow_client
+            .send_to(&query("slow.example"), address)
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        let refused = exchange(address, &query("codecrafters.io"));
+        assert_eq!(refused.header.rescode, ResponseCode::REFUSED);
+        assert!(stats::report().contains("overload:"));
+
+        let mut buf = [0; 512];
+        let size = slow_client.recv(&mut buf).unwrap();
+        let answered = DnsPacket::parse(&buf[..size]).unwrap();
+        assert_eq!(answered.header.rescode, ResponseCode::NOERROR);
+        assert_eq!(answered.answers.len(), 1);
+
+        // the slot is free again
+        let answered = exchange(address, &query("codecrafters.io"));
+        assert_eq!(answered.header.rescode, ResponseCode::NOERROR);
+    }
+
+    #[test]
+    fn test_queries_over_in_flight_limit_are_dropped() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .pipeline(Arc::new(Pipeline::new().with(SlowHandler)))
+            .max_in_flight(1, Overload::Drop)
+            .build()
+            .unwrap();
+        let address = server.local_addr();
+        std::thread::spawn(move || server.run());
+
+        let slow_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        slow_client
+            .send_to(&query("slow.example"), address)
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        let dropped_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        dropped_client
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        dropped_client
+            .send_to(&query("codecrafters.io"), address)
+            .unwrap();
+        let mut buf = [0; 512];
+        assert!(dropped_client.recv(&mut buf).is_err());
+    }
+}
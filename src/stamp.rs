@@ -0,0 +1,271 @@
+//! DNS stamps (`sdns://...`), compact descriptions of DNS servers
+//!
+//! A stamp is URL-safe base64 (without padding) of the protocol identifier, the properties
+//! announced by the server (DNSSEC validation, no logs, no filtering) and protocol-specific
+//! fields: address, hashes of certificates in the chain, host name, path, public key, ...
+//! See <https://dnscrypt.info/stamps-specifications>.
+//!
+//! ```
+//! use dns_starter_rust::stamp::{Protocol, Stamp};
+//!
+//! let stamp: Stamp = "sdns://AAcAAAAAAAAABzguOC44Ljg".parse().unwrap();
+//! assert!(stamp.dnssec);
+//! assert!(matches!(stamp.protocol, Protocol::Plain { address } if address.to_string() == "8.8.8.8:53"));
+//! ```
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use anyhow::Context;
+
+use crate::base64;
+
+const PLAIN_PORT: u16 = 53;
+const DNSCRYPT_PORT: u16 = 443;
+const DOH_PORT: u16 = 443;
+const DOT_PORT: u16 = 853;
+const DOQ_PORT: u16 = 853;
+
+/// Decoded DNS stamp
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stamp {
+    /// Server validates DNSSEC
+    pub dnssec: bool,
+    /// Server doesn't log queries
+    pub no_logs: bool,
+    /// Server doesn't block any names
+    pub no_filter: bool,
+    pub protocol: Protocol,
+}
+
+/// Protocol of the server with the fields needed to reach it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Protocol {
+    /// Plain DNS (`0x00`)
+    Plain { address: SocketAddr },
+    /// DNSCrypt (`0x01`)
+    DnsCrypt {
+        address: SocketAddr,
+        /// Provider's Ed25519 public key
+        public_key: Vec<u8>,
+        /// e.g. `2.dnscrypt-cert.example.com`
+        provider_name: String,
+    },
+    /// DNS over HTTPS (`0x02`)
+    Https(TlsServer),
+    /// DNS over TLS (`0x03`)
+    Tls(TlsServer),
+    /// DNS over QUIC (`0x04`)
+    Quic(TlsServer),
+}
+
+/// Server reached over TLS, authenticated by its host name
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsServer {
+    /// Address of the server, `None` if the host name must be resolved
+    pub address: Option<IpAddr>,
+    pub port: u16,
+    /// Host name for SNI and certificate verification
+    pub hostname: String,
+    /// SHA256 digests of TBS certificates, one of which must be in the server's chain
+    pub cert_hashes: Vec<Vec<u8>>,
+    /// Path of DoH queries, e.g. `/dns-query`
+    pub path: Option<String>,
+    /// Addresses of resolvers for looking up the host name
+    pub bootstrap: Vec<IpAddr>,
+}
+
+impl FromStr for Stamp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s
+            .strip_prefix("sdns://")
+            .context("stamp must start with sdns://")?;
+        let bytes = base64::decode(encoded).context("stamp is not valid base64")?;
+        let mut reader = Reader(&bytes);
+
+        let protocol_id = reader.take(1).context("empty stamp")?[0];
+        let properties = u64::from_le_bytes(
+            reader
+                .take(8)
+                .context("stamp too short")?
+                .try_into()
+                .expect("8 bytes"),
+        );
+
+        let protocol = match protocol_id {
+            0x00 => Protocol::Plain {
+                address: parse_address(&reader.string()?, PLAIN_PORT)?
+                    .context("plain DNS stamp without address")?,
+            },
+            0x01 => Protocol::DnsCrypt {
+                address: parse_address(&reader.string()?, DNSCRYPT_PORT)?
+                    .context("DNSCrypt stamp without address")?,
+                public_key: reader.bytes()?.to_vec(),
+                provider_name: reader.string()?,
+            },
+            0x02 => Protocol::Https(reader.tls_server(DOH_PORT, true)?),
+            0x03 => Protocol::Tls(reader.tls_server(DOT_PORT, false)?),
+            0x04 => Protocol::Quic(reader.tls_server(DOQ_PORT, false)?),
+            id => anyhow::bail!("unsupported stamp protocol {:#04x}", id),
+        };
+
+        Ok(Self {
+            dnssec: properties & 1 != 0,
+            no_logs: properties & 2 != 0,
+            no_filter: properties & 4 != 0,
+            protocol,
+        })
+    }
+}
+
+/// Cursor over the decoded stamp
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = self.0.split_at_checked(len)?;
+        self.0 = rest;
+        Some(taken)
+    }
+
+    /// Length-prefixed bytes (`LP`)
+    fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.take(1).context("stamp too short")?[0];
+        self.take(len as usize).context("stamp too short")
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec()).context("stamp field is not UTF-8")
+    }
+
+    /// Set of length-prefixed bytes (`VLP`), lengths of all but the last have the high bit set
+    fn byte_set(&mut self) -> anyhow::Result<Vec<&'a [u8]>> {
+        let mut items = Vec::new();
+        loop {
+            let len = self.take(1).context("stamp too short")?[0];
+            let item = self
+                .take((len & 0x7f) as usize)
+                .context("stamp too short")?;
+            if !item.is_empty() {
+                items.push(item);
+            }
+            if len & 0x80 == 0 {
+                return Ok(items);
+            }
+        }
+    }
+
+    /// Address, hashes, host name, DoH path and optional bootstrap addresses
+    fn tls_server(&mut self, default_port: u16, has_path: bool) -> anyhow::Result<TlsServer> {
+        let address = parse_address(&self.string()?, default_port)?;
+        let cert_hashes = self.byte_set()?.into_iter().map(<[u8]>::to_vec).collect();
+        let host = self.string()?;
+        let path = if has_path { Some(self.string()?) } else { None };
+        let bootstrap = match self.0 {
+            [] => Vec::new(),
+            _ => self
+                .byte_set()?
+                .into_iter()
+                .map(|address| {
+                    let address = String::from_utf8_lossy(address);
+                    address
+                        .parse()
+                        .with_context(|| format!("invalid bootstrap address {}", address))
+                })
+                .collect::<anyhow::Result<_>>()?,
+        };
+
+        // port given with the host name applies when the address has none
+        let (hostname, host_port) = match host.rsplit_once(':') {
+            Some((hostname, port)) => (hostname, Some(port.parse().context("invalid port")?)),
+            None => (host.as_str(), None),
+        };
+        if hostname.is_empty() {
+            anyhow::bail!("stamp without host name");
+        }
+        let port = match address {
+            Some(address) if address.port() != default_port => address.port(),
+            _ => host_port.unwrap_or(default_port),
+        };
+
+        Ok(TlsServer {
+            address: address.map(|address| address.ip()),
+            port,
+            hostname: hostname.to_string(),
+            cert_hashes,
+            path,
+            bootstrap,
+        })
+    }
+}
+
+/// Parses `ip`, `ip:port`, `[ipv6]` or `[ipv6]:port`, empty address is `None`
+fn parse_address(address: &str, default_port: u16) -> anyhow::Result<Option<SocketAddr>> {
+    if address.is_empty() {
+        return Ok(None);
+    }
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        return Ok(Some(address));
+    }
+
+    let ip = address.trim_start_matches('[').trim_end_matches(']');
+    let ip: IpAddr = ip
+        .parse()
+        .with_context(|| format!("invalid address {} in stamp", address))?;
+    Ok(Some(SocketAddr::new(ip, default_port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stamps() {
+        let doh: Stamp = "sdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQovZG5zLXF1ZXJ5"
+            .parse()
+            .unwrap();
+        assert!(doh.dnssec && doh.no_logs && doh.no_filter);
+        assert_eq!(
+            doh.protocol,
+            Protocol::Https(TlsServer {
+                address: Some("1.0.0.1".parse().unwrap()),
+                port: 443,
+                hostname: "dns.cloudflare.com".into(),
+                cert_hashes: Vec::new(),
+                path: Some("/dns-query".into()),
+                bootstrap: Vec::new(),
+            })
+        );
+
+        let dot: Stamp = "sdns://AwMAAAAAAAAADzE0OS4xMTIuMTEyLjExMiAtcRZCtyawRAFifKn7rDL1yFMPsZA8xNsCJYcXkhpIgQ1kbnMucXVhZDkubmV0"
+            .parse()
+            .unwrap();
+        let Protocol::Tls(server) = dot.protocol else {
+            panic!("DoT stamp expected");
+        };
+        assert_eq!(server.port, 853);
+        assert_eq!(server.hostname, "dns.quad9.net");
+        assert_eq!(server.cert_hashes.len(), 1);
+        assert_eq!(server.cert_hashes[0].len(), 32);
+
+        // no address, port with the host name and a bootstrap resolver
+        let Protocol::Tls(server) = "sdns://AwAAAAAAAAAAAAAQZG90LmV4YW1wbGU6ODg1Mwc5LjkuOS45"
+            .parse::<Stamp>()
+            .unwrap()
+            .protocol
+        else {
+            panic!("DoT stamp expected");
+        };
+        assert_eq!(server.address, None);
+        assert_eq!(
+            (server.hostname.as_str(), server.port),
+            ("dot.example", 8853)
+        );
+        assert_eq!(server.bootstrap, ["9.9.9.9".parse::<IpAddr>().unwrap()]);
+
+        assert!("sdns://AA".parse::<Stamp>().is_err());
+    }
+}
@@ -0,0 +1,470 @@
+//! Server statistics
+//!
+//! Counters of answered queries, cache hits, blocked queries, DNSSEC validation results,
+//! queries shed under overload, cache efficiency and exchanges with each upstream, kept
+//! since the start of the server. A snapshot is printed on `SIGUSR1` ([`dump_on_signal`])
+//! and served by the admin endpoint at `GET /stats`.
+//!
+//! Rankings of the busiest clients, the most queried, most blocked and bogus domains cover
+//! only the last [`RANKING_WINDOW`], they are served at `GET /top` ([`top_report`]).
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::domain_name::DomainName;
+use crate::overload::Overload;
+use crate::packet::DnsPacket;
+use crate::upstream::Upstream;
+
+/// Period covered by the rankings
+pub const RANKING_WINDOW: Duration = Duration::from_secs(3600);
+/// Rankings are kept in buckets of this length, the oldest one is dropped as a new one starts
+const RANKING_BUCKET: Duration = Duration::from_secs(600);
+/// Distinct keys counted in one bucket at most, further ones are ignored
+const RANKING_KEYS: usize = 10_000;
+/// Entries of each ranking in the [`top_report`]
+const TOP: usize = 10;
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static QUERIES: AtomicU64 = AtomicU64::new(0);
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static BLOCKED: AtomicU64 = AtomicU64::new(0);
+static SECURE: AtomicU64 = AtomicU64::new(0);
+static INSECURE: AtomicU64 = AtomicU64::new(0);
+static BOGUS: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static REFUSED: AtomicU64 = AtomicU64::new(0);
+static UPSTREAMS: Mutex<Vec<Arc<UpstreamStats>>> = Mutex::new(Vec::new());
+static CACHES: Mutex<Vec<Arc<CacheStats>>> = Mutex::new(Vec::new());
+static CLIENTS: Ranking = Ranking::new();
+static DOMAINS: Ranking = Ranking::new();
+static BLOCKED_DOMAINS: Ranking = Ranking::new();
+static BOGUS_DOMAINS: Ranking = Ranking::new();
+
+/// Starts measuring uptime
+pub fn start() {
+    STARTED.get_or_init(Instant::now);
+}
+
+/// Counts an answered query from `client` (as it should be logged) for `name`
+pub fn query(client: Option<&str>, name: Option<&DomainName>, cache_hit: bool) {
+    QUERIES.fetch_add(1, Ordering::Relaxed);
+    if cache_hit {
+        CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+    }
+    let now = Instant::now();
+    CLIENTS.record(client.unwrap_or("local"), now);
+    if let Some(name) = name {
+        DOMAINS.record(&name.canonicalize().to_string(), now);
+    }
+}
+
+/// Counts a query for `name` blocked by policy
+pub fn blocked(name: &DomainName) {
+    BLOCKED.fetch_add(1, Ordering::Relaxed);
+    BLOCKED_DOMAINS.record(&name.canonicalize().to_string(), Instant::now());
+}
+
+/// Counts an upstream answer which passed DNSSEC validation, as `secure` or insecure
+pub fn validated(secure: bool) {
+    match secure {
+        true => SECURE.fetch_add(1, Ordering::Relaxed),
+        false => INSECURE.fetch_add(1, Ordering::Relaxed),
+    };
+}
+
+/// Counts an upstream answer for `name` which failed DNSSEC validation
+pub fn bogus(name: &DomainName) {
+    BOGUS.fetch_add(1, Ordering::Relaxed);
+    BOGUS_DOMAINS.record(&name.canonicalize().to_string(), Instant::now());
+}
+
+/// Counts a query shed because too many were being answered, see [`crate::overload`]
+pub fn shed(policy: Overload) {
+    match policy {
+        Overload::Drop => DROPPED.fetch_add(1, Ordering::Relaxed),
+        Overload::Refuse => REFUSED.fetch_add(1, Ordering::Relaxed),
+    };
+}
+
+/// Counts of keys over the last [`RANKING_WINDOW`]
+struct Ranking {
+    /// Start of each bucket and the counts in it, newest last
+    buckets: Mutex<VecDeque<(Instant, HashMap<String, u64>)>>,
+}
+
+impl Ranking {
+    const fn new() -> Self {
+        Self {
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn record(&self, key: &str, now: Instant) {
+        let mut buckets = self.buckets.lock().expect("stats lock poisoned");
+        if buckets
+            .back()
+            .is_none_or(|(start, _)| now.duration_since(*start) >= RANKING_BUCKET)
+        {
+            buckets.push_back((now, HashMap::new()));
+        }
+        while buckets
+            .front()
+            .is_some_and(|(start, _)| now.duration_since(*start) >= RANKING_WINDOW)
+        {
+            buckets.pop_front();
+        }
+
+        let (_, counts) = buckets.back_mut().expect("current bucket was just ensured");
+        if let Some(count) = counts.get_mut(key) {
+            *count += 1;
+        } else if counts.len() < RANKING_KEYS {
+            counts.insert(key.to_string(), 1);
+        }
+    }
+
+    /// `n` keys with the highest counts within the window, highest first
+    fn top(&self, n: usize, now: Instant) -> Vec<(String, u64)> {
+        let buckets = self.buckets.lock().expect("stats lock poisoned");
+        let mut totals: HashMap<&str, u64> = HashMap::new();
+        for (_, counts) in buckets
+            .iter()
+            .filter(|(start, _)| now.duration_since(*start) < RANKING_WINDOW)
+        {
+            for (key, count) in counts {
+                *totals.entry(key).or_default() += count;
+            }
+        }
+
+        let mut top: Vec<(String, u64)> = totals
+            .into_iter()
+            .map(|(key, count)| (key.to_string(), count))
+            .collect();
+        top.sort_by(|(a_key, a), (b_key, b)| b.cmp(a).then_with(|| a_key.cmp(b_key)));
+        top.truncate(n);
+        top
+    }
+}
+
+/// Content and efficiency of one cache, see [`CacheHandler`](crate::handler::CacheHandler)
+///
+/// Expired entries found by lookups count as misses too.
+#[derive(Default)]
+pub struct CacheStats {
+    entries: AtomicU64,
+    /// Approximate memory taken by the entries
+    bytes: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Misses answered from the cache shared with other servers
+    shared_hits: AtomicU64,
+    expired: AtomicU64,
+    /// Entries removed to make room for new ones
+    evicted: AtomicU64,
+    inserts: AtomicU64,
+    /// Entries not stored because the cache was full of live ones
+    rejected: AtomicU64,
+}
+
+/// Counters of a new cache, included in the [`report`]
+pub fn cache() -> Arc<CacheStats> {
+    let stats = Arc::new(CacheStats::default());
+    CACHES
+        .lock()
+        .expect("stats lock poisoned")
+        .push(stats.clone());
+    stats
+}
+
+impl CacheStats {
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn shared_hit(&self) {
+        self.shared_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn expired(&self) {
+        self.expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn evicted(&self) {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of entries and their approximate memory
+    pub fn size(&self) -> (u64, u64) {
+        (
+            self.entries.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Entry of `bytes` was stored
+    pub fn inserted(&self, bytes: usize) {
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+        self.entries.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Entry of `bytes` was removed (or replaced)
+    pub fn removed(&self, bytes: usize) {
+        self.entries.fetch_sub(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn report(&self, report: &mut String, uptime: Duration) {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let inserts = self.inserts.load(Ordering::Relaxed);
+        let (entries, bytes) = self.size();
+        let _ = writeln!(
+            report,
+            "cache: {} entries, ~{} KiB",
+            entries,
+            bytes.div_ceil(1024)
+        );
+        let _ = writeln!(
+            report,
+            "cache lookups: {} hits, {} misses ({:.1}% hit ratio), {} expired",
+            hits,
+            misses,
+            percentage(hits, hits + misses),
+            self.expired.load(Ordering::Relaxed)
+        );
+        let shared_hits = self.shared_hits.load(Ordering::Relaxed);
+        if shared_hits > 0 {
+            let _ = writeln!(
+                report,
+                "shared cache: {} hits ({:.1}% of misses)",
+                shared_hits,
+                percentage(shared_hits, misses)
+            );
+        }
+        let _ = writeln!(
+            report,
+            "cache inserts: {} ({:.2}/s), {} evicted, {} rejected as full",
+            inserts,
+            inserts as f64 / uptime.as_secs_f64().max(1.0),
+            self.evicted.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Exchanges with one upstream
+#[derive(Default)]
+struct UpstreamStats {
+    name: String,
+    successes: AtomicU64,
+    failures: AtomicU64,
+    /// Sum of the durations of successful exchanges
+    latency_micros: AtomicU64,
+}
+
+/// Upstream whose exchanges are counted under `name`
+pub struct MeasuredUpstream {
+    inner: Arc<dyn Upstream>,
+    stats: Arc<UpstreamStats>,
+}
+
+/// Counts exchanges with `upstream`, reported as `name`
+pub fn measure(name: impl ToString, upstream: Arc<dyn Upstream>) -> Arc<dyn Upstream> {
+    let stats = Arc::new(UpstreamStats {
+        name: name.to_string(),
+        ..UpstreamStats::default()
+    });
+    UPSTREAMS
+        .lock()
+        .expect("stats lock poisoned")
+        .push(stats.clone());
+    Arc::new(MeasuredUpstream {
+        inner: upstream,
+        stats,
+    })
+}
+
+impl Upstream for MeasuredUpstream {
+    fn exchange(&self, packet: &DnsPacket) -> Result<DnsPacket> {
+        let started = Instant::now();
+        let response = self.inner.exchange(packet);
+        match &response {
+            Ok(_) => {
+                let micros = started.elapsed().as_micros() as u64;
+                self.stats.successes.fetch_add(1, Ordering::Relaxed);
+                self.stats
+                    .latency_micros
+                    .fetch_add(micros, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.stats.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        response
+    }
+}
+
+/// Human readable snapshot of the counters
+pub fn report() -> String {
+    let uptime = STARTED
+        .get()
+        .map_or(Duration::ZERO, |started| started.elapsed());
+    let queries = QUERIES.load(Ordering::Relaxed);
+    let cache_hits = CACHE_HITS.load(Ordering::Relaxed);
+
+    let mut report = String::new();
+    let _ = writeln!(report, "uptime: {}s", uptime.as_secs());
+    let _ = writeln!(report, "queries: {}", queries);
+    let _ = writeln!(
+        report,
+        "cache hits: {} ({:.1}%)",
+        cache_hits,
+        percentage(cache_hits, queries)
+    );
+    let _ = writeln!(report, "blocked: {}", BLOCKED.load(Ordering::Relaxed));
+    let validated = [&SECURE, &INSECURE, &BOGUS].map(|counter| counter.load(Ordering::Relaxed));
+    if validated.iter().any(|&count| count > 0) {
+        let [secure, insecure, bogus] = validated;
+        let _ = writeln!(
+            report,
+            "dnssec: {} secure, {} insecure, {} bogus",
+            secure, insecure, bogus
+        );
+    }
+    let shed = [&DROPPED, &REFUSED].map(|counter| counter.load(Ordering::Relaxed));
+    if shed.iter().any(|&count| count > 0) {
+        let [dropped, refused] = shed;
+        let _ = writeln!(report, "overload: {} dropped, {} refused", dropped, refused);
+    }
+    for cache in CACHES.lock().expect("stats lock poisoned").iter() {
+        cache.report(&mut report, uptime);
+    }
+    for upstream in UPSTREAMS.lock().expect("stats lock poisoned").iter() {
+        let successes = upstream.successes.load(Ordering::Relaxed);
+        let failures = upstream.failures.load(Ordering::Relaxed);
+        let latency = match successes {
+            0 => 0.0,
+            _ => upstream.latency_micros.load(Ordering::Relaxed) as f64 / successes as f64 / 1000.0,
+        };
+        let _ = writeln!(
+            report,
+            "upstream {}: {} ok, {} failed ({:.1}% success), {:.3}ms average",
+            upstream.name,
+            successes,
+            failures,
+            percentage(successes, successes + failures),
+            latency
+        );
+    }
+    report
+}
+
+/// Busiest clients, most queried, most blocked and bogus domains over the last [`RANKING_WINDOW`]
+pub fn top_report() -> String {
+    let now = Instant::now();
+    let mut report = String::new();
+    for (title, ranking) in [
+        ("clients", &CLIENTS),
+        ("domains", &DOMAINS),
+        ("blocked domains", &BLOCKED_DOMAINS),
+        ("bogus domains", &BOGUS_DOMAINS),
+    ] {
+        let _ = writeln!(report, "top {}:", title);
+        for (key, count) in ranking.top(TOP, now) {
+            let _ = writeln!(report, "  {} {}", count, key);
+        }
+    }
+    report
+}
+
+fn percentage(part: u64, total: u64) -> f64 {
+    match total {
+        0 => 0.0,
+        _ => part as f64 * 100.0 / total as f64,
+    }
+}
+
+/// Prints the [`report`] to stderr whenever the process receives `SIGUSR1`
+#[cfg(unix)]
+pub fn dump_on_signal() -> Result<()> {
+    use signal_hook::{consts::SIGUSR1, iterator::Signals};
+
+    let mut signals = Signals::new([SIGUSR1])?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            eprint!("{}", report());
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FlakyUpstream;
+
+    impl Upstream for FlakyUpstream {
+        fn exchange(&self, packet: &DnsPacket) -> Result<DnsPacket> {
+            match packet.header.id {
+                0 => anyhow::bail!("timeout"),
+                _ => Ok(packet.clone()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_upstream_exchanges_are_counted() {
+        let upstream = measure("flaky", Arc::new(FlakyUpstream));
+        let mut query = DnsPacket::new();
+        assert!(upstream.exchange(&query).is_err());
+        query.header.id = 1;
+        assert!(upstream.exchange(&query).is_ok());
+        assert!(upstream.exchange(&query).is_ok());
+
+        let report = report();
+        let line = report
+            .lines()
+            .find(|line| line.starts_with("upstream flaky:"))
+            .unwrap();
+        assert!(line.contains("2 ok, 1 failed (66.7% success)"), "{}", line);
+    }
+
+    #[test]
+    fn test_rankings_cover_only_the_window() {
+        let ranking = Ranking::new();
+        let start = Instant::now();
+        for key in ["a", "b", "b"] {
+            ranking.record(key, start);
+        }
+        let later = start + RANKING_WINDOW - RANKING_BUCKET;
+        for key in ["a", "a", "c"] {
+            ranking.record(key, later);
+        }
+        assert_eq!(
+            ranking.top(2, later),
+            [("a".to_string(), 3), ("b".to_string(), 2)]
+        );
+
+        let after_window = start + RANKING_WINDOW;
+        ranking.record("c", after_window);
+        assert_eq!(
+            ranking.top(10, after_window),
+            [("a".to_string(), 2), ("c".to_string(), 2)]
+        );
+    }
+}
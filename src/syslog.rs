@@ -0,0 +1,207 @@
+//! Logging to syslog (RFC 5424 messages)
+//!
+//! Messages go either to the local syslog daemon through its `/dev/log` socket, or
+//! over UDP to a remote collector (RFC 5426). Sending never blocks query processing:
+//! messages the daemon can't take right now are dropped.
+
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+
+use crate::log::timestamp;
+
+/// Socket of the local syslog daemon
+#[cfg(unix)]
+const LOCAL_SOCKET: &str = "/dev/log";
+const APP_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Facility the messages are filed under
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Facility(u8);
+
+impl Facility {
+    pub const DAEMON: Self = Self(3);
+}
+
+impl Default for Facility {
+    fn default() -> Self {
+        Self::DAEMON
+    }
+}
+
+/// Parses facility names as in syslog.conf (`daemon`, `local0`, ...)
+impl FromStr for Facility {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let code = match s {
+            "kern" => 0,
+            "user" => 1,
+            "mail" => 2,
+            "daemon" => 3,
+            "auth" => 4,
+            "syslog" => 5,
+            "lpr" => 6,
+            "news" => 7,
+            "uucp" => 8,
+            "cron" => 9,
+            "authpriv" => 10,
+            "ftp" => 11,
+            _ => match s.strip_prefix("local").and_then(|n| n.parse::<u8>().ok()) {
+                Some(n @ 0..=7) => 16 + n,
+                _ => anyhow::bail!("unknown syslog facility '{}'", s),
+            },
+        };
+        Ok(Self(code))
+    }
+}
+
+/// Importance of the message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+}
+
+enum Socket {
+    #[cfg(unix)]
+    Local(UnixDatagram),
+    Remote(UdpSocket),
+}
+
+/// Connection to a syslog daemon
+pub struct Syslog {
+    socket: Socket,
+    facility: Facility,
+    hostname: String,
+    /// Description for the startup message
+    destination: String,
+}
+
+impl Syslog {
+    /// Connects to the local daemon
+    #[cfg(unix)]
+    pub fn local(facility: Facility) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(LOCAL_SOCKET)
+            .with_context(|| format!("failed to connect to {}", LOCAL_SOCKET))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: Socket::Local(socket),
+            facility,
+            hostname: hostname(),
+            destination: LOCAL_SOCKET.to_string(),
+        })
+    }
+
+    /// Sends messages to the collector at `address` (`host:port`) over UDP
+    pub fn remote(address: &str, facility: Facility) -> Result<Self> {
+        let collector = address
+            .to_socket_addrs()
+            .with_context(|| format!("invalid syslog address {}", address))?
+            .next()
+            .with_context(|| format!("{} has no addresses", address))?;
+        let local: SocketAddr = match collector {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(collector)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: Socket::Remote(socket),
+            facility,
+            hostname: hostname(),
+            destination: address.to_string(),
+        })
+    }
+
+    /// Connects to `local` daemon or a remote `host:port`
+    pub fn open(destination: &str, facility: Facility) -> Result<Self> {
+        match destination {
+            #[cfg(unix)]
+            "local" => Self::local(facility),
+            #[cfg(not(unix))]
+            "local" => anyhow::bail!("local syslog is not supported on this platform"),
+            address => Self::remote(address, facility),
+        }
+    }
+
+    /// Sends `message` with `severity`
+    pub fn send(&self, severity: Severity, message: &str) {
+        let message = self.format(severity, SystemTime::now(), message);
+        let _ = match &self.socket {
+            #[cfg(unix)]
+            Socket::Local(socket) => socket.send(message.as_bytes()),
+            Socket::Remote(socket) => socket.send(message.as_bytes()),
+        };
+    }
+
+    /// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID SD MSG`
+    fn format(&self, severity: Severity, time: SystemTime, message: &str) -> String {
+        format!(
+            "<{}>1 {} {} {} {} - - {}",
+            self.facility.0 as u16 * 8 + severity as u16,
+            timestamp(time),
+            self.hostname,
+            APP_NAME,
+            std::process::id(),
+            message
+        )
+    }
+}
+
+impl fmt::Display for Syslog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.destination)
+    }
+}
+
+/// Name of this host, `-` (nil value) if it is unknown
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty() && name.is_ascii())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_facilities() {
+        assert_eq!("daemon".parse::<Facility>().unwrap(), Facility::DAEMON);
+        assert_eq!("local7".parse::<Facility>().unwrap(), Facility(23));
+        assert!("local8".parse::<Facility>().is_err());
+    }
+
+    #[test]
+    fn test_messages_are_sent_to_remote_collector() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = collector.local_addr().unwrap().to_string();
+        let syslog = Syslog::remote(&address, "local0".parse().unwrap()).unwrap();
+
+        let time = UNIX_EPOCH + Duration::from_millis(1500);
+        let message = syslog.format(Severity::Warning, time, "hello");
+        assert!(message.starts_with("<132>1 1970-01-01T00:00:01.500Z "));
+        assert!(message.ends_with(&format!(" {} - - hello", std::process::id())));
+
+        syslog.send(Severity::Info, "hello");
+        let mut buf = [0; 512];
+        let size = collector.recv(&mut buf).unwrap();
+        let received = std::str::from_utf8(&buf[..size]).unwrap();
+        assert!(received.starts_with("<134>1 "), "{}", received);
+        assert!(received.ends_with("hello"));
+    }
+}
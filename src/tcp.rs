@@ -0,0 +1,491 @@
+//! DNS over TCP (RFC 7766)
+//!
+//! Messages are prefixed with two byte length (RFC 1035 section 4.2.2). TCP carries
+//! answers too large for UDP and zone transfers, which are not allowed over UDP
+//! (RFC 5936 section 4.2). Answers too large even for a single TCP message are split
+//! into several messages.
+//!
+//! Queries signed with one of the TSIG keys get signed responses, the handlers see
+//! the key in [`Request::tsig_key`]. Queries with a bad signature are answered NOTAUTH.
+//!
+//! Queries pipelined on one connection are answered concurrently, responses go out as
+//! soon as they are ready, in any order.
+//!
+//! With `--proxy-protocol`, connections start with PROXY protocol header naming the client
+//! (see [`crate::proxy_protocol`]).
+//!
+//! Number of connections and their idle time are limited, see [`crate::connections`].
+//! Once a message starts arriving, all of it must arrive within the message timeout,
+//! so clients trickling bytes (slowloris) can't hold connections forever.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use bytes::BytesMut;
+
+use crate::connections::{Connection, ConnectionLimits, ConnectionTracker};
+use crate::edns::{EdnsOption, OPTION_TCP_KEEPALIVE};
+use crate::handler::{response_builder, Request};
+use crate::header::ResponseCode;
+use crate::listen;
+use crate::log;
+use crate::packet::{BytesPacket, DnsPacket, ParseMode};
+use crate::proxy_protocol;
+use crate::trace;
+use crate::tsig::{self, TsigKey};
+
+/// Largest DNS message, limited by the two byte length prefix
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// Room left in split messages for the TSIG record
+const TSIG_RESERVE: usize = 512;
+
+/// Queries of one connection answered at the same time at most
+const MAX_PIPELINED: usize = 16;
+
+/// How often a connection with queries being answered checks for their responses
+const RESPONSE_POLL: Duration = Duration::from_millis(10);
+
+/// How long a connection may stay open without any query by default (RFC 7766 section 6.2.3)
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the rest of a message may take to arrive after its first byte by default
+pub const MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves queries on `address`, each connection in its own thread
+///
+/// Queries signed with one of `keys` are answered with signed responses, queries are
+/// parsed as strictly as `mode` says. With `proxy_protocol`, every connection must start
+/// with PROXY protocol v2 header (see [`crate::proxy_protocol`]) naming the client.
+pub fn serve<F>(
+    address: &str,
+    keys: Vec<TsigKey>,
+    limits: ConnectionLimits,
+    mode: ParseMode,
+    proxy_protocol: bool,
+    handler: F,
+) -> Result<()>
+where
+    F: Fn(&Request) -> Result<DnsPacket> + Send + Sync + 'static,
+{
+    let listener = listen::tcp(address)
+        .with_context(|| format!("Failed to bind TCP listener to {}", address))?;
+
+    println!("Listening on TCP {}", address);
+
+    let keys: Arc<[TsigKey]> = keys.into();
+    let handler = Arc::new(handler);
+    let tracker = ConnectionTracker::new(limits);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("TCP: error accepting connection: {}", e);
+                continue;
+            }
+        };
+        let connection = match tracker.admit(&stream) {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("TCP: refused connection: {:#}", e);
+                continue;
+            }
+        };
+
+        let (keys, handler) = (keys.clone(), handler.clone());
+        thread::spawn(move || {
+            let handled = handle_connection(
+                stream,
+                &connection,
+                &keys,
+                mode,
+                proxy_protocol,
+                handler.as_ref(),
+            );
+            if let Err(e) = handled {
+                eprintln!("TCP: error handling connection: {:#}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    connection: &Connection,
+    keys: &[TsigKey],
+    mode: ParseMode,
+    proxy_protocol: bool,
+    handler: &(impl Fn(&Request) -> Result<DnsPacket> + Sync),
+) -> Result<()> {
+    let client = match proxy_protocol {
+        true => proxy_protocol::read_client(&stream, connection.message_timeout())?,
+        false => stream.peer_addr()?,
+    };
+    handle_messages(&mut stream, client, connection, keys, mode, handler)
+}
+
+/// Stream whose reads time out
+pub(crate) trait ReadTimeout {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl ReadTimeout for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Answers length-prefixed queries until the client is done or idle for too long
+///
+/// Queries pipelined on the connection are answered concurrently, each response is
+/// written as soon as it's ready, possibly out of order (RFC 7766 section 6.2.1.1).
+/// Clients match responses to queries by ID.
+pub(crate) fn handle_messages(
+    stream: &mut (impl Read + Write + ReadTimeout),
+    client: SocketAddr,
+    connection: &Connection,
+    keys: &[TsigKey],
+    mode: ParseMode,
+    handler: &(impl Fn(&Request) -> Result<DnsPacket> + Sync),
+) -> Result<()> {
+    use std::io::ErrorKind;
+
+    let (sender, responses) = mpsc::channel::<Result<Vec<u8>>>();
+    thread::scope(|scope| {
+        let mut pending = 0;
+        loop {
+            // write responses answered meanwhile, wait for one if too many queries are pending
+            while pending > 0 {
+                let framed = if pending >= MAX_PIPELINED {
+                    responses.recv()?
+                } else {
+                    match responses.try_recv() {
+                        Ok(framed) => framed,
+                        Err(_) => break,
+                    }
+                };
+                pending -= 1;
+                stream.write_all(&framed?)?;
+                if pending == 0 {
+                    connection.idle();
+                }
+            }
+
+            let mut len = [0; 2];
+            let timeout = match pending {
+                0 => connection.idle_timeout(),
+                _ => RESPONSE_POLL,
+            };
+            stream.set_read_timeout(Some(timeout))?;
+            match stream.read_exact(&mut len[..1]) {
+                Ok(()) => {}
+                Err(e)
+                    if pending > 0
+                        && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    continue
+                }
+                // client is done or idle for too long
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+                Err(e) => return Err(e.into()),
+            }
+            if pending == 0 {
+                connection.busy();
+            }
+
+            let deadline = Instant::now() + connection.message_timeout();
+            read_before(stream, &mut len[1..], deadline)?;
+            let mut message = vec![0; u16::from_be_bytes(len) as usize];
+            read_before(stream, &mut message, deadline)?;
+
+            pending += 1;
+            let sender = sender.clone();
+            let idle_timeout = connection.idle_timeout();
+            scope.spawn(move || {
+                let answered = panic::catch_unwind(AssertUnwindSafe(|| {
+                    answer(&message, client, idle_timeout, keys, mode, handler)
+                }))
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("handler panicked")));
+                let framed = answered.map(|messages| {
+                    let mut framed = Vec::new();
+                    for response in messages {
+                        framed.extend_from_slice(&(response.len() as u16).to_be_bytes());
+                        framed.extend_from_slice(&response);
+                    }
+                    framed
+                });
+                // the connection is gone if nobody receives
+                let _ = sender.send(framed);
+            });
+        }
+
+        // the client may half-close after its last query and still wait for the responses
+        for _ in 0..pending {
+            stream.write_all(&responses.recv()??)?;
+        }
+        connection.idle();
+        Ok(())
+    })
+}
+
+/// Fills `buf` from the stream, fails if the data doesn't arrive before `deadline`
+fn read_before(
+    stream: &mut (impl Read + ReadTimeout),
+    buf: &mut [u8],
+    deadline: Instant,
+) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            anyhow::bail!(
+                "message not received in time ({} bytes missing)",
+                buf.len() - filled
+            );
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => anyhow::bail!("connection closed in the middle of a message"),
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Parses the query and serializes its response, possibly split into several messages
+fn answer(
+    message: &[u8],
+    client: SocketAddr,
+    idle_timeout: Duration,
+    keys: &[TsigKey],
+    mode: ParseMode,
+    handler: &impl Fn(&Request) -> Result<DnsPacket>,
+) -> Result<Vec<BytesMut>> {
+    let query = DnsPacket::parse_with(message, mode).context("malformed query")?;
+    if log::is_text() {
+        println!("<<< Received DNS packet (TCP from {}):\n{}", client, query);
+    }
+    let keepalive = query
+        .opt
+        .as_ref()
+        .is_some_and(|opt| opt.has_option(OPTION_TCP_KEEPALIVE));
+    let mut span = trace::query_span("tcp", &query, Some(client));
+    let query_log = log::query("tcp");
+
+    let mut request = Request::new(query, Some(client));
+    let mut signer = match tsig::verify(keys, message) {
+        Ok(signer) => signer,
+        Err(e) => {
+            eprintln!("TCP: rejected query from {}: {}", client, e);
+            let response = response_builder(&request.query)
+                .rescode(ResponseCode::NOTAUTH)
+                .additional(e.record(request.query.header.id))
+                .build();
+            span.response(&response);
+            query_log.finish(Some(client), &response);
+            return Ok(vec![BytesPacket::from(response).buf]);
+        }
+    };
+    if let Some(signer) = &signer {
+        // TSIG is the last additional record, handlers don't need it
+        request.query.additionals.pop();
+        request.query.header.additional_entries -= 1;
+        request = request.signed_by(signer.key_name().clone());
+    }
+
+    let mut response = span.record(handler(&request))?;
+    if let (Some(opt), true) = (response.opt.as_mut(), keepalive) {
+        opt.options
+            .push(EdnsOption::tcp_keepalive(Some(idle_timeout)));
+    }
+    span.response(&response);
+    query_log.finish(Some(client), &response);
+    if log::is_text() {
+        println!(">>> Sent DNS packet (TCP to {}):\n{}", client, response);
+    }
+
+    let _span = trace::span("dns.serialize");
+    let mut messages = split(response);
+    if let Some(signer) = signer.as_mut() {
+        for message in messages.iter_mut() {
+            signer.sign(message);
+        }
+    }
+    Ok(messages)
+}
+
+/// Wire format of the response, answers which don't fit into one message are spread over several
+///
+/// Every message repeats the question, authority and additional records go to the first one
+/// (RFC 5936 section 2.2).
+fn split(response: DnsPacket) -> Vec<BytesMut> {
+    let limit = MAX_MESSAGE_SIZE - TSIG_RESERVE;
+    let whole = BytesPacket::from(&response).buf;
+    if whole.len() <= limit {
+        return vec![whole];
+    }
+
+    let mut template = response;
+    let mut rest = std::mem::take(&mut template.answers);
+    let mut messages = Vec::new();
+    let mut count = rest.len();
+    while !rest.is_empty() {
+        // halve the number of answers until they fit
+        count = count.min(rest.len());
+        let message = loop {
+            let mut message = template.clone();
+            message.answers = rest[..count].to_vec();
+            message.header.answer_entries = count as u16;
+            let bytes = BytesPacket::from(message).buf;
+            if bytes.len() <= limit || count == 1 {
+                break bytes;
+            }
+            count /= 2;
+        };
+        messages.push(message);
+        rest.drain(..count);
+
+        template.authorities.clear();
+        template.header.authoritative_entries = 0;
+        template.additionals.clear();
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::domain_name::DomainName;
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+
+    #[test]
+    fn test_trickled_message_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        client.write_all(&[0]).unwrap();
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(200);
+        let error = read_before(&mut server, &mut [0; 2], deadline).unwrap_err();
+
+        assert!(
+            error.to_string().contains("not received in time"),
+            "{}",
+            error
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_pipelined_queries_are_answered_out_of_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, address) = listener.accept().unwrap();
+        let tracker = ConnectionTracker::new(ConnectionLimits::default());
+        let connection = tracker.admit(&server).unwrap();
+        let handler = |request: &Request| {
+            if request.query.questions[0].domain_name == DomainName::from("slow.test") {
+                thread::sleep(Duration::from_millis(300));
+            }
+            Ok(response_builder(&request.query).build())
+        };
+        thread::spawn(move || {
+            handle_messages(
+                &mut server,
+                address,
+                &connection,
+                &[],
+                ParseMode::Lenient,
+                &handler,
+            )
+        });
+
+        for (id, name) in [(1, "slow.test"), (2, "fast.test")] {
+            let query = DnsPacket::builder()
+                .id(id)
+                .question(DnsQuestion::new(
+                    DomainName::from(name),
+                    QueryType::A,
+                    QueryClass::IN,
+                ))
+                .build();
+            let bytes = BytesPacket::from(query).buf;
+            client
+                .write_all(&(bytes.len() as u16).to_be_bytes())
+                .unwrap();
+            client.write_all(&bytes).unwrap();
+        }
+
+        let ids: Vec<u16> = (0..2)
+            .map(|_| {
+                let mut len = [0; 2];
+                client.read_exact(&mut len).unwrap();
+                let mut message = vec![0; u16::from_be_bytes(len) as usize];
+                client.read_exact(&mut message).unwrap();
+                DnsPacket::parse(&message).unwrap().header.id
+            })
+            .collect();
+        assert_eq!(ids, [2, 1]);
+    }
+
+    #[test]
+    fn test_client_address_is_taken_from_proxy_header() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let tracker = ConnectionTracker::new(ConnectionLimits::default());
+        let connection = tracker.admit(&server).unwrap();
+        let seen = std::sync::Mutex::new(None);
+        let handler = |request: &Request| {
+            *seen.lock().unwrap() = request.client;
+            Ok(response_builder(&request.query).build())
+        };
+
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]); // v2 PROXY, TCP over IPv4
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xC3, 0x50, 0x00, 0x35]);
+        client.write_all(&header).unwrap();
+        let query = DnsPacket::builder()
+            .id(7)
+            .question(DnsQuestion::new(
+                DomainName::from("example.com"),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .build();
+        let bytes = BytesPacket::from(query).buf;
+        client
+            .write_all(&(bytes.len() as u16).to_be_bytes())
+            .unwrap();
+        client.write_all(&bytes).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+
+        handle_connection(server, &connection, &[], ParseMode::Lenient, true, &handler).unwrap();
+        drop(connection); // the tracker's handle keeps the connection open otherwise
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert_eq!(DnsPacket::parse(&response[2..]).unwrap().header.id, 7);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            Some("192.0.2.1:50000".parse().unwrap())
+        );
+    }
+}
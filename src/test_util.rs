@@ -0,0 +1,369 @@
+//! Helpers for testing code which builds or parses DNS messages
+//!
+//! - [`PacketGenerator`] - random but valid packets, reproducible from a seed
+//! - [`CORPUS`] - messages as real-world clients and servers send them
+//! - [`assert_round_trip`], [`assert_wire_round_trip`] - encoding and parsing give back the same
+//!
+//! New record types and parser changes get property-style coverage by running every
+//! generated packet and the whole corpus through the round-trip assertions:
+//!
+//! ```
+//! use dns_starter_rust::test_util::{assert_round_trip, PacketGenerator};
+//!
+//! for packet in PacketGenerator::seeded(7).take(100) {
+//!     assert_round_trip(&packet);
+//! }
+//! ```
+//!
+//! Available in the crate's own tests and with the `test_util` feature.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::domain_name::DomainName;
+use crate::edns::{EdnsOption, OptRecord};
+use crate::header::ResponseCode;
+use crate::hexdump::hexdump;
+use crate::packet::{BytesPacket, DnsPacket};
+use crate::question::{DnsQuestion, QueryClass, QueryType};
+use crate::record::{DnsRecord, RecordClass, RecordData, RecordType, Soa};
+
+/// Records in each section of generated packets by default
+pub const DEFAULT_MAX_RECORDS: usize = 4;
+
+/// Response codes kept as they are on the wire (unknown ones are read as NOERROR)
+const RESPONSE_CODES: &[ResponseCode] = &[
+    ResponseCode::NOERROR,
+    ResponseCode::FORMERR,
+    ResponseCode::SERVFAIL,
+    ResponseCode::NXDOMAIN,
+    ResponseCode::NOTIMP,
+    ResponseCode::REFUSED,
+    ResponseCode::NOTAUTH,
+];
+
+/// Types whose RDATA is kept opaque: TXT, SRV, DS, RRSIG, DNSKEY and an unassigned one
+const OPAQUE_TYPES: &[u16] = &[16, 33, 43, 46, 48, 65280];
+
+/// Random but valid packets, the same seed gives the same packets
+///
+/// Names of a packet often share suffixes, so the packets exercise message compression.
+/// Generated packets parse back in [`ParseMode::Strict`](crate::packet::ParseMode::Strict).
+pub struct PacketGenerator {
+    rng: StdRng,
+    max_records: usize,
+    /// Names of the packet being generated, for suffixes of the next ones
+    names: Vec<DomainName>,
+}
+
+impl PacketGenerator {
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            max_records: DEFAULT_MAX_RECORDS,
+            names: Vec::new(),
+        }
+    }
+
+    /// Generates at most `max_records` records in each section
+    pub fn max_records(mut self, max_records: usize) -> Self {
+        self.max_records = max_records;
+        self
+    }
+
+    /// Name of 1-4 labels, mostly letters, digits and hyphens of both cases
+    ///
+    /// Often a name generated before or its subdomain.
+    pub fn name(&mut self) -> DomainName {
+        let name = match self.names.choose(&mut self.rng).cloned() {
+            Some(name) if self.rng.gen_bool(0.2) => name,
+            Some(parent) if self.rng.gen_bool(0.5) && parent.label_count() < 4 => {
+                let label = self.label();
+                DomainName::from_labels(
+                    std::iter::once(label).chain(parent.labels().map(Box::from)),
+                )
+            }
+            _ => {
+                let count = self.rng.gen_range(1..=3);
+                DomainName::from_labels((0..count).map(|_| self.label()).collect::<Vec<_>>())
+            }
+        };
+        self.names.push(name.clone());
+        name
+    }
+
+    fn label(&mut self) -> Box<[u8]> {
+        const LDH: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-";
+        let len = self.rng.gen_range(1..=12);
+        (0..len)
+            .map(|_| match self.rng.gen_bool(0.05) {
+                // labels are binary on the wire
+                true => self.rng.gen(),
+                false => *LDH.choose(&mut self.rng).unwrap(),
+            })
+            .collect()
+    }
+
+    pub fn question(&mut self) -> DnsQuestion {
+        let query_type = match self.rng.gen_bool(0.5) {
+            true => QueryType::A,
+            false => QueryType::from(self.rng.gen_range(2..=255)),
+        };
+        let class = *[1, 1, 1, 3, 255].choose(&mut self.rng).unwrap();
+        DnsQuestion::new(self.name(), query_type, QueryClass::from(class))
+    }
+
+    /// Record of a random type with RDATA matching the type
+    pub fn record(&mut self) -> DnsRecord {
+        let (record_type, data) = match self.rng.gen_range(0..7) {
+            0 => (
+                RecordType::A,
+                RecordData::A(Ipv4Addr::from(self.rng.gen::<u32>())),
+            ),
+            1 => (
+                RecordType::AAAA,
+                RecordData::Aaaa(Ipv6Addr::from(self.rng.gen::<u128>())),
+            ),
+            2 => {
+                let record_type = [RecordType::NS, RecordType::CNAME, RecordType::PTR]
+                    .choose(&mut self.rng)
+                    .unwrap()
+                    .clone();
+                (record_type, RecordData::Name(self.name()))
+            }
+            3 => (
+                RecordType::MX,
+                RecordData::Mx {
+                    preference: self.rng.gen(),
+                    exchange: self.name(),
+                },
+            ),
+            4 => (
+                RecordType::SOA,
+                RecordData::Soa(Soa {
+                    mname: self.name(),
+                    rname: self.name(),
+                    serial: self.rng.gen(),
+                    refresh: self.rng.gen(),
+                    retry: self.rng.gen(),
+                    expire: self.rng.gen(),
+                    minimum: self.rng.gen(),
+                }),
+            ),
+            _ => {
+                let record_type = *OPAQUE_TYPES.choose(&mut self.rng).unwrap();
+                let len = self.rng.gen_range(0..=64);
+                let data = (0..len).map(|_| self.rng.gen()).collect();
+                (RecordType::from(record_type), RecordData::Unknown(data))
+            }
+        };
+        let class = match self.rng.gen_bool(0.9) {
+            true => RecordClass::IN,
+            false => RecordClass::CH,
+        };
+        let ttl = self.rng.gen_range(0..=604800);
+        DnsRecord::new(self.name(), record_type, class, ttl, data)
+    }
+
+    pub fn opt(&mut self) -> OptRecord {
+        let mut opt = OptRecord::new(self.rng.gen_range(512..=4096));
+        opt.dnssec_ok = self.rng.gen();
+        for _ in 0..self.rng.gen_range(0..=2) {
+            let len = self.rng.gen_range(0..=16);
+            opt.options.push(EdnsOption {
+                code: self.rng.gen_range(1..=20),
+                data: (0..len).map(|_| self.rng.gen()).collect(),
+            });
+        }
+        opt
+    }
+
+    /// Query or response with random flags, sections and maybe EDNS
+    pub fn packet(&mut self) -> DnsPacket {
+        self.names.clear();
+        let mut builder = DnsPacket::builder()
+            .id(self.rng.gen())
+            .opcode(self.rng.gen_range(0..=5))
+            .recursion_desired(self.rng.gen())
+            .question(self.question());
+        if self.rng.gen_bool(0.7) {
+            let rescode = *RESPONSE_CODES.choose(&mut self.rng).unwrap();
+            let max = self.max_records;
+            builder = builder
+                .response()
+                .rescode(rescode)
+                .authoritative_answer(self.rng.gen())
+                .recursion_available(self.rng.gen())
+                .authed_data(self.rng.gen())
+                .answers(self.records(max))
+                .authorities(self.records(max / 2))
+                .additionals(self.records(max / 2));
+        }
+        let opt = self.rng.gen_bool(0.5).then(|| self.opt());
+        builder.opt(opt).build()
+    }
+
+    fn records(&mut self, max: usize) -> Vec<DnsRecord> {
+        let count = self.rng.gen_range(0..=max);
+        (0..count).map(|_| self.record()).collect()
+    }
+}
+
+/// Endless stream of [`PacketGenerator::packet`]
+impl Iterator for PacketGenerator {
+    type Item = DnsPacket;
+
+    fn next(&mut self) -> Option<DnsPacket> {
+        Some(self.packet())
+    }
+}
+
+/// Messages as real-world clients and servers send them, by description
+///
+/// Names are compressed the way servers do it, including pointers into RDATA and
+/// into the middle of earlier names.
+pub const CORPUS: &[(&str, &[u8])] = &[
+    (
+        "dig query with EDNS cookie",
+        &[
+            0x3c, 0x1e, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // header
+            0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00,
+            0x01, 0x00, 0x01, // example.com. IN A
+            0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, // OPT
+            0x00, 0x0a, 0x00, 0x08, 0x5e, 0x1f, 0x9a, 0x3b, 0x20, 0xc7, 0x41, 0x0d, // COOKIE
+        ],
+    ),
+    (
+        "resolver answer with server cookie",
+        &[
+            0x3c, 0x1e, 0x81, 0xa0, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, // header
+            0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00,
+            0x01, 0x00, 0x01, // example.com. IN A
+            0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x04, 0x5d, 0xb8,
+            0xd8, 0x22, // example.com. 3600 IN A 93.184.216.34
+            0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1c, // OPT
+            0x00, 0x0a, 0x00, 0x18, 0x5e, 0x1f, 0x9a, 0x3b, 0x20, 0xc7, 0x41, 0x0d, 0x01, 0x00,
+            0x00, 0x00, 0x66, 0x2f, 0x8e, 0x11, 0x9c, 0x5d, 0x31, 0x7a, 0xe2, 0x08, 0x4b,
+            0xf6, // COOKIE, client and server part
+        ],
+    ),
+    (
+        "CNAME chain",
+        &[
+            0x9a, 0x41, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, // header
+            0x03, b'w', b'w', b'w', 0x06, b'g', b'i', b't', b'h', b'u', b'b', 0x03, b'c', b'o',
+            b'm', 0x00, 0x00, 0x01, 0x00, 0x01, // www.github.com. IN A
+            0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x02, 0xc0,
+            0x10, // www.github.com. 3600 IN CNAME github.com.
+            0xc0, 0x10, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 0x8c, 0x52,
+            0x79, 0x04, // github.com. 60 IN A 140.82.121.4
+        ],
+    ),
+    (
+        "NXDOMAIN with SOA",
+        &[
+            0x52, 0x07, 0x81, 0x83, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, // header
+            0x0b, b'n', b'o', b'n', b'e', b'x', b'i', b's', b't', b'e', b'n', b't', 0x07, b'e',
+            b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00,
+            0x01, // nonexistent.example.com. IN A
+            0xc0, 0x18, 0x00, 0x06, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x2c, // SOA
+            0x02, b'n', b's', 0x05, b'i', b'c', b'a', b'n', b'n', 0x03, b'o', b'r', b'g',
+            0x00, // ns.icann.org.
+            0x03, b'n', b'o', b'c', 0x03, b'd', b'n', b's', 0xc0, 0x38, // noc.dns.icann.org.
+            0x78, 0xa2, 0xc2, 0xf5, 0x00, 0x00, 0x1c, 0x20, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x12,
+            0x75, 0x00, 0x00, 0x00, 0x0e, 0x10, // serial, refresh, retry, expire, minimum
+        ],
+    ),
+    (
+        "MX answer pointing into RDATA",
+        &[
+            0x0d, 0xe3, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, // header
+            0x05, b'g', b'm', b'a', b'i', b'l', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x0f, 0x00,
+            0x01, // gmail.com. IN MX
+            0xc0, 0x0c, 0x00, 0x0f, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x1b, 0x00, 0x05,
+            0x0d, b'g', b'm', b'a', b'i', b'l', b'-', b's', b'm', b't', b'p', b'-', b'i', b'n',
+            0x01, b'l', 0x06, b'g', b'o', b'o', b'g', b'l', b'e', 0xc0,
+            0x12, // gmail.com. 3600 IN MX 5 gmail-smtp-in.l.google.com.
+            0xc0, 0x0c, 0x00, 0x0f, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x09, 0x00, 0x0a,
+            0x04, b'a', b'l', b't', b'1', 0xc0,
+            0x29, // gmail.com. 3600 IN MX 10 alt1.gmail-smtp-in.l.google.com.
+        ],
+    ),
+    (
+        "validated AAAA answer to DNSSEC client",
+        &[
+            0xb1, 0x6c, 0x81, 0xa0, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, // header
+            0x0a, b'c', b'l', b'o', b'u', b'd', b'f', b'l', b'a', b'r', b'e', 0x03, b'c', b'o',
+            b'm', 0x00, 0x00, 0x1c, 0x00, 0x01, // cloudflare.com. IN AAAA
+            0xc0, 0x0c, 0x00, 0x1c, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x10, 0x26, 0x06,
+            0x47, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x68, 0x10, 0x84,
+            0xe5, // cloudflare.com. 300 IN AAAA 2606:4700::6810:84e5
+            0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, // OPT, DO
+        ],
+    ),
+];
+
+/// Asserts that `packet` encoded and parsed (strictly) again is the same packet
+///
+/// Panics with both packets and the hexdump of the wire format otherwise.
+pub fn assert_round_trip(packet: &DnsPacket) {
+    let bytes = BytesPacket::from(packet.clone()).buf;
+    match DnsPacket::parse_strict(&bytes) {
+        Ok(parsed) => assert_eq!(
+            &parsed,
+            packet,
+            "packet changed on round trip, wire format:\n{}",
+            hexdump(&bytes)
+        ),
+        Err(e) => panic!(
+            "encoded packet doesn't parse: {}\n{}\n{}",
+            e,
+            packet,
+            hexdump(&bytes)
+        ),
+    }
+}
+
+/// Asserts that wire format `bytes` parses (strictly) and the packet round-trips, returns it
+///
+/// The bytes themselves may change, e.g. names may be compressed differently.
+pub fn assert_wire_round_trip(bytes: &[u8]) -> DnsPacket {
+    let packet = DnsPacket::parse_strict(bytes)
+        .unwrap_or_else(|e| panic!("message doesn't parse: {}\n{}", e, hexdump(bytes)));
+    assert_round_trip(&packet);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_packets_round_trip() {
+        let packets: Vec<DnsPacket> = PacketGenerator::seeded(1729).take(500).collect();
+        packets.iter().for_each(assert_round_trip);
+
+        // reproducible, yet varied
+        assert_eq!(PacketGenerator::seeded(1729).packet(), packets[0]);
+        assert!(packets.iter().any(|packet| packet.opt.is_some()));
+        assert!(packets.iter().any(|packet| packet.answers.len() > 2));
+    }
+
+    #[test]
+    fn test_corpus_round_trips() {
+        for (description, bytes) in CORPUS {
+            let packet = assert_wire_round_trip(bytes);
+            assert!(!packet.questions.is_empty(), "{}", description);
+        }
+
+        let (_, mx) = CORPUS[4];
+        let packet = assert_wire_round_trip(mx);
+        assert_eq!(
+            packet.answers[1].data.to_string(),
+            "10 alt1.gmail-smtp-in.l.google.com."
+        );
+    }
+}
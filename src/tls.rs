@@ -0,0 +1,803 @@
+//! Encrypted upstreams: DNS over TLS (RFC 7858) and DNS over HTTPS (RFC 8484)
+//!
+//! Server certificates are verified against the system root certificates (`SSL_CERT_FILE`
+//! and `SSL_CERT_DIR` override them) and the host name of the server. Servers given by
+//! DNS stamps may in addition require one of the certificates of the chain to have a known
+//! hash ([`TlsServer::cert_hashes`]). Public keys may be pinned too, in addition to the usual
+//! verification or instead of it, see [`TlsOptions::spki_pins`].
+//!
+//! DoT queries are pipelined over a single connection. DoH connections carry one exchange
+//! at a time and concurrent queries open more of them; they are kept open for later queries
+//! and a query whose reused connection turns out to be closed by the server is sent once
+//! more over a new one. Reconnecting resumes the previous TLS session (session tickets or
+//! TLS 1.2 session IDs), which saves a full handshake.
+//!
+//! Servers known only by host name are looked up through bootstrap resolvers (plain DNS,
+//! [`TlsServer::bootstrap`]), since resolving them through themselves can't work. The name
+//! is resolved at startup and again whenever connecting to the resolved address fails.
+//!
+//! In [`Privacy::Opportunistic`] mode, queries which fail over TLS (e.g. the server doesn't
+//! support it or its certificate doesn't validate) are sent in plain text to port 53 of the
+//! same server instead.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::{Resumption, WebPkiServerVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, ClientConnection, ConnectionCommon, DigitallySignedStruct, RootCertStore,
+    SignatureScheme, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+
+use crate::packet::{BytesPacket, DnsPacket};
+use crate::record::RecordType;
+use crate::resolver::Resolver;
+use crate::stamp::TlsServer;
+use crate::trace;
+use crate::upstream::{
+    is_response_to, wait_for_response, PendingQueries, Privacy, TlsOptions, Upstream,
+};
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Idle DoH connections kept open per upstream
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// TLS sessions remembered for resumption, per upstream
+const MAX_RESUMABLE_SESSIONS: usize = 16;
+
+/// Longest HTTP response head accepted from DoH servers
+const MAX_HTTP_HEAD: usize = 16 * 1024;
+
+/// DNS over TLS (RFC 7858), messages are length-prefixed like over TCP
+///
+/// Like [`crate::upstream::TcpUpstream`], one connection is shared by all queries, which are
+/// pipelined on it and matched to the responses by (rewritten) message ID. A query whose
+/// connection was lost before its response arrived is sent once more over a new connection.
+pub struct TlsUpstream {
+    connector: Connector,
+    connection: Mutex<Option<Arc<DotConnection>>>,
+}
+
+/// Open DoT connection with queries waiting for their responses
+struct DotConnection {
+    socket: TcpStream,
+    /// Locked briefly for encrypting a query or decrypting received data, never while
+    /// waiting for the socket to become readable
+    tls: Mutex<ClientConnection>,
+    pending: PendingQueries,
+    /// Set once the connection must not be used for new queries
+    closed: AtomicBool,
+}
+
+impl DotConnection {
+    fn send(&self, query: &DnsPacket) -> std::io::Result<()> {
+        let bytes_packet = BytesPacket::from(query);
+        let mut message = Vec::with_capacity(2 + bytes_packet.buf.len());
+        message.extend_from_slice(&(bytes_packet.buf.len() as u16).to_be_bytes());
+        message.extend_from_slice(&bytes_packet.buf);
+
+        let mut tls = self.tls.lock().expect("upstream lock poisoned");
+        tls.writer().write_all(&message)?;
+        while tls.wants_write() {
+            tls.write_tls(&mut &self.socket)?;
+        }
+        Ok(())
+    }
+
+    /// Stops using the connection, its reader thread wakes up and disconnects waiting queries
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+impl TlsUpstream {
+    /// Upstream at `server`, its host name is looked up if the address is not known
+    pub fn new(server: &TlsServer, options: &TlsOptions, timeout: Duration) -> Result<Self> {
+        Ok(Self {
+            connector: Connector::new(server, options, b"dot", timeout)?,
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// Open connection, a new one is made (and its reader started) if there is none usable
+    fn connection(&self) -> Result<Arc<DotConnection>> {
+        let mut connection = self.connection.lock().expect("upstream lock poisoned");
+        if let Some(connection) = connection.as_ref().filter(|c| !c.is_closed()) {
+            return Ok(connection.clone());
+        }
+
+        let (tls, socket) = self.connector.connect()?.into_parts();
+        // reader waits for responses as long as the connection is open
+        socket.set_read_timeout(None)?;
+        let reader_socket = socket.try_clone()?;
+        let new_connection = Arc::new(DotConnection {
+            socket,
+            tls: Mutex::new(tls),
+            pending: PendingQueries::default(),
+            closed: AtomicBool::new(false),
+        });
+        let reader_connection = new_connection.clone();
+        thread::spawn(move || read_dot_responses(reader_socket, &reader_connection));
+
+        *connection = Some(new_connection.clone());
+        Ok(new_connection)
+    }
+
+    /// Exchange over the current connection, `None` if the connection was lost in the meantime
+    fn try_exchange(&self, packet: &DnsPacket) -> Result<Option<DnsPacket>> {
+        let connection = self.connection()?;
+        let (query, receiver) = connection
+            .pending
+            .register(packet, &self.connector.hostname)?;
+
+        // reader disconnects waiting queries only after marking the connection as closed
+        if connection.is_closed() || connection.send(&query).is_err() {
+            connection.pending.remove(query.header.id);
+            connection.close();
+            return Ok(None);
+        }
+
+        let received = wait_for_response(&receiver, Some(self.connector.timeout));
+        connection.pending.remove(query.header.id);
+
+        match received {
+            Ok(mut response) => {
+                response.header.id = packet.header.id;
+                Ok(Some(response))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Ok(None),
+            Err(e @ mpsc::RecvTimeoutError::Timeout) => {
+                connection.close();
+                Err(e).with_context(|| format!("no response from {}", self.connector.hostname))
+            }
+        }
+    }
+}
+
+impl Upstream for TlsUpstream {
+    fn exchange(&self, packet: &DnsPacket) -> Result<DnsPacket> {
+        let hostname = &self.connector.hostname;
+        let attempt = || trace::connection_attempt("tls", hostname, || self.try_exchange(packet));
+        let response = attempt().and_then(|response| match response {
+            Some(response) => Ok(response),
+            None => attempt()?
+                .with_context(|| format!("TLS connection to {} lost", self.connector.hostname)),
+        });
+        self.connector.or_plaintext(response, packet)
+    }
+}
+
+impl Drop for TlsUpstream {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.get_mut().ok().and_then(|c| c.take()) {
+            connection.close();
+        }
+    }
+}
+
+/// Decrypts data from the connection and delivers responses until it is closed or broken
+fn read_dot_responses(mut socket: TcpStream, connection: &DotConnection) {
+    let mut received = vec![0; 16 * 1024];
+    let mut plaintext = Vec::new();
+    loop {
+        let len = match socket.read(&mut received) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => len,
+        };
+        let decrypted = {
+            let mut tls = connection.tls.lock().expect("upstream lock poisoned");
+            decrypt(
+                &mut tls,
+                &connection.socket,
+                &received[..len],
+                &mut plaintext,
+            )
+        };
+        if decrypted.is_err() {
+            break;
+        }
+
+        while let [high, low, rest @ ..] = plaintext.as_slice() {
+            let len = u16::from_be_bytes([*high, *low]) as usize;
+            if rest.len() < len {
+                break;
+            }
+            // framing is intact even if the message itself is garbage
+            if let Ok(response) = DnsPacket::parse(&rest[..len]) {
+                connection.pending.deliver(response);
+            }
+            plaintext.drain(..2 + len);
+        }
+    }
+    connection.close();
+    connection.pending.clear();
+}
+
+/// Feeds received TLS records to the connection, appends the decrypted data to `plaintext`
+///
+/// Whatever the connection has to send in reply (e.g. alerts or key updates) goes to `socket`.
+pub(crate) fn decrypt<Side>(
+    tls: &mut ConnectionCommon<Side>,
+    socket: &TcpStream,
+    mut received: &[u8],
+    plaintext: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    use std::io::{Error, ErrorKind};
+
+    while !received.is_empty() {
+        tls.read_tls(&mut received)?;
+        let state = tls
+            .process_new_packets()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if state.peer_has_closed() && state.plaintext_bytes_to_read() == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut chunk = [0; 4096];
+        loop {
+            match tls.reader().read(&mut chunk) {
+                Ok(0) => break,
+                Ok(len) => plaintext.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    while tls.wants_write() {
+        tls.write_tls(&mut &*socket)?;
+    }
+    Ok(())
+}
+
+/// DNS over HTTPS (RFC 8484), queries are POSTed as `application/dns-message` over HTTP/1.1
+pub struct HttpsUpstream {
+    connector: Connector,
+    /// Host header, with the port unless it is the default one
+    host: String,
+    path: String,
+}
+
+impl HttpsUpstream {
+    /// Upstream at `server` (with path of `/dns-query` if none is given)
+    pub fn new(server: &TlsServer, options: &TlsOptions, timeout: Duration) -> Result<Self> {
+        let host = match server.port {
+            443 => server.hostname.clone(),
+            port => format!("{}:{}", server.hostname, port),
+        };
+        Ok(Self {
+            connector: Connector::new(server, options, b"http/1.1", timeout)?,
+            host,
+            path: server.path.clone().unwrap_or("/dns-query".into()),
+        })
+    }
+}
+
+impl Upstream for HttpsUpstream {
+    fn exchange(&self, packet: &DnsPacket) -> Result<DnsPacket> {
+        // ID 0 makes the response cacheable by HTTP caches (RFC 8484 section 4.1)
+        let mut query = packet.clone();
+        query.header.id = 0;
+        let body = BytesPacket::from(&query).buf;
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/dns-message\r\nAccept: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+            self.path,
+            self.host,
+            body.len()
+        );
+
+        let mut span = trace::upstream_span("https", &self.host);
+        let response = self.connector.with_connection(|stream| {
+            stream.write_all(head.as_bytes())?;
+            stream.write_all(&body)?;
+            stream.flush()?;
+
+            let response = read_http_response(stream)?;
+            if response.status != 200 {
+                anyhow::bail!("HTTP status {}", response.status);
+            }
+            let content_type = response.header("content-type").unwrap_or_default();
+            if !content_type.starts_with("application/dns-message") {
+                anyhow::bail!("unexpected content type {:?}", content_type);
+            }
+            let packet = DnsPacket::parse(&response.body).context("malformed response")?;
+            if !is_response_to(&packet, &query) {
+                anyhow::bail!("response does not match the query");
+            }
+            Ok((packet, response.keep_alive))
+        });
+        let response = span.record(response);
+        drop(span);
+
+        let response = response.map(|mut response| {
+            response.header.id = packet.header.id;
+            response
+        });
+        self.connector.or_plaintext(response, packet)
+    }
+}
+
+/// Opens TLS connections to one server and keeps idle ones for later
+struct Connector {
+    /// Known address of the server, `None` until its host name is resolved
+    address: Mutex<Option<SocketAddr>>,
+    /// Address may change, the host name is resolved again when connecting fails
+    resolvable: bool,
+    hostname: String,
+    port: u16,
+    /// Plain DNS resolvers for the host name, the system resolver if empty
+    bootstrap: Vec<Resolver>,
+    server_name: ServerName<'static>,
+    config: Arc<ClientConfig>,
+    timeout: Duration,
+    idle: Mutex<Vec<TlsStream>>,
+    privacy: Privacy,
+    /// Plain DNS to the server's address, used in opportunistic mode
+    plaintext: Mutex<Option<(IpAddr, Arc<Resolver>)>>,
+}
+
+impl Connector {
+    fn new(
+        server: &TlsServer,
+        options: &TlsOptions,
+        alpn: &[u8],
+        timeout: Duration,
+    ) -> Result<Self> {
+        let server_name = ServerName::try_from(server.hostname.clone())
+            .with_context(|| format!("invalid server name {}", server.hostname))?;
+        let bootstrap = server
+            .bootstrap
+            .iter()
+            .map(|address| {
+                Resolver::new(SocketAddr::new(*address, 53).to_string()).with_timeout(timeout)
+            })
+            .collect();
+
+        let connector = Self {
+            address: Mutex::new(server.address.map(|ip| SocketAddr::new(ip, server.port))),
+            resolvable: server.address.is_none(),
+            hostname: server.hostname.clone(),
+            port: server.port,
+            bootstrap,
+            server_name,
+            config: client_config(alpn, server, options)?,
+            timeout,
+            idle: Mutex::new(Vec::new()),
+            privacy: options.privacy,
+            plaintext: Mutex::new(None),
+        };
+        if connector.resolvable {
+            // not fatal, the first query tries again
+            match connector.resolve() {
+                Ok(address) => println!("Resolved {} to {}", connector.hostname, address),
+                Err(e) => eprintln!("Failed to resolve {}: {:#}", connector.hostname, e),
+            }
+        }
+        Ok(connector)
+    }
+
+    /// Looks the host name up via the bootstrap resolvers (or the system) and remembers the address
+    fn resolve(&self) -> Result<SocketAddr> {
+        let ip = if self.bootstrap.is_empty() {
+            (self.hostname.as_str(), self.port)
+                .to_socket_addrs()?
+                .next()
+                .map(|address| address.ip())
+        } else {
+            self.bootstrap_lookup()?
+        };
+        let address = SocketAddr::new(
+            ip.with_context(|| format!("{} has no address", self.hostname))?,
+            self.port,
+        );
+
+        *self.address.lock().expect("upstream lock poisoned") = Some(address);
+        Ok(address)
+    }
+
+    /// First address from the first bootstrap resolver that responds
+    fn bootstrap_lookup(&self) -> Result<Option<IpAddr>> {
+        let mut last_error = None;
+        for resolver in self.bootstrap.iter() {
+            for record_type in [RecordType::A, RecordType::AAAA] {
+                match resolver.lookup(self.hostname.as_str(), record_type) {
+                    Ok(records) => {
+                        if let Some(ip) = records.iter().find_map(|record| record.data.address()) {
+                            return Ok(Some(ip));
+                        }
+                    }
+                    Err(e) => last_error = Some(e),
+                }
+            }
+        }
+        match last_error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    /// Connects to the known address, or the one the host name resolves to now if that fails
+    fn connect(&self) -> Result<TlsStream> {
+        let known = *self.address.lock().expect("upstream lock poisoned");
+        let address = match known {
+            Some(address) => address,
+            None => self.resolve()?,
+        };
+        match self.connect_to(address) {
+            Err(e) if self.resolvable && known.is_some() => {
+                // server may have moved
+                match self.resolve() {
+                    Ok(fresh) if fresh != address => self.connect_to(fresh),
+                    _ => Err(e),
+                }
+            }
+            result => result,
+        }
+    }
+
+    fn connect_to(&self, address: SocketAddr) -> Result<TlsStream> {
+        let stream = TcpStream::connect_timeout(&address, self.timeout)
+            .with_context(|| format!("Failed to connect to {}", address))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_nodelay(true)?;
+
+        let connection = ClientConnection::new(self.config.clone(), self.server_name.clone())?;
+        let mut stream = StreamOwned::new(connection, stream);
+        // handshake right away, so certificate problems are reported as such
+        while stream.conn.is_handshaking() {
+            stream
+                .conn
+                .complete_io(&mut stream.sock)
+                .with_context(|| format!("TLS handshake with {} failed", address))?;
+        }
+        Ok(stream)
+    }
+
+    /// Runs `exchange` over an idle connection or a new one, the connection is kept if it says so
+    fn with_connection<T>(
+        &self,
+        exchange: impl Fn(&mut TlsStream) -> Result<(T, bool)>,
+    ) -> Result<T> {
+        let idle = self.idle.lock().expect("upstream lock poisoned").pop();
+        if let Some(mut stream) = idle {
+            // server may have closed the idle connection in the meantime
+            if let Ok((result, keep)) = exchange(&mut stream) {
+                if keep {
+                    self.release(stream);
+                }
+                return Ok(result);
+            }
+        }
+
+        let mut stream = self.connect()?;
+        let (result, keep) =
+            exchange(&mut stream).with_context(|| format!("exchange with {}", self.hostname))?;
+        if keep {
+            self.release(stream);
+        }
+        Ok(result)
+    }
+
+    /// Result of the encrypted exchange, or the plain DNS one if it failed and privacy allows it
+    fn or_plaintext(&self, result: Result<DnsPacket>, packet: &DnsPacket) -> Result<DnsPacket> {
+        let e = match (result, self.privacy) {
+            (Err(e), Privacy::Opportunistic) => e,
+            (result, _) => return result,
+        };
+        let Some(address) = *self.address.lock().expect("upstream lock poisoned") else {
+            return Err(e);
+        };
+        eprintln!(
+            "TLS with {} failed, falling back to plain DNS: {:#}",
+            self.hostname, e
+        );
+
+        let resolver = {
+            let mut plaintext = self.plaintext.lock().expect("upstream lock poisoned");
+            match plaintext.as_ref() {
+                Some((ip, resolver)) if *ip == address.ip() => resolver.clone(),
+                _ => {
+                    let resolver = Resolver::new(SocketAddr::new(address.ip(), 53).to_string())
+                        .with_timeout(self.timeout);
+                    let resolver = Arc::new(resolver);
+                    *plaintext = Some((address.ip(), resolver.clone()));
+                    resolver
+                }
+            }
+        };
+        resolver.exchange(packet)
+    }
+
+    fn release(&self, stream: TlsStream) {
+        let mut idle = self.idle.lock().expect("upstream lock poisoned");
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(stream);
+        }
+    }
+}
+
+/// System root certificates, `SSL_CERT_FILE` and `SSL_CERT_DIR` override them
+pub(crate) fn system_roots() -> Result<RootCertStore> {
+    let native = rustls_native_certs::load_native_certs();
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(native.certs);
+    if roots.is_empty() {
+        anyhow::bail!("no root certificates found: {:?}", native.errors);
+    }
+    Ok(roots)
+}
+
+/// Client configuration trusting the system root certificates (unless only pins are trusted)
+fn client_config(
+    alpn: &[u8],
+    server: &TlsServer,
+    options: &TlsOptions,
+) -> Result<Arc<ClientConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let webpki = if options.pins_only {
+        None
+    } else {
+        let roots = system_roots()?;
+        let webpki = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+            .build()
+            .context("invalid root certificates")?;
+        Some(webpki)
+    };
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("no usable TLS version")?;
+
+    let mut config = match webpki {
+        Some(webpki) if server.cert_hashes.is_empty() && options.spki_pins.is_empty() => {
+            builder.with_webpki_verifier(webpki)
+        }
+        webpki => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinningVerifier {
+                webpki,
+                algorithms: provider.signature_verification_algorithms,
+                cert_hashes: server.cert_hashes.clone(),
+                spki_pins: options.spki_pins.clone(),
+            })),
+    }
+    .with_no_client_auth();
+    config.alpn_protocols = vec![alpn.to_vec()];
+    config.resumption = Resumption::in_memory_sessions(MAX_RESUMABLE_SESSIONS);
+
+    Ok(Arc::new(config))
+}
+
+/// Usual verification (if any), plus the chain must contain one of the hashes and pins
+#[derive(Debug)]
+struct PinningVerifier {
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    algorithms: WebPkiSupportedAlgorithms,
+    /// SHA256 digests of TBS certificates
+    cert_hashes: Vec<Vec<u8>>,
+    /// SHA256 digests of SubjectPublicKeyInfo
+    spki_pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+
+        let chain = || std::iter::once(end_entity).chain(intermediates);
+        let matches = |hashes: &[Vec<u8>], part: fn(&[u8]) -> Option<&[u8]>| {
+            chain()
+                .filter_map(|certificate| part(certificate))
+                .any(|part| {
+                    let hash = Sha256::digest(part);
+                    hashes.iter().any(|pinned| pinned[..] == hash[..])
+                })
+        };
+        if !self.cert_hashes.is_empty() && !matches(&self.cert_hashes, tbs_certificate) {
+            return Err(rustls::Error::General(
+                "no certificate in the chain matches the hashes of the stamp".into(),
+            ));
+        }
+        if !self.spki_pins.is_empty() && !matches(&self.spki_pins, subject_public_key_info) {
+            return Err(rustls::Error::General(
+                "no public key in the chain matches the pins".into(),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, certificate, signature, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, certificate, signature, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// DER of the `tbsCertificate`, the first element of the certificate SEQUENCE (RFC 5280)
+pub(crate) fn tbs_certificate(certificate: &[u8]) -> Option<&[u8]> {
+    let (_, content) = der_element(certificate)?;
+    let (length, _) = der_element(content)?;
+    content.get(..length)
+}
+
+/// DER of the `subjectPublicKeyInfo`, the 7th field of `tbsCertificate` (RFC 5280)
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    let (_, mut fields) = der_element(tbs_certificate(certificate)?)?;
+    // version field is optional, explicitly tagged [0]
+    let skip = if fields.first() == Some(&0xa0) { 6 } else { 5 };
+    for _ in 0..skip {
+        let (length, _) = der_element(fields)?;
+        fields = fields.get(length..)?;
+    }
+    let (length, _) = der_element(fields)?;
+    fields.get(..length)
+}
+
+/// Length of the whole DER element (header included) at the start of `der` and its content
+pub(crate) fn der_element(der: &[u8]) -> Option<(usize, &[u8])> {
+    let first = *der.get(1)?;
+    let (header, length) = match first {
+        0..=0x7f => (2, first as usize),
+        0x81..=0x84 => {
+            let count = (first & 0x7f) as usize;
+            let bytes = der.get(2..2 + count)?;
+            let length = bytes.iter().fold(0, |length, b| length << 8 | *b as usize);
+            (2 + count, length)
+        }
+        _ => return None,
+    };
+    Some((header + length, der.get(header..header + length)?))
+}
+
+/// Status, headers and body of HTTP/1.1 response
+pub(crate) struct HttpResponse {
+    pub(crate) status: u16,
+    /// Names in lowercase
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+    /// Server allows sending another request over the connection
+    pub(crate) keep_alive: bool,
+}
+
+impl HttpResponse {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub(crate) fn read_http_response(stream: &mut impl Read) -> Result<HttpResponse> {
+    read_http_response_limited(stream, u16::MAX as usize)
+}
+
+/// Response with body of at most `max_body` bytes
+pub(crate) fn read_http_response_limited(
+    stream: &mut impl Read,
+    max_body: usize,
+) -> Result<HttpResponse> {
+    let head = read_until(stream, b"\r\n\r\n", MAX_HTTP_HEAD)?;
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .context("invalid HTTP status line")?;
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    let (mut content_length, mut chunked, mut keep_alive) = (None, false, true);
+    for (name, value) in headers.iter() {
+        match name.as_str() {
+            "content-length" => content_length = Some(value.parse().context("invalid length")?),
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+            _ => {}
+        }
+    }
+
+    let body = match (chunked, content_length) {
+        (true, _) => {
+            let mut body = Vec::new();
+            loop {
+                let size = read_until(stream, b"\r\n", 64)?;
+                let size = String::from_utf8_lossy(&size);
+                let size = size.trim().split(';').next().unwrap_or_default();
+                let size = usize::from_str_radix(size, 16).context("invalid chunk size")?;
+                if size == 0 {
+                    read_until(stream, b"\r\n", MAX_HTTP_HEAD)?; // no trailers expected
+                    break body;
+                }
+                let start = body.len();
+                body.resize(start + size + 2, 0);
+                stream.read_exact(&mut body[start..])?;
+                body.truncate(start + size);
+                if body.len() > max_body {
+                    anyhow::bail!("response too long");
+                }
+            }
+        }
+        (false, Some(length)) if length <= max_body => {
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body)?;
+            body
+        }
+        (false, Some(length)) => anyhow::bail!("response too long ({} bytes)", length),
+        (false, None) => {
+            keep_alive = false;
+            let mut body = Vec::new();
+            stream.take(max_body as u64 + 1).read_to_end(&mut body)?;
+            body
+        }
+    };
+
+    Ok(HttpResponse {
+        status,
+        headers,
+        body,
+        keep_alive,
+    })
+}
+
+/// Reads byte by byte up to and including `delimiter` (which is not returned)
+fn read_until(stream: &mut impl Read, delimiter: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let mut byte = [0];
+    while !buf.ends_with(delimiter) {
+        if buf.len() >= limit {
+            anyhow::bail!("HTTP response head too long");
+        }
+        stream.read_exact(&mut byte)?;
+        buf.push(byte[0]);
+    }
+    buf.truncate(buf.len() - delimiter.len());
+    Ok(buf)
+}
@@ -0,0 +1,304 @@
+//! Query tracing with OpenTelemetry-style spans
+//!
+//! Every query gets a span covering its whole processing, with child spans for the steps
+//! worth timing: cache lookup, each attempt at an upstream and serialization of the response.
+//! A new span is the child of the span current in its thread and becomes the current one
+//! until it ends. Work handed over to other threads takes the current span along, see
+//! [`current`] and [`set_current`].
+//!
+//! Nothing is recorded until an exporter is installed ([`install`]), so tracing costs next
+//! to nothing when it's off.
+
+use std::cell::Cell;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+use crate::packet::DnsPacket;
+
+/// Trace and span IDs identifying a span
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+}
+
+/// Role of the span, numbered as in OTLP
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpanKind {
+    Internal = 1,
+    /// Handling of a request from a client
+    Server = 2,
+    /// Request to another server
+    Client = 3,
+}
+
+/// Attribute value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Self {
+        Self::Int(value as i64)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+/// Finished span, as handed over to the exporter
+#[derive(Debug, Clone)]
+pub struct SpanData {
+    pub context: SpanContext,
+    pub parent_span_id: Option<[u8; 8]>,
+    pub name: &'static str,
+    pub kind: SpanKind,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, Value)>,
+    /// Error message, if the traced operation failed
+    pub error: Option<String>,
+}
+
+/// Destination of finished spans, must not block
+pub trait SpanExporter: Send + Sync {
+    fn export(&self, span: SpanData);
+}
+
+static EXPORTER: OnceLock<Box<dyn SpanExporter>> = OnceLock::new();
+
+thread_local! {
+    static CURRENT: Cell<Option<SpanContext>> = const { Cell::new(None) };
+}
+
+/// Starts recording spans, they are handed to `exporter` as they end
+pub fn install(exporter: impl SpanExporter + 'static) -> Result<()> {
+    EXPORTER
+        .set(Box::new(exporter))
+        .map_err(|_| anyhow::anyhow!("span exporter already installed"))
+}
+
+/// Span current in this thread
+pub fn current() -> Option<SpanContext> {
+    CURRENT.with(Cell::get)
+}
+
+/// Makes `context` (e.g. taken by [`current`] in another thread) the parent of new spans
+pub fn set_current(context: Option<SpanContext>) {
+    CURRENT.with(|current| current.set(context));
+}
+
+/// Span in progress, it ends when dropped
+#[must_use = "the span ends when dropped"]
+pub struct Span {
+    /// `None` when spans aren't recorded
+    data: Option<SpanData>,
+    /// Span which was current before this one started
+    previous: Option<SpanContext>,
+}
+
+/// Starts a span called `name`
+pub fn span(name: &'static str) -> Span {
+    if EXPORTER.get().is_none() {
+        return Span {
+            data: None,
+            previous: None,
+        };
+    }
+
+    let previous = current();
+    let context = SpanContext {
+        trace_id: previous.map_or_else(rand::random, |parent| parent.trace_id),
+        span_id: rand::random(),
+    };
+    set_current(Some(context));
+
+    let start = SystemTime::now();
+    Span {
+        data: Some(SpanData {
+            context,
+            parent_span_id: previous.map(|parent| parent.span_id),
+            name,
+            kind: SpanKind::Internal,
+            start,
+            end: start,
+            attributes: Vec::new(),
+            error: None,
+        }),
+        previous,
+    }
+}
+
+/// Span of a query received over `transport` (`udp`, `tcp` or `unix`), until its response
+/// is sent
+pub fn query_span(transport: &'static str, query: &DnsPacket, client: Option<SocketAddr>) -> Span {
+    let mut span = span("dns.query").kind(SpanKind::Server);
+    if span.is_recording() {
+        span.attribute("network.transport", transport);
+        if let Some(client) = client {
+            span.attribute("client.address", client.ip().to_string());
+        }
+        if let Some(question) = query.questions.first() {
+            span.attribute("dns.question.name", question.domain_name.to_string());
+            span.attribute("dns.question.type", question.query_type.to_string());
+        }
+    }
+    span
+}
+
+/// Span of an attempt at exchanging a query with the upstream `server` over `protocol`
+/// (`udp`, `tcp`, `tls` or `https`)
+pub fn upstream_span(protocol: &'static str, server: &impl Display) -> Span {
+    let mut span = span("upstream.exchange").kind(SpanKind::Client);
+    if span.is_recording() {
+        span.attribute("dns.upstream.protocol", protocol);
+        span.attribute("server.address", server.to_string());
+    }
+    span
+}
+
+/// Traces an attempt at exchanging a query over a connection to the upstream `server`;
+/// `exchange` gives `None` when the connection was lost before the response came
+pub fn connection_attempt<T>(
+    protocol: &'static str,
+    server: &impl Display,
+    exchange: impl FnOnce() -> Result<Option<T>>,
+) -> Result<Option<T>> {
+    let mut span = upstream_span(protocol, server);
+    let response = span.record(exchange());
+    if let Ok(None) = response {
+        span.error(&"connection lost");
+    }
+    response
+}
+
+impl Span {
+    pub fn kind(mut self, kind: SpanKind) -> Self {
+        if let Some(data) = self.data.as_mut() {
+            data.kind = kind;
+        }
+        self
+    }
+
+    /// Whether the span is recorded, attributes needn't be computed otherwise
+    pub fn is_recording(&self) -> bool {
+        self.data.is_some()
+    }
+
+    pub fn attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        if let Some(data) = self.data.as_mut() {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    /// Marks the operation as failed
+    pub fn error(&mut self, error: &impl Display) {
+        if let Some(data) = self.data.as_mut() {
+            data.error = Some(format!("{:#}", error));
+        }
+    }
+
+    /// Marks the operation as failed if `result` is an error, passes the result on
+    pub fn record<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(e) = &result {
+            self.error(e);
+        }
+        result
+    }
+
+    /// Records the response code and the number of answers of the response to the query
+    pub fn response(&mut self, response: &DnsPacket) {
+        if self.is_recording() {
+            self.attribute(
+                "dns.response.code",
+                format!("{:?}", response.header.rescode),
+            );
+            self.attribute("dns.response.answers", response.answers.len());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(mut data) = self.data.take() {
+            set_current(self.previous);
+            data.end = SystemTime::now();
+            if let Some(exporter) = EXPORTER.get() {
+                exporter.export(data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    static EXPORTED: Mutex<Vec<SpanData>> = Mutex::new(Vec::new());
+
+    struct Collector;
+
+    impl SpanExporter for Collector {
+        fn export(&self, span: SpanData) {
+            EXPORTED.lock().unwrap().push(span);
+        }
+    }
+
+    #[test]
+    fn test_spans_nest_within_and_across_threads() {
+        install(Collector).unwrap();
+
+        let root = span("root").kind(SpanKind::Server);
+        let context = current();
+        {
+            let mut child = span("child");
+            child.error(&"failed");
+        }
+        std::thread::spawn(move || {
+            set_current(context);
+            let _remote = span("remote");
+        })
+        .join()
+        .unwrap();
+        drop(root);
+        assert_eq!(current(), None);
+
+        let exported = EXPORTED.lock().unwrap();
+        let [child, remote, root] = &exported[..] else {
+            panic!("unexpected spans {:?}", exported);
+        };
+        assert_eq!(
+            (root.name, root.kind, root.parent_span_id),
+            ("root", SpanKind::Server, None)
+        );
+        for span in [child, remote] {
+            assert_eq!(span.context.trace_id, root.context.trace_id);
+            assert_eq!(span.parent_span_id, Some(root.context.span_id));
+        }
+        assert_eq!(child.error.as_deref(), Some("failed"));
+        assert!(root.end >= child.end);
+    }
+}
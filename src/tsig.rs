@@ -0,0 +1,474 @@
+//! Transaction signatures (TSIG, RFC 8945)
+//!
+//! A query signed with a shared secret key proves who sent it, e.g. a secondary server
+//! allowed to transfer a zone. Responses to signed queries are signed with the same key,
+//! every message of a multi-message response (zone transfer) on its own, chained to
+//! the previous one. Only HMAC-SHA256 is supported and it needs the `tsig` feature.
+//!
+//! Keys are given like to `dig -y`: `[hmac-sha256:]<name>:<base64 secret>`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Context;
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::base64;
+use crate::domain_name::{DomainName, LookupTable};
+use crate::header::DnsHeader;
+use crate::leases::unix_time;
+use crate::question::DnsQuestion;
+use crate::record::{DnsRecord, RecordClass, RecordData, RecordType};
+use crate::register::constant_time_eq;
+
+/// TYPE of TSIG pseudo-record, the last one of the additional section
+pub const TSIG_RECORD_TYPE: u16 = 250;
+
+/// CLASS of TSIG pseudo-record (ANY)
+const TSIG_CLASS: u16 = 255;
+
+const ALGORITHM: &str = "hmac-sha256";
+
+/// Allowed difference between the time signed and our clock, in seconds
+const FUDGE: u16 = 300;
+
+/// MAC does not match the message (RFC 8945 section 3)
+pub const BADSIG: u16 = 16;
+/// Key or algorithm is unknown
+pub const BADKEY: u16 = 17;
+/// Message was signed too long ago, or our clock is off
+pub const BADTIME: u16 = 18;
+
+/// Shared secret of a HMAC-SHA256 key
+#[derive(Clone)]
+pub struct TsigKey {
+    name: DomainName,
+    #[cfg_attr(not(feature = "tsig"), allow(dead_code))]
+    secret: Vec<u8>,
+}
+
+impl TsigKey {
+    pub fn name(&self) -> &DomainName {
+        &self.name
+    }
+
+    #[cfg(feature = "tsig")]
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        use hmac::{Hmac, Mac};
+
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    #[cfg(not(feature = "tsig"))]
+    fn mac(&self, _data: &[u8]) -> Vec<u8> {
+        unreachable!("TSIG keys can't be created without the tsig feature")
+    }
+}
+
+/// Secret is not shown
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TsigKey").field("name", &self.name).finish()
+    }
+}
+
+/// Parses `[hmac-sha256:]<name>:<base64 secret>`
+impl FromStr for TsigKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if cfg!(not(feature = "tsig")) {
+            anyhow::bail!("TSIG keys require the server to be built with the tsig feature");
+        }
+
+        let parts: Vec<&str> = s.split(':').collect();
+        let (name, secret) = match parts[..] {
+            [algorithm, name, secret] if algorithm.eq_ignore_ascii_case(ALGORITHM) => {
+                (name, secret)
+            }
+            [algorithm, _, _] => anyhow::bail!("unsupported TSIG algorithm {}", algorithm),
+            [name, secret] => (name, secret),
+            _ => anyhow::bail!("TSIG key must be [hmac-sha256:]<name>:<base64 secret>"),
+        };
+        let secret = base64::decode(secret)
+            .filter(|secret| !secret.is_empty())
+            .with_context(|| format!("invalid secret of TSIG key {}", name))?;
+
+        Ok(Self {
+            name: DomainName::from(name),
+            secret,
+        })
+    }
+}
+
+/// Verified signature of a query, signs the responses to it
+pub struct Signer {
+    key: TsigKey,
+    /// MAC of the query or of the previous response message
+    mac: Vec<u8>,
+    /// Messages after the first one of a response are chained to their predecessor
+    chained: bool,
+}
+
+impl Signer {
+    /// Key which signed the query
+    pub fn key_name(&self) -> &DomainName {
+        &self.key.name
+    }
+
+    /// Appends TSIG record to the response message in wire format
+    pub fn sign(&mut self, message: &mut BytesMut) {
+        let tsig = TsigData::new(u16::from_be_bytes([message[0], message[1]]));
+
+        let mut data = Vec::with_capacity(2 + self.mac.len() + message.len() + 64);
+        data.put_u16(self.mac.len() as u16);
+        data.put_slice(&self.mac);
+        data.put_slice(message);
+        if self.chained {
+            // subsequent messages cover only the timers (RFC 8945 section 5.3.1)
+            data.put_slice(&tsig.time_signed.to_be_bytes()[2..]);
+            data.put_u16(tsig.fudge);
+        } else {
+            data.extend(tsig.variables(&self.key.name));
+        }
+
+        let tsig = TsigData {
+            mac: self.key.mac(&data),
+            ..tsig
+        };
+        self.mac = tsig.mac.clone();
+        self.chained = true;
+        append(message, tsig.record(&self.key.name));
+    }
+}
+
+/// Signature of a query is not valid
+#[derive(Debug, Clone, PartialEq)]
+pub struct TsigError {
+    key_name: DomainName,
+    /// [`BADSIG`], [`BADKEY`] or [`BADTIME`]
+    pub error: u16,
+}
+
+impl TsigError {
+    /// Unsigned TSIG record telling the client what went wrong, for the NOTAUTH response
+    pub fn record(&self, id: u16) -> DnsRecord {
+        let tsig = TsigData {
+            error: self.error,
+            ..TsigData::new(id)
+        };
+        tsig.record(&self.key_name)
+    }
+}
+
+impl fmt::Display for TsigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.error {
+            BADSIG => "bad signature",
+            BADKEY => "unknown key",
+            BADTIME => "signed at a wrong time",
+            _ => "error",
+        };
+        write!(f, "TSIG key {}: {}", self.key_name, reason)
+    }
+}
+
+/// Checks TSIG record of the query in wire format, `None` if the query is not signed
+pub fn verify(keys: &[TsigKey], message: &[u8]) -> Result<Option<Signer>, TsigError> {
+    let Some((offset, record)) = find_tsig(message) else {
+        return Ok(None);
+    };
+    let error = |error| TsigError {
+        key_name: record.domain_name.clone(),
+        error,
+    };
+
+    let tsig = match &record.data {
+        RecordData::Unknown(rdata) => TsigData::parse(rdata).ok_or_else(|| error(BADSIG))?,
+        _ => return Err(error(BADSIG)),
+    };
+    let key = keys
+        .iter()
+        .find(|key| key.name.eq_ignore_case(&record.domain_name))
+        .filter(|_| tsig.algorithm.eq_ignore_case(&DomainName::from(ALGORITHM)))
+        .ok_or_else(|| error(BADKEY))?;
+
+    // MAC covers the query as it was before signing
+    let mut data = message[..offset].to_vec();
+    let additional_entries = u16::from_be_bytes([data[10], data[11]]) - 1;
+    data[..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+    data[10..12].copy_from_slice(&additional_entries.to_be_bytes());
+    data.extend(tsig.variables(&record.domain_name));
+
+    if !constant_time_eq(&key.mac(&data), &tsig.mac) {
+        return Err(error(BADSIG));
+    }
+    if unix_time().abs_diff(tsig.time_signed) > tsig.fudge as u64 {
+        return Err(error(BADTIME));
+    }
+
+    Ok(Some(Signer {
+        key: key.clone(),
+        mac: tsig.mac,
+        chained: false,
+    }))
+}
+
+/// Signs query in wire format, e.g. for transfers of zones from other servers
+pub fn sign_query(key: &TsigKey, message: &mut BytesMut) {
+    let tsig = TsigData::new(u16::from_be_bytes([message[0], message[1]]));
+    let mut data = message.to_vec();
+    data.extend(tsig.variables(&key.name));
+
+    let tsig = TsigData {
+        mac: key.mac(&data),
+        ..tsig
+    };
+    append(message, tsig.record(&key.name));
+}
+
+/// Offset and the record itself, if the last record of the message is TSIG
+fn find_tsig(message: &[u8]) -> Option<(usize, DnsRecord)> {
+    let mut buf = message;
+    let mut header = DnsHeader::new();
+    header.read_bytes(&mut buf).ok()?;
+    if header.additional_entries == 0 {
+        return None;
+    }
+
+    let mut lookup_table = LookupTable::strict(message);
+    for _ in 0..header.question_entries {
+        DnsQuestion::from_bytes(&mut buf, &mut lookup_table).ok()?;
+    }
+    let records = header.answer_entries as usize
+        + header.authoritative_entries as usize
+        + header.additional_entries as usize;
+
+    let mut last = None;
+    for _ in 0..records {
+        let offset = message.len() - buf.remaining();
+        last = Some((
+            offset,
+            DnsRecord::from_bytes(&mut buf, &mut lookup_table).ok()?,
+        ));
+    }
+    last.filter(|(_, record)| u16::from(record.record_type.clone()) == TSIG_RECORD_TYPE)
+}
+
+/// Appends record to the additional section of the message in wire format
+fn append(message: &mut BytesMut, record: DnsRecord) {
+    let additional_entries = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&additional_entries.to_be_bytes());
+    // TSIG is never compressed, so nothing else of the message is needed
+    record.write_bytes(message, &mut LookupTable::new(0));
+}
+
+/// RDATA of TSIG record (RFC 8945 section 4.2)
+struct TsigData {
+    algorithm: DomainName,
+    /// Seconds since UNIX epoch, 48 bits on wire
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl TsigData {
+    /// Unsigned data for a message with `id` signed now
+    fn new(id: u16) -> Self {
+        Self {
+            algorithm: DomainName::from(ALGORITHM),
+            time_signed: unix_time(),
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: id,
+            error: 0,
+            other: Vec::new(),
+        }
+    }
+
+    fn parse(mut rdata: &[u8]) -> Option<Self> {
+        let buf = &mut rdata;
+        let algorithm = DomainName::from_bytes(buf, &mut LookupTable::new(0)).ok()?;
+        let time_signed = number(buf, 6)?;
+        let fudge = number(buf, 2)? as u16;
+        let mac_size = number(buf, 2)? as usize;
+        let mac = take(buf, mac_size)?.to_vec();
+        let original_id = number(buf, 2)? as u16;
+        let error = number(buf, 2)? as u16;
+        let other_size = number(buf, 2)? as usize;
+        let other = take(buf, other_size)?.to_vec();
+
+        Some(Self {
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+
+    /// TSIG variables covered by the MAC after the message (RFC 8945 section 4.3.3)
+    fn variables(&self, key_name: &DomainName) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        key_name
+            .canonicalize()
+            .write_bytes(&mut buf, &mut LookupTable::new(0));
+        buf.put_u16(TSIG_CLASS);
+        buf.put_u32(0); // TTL
+        self.algorithm
+            .canonicalize()
+            .write_bytes(&mut buf, &mut LookupTable::new(0));
+        buf.put_uint(self.time_signed, 6);
+        buf.put_u16(self.fudge);
+        buf.put_u16(self.error);
+        buf.put_u16(self.other.len() as u16);
+        buf.put_slice(&self.other);
+        buf.to_vec()
+    }
+
+    fn record(&self, key_name: &DomainName) -> DnsRecord {
+        let mut rdata = BytesMut::new();
+        self.algorithm
+            .write_bytes(&mut rdata, &mut LookupTable::new(0));
+        rdata.put_uint(self.time_signed, 6);
+        rdata.put_u16(self.fudge);
+        rdata.put_u16(self.mac.len() as u16);
+        rdata.put_slice(&self.mac);
+        rdata.put_u16(self.original_id);
+        rdata.put_u16(self.error);
+        rdata.put_u16(self.other.len() as u16);
+        rdata.put_slice(&self.other);
+
+        DnsRecord::new(
+            key_name.clone(),
+            RecordType::from(TSIG_RECORD_TYPE),
+            RecordClass::from(TSIG_CLASS),
+            0,
+            RecordData::Unknown(rdata.to_vec()),
+        )
+    }
+}
+
+/// Next `len` bytes of `buf`, if there are enough of them
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let (taken, rest) = buf.split_at_checked(len)?;
+    *buf = rest;
+    Some(taken)
+}
+
+/// Big-endian unsigned number of `len` bytes
+fn number(buf: &mut &[u8], len: usize) -> Option<u64> {
+    let bytes = take(buf, len)?;
+    Some(
+        bytes
+            .iter()
+            .fold(0, |number, byte| number << 8 | *byte as u64),
+    )
+}
+
+#[cfg(all(test, feature = "tsig"))]
+mod tests {
+    use super::*;
+    use crate::packet::{BytesPacket, DnsPacket};
+    use crate::question::{QueryClass, QueryType};
+
+    #[test]
+    fn test_signed_query_is_verified() {
+        let key: TsigKey = "hmac-sha256:transfer:c2VjcmV0LWtleQ==".parse().unwrap();
+        let other: TsigKey = "transfer:b3RoZXIta2V5".parse().unwrap();
+        let query = DnsPacket::builder()
+            .id(4321)
+            .question(DnsQuestion::new(
+                "home.arpa".into(),
+                QueryType::UNKNOWN(252),
+                QueryClass::IN,
+            ))
+            .build();
+        let mut message = BytesPacket::from(query).buf;
+
+        assert!(verify(std::slice::from_ref(&key), &message)
+            .unwrap()
+            .is_none());
+
+        sign_query(&key, &mut message);
+        let signer = verify(std::slice::from_ref(&key), &message)
+            .unwrap()
+            .unwrap();
+        assert_eq!(signer.key_name(), &DomainName::from("transfer"));
+
+        let rejected = verify(&[other], &message).err().unwrap();
+        assert_eq!(rejected.error, BADSIG);
+
+        let last = message.len() - 1;
+        message[last] ^= 1; // other data length
+        assert!(verify(&[key], &message).is_err());
+    }
+
+    /// Query signed by `key` at `time_signed`
+    fn signed_query(key: &TsigKey, time_signed: u64) -> BytesMut {
+        let query = DnsPacket::builder()
+            .id(4321)
+            .question(DnsQuestion::new(
+                "home.arpa".into(),
+                QueryType::UNKNOWN(252),
+                QueryClass::IN,
+            ))
+            .build();
+        let mut message = BytesPacket::from(query).buf;
+        let tsig = TsigData {
+            time_signed,
+            ..TsigData::new(4321)
+        };
+        let mut data = message.to_vec();
+        data.extend(tsig.variables(&key.name));
+        let tsig = TsigData {
+            mac: key.mac(&data),
+            ..tsig
+        };
+        append(&mut message, tsig.record(&key.name));
+        message
+    }
+
+    #[test]
+    fn test_bad_signatures_are_rejected() {
+        let key: TsigKey = "transfer:c2VjcmV0LWtleQ==".parse().unwrap();
+        let keys = std::slice::from_ref(&key);
+
+        // MAC of 32 bytes ends before original ID, error and other data length
+        let mut tampered = signed_query(&key, unix_time());
+        let mac_end = tampered.len() - 6;
+        tampered[mac_end - 1] ^= 1;
+        assert_eq!(verify(keys, &tampered).err().unwrap().error, BADSIG);
+
+        let stranger: TsigKey = "stranger:c2VjcmV0LWtleQ==".parse().unwrap();
+        let unknown = signed_query(&stranger, unix_time());
+        let rejected = verify(keys, &unknown).err().unwrap();
+        assert_eq!(rejected.error, BADKEY);
+        assert_eq!(rejected.to_string(), "TSIG key stranger.: unknown key");
+
+        let late = signed_query(&key, unix_time() - 2 * FUDGE as u64);
+        assert_eq!(verify(keys, &late).err().unwrap().error, BADTIME);
+        let early = signed_query(&key, unix_time() + 2 * FUDGE as u64);
+        assert_eq!(verify(keys, &early).err().unwrap().error, BADTIME);
+    }
+
+    #[test]
+    fn test_malformed_keys_are_rejected() {
+        for key in [
+            "transfer",
+            "hmac-md5:transfer:c2VjcmV0",
+            "transfer:not base64!",
+            "a:b:c:d",
+        ] {
+            assert!(key.parse::<TsigKey>().is_err(), "{}", key);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! DNS over unix domain sockets
+//!
+//! Local services (e.g. other containers sharing a volume) can query the server without
+//! going through the network stack. Stream sockets carry messages prefixed with two byte
+//! length, like TCP (RFC 1035 section 4.2.2); datagram sockets carry one message per
+//! datagram, like UDP, but without its size limits.
+//!
+//! Idle stream connections are closed after [`IDLE_TIMEOUT`], which is announced to clients
+//! asking with edns-tcp-keepalive option (RFC 7828).
+
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::edns::{EdnsOption, OPTION_TCP_KEEPALIVE};
+use crate::handler::Request;
+use crate::log;
+use crate::packet::{BytesPacket, DnsPacket};
+use crate::trace;
+
+/// Largest DNS message, limited by the two byte length prefix
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// How long a stream connection may stay open without any query (RFC 7766 section 6.2.3)
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves length-prefixed queries on a stream socket at `path`, each connection in its own thread
+pub fn serve_stream<F>(path: &Path, handler: F) -> Result<()>
+where
+    F: Fn(&Request) -> Result<DnsPacket> + Send + Sync + 'static,
+{
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind unix socket {}", path.display()))?;
+
+    println!("Listening on unix stream socket {}", path.display());
+
+    let handler = Arc::new(handler);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Unix socket: error accepting connection: {}", e);
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, handler.as_ref()) {
+                eprintln!("Unix socket: error handling connection: {:#}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Serves queries on a datagram socket at `path`
+///
+/// Clients must bind their own socket to a path, unnamed sockets can't be answered.
+pub fn serve_datagram(path: &Path, handler: impl Fn(&Request) -> Result<DnsPacket>) -> Result<()> {
+    remove_stale_socket(path)?;
+    let socket = UnixDatagram::bind(path)
+        .with_context(|| format!("Failed to bind unix socket {}", path.display()))?;
+
+    println!("Listening on unix datagram socket {}", path.display());
+
+    let mut buf = vec![0; MAX_MESSAGE_SIZE];
+    loop {
+        let (size, source) = socket.recv_from(&mut buf)?;
+        let Some(source) = source.as_pathname() else {
+            eprintln!("Unix socket: ignoring query from unnamed socket");
+            continue;
+        };
+
+        match answer(&buf[..size], &handler, None) {
+            Ok(response) => {
+                if let Err(e) = socket.send_to(&response.buf, source) {
+                    eprintln!("Unix socket: error sending response: {}", e);
+                }
+            }
+            Err(e) => eprintln!("Unix socket: {:#}", e),
+        }
+    }
+}
+
+fn handle_connection(
+    mut stream: UnixStream,
+    handler: &impl Fn(&Request) -> Result<DnsPacket>,
+) -> Result<()> {
+    use std::io::ErrorKind;
+
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+
+    let mut buf = vec![0; MAX_MESSAGE_SIZE];
+    loop {
+        let mut len = [0; 2];
+        match stream.read_exact(&mut len) {
+            Ok(()) => {}
+            // client is done or idle for too long
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(())
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let message = &mut buf[..u16::from_be_bytes(len) as usize];
+        stream.read_exact(message)?;
+
+        let response = answer(message, handler, Some(IDLE_TIMEOUT))?.buf;
+        let mut framed = Vec::with_capacity(2 + response.len());
+        framed.extend_from_slice(&(response.len() as u16).to_be_bytes());
+        framed.extend_from_slice(&response);
+        stream.write_all(&framed)?;
+    }
+}
+
+/// Parses the query and serializes its response
+///
+/// Queries asking for edns-tcp-keepalive get the `idle_timeout` of the connection, if any.
+fn answer(
+    message: &[u8],
+    handler: &impl Fn(&Request) -> Result<DnsPacket>,
+    idle_timeout: Option<Duration>,
+) -> Result<BytesPacket> {
+    let query = DnsPacket::parse(message).context("malformed query")?;
+    if log::is_text() {
+        println!("<<< Received DNS packet (unix socket):\n{}", query);
+    }
+    let keepalive = query
+        .opt
+        .as_ref()
+        .is_some_and(|opt| opt.has_option(OPTION_TCP_KEEPALIVE));
+    let mut span = trace::query_span("unix", &query, None);
+    let query_log = log::query("unix");
+
+    let mut response = span.record(handler(&Request::new(query, None)))?;
+    if let (Some(opt), Some(timeout)) = (response.opt.as_mut(), idle_timeout.filter(|_| keepalive))
+    {
+        opt.options.push(EdnsOption::tcp_keepalive(Some(timeout)));
+    }
+    span.response(&response);
+    query_log.finish(None, &response);
+    if log::is_text() {
+        println!(">>> Sent DNS packet (unix socket):\n{}", response);
+    }
+
+    let _span = trace::span("dns.serialize");
+    Ok(BytesPacket::from(response))
+}
+
+/// Socket file left behind by a previous run would prevent binding
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)
+            .with_context(|| format!("Failed to remove old socket {}", path.display())),
+        Ok(_) => anyhow::bail!("{} exists and is not a socket", path.display()),
+        Err(_) => Ok(()),
+    }
+}
@@ -0,0 +1,806 @@
+//! Transports used for forwarding queries to an upstream DNS server
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::edns::EdnsOption;
+use crate::listen::SocketBuffers;
+use crate::packet::{BytesPacket, DnsPacket};
+use crate::stamp::{Protocol, Stamp, TlsServer};
+use crate::trace;
+
+/// Largest UDP response accepted from upstream servers
+pub const MAX_UDP_PAYLOAD_SIZE: u16 = 4096;
+
+/// Upstream DNS server, exchanges a query for its response
+pub trait Upstream: Send + Sync {
+    fn exchange(&self, packet: &DnsPacket) -> Result<DnsPacket>;
+}
+
+/// Plain DNS over UDP (RFC 1035 section 4.2.1)
+///
+/// All queries share one long-lived socket, bound on the first exchange. Responses are read
+/// by a background thread and handed over to the waiting exchanges by message ID. IDs are
+/// rewritten to unique ones on the wire, so concurrent queries with equal IDs don't collide.
+#[derive(Debug)]
+pub struct UdpUpstream {
+    address: String,
+    timeout: Option<Duration>,
+    buffers: SocketBuffers,
+    connection: Mutex<Option<Arc<UdpConnection>>>,
+}
+
+/// Socket shared by all exchanges with queries waiting for their responses
+#[derive(Debug)]
+struct UdpConnection {
+    socket: UdpSocket,
+    pending: PendingQueries,
+}
+
+/// How often the reader thread checks whether the upstream still exists
+const READER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+impl UdpUpstream {
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            timeout: None,
+            buffers: SocketBuffers::default(),
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Time to wait for the response, waits forever if not set
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Kernel buffer sizes of the socket, the system defaults if not set
+    pub fn with_buffers(mut self, buffers: SocketBuffers) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    /// Shared socket, bound (and its reader started) when used for the first time
+    fn connection(&self) -> Result<Arc<UdpConnection>> {
+        let mut connection = self.connection.lock().expect("upstream lock poisoned");
+        if let Some(connection) = connection.as_ref() {
+            return Ok(connection.clone());
+        }
+
+        let address = resolve_address(&self.address)?;
+        let local: SocketAddr = match address {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+            SocketAddr::V6(_) => "[::]:0".parse()?,
+        };
+
+        let socket = UdpSocket::bind(local).context("Failed to bind upstream socket")?;
+        socket.connect(address)?;
+        if self.buffers.is_set() {
+            let (receive, send) = self.buffers.apply(&socket)?;
+            println!(
+                "Upstream socket to {}: {} bytes receive buffer, {} bytes send buffer",
+                address, receive, send
+            );
+        }
+        let reader_socket = socket.try_clone()?;
+        reader_socket.set_read_timeout(Some(READER_POLL_INTERVAL))?;
+
+        let new_connection = Arc::new(UdpConnection {
+            socket,
+            pending: PendingQueries::default(),
+        });
+        let weak = Arc::downgrade(&new_connection);
+        thread::spawn(move || read_udp_responses(&reader_socket, &weak));
+
+        *connection = Some(new_connection.clone());
+        Ok(new_connection)
+    }
+}
+
+impl Upstream for UdpUpstream {
+    fn exchange(&self, packet: &DnsPacket) -> Result<DnsPacket> {
+        let mut span = trace::upstream_span("udp", &self.address);
+        let connection = span.record(self.connection())?;
+        let (query, receiver) = span.record(connection.pending.register(packet, &self.address))?;
+
+        let bytes_packet = BytesPacket::from(&query);
+        let sent = connection
+            .socket
+            .send(&bytes_packet.buf)
+            .with_context(|| format!("Failed to send query to {}", self.address));
+
+        let received = sent.and_then(|_| {
+            wait_for_response(&receiver, self.timeout)
+                .with_context(|| format!("no response from {}", self.address))
+        });
+        connection.pending.remove(query.header.id);
+
+        let mut response = span.record(received)?;
+        response.header.id = packet.header.id;
+        Ok(response)
+    }
+}
+
+/// Reads responses from the shared socket until the upstream is dropped
+fn read_udp_responses(socket: &UdpSocket, connection: &Weak<UdpConnection>) {
+    let mut buf = [0; MAX_UDP_PAYLOAD_SIZE as usize];
+    loop {
+        let received = socket.recv(&mut buf);
+        let Some(connection) = connection.upgrade() else {
+            return; // nobody is interested anymore
+        };
+
+        // timeouts and ICMP errors can't be attributed to any query, those time out on their own
+        let Ok(size) = received else {
+            continue;
+        };
+        if let Ok(response) = DnsPacket::parse(&buf[..size]) {
+            connection.pending.deliver(response);
+        }
+    }
+}
+
+/// Plain DNS over TCP (RFC 1035 section 4.2.2, RFC 7766)
+///
+/// One connection is kept open and shared by all queries, which are pipelined on it without
+/// waiting for each other. Responses may come in any order, they are matched to the queries
+/// by the (rewritten) message ID, like with [`UdpUpstream`]. When the server closes the
+/// connection or it breaks, the next exchange opens a new one; a query whose connection was
+/// lost before its response arrived is transparently sent once more over a new connection.
+///
+/// Queries with EDNS ask for the server's idle timeout (edns-tcp-keepalive, RFC 7828),
+/// a connection left idle for longer is not reused.
+#[derive(Debug)]
+pub struct TcpUpstream {
+    address: String,
+    timeout: Option<Duration>,
+    connection: Mutex<Option<Arc<TcpConnection>>>,
+}
+
+/// Open connection with queries waiting for their responses
+#[derive(Debug)]
+struct TcpConnection {
+    stream: TcpStream,
+    /// Held while writing a message, so messages of concurrent queries don't interleave
+    writing: Mutex<()>,
+    pending: PendingQueries,
+    /// Set once the connection must not be used for new queries
+    closed: AtomicBool,
+    /// Last query or response sent over the connection
+    last_activity: Mutex<Instant>,
+    /// Idle timeout announced by the server
+    idle_timeout: Mutex<Option<Duration>>,
+}
+
+impl TcpConnection {
+    fn send(&self, query: &DnsPacket) -> std::io::Result<()> {
+        // TCP messages are prefixed with two byte length
+        let bytes_packet = BytesPacket::from(query);
+        let mut message = Vec::with_capacity(2 + bytes_packet.buf.len());
+        message.extend_from_slice(&(bytes_packet.buf.len() as u16).to_be_bytes());
+        message.extend_from_slice(&bytes_packet.buf);
+
+        let _writing = self.writing.lock().expect("upstream lock poisoned");
+        (&self.stream).write_all(&message)
+    }
+
+    /// Stops using the connection, its reader thread wakes up and disconnects waiting queries
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn touch(&self) {
+        *self.last_activity.lock().expect("upstream lock poisoned") = Instant::now();
+    }
+
+    /// Server may have already closed the connection, as it was idle longer than it allows
+    fn is_idle_expired(&self) -> bool {
+        let idle_timeout = *self.idle_timeout.lock().expect("upstream lock poisoned");
+        let last_activity = *self.last_activity.lock().expect("upstream lock poisoned");
+
+        idle_timeout.is_some_and(|timeout| last_activity.elapsed() >= timeout)
+            && self.pending.is_empty()
+    }
+}
+
+impl TcpUpstream {
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            timeout: None,
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Time to wait for the connection and the response, waits forever if not set
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Open connection, a new one is made (and its reader started) if there is none usable
+    fn connection(&self) -> Result<Arc<TcpConnection>> {
+        let mut connection = self.connection.lock().expect("upstream lock poisoned");
+        if let Some(connection) = connection.as_ref() {
+            if connection.is_idle_expired() {
+                connection.close();
+            } else if !connection.is_closed() {
+                return Ok(connection.clone());
+            }
+        }
+
+        let address = resolve_address(&self.address)?;
+        let stream = match self.timeout {
+            Some(timeout) => TcpStream::connect_timeout(&address, timeout),
+            None => TcpStream::connect(address),
+        }
+        .with_context(|| format!("Failed to connect to {} over TCP", address))?;
+        stream.set_write_timeout(self.timeout)?;
+        stream.set_nodelay(true)?;
+        let reader_stream = stream.try_clone()?;
+
+        let new_connection = Arc::new(TcpConnection {
+            stream,
+            writing: Mutex::new(()),
+            pending: PendingQueries::default(),
+            closed: AtomicBool::new(false),
+            last_activity: Mutex::new(Instant::now()),
+            idle_timeout: Mutex::new(None),
+        });
+        let reader_connection = new_connection.clone();
+        thread::spawn(move || read_tcp_responses(reader_stream, &reader_connection));
+
+        *connection = Some(new_connection.clone());
+        Ok(new_connection)
+    }
+
+    /// Exchange over the current connection, `None` if the connection was lost in the meantime
+    fn try_exchange(&self, packet: &DnsPacket) -> Result<Option<DnsPacket>> {
+        let connection = self.connection()?;
+        let (mut query, receiver) = connection.pending.register(packet, &self.address)?;
+        if let Some(opt) = query.opt.as_mut() {
+            opt.options.push(EdnsOption::tcp_keepalive(None));
+        }
+        connection.touch();
+
+        // reader disconnects waiting queries only after marking the connection as closed
+        if connection.is_closed() || connection.send(&query).is_err() {
+            connection.pending.remove(query.header.id);
+            connection.close();
+            return Ok(None);
+        }
+
+        let received = wait_for_response(&receiver, self.timeout);
+        connection.pending.remove(query.header.id);
+
+        match received {
+            Ok(mut response) => {
+                connection.touch();
+                if let Some(timeout) = response
+                    .opt
+                    .as_ref()
+                    .and_then(|o| o.tcp_keepalive_timeout())
+                {
+                    *connection
+                        .idle_timeout
+                        .lock()
+                        .expect("upstream lock poisoned") = Some(timeout);
+                }
+                response.header.id = packet.header.id;
+                Ok(Some(response))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Ok(None),
+            Err(e @ mpsc::RecvTimeoutError::Timeout) => {
+                // silent server or dead connection (e.g. dropped by a NAT), start afresh next time
+                connection.close();
+                Err(e).with_context(|| format!("no response from {} over TCP", self.address))
+            }
+        }
+    }
+}
+
+impl Upstream for TcpUpstream {
+    fn exchange(&self, packet: &DnsPacket) -> Result<DnsPacket> {
+        let attempt =
+            || trace::connection_attempt("tcp", &self.address, || self.try_exchange(packet));
+        if let Some(response) = attempt()? {
+            return Ok(response);
+        }
+        attempt()?.with_context(|| format!("TCP connection to {} lost", self.address))
+    }
+}
+
+impl Drop for TcpUpstream {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.get_mut().ok().and_then(|c| c.take()) {
+            connection.close();
+        }
+    }
+}
+
+/// Reads responses from the connection until it is closed (by either side) or broken
+fn read_tcp_responses(mut stream: TcpStream, connection: &TcpConnection) {
+    loop {
+        let mut len = [0; 2];
+        let mut buf = Vec::new();
+        let received = stream.read_exact(&mut len).and_then(|_| {
+            buf.resize(u16::from_be_bytes(len) as usize, 0);
+            stream.read_exact(&mut buf)
+        });
+
+        if received.is_err() {
+            connection.close();
+            connection.pending.clear();
+            return;
+        }
+        // framing is intact even if the message itself is garbage
+        if let Ok(response) = DnsPacket::parse(&buf) {
+            connection.pending.deliver(response);
+        }
+    }
+}
+
+/// Queries waiting for their responses, by the ID used on the wire
+#[derive(Debug, Default)]
+pub(crate) struct PendingQueries {
+    /// Queries (with rewritten ID) and where to send their responses
+    queries: Mutex<HashMap<u16, (DnsPacket, mpsc::Sender<DnsPacket>)>>,
+}
+
+impl PendingQueries {
+    /// Registers a copy of `packet` under an ID not used by any other waiting query,
+    /// returns the copy (to be sent) and the receiver of its response
+    pub(crate) fn register(
+        &self,
+        packet: &DnsPacket,
+        address: &str,
+    ) -> Result<(DnsPacket, mpsc::Receiver<DnsPacket>)> {
+        let (sender, receiver) = mpsc::channel();
+        let mut query = packet.clone();
+
+        let mut queries = self.queries.lock().expect("upstream lock poisoned");
+        if queries.len() > u16::MAX as usize / 2 {
+            anyhow::bail!("too many queries waiting for {}", address);
+        }
+        query.header.id = loop {
+            let id = rand::random();
+            if !queries.contains_key(&id) {
+                break id;
+            }
+        };
+        queries.insert(query.header.id, (query.clone(), sender));
+
+        Ok((query, receiver))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queries
+            .lock()
+            .expect("upstream lock poisoned")
+            .is_empty()
+    }
+
+    pub(crate) fn remove(&self, id: u16) {
+        self.queries
+            .lock()
+            .expect("upstream lock poisoned")
+            .remove(&id);
+    }
+
+    /// Hands the response over to its query, unrelated or spoofed packets are ignored
+    pub(crate) fn deliver(&self, response: DnsPacket) {
+        let mut queries = self.queries.lock().expect("upstream lock poisoned");
+        let matching = queries
+            .get(&response.header.id)
+            .is_some_and(|(query, _)| is_response_to(&response, query));
+        if matching {
+            let (_, sender) = queries
+                .remove(&response.header.id)
+                .expect("pending query exists");
+            let _ = sender.send(response);
+        }
+    }
+
+    /// Drops all waiting queries, their receivers get disconnected
+    pub(crate) fn clear(&self) {
+        self.queries.lock().expect("upstream lock poisoned").clear();
+    }
+}
+
+pub(crate) fn wait_for_response(
+    receiver: &mpsc::Receiver<DnsPacket>,
+    timeout: Option<Duration>,
+) -> Result<DnsPacket, mpsc::RecvTimeoutError> {
+    match timeout {
+        Some(timeout) => receiver.recv_timeout(timeout),
+        None => receiver
+            .recv()
+            .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+    }
+}
+
+/// Several upstreams with the same content, tried in order until one of them responds
+///
+/// Used when the system resolver configuration lists more name servers.
+pub struct FailoverUpstream {
+    upstreams: Vec<Arc<dyn Upstream>>,
+}
+
+impl FailoverUpstream {
+    pub fn new(upstreams: impl IntoIterator<Item = Arc<dyn Upstream>>) -> Self {
+        Self {
+            upstreams: upstreams.into_iter().collect(),
+        }
+    }
+}
+
+impl Upstream for FailoverUpstream {
+    fn exchange(&self, packet: &DnsPacket) -> Result<DnsPacket> {
+        let mut last_error = anyhow::anyhow!("no upstream configured");
+        for upstream in self.upstreams.iter() {
+            match upstream.exchange(packet) {
+                Ok(response) => return Ok(response),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// How encrypted upstreams treat servers whose TLS can't be established or validated
+///
+/// Same semantics as `DNSOverTLS=yes` and `DNSOverTLS=opportunistic` of systemd-resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Privacy {
+    /// Queries fail rather than being sent unencrypted
+    #[default]
+    Strict,
+    /// Queries are sent in plain text to port 53 of the same server if TLS fails
+    Opportunistic,
+}
+
+impl FromStr for Privacy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "opportunistic" => Ok(Self::Opportunistic),
+            _ => anyhow::bail!("unknown privacy mode {:?} (strict or opportunistic)", s),
+        }
+    }
+}
+
+/// Settings of encrypted upstreams
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    pub privacy: Privacy,
+    /// SHA256 digests of SubjectPublicKeyInfo, one of them must be in the server's chain
+    /// (like `pin-sha256` of RFC 7469)
+    pub spki_pins: Vec<Vec<u8>>,
+    /// Pins are trusted instead of the root certificates, the host name isn't checked either
+    pub pins_only: bool,
+}
+
+/// Parses SPKI pin `sha256/<base64>` (prefix optional), as printed by e.g.
+/// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl sha256 -binary | base64`
+pub fn parse_spki_pin(pin: &str) -> Result<Vec<u8>> {
+    let encoded = pin.strip_prefix("sha256/").unwrap_or(pin);
+    crate::base64::decode(encoded)
+        .filter(|hash| hash.len() == 32)
+        .with_context(|| {
+            format!(
+                "invalid SPKI pin {:?}, base64 of SHA256 digest expected",
+                pin
+            )
+        })
+}
+
+/// Upstream server as given on the command line
+///
+/// - `host:port` - plain DNS over UDP
+/// - `tls://host[:port]` - DNS over TLS, port 853 by default
+/// - `https://host[:port][/path]` - DNS over HTTPS, path `/dns-query` by default
+/// - `sdns://...` - DNS stamp of a plain, DoT or DoH server, see [`crate::stamp`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpstreamSpec {
+    Udp(String),
+    Tls(TlsServer),
+    Https(TlsServer),
+}
+
+impl UpstreamSpec {
+    /// Adds plain DNS resolvers for looking up the host name of an encrypted upstream
+    pub fn with_bootstrap(mut self, resolvers: &[IpAddr]) -> Self {
+        if let Self::Tls(server) | Self::Https(server) = &mut self {
+            server.bootstrap.extend_from_slice(resolvers);
+        }
+        self
+    }
+
+    /// Creates the client for the upstream, encrypted ones need the `tls` feature
+    ///
+    /// `buffers` apply to plain UDP upstreams only.
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    pub fn build(
+        &self,
+        timeout: Duration,
+        options: &TlsOptions,
+        buffers: SocketBuffers,
+    ) -> Result<Arc<dyn Upstream>> {
+        match self {
+            Self::Udp(address) => Ok(Arc::new(
+                UdpUpstream::new(address.clone())
+                    .with_timeout(timeout)
+                    .with_buffers(buffers),
+            )),
+            #[cfg(feature = "tls")]
+            Self::Tls(server) => Ok(Arc::new(crate::tls::TlsUpstream::new(
+                server, options, timeout,
+            )?)),
+            #[cfg(feature = "tls")]
+            Self::Https(server) => Ok(Arc::new(crate::tls::HttpsUpstream::new(
+                server, options, timeout,
+            )?)),
+            #[cfg(not(feature = "tls"))]
+            Self::Tls(_) | Self::Https(_) => {
+                anyhow::bail!("encrypted upstreams require the tls feature")
+            }
+        }
+    }
+}
+
+impl FromStr for UpstreamSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("sdns://") {
+            let stamp: Stamp = s.parse()?;
+            return match stamp.protocol {
+                Protocol::Plain { address } => Ok(Self::Udp(address.to_string())),
+                Protocol::Tls(server) => Ok(Self::Tls(server)),
+                Protocol::Https(server) => Ok(Self::Https(server)),
+                Protocol::DnsCrypt { .. } => anyhow::bail!("DNSCrypt upstreams are not supported"),
+                Protocol::Quic(_) => anyhow::bail!("DNS over QUIC upstreams are not supported"),
+            };
+        }
+        if let Some(rest) = s.strip_prefix("tls://") {
+            return Ok(Self::Tls(parse_url_server(
+                rest.trim_end_matches('/'),
+                853,
+            )?));
+        }
+        if let Some(rest) = s.strip_prefix("https://") {
+            let (authority, path) = match rest.find('/') {
+                Some(slash) => rest.split_at(slash),
+                None => (rest, "/dns-query"),
+            };
+            let mut server = parse_url_server(authority, 443)?;
+            server.path = Some(path.to_string());
+            return Ok(Self::Https(server));
+        }
+        Ok(Self::Udp(s.to_string()))
+    }
+}
+
+impl fmt::Display for UpstreamSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Udp(address) => write!(f, "{}", address),
+            Self::Tls(server) => write!(f, "tls://{}:{}", server.hostname, server.port),
+            Self::Https(server) => write!(
+                f,
+                "https://{}:{}{}",
+                server.hostname,
+                server.port,
+                server.path.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// Host name (or IP address) and optional port of the URL
+fn parse_url_server(authority: &str, default_port: u16) -> Result<TlsServer> {
+    let (host, port) = match authority.rsplit_once(':') {
+        // bare IPv6 address has colons too
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+            (host, port.parse().context("invalid port")?)
+        }
+        _ => (authority, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        anyhow::bail!("missing host name in {}", authority);
+    }
+
+    Ok(TlsServer {
+        address: host.parse().ok(),
+        port,
+        hostname: host.to_string(),
+        cert_hashes: Vec::new(),
+        path: None,
+        bootstrap: Vec::new(),
+    })
+}
+
+/// Upstream answering from a closure, for testing without network I/O
+///
+/// ```
+/// use dns_starter_rust::packet::DnsPacket;
+/// use dns_starter_rust::upstream::{MockUpstream, Upstream};
+///
+/// let upstream = MockUpstream::new(|query: &DnsPacket| {
+///     Ok(DnsPacket::builder().header(query.header).response().build())
+/// });
+///
+/// assert!(upstream.exchange(&DnsPacket::new()).unwrap().header.response);
+/// ```
+pub struct MockUpstream<F> {
+    respond: F,
+}
+
+impl<F> MockUpstream<F>
+where
+    F: Fn(&DnsPacket) -> Result<DnsPacket> + Send + Sync,
+{
+    pub fn new(respond: F) -> Self {
+        Self { respond }
+    }
+}
+
+impl<F> Upstream for MockUpstream<F>
+where
+    F: Fn(&DnsPacket) -> Result<DnsPacket> + Send + Sync,
+{
+    fn exchange(&self, packet: &DnsPacket) -> Result<DnsPacket> {
+        (self.respond)(packet)
+    }
+}
+
+fn resolve_address(address: &str) -> Result<SocketAddr> {
+    address
+        .to_socket_addrs()
+        .with_context(|| format!("invalid upstream address {}", address))?
+        .next()
+        .with_context(|| format!("upstream address {} not resolved", address))
+}
+
+/// Checks that `response` answers `query` (ID, QR flag and question names)
+pub fn is_response_to(response: &DnsPacket, query: &DnsPacket) -> bool {
+    response.header.response
+        && response.header.id == query.header.id
+        && response.questions.len() == query.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(query.questions.iter())
+            .all(|(r, q)| r.domain_name.eq_ignore_case(&q.domain_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::domain_name::DomainName;
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+
+    fn read_message(stream: &mut TcpStream) -> DnsPacket {
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf).unwrap();
+        DnsPacket::parse(&buf).unwrap()
+    }
+
+    fn answer(stream: &mut TcpStream, query: &DnsPacket) {
+        let response = DnsPacket::builder()
+            .header(query.header)
+            .response()
+            .questions(query.questions.clone())
+            .build();
+        let buf = BytesPacket::from(response).buf;
+        stream.write_all(&(buf.len() as u16).to_be_bytes()).unwrap();
+        stream.write_all(&buf).unwrap();
+    }
+
+    #[test]
+    fn test_tcp_queries_are_pipelined_and_reconnected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            // both queries arrive before any response is sent, responses go in reverse order
+            let (mut stream, _) = listener.accept().unwrap();
+            let first = read_message(&mut stream);
+            let second = read_message(&mut stream);
+            answer(&mut stream, &second);
+            answer(&mut stream, &first);
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let third = read_message(&mut stream);
+            answer(&mut stream, &third);
+        });
+
+        let upstream = TcpUpstream::new(address.to_string()).with_timeout(Duration::from_secs(5));
+        let query = |name: &str, id| {
+            DnsPacket::builder()
+                .id(id)
+                .question(DnsQuestion::new(
+                    DomainName::from(name),
+                    QueryType::A,
+                    QueryClass::IN,
+                ))
+                .build()
+        };
+
+        thread::scope(|scope| {
+            for (name, id) in [("one.example.com", 1), ("two.example.com", 2)] {
+                let query = query(name, id);
+                let upstream = &upstream;
+                scope.spawn(move || {
+                    let response = upstream.exchange(&query).unwrap();
+                    assert!(is_response_to(&response, &query));
+                });
+            }
+        });
+
+        // the server closed the first connection
+        let third = query("three.example.com", 3);
+        let response = upstream.exchange(&third).unwrap();
+        assert!(is_response_to(&response, &third));
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_parse_upstream_specs() {
+        assert_eq!(
+            "8.8.8.8:53".parse::<UpstreamSpec>().unwrap(),
+            UpstreamSpec::Udp("8.8.8.8:53".into())
+        );
+
+        let UpstreamSpec::Tls(server) = "tls://[2620:fe::fe]".parse().unwrap() else {
+            panic!("DoT upstream expected");
+        };
+        assert_eq!(server.address, Some("2620:fe::fe".parse().unwrap()));
+        assert_eq!(server.port, 853);
+
+        let UpstreamSpec::Https(server) = "https://dns.example:8443".parse().unwrap() else {
+            panic!("DoH upstream expected");
+        };
+        assert_eq!(
+            (server.hostname.as_str(), server.port),
+            ("dns.example", 8443)
+        );
+        assert_eq!(server.address, None);
+        assert_eq!(server.path.as_deref(), Some("/dns-query"));
+
+        let stamp = "sdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQovZG5zLXF1ZXJ5";
+        let spec: UpstreamSpec = stamp.parse().unwrap();
+        assert_eq!(spec.to_string(), "https://dns.cloudflare.com:443/dns-query");
+        assert_eq!(
+            "sdns://AAcAAAAAAAAABzguOC44Ljg"
+                .parse::<UpstreamSpec>()
+                .unwrap(),
+            UpstreamSpec::Udp("8.8.8.8:53".into())
+        );
+    }
+}
@@ -0,0 +1,1386 @@
+//! DNSSEC validation of forwarded answers (RFC 4035 section 5)
+//!
+//! The chain of trust is followed from a trust anchor, the root KSKs by default, down to
+//! the zone of every RRset in the answer. DS and DNSKEY RRsets of the zones on the way are
+//! queried from the upstream (with CD set, so it hands over even data it considers bogus)
+//! and checked, one label after another. Data is insecure only when signed NSEC or NSEC3
+//! records prove there's no DS at some zone cut on the way, when the DS has no algorithm
+//! we support, or when no trust anchor covers the name. Unsigned data of a secure zone
+//! is bogus.
+//!
+//! Negative answers must be proven by NSEC (RFC 4035 section 5.4) or NSEC3 (RFC 5155
+//! section 8) records, answers expanded from a wildcard by a proof that the queried name
+//! doesn't exist.
+//!
+//! Validated keys and zone cuts are cached for their TTL, at most [`MAX_CACHE_TTL`].
+//! Failures are cached for [`BOGUS_TTL`], so broken zones don't flood the upstream.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use ring::digest;
+
+use crate::dnssec::{self, covered_type, key_tag, name_bytes, signed_data};
+use crate::domain_name::DomainName;
+use crate::edns::OptRecord;
+use crate::header::ResponseCode;
+use crate::packet::DnsPacket;
+use crate::question::{DnsQuestion, QueryClass, QueryType};
+use crate::record::{DnsRecord, RecordData, RecordType};
+use crate::upstream::{Upstream, MAX_UDP_PAYLOAD_SIZE};
+use crate::zone;
+
+/// DS records of the root KSKs, KSK-2017 and KSK-2024 (IANA root-anchors.xml)
+const ROOT_ANCHORS: &str = "
+. IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D
+. IN DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16
+";
+
+/// Validated keys and zone cuts are cached at most this long
+pub const MAX_CACHE_TTL: Duration = Duration::from_secs(3600);
+
+/// Proven absence of DS (or of a zone cut) is cached this long
+const NEGATIVE_TTL: Duration = Duration::from_secs(300);
+
+/// Zone cuts which failed validation are not checked again for this long
+pub const BOGUS_TTL: Duration = Duration::from_secs(60);
+
+/// Cached zone cuts at most, expired ones are dropped when the cache fills up
+const MAX_CUTS: usize = 10_000;
+
+/// NSEC3 with more iterations are not worth the CPU, their zones are insecure (RFC 9276 section 3.2)
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+/// Query type ANY, answered by records of any types
+const QTYPE_ANY: u16 = 255;
+
+/// DNSKEY flag of zone keys, the only ones which may sign (RFC 4034 section 2.1.1)
+const ZONE_KEY_FLAG: u16 = 0x0100;
+
+/// NSEC3 flag of spans which may contain unsigned delegations (RFC 5155 section 3.1.2.1)
+const OPT_OUT_FLAG: u8 = 0x01;
+
+/// Reads DS records of trust anchors from a file in master file format
+pub fn read_trust_anchors(path: impl AsRef<Path>) -> Result<Vec<DnsRecord>> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read trust anchors {}", path.display()))?;
+    zone::parse_records(&text, &DomainName::new())
+        .with_context(|| format!("invalid trust anchors {}", path.display()))
+}
+
+/// What to do with answers which fail validation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValidationMode {
+    /// Bogus answers are replaced by SERVFAIL
+    Enforce,
+    /// Bogus answers are logged and counted, but still returned to clients
+    Permissive,
+}
+
+impl FromStr for ValidationMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "enforce" => Ok(Self::Enforce),
+            "permissive" => Ok(Self::Permissive),
+            _ => anyhow::bail!("unknown validation mode {:?}", s),
+        }
+    }
+}
+
+/// Outcome of the validation of an answer (RFC 4033 section 5)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Security {
+    Secure,
+    Insecure,
+    /// Validation failed, for the given reason
+    Bogus(String),
+}
+
+impl fmt::Display for Security {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Secure => write!(f, "secure"),
+            Self::Insecure => write!(f, "insecure"),
+            Self::Bogus(reason) => write!(f, "bogus: {}", reason),
+        }
+    }
+}
+
+/// Step of a validation, reported to the observer (see [`Validator::observe`])
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// DS records of the trust anchor of `zone`, by key tag
+    Anchor { zone: DomainName, tags: Vec<u16> },
+    /// DNSKEYs of `zone` by key tag, `trusted` ones match its DS
+    Keys {
+        zone: DomainName,
+        tags: Vec<u16>,
+        trusted: Vec<u16>,
+    },
+    /// RRset checked with the keys of `zone`, `Ok` with the tag of the key which signed it
+    Signature {
+        owner: DomainName,
+        record_type: RecordType,
+        zone: DomainName,
+        result: Result<u16, String>,
+    },
+    /// Proof that there are no `record_type` records at `name`
+    Denial {
+        name: DomainName,
+        record_type: RecordType,
+        result: Result<String, String>,
+    },
+    /// What the chain of trust finds at `name`: a secure or insecure zone, or no zone cut
+    Cut { name: DomainName, outcome: String },
+}
+
+/// One line per step, as printed by the `trace` subcommand
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tags = |tags: &[u16]| {
+            tags.iter()
+                .map(u16::to_string)
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        match self {
+            Self::Anchor { zone, tags: ds } => write!(f, "trust anchor {} DS {}", zone, tags(ds)),
+            Self::Keys {
+                zone,
+                tags: keys,
+                trusted,
+            } => write!(
+                f,
+                "{} DNSKEY {}, matching DS: {}",
+                zone,
+                tags(keys),
+                tags(trusted)
+            ),
+            Self::Signature {
+                owner,
+                record_type,
+                zone,
+                result,
+            } => match result {
+                Ok(tag) => write!(
+                    f,
+                    "{} {} signed by {} with key {}",
+                    owner, record_type, zone, tag
+                ),
+                Err(reason) => write!(f, "{} {}: bogus: {}", owner, record_type, reason),
+            },
+            Self::Denial {
+                name,
+                record_type,
+                result,
+            } => match result {
+                Ok(proof) => write!(f, "no {} at {}: {}", record_type, name, proof),
+                Err(reason) => write!(f, "no {} at {}: bogus: {}", record_type, name, reason),
+            },
+            Self::Cut { name, outcome } => write!(f, "{}: {}", name, outcome),
+        }
+    }
+}
+
+/// What the delegation at a name tells about the zone below it
+#[derive(Debug, Clone)]
+enum Cut {
+    /// Signed zone starts at the name, with these DNSKEYs
+    Secure(Vec<Vec<u8>>),
+    /// No zone starts at the name
+    Inside,
+    /// Name doesn't exist, nor do names below it
+    Absent,
+    /// Unsigned zone starts at the name
+    Insecure,
+    Bogus(String),
+}
+
+impl fmt::Display for Cut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Secure(_) => write!(f, "secure zone"),
+            Self::Inside => write!(f, "no zone cut"),
+            Self::Absent => write!(f, "name doesn't exist"),
+            Self::Insecure => write!(f, "insecure zone"),
+            Self::Bogus(reason) => write!(f, "bogus: {}", reason),
+        }
+    }
+}
+
+type Observer = Box<dyn Fn(&Step) + Send + Sync>;
+
+/// Validator of answers from the upstream, which is asked for DS and DNSKEY records too
+pub struct Validator {
+    upstream: Arc<dyn Upstream>,
+    /// DS records of the trust anchors
+    anchors: Vec<DnsRecord>,
+    mode: ValidationMode,
+    /// Zone cuts by canonical name, with their expiration
+    cuts: Mutex<HashMap<DomainName, (Cut, Instant)>>,
+    observer: Option<Observer>,
+}
+
+impl Validator {
+    /// Validator trusting the root KSKs, enforcing validation
+    pub fn new(upstream: Arc<dyn Upstream>) -> Self {
+        Self {
+            upstream,
+            anchors: zone::parse_records(ROOT_ANCHORS, &DomainName::new())
+                .expect("valid root trust anchors"),
+            mode: ValidationMode::Enforce,
+            cuts: Mutex::new(HashMap::new()),
+            observer: None,
+        }
+    }
+
+    /// Trusts DS records `anchors` instead of the root KSKs, e.g. of a private root or zone
+    pub fn trust_anchors(mut self, anchors: Vec<DnsRecord>) -> Result<Self> {
+        if let Some(anchor) = anchors
+            .iter()
+            .find(|anchor| anchor.record_type != RecordType::DS)
+        {
+            anyhow::bail!("trust anchor {} is not a DS record", anchor);
+        }
+        if anchors.is_empty() {
+            anyhow::bail!("no trust anchors");
+        }
+        self.anchors = anchors;
+        Ok(self)
+    }
+
+    /// Only logs and counts bogus answers, see [`ValidationMode::Permissive`]
+    pub fn permissive(mut self) -> Self {
+        self.mode = ValidationMode::Permissive;
+        self
+    }
+
+    pub fn mode(&self) -> ValidationMode {
+        self.mode
+    }
+
+    /// Reports every step of the validations to `observer`, e.g. to explain the result
+    pub fn observe(mut self, observer: impl Fn(&Step) + Send + Sync + 'static) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    fn report(&self, step: impl FnOnce() -> Step) {
+        if let Some(observer) = &self.observer {
+            observer(&step());
+        }
+    }
+
+    /// [`verify_rrset`], reported as a step
+    fn verify(
+        &self,
+        rrset: &[&DnsRecord],
+        section: &[DnsRecord],
+        zone: &DomainName,
+        keys: &[Vec<u8>],
+        now: u32,
+    ) -> Result<Option<DomainName>, String> {
+        let result = verify_rrset(rrset, section, zone, keys, now);
+        self.report(|| Step::Signature {
+            owner: rrset[0].domain_name.clone(),
+            record_type: rrset[0].record_type.clone(),
+            zone: zone.clone(),
+            result: result.clone().map(|(tag, _)| tag),
+        });
+        result.map(|(_, encloser)| encloser)
+    }
+
+    /// Proves with NSEC or NSEC3 records of `section` that there's no `query_type` at `name`
+    #[allow(clippy::too_many_arguments)]
+    fn deny(
+        &self,
+        section: &[DnsRecord],
+        zone: &DomainName,
+        keys: &[Vec<u8>],
+        name: &DomainName,
+        query_type: u16,
+        now: u32,
+    ) -> Result<Denial, String> {
+        let denial = Proof::new(self, section, zone, keys, now)
+            .and_then(|proof| proof.deny(name, query_type));
+        self.report(|| Step::Denial {
+            name: name.clone(),
+            record_type: RecordType::from(query_type),
+            result: denial
+                .as_ref()
+                .map(Denial::to_string)
+                .map_err(String::clone),
+        });
+        denial
+    }
+
+    /// Validates the upstream `response` to `question`, queried with DO and CD set
+    ///
+    /// Error responses (SERVFAIL, REFUSED, ...) carry nothing to validate, they're insecure.
+    pub fn validate(&self, question: &DnsQuestion, response: &DnsPacket) -> Security {
+        let now = dnssec::timestamp(SystemTime::now());
+        match self.check(question, response, now) {
+            Ok(()) => Security::Secure,
+            Err(security) => security,
+        }
+    }
+
+    /// Checks every RRset of the answer and the proof of what's missing in it
+    fn check(
+        &self,
+        question: &DnsQuestion,
+        response: &DnsPacket,
+        now: u32,
+    ) -> Result<(), Security> {
+        let rescode = response.header.rescode;
+        if !matches!(rescode, ResponseCode::NOERROR | ResponseCode::NXDOMAIN) {
+            return Err(Security::Insecure);
+        }
+        let query_type = u16::from(question.query_type.clone());
+        let records: Vec<DnsRecord> = response
+            .answers
+            .iter()
+            .filter(|record| record.record_type != RecordType::RRSIG)
+            .cloned()
+            .collect();
+
+        let mut insecure = false;
+        for rrset in dnssec::rrsets(&records) {
+            let owner = &rrset[0].domain_name;
+            let (zone, keys) = match self.enclosing_zone(owner, rrset[0].record_type.clone(), now) {
+                Ok(zone) => zone,
+                Err(Security::Insecure) => {
+                    insecure = true;
+                    continue;
+                }
+                Err(security) => return Err(security),
+            };
+            let expanded = self
+                .verify(&rrset, &response.answers, &zone, &keys, now)
+                .map_err(Security::Bogus)?;
+            if let Some(encloser) = expanded {
+                Proof::new(self, &response.authorities, &zone, &keys, now)
+                    .and_then(|proof| proof.no_name(owner, &encloser))
+                    .map_err(|reason| {
+                        Security::Bogus(format!("wildcard answer for {}: {}", owner, reason))
+                    })?;
+            }
+        }
+
+        // aliases are followed to the name whose records were asked for
+        let mut name = question.domain_name.clone();
+        for _ in 0..records.len() {
+            match records.iter().find_map(|record| match &record.data {
+                RecordData::Name(target)
+                    if record.record_type == RecordType::CNAME
+                        && record.domain_name.eq_ignore_case(&name) =>
+                {
+                    Some(target.clone())
+                }
+                _ => None,
+            }) {
+                Some(target) => name = target,
+                None => break,
+            }
+        }
+        let answered = records.iter().any(|record| {
+            record.domain_name.eq_ignore_case(&name)
+                && u16::from(record.record_type.clone()) == query_type
+        }) || (!records.is_empty()
+            && (RecordType::from(query_type) == RecordType::CNAME || query_type == QTYPE_ANY));
+
+        if !answered {
+            let record_type = RecordType::from(query_type);
+            match self.enclosing_zone(&name, record_type.clone(), now) {
+                Ok((zone, keys)) => {
+                    let denial = self
+                        .deny(&response.authorities, &zone, &keys, &name, query_type, now)
+                        .map_err(|reason| {
+                            Security::Bogus(format!("no {} at {}: {}", record_type, name, reason))
+                        })?;
+                    match (denial, rescode) {
+                        (Denial::NxDomain, ResponseCode::NXDOMAIN)
+                        | (Denial::NoData(_), ResponseCode::NOERROR) => {}
+                        (Denial::Insecure, _) => insecure = true,
+                        (_, rescode) => {
+                            return Err(Security::Bogus(format!(
+                                "proof of no {} at {} doesn't match {:?}",
+                                record_type, name, rescode
+                            )))
+                        }
+                    }
+                }
+                Err(Security::Insecure) => insecure = true,
+                Err(security) => return Err(security),
+            }
+        }
+
+        match insecure {
+            true => Err(Security::Insecure),
+            false => Ok(()),
+        }
+    }
+
+    /// Apex and DNSKEYs of the secure zone of `record_type` records at `name`
+    ///
+    /// Delegations are followed from the closest trust anchor down to the name. DS records
+    /// live on the parent side of a zone cut, so for them the search stops above the name.
+    fn enclosing_zone(
+        &self,
+        name: &DomainName,
+        record_type: RecordType,
+        now: u32,
+    ) -> Result<(DomainName, Vec<Vec<u8>>), Security> {
+        let depth = match record_type {
+            RecordType::DS => name.label_count().saturating_sub(1),
+            _ => name.label_count(),
+        };
+        let anchor = self
+            .anchors
+            .iter()
+            .map(|anchor| &anchor.domain_name)
+            .filter(|owner| owner.label_count() <= depth && name.is_subdomain_of(owner))
+            .max_by_key(|owner| owner.label_count())
+            .ok_or(Security::Insecure)?;
+
+        let mut zone = anchor.clone();
+        let mut keys = match self.cut(&zone, None, now) {
+            Cut::Secure(keys) => keys,
+            Cut::Bogus(reason) => return Err(Security::Bogus(reason)),
+            _ => return Err(Security::Insecure),
+        };
+        for labels in zone.label_count() + 1..=depth {
+            let child = ancestor(name, labels);
+            match self.cut(&child, Some((&zone, &keys)), now) {
+                Cut::Secure(child_keys) => (zone, keys) = (child, child_keys),
+                Cut::Inside => {}
+                Cut::Absent => break,
+                Cut::Insecure => return Err(Security::Insecure),
+                Cut::Bogus(reason) => return Err(Security::Bogus(reason)),
+            }
+        }
+        Ok((zone, keys))
+    }
+
+    /// Zone cut at `name` below `parent` zone with its keys, the trust anchor without parent
+    fn cut(&self, name: &DomainName, parent: Option<(&DomainName, &[Vec<u8>])>, now: u32) -> Cut {
+        let cut = self.cached_cut(name, parent, now);
+        self.report(|| Step::Cut {
+            name: name.clone(),
+            outcome: cut.to_string(),
+        });
+        cut
+    }
+
+    /// [`Self::find_cut`], cached until the TTL of the records it was found from expires
+    fn cached_cut(
+        &self,
+        name: &DomainName,
+        parent: Option<(&DomainName, &[Vec<u8>])>,
+        now: u32,
+    ) -> Cut {
+        let key = name.canonicalize();
+        if let Some((cut, expires)) = self.cuts.lock().expect("cut cache lock poisoned").get(&key) {
+            if *expires > Instant::now() {
+                return cut.clone();
+            }
+        }
+
+        let (cut, ttl) = self.find_cut(name, parent, now);
+        if let Some(ttl) = ttl {
+            let now = Instant::now();
+            let mut cuts = self.cuts.lock().expect("cut cache lock poisoned");
+            if cuts.len() >= MAX_CUTS {
+                cuts.retain(|_, (_, expires)| *expires > now);
+                if cuts.len() >= MAX_CUTS {
+                    cuts.clear();
+                }
+            }
+            cuts.insert(key, (cut.clone(), now + ttl.min(MAX_CACHE_TTL)));
+        }
+        cut
+    }
+
+    /// Finds out the zone cut at `name`, with its TTL (none if it's not to be cached)
+    fn find_cut(
+        &self,
+        name: &DomainName,
+        parent: Option<(&DomainName, &[Vec<u8>])>,
+        now: u32,
+    ) -> (Cut, Option<Duration>) {
+        let Some((zone, keys)) = parent else {
+            let anchors: Vec<Vec<u8>> = self
+                .anchors
+                .iter()
+                .filter(|anchor| anchor.domain_name.eq_ignore_case(name))
+                .filter_map(|anchor| match &anchor.data {
+                    RecordData::Unknown(data) => Some(data.clone()),
+                    _ => None,
+                })
+                .collect();
+            self.report(|| Step::Anchor {
+                zone: name.clone(),
+                tags: anchors
+                    .iter()
+                    .map(|ds| u16::from_be_bytes([ds[0], ds[1]]))
+                    .collect(),
+            });
+            return self.zone_keys(name, &anchors, MAX_CACHE_TTL, now);
+        };
+
+        let response = match self.query(name, RecordType::DS) {
+            Ok(response) => response,
+            Err(e) => {
+                return (
+                    Cut::Bogus(format!("DS query for {} failed: {:#}", name, e)),
+                    None,
+                )
+            }
+        };
+        let ds: Vec<&DnsRecord> = response
+            .answers
+            .iter()
+            .filter(|record| {
+                record.record_type == RecordType::DS && record.domain_name.eq_ignore_case(name)
+            })
+            .collect();
+
+        // alias can't be a zone cut, it's a name of the parent zone
+        let cname: Vec<&DnsRecord> = response
+            .answers
+            .iter()
+            .filter(|record| {
+                record.record_type == RecordType::CNAME && record.domain_name.eq_ignore_case(name)
+            })
+            .collect();
+        if ds.is_empty() && !cname.is_empty() {
+            return match self.verify(&cname, &response.answers, zone, keys, now) {
+                Ok(_) => (Cut::Inside, Some(NEGATIVE_TTL)),
+                Err(reason) => (Cut::Bogus(reason), Some(BOGUS_TTL)),
+            };
+        }
+        if ds.is_empty() {
+            let denial = self.deny(
+                &response.authorities,
+                zone,
+                keys,
+                name,
+                RecordType::DS.into(),
+                now,
+            );
+            let cut = match denial {
+                // delegation without DS, or there's no zone cut at all
+                Ok(Denial::NoData(types)) => {
+                    let has = |record_type: RecordType| types.contains(&record_type.into());
+                    match has(RecordType::NS) && !has(RecordType::SOA) {
+                        true => Cut::Insecure,
+                        false => Cut::Inside,
+                    }
+                }
+                Ok(Denial::NxDomain) => Cut::Absent,
+                Ok(Denial::Insecure) => Cut::Insecure,
+                Err(reason) => {
+                    let reason = format!("no DS of {} proven: {}", name, reason);
+                    return (Cut::Bogus(reason), Some(BOGUS_TTL));
+                }
+            };
+            return (cut, Some(NEGATIVE_TTL));
+        }
+
+        if let Err(reason) = self.verify(&ds, &response.answers, zone, keys, now) {
+            return (
+                Cut::Bogus(format!("DS of {}: {}", name, reason)),
+                Some(BOGUS_TTL),
+            );
+        }
+        let ttl = Duration::from_secs(ds[0].ttl.into());
+        let ds: Vec<Vec<u8>> = ds
+            .iter()
+            .filter_map(|record| match &record.data {
+                RecordData::Unknown(data) => Some(data.clone()),
+                _ => None,
+            })
+            .collect();
+        self.zone_keys(name, &ds, ttl, now)
+    }
+
+    /// DNSKEYs of `zone` if one of them matches one of the `ds` and signs them
+    fn zone_keys(
+        &self,
+        zone: &DomainName,
+        ds: &[Vec<u8>],
+        ds_ttl: Duration,
+        now: u32,
+    ) -> (Cut, Option<Duration>) {
+        // DS of algorithms we can't check make the zone insecure (RFC 4035 section 5.2)
+        let ds: Vec<&Vec<u8>> = ds
+            .iter()
+            .filter(|ds| ds.len() > 4 && dnssec::is_supported(ds[2]) && ds_digest(ds[3]).is_some())
+            .collect();
+        if ds.is_empty() {
+            return (Cut::Insecure, Some(NEGATIVE_TTL));
+        }
+
+        let response = match self.query(zone, RecordType::DNSKEY) {
+            Ok(response) => response,
+            Err(e) => {
+                return (
+                    Cut::Bogus(format!("DNSKEY query for {} failed: {:#}", zone, e)),
+                    None,
+                )
+            }
+        };
+        let dnskeys: Vec<&DnsRecord> = response
+            .answers
+            .iter()
+            .filter(|record| {
+                record.record_type == RecordType::DNSKEY && record.domain_name.eq_ignore_case(zone)
+            })
+            .collect();
+        let keys: Vec<Vec<u8>> = dnskeys
+            .iter()
+            .filter_map(|record| match &record.data {
+                RecordData::Unknown(data) => Some(data.clone()),
+                _ => None,
+            })
+            .collect();
+
+        let trusted: Vec<Vec<u8>> = keys
+            .iter()
+            .filter(|key| ds.iter().any(|ds| ds_matches(ds, zone, key)))
+            .cloned()
+            .collect();
+        self.report(|| Step::Keys {
+            zone: zone.clone(),
+            tags: keys.iter().map(|key| key_tag(key)).collect(),
+            trusted: trusted.iter().map(|key| key_tag(key)).collect(),
+        });
+        if trusted.is_empty() {
+            let reason = format!("no DNSKEY of {} matches its DS", zone);
+            return (Cut::Bogus(reason), Some(BOGUS_TTL));
+        }
+        if let Err(reason) = self.verify(&dnskeys, &response.answers, zone, &trusted, now) {
+            return (
+                Cut::Bogus(format!("DNSKEY of {}: {}", zone, reason)),
+                Some(BOGUS_TTL),
+            );
+        }
+        let ttl = ds_ttl.min(Duration::from_secs(dnskeys[0].ttl.into()));
+        (Cut::Secure(keys), Some(ttl))
+    }
+
+    /// Asks the upstream for `record_type` records at `name` with their signatures
+    pub fn query(&self, name: &DomainName, record_type: RecordType) -> Result<DnsPacket> {
+        let mut opt = OptRecord::new(MAX_UDP_PAYLOAD_SIZE);
+        opt.dnssec_ok = true;
+        let mut query = DnsPacket::builder()
+            .id(rand::random())
+            .recursion_desired(true)
+            .question(DnsQuestion::new(
+                name.clone(),
+                QueryType::from(u16::from(record_type)),
+                QueryClass::IN,
+            ))
+            .opt(Some(opt))
+            .build();
+        // we check the data ourselves, the upstream must not hold back what it finds bogus
+        query.header.checking_disabled = true;
+
+        let response = self.upstream.exchange(&query)?;
+        if response.header.id != query.header.id {
+            anyhow::bail!(
+                "ID mismatch: expected ID {}, got {}",
+                query.header.id,
+                response.header.id
+            );
+        }
+        Ok(response)
+    }
+}
+
+/// Checks that one of the RRSIGs in `section` of `rrset` was made by one of `keys` of `zone`
+///
+/// Returns the tag of the key which made it and the closest encloser if the RRset was
+/// expanded from a wildcard.
+fn verify_rrset(
+    rrset: &[&DnsRecord],
+    section: &[DnsRecord],
+    zone: &DomainName,
+    keys: &[Vec<u8>],
+    now: u32,
+) -> Result<(u16, Option<DomainName>), String> {
+    let first = rrset[0];
+    let covered = u16::from(first.record_type.clone());
+    let mut reason = format!("{} {} is not signed", first.domain_name, first.record_type);
+    for signature in section.iter().filter(|record| {
+        record.record_type == RecordType::RRSIG
+            && record.domain_name.eq_ignore_case(&first.domain_name)
+            && covered_type(record) == covered
+    }) {
+        match verify_signature(signature, rrset, zone, keys, now) {
+            Ok(expanded) => return Ok((signature_key_tag(signature), expanded)),
+            Err(e) => reason = format!("{} {}: {}", first.domain_name, first.record_type, e),
+        }
+    }
+    Err(reason)
+}
+
+fn signature_key_tag(signature: &DnsRecord) -> u16 {
+    match &signature.data {
+        RecordData::Unknown(data) if data.len() >= 18 => u16::from_be_bytes([data[16], data[17]]),
+        _ => 0,
+    }
+}
+
+/// Checks RRSIG `signature` of `rrset` (RFC 4035 section 5.3)
+fn verify_signature(
+    signature: &DnsRecord,
+    rrset: &[&DnsRecord],
+    zone: &DomainName,
+    keys: &[Vec<u8>],
+    now: u32,
+) -> Result<Option<DomainName>, String> {
+    let RecordData::Unknown(data) = &signature.data else {
+        return Err("RRSIG is not opaque".to_string());
+    };
+    let (signer, signer_len) = data.get(18..).and_then(read_name).ok_or("invalid RRSIG")?;
+    if !signer.eq_ignore_case(zone) {
+        return Err(format!("signed by {} instead of zone {}", signer, zone));
+    }
+    let field = |offset: usize| u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+    let (algorithm, labels, original_ttl) = (data[2], data[3] as usize, field(4));
+    let (expiration, inception) = (field(8), field(12));
+    let tag = u16::from_be_bytes([data[16], data[17]]);
+
+    // serial number arithmetic, times wrap around in 2106 (RFC 4034 section 3.1.5)
+    if (now.wrapping_sub(inception) as i32) < 0 {
+        return Err("signature is not valid yet".to_string());
+    }
+    if (expiration.wrapping_sub(now) as i32) < 0 {
+        return Err("signature expired".to_string());
+    }
+
+    let owner = &rrset[0].domain_name;
+    let owner_labels = owner.label_count() - usize::from(owner.labels().next() == Some(b"*"));
+    if labels > owner_labels {
+        return Err("RRSIG has more labels than its owner".to_string());
+    }
+    // the signature covers the wildcard the RRset was expanded from (RFC 4035 section 5.3.2)
+    let encloser = (labels < owner_labels).then(|| ancestor(owner, labels));
+    let records: Vec<DnsRecord> = rrset
+        .iter()
+        .map(|&record| DnsRecord {
+            domain_name: encloser
+                .as_ref()
+                .map_or(record.domain_name.clone(), wildcard),
+            ttl: original_ttl,
+            ..record.clone()
+        })
+        .collect();
+    let records: Vec<&DnsRecord> = records.iter().collect();
+    let prefix = [&data[..18], &name_bytes(&signer.canonicalize())].concat();
+    let message = signed_data(&prefix, &records);
+
+    let mut reason = format!("no DNSKEY {} of {}", tag, zone);
+    for key in keys.iter().filter(|key| {
+        key.len() > 4
+            && u16::from_be_bytes([key[0], key[1]]) & ZONE_KEY_FLAG != 0
+            && key[3] == algorithm
+            && key_tag(key) == tag
+    }) {
+        match dnssec::verify_signature(key, &message, &data[18 + signer_len..]) {
+            Ok(()) => return Ok(encloser),
+            Err(e) => reason = format!("signature by key {}: {:#}", tag, e),
+        }
+    }
+    Err(reason)
+}
+
+/// Digest algorithm of DS digest type `digest_type`
+fn ds_digest(digest_type: u8) -> Option<&'static digest::Algorithm> {
+    match digest_type {
+        1 => Some(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+        2 => Some(&digest::SHA256),
+        4 => Some(&digest::SHA384),
+        _ => None,
+    }
+}
+
+/// Whether DS RDATA `ds` refers to zone key `dnskey` of `zone` (RFC 4034 section 5.1.4)
+fn ds_matches(ds: &[u8], zone: &DomainName, dnskey: &[u8]) -> bool {
+    let Some(algorithm) = ds_digest(ds[3]) else {
+        return false;
+    };
+    if dnskey.len() <= 4
+        || u16::from_be_bytes([dnskey[0], dnskey[1]]) & ZONE_KEY_FLAG == 0
+        || dnskey[3] != ds[2]
+        || key_tag(dnskey) != u16::from_be_bytes([ds[0], ds[1]])
+    {
+        return false;
+    }
+    let mut owner_and_key = name_bytes(&zone.canonicalize());
+    owner_and_key.extend_from_slice(dnskey);
+    digest::digest(algorithm, &owner_and_key).as_ref() == &ds[4..]
+}
+
+/// What a proof of denial shows
+#[derive(Debug)]
+enum Denial {
+    /// Name exists (or is an empty non-terminal) and has these types only
+    NoData(Vec<u16>),
+    NxDomain,
+    /// Nothing is proven: opt-out span or too many NSEC3 iterations
+    Insecure,
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoData(_) => write!(f, "NODATA proven"),
+            Self::NxDomain => write!(f, "NXDOMAIN proven"),
+            Self::Insecure => write!(f, "not proven (opt-out or too many NSEC3 iterations)"),
+        }
+    }
+}
+
+/// Authenticated NSEC and NSEC3 records of a negative answer
+struct Proof {
+    zone: DomainName,
+    nsecs: Vec<Nsec>,
+    nsec3s: Vec<Nsec3>,
+}
+
+struct Nsec {
+    owner: DomainName,
+    next: DomainName,
+    types: Vec<u16>,
+}
+
+struct Nsec3 {
+    hash: Vec<u8>,
+    next: Vec<u8>,
+    opt_out: bool,
+    iterations: u16,
+    salt: Vec<u8>,
+    types: Vec<u16>,
+}
+
+impl Proof {
+    /// NSEC and NSEC3 records of `section` signed by `zone` with one of its `keys`
+    fn new(
+        validator: &Validator,
+        section: &[DnsRecord],
+        zone: &DomainName,
+        keys: &[Vec<u8>],
+        now: u32,
+    ) -> Result<Self, String> {
+        let records: Vec<DnsRecord> = section
+            .iter()
+            .filter(|record| matches!(record.record_type, RecordType::NSEC | RecordType::NSEC3))
+            .cloned()
+            .collect();
+        let mut proof = Self {
+            zone: zone.clone(),
+            nsecs: Vec::new(),
+            nsec3s: Vec::new(),
+        };
+        for rrset in dnssec::rrsets(&records) {
+            validator.verify(&rrset, section, zone, keys, now)?;
+            for record in rrset {
+                let RecordData::Unknown(data) = &record.data else {
+                    continue;
+                };
+                let invalid = || format!("invalid {}", record);
+                if record.record_type == RecordType::NSEC {
+                    let (next, len) = read_name(data).ok_or_else(invalid)?;
+                    proof.nsecs.push(Nsec {
+                        owner: record.domain_name.clone(),
+                        next,
+                        types: bitmap_types(&data[len..]),
+                    });
+                } else {
+                    proof
+                        .nsec3s
+                        .push(parse_nsec3(record, zone).ok_or_else(invalid)?);
+                }
+            }
+        }
+        if proof.nsecs.is_empty() && proof.nsec3s.is_empty() {
+            return Err("no NSEC or NSEC3 records".to_string());
+        }
+        Ok(proof)
+    }
+
+    /// Proves that there are no `query_type` records at `name`
+    fn deny(&self, name: &DomainName, query_type: u16) -> Result<Denial, String> {
+        let no_type = |types: &Vec<u16>| {
+            let cname = u16::from(RecordType::CNAME);
+            match types.contains(&query_type) || types.contains(&cname) {
+                true => Err(format!("the type exists at {}", name)),
+                false => Ok(Denial::NoData(types.clone())),
+            }
+        };
+
+        if !self.nsecs.is_empty() {
+            if let Some(nsec) = self
+                .nsecs
+                .iter()
+                .find(|nsec| nsec.owner.eq_ignore_case(name))
+            {
+                return no_type(&nsec.types);
+            }
+            let covering = self
+                .nsecs
+                .iter()
+                .find(|nsec| covers(&nsec.owner, &nsec.next, name))
+                .ok_or_else(|| format!("no NSEC covers {}", name))?;
+            // names below it exist, so it's an empty non-terminal
+            if covering.next.is_subdomain_of(name) {
+                return Ok(Denial::NoData(Vec::new()));
+            }
+            let encloser = [&covering.owner, &covering.next]
+                .into_iter()
+                .map(|other| common_labels(name, other))
+                .max()
+                .unwrap_or_default();
+            let wildcard = wildcard(&ancestor(name, encloser));
+            if let Some(nsec) = self
+                .nsecs
+                .iter()
+                .find(|nsec| nsec.owner.eq_ignore_case(&wildcard))
+            {
+                return no_type(&nsec.types);
+            }
+            return match self
+                .nsecs
+                .iter()
+                .any(|nsec| covers(&nsec.owner, &nsec.next, &wildcard))
+            {
+                true => Ok(Denial::NxDomain),
+                false => Err(format!("no NSEC proves there's no {}", wildcard)),
+            };
+        }
+
+        if self.nsec3s[0].iterations > MAX_NSEC3_ITERATIONS {
+            return Ok(Denial::Insecure);
+        }
+        if let Some(nsec3) = self.matching_nsec3(name) {
+            return no_type(&nsec3.types);
+        }
+        // closest encloser proof (RFC 5155 section 8.3)
+        for labels in (self.zone.label_count()..name.label_count()).rev() {
+            let encloser = ancestor(name, labels);
+            if self.matching_nsec3(&encloser).is_none() {
+                continue;
+            }
+            let next_closer = ancestor(name, labels + 1);
+            let covering = self
+                .covering_nsec3(&next_closer)
+                .ok_or_else(|| format!("no NSEC3 covers {}", next_closer))?;
+            if covering.opt_out {
+                return Ok(Denial::Insecure);
+            }
+            let wildcard = wildcard(&encloser);
+            if let Some(nsec3) = self.matching_nsec3(&wildcard) {
+                return no_type(&nsec3.types);
+            }
+            return match self.covering_nsec3(&wildcard) {
+                Some(_) => Ok(Denial::NxDomain),
+                None => Err(format!("no NSEC3 proves there's no {}", wildcard)),
+            };
+        }
+        Err(format!("no NSEC3 proves the closest encloser of {}", name))
+    }
+
+    /// Proves that `name`, answered from the wildcard at `encloser`, doesn't exist itself
+    fn no_name(&self, name: &DomainName, encloser: &DomainName) -> Result<(), String> {
+        let covered = match self.nsecs.is_empty() {
+            false => self
+                .nsecs
+                .iter()
+                .any(|nsec| covers(&nsec.owner, &nsec.next, name)),
+            true => self
+                .covering_nsec3(&ancestor(name, encloser.label_count() + 1))
+                .is_some(),
+        };
+        match covered {
+            true => Ok(()),
+            false => Err(format!("no proof that {} doesn't exist", name)),
+        }
+    }
+
+    fn matching_nsec3(&self, name: &DomainName) -> Option<&Nsec3> {
+        let params = &self.nsec3s[0];
+        let hash = nsec3_hash(name, &params.salt, params.iterations);
+        self.nsec3s.iter().find(|nsec3| nsec3.hash == hash)
+    }
+
+    fn covering_nsec3(&self, name: &DomainName) -> Option<&Nsec3> {
+        let params = &self.nsec3s[0];
+        let hash = nsec3_hash(name, &params.salt, params.iterations);
+        self.nsec3s
+            .iter()
+            .find(|nsec3| match nsec3.next > nsec3.hash {
+                true => nsec3.hash < hash && hash < nsec3.next,
+                // the last NSEC3 of the chain wraps around
+                false => nsec3.hash < hash || hash < nsec3.next,
+            })
+    }
+}
+
+/// Whether the NSEC interval from `owner` to `next` contains `name` in canonical order
+fn covers(owner: &DomainName, next: &DomainName, name: &DomainName) -> bool {
+    let after_owner = owner.canonical_cmp(name).is_lt();
+    match owner.canonical_cmp(next).is_lt() {
+        true => after_owner && name.canonical_cmp(next).is_lt(),
+        // the last NSEC of the chain points back to the apex
+        false => after_owner || name.canonical_cmp(next).is_lt(),
+    }
+}
+
+/// NSEC3 record of `zone`, `None` if it's malformed or of another zone or hash
+fn parse_nsec3(record: &DnsRecord, zone: &DomainName) -> Option<Nsec3> {
+    let mut labels = record.domain_name.labels();
+    let hash = base32hex(labels.next()?)?;
+    if !DomainName::from_labels(labels).eq_ignore_case(zone) {
+        return None;
+    }
+    let RecordData::Unknown(data) = &record.data else {
+        return None;
+    };
+    let (&algorithm, &flags) = (data.first()?, data.get(1)?);
+    if algorithm != 1 {
+        return None;
+    }
+    let iterations = u16::from_be_bytes([*data.get(2)?, *data.get(3)?]);
+    let salt_len = *data.get(4)? as usize;
+    let salt = data.get(5..5 + salt_len)?.to_vec();
+    let hash_len = *data.get(5 + salt_len)? as usize;
+    let next_start = 6 + salt_len;
+    let next = data.get(next_start..next_start + hash_len)?.to_vec();
+    Some(Nsec3 {
+        hash,
+        next,
+        opt_out: flags & OPT_OUT_FLAG != 0,
+        iterations,
+        salt,
+        types: bitmap_types(&data[next_start + hash_len..]),
+    })
+}
+
+/// Hashed owner name of NSEC3 with SHA-1 (RFC 5155 section 5)
+fn nsec3_hash(name: &DomainName, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let hash = |data: &[u8]| {
+        let mut context = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        context.update(data);
+        context.update(salt);
+        context.finish().as_ref().to_vec()
+    };
+    let mut digest = hash(&name_bytes(&name.canonicalize()));
+    for _ in 0..iterations {
+        digest = hash(&digest);
+    }
+    digest
+}
+
+/// Decodes base32 with extended hex alphabet, without padding (RFC 4648 section 7)
+fn base32hex(text: &[u8]) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &c in text {
+        let value = match c.to_ascii_lowercase() {
+            c @ b'0'..=b'9' => c - b'0',
+            c @ b'a'..=b'v' => c - b'a' + 10,
+            _ => return None,
+        };
+        buffer = buffer << 5 | u32::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+/// Types in NSEC/NSEC3 type bit maps field (RFC 4034 section 4.1.2)
+fn bitmap_types(mut data: &[u8]) -> Vec<u16> {
+    let mut types = Vec::new();
+    while let [window, len, rest @ ..] = data {
+        let len = (*len as usize).min(rest.len());
+        for (index, byte) in rest[..len].iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push(u16::from(*window) << 8 | (index * 8 + bit) as u16);
+                }
+            }
+        }
+        data = &rest[len..];
+    }
+    types
+}
+
+/// Uncompressed name at the start of `data` and its length
+fn read_name(data: &[u8]) -> Option<(DomainName, usize)> {
+    let mut labels = Vec::new();
+    let mut pos = 0;
+    loop {
+        let len = *data.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            return Some((DomainName::from_labels(labels), pos));
+        }
+        if len > 63 {
+            return None;
+        }
+        labels.push(data.get(pos..pos + len)?);
+        pos += len;
+    }
+}
+
+/// Last `labels` labels of `name`
+fn ancestor(name: &DomainName, labels: usize) -> DomainName {
+    DomainName::from_labels(
+        name.labels()
+            .skip(name.label_count().saturating_sub(labels)),
+    )
+}
+
+fn wildcard(encloser: &DomainName) -> DomainName {
+    DomainName::from_labels(std::iter::once(&b"*"[..]).chain(encloser.labels()))
+}
+
+/// Number of trailing labels `a` and `b` have in common
+fn common_labels(a: &DomainName, b: &DomainName) -> usize {
+    a.labels()
+        .rev()
+        .zip(b.labels().rev())
+        .take_while(|(a, b)| a.eq_ignore_ascii_case(b))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use ring::rand::SystemRandom;
+    use ring::signature::Ed25519KeyPair;
+
+    use super::*;
+    use crate::dnssec::{Algorithm, KeyRole, SigningKey};
+    use crate::handler::{ForwardHandler, Pipeline, Request, ZoneHandler};
+    use crate::record::RecordClass;
+    use crate::upstream::MockUpstream;
+    use crate::zone::Zone;
+
+    const ZONE: &str = "$ORIGIN example.
+@    IN SOA ns1 hostmaster 1 7200 900 1209600 300
+     IN NS  ns1
+ns1  IN A   192.0.2.1
+www  IN CNAME ns1
+sub  IN NS  ns.sub
+ns.sub IN A 192.0.2.2
+";
+
+    /// Upstream serving zone `example.` signed with a CSK, the DS of which is returned too
+    fn signed_upstream(tamper: fn(&mut DnsPacket)) -> (Arc<dyn Upstream>, DnsRecord) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key = SigningKey::from_pkcs8(Algorithm::Ed25519, KeyRole::Csk, pkcs8.as_ref()).unwrap();
+        let zone = Zone::parse(ZONE).unwrap();
+        let origin = zone.origin().clone();
+        let zone = dnssec::sign_zone(&zone, &[key], SystemTime::now()).unwrap();
+        let key = SigningKey::from_pkcs8(Algorithm::Ed25519, KeyRole::Csk, pkcs8.as_ref()).unwrap();
+        let ds = DnsRecord::new(
+            origin.clone(),
+            RecordType::DS,
+            RecordClass::IN,
+            3600,
+            RecordData::Unknown(key.ds_rdata(&origin)),
+        );
+
+        let pipeline = Pipeline::new().with(ZoneHandler::new([zone]));
+        let upstream = MockUpstream::new(move |query: &DnsPacket| {
+            assert!(query.header.checking_disabled);
+            let mut response = pipeline.handle(&Request::new(query.clone(), None))?;
+            tamper(&mut response);
+            Ok(response)
+        });
+        (Arc::new(upstream), ds)
+    }
+
+    fn validator(tamper: fn(&mut DnsPacket)) -> (Validator, Arc<dyn Upstream>) {
+        let (upstream, ds) = signed_upstream(tamper);
+        let validator = Validator::new(upstream.clone())
+            .trust_anchors(vec![ds])
+            .unwrap();
+        (validator, upstream)
+    }
+
+    fn query(name: &str, record_type: RecordType) -> DnsPacket {
+        let mut opt = OptRecord::new(MAX_UDP_PAYLOAD_SIZE);
+        opt.dnssec_ok = true;
+        DnsPacket::builder()
+            .id(1)
+            .question(DnsQuestion::new(
+                DomainName::from(name),
+                QueryType::from(u16::from(record_type)),
+                QueryClass::IN,
+            ))
+            .opt(Some(opt))
+            .build()
+    }
+
+    fn validate(
+        validator: &Validator,
+        upstream: &dyn Upstream,
+        name: &str,
+        record_type: RecordType,
+    ) -> Security {
+        let query = query(name, record_type);
+        let mut forwarded = query.clone();
+        forwarded.header.checking_disabled = true;
+        let response = upstream.exchange(&forwarded).unwrap();
+        validator.validate(&query.questions[0], &response)
+    }
+
+    #[test]
+    fn test_signed_answers_are_secure() {
+        let (validator, upstream) = validator(|_| {});
+        let validate = |name, record_type| validate(&validator, &*upstream, name, record_type);
+
+        assert_eq!(validate("www.example", RecordType::A), Security::Secure);
+        assert_eq!(validate("example", RecordType::DNSKEY), Security::Secure);
+        // NXDOMAIN and NODATA proven by NSEC
+        assert_eq!(validate("missing.example", RecordType::A), Security::Secure);
+        assert_eq!(validate("ns1.example", RecordType::AAAA), Security::Secure);
+        // NSEC of the delegation proves there's no DS
+        assert_eq!(
+            validate("ns.sub.example", RecordType::A),
+            Security::Insecure
+        );
+        // no trust anchor covers the name
+        assert_eq!(validate("example.org", RecordType::A), Security::Insecure);
+    }
+
+    #[test]
+    fn test_tampered_answers_are_bogus() {
+        let is_bogus = |tamper: fn(&mut DnsPacket), name, record_type| {
+            let (validator, upstream) = validator(tamper);
+            matches!(
+                validate(&validator, &*upstream, name, record_type),
+                Security::Bogus(_)
+            )
+        };
+
+        let spoof: fn(&mut DnsPacket) = |response| {
+            for answer in response.answers.iter_mut() {
+                if answer.record_type == RecordType::A {
+                    answer.data = Ipv4Addr::new(203, 0, 113, 1).into();
+                }
+            }
+        };
+        assert!(is_bogus(spoof, "ns1.example", RecordType::A));
+
+        let unsigned: fn(&mut DnsPacket) = |response| {
+            response
+                .answers
+                .retain(|answer| answer.record_type != RecordType::RRSIG);
+        };
+        assert!(is_bogus(unsigned, "ns1.example", RecordType::A));
+
+        let unproven: fn(&mut DnsPacket) = |response| {
+            response
+                .authorities
+                .retain(|authority| authority.record_type == RecordType::SOA);
+        };
+        assert!(is_bogus(unproven, "missing.example", RecordType::A));
+    }
+
+    #[test]
+    fn test_permissive_mode_returns_bogus_answers() {
+        let (upstream, ds) = signed_upstream(|response| {
+            for answer in response.answers.iter_mut() {
+                if answer.record_type == RecordType::A {
+                    answer.data = Ipv4Addr::new(203, 0, 113, 1).into();
+                }
+            }
+        });
+        let forward = |mode: ValidationMode, name, record_type| {
+            let mut validator = Validator::new(upstream.clone())
+                .trust_anchors(vec![ds.clone()])
+                .unwrap();
+            if mode == ValidationMode::Permissive {
+                validator = validator.permissive();
+            }
+            let forward = ForwardHandler::new(upstream.clone()).validator(Arc::new(validator));
+            Pipeline::new()
+                .with(forward)
+                .handle(&Request::new(query(name, record_type), None))
+                .unwrap()
+        };
+
+        for mode in [ValidationMode::Enforce, ValidationMode::Permissive] {
+            let response = forward(mode, "example", RecordType::NS);
+            assert!(response.header.authed_data);
+        }
+
+        let response = forward(ValidationMode::Enforce, "ns1.example", RecordType::A);
+        assert_eq!(response.header.rescode, ResponseCode::SERVFAIL);
+        let response = forward(ValidationMode::Permissive, "ns1.example", RecordType::A);
+        assert_eq!(response.header.rescode, ResponseCode::NOERROR);
+        assert!(!response.header.authed_data);
+        assert_eq!(response.answers[0].data, Ipv4Addr::new(203, 0, 113, 1));
+    }
+
+    #[test]
+    fn test_observer_sees_chain_of_trust() {
+        let (upstream, ds) = signed_upstream(|_| {});
+        let steps = Arc::new(Mutex::new(Vec::new()));
+        let observed = steps.clone();
+        let validator = Validator::new(upstream.clone())
+            .trust_anchors(vec![ds])
+            .unwrap()
+            .observe(move |step| observed.lock().unwrap().push(step.clone()));
+
+        assert_eq!(
+            validate(&validator, &*upstream, "missing.example", RecordType::A),
+            Security::Secure
+        );
+        let steps = steps.lock().unwrap();
+        let zone = DomainName::from("example");
+        assert!(matches!(&steps[0], Step::Anchor { zone: z, .. } if *z == zone));
+        assert!(matches!(&steps[1], Step::Keys { trusted, .. } if trusted.len() == 1));
+        assert!(steps.contains(&Step::Cut {
+            name: zone.clone(),
+            outcome: "secure zone".to_string()
+        }));
+        assert!(steps.contains(&Step::Denial {
+            name: DomainName::from("missing.example"),
+            record_type: RecordType::A,
+            result: Ok("NXDOMAIN proven".to_string()),
+        }));
+    }
+
+    #[test]
+    fn test_nsec3_hash() {
+        // RFC 5155 appendix A
+        let salt = [0xaa, 0xbb, 0xcc, 0xdd];
+        assert_eq!(
+            nsec3_hash(&DomainName::from("example"), &salt, 12),
+            base32hex(b"0p9mhaveqvm6t7vbl5lop2u3t2rp3tom").unwrap()
+        );
+        assert_eq!(
+            nsec3_hash(&DomainName::from("a.example"), &salt, 12),
+            base32hex(b"35mthgpgcu1qg68fab165klnsnk3dpvl").unwrap()
+        );
+    }
+}
@@ -0,0 +1,408 @@
+//! Cache of encoded responses, the fast path of the UDP server
+//!
+//! Most queries ask a single question which was asked many times before. The server keeps the
+//! bytes of the response it sent for such a question and answers it again by copying them,
+//! patching only the message ID, the question (its name as the client spelled it) and the TTLs
+//! (decremented by the time spent in the cache). The query is not parsed beyond its question,
+//! no handler runs and nothing is encoded.
+//!
+//! Only plain queries take the fast path: one question, no records but an OPT without options,
+//! no reserved bits. Queries differing in RD, CD, AD or DO get responses of their own. Answers
+//! served from here skip the handlers, and with them the query log, tracing and sampling, so
+//! the cache is for pipelines whose answers don't depend on the client (no client routes,
+//! hairpinning or scripts).
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use smallvec::SmallVec;
+
+use crate::edns::OPT_RECORD_TYPE;
+use crate::header::{ResponseCode, HEADER_LENGTH};
+use crate::packet::{DnsPacket, MIN_UDP_SIZE};
+use crate::stats::{self, CacheStats};
+
+/// Question (name lowercased) and the flags of the query affecting its response
+type WireKey = SmallVec<[u8; 64]>;
+
+/// Query which can be answered from the cache, found in its wire format
+#[derive(Debug)]
+pub struct WireQuery {
+    key: WireKey,
+    /// Where the question is in the query (and in the response)
+    question: Range<usize>,
+    /// UDP payload size of EDNS
+    udp_payload_size: Option<u16>,
+}
+
+impl WireQuery {
+    /// Plain query of `bytes`, `None` if it must go through the handlers
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let header = bytes.get(..HEADER_LENGTH as usize)?;
+        let (flags, flags2) = (header[2], header[3]);
+        let count = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+        // QR, opcode, AA, TC, Z must be clear, only RD, AD and CD may be set
+        if flags & 0xFE != 0 || flags2 & 0xCF != 0 {
+            return None;
+        }
+        let additionals = count(10);
+        if count(4) != 1 || count(6) != 0 || count(8) != 0 || additionals > 1 {
+            return None;
+        }
+
+        let start = HEADER_LENGTH as usize;
+        let mut pos = start;
+        let mut key = WireKey::new();
+        loop {
+            let len = *bytes.get(pos)? as usize;
+            if len & 0xC0 != 0 {
+                return None; // nothing to compress against in the first name
+            }
+            key.push(len as u8);
+            key.extend(
+                bytes
+                    .get(pos + 1..pos + 1 + len)?
+                    .iter()
+                    .map(u8::to_ascii_lowercase),
+            );
+            pos += 1 + len;
+            if len == 0 {
+                break;
+            }
+        }
+        key.extend_from_slice(bytes.get(pos..pos + 4)?);
+        pos += 4;
+        let question = start..pos;
+
+        let mut udp_payload_size = None;
+        let mut dnssec_ok = false;
+        if additionals == 1 {
+            let opt = bytes.get(pos..pos + 11)?;
+            let plain = opt[0] == 0 // root
+                && u16::from_be_bytes([opt[1], opt[2]]) == OPT_RECORD_TYPE
+                && opt[5..7] == [0, 0] // extended RCODE, version
+                && opt[9..] == [0, 0]; // no options
+            if !plain {
+                return None;
+            }
+            udp_payload_size = Some(u16::from_be_bytes([opt[3], opt[4]]));
+            dnssec_ok = opt[7] & 0x80 != 0;
+            pos += 11;
+        }
+        if pos != bytes.len() {
+            return None;
+        }
+
+        let edns = (udp_payload_size.is_some() as u8) << 1 | dnssec_ok as u8;
+        key.extend_from_slice(&[flags & 0x01, flags2 & 0x30, edns]);
+        Some(Self {
+            key,
+            question,
+            udp_payload_size,
+        })
+    }
+
+    /// Returns true if `question` in wire format is the question of the query (name in any case)
+    fn is_question(&self, question: &[u8]) -> bool {
+        let (name, rest) = question.split_at(question.len().saturating_sub(4));
+        let asked = &self.key[..self.question.len()];
+        question.len() == asked.len()
+            && name.eq_ignore_ascii_case(&asked[..name.len()])
+            && rest == &asked[name.len()..]
+    }
+
+    /// Largest UDP response the client accepts, but at most `max_size`, as
+    /// [`DnsPacket::udp_response_limit`]
+    pub fn udp_response_limit(&self, max_size: u16) -> usize {
+        let max_size = max_size.max(MIN_UDP_SIZE);
+        match self.udp_payload_size {
+            Some(size) => size.clamp(MIN_UDP_SIZE, max_size) as usize,
+            None => MIN_UDP_SIZE as usize,
+        }
+    }
+}
+
+struct WireEntry {
+    response: Vec<u8>,
+    /// Positions of the TTLs in the response and their values when stored
+    ttls: Vec<(usize, u32)>,
+    stored_at: Instant,
+}
+
+impl WireEntry {
+    /// Seconds until the first of the records expires
+    fn remaining(&self) -> Option<u32> {
+        let min_ttl = self.ttls.iter().map(|&(_, ttl)| ttl).min()?;
+        let elapsed = self.stored_at.elapsed().as_secs();
+        (min_ttl as u64)
+            .checked_sub(elapsed)
+            .filter(|&remaining| remaining > 0)
+            .map(|remaining| remaining as u32)
+    }
+
+    fn size(&self, key: &WireKey) -> usize {
+        std::mem::size_of::<(WireKey, WireEntry)>()
+            + if key.spilled() { key.capacity() } else { 0 }
+            + self.response.len()
+            + self.ttls.len() * std::mem::size_of::<(usize, u32)>()
+    }
+}
+
+/// Encoded responses by question, see the [module](self) documentation
+pub struct WireCache {
+    capacity: usize,
+    entries: Mutex<HashMap<WireKey, WireEntry>>,
+    stats: Arc<CacheStats>,
+}
+
+impl WireCache {
+    /// Cache holding responses to at most `capacity` questions
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+            stats: stats::cache(),
+        }
+    }
+
+    /// Response to `query` (whose wire format is `bytes`) if cached, fresh and at most
+    /// `size_limit` bytes long
+    pub fn lookup(&self, query: &WireQuery, bytes: &[u8], size_limit: usize) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        let Some(entry) = entries.get(&query.key[..]) else {
+            self.stats.miss();
+            return None;
+        };
+        if entry.remaining().is_none() {
+            self.stats.miss();
+            self.stats.expired();
+            self.stats.removed(entry.size(&query.key));
+            entries.remove(&query.key[..]);
+            return None;
+        }
+        if entry.response.len() > size_limit {
+            self.stats.miss();
+            return None; // to be truncated
+        }
+        self.stats.hit();
+
+        let elapsed = entry.stored_at.elapsed().as_secs() as u32;
+        let mut response = entry.response.clone();
+        response[..2].copy_from_slice(&bytes[..2]); // ID
+        response[query.question.clone()].copy_from_slice(&bytes[query.question.clone()]);
+        for &(pos, ttl) in entry.ttls.iter() {
+            response[pos..pos + 4].copy_from_slice(&(ttl - elapsed).to_be_bytes());
+        }
+        Some(response)
+    }
+
+    /// Keeps `encoded` response to `query`, if it's fit for any client asking the same
+    ///
+    /// Positive answers and negative ones with SOA are kept until their first record
+    /// expires, truncated responses and those with EDNS options are not kept.
+    pub fn store(&self, query: &WireQuery, response: &DnsPacket, encoded: &[u8]) {
+        let cacheable = matches!(
+            response.header.rescode,
+            ResponseCode::NOERROR | ResponseCode::NXDOMAIN
+        ) && !response.header.truncated_message
+            && response
+                .opt
+                .as_ref()
+                .is_none_or(|opt| opt.options.is_empty());
+        if !cacheable {
+            return;
+        }
+        let Some(ttls) = ttl_positions(encoded, query.question.end) else {
+            return;
+        };
+        if ttls.is_empty() || ttls.iter().any(|&(_, ttl)| ttl == 0) {
+            return;
+        }
+        // the question must be where the query has it, patched with the query's one
+        if !encoded
+            .get(query.question.clone())
+            .is_some_and(|question| query.is_question(question))
+        {
+            return;
+        }
+
+        let entry = WireEntry {
+            response: encoded.to_vec(),
+            ttls,
+            stored_at: Instant::now(),
+        };
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        if entries.len() >= self.capacity && !entries.contains_key(&query.key[..]) {
+            entries.retain(|key, entry| {
+                let live = entry.remaining().is_some();
+                if !live {
+                    self.stats.evicted();
+                    self.stats.removed(entry.size(key));
+                }
+                live
+            });
+            if entries.len() >= self.capacity {
+                self.stats.rejected();
+                return; // full of live entries
+            }
+        }
+        self.stats.inserted(entry.size(&query.key));
+        if let Some(replaced) = entries.insert(query.key.clone(), entry) {
+            self.stats.removed(replaced.size(&query.key));
+        }
+    }
+}
+
+/// Positions and values of TTLs of the records (not OPT) in `message`, whose records start at
+/// `records`, `None` if the message is not a single-question one
+fn ttl_positions(message: &[u8], records: usize) -> Option<Vec<(usize, u32)>> {
+    let counts = message.get(4..HEADER_LENGTH as usize)?;
+    if counts[..2] != [0, 1] {
+        return None;
+    }
+    let count = |i: usize| u16::from_be_bytes([counts[i], counts[i + 1]]) as usize;
+    let records_count = count(2) + count(4) + count(6);
+
+    let mut ttls = Vec::with_capacity(records_count);
+    let mut pos = records;
+    for _ in 0..records_count {
+        // owner name, ends with the root label or a pointer
+        loop {
+            let len = *message.get(pos)? as usize;
+            if len & 0xC0 == 0xC0 {
+                pos += 2;
+                break;
+            }
+            pos += 1 + len;
+            if len == 0 {
+                break;
+            }
+        }
+        let fixed = message.get(pos..pos + 10)?;
+        let record_type = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+        let length = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        if record_type != OPT_RECORD_TYPE {
+            ttls.push((pos + 4, ttl));
+        }
+        pos += 10 + length;
+    }
+    (pos == message.len()).then_some(ttls)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::domain_name::DomainName;
+    use crate::edns::{EdnsOption, OptRecord};
+    use crate::handler::response_builder;
+    use crate::packet::BytesPacket;
+    use crate::question::{DnsQuestion, QueryClass, QueryType};
+    use crate::record::{DnsRecord, RecordClass, RecordType};
+
+    fn query(id: u16, name: &str, opt: Option<OptRecord>) -> Vec<u8> {
+        let query = DnsPacket::builder()
+            .id(id)
+            .recursion_desired(true)
+            .question(DnsQuestion::new(
+                DomainName::from(name),
+                QueryType::A,
+                QueryClass::IN,
+            ))
+            .opt(opt)
+            .build();
+        BytesPacket::from(query).buf.to_vec()
+    }
+
+    fn answer(query: &[u8]) -> (DnsPacket, Vec<u8>) {
+        let query = DnsPacket::parse(query).unwrap();
+        let name = query.questions[0].domain_name.clone();
+        let response = response_builder(&query)
+            .recursion_available(true)
+            .answer(DnsRecord::new(
+                name,
+                RecordType::A,
+                RecordClass::IN,
+                300,
+                Ipv4Addr::new(192, 0, 2, 1),
+            ))
+            .build();
+        let encoded = BytesPacket::from(&response).buf.to_vec();
+        (response, encoded)
+    }
+
+    #[test]
+    fn test_cached_response_is_patched_for_the_query() {
+        let cache = WireCache::new(10);
+        let first = query(1, "example.com", Some(OptRecord::new(1232)));
+        let wire_query = WireQuery::parse(&first).unwrap();
+        assert!(cache.lookup(&wire_query, &first, 512).is_none());
+        let (response, encoded) = answer(&first);
+        cache.store(&wire_query, &response, &encoded);
+
+        // pretend it was stored 100 seconds ago
+        for entry in cache.entries.lock().unwrap().values_mut() {
+            entry.stored_at = Instant::now() - Duration::from_secs(100);
+        }
+        let second = query(2, "EXAMPLE.com", Some(OptRecord::new(4096)));
+        let wire_query = WireQuery::parse(&second).unwrap();
+        let cached = cache.lookup(&wire_query, &second, 1232).unwrap();
+        let cached = DnsPacket::parse_strict(&cached).unwrap();
+        assert_eq!(cached.header.id, 2);
+        assert_eq!(cached.questions[0].domain_name.to_string(), "EXAMPLE.com.");
+        assert_eq!(cached.answers[0].ttl, 200);
+        assert_eq!(cached.answers[0].data, Ipv4Addr::new(192, 0, 2, 1));
+
+        // no EDNS, DO and RD=0 are other questions
+        let plain = query(3, "example.com", None);
+        let wire_query = WireQuery::parse(&plain).unwrap();
+        assert!(cache.lookup(&wire_query, &plain, 512).is_none());
+        let mut dnssec = OptRecord::new(1232);
+        dnssec.dnssec_ok = true;
+        let signed = query(4, "example.com", Some(dnssec));
+        assert!(cache
+            .lookup(&WireQuery::parse(&signed).unwrap(), &signed, 1232)
+            .is_none());
+        let mut iterative = first.clone();
+        iterative[2] &= !0x01;
+        assert!(cache
+            .lookup(&WireQuery::parse(&iterative).unwrap(), &iterative, 1232)
+            .is_none());
+
+        for entry in cache.entries.lock().unwrap().values_mut() {
+            entry.stored_at = Instant::now() - Duration::from_secs(300);
+        }
+        let wire_query = WireQuery::parse(&first).unwrap();
+        assert!(cache.lookup(&wire_query, &first, 1232).is_none());
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_only_plain_queries_take_fast_path() {
+        assert!(WireQuery::parse(&query(1, "example.com", None)).is_some());
+
+        let mut cookie = OptRecord::new(1232);
+        cookie.options.push(EdnsOption {
+            code: 10,
+            data: vec![1; 8],
+        });
+        assert!(WireQuery::parse(&query(1, "example.com", Some(cookie))).is_none());
+
+        let mut two_questions = query(1, "example.com", None);
+        two_questions[5] = 2;
+        assert!(WireQuery::parse(&two_questions).is_none());
+
+        let mut trailing = query(1, "example.com", None);
+        trailing.push(0);
+        assert!(WireQuery::parse(&trailing).is_none());
+
+        let mut response = query(1, "example.com", None);
+        response[2] |= 0x80;
+        assert!(WireQuery::parse(&response).is_none());
+    }
+}
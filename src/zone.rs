@@ -0,0 +1,718 @@
+//! Local zones loaded from master files (RFC 1035 section 5)
+//!
+//! Only the subset of the format used by small home/lab zones is understood:
+//! `$ORIGIN` and `$TTL` directives, `@` and relative owner names, blank owners
+//! (repeating the previous one), parentheses spanning lines, `;` comments and
+//! records of types A, AAAA, NS, CNAME, PTR, MX, SOA, ZONEMD and DNSSEC types DNSKEY,
+//! RRSIG, DS, CDS and CDNSKEY. Other types can be given in the generic format of RFC 3597
+//! (`TYPE65534 \# 2 abcd`), which is understood for all the other types too.
+//!
+//! ```text
+//! $ORIGIN home.arpa.
+//! $TTL 3600
+//! @       IN  SOA  ns1 hostmaster ( 1 7200 900 1209600 300 )
+//!         IN  NS   ns1
+//! ns1     IN  A    192.168.1.1
+//! nas         A    192.168.1.10
+//! ```
+//!
+//! Zones can be transferred (AXFR/IXFR) only by clients matching one of their
+//! [`TransferRule`]s, nobody may transfer a zone without any. Zones replaced by their new
+//! versions keep a journal of the changes for IXFR, see [`crate::journal`].
+
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use bytes::{BufMut, BytesMut};
+
+use crate::base64;
+use crate::domain_name::{DomainName, LookupTable};
+use crate::journal::{ZoneDiff, JOURNAL_LENGTH};
+use crate::log::days_from_civil;
+use crate::network::Network;
+use crate::record::{DnsRecord, RecordClass, RecordData, RecordType, Soa};
+
+/// TTL of records before the first `$TTL` directive or explicit TTL
+const DEFAULT_TTL: u32 = 3600;
+
+/// Records of a single zone, the zone is authoritative for its origin and all names below it
+#[derive(Debug, Clone)]
+pub struct Zone {
+    origin: DomainName,
+    records: Vec<DnsRecord>,
+    /// Changes from the previous versions of the zone, oldest first
+    journal: Vec<ZoneDiff>,
+}
+
+impl Zone {
+    /// Creates zone from its records, one of them must be SOA of the origin
+    pub fn new(origin: DomainName, records: Vec<DnsRecord>) -> Result<Self> {
+        let zone = Self {
+            origin,
+            records,
+            journal: Vec::new(),
+        };
+        if zone.soa().is_none() {
+            anyhow::bail!("zone {} has no SOA record", zone.origin);
+        }
+        if let Some(record) = zone
+            .records
+            .iter()
+            .find(|record| !record.domain_name.is_subdomain_of(&zone.origin))
+        {
+            anyhow::bail!("record {} is outside of zone {}", record, zone.origin);
+        }
+        Ok(zone)
+    }
+
+    /// Loads zone from a master file, origin is given by `$ORIGIN` or the owner of SOA
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read zone file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("invalid zone file {}", path.display()))
+    }
+
+    /// Parses zone in master file format
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser::default();
+        parser.parse(text)?;
+
+        let origin = match (parser.origin, parser.records.first()) {
+            (Some(origin), _) => origin,
+            (None, Some(first)) if first.record_type == RecordType::SOA => {
+                first.domain_name.clone()
+            }
+            _ => anyhow::bail!("zone origin is unknown, use $ORIGIN or start with SOA"),
+        };
+        Self::new(origin, parser.records)
+    }
+
+    pub fn origin(&self) -> &DomainName {
+        &self.origin
+    }
+
+    pub fn records(&self) -> &[DnsRecord] {
+        &self.records
+    }
+
+    /// `new` version of this zone, with the changes added to the journal
+    ///
+    /// Changes are kept only while the serial grows (RFC 1982 serial number arithmetic),
+    /// otherwise secondaries couldn't tell the versions apart and the journal starts anew.
+    pub fn next_version(&self, mut new: Zone) -> Zone {
+        let Ok(diff) = ZoneDiff::between(self, &new) else {
+            return new;
+        };
+        let (old_serial, new_serial) = (diff.old_serial(), diff.new_serial());
+        if new_serial.wrapping_sub(old_serial) as i32 > 0 {
+            new.journal = self.journal.clone();
+            new.journal.push(diff);
+            if new.journal.len() > JOURNAL_LENGTH {
+                new.journal.remove(0);
+            }
+        } else if diff.is_empty() && old_serial == new_serial {
+            new.journal = self.journal.clone();
+        }
+        new
+    }
+
+    /// Changes from the previous versions, oldest first
+    pub fn journal(&self) -> &[ZoneDiff] {
+        &self.journal
+    }
+
+    /// Changes from version `serial` to the current one, `None` if the journal doesn't reach
+    /// that far back
+    pub fn changes_since(&self, serial: u32) -> Option<&[ZoneDiff]> {
+        let start = self
+            .journal
+            .iter()
+            .position(|diff| diff.old_serial() == serial)?;
+        Some(&self.journal[start..])
+    }
+
+    /// SOA record of the zone apex
+    pub fn soa(&self) -> Option<&DnsRecord> {
+        self.records.iter().find(|record| {
+            record.record_type == RecordType::SOA && record.domain_name.eq_ignore_case(&self.origin)
+        })
+    }
+
+    /// Records owned by `name` (of any type)
+    pub fn records_at<'a>(&'a self, name: &'a DomainName) -> impl Iterator<Item = &'a DnsRecord> {
+        self.records
+            .iter()
+            .filter(move |record| record.domain_name.eq_ignore_case(name))
+    }
+
+    /// Returns true if `name` owns records or some name below it does (empty non-terminal)
+    pub fn contains_name(&self, name: &DomainName) -> bool {
+        self.records
+            .iter()
+            .any(|record| record.domain_name.is_subdomain_of(name))
+    }
+
+    /// NS records of the zone cut at or above `name`, if `name` is delegated to another zone
+    ///
+    /// NS records of the origin describe the zone itself and are not a delegation.
+    pub fn delegation(&self, name: &DomainName) -> Option<Vec<DnsRecord>> {
+        let origin_labels = self.origin.label_count();
+        let labels: Vec<&[u8]> = name.labels().collect();
+
+        // closest cut to the origin wins, names below it belong to the child zone
+        (origin_labels + 1..=labels.len()).find_map(|count| {
+            let cut = DomainName::from_labels(labels[labels.len() - count..].iter().copied());
+            let ns: Vec<DnsRecord> = self
+                .records_at(&cut)
+                .filter(|record| record.record_type == RecordType::NS)
+                .cloned()
+                .collect();
+            (!ns.is_empty()).then_some(ns)
+        })
+    }
+
+    /// All records in the order of zone transfer: SOA first, then the rest and SOA again (RFC 5936 section 2.2)
+    pub fn transfer(&self) -> Vec<DnsRecord> {
+        let soa = self.soa().expect("zone has SOA");
+        let mut records = vec![soa.clone()];
+        records.extend(self.records.iter().filter(|record| *record != soa).cloned());
+        records.push(soa.clone());
+        records
+    }
+
+    /// In-zone A/AAAA records of the name servers in `ns_records` (glue)
+    pub fn glue(&self, ns_records: &[DnsRecord]) -> Vec<DnsRecord> {
+        let mut glue: Vec<DnsRecord> = Vec::new();
+        for ns in ns_records {
+            let RecordData::Name(target) = &ns.data else {
+                continue;
+            };
+            if !target.is_subdomain_of(&self.origin) {
+                continue;
+            }
+            for record in self.records_at(target) {
+                if matches!(record.record_type, RecordType::A | RecordType::AAAA)
+                    && !glue.contains(record)
+                {
+                    glue.push(record.clone());
+                }
+            }
+        }
+        glue
+    }
+
+    /// RRSIG records of the RRset of `record_type` owned by `name`
+    pub fn signatures(&self, name: &DomainName, record_type: &RecordType) -> Vec<DnsRecord> {
+        let covered = u16::from(record_type.clone()).to_be_bytes();
+        self.records_at(name)
+            .filter(|record| {
+                record.record_type == RecordType::RRSIG
+                    && matches!(&record.data, RecordData::Unknown(data) if data.starts_with(&covered))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// NSEC records proving that `name` doesn't exist (`nxdomain`) or has no records of
+    /// the queried type (RFC 4035 section 3.1.3), without their signatures
+    pub fn denial(&self, name: &DomainName, nxdomain: bool) -> Vec<DnsRecord> {
+        let mut proof: Vec<DnsRecord> = self.covering_nsec(name).into_iter().collect();
+        if nxdomain {
+            // nor is there a wildcard at the closest existing ancestor
+            let labels: Vec<&[u8]> = name.labels().collect();
+            let encloser = (1..labels.len())
+                .map(|skip| DomainName::from_labels(labels[skip..].iter().copied()))
+                .find(|ancestor| self.contains_name(ancestor))
+                .unwrap_or_else(|| self.origin.clone());
+            let wildcard =
+                DomainName::from_labels(std::iter::once(&b"*"[..]).chain(encloser.labels()));
+            if let Some(nsec) = self.covering_nsec(&wildcard) {
+                if !proof.contains(&nsec) {
+                    proof.push(nsec);
+                }
+            }
+        }
+        proof
+    }
+
+    /// NSEC record owned by `name`, or the one whose interval contains it
+    fn covering_nsec(&self, name: &DomainName) -> Option<DnsRecord> {
+        self.records
+            .iter()
+            .filter(|record| record.record_type == RecordType::NSEC)
+            .filter(|record| record.domain_name.canonical_cmp(name).is_le())
+            .max_by(|a, b| a.domain_name.canonical_cmp(&b.domain_name))
+            .cloned()
+    }
+}
+
+/// Clients allowed to transfer a zone, by network, TSIG key or both
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferRule {
+    pub network: Option<Network>,
+    /// Name of the key which must sign the transfer request
+    pub key: Option<DomainName>,
+}
+
+/// Parses `<network>`, `key:<name>` or `key:<name>@<network>`
+impl FromStr for TransferRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, network) = match s.strip_prefix("key:") {
+            Some(rest) => match rest.split_once('@') {
+                Some((key, network)) => (Some(key), Some(network)),
+                None => (Some(rest), None),
+            },
+            None => (None, Some(s)),
+        };
+        if key.is_some_and(str::is_empty) {
+            anyhow::bail!("missing key name in transfer rule {:?}", s);
+        }
+
+        Ok(Self {
+            network: network.map(str::parse).transpose()?,
+            key: key.map(DomainName::from),
+        })
+    }
+}
+
+impl fmt::Display for TransferRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.key, &self.network) {
+            (Some(key), Some(network)) => write!(f, "key:{}@{}", key, network),
+            (Some(key), None) => write!(f, "key:{}", key),
+            (None, Some(network)) => write!(f, "{}", network),
+            (None, None) => f.write_str("anyone"),
+        }
+    }
+}
+
+/// Parses records in master file format which don't make a whole zone (no SOA), e.g.
+/// an RRset signed elsewhere; relative names are relative to `origin`
+pub fn parse_records(text: &str, origin: &DomainName) -> Result<Vec<DnsRecord>> {
+    let mut parser = Parser {
+        origin: Some(origin.clone()),
+        ..Default::default()
+    };
+    parser.parse(text)?;
+    Ok(parser.records)
+}
+
+/// Bytes of hexadecimal `tokens`, which may split the value anywhere between digits
+fn hex(tokens: &[&str]) -> Result<Vec<u8>> {
+    let digits: String = tokens.concat();
+    if !digits.len().is_multiple_of(2) || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        anyhow::bail!("invalid hexadecimal value {:?}", digits);
+    }
+    Ok((0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).expect("hexadecimal digits"))
+        .collect())
+}
+
+/// Bytes of base64 `tokens`, which may split the value anywhere
+fn base64_value(tokens: &[&str]) -> Result<Vec<u8>> {
+    let text = tokens.concat();
+    base64::decode(&text).with_context(|| format!("invalid base64 value {:?}", text))
+}
+
+/// Signature expiration or inception, `YYYYMMDDHHmmSS` in UTC or seconds since the epoch
+/// (RFC 4034 section 3.2)
+fn signature_time(value: &str) -> Result<u32> {
+    if value.len() != 14 {
+        return value
+            .parse()
+            .with_context(|| format!("invalid signature time {:?}", value));
+    }
+
+    let number = |range: std::ops::Range<usize>| value[range].parse::<u64>().ok();
+    let date = (|| {
+        let (year, month, day) = (number(0..4)?, number(4..6)?, number(6..8)?);
+        if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return None;
+        }
+        let seconds = days_from_civil(year, month, day) * 86400
+            + number(8..10)? * 3600
+            + number(10..12)? * 60
+            + number(12..14)?;
+        // serial number arithmetic, the value wraps in 2106 (RFC 4034 section 3.1.5)
+        Some(seconds as u32)
+    })();
+    date.with_context(|| format!("invalid signature time {:?}", value))
+}
+
+/// Joins lines continued by parentheses and strips comments, yields them with the number of the first line
+fn logical_lines(text: &str) -> Result<Vec<(usize, String)>> {
+    let mut lines = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    let mut depth = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or_default();
+        let (_, joined) = current.get_or_insert_with(|| (index + 1, String::new()));
+        for c in line.chars() {
+            match c {
+                '(' => depth += 1,
+                ')' if depth == 0 => anyhow::bail!("line {}: unbalanced ')'", index + 1),
+                ')' => depth -= 1,
+                c => joined.push(c),
+            }
+        }
+        joined.push(' ');
+
+        if depth == 0 {
+            lines.extend(current.take());
+        }
+    }
+
+    if let Some((number, _)) = current.filter(|_| depth > 0) {
+        anyhow::bail!("line {}: unbalanced '('", number);
+    }
+    Ok(lines)
+}
+
+/// State carried between lines of a master file
+#[derive(Default)]
+struct Parser {
+    origin: Option<DomainName>,
+    default_ttl: Option<u32>,
+    last_owner: Option<DomainName>,
+    last_ttl: Option<u32>,
+    records: Vec<DnsRecord>,
+}
+
+impl Parser {
+    fn parse(&mut self, text: &str) -> Result<()> {
+        for (number, line) in logical_lines(text)? {
+            self.parse_line(&line)
+                .with_context(|| format!("line {}", number))?;
+        }
+        Ok(())
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<()> {
+        let mut tokens = line.split_whitespace().peekable();
+        let Some(&first) = tokens.peek() else {
+            return Ok(()); // blank line
+        };
+
+        match first.to_ascii_uppercase().as_str() {
+            "$ORIGIN" => {
+                let origin = tokens.nth(1).context("missing $ORIGIN name")?;
+                self.origin = Some(self.name(origin)?);
+                return Ok(());
+            }
+            "$TTL" => {
+                let ttl = tokens.nth(1).context("missing $TTL value")?;
+                self.default_ttl = Some(ttl.parse().context("invalid $TTL")?);
+                return Ok(());
+            }
+            directive if directive.starts_with('$') => {
+                anyhow::bail!("unsupported directive {}", first)
+            }
+            _ => {}
+        }
+
+        // owner is omitted when the line starts with a blank
+        let owner = if line.starts_with([' ', '\t']) {
+            self.last_owner.clone().context("missing owner name")?
+        } else {
+            let owner = tokens.next().context("missing owner name")?;
+            self.name(owner)?
+        };
+
+        // TTL and class may come in any order before the type
+        let mut ttl = None;
+        let record_type = loop {
+            let token = tokens.next().context("missing record type")?;
+            if let Ok(value) = token.parse::<u32>() {
+                ttl = Some(value);
+            } else if token.eq_ignore_ascii_case("IN") {
+                continue;
+            } else {
+                break token.to_ascii_uppercase();
+            }
+        };
+
+        let rdata: Vec<&str> = tokens.collect();
+        let (record_type, data) = self.rdata(&record_type, &rdata)?;
+
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .unwrap_or(DEFAULT_TTL);
+        self.last_owner = Some(owner.clone());
+        self.last_ttl = Some(ttl);
+        self.records.push(DnsRecord::new(
+            owner,
+            record_type,
+            RecordClass::IN,
+            ttl,
+            data,
+        ));
+        Ok(())
+    }
+
+    fn rdata(&self, record_type: &str, rdata: &[&str]) -> Result<(RecordType, RecordData)> {
+        let field = |index: usize| -> Result<&str> {
+            rdata
+                .get(index)
+                .copied()
+                .with_context(|| format!("missing RDATA field {} of {}", index + 1, record_type))
+        };
+        let number = |index: usize| -> Result<u32> {
+            let value = field(index)?;
+            value
+                .parse()
+                .with_context(|| format!("invalid number {:?}", value))
+        };
+
+        // generic format of any type (RFC 3597 section 5): <type> \# <length> <hex>
+        if rdata.first() == Some(&"\\#") {
+            let record_type: RecordType = record_type.parse()?;
+            let length = number(1)? as usize;
+            let data = hex(&rdata[2..])?;
+            if data.len() != length {
+                anyhow::bail!("RDATA of {} is not {} bytes long", record_type, length);
+            }
+            let data = RecordData::from_bytes(
+                &record_type,
+                data.len(),
+                &mut &data[..],
+                &mut LookupTable::new(data.len()),
+            )
+            .map_err(|e| anyhow::anyhow!("invalid RDATA of {}: {}", record_type, e))?;
+            return Ok((record_type, data));
+        }
+
+        let parsed = match record_type {
+            "A" => (
+                RecordType::A,
+                RecordData::A(field(0)?.parse::<Ipv4Addr>().context("invalid address")?),
+            ),
+            "AAAA" => (
+                RecordType::AAAA,
+                RecordData::Aaaa(field(0)?.parse::<Ipv6Addr>().context("invalid address")?),
+            ),
+            "NS" => (RecordType::NS, RecordData::Name(self.name(field(0)?)?)),
+            "CNAME" => (RecordType::CNAME, RecordData::Name(self.name(field(0)?)?)),
+            "PTR" => (RecordType::PTR, RecordData::Name(self.name(field(0)?)?)),
+            "MX" => (
+                RecordType::MX,
+                RecordData::Mx {
+                    preference: number(0)?.try_into().context("invalid preference")?,
+                    exchange: self.name(field(1)?)?,
+                },
+            ),
+            "SOA" => (
+                RecordType::SOA,
+                RecordData::Soa(Soa {
+                    mname: self.name(field(0)?)?,
+                    rname: self.name(field(1)?)?,
+                    serial: number(2)?,
+                    refresh: number(3)?,
+                    retry: number(4)?,
+                    expire: number(5)?,
+                    minimum: number(6)?,
+                }),
+            ),
+            "ZONEMD" => {
+                let mut data = number(0)?.to_be_bytes().to_vec();
+                for index in [1, 2] {
+                    data.push(number(index)?.try_into().context("invalid ZONEMD field")?);
+                }
+                data.extend(hex(&rdata[3..])?);
+                (RecordType::ZONEMD, RecordData::Unknown(data))
+            }
+            // DNSSEC records (RFC 4034 sections 2.2, 3.2 and 5.3, RFC 7344 section 3)
+            "DNSKEY" | "CDNSKEY" => {
+                let mut data = Vec::new();
+                data.put_u16(number(0)?.try_into().context("invalid DNSKEY flags")?);
+                for index in [1, 2] {
+                    data.push(number(index)?.try_into().context("invalid DNSKEY field")?);
+                }
+                data.extend(base64_value(&rdata[3..])?);
+                (record_type.parse()?, RecordData::Unknown(data))
+            }
+            "DS" | "CDS" => {
+                let mut data = Vec::new();
+                data.put_u16(number(0)?.try_into().context("invalid key tag")?);
+                for index in [1, 2] {
+                    data.push(number(index)?.try_into().context("invalid DS field")?);
+                }
+                data.extend(hex(&rdata[3..])?);
+                (record_type.parse()?, RecordData::Unknown(data))
+            }
+            "RRSIG" => {
+                let mut data = Vec::new();
+                data.put_u16(field(0)?.parse::<RecordType>()?.into());
+                for index in [1, 2] {
+                    data.push(number(index)?.try_into().context("invalid RRSIG field")?);
+                }
+                data.put_u32(number(3)?);
+                for index in [4, 5] {
+                    data.put_u32(signature_time(field(index)?)?);
+                }
+                data.put_u16(number(6)?.try_into().context("invalid key tag")?);
+                let mut signer = BytesMut::new();
+                self.name(field(7)?)?
+                    .write_bytes(&mut signer, &mut LookupTable::new(0));
+                data.extend(signer);
+                data.extend(base64_value(&rdata[8..])?);
+                (RecordType::RRSIG, RecordData::Unknown(data))
+            }
+            generic if generic.starts_with("TYPE") && generic[4..].parse::<u16>().is_ok() => {
+                anyhow::bail!("RDATA of {} must be in the generic format", record_type)
+            }
+            _ => anyhow::bail!("unsupported record type {}", record_type),
+        };
+        Ok(parsed)
+    }
+
+    /// Absolute name, relative names are completed with the current origin
+    fn name(&self, name: &str) -> Result<DomainName> {
+        if name == "@" {
+            return self.origin.clone().context("@ used before $ORIGIN");
+        }
+        if name.ends_with('.') {
+            return DomainName::from_unicode(name);
+        }
+
+        let origin = self
+            .origin
+            .as_ref()
+            .with_context(|| format!("relative name {} used before $ORIGIN", name))?;
+        let relative = DomainName::from_unicode(name)?;
+        let absolute = DomainName::from_labels(relative.labels().chain(origin.labels()));
+        absolute
+            .ensure_max_length()
+            .with_context(|| format!("name {} in {} is too long", name, origin))?;
+        Ok(absolute)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_zone_file() {
+        let zone = Zone::parse(
+            "$ORIGIN home.arpa.
+$TTL 600
+@   IN  SOA  ns1 hostmaster.home.arpa. (
+             2024010101 ; serial
+             7200 900 1209600 300 )
+    IN  NS   ns1
+ns1 IN  A    192.168.1.1
+nas 60  AAAA fd00::10
+        A    192.168.1.10
+",
+        )
+        .unwrap();
+
+        assert_eq!(zone.origin(), &DomainName::from("home.arpa"));
+        assert_eq!(zone.records().len(), 5);
+
+        let soa = zone.soa().unwrap();
+        let RecordData::Soa(soa_data) = &soa.data else {
+            panic!("SOA expected");
+        };
+        assert_eq!(soa.ttl, 600);
+        assert_eq!(soa_data.mname, DomainName::from("ns1.home.arpa"));
+        assert_eq!(soa_data.serial, 2024010101);
+        assert_eq!(soa_data.minimum, 300);
+
+        let name = DomainName::from("NAS.home.arpa");
+        let nas: Vec<&DnsRecord> = zone.records_at(&name).collect();
+        assert_eq!(nas.len(), 2);
+        assert_eq!(nas[0].data, RecordData::Aaaa("fd00::10".parse().unwrap()));
+        assert_eq!(nas[1].data, Ipv4Addr::new(192, 168, 1, 10));
+        assert_eq!(nas[1].ttl, 600);
+    }
+
+    #[test]
+    fn test_generic_format() {
+        let zone = Zone::parse(
+            "$ORIGIN home.arpa.
+@     SOA ns1 hostmaster 1 7200 900 1209600 300
+ns1   TYPE1 \\# 4 c0a80101
+key   TYPE65534 \\# 3 ab ( cd
+      ef )
+",
+        )
+        .unwrap();
+        assert_eq!(zone.records()[1].data, Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(zone.records()[2].record_type, RecordType::UNKNOWN(65534));
+        assert_eq!(
+            zone.records()[2].data,
+            RecordData::Unknown(vec![0xab, 0xcd, 0xef])
+        );
+
+        assert!(Zone::parse("$ORIGIN home.arpa.\nns1 TYPE1 \\# 3 c0a801").is_err());
+        assert!(Zone::parse("$ORIGIN home.arpa.\nns1 TYPE1 c0a80101").is_err());
+    }
+
+    #[test]
+    fn test_internationalized_names() {
+        let zone = Zone::parse(
+            "$ORIGIN home.arpa.
+@           SOA ns1 hostmaster 1 7200 900 1209600 300
+kaffeeküche A   192.168.1.2
+",
+        )
+        .unwrap();
+        let name = DomainName::from_unicode("kaffeeküche.home.arpa").unwrap();
+        assert_eq!(name.to_string(), "xn--kaffeekche-geb.home.arpa.");
+        assert_eq!(zone.records_at(&name).count(), 1);
+
+        let long_label = format!("$ORIGIN home.arpa.\n{} A 192.168.1.3", "a".repeat(64));
+        assert!(Zone::parse(&long_label).is_err());
+    }
+
+    #[test]
+    fn test_dnssec_records() {
+        // RFC 4034 sections 2.3 and 3.3
+        let records = parse_records(
+            "example.com. 86400 IN DNSKEY 256 3 5 ( AQPSKmynfzW4kyBv015MUG2DeIQ3
+                                      Cbl+BBZH4b/0PY1kxkmvHjcZc8no )
+host 86400 IN RRSIG A 5 3 86400 20030322173103 (
+                   20030220173103 2642 example.com.
+                   oJB1W6WNGv+ldvQ3WDG0MQkg5IEhjRip8WTr )
+",
+            &DomainName::from("example.com."),
+        )
+        .unwrap();
+
+        let RecordData::Unknown(dnskey) = &records[0].data else {
+            panic!("DNSKEY is opaque");
+        };
+        assert_eq!(dnskey[..5], [0x01, 0x00, 3, 5, 0x01]);
+        assert_eq!(dnskey.len(), 4 + 42);
+
+        assert_eq!(records[1].record_type, RecordType::RRSIG);
+        assert_eq!(
+            records[1].domain_name,
+            DomainName::from("host.example.com.")
+        );
+        let RecordData::Unknown(rrsig) = &records[1].data else {
+            panic!("RRSIG is opaque");
+        };
+        assert_eq!(rrsig[..4], [0, 1, 5, 3]);
+        assert_eq!(rrsig[8..12], 1048354263u32.to_be_bytes());
+        assert_eq!(rrsig[12..16], 1045762263u32.to_be_bytes());
+        assert_eq!(rrsig[16..18], 2642u16.to_be_bytes());
+        assert_eq!(rrsig[18..31], *b"\x07example\x03com\x00");
+
+        assert!(parse_records(
+            "@ RRSIG A 5 3 86400 20031322173103 1 1 . AA==",
+            &"x.".into()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! Message digest of a whole zone (ZONEMD, RFC 8976)
+//!
+//! The digest covers every record of the zone in canonical form and order, so a copy of
+//! the zone obtained elsewhere (a transfer, a file from the primary) can be checked for
+//! corruption or tampering before it is served. In signed zones the ZONEMD record is
+//! signed like any other, see [`crate::dnssec`].
+//!
+//! Only the SIMPLE scheme is implemented, with SHA-384 (published) or SHA-512.
+
+use anyhow::Result;
+use bytes::BufMut;
+use ring::digest;
+
+use crate::dnssec::{canonical_rdata, name_bytes};
+use crate::domain_name::DomainName;
+use crate::record::{DnsRecord, RecordData, RecordType};
+use crate::zone::Zone;
+
+/// Scheme digesting the zone as a single stream of records
+const SCHEME_SIMPLE: u8 = 1;
+
+/// Hash algorithm SHA-384
+const HASH_SHA384: u8 = 1;
+
+/// Hash algorithm SHA-512
+const HASH_SHA512: u8 = 2;
+
+/// Publishes ZONEMD record (SIMPLE, SHA-384) at the apex of `zone`, replacing existing ones
+pub fn publish(zone: &Zone) -> Result<Zone> {
+    let origin = zone.origin();
+    let soa = zone.soa().expect("zone has SOA");
+    let mut records: Vec<DnsRecord> = zone
+        .records()
+        .iter()
+        .filter(|record| !is_excluded(origin, record))
+        .cloned()
+        .collect();
+    records.push(DnsRecord::new(
+        origin.clone(),
+        RecordType::ZONEMD,
+        soa.class.clone(),
+        soa.ttl,
+        RecordData::Unknown(rdata(0, HASH_SHA384, &[])),
+    ));
+    update(origin, &mut records);
+    Zone::new(origin.clone(), records)
+}
+
+/// Recomputes the digests of ZONEMD records at the apex `origin` after the zone changed
+pub(crate) fn update(origin: &DomainName, records: &mut [DnsRecord]) {
+    let serial = serial(records, origin);
+    let updated: Vec<(usize, Vec<u8>)> = records
+        .iter()
+        .enumerate()
+        .filter(|(_, record)| {
+            record.record_type == RecordType::ZONEMD && record.domain_name.eq_ignore_case(origin)
+        })
+        .map(|(index, record)| {
+            let (hash, algorithm) = match &record.data {
+                RecordData::Unknown(data) if data.get(5) == Some(&HASH_SHA512) => {
+                    (HASH_SHA512, &digest::SHA512)
+                }
+                _ => (HASH_SHA384, &digest::SHA384),
+            };
+            let digest = zone_digest(origin, records, algorithm);
+            (index, rdata(serial, hash, digest.as_ref()))
+        })
+        .collect();
+    for (index, data) in updated {
+        records[index].data = RecordData::Unknown(data);
+    }
+}
+
+/// Checks ZONEMD records of the zone (RFC 8976 section 4), returns false if the zone has
+/// none which could be verified: there's none, or it has another serial, scheme or hash
+pub fn verify(zone: &Zone) -> Result<bool> {
+    let origin = zone.origin();
+    let serial = serial(zone.records(), origin);
+
+    let mut verifiable = false;
+    for record in zone.records_at(origin) {
+        let (RecordType::ZONEMD, RecordData::Unknown(data)) = (&record.record_type, &record.data)
+        else {
+            continue;
+        };
+        if data.len() < 6 || data[..4] != serial.to_be_bytes() || data[4] != SCHEME_SIMPLE {
+            continue;
+        }
+        let algorithm = match data[5] {
+            HASH_SHA384 => &digest::SHA384,
+            HASH_SHA512 => &digest::SHA512,
+            _ => continue,
+        };
+        verifiable = true;
+        if zone_digest(origin, zone.records(), algorithm).as_ref() == &data[6..] {
+            return Ok(true);
+        }
+    }
+
+    if verifiable {
+        anyhow::bail!("ZONEMD digest of zone {} doesn't match its content", origin);
+    }
+    Ok(false)
+}
+
+/// Serial of the SOA at the apex
+fn serial(records: &[DnsRecord], origin: &DomainName) -> u32 {
+    records
+        .iter()
+        .filter(|record| record.domain_name.eq_ignore_case(origin))
+        .find_map(|record| match &record.data {
+            RecordData::Soa(soa) => Some(soa.serial),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+fn rdata(serial: u32, hash: u8, digest: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(6 + digest.len());
+    data.put_u32(serial);
+    data.put_u8(SCHEME_SIMPLE);
+    data.put_u8(hash);
+    data.extend_from_slice(digest);
+    data
+}
+
+/// ZONEMD RRset at the apex and its signatures, left out of the digest
+fn is_excluded(origin: &DomainName, record: &DnsRecord) -> bool {
+    let covered = u16::from(RecordType::ZONEMD).to_be_bytes();
+    record.domain_name.eq_ignore_case(origin)
+        && match (&record.record_type, &record.data) {
+            (RecordType::ZONEMD, _) => true,
+            (RecordType::RRSIG, RecordData::Unknown(data)) => data.starts_with(&covered),
+            _ => false,
+        }
+}
+
+/// Digest of the records in canonical order, duplicates removed (RFC 8976 section 3.3.1)
+fn zone_digest(
+    origin: &DomainName,
+    records: &[DnsRecord],
+    algorithm: &'static digest::Algorithm,
+) -> digest::Digest {
+    let mut canonical: Vec<(&DnsRecord, u16, Vec<u8>)> = records
+        .iter()
+        .filter(|record| !is_excluded(origin, record))
+        .map(|record| {
+            let record_type = u16::from(record.record_type.clone());
+            (record, record_type, canonical_rdata(&record.data))
+        })
+        .collect();
+    canonical.sort_by(|(a, a_type, a_data), (b, b_type, b_data)| {
+        a.domain_name
+            .canonical_cmp(&b.domain_name)
+            .then(a_type.cmp(b_type))
+            .then(a_data.cmp(b_data))
+    });
+    canonical.dedup_by(|(a, a_type, a_data), (b, b_type, b_data)| {
+        a.domain_name.eq_ignore_case(&b.domain_name) && a_type == b_type && a_data == b_data
+    });
+
+    let mut context = digest::Context::new(algorithm);
+    let mut buf = Vec::new();
+    for (record, record_type, data) in canonical {
+        buf.clear();
+        buf.extend(name_bytes(&record.domain_name.canonicalize()));
+        buf.put_u16(record_type);
+        buf.put_u16(record.class.clone().into());
+        buf.put_u32(record.ttl);
+        buf.put_u16(data.len() as u16);
+        buf.extend(data);
+        context.update(&buf);
+    }
+    context.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 8976 appendix A.1
+    const EXAMPLE: &str = "example. 86400 IN SOA ns1 admin 2018031900 (
+                         1800 900 604800 86400 )
+        86400 IN NS ns1
+        86400 IN NS ns2
+        86400 IN ZONEMD 2018031900 1 1 (
+                         c68090d90a7aed71
+                         6bc459f9340e3d7c
+                         1370d4d24b7e2fc3
+                         a1ddc0b9a87153b9
+                         a9713b3c9ae5cc27
+                         777f98b8e730044c )
+ns1 3600 IN A 203.0.113.63
+ns2 3600 IN AAAA 2001:db8::63
+";
+
+    #[test]
+    fn test_digest_is_verified() {
+        let zone = Zone::parse(&format!("$ORIGIN example.\n{}", EXAMPLE)).unwrap();
+        assert!(verify(&zone).unwrap());
+
+        let tampered = EXAMPLE.replace("203.0.113.63", "203.0.113.64");
+        let zone = Zone::parse(&format!("$ORIGIN example.\n{}", tampered)).unwrap();
+        assert!(verify(&zone).is_err());
+
+        // digest of another version of the zone can't be verified
+        let newer = EXAMPLE.replacen("2018031900", "2018031901", 1);
+        let zone = Zone::parse(&format!("$ORIGIN example.\n{}", newer)).unwrap();
+        assert!(!verify(&zone).unwrap());
+    }
+
+    #[test]
+    fn test_published_digest_matches() {
+        let zone = Zone::parse(&format!("$ORIGIN example.\n{}", EXAMPLE)).unwrap();
+        let published = publish(&zone).unwrap();
+        let zonemd: Vec<&DnsRecord> = published
+            .records()
+            .iter()
+            .filter(|record| record.record_type == RecordType::ZONEMD)
+            .collect();
+        assert_eq!(zonemd.len(), 1);
+        assert_eq!(
+            zonemd[0].data,
+            zone.records_at(zone.origin())
+                .find(|record| record.record_type == RecordType::ZONEMD)
+                .unwrap()
+                .data
+        );
+    }
+}